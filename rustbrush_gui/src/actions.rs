@@ -0,0 +1,469 @@
+use std::rc::Rc;
+
+use eframe::egui::{self, Key, KeyboardShortcut, Modifiers};
+use rustbrush_utils::filter_registry::FilterRegistry;
+
+use crate::adjustments::AdjustmentDialog;
+use crate::user::Tool;
+use crate::view::ViewState;
+use crate::App;
+
+/// Opens and closes the command palette.
+pub const PALETTE_SHORTCUT: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::P);
+
+/// How many more points a query character gets for matching at the start of a word.
+const WORD_START_BONUS: i32 = 8;
+/// How many more points a query character gets for matching right after the last one.
+const CONSECUTIVE_BONUS: i32 = 4;
+
+type Run = Rc<dyn Fn(&mut App, &egui::Context)>;
+
+/// An action in the registry: its name, its shortcut if it has one, when it can run and
+/// what it does.
+pub struct Action {
+    name: String,
+    shortcut: Option<KeyboardShortcut>,
+    enabled: Box<dyn Fn(&App) -> bool>,
+    run: Run,
+}
+
+impl Action {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn shortcut(&self) -> Option<KeyboardShortcut> {
+        self.shortcut
+    }
+
+    pub fn is_enabled(&self, app: &App) -> bool {
+        (self.enabled)(app)
+    }
+}
+
+/// Every action the command palette offers and the keyboard shortcuts run, in the order
+/// the palette lists them before anything's typed.
+#[derive(Default)]
+pub struct ActionRegistry {
+    actions: Vec<Action>,
+}
+
+impl ActionRegistry {
+    /// The app's actions, with one for each filter in `filters`.
+    pub fn with_builtins(filters: &FilterRegistry) -> Self {
+        let mut registry = Self::default();
+        let always = |_: &App| true;
+        let editable = |app: &App| !app.canvas.is_read_only();
+        let command = |key| Some(KeyboardShortcut::new(Modifiers::COMMAND, key));
+
+        registry.register("Undo", command(Key::Z), editable, |app, _| {
+            app.user.undo(&mut app.canvas)
+        });
+        registry.register("Redo", command(Key::Y), editable, |app, _| {
+            app.user.redo(&mut app.canvas)
+        });
+        // egui sends ctrl+C and ctrl+V as copy and paste events rather than key presses,
+        // so those are handled with the other pointer input rather than given shortcuts here
+        registry.register("Copy", None, always, |app, _| app.copy());
+        registry.register(
+            "Paste",
+            None,
+            |app| app.clipboard.is_some() && !app.canvas.is_read_only(),
+            |app, ctx| app.paste(ctx, false),
+        );
+        registry.register(
+            "Paste in Place",
+            None,
+            |app| app.clipboard.is_some() && !app.canvas.is_read_only(),
+            |app, ctx| app.paste(ctx, true),
+        );
+        registry.register(
+            "Deselect",
+            command(Key::D),
+            |app| app.canvas.selection().is_some() && !app.canvas.is_read_only(),
+            |app, _| {
+                let result = app.user.select(&mut app.canvas, None);
+                app.report_edit_error(result);
+            },
+        );
+
+        registry.register("Clear Layer", None, editable, |app, _| {
+            app.canvas.clear_layer(app.user.current_layer)
+        });
+        registry.register("Add Layer", None, editable, |app, _| {
+            let result = app.canvas.add_layer().map(|_| ());
+            app.report_edit_error(result);
+        });
+        registry.register("Add Stencil", None, editable, |app, _| {
+            let result = app.canvas.add_stencil_layer().map(|_| ());
+            app.report_edit_error(result);
+        });
+        registry.register(
+            "Merge Visible",
+            Some(KeyboardShortcut::new(
+                Modifiers::COMMAND
+                    .plus(Modifiers::SHIFT)
+                    .plus(Modifiers::ALT),
+                Key::E,
+            )),
+            editable,
+            |app, _| {
+                let result = app.user.merge_visible(&mut app.canvas);
+                app.report_edit_error(result);
+            },
+        );
+
+        registry.register("Save PNG", command(Key::S), always, |app, _| {
+            app.save_painting()
+        });
+        registry.register("Import PNG…", None, editable, |app, _| {
+            app.import_path = Some(String::new())
+        });
+        registry.register("Export Again", command(Key::E), always, |app, _| {
+            app.quick_export()
+        });
+        registry.register(
+            "Export PNG…",
+            Some(KeyboardShortcut::new(
+                Modifiers::COMMAND.plus(Modifiers::SHIFT),
+                Key::E,
+            )),
+            always,
+            |app, _| app.open_export_dialog(),
+        );
+
+        for (tool, name) in [
+            (Tool::Brush, "Brush Tool"),
+            (Tool::Eraser, "Eraser Tool"),
+            (Tool::Eyedropper, "Eyedropper Tool"),
+            (Tool::Move, "Move Tool"),
+            (Tool::Fill, "Fill Tool"),
+            (Tool::Shape, "Shape Tool"),
+            (Tool::DodgeBurn, "Dodge/Burn Tool"),
+            (Tool::Noise, "Noise Tool"),
+            (Tool::HueShift, "Hue Shift Tool"),
+            (Tool::Clone, "Clone Tool"),
+        ] {
+            registry.register(name, None, editable, move |app, _| {
+                app.user.current_tool = tool
+            });
+        }
+
+        for (index, entry) in filters.entries().iter().enumerate() {
+            let name = format!("{}…", entry.name());
+            registry.register(name, None, editable, move |app, _| {
+                if let Some(entry) = app.filters.entries().get(index) {
+                    app.adjustment_dialog =
+                        AdjustmentDialog::open(&mut app.canvas, &app.user, entry);
+                }
+            });
+        }
+
+        registry.register("Reset View", None, always, |app, _| {
+            app.view = ViewState {
+                pixels_per_point: app.view.pixels_per_point,
+                smooth: app.view.smooth,
+                ..ViewState::default()
+            };
+        });
+        registry.register("Toggle Rulers", None, always, |app, _| {
+            app.rulers.visible = !app.rulers.visible
+        });
+        registry.register("Toggle Performance", None, always, |app, _| {
+            app.show_perf = !app.show_perf
+        });
+        registry.register("Toggle Document Info", None, always, |app, _| {
+            app.show_document_info = !app.show_document_info
+        });
+        registry.register("Toggle History", None, always, |app, _| {
+            app.show_history = !app.show_history
+        });
+        registry.register("Take Snapshot", None, always, |app, ctx| {
+            let options = app.view.texture_options();
+            app.compare.take(ctx, &app.canvas, options);
+        });
+        registry.register(
+            "Command Palette",
+            Some(PALETTE_SHORTCUT),
+            |app| !app.command_palette.is_open(),
+            |app, _| app.command_palette.toggle(),
+        );
+        registry
+    }
+
+    /// Adds an action at the end. `enabled` says whether it can run, given the app as it is,
+    /// and disabled actions are still listed but can't be picked.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        shortcut: Option<KeyboardShortcut>,
+        enabled: impl Fn(&App) -> bool + 'static,
+        run: impl Fn(&mut App, &egui::Context) + 'static,
+    ) {
+        self.actions.push(Action {
+            name: name.into(),
+            shortcut,
+            enabled: Box::new(enabled),
+            run: Rc::new(run),
+        });
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// What the action at `index` does, to run once the registry is no longer borrowed.
+    pub fn handler(&self, index: usize) -> Option<Run> {
+        self.actions.get(index).map(|action| Rc::clone(&action.run))
+    }
+
+    /// The index of the action whose shortcut was pressed this frame, consuming the key
+    /// press. Shortcuts with more modifiers are tried first, since egui lets extra shift and
+    /// alt through, so ctrl+shift+E would otherwise also be taken as ctrl+E.
+    pub fn pressed(&self, ctx: &egui::Context) -> Option<usize> {
+        let mut shortcuts: Vec<(usize, KeyboardShortcut)> = self
+            .actions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, action)| Some((index, action.shortcut?)))
+            .collect();
+        shortcuts.sort_by_key(|(_, shortcut)| std::cmp::Reverse(modifier_count(shortcut)));
+        ctx.input_mut(|i| {
+            shortcuts
+                .into_iter()
+                .find(|(_, shortcut)| i.consume_shortcut(shortcut))
+                .map(|(index, _)| index)
+        })
+    }
+}
+
+fn modifier_count(shortcut: &KeyboardShortcut) -> usize {
+    let modifiers = shortcut.modifiers;
+    let command = modifiers.ctrl || modifiers.command || modifiers.mac_cmd;
+    [modifiers.alt, modifiers.shift, command]
+        .into_iter()
+        .filter(|&held| held)
+        .count()
+}
+
+/// How well `query` matches `name`, higher being better, or `None` if it doesn't match.
+/// Every character of the query has to appear in the name in order, ignoring case and the
+/// query's spaces. Characters at the start of a word, or right after the one before, score
+/// more, and each character skipped in between scores less, so "ml" finds "Merge Layers"
+/// before "Smaller".
+pub fn fuzzy_score(query: &str, name: &str) -> Option<i32> {
+    let query: Vec<char> = query
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(fold_case)
+        .collect();
+    let name: Vec<char> = name.chars().collect();
+    let Some(&first) = query.first() else {
+        return Some(0);
+    };
+    // the rest is matched greedily from each place the first character could go, keeping
+    // the best, so an early match in the middle of a word doesn't hide a better one later
+    (0..name.len())
+        .filter(|&start| fold_case(name[start]) == first)
+        .filter_map(|start| score_from(&query[1..], &name, start))
+        .max()
+}
+
+/// The score of `query` matched greedily in `name` after its first character was matched
+/// at `start`, see [`fuzzy_score`].
+fn score_from(query: &[char], name: &[char], start: usize) -> Option<i32> {
+    let is_word_start = |index: usize| index == 0 || !name[index - 1].is_alphanumeric();
+    let mut score = 1 - start as i32;
+    if is_word_start(start) {
+        score += WORD_START_BONUS;
+    }
+    let mut last = start;
+    for &c in query {
+        let index = last + 1 + name[last + 1..].iter().position(|&n| fold_case(n) == c)?;
+        score += 1;
+        if is_word_start(index) {
+            score += WORD_START_BONUS;
+        }
+        if index == last + 1 {
+            score += CONSECUTIVE_BONUS;
+        } else {
+            score -= (index - last - 1) as i32;
+        }
+        last = index;
+    }
+    Some(score)
+}
+
+fn fold_case(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// The indices of the `names` that match `query`, best first, see [`fuzzy_score`]. Equally
+/// good matches, and everything when the query is empty, keep the order they came in.
+pub fn rank<'a>(query: &str, names: impl IntoIterator<Item = &'a str>) -> Vec<usize> {
+    let mut matches: Vec<(usize, i32)> = names
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, name)| Some((index, fuzzy_score(query, name)?)))
+        .collect();
+    matches.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    matches.into_iter().map(|(index, _)| index).collect()
+}
+
+/// A search field over every action, listing the matches with their shortcuts. The arrow
+/// keys move through the matches, Enter runs the highlighted one and Escape closes it.
+#[derive(Default)]
+pub struct CommandPalette {
+    open: bool,
+    query: String,
+    /// Which of the matches is highlighted.
+    selected: usize,
+}
+
+impl CommandPalette {
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Opens the palette with an empty query, or closes it.
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.query.clear();
+        self.selected = 0;
+    }
+
+    /// Shows the palette if it's open, returning the index in `registry` of the action that
+    /// was picked, if any, which closes it. `enabled` says which of the actions can run.
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        registry: &ActionRegistry,
+        enabled: &[bool],
+    ) -> Option<usize> {
+        if !self.open {
+            return None;
+        }
+        let actions = registry.actions();
+        let matches = rank(&self.query, actions.iter().map(Action::name));
+        let (up, down, enter, escape) = ctx.input_mut(|i| {
+            (
+                i.consume_key(Modifiers::NONE, Key::ArrowUp),
+                i.consume_key(Modifiers::NONE, Key::ArrowDown),
+                i.consume_key(Modifiers::NONE, Key::Enter),
+                i.consume_key(Modifiers::NONE, Key::Escape)
+                    || i.consume_shortcut(&PALETTE_SHORTCUT),
+            )
+        });
+        if up {
+            self.selected = self.selected.saturating_sub(1);
+        }
+        if down {
+            self.selected += 1;
+        }
+        self.selected = self.selected.min(matches.len().saturating_sub(1));
+
+        let mut picked = None;
+        if enter {
+            picked = matches.get(self.selected).copied();
+        }
+        let mut query_changed = false;
+        let window = egui::Window::new("Command Palette")
+            .title_bar(false)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_TOP, [0.0, 60.0])
+            .show(ctx, |ui| {
+                ui.set_width(360.0);
+                let field = ui.add(
+                    egui::TextEdit::singleline(&mut self.query)
+                        .hint_text("Type an action")
+                        .desired_width(f32::INFINITY),
+                );
+                field.request_focus();
+                query_changed = field.changed();
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .max_height(320.0)
+                    .show(ui, |ui| {
+                        if matches.is_empty() {
+                            ui.weak("No matching actions");
+                        }
+                        for (position, &index) in matches.iter().enumerate() {
+                            let action = &actions[index];
+                            ui.add_enabled_ui(enabled[index], |ui| {
+                                ui.horizontal(|ui| {
+                                    let highlighted = position == self.selected;
+                                    let label = ui.selectable_label(highlighted, action.name());
+                                    if highlighted && (up || down) {
+                                        label.scroll_to_me(None);
+                                    }
+                                    if label.clicked() {
+                                        picked = Some(index);
+                                    }
+                                    if let Some(shortcut) = action.shortcut() {
+                                        ui.with_layout(
+                                            egui::Layout::right_to_left(egui::Align::Center),
+                                            |ui| ui.weak(ctx.format_shortcut(&shortcut)),
+                                        );
+                                    }
+                                });
+                            });
+                        }
+                    });
+            });
+        if query_changed {
+            self.selected = 0;
+        }
+
+        let clicked_elsewhere = window.is_some_and(|window| window.response.clicked_elsewhere());
+        let picked = picked.filter(|&index| enabled[index]);
+        if picked.is_some() || escape || clicked_elsewhere {
+            self.toggle();
+        }
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_beat_word_starts_beat_the_middle_of_words() {
+        // "lay" starts the first, starts a later word in the second, and is in the middle
+        // of the third
+        let names = ["Display Grid", "Clear Layer", "Layer Properties"];
+        let scores = names.map(|name| fuzzy_score("lay", name).unwrap());
+        assert!(scores[2] > scores[1] && scores[1] > scores[0], "{scores:?}");
+        assert_eq!(rank("lay", names), [2, 1, 0]);
+        assert_eq!(rank("ml", ["Smaller", "Merge Layers"]), [1, 0]);
+    }
+
+    #[test]
+    fn equal_matches_keep_their_order() {
+        assert_eq!(rank("grid", ["Show Grid", "Hide Grid"]), [0, 1]);
+        assert_eq!(rank("grid", ["Hide Grid", "Show Grid"]), [0, 1]);
+        assert_eq!(rank("", ["Undo", "Redo", "Copy"]), [0, 1, 2]);
+        assert_eq!(rank("  ", ["Undo", "Redo"]), [0, 1]);
+    }
+
+    #[test]
+    fn case_and_spaces_in_the_query_are_ignored() {
+        let score = fuzzy_score("merge layers", "Merge Layers");
+        assert!(score.is_some());
+        assert_eq!(fuzzy_score("MERGE LAYERS", "Merge Layers"), score);
+        assert_eq!(fuzzy_score("mergelayers", "Merge Layers"), score);
+        assert_eq!(fuzzy_score("merge layers", "MERGE LAYERS"), score);
+        assert!(fuzzy_score("ÉGAL", "égaliser").is_some());
+    }
+
+    #[test]
+    fn a_query_out_of_order_or_not_in_the_name_matches_nothing() {
+        assert_eq!(fuzzy_score("yal", "Layer"), None);
+        assert_eq!(fuzzy_score("layers", "Layer"), None);
+        assert_eq!(fuzzy_score("q", "Merge Layers"), None);
+        assert_eq!(rank("zz", ["Undo", "Redo"]), Vec::<usize>::new());
+        assert_eq!(rank("redo", ["Undo", "Redo"]), [1]);
+    }
+}
@@ -0,0 +1,625 @@
+use crate::canvas::Canvas;
+use crate::user::{LayerIdx, User};
+use eframe::egui::{self, Color32, Pos2, Rect, Rgba, Sense, Stroke, Vec2};
+use rustbrush_utils::filter_registry::{
+    apply_filter_in_task, BackgroundFilter, Filter, FilterEntry,
+};
+use rustbrush_utils::filters::{
+    Adjustment, ColorBalance, Histogram, Levels, LevelsChannel, ReplaceColor, ToneRange,
+};
+use rustbrush_utils::pixel_buffer::{DirtyRect, PixelBuffer, PixelSlice};
+use rustbrush_utils::task::{BackgroundTask, TaskStatus};
+use tracing::error;
+
+const CHANNEL_LABELS: [(&str, &str); 3] =
+    [("Cyan", "Red"), ("Magenta", "Green"), ("Yellow", "Blue")];
+
+/// An open adjustment dialog. Only one can be open at a time.
+pub enum AdjustmentDialog {
+    ColorBalance(ColorBalanceDialog),
+    Levels(Box<LevelsDialog>),
+    ReplaceColor(ReplaceColorDialog),
+    Filter(FilterDialog),
+}
+
+impl AdjustmentDialog {
+    /// Opens the dialog for a filter from the registry on `layer`. The built-in adjustments
+    /// get their dedicated dialogs, every other filter the generic one.
+    pub fn open(canvas: &mut Canvas, user: &User, entry: &FilterEntry) -> Option<Self> {
+        let layer = user.current_layer;
+        match entry.name() {
+            ColorBalance::NAME => {
+                ColorBalanceDialog::open(canvas, layer).map(AdjustmentDialog::ColorBalance)
+            }
+            Levels::NAME => LevelsDialog::open(canvas, layer)
+                .map(|dialog| AdjustmentDialog::Levels(Box::new(dialog))),
+            ReplaceColor::NAME => {
+                let color = user.current_color;
+                let from = Color32::from(Rgba::from_rgb(color.r(), color.g(), color.b()));
+                ReplaceColorDialog::open(canvas, layer, from).map(AdjustmentDialog::ReplaceColor)
+            }
+            _ => FilterDialog::open(canvas, layer, entry.create()).map(AdjustmentDialog::Filter),
+        }
+    }
+
+    /// Shows the dialog, returning false once it has been applied or cancelled.
+    pub fn show(&mut self, ctx: &egui::Context, canvas: &mut Canvas, user: &mut User) -> bool {
+        match self {
+            AdjustmentDialog::ColorBalance(dialog) => dialog.show(ctx, canvas, user),
+            AdjustmentDialog::Levels(dialog) => dialog.show(ctx, canvas, user),
+            AdjustmentDialog::ReplaceColor(dialog) => dialog.show(ctx, canvas, user),
+            AdjustmentDialog::Filter(dialog) => dialog.show(ctx, canvas, user),
+        }
+    }
+}
+
+/// The live preview of an adjustment on one or more layers, on top of the canvas's preview
+/// session, along with whether it's shown.
+struct AdjustmentPreview {
+    enabled: bool,
+}
+
+impl AdjustmentPreview {
+    /// Starts previewing on `layers`, targeting all of them.
+    fn open(canvas: &mut Canvas, layers: Vec<LayerIdx>) -> Option<Self> {
+        canvas.begin_preview(layers).ok()?;
+        Some(Self { enabled: true })
+    }
+
+    fn update(&self, canvas: &mut Canvas, filter: &dyn Filter) {
+        let enabled = self.enabled;
+        canvas.update_preview(|canvas, layer| match enabled {
+            true => canvas.apply_filter(layer, filter),
+            false => DirtyRect::default(),
+        });
+    }
+
+    fn apply(&self, canvas: &mut Canvas, user: &mut User, adjustment: Adjustment) {
+        canvas.update_preview(|canvas, layer| canvas.apply_filter(layer, &adjustment));
+        let layers = canvas
+            .commit_preview()
+            .into_iter()
+            .map(|(layer, _)| layer)
+            .collect();
+        user.record_adjustment(layers, adjustment);
+    }
+
+    /// Like [`AdjustmentPreview::apply`] for filters that can't be replayed, which are
+    /// recorded as snapshots of what they changed.
+    fn apply_filter(&self, canvas: &mut Canvas, user: &mut User, filter: &dyn Filter) {
+        canvas.update_preview(|canvas, layer| canvas.apply_filter(layer, filter));
+        self.commit_filter(canvas, user);
+    }
+
+    /// Ends the preview with the layers as they are, recording them like
+    /// [`AdjustmentPreview::apply_filter`], for a filter whose result is already showing.
+    fn commit_filter(&self, canvas: &mut Canvas, user: &mut User) {
+        for (layer, rect) in canvas.commit_preview() {
+            if let Some(pixels) = canvas
+                .snapshot_rect(layer, rect)
+                .filter(|_| !rect.is_empty())
+            {
+                user.record_filter(layer, rect, pixels);
+            }
+        }
+    }
+
+    fn cancel(&self, canvas: &mut Canvas) {
+        canvas.cancel_preview();
+    }
+
+    /// Shows the preview checkbox and the OK/Cancel buttons, returning whether the preview
+    /// was toggled, OK was clicked, and Cancel was clicked.
+    fn buttons(&mut self, ui: &mut egui::Ui) -> (bool, bool, bool) {
+        let toggled = ui.checkbox(&mut self.enabled, "Preview").changed();
+        let mut apply = false;
+        let mut cancel = false;
+        ui.horizontal(|ui| {
+            apply = ui.button("OK").clicked();
+            cancel = ui.button("Cancel").clicked();
+        });
+        (toggled, apply, cancel)
+    }
+
+    /// Applies the outcome of a frame of a dialog, returning false once it's closed.
+    fn finish(
+        &self,
+        canvas: &mut Canvas,
+        user: &mut User,
+        adjustment: Adjustment,
+        (changed, apply, cancel): (bool, bool, bool),
+    ) -> bool {
+        if apply {
+            self.apply(canvas, user, adjustment);
+            return false;
+        }
+        if cancel {
+            self.cancel(canvas);
+            return false;
+        }
+        if changed {
+            self.update(canvas, &adjustment);
+        }
+        true
+    }
+}
+
+/// The color balance dialog.
+pub struct ColorBalanceDialog {
+    preview: AdjustmentPreview,
+    color_balance: ColorBalance,
+    range: ToneRange,
+}
+
+impl ColorBalanceDialog {
+    pub fn open(canvas: &mut Canvas, layer: LayerIdx) -> Option<Self> {
+        Some(Self {
+            preview: AdjustmentPreview::open(canvas, vec![layer])?,
+            color_balance: ColorBalance::default(),
+            range: ToneRange::Midtones,
+        })
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, canvas: &mut Canvas, user: &mut User) -> bool {
+        let mut changed = false;
+        let mut buttons = (false, false, false);
+
+        egui::Window::new("Color Balance")
+            .resizable(false)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    for range in ToneRange::ALL {
+                        ui.radio_value(&mut self.range, range, range.label());
+                    }
+                });
+                let offsets = self.color_balance.offsets_mut(self.range);
+                for (offset, (low, high)) in offsets.iter_mut().zip(CHANNEL_LABELS) {
+                    ui.horizontal(|ui| {
+                        ui.label(low);
+                        changed |= ui
+                            .add(egui::Slider::new(offset, -0.5..=0.5).show_value(false))
+                            .changed();
+                        ui.label(high);
+                    });
+                }
+                changed |= ui
+                    .checkbox(
+                        &mut self.color_balance.preserve_luminosity,
+                        "Preserve Luminosity",
+                    )
+                    .changed();
+                buttons = self.preview.buttons(ui);
+            });
+
+        let adjustment = Adjustment::ColorBalance(self.color_balance.clone());
+        buttons.0 |= changed;
+        self.preview.finish(canvas, user, adjustment, buttons)
+    }
+}
+
+const LEVELS_CHANNELS: [(Option<usize>, &str); 4] = [
+    (None, "RGB"),
+    (Some(0), "Red"),
+    (Some(1), "Green"),
+    (Some(2), "Blue"),
+];
+
+const HISTOGRAM_SIZE: Vec2 = Vec2::new(256.0, 100.0);
+const MARKER_SIZE: f32 = 6.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum LevelsMarker {
+    Black,
+    Gamma,
+    White,
+}
+
+/// The levels dialog. The histogram is of the layer as it was when the dialog opened.
+pub struct LevelsDialog {
+    preview: AdjustmentPreview,
+    histogram: Histogram,
+    levels: Levels,
+    channel: Option<usize>,
+    dragging: Option<LevelsMarker>,
+}
+
+impl LevelsDialog {
+    pub fn open(canvas: &mut Canvas, layer: LayerIdx) -> Option<Self> {
+        let preview = AdjustmentPreview::open(canvas, vec![layer])?;
+        let original = canvas.preview_original(layer)?;
+        Some(Self {
+            histogram: Histogram::from_pixels(&original.pixels.colors()),
+            preview,
+            levels: Levels::default(),
+            channel: None,
+            dragging: None,
+        })
+    }
+
+    fn channel_mut(&mut self) -> &mut LevelsChannel {
+        match self.channel {
+            Some(channel) => &mut self.levels.channels[channel],
+            None => &mut self.levels.rgb,
+        }
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, canvas: &mut Canvas, user: &mut User) -> bool {
+        let mut changed = false;
+        let mut buttons = (false, false, false);
+
+        egui::Window::new("Levels")
+            .resizable(false)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    for (channel, label) in LEVELS_CHANNELS {
+                        ui.radio_value(&mut self.channel, channel, label);
+                    }
+                });
+                changed |= self.histogram_ui(ui);
+
+                let levels = self.channel_mut();
+                changed |= ui
+                    .add(egui::Slider::new(&mut levels.input_black, 0..=254).text("Input Black"))
+                    .changed();
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut levels.gamma, 0.1..=9.99)
+                            .logarithmic(true)
+                            .text("Gamma"),
+                    )
+                    .changed();
+                changed |= ui
+                    .add(egui::Slider::new(&mut levels.input_white, 1..=255).text("Input White"))
+                    .changed();
+                changed |= ui
+                    .add(egui::Slider::new(&mut levels.output_black, 0..=255).text("Output Black"))
+                    .changed();
+                changed |= ui
+                    .add(egui::Slider::new(&mut levels.output_white, 0..=255).text("Output White"))
+                    .changed();
+                if levels.input_white <= levels.input_black {
+                    levels.input_white = levels.input_black + 1;
+                }
+
+                ui.horizontal(|ui| {
+                    if ui.button("Auto").clicked() {
+                        let channel = self.channel;
+                        let histogram = self.histogram.clone();
+                        self.channel_mut().auto(&histogram, channel);
+                        changed = true;
+                    }
+                    if ui.button("Reset").clicked() {
+                        *self.channel_mut() = LevelsChannel::default();
+                        changed = true;
+                    }
+                });
+                buttons = self.preview.buttons(ui);
+            });
+
+        let adjustment = Adjustment::Levels(self.levels.clone());
+        buttons.0 |= changed;
+        self.preview.finish(canvas, user, adjustment, buttons)
+    }
+
+    /// Draws the histogram with the input markers underneath, which can be dragged.
+    /// Returns true if a marker moved.
+    fn histogram_ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let (response, painter) = ui.allocate_painter(
+            HISTOGRAM_SIZE + Vec2::new(0.0, MARKER_SIZE * 2.0),
+            Sense::drag(),
+        );
+        let rect = Rect::from_min_size(response.rect.min, HISTOGRAM_SIZE);
+        painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+
+        let counts = self.histogram.counts(self.channel);
+        let max = counts.iter().copied().max().unwrap_or(0).max(1) as f32;
+        let bar_color = ui.visuals().text_color();
+        for (value, &count) in counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let x = rect.left() + value as f32 + 0.5;
+            let height = count as f32 / max * rect.height();
+            painter.line_segment(
+                [
+                    Pos2::new(x, rect.bottom()),
+                    Pos2::new(x, rect.bottom() - height),
+                ],
+                Stroke::new(1.0, bar_color),
+            );
+        }
+
+        let x_to_value = |x: f32| ((x - rect.left()) / rect.width() * 255.0).clamp(0.0, 255.0);
+        let value_to_x = |value: f32| rect.left() + value / 255.0 * rect.width();
+
+        let levels = *self.channel_mut();
+        let black = levels.input_black as f32;
+        let white = levels.input_white as f32;
+        // the gamma marker sits at the input value that maps to mid-grey
+        let gamma = black + (white - black) * 0.5f32.powf(levels.gamma);
+        let markers = [
+            (LevelsMarker::Black, black, Color32::BLACK),
+            (LevelsMarker::Gamma, gamma, Color32::GRAY),
+            (LevelsMarker::White, white, Color32::WHITE),
+        ];
+        for (_, value, color) in markers {
+            let tip = Pos2::new(value_to_x(value), rect.bottom());
+            painter.add(egui::Shape::convex_polygon(
+                vec![
+                    tip,
+                    tip + Vec2::new(MARKER_SIZE, MARKER_SIZE * 2.0),
+                    tip + Vec2::new(-MARKER_SIZE, MARKER_SIZE * 2.0),
+                ],
+                color,
+                Stroke::new(1.0, Color32::DARK_GRAY),
+            ));
+        }
+
+        let Some(pointer) = response.interact_pointer_pos() else {
+            self.dragging = None;
+            return false;
+        };
+        if response.drag_started() {
+            let value = x_to_value(pointer.x);
+            self.dragging = markers
+                .iter()
+                .min_by(|a, b| (a.1 - value).abs().total_cmp(&(b.1 - value).abs()))
+                .map(|(marker, _, _)| *marker);
+        }
+        let Some(marker) = self.dragging else {
+            return false;
+        };
+
+        let value = x_to_value(pointer.x);
+        let levels = self.channel_mut();
+        let before = *levels;
+        match marker {
+            LevelsMarker::Black => {
+                levels.input_black = value.min(white - 1.0).round() as u8;
+            }
+            LevelsMarker::White => {
+                levels.input_white = value.max(black + 1.0).round() as u8;
+            }
+            LevelsMarker::Gamma => {
+                let t = ((value - black) / (white - black)).clamp(0.01, 0.99);
+                levels.gamma = (t.ln() / 0.5f32.ln()).clamp(0.1, 9.99);
+            }
+        }
+        *levels != before
+    }
+}
+
+/// The replace color dialog. Every layer is snapshotted up front so "All Layers" can be
+/// toggled while previewing.
+pub struct ReplaceColorDialog {
+    preview: AdjustmentPreview,
+    layer: LayerIdx,
+    replace_color: ReplaceColor,
+    all_layers: bool,
+}
+
+impl ReplaceColorDialog {
+    pub fn open(canvas: &mut Canvas, layer: LayerIdx, from: Color32) -> Option<Self> {
+        let preview = AdjustmentPreview::open(canvas, (0..canvas.state.layers.len()).collect())?;
+        canvas.set_preview_targets(vec![layer]);
+        Some(Self {
+            preview,
+            layer,
+            replace_color: ReplaceColor {
+                from,
+                to: from,
+                tolerance: 0.0,
+            },
+            all_layers: false,
+        })
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, canvas: &mut Canvas, user: &mut User) -> bool {
+        let mut changed = false;
+        let mut buttons = (false, false, false);
+        let current_color = {
+            let color = user.current_color;
+            Color32::from(Rgba::from_rgb(color.r(), color.g(), color.b()))
+        };
+
+        egui::Window::new("Replace Color")
+            .resizable(false)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("replace_color").show(ui, |ui| {
+                    for (label, color) in [
+                        ("Replace", &mut self.replace_color.from),
+                        ("With", &mut self.replace_color.to),
+                    ] {
+                        ui.label(label);
+                        changed |= ui.color_edit_button_srgba(color).changed();
+                        if ui.button("Use Current Color").clicked() {
+                            *color = current_color;
+                            changed = true;
+                        }
+                        ui.end_row();
+                    }
+                });
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut self.replace_color.tolerance, 0.0..=0.5)
+                            .text("Tolerance"),
+                    )
+                    .changed();
+                if ui.checkbox(&mut self.all_layers, "All Layers").changed() {
+                    let targets = if self.all_layers {
+                        (0..canvas.state.layers.len()).collect()
+                    } else {
+                        vec![self.layer]
+                    };
+                    canvas.set_preview_targets(targets);
+                    changed = true;
+                }
+                buttons = self.preview.buttons(ui);
+            });
+
+        let adjustment = Adjustment::ReplaceColor(self.replace_color.clone());
+        buttons.0 |= changed;
+        self.preview.finish(canvas, user, adjustment, buttons)
+    }
+}
+
+/// A filter running on a background thread, with the part of the layer it changed and
+/// those pixels once it's done, and whether to apply it then.
+struct FilterTask {
+    task: BackgroundTask<(DirtyRect, Vec<Color32>)>,
+    apply: bool,
+}
+
+/// The dialog for filters from the registry other than the built-in adjustments, showing
+/// whatever settings the filter has. Filters that can run in the background, see
+/// [`Filter::background`], are previewed and applied there, with a progress bar in the
+/// dialog, so the UI doesn't freeze on a large layer.
+pub struct FilterDialog {
+    preview: AdjustmentPreview,
+    filter: Box<dyn Filter>,
+    layer: LayerIdx,
+    /// Whether the preview has been drawn yet. It has to be drawn once before anything
+    /// changes, since a filter without settings never reports a change.
+    previewed: bool,
+    task: Option<FilterTask>,
+    /// Whether the layer shows what the background task made with the current settings.
+    filtered: bool,
+}
+
+impl FilterDialog {
+    pub fn open(canvas: &mut Canvas, layer: LayerIdx, filter: Box<dyn Filter>) -> Option<Self> {
+        Some(Self {
+            preview: AdjustmentPreview::open(canvas, vec![layer])?,
+            filter,
+            layer,
+            previewed: false,
+            task: None,
+            filtered: false,
+        })
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, canvas: &mut Canvas, user: &mut User) -> bool {
+        let mut changed = false;
+        let mut buttons = (false, false, false);
+        let mut stop = false;
+
+        egui::Window::new(self.filter.name().to_string())
+            .resizable(false)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                changed |= self.filter.params_ui(ui);
+                if let Some(FilterTask { task, .. }) = &self.task {
+                    ui.horizontal(|ui| {
+                        let progress = task.progress();
+                        ui.add(
+                            egui::ProgressBar::new(progress.fraction)
+                                .desired_width(160.0)
+                                .text(&progress.message),
+                        );
+                        stop = ui.button("Stop").clicked();
+                    });
+                }
+                buttons = self.preview.buttons(ui);
+            });
+
+        let (toggled, apply, cancel) = buttons;
+        if cancel {
+            self.task = None;
+            self.preview.cancel(canvas);
+            return false;
+        }
+        if stop {
+            self.task = None;
+        }
+        if toggled || changed || !self.previewed {
+            self.update(canvas);
+            self.previewed = true;
+        }
+        if apply {
+            let Some(filter) = self.filter.background() else {
+                self.preview.apply_filter(canvas, user, &*self.filter);
+                return false;
+            };
+            if self.task.is_none() && !self.filtered {
+                self.start(canvas, filter, true);
+            }
+            match &mut self.task {
+                Some(task) => task.apply = true,
+                // showing already, or filtered here because it couldn't be started
+                None if self.filtered => {
+                    self.preview.commit_filter(canvas, user);
+                    return false;
+                }
+                None => {}
+            }
+        }
+        self.poll(ctx, canvas, user)
+    }
+
+    /// Shows the filter with the current settings, if the preview is on, starting it in the
+    /// background for filters that run there. A task still running for older settings is
+    /// cancelled.
+    fn update(&mut self, canvas: &mut Canvas) {
+        self.task = None;
+        self.filtered = false;
+        match self.filter.background() {
+            Some(filter) if self.preview.enabled => self.start(canvas, filter, false),
+            _ => self.preview.update(canvas, &*self.filter),
+        }
+    }
+
+    /// Starts `filter` on a copy of the layer as it was, which is shown until it's done.
+    fn start(&mut self, canvas: &mut Canvas, filter: Box<dyn BackgroundFilter>, apply: bool) {
+        canvas.update_preview(|_, _| DirtyRect::default());
+        let (width, height) = (canvas.state.width, canvas.state.height);
+        let Some(mut pixels) = canvas.filter_source(self.layer) else {
+            return;
+        };
+        let selection = canvas.selection().cloned();
+        let work = move |task: &_| {
+            let mut buffer = PixelSlice::new(&mut pixels, width, height);
+            let dirty =
+                apply_filter_in_task(&*filter, &mut buffer, None, selection.as_ref(), task)?;
+            Some((dirty, buffer.copy_rect(dirty)))
+        };
+        match BackgroundTask::spawn("filter", work) {
+            Ok(task) => self.task = Some(FilterTask { task, apply }),
+            Err(e) => {
+                // filtering here is slow, but better than not at all
+                error!("Couldn't start filtering in the background: {}", e);
+                self.preview.update(canvas, &*self.filter);
+                self.filtered = true;
+            }
+        }
+    }
+
+    /// Shows what the background task made once it's done, and applies it if OK was
+    /// clicked, returning false once the dialog is closed.
+    fn poll(&mut self, ctx: &egui::Context, canvas: &mut Canvas, user: &mut User) -> bool {
+        let Some(FilterTask { task, apply }) = &mut self.task else {
+            return true;
+        };
+        let apply = *apply;
+        match task.poll() {
+            TaskStatus::Running => {
+                ctx.request_repaint();
+                return true;
+            }
+            TaskStatus::Finished((dirty, pixels)) => {
+                canvas.update_preview(|canvas, layer| canvas.write_filtered(layer, dirty, &pixels));
+                self.filtered = true;
+            }
+            TaskStatus::Cancelled => {}
+            TaskStatus::Failed(message) => error!("Filtering failed: {}", message),
+        }
+        self.task = None;
+        if apply && self.filtered {
+            self.preview.commit_filter(canvas, user);
+            return false;
+        }
+        true
+    }
+}
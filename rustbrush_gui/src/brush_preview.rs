@@ -0,0 +1,99 @@
+use eframe::egui::{self, Color32, Rgba};
+use rustbrush_utils::{
+    jitter::StrokeRng,
+    operations::PaintOperation,
+    path,
+    pixel_buffer::PixelSlice,
+    stamp_cache::StampCache,
+    stroke::{StrokeBuffer, StrokeSegment, StrokeState},
+    symmetry::Symmetry,
+    Brush,
+};
+
+pub const PREVIEW_WIDTH: usize = 96;
+pub const PREVIEW_HEIGHT: usize = 32;
+const PREVIEW_SEGMENTS: usize = 48;
+
+/// Renders a figure-eight scribble with `brush` so overlapping dabs are visible, which is
+/// what tells build-up and wash strokes apart.
+pub fn render_scribble(brush: &Brush, color: Rgba) -> egui::ColorImage {
+    let width = PREVIEW_WIDTH as f32;
+    let height = PREVIEW_HEIGHT as f32;
+    let brush = brush.clone().with_radius(brush.radius().min(height / 6.0));
+    let margin = brush.radius() + 2.0;
+
+    render_stroke(&brush, color, [PREVIEW_WIDTH, PREVIEW_HEIGHT], |t| {
+        let t = t * std::f32::consts::TAU;
+        (
+            width * 0.5 + (width * 0.5 - margin) * t.sin(),
+            height * 0.5 + (height * 0.5 - margin) * (2.0 * t).sin(),
+        )
+    })
+}
+
+/// Renders an S-curve with `brush` at its real size, for previewing it over the canvas.
+pub fn render_s_curve(brush: &Brush, color: Rgba) -> egui::ColorImage {
+    let margin = brush.radius() + 2.0;
+    let width = (brush.radius() * 10.0).max(100.0) + margin * 2.0;
+    let height = (brush.radius() * 4.0).max(40.0) + margin * 2.0;
+
+    render_stroke(brush, color, [width as usize, height as usize], |t| {
+        (
+            margin + (width - margin * 2.0) * t,
+            height * 0.5 - (height * 0.5 - margin) * (t * std::f32::consts::TAU).sin(),
+        )
+    })
+}
+
+/// Paints a stroke along `path` (sampled from 0 to 1) into a transparent image of `size`,
+/// going through the same operation as strokes on the canvas.
+fn render_stroke(
+    brush: &Brush,
+    color: Rgba,
+    size: [usize; 2],
+    path: impl Fn(f32) -> (f32, f32),
+) -> egui::ColorImage {
+    let point = |i: usize| path(i as f32 / PREVIEW_SEGMENTS as f32);
+
+    let mut pixels = vec![Color32::TRANSPARENT; size[0] * size[1]];
+    let accumulation = brush.accumulation();
+    let mut stroke_buffer = (accumulation.needs_buffer(brush.opacity()) || brush.float_buffer())
+        .then(StrokeBuffer::default);
+
+    let mut stamp_cache = StampCache::default();
+    // a fixed seed, so a jittered brush's preview doesn't change every time it's drawn
+    let mut rng = StrokeRng::new(0);
+    let mut stroke_state = StrokeState::default();
+    // the whole stroke is known up front, so it tapers at both ends
+    let points: Vec<(f32, f32)> = (0..=PREVIEW_SEGMENTS).map(point).collect();
+    let length = path::length(&points);
+    for i in 1..=PREVIEW_SEGMENTS {
+        PaintOperation {
+            pixels: &mut PixelSlice::new(&mut pixels, size[0] as u32, size[1] as u32),
+            brush,
+            color: color.into(),
+            segment: StrokeSegment {
+                from: point(i - 1),
+                to: point(i),
+            },
+            previous_cursor_position: None,
+            is_eraser: false,
+            accumulation,
+            stroke_buffer: stroke_buffer.as_mut(),
+            elapsed: 0.0,
+            stamp_cache: &mut stamp_cache,
+            rng: &mut rng,
+            stroke_state: &mut stroke_state,
+            taper_in: brush.taper_in(),
+            taper_out: brush.taper_out(),
+            stroke_length: Some(length),
+            mask: None,
+            alpha_lock: false,
+            symmetry: Symmetry::None,
+            stats: None,
+        }
+        .process();
+    }
+
+    egui::ColorImage { size, pixels }
+}
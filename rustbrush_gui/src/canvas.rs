@@ -1,162 +1,244 @@
-use crate::user::{BrushStrokeFrame, BrushStrokeKind};
-use eframe::egui::{self, Color32};
-use image::{ImageBuffer, Rgba};
-use rustbrush_utils::operations::{PaintOperation, SmudgeOperation};
-
-#[derive(Clone)]
-pub struct CanvasLayer {
-    pixels: Vec<Color32>,
-    pub texture: Option<egui::TextureHandle>,
-    pub visible: bool,
-    pub name: String,
-    dirty: bool,
+use crate::symmetry::Symmetry;
+use eframe::egui;
+use rustbrush_utils::canvas::{
+    self, CanvasError, CanvasLayer, CanvasState, LayerBounds, LayerKind, LayerLook,
+};
+use rustbrush_utils::symmetry::Symmetry as DabSymmetry;
+use std::ops::{Deref, DerefMut};
+
+/// The document, see [`canvas::Canvas`], along with the textures it's drawn with. Painting,
+/// compositing and saving all happen in the shared canvas; this only keeps what egui needs
+/// to show it.
+pub struct Canvas {
+    canvas: canvas::Canvas,
+    /// The visible layers composited into one texture, along with how each layer looked when
+    /// it was, see [`canvas::Canvas::layer_looks`].
+    composite: Option<(Vec<LayerLook>, egui::TextureHandle)>,
+    /// The textures of the stencil layers, which are drawn over the composite on their own,
+    /// by layer index.
+    layer_textures: Vec<Option<egui::TextureHandle>>,
 }
 
-impl CanvasLayer {
-    pub fn new(width: u32, height: u32, name: String) -> Self {
-        Self {
-            pixels: vec![Color32::TRANSPARENT; width as usize * height as usize],
-            texture: None,
-            visible: true,
-            name,
-            dirty: true,
-        }
-    }
-
-    pub fn mark_dirty(&mut self) {
-        self.dirty = true;
-    }
-
-    pub fn mark_clean(&mut self) {
-        self.dirty = false;
+impl Canvas {
+    /// Fails if the canvas, or any of its layers, is outside the default limits.
+    pub fn new(state: CanvasState) -> Result<Self, CanvasError> {
+        Ok(Self {
+            canvas: canvas::Canvas::new(state)?,
+            composite: None,
+            layer_textures: Vec::new(),
+        })
     }
 
-    pub fn is_dirty(&self) -> bool {
-        self.dirty
+    /// [`canvas::Canvas::begin_brush_stroke`] with the symmetry the stroke was painted with,
+    /// if it was on.
+    pub fn begin_brush_stroke(
+        &mut self,
+        symmetry: Option<Symmetry>,
+        stencil: Option<usize>,
+        seed: u64,
+        length: Option<f32>,
+    ) {
+        let symmetry = symmetry.map_or(DabSymmetry::None, |symmetry| symmetry.dabs());
+        self.canvas
+            .begin_brush_stroke(symmetry, stencil, seed, length);
     }
 
-    pub fn pixels(&self) -> &Vec<Color32> {
-        &self.pixels
+    /// [`canvas::Canvas::insert_layer`], moving the textures of the layers above along.
+    pub fn insert_layer(&mut self, index: usize, layer: CanvasLayer) -> Result<(), CanvasError> {
+        self.canvas.insert_layer(index, layer)?;
+        if index <= self.layer_textures.len() {
+            self.layer_textures.insert(index, None);
+        }
+        Ok(())
     }
-}
 
-pub struct CanvasState {
-    pub layers: Vec<CanvasLayer>,
-    pub width: u32,
-    pub height: u32,
-}
-
-pub struct Canvas {
-    pub state: CanvasState,
-}
-
-impl Canvas {
-    pub fn process_brush_stroke_frame(
-        &mut self,
-        layer: usize,
-        kind: BrushStrokeKind,
-        frame: &BrushStrokeFrame,
-    ) {
-        match kind {
-            BrushStrokeKind::Paint => self.paint(layer, &frame),
-            BrushStrokeKind::Erase => self.erase(layer, &frame),
-            BrushStrokeKind::Smudge => self.smudge(layer, &frame),
+    /// [`canvas::Canvas::remove_layer`], dropping the layer's texture and moving the textures
+    /// of the layers above along.
+    pub fn remove_layer(&mut self, index: usize) -> Option<CanvasLayer> {
+        let layer = self.canvas.remove_layer(index)?;
+        if index < self.layer_textures.len() {
+            self.layer_textures.remove(index);
         }
+        Some(layer)
     }
 
-    pub fn clear(&mut self) {
-        for layer in self.state.layers.iter_mut() {
-            layer.pixels.fill(Color32::TRANSPARENT);
-            layer.mark_dirty();
+    /// Uploads what changed since the last upload: the part of the composite the layers
+    /// changed, or all of it once a layer is added, moved, hidden or restyled, and the stencil
+    /// layers, which are drawn over it on their own. Textures are kept, so new pixels show up
+    /// even where they were already drawn this frame.
+    pub fn upload_textures(&mut self, ctx: &egui::Context, options: egui::TextureOptions) {
+        let (width, height) = (self.state.width, self.state.height);
+        let size = [width as usize, height as usize];
+        let looks = self.layer_looks();
+        let rect = match &self.composite {
+            Some((shown, texture)) if *shown == looks && texture.size() == size => {
+                self.composite_dirty_rect()
+            }
+            _ => LayerBounds::canvas(width, height),
+        };
+        if !rect.is_empty() {
+            let image = egui::ColorImage {
+                size: [rect.width as usize, rect.height as usize],
+                pixels: self.composite_rect(rect),
+            };
+            match &mut self.composite {
+                Some((shown, texture)) if texture.size() == size => {
+                    let at = [rect.x as usize, rect.y as usize];
+                    texture.set_partial(at, image, options);
+                    *shown = looks;
+                }
+                _ => {
+                    let texture = ctx.load_texture("canvas_composite", image, options);
+                    self.composite = Some((looks, texture));
+                }
+            }
         }
-    }
 
-    pub fn clear_layer(&mut self, layer: usize) {
-        if let Some(layer) = self.layers().get_mut(layer) {
-            layer.pixels.fill(Color32::TRANSPARENT);
-            layer.mark_dirty();
+        let layers = self.canvas.layers();
+        self.layer_textures.resize(layers.len(), None);
+        for (layer, texture) in layers.iter_mut().zip(&mut self.layer_textures) {
+            let bounds = layer.bounds();
+            if layer.kind() == LayerKind::Color {
+                // shown in the composite
+                *texture = None;
+                layer.mark_clean();
+                continue;
+            }
+            if !(layer.is_dirty() || texture.is_none()) || bounds.is_empty() {
+                continue;
+            }
+            let size = [bounds.width as usize, bounds.height as usize];
+            let dirty = layer.dirty_rect();
+            match texture {
+                Some(texture) if texture.size() == size => {
+                    let image = egui::ColorImage {
+                        size: [dirty.width as usize, dirty.height as usize],
+                        pixels: layer.rect_pixels(dirty),
+                    };
+                    texture.set_partial([dirty.x as usize, dirty.y as usize], image, options);
+                }
+                _ => {
+                    let image = egui::ColorImage {
+                        size,
+                        pixels: layer.pixels().into_owned(),
+                    };
+                    match texture {
+                        Some(texture) => texture.set(image, options),
+                        None => {
+                            *texture = Some(ctx.load_texture("layer_texture", image, options));
+                        }
+                    }
+                }
+            }
+            layer.mark_clean();
         }
     }
 
-    pub fn add_layer(&mut self) {
-        let width = self.state.width;
-        let height = self.state.height;
-        let layer_num = self.layers().len() + 1;
-        self.layers().push(CanvasLayer::new(
-            width,
-            height,
-            format!("Layer {}", layer_num),
-        ));
+    /// The visible layers composited, as of the last upload.
+    pub fn composite_texture(&self) -> Option<&egui::TextureHandle> {
+        self.composite.as_ref().map(|(_, texture)| texture)
     }
 
-    pub fn layers(&mut self) -> &mut Vec<CanvasLayer> {
-        &mut self.state.layers
+    /// The texture of `layer` if it's a stencil layer, as of the last upload.
+    pub fn layer_texture(&self, layer: usize) -> Option<&egui::TextureHandle> {
+        self.layer_textures.get(layer)?.as_ref()
     }
 
-    pub fn save_as_png(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let width = self.state.width;
-        let height = self.state.height;
+    /// Drops every texture, so the next upload makes them again, such as with new filtering.
+    pub fn forget_textures(&mut self) {
+        self.composite = None;
+        self.layer_textures.clear();
+    }
+}
 
-        let mut merged = vec![0u8; (width * height * 4) as usize];
+impl Deref for Canvas {
+    type Target = canvas::Canvas;
 
-        for layer in self.state.layers.iter() {
-            for (i, pixel) in layer.pixels.iter().enumerate() {
-                let rgba = Rgba([pixel.r(), pixel.g(), pixel.b(), pixel.a()]);
-                merged[i * 4] = rgba[0];
-                merged[i * 4 + 1] = rgba[1];
-                merged[i * 4 + 2] = rgba[2];
-                merged[i * 4 + 3] = rgba[3];
-            }
-        }
+    fn deref(&self) -> &Self::Target {
+        &self.canvas
+    }
+}
 
-        let image_buffer: ImageBuffer<Rgba<u8>, Vec<u8>> =
-            ImageBuffer::from_raw(width, height, merged).ok_or("Failed to create image buffer")?;
+impl DerefMut for Canvas {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.canvas
+    }
+}
 
-        image_buffer.save(path)?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symmetry::SymmetryMode;
+    use crate::test_support::canvas;
+    use eframe::egui::{Pos2, Rgba};
+    use rustbrush_utils::canvas::BrushStrokeKind;
+    use rustbrush_utils::testing::{frame, hard_brush};
+
+    fn uploaded(canvas: &mut Canvas, ctx: &egui::Context) {
+        canvas.upload_textures(ctx, egui::TextureOptions::NEAREST);
     }
 
-    fn paint(&mut self, layer: usize, frame: &BrushStrokeFrame) {
-        self.layers()[layer].mark_dirty();
-        PaintOperation {
-            brush: &frame.brush,
-            color: frame.color,
-            cursor_position: (frame.cursor_position.x, frame.cursor_position.y),
-            last_cursor_position: (frame.last_cursor_position.x, frame.last_cursor_position.y),
-            is_eraser: false,
-            pixel_buffer: &mut self.state.layers[layer].pixels,
-            canvas_width: self.state.width,
-            canvas_height: self.state.height,
-        }
-        .process();
+    #[test]
+    fn stencil_textures_follow_their_layers() {
+        let ctx = egui::Context::default();
+        let mut canvas = canvas(8, 8, 1);
+        let stencil = canvas.add_stencil_layer().unwrap();
+        uploaded(&mut canvas, &ctx);
+        assert!(canvas.composite_texture().is_some());
+        // color layers are only shown in the composite
+        assert!(canvas.layer_texture(0).is_none());
+        let id = canvas.layer_texture(stencil).unwrap().id();
+
+        let layer = CanvasLayer::new(8, 8, "Under".into()).unwrap();
+        canvas.insert_layer(0, layer).unwrap();
+        assert!(canvas.layer_texture(stencil).is_none());
+        assert_eq!(canvas.layer_texture(stencil + 1).unwrap().id(), id);
+        uploaded(&mut canvas, &ctx);
+        assert_eq!(canvas.layer_texture(stencil + 1).unwrap().id(), id);
+
+        canvas.remove_layer(0).unwrap();
+        assert_eq!(canvas.layer_texture(stencil).unwrap().id(), id);
+        canvas.remove_layer(stencil).unwrap();
+        assert!(canvas.layer_texture(stencil).is_none());
     }
 
-    fn erase(&mut self, layer: usize, frame: &BrushStrokeFrame) {
-        self.layers()[layer].mark_dirty();
-        PaintOperation {
-            brush: &frame.brush,
-            color: egui::Rgba::WHITE,
-            cursor_position: (frame.cursor_position.x, frame.cursor_position.y),
-            last_cursor_position: (frame.last_cursor_position.x, frame.last_cursor_position.y),
-            is_eraser: true,
-            pixel_buffer: &mut self.state.layers[layer].pixels,
-            canvas_width: self.state.width,
-            canvas_height: self.state.height,
-        }
-        .process();
+    #[test]
+    fn uploading_keeps_the_textures_until_they_are_forgotten() {
+        let ctx = egui::Context::default();
+        let mut canvas = canvas(8, 8, 1);
+        uploaded(&mut canvas, &ctx);
+        let id = canvas.composite_texture().unwrap().id();
+        assert!(!canvas.state.layers[0].is_dirty());
+
+        canvas.clear_layer(0);
+        assert!(canvas.state.layers[0].is_dirty());
+        uploaded(&mut canvas, &ctx);
+        assert_eq!(canvas.composite_texture().unwrap().id(), id);
+        assert!(!canvas.state.layers[0].is_dirty());
+
+        canvas.forget_textures();
+        assert!(canvas.composite_texture().is_none());
+        uploaded(&mut canvas, &ctx);
+        assert!(canvas.composite_texture().unwrap().id() != id);
     }
 
-    fn smudge(&mut self, layer: usize, frame: &BrushStrokeFrame) {
-        self.layers()[layer].mark_dirty();
-        SmudgeOperation {
-            brush: &frame.brush,
-            cursor_position: (frame.cursor_position.x, frame.cursor_position.y),
-            last_cursor_position: (frame.last_cursor_position.x, frame.last_cursor_position.y),
-            smudge_strength: 1.0, // @todo: doesn't belong here, infact can probably just use opacity
-            pixel_buffer: &mut self.state.layers[layer].pixels,
-            pixel_buffer_width: self.state.width,
-            pixel_buffer_height: self.state.height,
-        }
-        .process();
+    #[test]
+    fn strokes_are_mirrored_by_the_symmetry_they_were_painted_with() {
+        let mut canvas = canvas(32, 16, 1);
+        let symmetry = Symmetry {
+            enabled: true,
+            mode: SymmetryMode::Vertical,
+            ..Symmetry::centered(32, 16)
+        };
+        canvas.begin_brush_stroke(Some(symmetry), None, 0, None);
+        let at = Pos2::new(6.0, 8.0);
+        let frame = frame(&hard_brush(3.0), Rgba::WHITE, at, at, 0.0);
+        canvas
+            .process_brush_stroke_frame(0, BrushStrokeKind::Paint, &frame)
+            .unwrap();
+        let layer = &canvas.state.layers[0];
+        assert_eq!(layer.pixel_at(6, 8).a(), 255);
+        assert_eq!(layer.pixel_at(25, 8).a(), 255);
+        assert_eq!(layer.pixel_at(16, 8).a(), 0);
     }
 }
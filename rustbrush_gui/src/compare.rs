@@ -0,0 +1,148 @@
+use eframe::egui::{self, Pos2};
+
+use crate::canvas::Canvas;
+use crate::overlay::CanvasOverlay;
+
+/// A picture of the canvas to flip back to, such as the colors before an adjustment. It's
+/// the composite rather than the layers, so it stays as it was whatever happens to them.
+pub struct CompareSnapshot {
+    overlay: CanvasOverlay,
+    size: (u32, u32),
+}
+
+impl CompareSnapshot {
+    pub fn take(ctx: &egui::Context, canvas: &Canvas, options: egui::TextureOptions) -> Self {
+        let size = (canvas.state.width, canvas.state.height);
+        let image = egui::ColorImage {
+            size: [size.0 as usize, size.1 as usize],
+            pixels: canvas.snapshot().merged(),
+        };
+        Self {
+            overlay: CanvasOverlay::with_options(
+                ctx,
+                "compare_snapshot",
+                image,
+                Pos2::ZERO,
+                options,
+            ),
+            size,
+        }
+    }
+
+    /// Whether the snapshot still covers `canvas` exactly. Once the canvas changes size there's
+    /// nothing sensible to compare it with.
+    pub fn fits(&self, canvas: &Canvas) -> bool {
+        self.size == (canvas.state.width, canvas.state.height)
+    }
+
+    /// Draws the snapshot given the screen position of the canvas origin and the zoom.
+    pub fn paint(&self, painter: &egui::Painter, canvas_origin: Pos2, zoom: f32) {
+        self.overlay.paint(painter, canvas_origin, zoom);
+    }
+}
+
+/// The snapshot the canvas is compared with, if one was taken, see [`CompareSnapshot`].
+#[derive(Default)]
+pub struct Compare {
+    snapshot: Option<CompareSnapshot>,
+}
+
+impl Compare {
+    /// Takes a snapshot of the canvas as it is now, replacing the one there was.
+    pub fn take(&mut self, ctx: &egui::Context, canvas: &Canvas, options: egui::TextureOptions) {
+        self.snapshot = Some(CompareSnapshot::take(ctx, canvas, options));
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshot = None;
+    }
+
+    pub fn has_snapshot(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    /// Drops the snapshot once it no longer fits `canvas`, see [`CompareSnapshot::fits`],
+    /// returning whether it did.
+    pub fn drop_if_stale(&mut self, canvas: &Canvas) -> bool {
+        let stale = self.snapshot.take_if(|snapshot| !snapshot.fits(canvas));
+        stale.is_some()
+    }
+
+    /// The snapshot to show in place of the canvas, while `comparing`.
+    pub fn shown(&self, comparing: bool) -> Option<&CompareSnapshot> {
+        self.snapshot.as_ref().filter(|_| comparing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::canvas;
+    use eframe::egui::{Color32, ImageData, Rgba, TextureId};
+    use rustbrush_utils::canvas::{BrushStrokeKind, LayerBounds};
+    use rustbrush_utils::testing::{hard_brush, stroke};
+
+    /// The pixels last uploaded for `texture`.
+    fn uploaded(ctx: &egui::Context, texture: TextureId) -> Vec<Color32> {
+        let delta = ctx.tex_manager().write().take_delta();
+        let (_, image) = delta.set.iter().rfind(|(id, _)| *id == texture).unwrap();
+        match &image.image {
+            ImageData::Color(image) => image.pixels.clone(),
+            ImageData::Font(_) => panic!("not a color image"),
+        }
+    }
+
+    fn painted(canvas: &mut Canvas, color: Rgba) {
+        let points = [Pos2::new(2.0, 4.0), Pos2::new(14.0, 4.0)];
+        let kind = BrushStrokeKind::Paint;
+        stroke(canvas, 0, kind, &hard_brush(2.0), color, &points);
+    }
+
+    #[test]
+    fn a_snapshot_shows_the_canvas_as_it_was_until_cleared() {
+        let ctx = egui::Context::default();
+        let options = egui::TextureOptions::NEAREST;
+        let mut canvas = canvas(16, 8, 1);
+        painted(&mut canvas, Rgba::from_rgb(1.0, 0.0, 0.0));
+        let before = canvas.snapshot().merged();
+
+        let mut compare = Compare::default();
+        assert!(!compare.has_snapshot() && compare.shown(true).is_none());
+        compare.take(&ctx, &canvas, options);
+        painted(&mut canvas, Rgba::from_rgb(0.0, 0.0, 1.0));
+        assert!(canvas.snapshot().merged() != before);
+
+        // only shown while comparing
+        assert!(compare.shown(false).is_none());
+        let shown = compare.shown(true).unwrap();
+        assert_eq!(shown.overlay.texture.size(), [16, 8]);
+        assert!(uploaded(&ctx, shown.overlay.texture.id()) == before);
+
+        // a new one replaces it
+        compare.take(&ctx, &canvas, options);
+        let texture = compare.shown(true).unwrap().overlay.texture.id();
+        assert!(uploaded(&ctx, texture) == canvas.snapshot().merged());
+
+        compare.clear();
+        assert!(!compare.has_snapshot() && compare.shown(true).is_none());
+    }
+
+    #[test]
+    fn a_snapshot_is_dropped_once_the_canvas_changes_size() {
+        let ctx = egui::Context::default();
+        let mut canvas = canvas(16, 8, 1);
+        let mut compare = Compare::default();
+        assert!(!compare.drop_if_stale(&canvas));
+        compare.take(&ctx, &canvas, egui::TextureOptions::NEAREST);
+
+        // painting doesn't change the size
+        painted(&mut canvas, Rgba::WHITE);
+        assert!(!compare.drop_if_stale(&canvas));
+        assert!(compare.has_snapshot());
+
+        canvas.resize(LayerBounds::new(0, 0, 16, 4)).unwrap();
+        assert!(compare.drop_if_stale(&canvas));
+        assert!(!compare.has_snapshot());
+        assert!(!compare.drop_if_stale(&canvas));
+    }
+}
@@ -0,0 +1,84 @@
+use eframe::egui::{self, Color32, Pos2, Sense, Stroke, Vec2};
+use rustbrush_utils::falloff::CurvePoints;
+
+const EDITOR_SIZE: Vec2 = Vec2::new(200.0, 140.0);
+const POINT_RADIUS: f32 = 4.0;
+const GRAB_RADIUS: f32 = 8.0;
+const CURVE_SAMPLES: usize = 64;
+
+/// A small editor for a falloff curve: drag points to move them, double-click to add a
+/// point and right-click a point to remove it. Returns true when the curve changed.
+pub fn curve_editor(ui: &mut egui::Ui, points: &mut CurvePoints) -> bool {
+    let (rect, response) = ui.allocate_exact_size(EDITOR_SIZE, Sense::click_and_drag());
+    let dragged_id = response.id.with("dragged_point");
+    let mut changed = false;
+
+    let to_screen = |(x, y): (f32, f32)| {
+        Pos2::new(
+            rect.left() + x * rect.width(),
+            rect.bottom() - y * rect.height(),
+        )
+    };
+    let to_curve = |pos: Pos2| {
+        (
+            ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0),
+            ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0),
+        )
+    };
+    let hovered_point = |pos: Pos2, points: &CurvePoints| {
+        points
+            .points()
+            .iter()
+            .position(|&p| to_screen(p).distance(pos) <= GRAB_RADIUS)
+    };
+
+    if let Some(pos) = response.interact_pointer_pos() {
+        if response.drag_started() {
+            let index = hovered_point(pos, points);
+            ui.data_mut(|d| d.insert_temp(dragged_id, index));
+        }
+        if response.dragged() {
+            if let Some(Some(index)) = ui.data(|d| d.get_temp::<Option<usize>>(dragged_id)) {
+                let (x, y) = to_curve(pos);
+                points.move_point(index, x, y);
+                changed = true;
+            }
+        }
+        if response.double_clicked() && hovered_point(pos, points).is_none() {
+            let (x, y) = to_curve(pos);
+            points.insert(x, y);
+            changed = true;
+        }
+        if response.secondary_clicked() {
+            if let Some(index) = hovered_point(pos, points) {
+                points.remove(index);
+                changed = true;
+            }
+        }
+    }
+    if response.drag_stopped() {
+        ui.data_mut(|d| d.remove::<Option<usize>>(dragged_id));
+    }
+
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 2.0, visuals.extreme_bg_color);
+    painter.rect_stroke(rect, 2.0, visuals.widgets.noninteractive.bg_stroke);
+
+    let curve: Vec<Pos2> = (0..=CURVE_SAMPLES)
+        .map(|i| {
+            let x = i as f32 / CURVE_SAMPLES as f32;
+            to_screen((x, points.evaluate(x)))
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        curve,
+        Stroke::new(1.5, visuals.widgets.active.fg_stroke.color),
+    ));
+
+    for &point in points.points() {
+        painter.circle_filled(to_screen(point), POINT_RADIUS, Color32::WHITE);
+    }
+
+    changed
+}
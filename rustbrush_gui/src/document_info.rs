@@ -0,0 +1,58 @@
+use eframe::egui;
+
+use rustbrush_utils::canvas::CanvasStats;
+
+/// Shows the document info window until it's closed.
+pub fn show(ctx: &egui::Context, open: &mut bool, stats: &CanvasStats) {
+    egui::Window::new("Document Info")
+        .open(open)
+        .resizable(false)
+        .show(ctx, |ui| {
+            ui.label(format!("{} × {} pixels", stats.width, stats.height));
+            ui.label(format!("{} colors", stats.distinct_colors));
+            ui.label(format!("{} in memory", format_bytes(stats.bytes)));
+            ui.separator();
+            egui::Grid::new("document_info_layers")
+                .striped(true)
+                .show(ui, |ui| {
+                    for heading in [
+                        "Layer",
+                        "Size",
+                        "Painted",
+                        "Painted Area",
+                        "Colors",
+                        "Memory",
+                        "Revision",
+                    ] {
+                        ui.strong(heading);
+                    }
+                    ui.end_row();
+                    // top layer first, like the layer panel
+                    for layer in stats.layers.iter().rev() {
+                        ui.label(&layer.name);
+                        ui.label(format!("{} × {}", layer.bounds.width, layer.bounds.height));
+                        ui.label(format!("{} px", layer.painted_pixels));
+                        let painted = layer.painted_bounds;
+                        ui.label(match painted.is_empty() {
+                            true => "—".to_string(),
+                            false => format!(
+                                "{} × {} at {}, {}",
+                                painted.width, painted.height, painted.x, painted.y
+                            ),
+                        });
+                        ui.label(layer.distinct_colors.to_string());
+                        ui.label(format_bytes(layer.bytes));
+                        ui.label(layer.revision.to_string());
+                        ui.end_row();
+                    }
+                });
+        });
+}
+
+pub fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        bytes if bytes >= 1 << 20 => format!("{:.1} MiB", bytes as f32 / (1 << 20) as f32),
+        bytes if bytes >= 1 << 10 => format!("{:.1} KiB", bytes as f32 / (1 << 10) as f32),
+        bytes => format!("{} bytes", bytes),
+    }
+}
@@ -0,0 +1,495 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use eframe::egui;
+use rustbrush_utils::composite::LayerBlendMode;
+use rustbrush_utils::task::TaskContext;
+use serde::{Deserialize, Serialize};
+
+use rustbrush_utils::canvas::{scaled_size, CanvasSnapshot, LayerBounds};
+
+/// Where and how the document was last exported, so it can be exported again the same way.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportSettings {
+    pub path: PathBuf,
+    /// How much to shrink the image by, from 1 (full size) down.
+    pub scale: f32,
+    /// Crop the image to the painted part of the canvas, the way sprites are usually
+    /// exported.
+    pub trim: bool,
+    /// Write an [`ExportManifest`] next to the image, see [`ExportSettings::manifest_path`].
+    pub emit_manifest: bool,
+    /// The resolution the image is tagged with, in dots per inch.
+    pub dpi: u32,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("painting.png"),
+            scale: 1.0,
+            trim: false,
+            emit_manifest: false,
+            dpi: 72,
+        }
+    }
+}
+
+impl ExportSettings {
+    /// Exports `snapshot`, replacing whatever is already at the path, and at the manifest
+    /// path if there's a manifest. Trimming a canvas with nothing painted on it is an error,
+    /// since there'd be no image left.
+    ///
+    /// It's meant to be run on a background thread, reporting progress to `task`. Once
+    /// `task` is cancelled it stops before writing anything more, failing with
+    /// [`rustbrush_utils::canvas::SAVE_CANCELLED`].
+    pub fn export(
+        &self,
+        snapshot: &CanvasSnapshot,
+        task: &TaskContext,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let rect = match self.trim {
+            true => snapshot.content_bounds(),
+            false => LayerBounds::canvas(snapshot.width, snapshot.height),
+        };
+        if rect.is_empty() {
+            return Err("there's nothing painted to trim the image to".into());
+        }
+        snapshot.save_rect_as_png(&self.path, rect, self.scale, Some(self.dpi), &[], task)?;
+        if self.emit_manifest {
+            let manifest = ExportManifest::new(snapshot, self, rect);
+            std::fs::write(
+                self.manifest_path(),
+                serde_json::to_string_pretty(&manifest)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Where the manifest goes: next to the image, with the same name and a `.json`
+    /// extension. An image that's already named `.json` gets the extension added on, so the
+    /// manifest doesn't replace it.
+    pub fn manifest_path(&self) -> PathBuf {
+        let path = self.path.with_extension("json");
+        match path == self.path {
+            true => PathBuf::from(format!("{}.json", self.path.display())),
+            false => path,
+        }
+    }
+
+    /// Whether exporting would replace a file that's already there, the image or the
+    /// manifest if there is one.
+    pub fn replaces_existing(&self) -> bool {
+        self.path.is_file() || (self.emit_manifest && self.manifest_path().is_file())
+    }
+}
+
+/// The version of the [`ExportManifest`] schema. It goes up whenever a field changes meaning
+/// or goes away, so importers can tell a manifest they don't understand; new fields don't
+/// change it.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Written next to an exported image, with what an importer needs to put the image back
+/// where it was on the canvas, such as a game engine positioning a trimmed sprite.
+///
+/// Positions and sizes are in canvas pixels, before the image was scaled; multiply them by
+/// `scale` for pixels of the image.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub version: u32,
+    /// The image's file name, which is in the same folder as the manifest.
+    pub image: String,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub canvas_width: u32,
+    pub canvas_height: u32,
+    /// The part of the canvas the image shows. Its position is the trim offset, and it's
+    /// the whole canvas for an image that wasn't trimmed.
+    pub trim: ManifestRect,
+    pub scale: f32,
+    pub dpi: u32,
+    /// Every layer, bottom to top, including hidden ones, which aren't in the image.
+    pub layers: Vec<ManifestLayer>,
+}
+
+/// A rectangle in canvas pixels, in an [`ExportManifest`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<LayerBounds> for ManifestRect {
+    fn from(bounds: LayerBounds) -> Self {
+        Self {
+            x: bounds.x,
+            y: bounds.y,
+            width: bounds.width,
+            height: bounds.height,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestLayer {
+    pub name: String,
+    pub visible: bool,
+    /// From 0 to 1, missing from manifests written before layers had one.
+    #[serde(default = "full_opacity")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub blend_mode: LayerBlendMode,
+    /// Where the layer's pixels are, which can reach past the canvas.
+    pub bounds: ManifestRect,
+}
+
+fn full_opacity() -> f32 {
+    1.0
+}
+
+impl ExportManifest {
+    /// The manifest of exporting `rect` of `snapshot` with `settings`.
+    pub fn new(snapshot: &CanvasSnapshot, settings: &ExportSettings, rect: LayerBounds) -> Self {
+        let (image_width, image_height) = scaled_size(rect.width, rect.height, settings.scale);
+        Self {
+            version: MANIFEST_VERSION,
+            image: file_name(&settings.path),
+            image_width,
+            image_height,
+            canvas_width: snapshot.width,
+            canvas_height: snapshot.height,
+            trim: rect.into(),
+            scale: settings.scale,
+            dpi: settings.dpi,
+            layers: snapshot
+                .layers
+                .iter()
+                .map(|layer| ManifestLayer {
+                    name: layer.name.clone(),
+                    visible: layer.visible,
+                    opacity: layer.opacity,
+                    blend_mode: layer.blend_mode,
+                    bounds: layer.bounds.into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+/// The export dialog, prefilled with the last settings used.
+pub struct ExportDialog {
+    settings: ExportSettings,
+    path: String,
+}
+
+/// What the export dialog was closed with.
+pub enum ExportDialogResult {
+    Export(ExportSettings),
+    Cancel,
+}
+
+impl ExportDialog {
+    pub fn new(settings: ExportSettings) -> Self {
+        Self {
+            path: settings.path.display().to_string(),
+            settings,
+        }
+    }
+
+    /// Shows the dialog, returning how it was closed once it is.
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        canvas_size: (u32, u32),
+    ) -> Option<ExportDialogResult> {
+        let mut result = None;
+        egui::Window::new("Export PNG")
+            .resizable(false)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Path:");
+                    ui.text_edit_singleline(&mut self.path);
+                });
+                ui.add(egui::Slider::new(&mut self.settings.scale, 0.1..=1.0).text("Scale"));
+                ui.checkbox(&mut self.settings.trim, "Trim to painted area");
+                let (width, height) =
+                    scaled_size(canvas_size.0, canvas_size.1, self.settings.scale);
+                match self.settings.trim {
+                    true => ui.label(format!("Up to {} × {} pixels", width, height)),
+                    false => ui.label(format!("{} × {} pixels", width, height)),
+                };
+                ui.horizontal(|ui| {
+                    ui.label("DPI:");
+                    ui.add(egui::DragValue::new(&mut self.settings.dpi).range(1..=2400));
+                });
+                ui.checkbox(&mut self.settings.emit_manifest, "Write manifest")
+                    .on_hover_text(
+                        "Save a .json file next to the image with the canvas size, trim \
+                         offset and layer bounds, for placing the image in a game engine",
+                    );
+
+                // exporting over an existing file needs a second look, quick exports don't
+                let settings = ExportSettings {
+                    path: PathBuf::from(self.path.trim()),
+                    ..self.settings.clone()
+                };
+                let exists = settings.replaces_existing();
+                if exists {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        "A file with this name already exists and will be replaced.",
+                    );
+                }
+                ui.horizontal(|ui| {
+                    let label = if exists { "Replace" } else { "Export" };
+                    if ui
+                        .add_enabled(!self.path.trim().is_empty(), egui::Button::new(label))
+                        .clicked()
+                    {
+                        result = Some(ExportDialogResult::Export(settings));
+                    }
+                    if ui.button("Cancel").clicked() {
+                        result = Some(ExportDialogResult::Cancel);
+                    }
+                });
+            });
+        result
+    }
+}
+
+/// A short message shown over the canvas for a few seconds, such as the outcome of an
+/// export. Errors stay up longer so there's time to read them.
+pub struct Toast {
+    message: String,
+    error: bool,
+    shown_at: Instant,
+}
+
+impl Toast {
+    pub fn info(message: String) -> Self {
+        Self {
+            message,
+            error: false,
+            shown_at: Instant::now(),
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        Self {
+            message,
+            error: true,
+            shown_at: Instant::now(),
+        }
+    }
+
+    fn duration(&self) -> Duration {
+        if self.error {
+            Duration::from_secs(8)
+        } else {
+            Duration::from_secs(3)
+        }
+    }
+
+    /// Shows the toast, returning false once it's been up long enough or was clicked away.
+    pub fn show(&self, ctx: &egui::Context) -> bool {
+        let remaining = self.duration().saturating_sub(self.shown_at.elapsed());
+        if remaining.is_zero() {
+            return false;
+        }
+        ctx.request_repaint_after(remaining);
+
+        let mut dismissed = false;
+        egui::Area::new(egui::Id::new("toast"))
+            .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -32.0))
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let text = egui::RichText::new(&self.message);
+                    let text = if self.error {
+                        text.color(ui.visuals().error_fg_color)
+                    } else {
+                        text
+                    };
+                    dismissed = ui
+                        .add(egui::Label::new(text).sense(egui::Sense::click()))
+                        .on_hover_text("Click to dismiss")
+                        .clicked();
+                });
+            });
+        !dismissed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{SavedSession, SESSION_KEY};
+    use crate::test_support::{canvas, MemoryStorage};
+    use eframe::egui::Color32;
+    use eframe::Storage;
+    use rustbrush_utils::canvas::{LayerContents, LayerPixels};
+    use std::sync::Arc;
+
+    /// An empty folder of its own for the test called `name`.
+    fn folder(name: &str) -> PathBuf {
+        let folder =
+            std::env::temp_dir().join(format!("rustbrush-export-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    #[test]
+    fn the_last_export_settings_are_restored_with_the_session() {
+        let settings = ExportSettings {
+            path: PathBuf::from("sprites/hero.png"),
+            scale: 0.5,
+            trim: true,
+            emit_manifest: true,
+            dpi: 300,
+        };
+        let session = SavedSession {
+            export: Some(settings.clone()),
+            ..SavedSession::default()
+        };
+        let mut storage = MemoryStorage::default();
+        eframe::set_value(&mut storage, SESSION_KEY, &session);
+        let restored: SavedSession = eframe::get_value(&storage, SESSION_KEY).unwrap();
+        assert!(restored.export == Some(settings));
+
+        // settings stored before a field was added get its default
+        storage.set_string("export", r#"(path: "old.png", scale: 0.25)"#.to_string());
+        let old: ExportSettings = eframe::get_value(&storage, "export").unwrap();
+        let expected = ExportSettings {
+            path: PathBuf::from("old.png"),
+            scale: 0.25,
+            ..ExportSettings::default()
+        };
+        assert!(old == expected);
+    }
+
+    #[test]
+    fn existing_files_are_noticed_and_replaced() {
+        let folder = folder("replace");
+        let mut settings = ExportSettings {
+            path: folder.join("hero.png"),
+            ..ExportSettings::default()
+        };
+        assert!(!settings.replaces_existing());
+
+        // a manifest is only in the way if there's going to be one
+        std::fs::write(settings.manifest_path(), "{}").unwrap();
+        assert!(!settings.replaces_existing());
+        settings.emit_manifest = true;
+        assert!(settings.replaces_existing());
+        std::fs::remove_file(settings.manifest_path()).unwrap();
+        std::fs::write(&settings.path, "not a png").unwrap();
+        assert!(settings.replaces_existing());
+
+        let snapshot = canvas(12, 8, 1).snapshot();
+        settings
+            .export(&snapshot, &TaskContext::detached())
+            .unwrap();
+        assert_eq!(image::image_dimensions(&settings.path).unwrap(), (12, 8));
+        let manifest = std::fs::read_to_string(settings.manifest_path()).unwrap();
+        assert!(manifest.contains("\"image\": \"hero.png\""), "{manifest}");
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn the_manifest_places_a_trimmed_image_and_every_layer() {
+        let mut canvas = canvas(40, 30, 1);
+        // painted from (6, 4) to (17, 13)
+        let mut pixels = vec![Color32::TRANSPARENT; 40 * 30];
+        for row in pixels.chunks_exact_mut(40).skip(4).take(10) {
+            row[6..18].fill(Color32::RED);
+        }
+        let bounds = LayerBounds::canvas(40, 30);
+        let pixels = LayerPixels::Color(Arc::new(pixels));
+        canvas.restore_layer(0, LayerContents { bounds, pixels });
+        let shadow = LayerBounds::new(-5, 20, 16, 16);
+        let layer = canvas.add_layer_at("Shadow".to_string(), shadow).unwrap();
+        canvas.layers()[layer].opacity = 0.5;
+        canvas.layers()[layer].blend_mode = LayerBlendMode::Multiply;
+        // hidden, so it's left out of the image and the trim, but not the manifest
+        let layer = canvas.add_named_layer("Sketch".to_string()).unwrap();
+        let pixels = LayerPixels::Color(Arc::new(vec![Color32::WHITE; 40 * 30]));
+        canvas.restore_layer(layer, LayerContents { bounds, pixels });
+        canvas.layers()[layer].visible = false;
+
+        let folder = folder("manifest");
+        let settings = ExportSettings {
+            path: folder.join("sprite.png"),
+            scale: 0.5,
+            trim: true,
+            emit_manifest: true,
+            dpi: 144,
+        };
+        settings
+            .export(&canvas.snapshot(), &TaskContext::detached())
+            .unwrap();
+        assert_eq!(image::image_dimensions(&settings.path).unwrap(), (6, 5));
+        let manifest = std::fs::read_to_string(settings.manifest_path()).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        let expected = serde_json::json!({
+            "version": 1,
+            "image": "sprite.png",
+            "image_width": 6,
+            "image_height": 5,
+            "canvas_width": 40,
+            "canvas_height": 30,
+            "trim": { "x": 6, "y": 4, "width": 12, "height": 10 },
+            "scale": 0.5,
+            "dpi": 144,
+            "layers": [
+                {
+                    "name": "Layer 1",
+                    "visible": true,
+                    "opacity": 1.0,
+                    "blend_mode": "normal",
+                    "bounds": { "x": 0, "y": 0, "width": 40, "height": 30 },
+                },
+                {
+                    "name": "Shadow",
+                    "visible": true,
+                    "opacity": 0.5,
+                    "blend_mode": "multiply",
+                    "bounds": { "x": -5, "y": 20, "width": 16, "height": 16 },
+                },
+                {
+                    "name": "Sketch",
+                    "visible": false,
+                    "opacity": 1.0,
+                    "blend_mode": "normal",
+                    "bounds": { "x": 0, "y": 0, "width": 40, "height": 30 },
+                },
+            ],
+        });
+        assert_eq!(manifest, expected);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn the_manifest_never_takes_the_images_name() {
+        let manifest = |path: &str| {
+            let settings = ExportSettings {
+                path: PathBuf::from(path),
+                ..ExportSettings::default()
+            };
+            settings.manifest_path()
+        };
+        assert_eq!(manifest("art/hero.png"), PathBuf::from("art/hero.json"));
+        assert_eq!(manifest("hero.v2.png"), PathBuf::from("hero.v2.json"));
+        assert_eq!(manifest("hero"), PathBuf::from("hero.json"));
+        assert_eq!(manifest("hero.json"), PathBuf::from("hero.json.json"));
+    }
+}
@@ -0,0 +1,121 @@
+use eframe::egui;
+
+use crate::canvas::Canvas;
+use crate::user::{User, UserAction};
+
+/// The history window: the milestones, actions that have been named, pinned at the top,
+/// then every action, newest first. Clicking an action undoes or redoes up to it, and
+/// right-clicking one names it.
+#[derive(Default)]
+pub struct HistoryPanel {
+    /// The id of the action being named, and the name being typed.
+    renaming: Option<(usize, String)>,
+}
+
+impl HistoryPanel {
+    /// Shows the window until it's closed.
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        open: &mut bool,
+        user: &mut User,
+        canvas: &mut Canvas,
+    ) {
+        let mut go_to = None;
+        let mut renamed = None;
+        egui::Window::new("History").open(open).show(ctx, |ui| {
+            let current = user.current_action_id;
+            let milestones: Vec<&UserAction> = user
+                .action_history
+                .iter()
+                .filter(|action| action.is_pinned())
+                .collect();
+            if !milestones.is_empty() {
+                ui.strong("Milestones");
+                for action in milestones {
+                    if self.row(ui, action, current, &mut renamed) {
+                        go_to = Some(action.id);
+                    }
+                }
+                ui.separator();
+            }
+
+            egui::ScrollArea::vertical()
+                .max_height(320.0)
+                .show(ui, |ui| {
+                    for action in user.action_history.iter().rev() {
+                        if self.row(ui, action, current, &mut renamed) {
+                            go_to = Some(action.id);
+                        }
+                    }
+                    if ui.selectable_label(current == 0, "Start").clicked() {
+                        go_to = Some(0);
+                    }
+                });
+        });
+
+        if let Some((id, name)) = renamed {
+            user.rename_action(id, &name);
+        }
+        if let Some(id) = go_to {
+            user.go_to_action(canvas, id);
+        }
+    }
+
+    /// One action in the list, undone ones faded, returning whether it was clicked. A name
+    /// given in its context menu is put in `renamed`.
+    fn row(
+        &mut self,
+        ui: &mut egui::Ui,
+        action: &UserAction,
+        current: usize,
+        renamed: &mut Option<(usize, String)>,
+    ) -> bool {
+        let mut text = egui::RichText::new(action.label());
+        if action.id > current {
+            text = text.weak();
+        }
+        let response = ui
+            .selectable_label(action.id == current, text)
+            .on_hover_text(details(action));
+        response.context_menu(|ui| {
+            let (_, name) = self.renaming.get_or_insert_with(|| {
+                (action.id, action.metadata.name.clone().unwrap_or_default())
+            });
+            let edit = ui.add(egui::TextEdit::singleline(name).hint_text("Milestone name"));
+            let entered = edit.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if ui.button("Name").clicked() || entered {
+                *renamed = Some((action.id, std::mem::take(name)));
+                ui.close_menu();
+            }
+            if action.is_pinned() && ui.button("Remove Name").clicked() {
+                *renamed = Some((action.id, String::new()));
+                ui.close_menu();
+            }
+        });
+        if renamed.is_some() || !response.context_menu_opened() {
+            self.renaming.take_if(|(id, _)| *id == action.id);
+        }
+        response.clicked()
+    }
+}
+
+/// What's known about `action` beyond its label.
+fn details(action: &UserAction) -> String {
+    let metadata = &action.metadata;
+    let mut details = action.kind.label().to_string();
+    if metadata.frame_count > 0 {
+        details.push_str(&format!(
+            "\n{} frames over {:.1}s",
+            metadata.frame_count,
+            metadata.duration.as_secs_f32()
+        ));
+    }
+    if let Some(bounds) = metadata.bounds {
+        details.push_str(&format!(
+            "\n{} × {} at {}, {}",
+            bounds.width, bounds.height, bounds.x, bounds.y
+        ));
+    }
+    details
+}
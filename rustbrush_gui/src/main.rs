@@ -1,109 +1,1502 @@
+mod actions;
+mod adjustments;
+mod brush_preview;
 mod canvas;
+mod compare;
+mod curve_editor;
+mod document_info;
+mod export;
+mod history;
+mod overlay;
+mod palette;
+mod paste;
+mod perf;
+mod presets;
+mod rulers;
+mod session;
+mod single_instance;
+mod stencils;
+mod symmetry;
+#[cfg(test)]
+mod test_support;
 mod user;
+mod view;
 
-use canvas::{Canvas, CanvasLayer, CanvasState};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use actions::{ActionRegistry, CommandPalette};
+use adjustments::AdjustmentDialog;
+use canvas::Canvas;
+use compare::Compare;
 use eframe::egui::{self, Color32, Pos2, Rect, Rgba, Vec2};
-use tracing::error;
+use export::{ExportDialog, ExportDialogResult, ExportSettings, Toast};
+use history::HistoryPanel;
+use overlay::CanvasOverlay;
+use palette::{Palette, SwatchPicker, SwatchScope};
+use paste::{FloatingPaste, PasteTarget};
+use perf::PerfStats;
+use presets::{BrushPreset, LibraryPicker, PresetPicker};
+use rulers::Rulers;
+use rustbrush_utils::alpha;
+use rustbrush_utils::canvas::{
+    BrushStrokeKind, CanvasError, CanvasLayer, CanvasState, LayerBounds, LayerKind, PasteImage,
+};
+use rustbrush_utils::composite::LayerBlendMode;
+use rustbrush_utils::falloff::{CurvePoints, FalloffCurve};
+use rustbrush_utils::filter_registry::FilterRegistry;
+use rustbrush_utils::library::BrushLibrary;
+use rustbrush_utils::operations::{DodgeBurnMode, NoiseMode, TonalRange};
+use rustbrush_utils::sampling::{SampleKernel, SampleSize};
+use rustbrush_utils::selection::SelectionMask;
+use rustbrush_utils::shape::ShapeKind;
+use rustbrush_utils::stroke::{FadeTail, StrokeAccumulation};
+use rustbrush_utils::task::{BackgroundTask, TaskContext, TaskStatus};
+use rustbrush_utils::{Brush, SecondaryPlacement, SecondaryTip};
 use rustbrush_utils::{ALPHA_CHANNEL, BLUE_CHANNEL, GREEN_CHANNEL, RED_CHANNEL};
-use user::User;
-
-struct ViewState {
-    offset: Vec2,
-    zoom: f32,
-}
+use session::{ReopenLastDocument, SavedSession, SESSION_KEY};
+use single_instance::InstanceListener;
+use symmetry::{Symmetry, SymmetryMode};
+use tracing::{error, warn};
+use user::{EraserMode, Tool, User};
+use view::ViewState;
 
-impl Default for ViewState {
-    fn default() -> Self {
-        Self {
-            offset: Vec2::ZERO,
-            zoom: 1.0,
-        }
-    }
-}
+/// What stencil layers are tinted with on screen, scaled by their alpha.
+const STENCIL_TINT: Color32 = Color32::from_rgba_premultiplied(120, 0, 60, 120);
 
 struct App {
     canvas: Canvas,
     view: ViewState,
+    rulers: Rulers,
     dragging_canvas: bool,
     last_drag_pos: Option<Pos2>,
     user: User,
+    /// The scribble swatch for the paint brush, along with the brush and color it was
+    /// rendered with so it's only re-rendered when those change.
+    brush_preview: Option<(Brush, Rgba, egui::TextureHandle)>,
+    /// Whether a paint brush setting is being dragged, which shows the stroke preview.
+    adjusting_brush: bool,
+    /// A full-size stroke drawn over the canvas while the brush is being adjusted, along with
+    /// the brush and color it was rendered with.
+    stroke_preview: Option<(Brush, Rgba, CanvasOverlay)>,
+    /// The tint showing the canvas selection, along with the mask it was rendered from.
+    selection_overlay: Option<(SelectionMask, CanvasOverlay)>,
+    /// The filters offered in the Filters menu.
+    filters: FilterRegistry,
+    /// What the command palette and keyboard shortcuts can run.
+    actions: ActionRegistry,
+    command_palette: CommandPalette,
+    adjustment_dialog: Option<AdjustmentDialog>,
+    /// The path being typed into the import window, while it's open.
+    import_path: Option<String>,
+    /// The last copied pixels, along with where they were copied from.
+    clipboard: Option<(PasteImage, (i32, i32))>,
+    /// Pasted or imported pixels that haven't been committed yet.
+    floating_paste: Option<FloatingPaste>,
+    paste_target: PasteTarget,
+    /// The last import or export problem, shown in the status bar.
+    status_message: Option<String>,
+    /// The last PNG saved or imported, offered for reopening next session.
+    last_document: Option<PathBuf>,
+    reopen_last_document: ReopenLastDocument,
+    /// The last document, while asking whether to reopen it.
+    reopen_prompt: Option<PathBuf>,
+    /// Why a canvas, layer or image was refused for its size, shown in a dialog until it's
+    /// dismissed.
+    size_error: Option<String>,
+    /// The settings of the last export, which Ctrl+E exports with again.
+    export_settings: Option<ExportSettings>,
+    export_dialog: Option<ExportDialog>,
+    /// The export running in the background, and where it's going.
+    export_task: Option<(PathBuf, BackgroundTask<Result<(), String>>)>,
+    toast: Option<Toast>,
+    perf: PerfStats,
+    show_perf: bool,
+    /// The canvas as it was when the snapshot was taken, shown instead of the live one while
+    /// the compare key is held.
+    compare: Compare,
+    show_document_info: bool,
+    show_history: bool,
+    history_panel: HistoryPanel,
+    /// Whether the symmetry axes are drawn while symmetry is on.
+    show_symmetry_guides: bool,
+    /// Whether the symmetry center handle is being dragged.
+    dragging_symmetry_center: bool,
+    presets: Vec<BrushPreset>,
+    preset_picker: PresetPicker,
+    /// The brush presets kept as files, if there's somewhere to keep them, see
+    /// [`presets::library_dir`].
+    library: Option<BrushLibrary>,
+    library_picker: LibraryPicker,
+    /// The global swatch groups and the document's.
+    palette: Palette,
+    swatch_picker: SwatchPicker,
+    /// Whether files opened while the app is already running come to this window rather
+    /// than a new one. It takes effect the next time the app starts.
+    single_instance: bool,
+    /// Where files opened by later instances arrive, if this is the instance listening.
+    instance_listener: Option<InstanceListener>,
+    /// Files to import, one at a time as each floating paste is committed or cancelled.
+    pending_imports: VecDeque<PathBuf>,
 }
 
 impl Default for App {
     fn default() -> Self {
         let width = 800;
         let height = 600;
-        let mut layers = Vec::new();
-        layers.push(CanvasLayer::new(width, height, "Background".to_string()));
-        layers.push(CanvasLayer::new(width, height, "Layer 1".to_string()));
+        let layer = |name: &str| {
+            CanvasLayer::new(width, height, name.to_string())
+                .expect("the default canvas is within the limits")
+        };
+        let layers = vec![layer("Background").with_background(true), layer("Layer 1")];
+        let mut user = User::default();
+        user.symmetry = Symmetry::centered(width, height);
+        let filters = FilterRegistry::with_builtins();
 
         Self {
-            canvas: Canvas {
-                state: CanvasState {
-                    layers,
-                    width,
-                    height,
-                },
-            },
+            canvas: Canvas::new(CanvasState {
+                layers,
+                width,
+                height,
+            })
+            .expect("the default canvas is within the limits"),
             view: ViewState::default(),
+            rulers: Rulers::default(),
             dragging_canvas: false,
             last_drag_pos: None,
-            user: User::default(),
+            user,
+            brush_preview: None,
+            adjusting_brush: false,
+            stroke_preview: None,
+            selection_overlay: None,
+            actions: ActionRegistry::with_builtins(&filters),
+            command_palette: CommandPalette::default(),
+            filters,
+            adjustment_dialog: None,
+            import_path: None,
+            clipboard: None,
+            floating_paste: None,
+            paste_target: PasteTarget::default(),
+            status_message: None,
+            last_document: None,
+            reopen_last_document: ReopenLastDocument::default(),
+            reopen_prompt: None,
+            size_error: None,
+            export_settings: None,
+            export_dialog: None,
+            export_task: None,
+            toast: None,
+            perf: PerfStats::default(),
+            show_perf: false,
+            compare: Compare::default(),
+            show_document_info: false,
+            show_history: false,
+            history_panel: HistoryPanel::default(),
+            show_symmetry_guides: true,
+            dragging_symmetry_center: false,
+            presets: Vec::new(),
+            preset_picker: PresetPicker::default(),
+            library: None,
+            library_picker: LibraryPicker::default(),
+            palette: Palette::default(),
+            swatch_picker: SwatchPicker::default(),
+            single_instance: true,
+            instance_listener: None,
+            pending_imports: VecDeque::new(),
         }
     }
 }
 
 impl App {
+    /// A new document with the tool state of the last session, reopening its document or
+    /// offering to, depending on the setting. A session that can't be read is ignored, and
+    /// the status bar says so.
+    ///
+    /// With `view`, that document is opened read-only instead, and the last session's
+    /// document is left for next time. Otherwise the first of `open` is opened instead of
+    /// the last document, and the rest are imported onto it.
+    fn new(cc: &eframe::CreationContext<'_>, view: Option<PathBuf>, open: Vec<PathBuf>) -> Self {
+        let session = cc
+            .storage
+            .map_or(Ok(SavedSession::default()), SavedSession::load);
+        let session_error = session.as_ref().err().map(|e| {
+            warn!("Ignoring the last session: {}", e);
+            format!("Couldn't restore the last session: {}", e)
+        });
+        let session = session.unwrap_or_default();
+        let mut app = Self {
+            user: session.restore_user(),
+            last_document: session.last_document.clone(),
+            reopen_last_document: session.reopen_last_document,
+            export_settings: session.export.clone(),
+            presets: session.restore_presets(),
+            palette: Palette {
+                groups: session.restore_swatches(),
+            },
+            status_message: session_error,
+            ..Self::default()
+        };
+        app.user.symmetry =
+            session.restore_symmetry(app.canvas.state.width, app.canvas.state.height);
+        if let Some(show) = session.show_symmetry_guides {
+            app.show_symmetry_guides = show;
+        }
+        if let Some(single_instance) = session.single_instance {
+            app.single_instance = single_instance;
+        }
+        app.library = presets::library_dir().map(|dir| {
+            let (library, errors) = BrushLibrary::load(dir);
+            for e in errors {
+                warn!("Skipping a brush in the library: {}", e);
+            }
+            library
+        });
+        if let Some(path) = view {
+            app.open_document(&path);
+            app.last_document = session.last_document.clone();
+            app.canvas.set_read_only(true);
+            return app;
+        }
+        if app.single_instance {
+            app.instance_listener = InstanceListener::start(cc.egui_ctx.clone());
+        }
+        let mut open = VecDeque::from(open);
+        if let Some(path) = open.pop_front() {
+            // cleared again if it can't be opened
+            app.last_document = Some(path.clone());
+            app.open_document(&path);
+            app.pending_imports = open;
+        } else if let Some(path) = session.document_to_reopen() {
+            match session.reopen_last_document {
+                ReopenLastDocument::Ask => app.reopen_prompt = Some(path.to_path_buf()),
+                ReopenLastDocument::Always => app.open_document(path),
+                ReopenLastDocument::Never => {}
+            }
+        }
+        app
+    }
+
+    /// Logs a stroke that couldn't be painted and shows why in the status bar.
+    fn report_stroke_error(&mut self, result: Result<(), CanvasError>) {
+        if let Err(e) = result {
+            error!("Error painting stroke: {}", e);
+            self.status_message = Some(format!("Couldn't paint: {}", e));
+        }
+    }
+
+    /// Logs an edit that couldn't be made and shows why in the status bar.
+    fn report_edit_error(&mut self, result: Result<(), CanvasError>) {
+        if let Err(e) = result {
+            error!("Error editing the document: {}", e);
+            self.status_message = Some(format!("Couldn't edit: {}", e));
+            self.report_size_error(&e);
+        }
+    }
+
+    /// Shows `e` in a dialog as well if it's a size the canvas refused, which needs more
+    /// explaining than fits in the status bar.
+    fn report_size_error(&mut self, e: &(dyn std::error::Error + 'static)) {
+        if let Some(CanvasError::BadSize { .. }) = e.downcast_ref::<CanvasError>() {
+            self.size_error = Some(e.to_string());
+        }
+    }
+
+    /// Starts exporting with `settings` in the background, remembering them for the next
+    /// quick export. An export still running is cancelled first. How it went is said in a
+    /// toast once it's done, see [`App::poll_export`].
+    fn export(&mut self, settings: ExportSettings) {
+        let snapshot = self.canvas.snapshot();
+        let path = settings.path.clone();
+        let export = settings.clone();
+        let work = move |task: &TaskContext| {
+            let result = export.export(&snapshot, task).map_err(|e| e.to_string());
+            (!task.is_cancelled()).then_some(result)
+        };
+        self.export_task = None;
+        match BackgroundTask::spawn("export", work) {
+            Ok(task) => self.export_task = Some((path, task)),
+            Err(e) => {
+                error!("Couldn't start exporting to {}: {}", path.display(), e);
+                let message = format!("Couldn't export to {}: {}", path.display(), e);
+                self.toast = Some(Toast::error(message));
+            }
+        }
+        self.export_settings = Some(settings);
+    }
+
+    /// Says how the background export went in a toast, once it's done.
+    fn poll_export(&mut self, ctx: &egui::Context) {
+        let Some((path, task)) = &mut self.export_task else {
+            return;
+        };
+        let path = path.display();
+        let toast = match task.poll() {
+            TaskStatus::Running => {
+                ctx.request_repaint();
+                return;
+            }
+            TaskStatus::Finished(Ok(())) => Toast::info(format!("Exported to {}", path)),
+            TaskStatus::Finished(Err(e)) | TaskStatus::Failed(e) => {
+                error!("Error exporting to {}: {}", path, e);
+                Toast::error(format!("Couldn't export to {}: {}", path, e))
+            }
+            TaskStatus::Cancelled => Toast::info("Export cancelled".to_string()),
+        };
+        self.toast = Some(toast);
+        self.export_task = None;
+    }
+
+    /// Exports again with the last settings, or opens the export dialog if there haven't
+    /// been any exports yet.
+    fn quick_export(&mut self) {
+        match self.export_settings.clone() {
+            Some(settings) => self.export(settings),
+            None => self.open_export_dialog(),
+        }
+    }
+
+    fn open_export_dialog(&mut self) {
+        let settings = self.export_settings.clone().unwrap_or_default();
+        self.export_dialog = Some(ExportDialog::new(settings));
+    }
+
+    /// Opens a PNG onto the background layer of the fresh document, along with the document
+    /// palette and stencil layers kept in it. If it can't be read the document stays blank,
+    /// and it's no longer offered next session.
+    fn open_document(&mut self, path: &Path) {
+        match paste::load_png(path) {
+            Ok((image, warning)) => {
+                self.canvas.paste(0, &image, (0, 0));
+                self.user.record_paste(0, image, (0, 0));
+                self.status_message = warning;
+                match palette::read_document_palette(path) {
+                    Ok(groups) => self.palette.set_document_groups(groups),
+                    Err(e) => {
+                        warn!("Ignoring the palette in {}: {}", path.display(), e);
+                        self.status_message =
+                            Some(format!("Couldn't read the palette in {}", path.display()));
+                    }
+                }
+                let stencils = stencils::read_document_stencils(path).and_then(|stencils| {
+                    stencils::open_stencils(&mut self.canvas, &mut self.user, &stencils)
+                });
+                if let Err(e) = stencils {
+                    warn!("Ignoring the stencils in {}: {}", path.display(), e);
+                    self.status_message =
+                        Some(format!("Couldn't read the stencils in {}", path.display()));
+                }
+            }
+            Err(e) => {
+                error!("Error reopening {}: {:?}", path.display(), e);
+                self.status_message = Some(format!("Couldn't reopen {}: {}", path.display(), e));
+                self.report_size_error(e.as_ref());
+                self.last_document = None;
+            }
+        }
+    }
+
+    /// Imports a PNG as a floating paste, to be placed and committed onto a layer.
+    fn import_png(&mut self, ctx: &egui::Context, path: &Path) {
+        match paste::load_png(path) {
+            Ok((image, warning)) => {
+                let name = path.file_stem().map_or("Imported".into(), |stem| {
+                    stem.to_string_lossy().into_owned()
+                });
+                self.floating_paste = Some(FloatingPaste::new(ctx, image, (0, 0), name));
+                self.status_message = warning;
+                self.last_document = Some(path.to_path_buf());
+            }
+            Err(e) => {
+                error!("Error importing {}: {:?}", path.display(), e);
+                self.status_message = Some(format!("Couldn't import {}: {}", path.display(), e));
+                self.report_size_error(e.as_ref());
+            }
+        }
+    }
+
+    /// Brings the window forward when another instance was started, and imports the files
+    /// it handed over, one at a time. There's only the one document, so they're imported
+    /// onto it rather than replacing it.
+    fn handle_forwarded_files(&mut self, ctx: &egui::Context) {
+        if let Some(paths) = self.instance_listener.as_ref().and_then(|l| l.received()) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Minimized(false));
+            ctx.send_viewport_cmd(egui::ViewportCommand::Focus);
+            self.pending_imports.extend(paths);
+        }
+        if self.floating_paste.is_some() || self.canvas.is_read_only() {
+            return;
+        }
+        if let Some(path) = self.pending_imports.pop_front() {
+            self.import_png(ctx, &path);
+        }
+    }
+
     fn screen_to_canvas(&self, screen_pos: Pos2, canvas_rect: Rect) -> Pos2 {
-        let relative_pos = screen_pos - canvas_rect.min - self.view.offset;
-        Pos2::new(
-            relative_pos.x / self.view.zoom,
-            relative_pos.y / self.view.zoom,
-        )
+        self.view.screen_to_canvas(screen_pos, canvas_rect)
+    }
+
+    /// Returns the scribble swatch for the current paint brush, re-rendering it if the brush
+    /// or color changed since it was last drawn.
+    fn brush_preview_texture(&mut self, ctx: &egui::Context) -> egui::TextureId {
+        let brush = &self.user.current_paint_brush;
+        let color = self.user.current_color;
+        let up_to_date = matches!(
+            &self.brush_preview,
+            Some((b, c, _)) if b == brush && *c == color
+        );
+        if !up_to_date {
+            let texture = ctx.load_texture(
+                "brush_preview",
+                brush_preview::render_scribble(brush, color),
+                egui::TextureOptions::default(),
+            );
+            self.brush_preview = Some((brush.clone(), color, texture));
+        }
+        self.brush_preview.as_ref().unwrap().2.id()
+    }
+
+    /// Keeps the stroke preview in step with the paint brush while it's being adjusted, and
+    /// drops it once it isn't.
+    fn update_stroke_preview(&mut self, ctx: &egui::Context) {
+        if !self.adjusting_brush {
+            self.stroke_preview = None;
+            return;
+        }
+
+        let brush = &self.user.current_paint_brush;
+        let color = self.user.current_color;
+        match &mut self.stroke_preview {
+            Some((b, c, _)) if b == brush && *c == color => {}
+            Some((b, c, overlay)) => {
+                overlay.set_image(brush_preview::render_s_curve(brush, color));
+                *b = brush.clone();
+                *c = color;
+            }
+            None => {
+                let overlay = CanvasOverlay::new(
+                    ctx,
+                    "stroke_preview",
+                    brush_preview::render_s_curve(brush, color),
+                    Pos2::ZERO,
+                );
+                self.stroke_preview = Some((brush.clone(), color, overlay));
+            }
+        }
+    }
+
+    /// Re-renders the selection tint when the selection changed since it was last drawn.
+    fn update_selection_overlay(&mut self, ctx: &egui::Context) {
+        let Some(selection) = self.canvas.selection() else {
+            self.selection_overlay = None;
+            return;
+        };
+
+        match &mut self.selection_overlay {
+            Some((mask, _)) if mask == selection => {}
+            Some((mask, overlay)) => {
+                overlay.set_image(overlay::selection_tint(selection));
+                *mask = selection.clone();
+            }
+            None => {
+                let overlay = CanvasOverlay::new(
+                    ctx,
+                    "selection",
+                    overlay::selection_tint(selection),
+                    Pos2::ZERO,
+                );
+                self.selection_overlay = Some((selection.clone(), overlay));
+            }
+        }
+    }
+
+    /// Runs the action at `index` in [`Self::actions`], unless it's disabled.
+    fn run_action(&mut self, ctx: &egui::Context, index: usize) {
+        let Some(action) = self.actions.actions().get(index) else {
+            return;
+        };
+        if !action.is_enabled(self) {
+            return;
+        }
+        if let Some(run) = self.actions.handler(index) {
+            run(self, ctx);
+        }
+    }
+
+    /// Saves the canvas next to the app as `painting_<seconds>.png`, along with the
+    /// document palette and the stencil layers.
+    fn save_painting(&mut self) {
+        let now_str = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let path = format!("painting_{}.png", now_str);
+        let chunks: Vec<_> = [
+            self.palette.document_chunk(),
+            stencils::document_chunk(&self.canvas),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        match self.canvas.save_as_png(&path, &chunks) {
+            Ok(()) => {
+                self.last_document = std::fs::canonicalize(&path).ok();
+            }
+            Err(e) => {
+                error!("Error saving canvas as PNG: {:?}", e);
+                self.status_message = Some(format!("Couldn't save: {}", e));
+            }
+        }
+    }
+
+    /// Copies the current layer, or the selected part of it, to the clipboard.
+    fn copy(&mut self) {
+        if let Some(copied) = self.canvas.copy(self.user.current_layer) {
+            self.clipboard = Some(copied);
+        }
+    }
+
+    /// Floats the clipboard above the canvas, either where it was copied from or centered
+    /// on the cursor.
+    fn paste(&mut self, ctx: &egui::Context, in_place: bool) {
+        let Some((image, origin)) = &self.clipboard else {
+            return;
+        };
+        if self.canvas.is_read_only() {
+            return;
+        }
+        let offset = if in_place {
+            *origin
+        } else {
+            (
+                self.user.cursor_position.x as i32 - image.width as i32 / 2,
+                self.user.cursor_position.y as i32 - image.height as i32 / 2,
+            )
+        };
+        self.floating_paste = Some(FloatingPaste::new(
+            ctx,
+            image.clone(),
+            offset,
+            "Pasted".to_string(),
+        ));
+    }
+
+    /// Samples the color under the cursor using the eyedropper settings, or `previous` where
+    /// there's nothing to pick.
+    fn sample_at_cursor(&self, previous: Rgba) -> Rgba {
+        let layer = if self.user.eyedropper_sample_merged {
+            None
+        } else {
+            Some(self.user.current_layer)
+        };
+        let position = (self.user.cursor_position.x, self.user.cursor_position.y);
+        self.canvas
+            .sample_color(layer, position, self.user.eyedropper_kernel, previous)
     }
 }
 
 impl eframe::App for App {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let mut session = SavedSession::capture(
+            &self.user,
+            self.last_document.clone(),
+            self.reopen_last_document,
+            self.export_settings.clone(),
+            self.show_symmetry_guides,
+            &self.presets,
+            self.single_instance,
+        );
+        session.swatches = self.palette.groups_in(SwatchScope::Global);
+        eframe::set_value(storage, SESSION_KEY, &session);
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        let width = self.canvas.state.width as usize;
-        let height = self.canvas.state.height as usize;
-        for layer in self.canvas.layers().iter_mut() {
-            if layer.is_dirty() || layer.texture.is_none() {
-                layer.texture = Some(ctx.load_texture(
-                    "layer_texture",
-                    egui::ColorImage {
-                        size: [width, height],
-                        pixels: layer.pixels().clone(),
-                    },
-                    egui::TextureOptions::default(),
-                ));
-                layer.mark_clean();
-            }
+        let started = Instant::now();
+        self.view.pixels_per_point = ctx.pixels_per_point();
+        self.handle_forwarded_files(ctx);
+        // new layers need a texture before they can be drawn
+        self.upload_layer_textures(ctx);
+        self.canvas.count_op_stats(self.show_perf);
+        self.show(ctx);
+        // what was painted this frame goes up now rather than at the start of the next one,
+        // so dabs show up in the frame that read the input for them
+        self.upload_layer_textures(ctx);
+        if self.user.holding_pointer_primary || self.user.holding_pointer_right {
+            ctx.request_repaint();
+        }
+
+        self.perf.record(started, ctx.input(|i| i.stable_dt));
+        if let Some(ops) = self.canvas.take_op_stats() {
+            self.perf.record_ops(ops);
         }
+        if self.show_perf {
+            self.perf.show(ctx, &self.canvas.memory_stats());
+        }
+    }
+}
 
+impl App {
+    /// Uploads what changed since the last upload, see [`Canvas::upload_textures`].
+    fn upload_layer_textures(&mut self, ctx: &egui::Context) {
+        let options = self.view.texture_options();
+        self.canvas.upload_textures(ctx, options);
+    }
+
+    fn show(&mut self, ctx: &egui::Context) {
         // Top panel
-        let mut new_brush_radius = self.user.current_paint_brush.radius();
+        let brush_radius = self.user.current_paint_brush.radius();
+        let mut new_brush_radius = brush_radius;
+        // `None` for a hard brush
+        let mut new_brush_falloff = self.user.current_paint_brush.falloff().cloned();
+        // `None` for anything but a soft circle
+        let mut new_brush_hardness = self.user.current_paint_brush.hardness();
+        // width, height and softness, for a rectangular brush
+        let mut new_brush_rectangle = self.user.current_paint_brush.rectangle();
+        let mut new_brush_accumulation = self.user.current_paint_brush.accumulation();
+        let mut new_brush_strength = self.user.current_paint_brush.strength();
+        let mut new_brush_opacity = self.user.current_paint_brush.opacity();
+        let mut new_brush_size_jitter = self.user.current_paint_brush.size_jitter();
+        let mut new_brush_opacity_jitter = self.user.current_paint_brush.opacity_jitter();
+        let mut new_brush_taper_in = self.user.current_paint_brush.taper_in();
+        let mut new_brush_taper_out = self.user.current_paint_brush.taper_out();
+        let mut new_brush_fade_tail = self.user.current_paint_brush.fade_tail();
+        let mut new_brush_flow = self.user.current_paint_brush.flow_per_second();
+        let mut new_brush_min_dab_interval = self.user.current_paint_brush.min_dab_interval();
+        let mut new_brush_pixel_snap = self.user.current_paint_brush.pixel_snap();
+        let mut new_brush_float_buffer = self.user.current_paint_brush.float_buffer();
+        let mut new_brush_secondary = self.user.current_paint_brush.secondary().cloned();
+        let brush_preview = self.brush_preview_texture(ctx);
         let mut new_brush_color = self.user.current_color.to_array();
+        // applied after the settings above, which would otherwise overwrite it
+        let mut picked_preset = None;
+        let mut picked_swatch = None;
         let mut canvas_rect = Rect::NOTHING;
+        let mut canvas_hovered = false;
+        // editing panels are left out while viewing
+        let read_only = self.canvas.is_read_only();
 
         egui::TopBottomPanel::top("controls").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.heading("Brushy");
                 ui.separator();
-                if ui.button("Clear Layer").clicked() {
-                    self.canvas.clear_layer(self.user.current_layer);
-                }
-                if ui.button("Add Layer").clicked() {
-                    self.canvas.add_layer();
+                if read_only {
+                    ui.label(egui::RichText::new("Read-only").strong())
+                        .on_hover_text("Opened for viewing, so it can't be edited");
+                    if ui.button("Export PNG…").clicked() {
+                        self.open_export_dialog();
+                    }
+                } else {
+                    ui.selectable_value(&mut self.user.current_tool, Tool::Brush, "Brush");
+                    ui.selectable_value(&mut self.user.current_tool, Tool::Eraser, "Eraser");
+                    ui.selectable_value(
+                        &mut self.user.current_tool,
+                        Tool::Eyedropper,
+                        "Eyedropper",
+                    );
+                    ui.selectable_value(&mut self.user.current_tool, Tool::Move, "Move");
+                    ui.selectable_value(&mut self.user.current_tool, Tool::Fill, "Fill");
+                    ui.selectable_value(&mut self.user.current_tool, Tool::Shape, "Shape");
+                    ui.selectable_value(&mut self.user.current_tool, Tool::DodgeBurn, "Dodge/Burn");
+                    ui.selectable_value(&mut self.user.current_tool, Tool::Noise, "Noise");
+                    ui.selectable_value(&mut self.user.current_tool, Tool::HueShift, "Hue Shift");
+                    ui.selectable_value(&mut self.user.current_tool, Tool::Clone, "Clone");
+                    if self.user.current_tool == Tool::Brush {
+                        ui.menu_button("Smoothing", |ui| {
+                            let mut enabled = self.user.post_smoothing.is_some();
+                            if ui.checkbox(&mut enabled, "Smooth on Release").changed() {
+                                self.user.post_smoothing = enabled.then_some(2.0);
+                            }
+                            if let Some(tolerance) = &mut self.user.post_smoothing {
+                                ui.add(egui::Slider::new(tolerance, 0.5..=20.0).text("Tolerance"));
+                            }
+                            ui.checkbox(
+                                &mut self.user.curve_between_frames,
+                                "Curve Between Frames",
+                            )
+                            .on_hover_text(
+                                "Curves each part of the stroke on from the one before it, \
+                                 so fast strokes don't turn into straight lines",
+                            );
+                        });
+                        ui.checkbox(&mut self.user.smudge_sample_merged, "Smudge All Layers")
+                            .on_hover_text(
+                                "Right-drag picks up color from every visible layer, \
+                                 and smudges it onto the current one",
+                            );
+                        ui.add(
+                            egui::Slider::new(&mut self.user.smudge_pickup_rate, 0.0..=1.0)
+                                .text("Smudge Pickup"),
+                        )
+                        .on_hover_text("How quickly smudged color mixes with what it passes over");
+                    }
+                    if self.user.current_tool == Tool::Eraser {
+                        let mut eraser_radius = self.user.current_eraser_brush.radius();
+                        if ui
+                            .add(
+                                egui::Slider::new(&mut eraser_radius, User::BRUSH_RADIUS)
+                                    .text("Eraser Size"),
+                            )
+                            .changed()
+                        {
+                            self.user.current_eraser_brush.set_radius(eraser_radius);
+                        }
+                        egui::ComboBox::from_id_salt("eraser_mode")
+                            .selected_text(self.user.eraser_mode.label())
+                            .show_ui(ui, |ui| {
+                                for mode in EraserMode::ALL {
+                                    ui.selectable_value(
+                                        &mut self.user.eraser_mode,
+                                        mode,
+                                        mode.label(),
+                                    );
+                                }
+                            });
+                    }
+                    if self.user.current_tool == Tool::DodgeBurn {
+                        let brush = &mut self.user.current_dodge_burn_brush;
+                        let mut radius = brush.radius();
+                        if ui
+                            .add(egui::Slider::new(&mut radius, User::RETOUCH_RADIUS).text("Size"))
+                            .changed()
+                        {
+                            brush.set_radius(radius);
+                        }
+                        let mut exposure = brush.strength();
+                        if ui
+                            .add(egui::Slider::new(&mut exposure, 0.0..=1.0).text("Exposure"))
+                            .changed()
+                        {
+                            brush.set_strength(exposure);
+                        }
+                        egui::ComboBox::from_id_salt("dodge_burn_mode")
+                            .selected_text(self.user.dodge_burn_mode.label())
+                            .show_ui(ui, |ui| {
+                                for mode in DodgeBurnMode::ALL {
+                                    ui.selectable_value(
+                                        &mut self.user.dodge_burn_mode,
+                                        mode,
+                                        mode.label(),
+                                    );
+                                }
+                            });
+                        egui::ComboBox::from_id_salt("tonal_range")
+                            .selected_text(self.user.tonal_range.label())
+                            .show_ui(ui, |ui| {
+                                for range in TonalRange::ALL {
+                                    ui.selectable_value(
+                                        &mut self.user.tonal_range,
+                                        range,
+                                        range.label(),
+                                    );
+                                }
+                            });
+                    }
+                    if self.user.current_tool == Tool::Noise {
+                        let brush = &mut self.user.current_noise_brush;
+                        let mut radius = brush.radius();
+                        if ui
+                            .add(egui::Slider::new(&mut radius, User::RETOUCH_RADIUS).text("Size"))
+                            .changed()
+                        {
+                            brush.set_radius(radius);
+                        }
+                        let mut strength = brush.strength();
+                        if ui
+                            .add(egui::Slider::new(&mut strength, 0.0..=1.0).text("Strength"))
+                            .changed()
+                        {
+                            brush.set_strength(strength);
+                        }
+                        ui.add(
+                            egui::Slider::new(&mut self.user.noise_scale, 1.0..=32.0)
+                                .text("Grain Size"),
+                        );
+                        egui::ComboBox::from_id_salt("noise_mode")
+                            .selected_text(self.user.noise_mode.label())
+                            .show_ui(ui, |ui| {
+                                for mode in NoiseMode::ALL {
+                                    ui.selectable_value(
+                                        &mut self.user.noise_mode,
+                                        mode,
+                                        mode.label(),
+                                    );
+                                }
+                            });
+                    }
+                    if self.user.current_tool == Tool::HueShift {
+                        let brush = &mut self.user.current_hue_shift_brush;
+                        let mut radius = brush.radius();
+                        if ui
+                            .add(egui::Slider::new(&mut radius, User::RETOUCH_RADIUS).text("Size"))
+                            .changed()
+                        {
+                            brush.set_radius(radius);
+                        }
+                        let shift = &mut self.user.hue_shift;
+                        ui.add(egui::Slider::new(&mut shift.hue, -180.0..=180.0).text("Hue"));
+                        ui.add(
+                            egui::Slider::new(&mut shift.saturation, -1.0..=1.0).text("Saturation"),
+                        );
+                        ui.add(egui::Slider::new(&mut shift.value, -1.0..=1.0).text("Value"));
+                    }
+                    if self.user.current_tool == Tool::Clone {
+                        let brush = &mut self.user.current_clone_brush;
+                        let mut radius = brush.radius();
+                        if ui
+                            .add(egui::Slider::new(&mut radius, User::RETOUCH_RADIUS).text("Size"))
+                            .changed()
+                        {
+                            brush.set_radius(radius);
+                        }
+                        let mut strength = brush.strength();
+                        if ui
+                            .add(egui::Slider::new(&mut strength, 0.0..=1.0).text("Strength"))
+                            .changed()
+                        {
+                            brush.set_strength(strength);
+                        }
+                        ui.label("Alt-click to set the source");
+                    }
+                    if self.user.current_tool == Tool::Eyedropper {
+                        let kernel = &mut self.user.eyedropper_kernel;
+                        egui::ComboBox::from_id_salt("eyedropper_kernel")
+                            .selected_text(kernel.label())
+                            .show_ui(ui, |ui| {
+                                for size in SampleSize::ALL {
+                                    let square = SampleKernel::Square(size);
+                                    ui.selectable_value(kernel, square, size.label());
+                                }
+                                let soft = matches!(kernel, SampleKernel::SoftCircle { .. });
+                                if ui.selectable_label(soft, "Soft Circle").clicked() && !soft {
+                                    *kernel = SampleKernel::SoftCircle { radius: 2.0 };
+                                }
+                            });
+                        if let SampleKernel::SoftCircle { radius } = kernel {
+                            ui.add(egui::Slider::new(radius, 0.0..=10.0).text("Sample Radius"));
+                        }
+                        ui.checkbox(&mut self.user.eyedropper_sample_merged, "Sample Merged");
+                    }
+                    if self.user.current_tool == Tool::Fill {
+                        let fill = &mut self.user.fill;
+                        let layers = &self.canvas.state.layers;
+                        let reference_name = |reference: Option<usize>| match reference {
+                            Some(layer) => layers.get(layer).map_or("?", |l| l.name.as_str()),
+                            None => "Merged",
+                        };
+                        egui::ComboBox::from_id_salt("fill_reference")
+                            .selected_text(format!("Regions: {}", reference_name(fill.reference)))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut fill.reference, None, "Merged");
+                                for layer in (0..layers.len()).rev() {
+                                    let name = reference_name(Some(layer));
+                                    ui.selectable_value(&mut fill.reference, Some(layer), name);
+                                }
+                            })
+                            .response
+                            .on_hover_text(
+                                "Where the regions are found. The fill goes on the current layer",
+                            );
+                        ui.add(egui::Slider::new(&mut fill.tolerance, 0..=255).text("Tolerance"));
+                        ui.checkbox(&mut fill.contiguous, "Contiguous")
+                            .on_hover_text("Fill only the region around the clicked pixel");
+                        ui.add_enabled(
+                            fill.contiguous,
+                            egui::Slider::new(&mut fill.max_gap, 0..=10).text("Close Gaps"),
+                        )
+                        .on_hover_text("Gaps up to this wide in the outline don't leak");
+                        ui.add(egui::Slider::new(&mut fill.grow, 0..=8).text("Grow"))
+                            .on_hover_text("Reach this far under the soft edges around the fill");
+                    }
+                    if self.user.current_tool == Tool::Shape {
+                        let shape = &mut self.user.shape;
+                        egui::ComboBox::from_id_salt("shape_kind")
+                            .selected_text(shape.kind.label())
+                            .show_ui(ui, |ui| {
+                                for kind in ShapeKind::ALL {
+                                    ui.selectable_value(&mut shape.kind, kind, kind.label());
+                                }
+                            });
+                        let mut filled = shape.outline.is_none();
+                        if ui.checkbox(&mut filled, "Filled").changed() {
+                            shape.outline = (!filled).then_some(4.0);
+                        }
+                        if let Some(outline) = &mut shape.outline {
+                            ui.add(egui::Slider::new(outline, 1.0..=100.0).text("Outline Width"));
+                        }
+                    }
+                    ui.separator();
+                    if ui.button("Clear Layer").clicked() {
+                        self.canvas.clear_layer(self.user.current_layer);
+                    }
+                    if ui.button("Add Layer").clicked() {
+                        let result = self.canvas.add_layer().map(|_| ());
+                        self.report_edit_error(result);
+                    }
+                    if ui
+                        .button("Add Stencil")
+                        .on_hover_text("A mask to paint other layers through, kept as a layer")
+                        .clicked()
+                    {
+                        let result = self.canvas.add_stencil_layer().map(|_| ());
+                        self.report_edit_error(result);
+                    }
+                    if ui
+                        .button("Merge Visible")
+                        .on_hover_text(
+                            "Copy the visible layers merged into a new layer above the current one \
+                             (Ctrl+Shift+Alt+E)",
+                        )
+                        .clicked()
+                    {
+                        let result = self.user.merge_visible(&mut self.canvas);
+                        self.report_edit_error(result);
+                    }
+                    if ui.button("Import PNG…").clicked() {
+                        self.import_path = Some(String::new());
+                    }
+                    if ui
+                        .button("Export PNG…")
+                        .on_hover_text("Ctrl+E exports again, Ctrl+Shift+E changes the settings")
+                        .clicked()
+                    {
+                        self.open_export_dialog();
+                    }
+                    ui.checkbox(&mut self.single_instance, "Single Window")
+                        .on_hover_text(
+                            "Open files in the window that's already running rather than a \
+                             new one, from the next start",
+                        );
+                    egui::ComboBox::from_label("Reopen Last")
+                        .selected_text(self.reopen_last_document.label())
+                        .show_ui(ui, |ui| {
+                            for mode in ReopenLastDocument::ALL {
+                                ui.selectable_value(
+                                    &mut self.reopen_last_document,
+                                    mode,
+                                    mode.label(),
+                                );
+                            }
+                        });
+                    ui.menu_button("Edit", |ui| {
+                        if ui.button("Copy").clicked() {
+                            self.copy();
+                            ui.close_menu();
+                        }
+                        if ui
+                            .add_enabled(self.clipboard.is_some(), egui::Button::new("Paste"))
+                            .clicked()
+                        {
+                            self.paste(ctx, false);
+                            ui.close_menu();
+                        }
+                        if ui
+                            .add_enabled(
+                                self.clipboard.is_some(),
+                                egui::Button::new("Paste in Place"),
+                            )
+                            .clicked()
+                        {
+                            self.paste(ctx, true);
+                            ui.close_menu();
+                        }
+                        ui.separator();
+                        ui.label("Paste Into");
+                        for target in PasteTarget::ALL {
+                            ui.radio_value(&mut self.paste_target, target, target.label());
+                        }
+                    });
+                    ui.menu_button("Canvas", |ui| {
+                        let selection = self.canvas.selection().map(|mask| mask.bounds());
+                        if ui
+                            .add_enabled(
+                                selection.is_some(),
+                                egui::Button::new("Crop to Selection"),
+                            )
+                            .clicked()
+                        {
+                            if let Some(rect) = selection {
+                                let rect = LayerBounds::new(
+                                    rect.x as i32,
+                                    rect.y as i32,
+                                    rect.width,
+                                    rect.height,
+                                );
+                                let result = self.user.resize_canvas(&mut self.canvas, rect);
+                                self.report_edit_error(result);
+                            }
+                            ui.close_menu();
+                        }
+                        if ui
+                            .button("Trim to Content")
+                            .on_hover_text("Crop the canvas to what's painted on it")
+                            .clicked()
+                        {
+                            let rect = self.canvas.snapshot().content_bounds();
+                            if !rect.is_empty() {
+                                let result = self.user.resize_canvas(&mut self.canvas, rect);
+                                self.report_edit_error(result);
+                            }
+                            ui.close_menu();
+                        }
+                        if ui
+                            .button("Expand to Layers")
+                            .on_hover_text("Grow the canvas to take in every layer")
+                            .clicked()
+                        {
+                            let (width, height) =
+                                (self.canvas.state.width, self.canvas.state.height);
+                            let rect = self
+                                .canvas
+                                .state
+                                .layers
+                                .iter()
+                                .fold(LayerBounds::canvas(width, height), |rect, layer| {
+                                    rect.union(layer.bounds())
+                                });
+                            let result = self.user.resize_canvas(&mut self.canvas, rect);
+                            self.report_edit_error(result);
+                            ui.close_menu();
+                        }
+                    });
+                    ui.menu_button("Filters", |ui| {
+                        for entry in self.filters.entries() {
+                            if ui.button(format!("{}…", entry.name())).clicked() {
+                                self.adjustment_dialog =
+                                    AdjustmentDialog::open(&mut self.canvas, &self.user, entry);
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                    self.adjusting_brush = ui
+                        .add(
+                            egui::Slider::new(&mut new_brush_radius, User::BRUSH_RADIUS)
+                                .text("Brush Size"),
+                        )
+                        .dragged();
+                    let is_rectangle = new_brush_rectangle.is_some();
+                    let shape_label = if is_rectangle {
+                        "Rectangle"
+                    } else {
+                        new_brush_falloff.as_ref().map_or("Hard", |f| f.label())
+                    };
+                    egui::ComboBox::from_id_salt("brush_falloff")
+                        .selected_text(shape_label)
+                        .show_ui(ui, |ui| {
+                            for falloff in FalloffCurve::PRESETS {
+                                let selected =
+                                    !is_rectangle && new_brush_falloff.as_ref() == Some(&falloff);
+                                if ui.selectable_label(selected, falloff.label()).clicked() {
+                                    new_brush_falloff = Some(falloff);
+                                    new_brush_rectangle = None;
+                                }
+                            }
+                            let is_curve = !is_rectangle
+                                && matches!(new_brush_falloff, Some(FalloffCurve::Points(_)));
+                            if ui.selectable_label(is_curve, "Curve").clicked() && !is_curve {
+                                new_brush_falloff =
+                                    Some(FalloffCurve::Points(CurvePoints::default()));
+                                new_brush_rectangle = None;
+                            }
+                            let is_hard = !is_rectangle && new_brush_falloff.is_none();
+                            if ui.selectable_label(is_hard, "Hard").clicked() {
+                                new_brush_falloff = None;
+                                new_brush_rectangle = None;
+                            }
+                            if ui.selectable_label(is_rectangle, "Rectangle").clicked()
+                                && !is_rectangle
+                            {
+                                let side = brush_radius * 2.0;
+                                new_brush_rectangle = Some((side, side, 0.0));
+                            }
+                        });
+                    if let Some((width, height, softness)) = &mut new_brush_rectangle {
+                        ui.add(egui::Slider::new(width, 1.0..=40.0).text("Width"));
+                        ui.add(egui::Slider::new(height, 1.0..=40.0).text("Height"));
+                        ui.add(egui::Slider::new(softness, 0.0..=10.0).text("Softness"));
+                    }
+                    if let Some(hardness) = &mut new_brush_hardness {
+                        ui.add(egui::Slider::new(hardness, 0.0..=1.0).text("Hardness"))
+                            .on_hover_text("How far out from the center the brush is solid");
+                    }
+                    egui::ComboBox::from_id_salt("brush_accumulation")
+                        .selected_text(new_brush_accumulation.label())
+                        .show_ui(ui, |ui| {
+                            for accumulation in StrokeAccumulation::ALL {
+                                ui.selectable_value(
+                                    &mut new_brush_accumulation,
+                                    accumulation,
+                                    accumulation.label(),
+                                );
+                            }
+                        });
+                    ui.add(egui::Slider::new(&mut new_brush_strength, 0.01..=1.0).text("Flow"))
+                        .on_hover_text("How much each dab lays down");
+                    ui.add(egui::Slider::new(&mut new_brush_opacity, 0.01..=1.0).text("Opacity"))
+                        .on_hover_text(
+                            "The most a single stroke lays down, however often it overlaps",
+                        );
+                    ui.checkbox(&mut new_brush_pixel_snap, "Pixel Snap")
+                        .on_hover_text("Hard, single-pixel dabs on whole pixels, for pixel art");
+                    ui.checkbox(&mut new_brush_float_buffer, "Float Buffer")
+                        .on_hover_text("Build up faint dabs smoothly, without banding");
+                    ui.menu_button("Presets", |ui| {
+                        picked_preset = self.preset_picker.show(
+                            ui,
+                            &mut self.presets,
+                            &self.user.current_paint_brush,
+                        );
+                    });
+                    if let Some(library) = &mut self.library {
+                        ui.menu_button("Library", |ui| {
+                            if let Some(brush) = self.library_picker.show(
+                                ui,
+                                library,
+                                &self.user.current_paint_brush,
+                            ) {
+                                picked_preset = Some(brush);
+                            }
+                        });
+                    }
+                    ui.menu_button("Dynamics", |ui| {
+                        let mut fade_tail_enabled = new_brush_fade_tail.is_some();
+                        if ui.checkbox(&mut fade_tail_enabled, "Fade Tail").changed() {
+                            new_brush_fade_tail = fade_tail_enabled.then(FadeTail::default);
+                        }
+                        if let Some(fade_tail) = &mut new_brush_fade_tail {
+                            ui.add(
+                                egui::Slider::new(&mut fade_tail.length, 5.0..=200.0)
+                                    .text("Tail Length"),
+                            );
+                            ui.add(
+                                egui::Slider::new(&mut fade_tail.min_speed, 0.0..=2000.0)
+                                    .text("Min Speed"),
+                            );
+                        }
+                        let mut airbrush = new_brush_flow.is_some();
+                        if ui
+                            .checkbox(&mut airbrush, "Airbrush")
+                            .on_hover_text("Keep building up paint while held still")
+                            .changed()
+                        {
+                            new_brush_flow = airbrush.then_some(10.0);
+                        }
+                        if let Some(flow) = &mut new_brush_flow {
+                            ui.add(egui::Slider::new(flow, 1.0..=60.0).text("Dabs per Second"));
+                        }
+                        let mut limit_dab_rate = new_brush_min_dab_interval.is_some();
+                        if ui
+                            .checkbox(&mut limit_dab_rate, "Limit Dab Rate")
+                            .on_hover_text(
+                                "Space dabs out in time as well, so fast strokes paint thinner",
+                            )
+                            .changed()
+                        {
+                            new_brush_min_dab_interval = limit_dab_rate.then_some(0.05);
+                        }
+                        if let Some(interval) = &mut new_brush_min_dab_interval {
+                            ui.add(
+                                egui::Slider::new(interval, 0.01..=0.5)
+                                    .text("Seconds Between Dabs"),
+                            );
+                        }
+                        ui.add(
+                            egui::Slider::new(&mut new_brush_size_jitter, 0.0..=1.0)
+                                .text("Size Jitter"),
+                        );
+                        ui.add(
+                            egui::Slider::new(&mut new_brush_opacity_jitter, 0.0..=1.0)
+                                .text("Opacity Jitter"),
+                        );
+                        ui.add(
+                            egui::Slider::new(&mut new_brush_taper_in, 0.0..=200.0)
+                                .text("Taper In"),
+                        )
+                        .on_hover_text("How far strokes grow from a point to full size");
+                        ui.add(
+                            egui::Slider::new(&mut new_brush_taper_out, 0.0..=200.0)
+                                .text("Taper Out"),
+                        )
+                        .on_hover_text(
+                            "How far strokes shrink back to a point, shown when they're finished",
+                        );
+                        let mut dual_tip = new_brush_secondary.is_some();
+                        if ui
+                            .checkbox(&mut dual_tip, "Dual Tip")
+                            .on_hover_text("Break up the brush with a second, smaller tip")
+                            .changed()
+                        {
+                            new_brush_secondary = dual_tip.then(|| SecondaryTip {
+                                brush: Box::new(Brush::default().with_radius(3.0)),
+                                placement: SecondaryPlacement::Scattered,
+                            });
+                        }
+                        if let Some(secondary) = &mut new_brush_secondary {
+                            let mut radius = secondary.brush.radius();
+                            if ui
+                                .add(egui::Slider::new(&mut radius, 1.0..=20.0).text("Tip Size"))
+                                .changed()
+                            {
+                                secondary.brush.set_radius(radius);
+                            }
+                            let mut scattered =
+                                secondary.placement == SecondaryPlacement::Scattered;
+                            if ui
+                                .checkbox(&mut scattered, "Scatter Tip")
+                                .on_hover_text(
+                                    "Move the tip at random on each dab instead of tiling it",
+                                )
+                                .changed()
+                            {
+                                secondary.placement = match scattered {
+                                    true => SecondaryPlacement::Scattered,
+                                    false => SecondaryPlacement::Tiled,
+                                };
+                            }
+                        }
+                    });
+                    ui.menu_button("Symmetry", |ui| {
+                        let symmetry = &mut self.user.symmetry;
+                        ui.checkbox(&mut symmetry.enabled, "Enabled");
+                        for mode in SymmetryMode::ALL {
+                            ui.radio_value(&mut symmetry.mode, mode, mode.label());
+                        }
+                        if symmetry.mode == SymmetryMode::Radial {
+                            ui.add(
+                                egui::Slider::new(&mut symmetry.segments, Symmetry::SEGMENTS)
+                                    .text("Segments"),
+                            );
+                        }
+                        ui.checkbox(&mut self.show_symmetry_guides, "Show Guides")
+                            .on_hover_text("Drag the center handle to move the axes");
+                        if ui.button("Center on Canvas").clicked() {
+                            let (width, height) =
+                                (self.canvas.state.width, self.canvas.state.height);
+                            symmetry.center = Symmetry::centered(width, height).center;
+                        }
+                    });
+                    ui.image((
+                        brush_preview,
+                        Vec2::new(
+                            brush_preview::PREVIEW_WIDTH as f32,
+                            brush_preview::PREVIEW_HEIGHT as f32,
+                        ),
+                    ));
+                    ui.color_edit_button_rgba_unmultiplied(&mut new_brush_color);
+                    ui.menu_button("Swatches", |ui| {
+                        picked_swatch =
+                            self.swatch_picker
+                                .show(ui, &mut self.palette, self.user.current_color);
+                    });
+                    let mut background_color = [
+                        self.user.background_color.r(),
+                        self.user.background_color.g(),
+                        self.user.background_color.b(),
+                    ];
+                    if ui
+                        .color_edit_button_rgb(&mut background_color)
+                        .on_hover_text("Background color, used when erasing to the background")
+                        .changed()
+                    {
+                        let [r, g, b] = background_color;
+                        self.user.background_color = Rgba::from_rgb(r, g, b);
+                    }
                 }
-                ui.add(egui::Slider::new(&mut new_brush_radius, 1.0..=20.0).text("Brush Size"));
-                ui.color_edit_button_rgba_unmultiplied(&mut new_brush_color);
                 ui.separator();
                 ui.label("View:");
                 if ui.button("Reset View").clicked() {
-                    self.view = ViewState::default();
+                    self.view = ViewState {
+                        pixels_per_point: self.view.pixels_per_point,
+                        smooth: self.view.smooth,
+                        ..ViewState::default()
+                    };
                 }
                 ui.add(egui::Slider::new(&mut self.view.zoom, 0.1..=10.0).text("Zoom"));
+                if ui.checkbox(&mut self.view.smooth, "Smooth").changed() {
+                    // re-upload the composite and layers with the new filtering
+                    self.canvas.forget_textures();
+                }
+                ui.checkbox(&mut self.rulers.visible, "Rulers");
+                ui.checkbox(&mut self.show_perf, "Performance");
+                ui.checkbox(&mut self.show_document_info, "Document Info");
+                ui.checkbox(&mut self.show_history, "History");
+                if ui
+                    .button("Snapshot")
+                    .on_hover_text("Hold \\ to compare the canvas with it")
+                    .clicked()
+                {
+                    let options = self.view.texture_options();
+                    self.compare.take(ctx, &self.canvas, options);
+                }
+                if self.compare.has_snapshot() && ui.button("Drop Snapshot").clicked() {
+                    self.compare.clear();
+                }
+                if !self.rulers.guides.is_empty() && ui.button("Clear Guides").clicked() {
+                    self.rulers.guides.clear();
+                }
+            });
+        });
+
+        // Falloff curve editor, shown while the brush uses a control-point curve
+        if let Some(FalloffCurve::Points(points)) = &mut new_brush_falloff {
+            egui::Window::new("Falloff Curve")
+                .resizable(false)
+                .show(ctx, |ui| {
+                    curve_editor::curve_editor(ui, points);
+                    ui.label("Drag to move, double-click to add, right-click to remove.");
+                });
+        }
+
+        // Import window
+        if let Some(path) = &mut self.import_path {
+            let mut import = false;
+            let mut cancel = false;
+            egui::Window::new("Import PNG")
+                .resizable(false)
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Path:");
+                        ui.text_edit_singleline(path);
+                    });
+                    ui.horizontal(|ui| {
+                        import = ui.button("Import").clicked();
+                        cancel = ui.button("Cancel").clicked();
+                    });
+                });
+            if import {
+                let path = PathBuf::from(path.as_str());
+                self.import_png(ctx, &path);
+            }
+            if import || cancel {
+                self.import_path = None;
+            }
+        }
+
+        // Export dialog
+        if let Some(dialog) = &mut self.export_dialog {
+            let canvas_size = (self.canvas.state.width, self.canvas.state.height);
+            if let Some(result) = dialog.show(ctx, canvas_size) {
+                self.export_dialog = None;
+                if let ExportDialogResult::Export(settings) = result {
+                    self.export(settings);
+                }
+            }
+        }
+
+        self.poll_export(ctx);
+        if let Some(toast) = &self.toast {
+            if !toast.show(ctx) {
+                self.toast = None;
+            }
+        }
+
+        // Reopen prompt
+        if let Some(path) = &self.reopen_prompt {
+            let mut reopen = false;
+            let mut dismiss = false;
+            egui::Window::new("Reopen Last Document")
+                .resizable(false)
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.label(format!("Reopen {}?", path.display()));
+                    ui.horizontal(|ui| {
+                        reopen = ui.button("Reopen").clicked();
+                        dismiss = ui.button("New Document").clicked();
+                    });
+                });
+            if reopen || dismiss {
+                let path = self.reopen_prompt.take().expect("checked above");
+                if reopen {
+                    self.open_document(&path);
+                }
+            }
+        }
+
+        if let Some(message) = &self.size_error {
+            let mut dismiss = false;
+            egui::Window::new("Unusable Size")
+                .resizable(false)
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.label(message);
+                    dismiss = ui.button("OK").clicked();
+                });
+            if dismiss {
+                self.size_error = None;
+            }
+        }
+
+        if self.show_document_info {
+            let stats = self.canvas.stats();
+            document_info::show(ctx, &mut self.show_document_info, &stats);
+        }
+        if self.show_history {
+            self.history_panel.show(
+                ctx,
+                &mut self.show_history,
+                &mut self.user,
+                &mut self.canvas,
+            );
+        }
+
+        // Adjustment dialogs
+        if let Some(dialog) = &mut self.adjustment_dialog {
+            if !dialog.show(ctx, &mut self.canvas, &mut self.user) {
+                self.adjustment_dialog = None;
+            }
+        }
+        self.user
+            .clamp_current_layer(self.canvas.state.layers.len());
+
+        // strokes would be lost when a dialog restores its snapshot of the layer
+        let dialog_open = self.adjustment_dialog.is_some() || self.export_dialog.is_some();
+
+        // Status bar
+        let cursor_position = self.user.cursor_position;
+        // anything picked has some alpha, so transparent means there was nothing to pick
+        let sampled_color =
+            Some(self.sample_at_cursor(Rgba::TRANSPARENT)).filter(|color| color.a() > 0.0);
+        egui::TopBottomPanel::bottom("status").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "{:.0}, {:.0}",
+                    cursor_position.x.floor(),
+                    cursor_position.y.floor()
+                ));
+                ui.separator();
+                match sampled_color {
+                    Some(color) => {
+                        let [r, g, b, a] = alpha::brush_color_to_srgba(color);
+                        let (rect, _) =
+                            ui.allocate_exact_size(Vec2::splat(12.0), egui::Sense::hover());
+                        ui.painter().rect_filled(
+                            rect,
+                            0.0,
+                            Color32::from_rgba_unmultiplied(r, g, b, 255),
+                        );
+                        ui.label(format!("#{:02X}{:02X}{:02X} α {}", r, g, b, a));
+                    }
+                    None => {
+                        ui.label("Transparent");
+                    }
+                }
+                if let Some(message) = &self.status_message {
+                    ui.separator();
+                    ui.label(message);
+                }
+                if let Some((_, task)) = &self.export_task {
+                    ui.separator();
+                    let progress = task.progress();
+                    ui.add(
+                        egui::ProgressBar::new(progress.fraction)
+                            .desired_width(160.0)
+                            .text(format!("Exporting: {}", progress.message)),
+                    );
+                    if ui.small_button("Cancel").clicked() {
+                        task.cancel();
+                    }
+                }
             });
         });
 
@@ -112,26 +1505,206 @@ impl eframe::App for App {
             ui.heading("Layers");
             ui.separator();
 
+            // (layer, threshold) to build a selection from, once the layers aren't borrowed
+            let mut select_opaque = None;
+            let mut deselect = false;
+            let has_selection = self.canvas.selection().is_some();
             for (i, layer) in self.canvas.layers().iter_mut().enumerate().rev() {
                 ui.horizontal(|ui| {
                     ui.checkbox(&mut layer.visible, "");
-                    if ui
+                    if !read_only {
+                        ui.toggle_value(&mut layer.lock_pixels, "🔒")
+                            .on_hover_text("Lock pixels");
+                        ui.toggle_value(&mut layer.lock_alpha, "α")
+                            .on_hover_text("Lock alpha");
+                        ui.toggle_value(&mut layer.auto_grow, "↔")
+                            .on_hover_text("Grow when painted outside, rather than clip");
+                    }
+                    if layer.kind() == LayerKind::Color {
+                        let percent = (layer.opacity * 100.0).round();
+                        ui.add_sized(
+                            [60.0, ui.spacing().interact_size.y],
+                            egui::Slider::new(&mut layer.opacity, 0.0..=1.0).show_value(false),
+                        )
+                        .on_hover_text(format!("Opacity {percent}%"));
+                        egui::ComboBox::from_id_salt(("blend_mode", i))
+                            .selected_text(layer.blend_mode.label())
+                            .width(80.0)
+                            .show_ui(ui, |ui| {
+                                for mode in LayerBlendMode::ALL {
+                                    ui.selectable_value(&mut layer.blend_mode, mode, mode.label());
+                                }
+                            })
+                            .response
+                            .on_hover_text("Blend mode");
+                    }
+                    if layer.kind() == LayerKind::Stencil {
+                        let mut active = self.user.stencil == Some(i);
+                        if ui
+                            .toggle_value(&mut active, "◐")
+                            .on_hover_text("Paint other layers through this stencil")
+                            .changed()
+                        {
+                            self.user.stencil = active.then_some(i);
+                        }
+                    }
+                    let response = ui
                         .selectable_label(self.user.current_layer == i, &layer.name)
-                        .clicked()
-                    {
-                        self.user.current_layer = i;
+                        .on_hover_text("Ctrl+click to select opaque pixels");
+                    if response.clicked() {
+                        if ui.input(|input| input.modifiers.command) {
+                            select_opaque = Some((i, None));
+                        } else {
+                            self.user.current_layer = i;
+                        }
                     }
+                    response.context_menu(|ui| {
+                        if ui.button("Select Opaque").clicked() {
+                            select_opaque = Some((i, None));
+                            ui.close_menu();
+                        }
+                        if ui.button("Select Opaque (Hard Edge)").clicked() {
+                            select_opaque = Some((i, Some(128)));
+                            ui.close_menu();
+                        }
+                        if ui
+                            .add_enabled(has_selection, egui::Button::new("Deselect"))
+                            .clicked()
+                        {
+                            deselect = true;
+                            ui.close_menu();
+                        }
+                    });
                 });
             }
+
+            if let Some((layer, threshold)) = select_opaque {
+                let selection = self.canvas.select_opaque(layer, threshold);
+                let result = self.user.select(&mut self.canvas, selection);
+                self.report_edit_error(result);
+            }
+            if deselect {
+                let result = self.user.select(&mut self.canvas, None);
+                self.report_edit_error(result);
+            }
         });
 
+        // Apply state updates
+        match new_brush_rectangle {
+            Some((width, height, softness)) => self
+                .user
+                .current_paint_brush
+                .set_rectangle(width, height, softness),
+            None => self.user.current_paint_brush.set_shape(new_brush_falloff),
+        }
+        // a rectangle's radius follows its sides, so only a moved size slider resizes it
+        if new_brush_radius != brush_radius {
+            self.user.current_paint_brush.set_radius(new_brush_radius);
+        }
+        if let Some(hardness) = new_brush_hardness {
+            self.user.current_paint_brush.set_hardness(hardness);
+        }
+        self.user
+            .current_paint_brush
+            .set_accumulation(new_brush_accumulation);
+        self.user
+            .current_paint_brush
+            .set_strength(new_brush_strength);
+        self.user.current_paint_brush.set_opacity(new_brush_opacity);
+        self.user
+            .current_paint_brush
+            .set_size_jitter(new_brush_size_jitter);
+        self.user
+            .current_paint_brush
+            .set_opacity_jitter(new_brush_opacity_jitter);
+        self.user
+            .current_paint_brush
+            .set_taper_in(new_brush_taper_in);
+        self.user
+            .current_paint_brush
+            .set_taper_out(new_brush_taper_out);
+        self.user
+            .current_paint_brush
+            .set_fade_tail(new_brush_fade_tail);
+        self.user
+            .current_paint_brush
+            .set_flow_per_second(new_brush_flow);
+        self.user
+            .current_paint_brush
+            .set_min_dab_interval(new_brush_min_dab_interval);
+        self.user
+            .current_paint_brush
+            .set_pixel_snap(new_brush_pixel_snap);
+        self.user
+            .current_paint_brush
+            .set_float_buffer(new_brush_float_buffer);
+        self.user
+            .current_paint_brush
+            .set_secondary(new_brush_secondary);
+        self.user.current_color = Rgba::from_rgba_premultiplied(
+            new_brush_color[RED_CHANNEL],
+            new_brush_color[GREEN_CHANNEL],
+            new_brush_color[BLUE_CHANNEL],
+            new_brush_color[ALPHA_CHANNEL],
+        );
+        if let Some(brush) = picked_preset {
+            self.user.current_paint_brush = brush;
+        }
+        if let Some(color) = picked_swatch {
+            self.user.current_color = color;
+        }
+
+        self.update_stroke_preview(ctx);
+        self.update_selection_overlay(ctx);
+
+        if self.compare.drop_if_stale(&self.canvas) {
+            self.status_message =
+                Some("The canvas changed size, take a new snapshot to compare with".to_string());
+        }
+        // flipping to the snapshot mid-stroke would hide what's being painted
+        let comparing = self.compare.has_snapshot()
+            && !self.user.is_stroking()
+            && !ctx.wants_keyboard_input()
+            && ctx.input(|i| i.key_down(egui::Key::Backslash));
+
         // Main canvas area
         egui::CentralPanel::default().show(ctx, |ui| {
             let available_size = ui.available_size();
-            canvas_rect = Rect::from_min_size(ui.cursor().min, available_size);
+            canvas_rect = self
+                .rulers
+                .canvas_area(Rect::from_min_size(ui.cursor().min, available_size));
 
             // Handle canvas panning
             let response = ui.allocate_rect(canvas_rect, egui::Sense::drag());
+            canvas_hovered = response.hovered() && !dialog_open;
+
+            // the symmetry center handle takes the pointer from the canvas while it's over it
+            let origin = self.view.origin(canvas_rect);
+            let scale = self.view.scale();
+            let symmetry_guides = self.user.symmetry.enabled && self.show_symmetry_guides;
+            let on_handle = response
+                .hover_pos()
+                .is_some_and(|pos| self.user.symmetry.handle_hit(origin, scale, pos));
+            if symmetry_guides && canvas_hovered && on_handle && !self.user.is_stroking() {
+                self.dragging_symmetry_center |= ui.input(|i| i.pointer.primary_pressed());
+            }
+            if self.dragging_symmetry_center {
+                match ui.input(|i| i.pointer.primary_down().then(|| i.pointer.hover_pos())) {
+                    Some(pointer_pos) => {
+                        if let Some(pointer_pos) = pointer_pos {
+                            let pos = self.view.screen_to_canvas(pointer_pos, canvas_rect);
+                            let (width, height) =
+                                (self.canvas.state.width, self.canvas.state.height);
+                            self.user.symmetry.move_center(pos, width, height);
+                        }
+                    }
+                    None => self.dragging_symmetry_center = false,
+                }
+            }
+            if symmetry_guides && (self.dragging_symmetry_center || on_handle) {
+                ctx.set_cursor_icon(egui::CursorIcon::Grab);
+                canvas_hovered = false;
+            }
             if response.dragged_by(egui::PointerButton::Middle) {
                 if self.last_drag_pos.is_some() {
                     let delta = response.drag_delta();
@@ -148,98 +1721,365 @@ impl eframe::App for App {
             if let Some(hover_pos) = response.hover_pos() {
                 let zoom_delta = ui.input(|i| i.raw_scroll_delta.y / 200.0);
                 if zoom_delta != 0.0 {
-                    let old_zoom = self.view.zoom;
-                    self.view.zoom = (self.view.zoom * (1.0 + zoom_delta)).clamp(0.1, 10.0);
-
-                    let zoom_center = hover_pos - canvas_rect.min - self.view.offset;
-                    let zoom_factor = self.view.zoom / old_zoom;
-                    let new_center = zoom_center * zoom_factor;
-                    self.view.offset += zoom_center - new_center;
+                    self.view
+                        .zoom_around(hover_pos, 1.0 + zoom_delta, canvas_rect);
                 }
             }
 
             // Draw all visible layers
-            let texture_size = Vec2::new(
-                self.canvas.state.width as f32 * self.view.zoom,
-                self.canvas.state.height as f32 * self.view.zoom,
-            );
+            let canvas_size = self
+                .view
+                .canvas_size(self.canvas.state.width, self.canvas.state.height);
+            // layers can hang off the edges of the canvas
+            let layer_painter = ui
+                .painter()
+                .with_clip_rect(Rect::from_min_size(origin, canvas_size).intersect(canvas_rect));
 
-            for layer in self.canvas.layers().iter().filter(|l| l.visible) {
-                if let Some(texture) = &layer.texture {
-                    ui.painter().image(
+            let compare_snapshot = self.compare.shown(comparing);
+            if let Some(snapshot) = compare_snapshot {
+                snapshot.paint(&layer_painter, origin, scale);
+            }
+            let live_layers = compare_snapshot.is_none();
+            let whole = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+            if let Some(texture) = self.canvas.composite_texture().filter(|_| live_layers) {
+                layer_painter.image(
+                    texture.id(),
+                    Rect::from_min_size(origin, canvas_size),
+                    whole,
+                    Color32::WHITE,
+                );
+            }
+            // stencils aren't part of the image, so they're tinted over it
+            for (index, layer) in self
+                .canvas
+                .state
+                .layers
+                .iter()
+                .enumerate()
+                .filter(|(_, l)| live_layers && l.visible && l.kind() == LayerKind::Stencil)
+            {
+                if let Some(texture) = self.canvas.layer_texture(index) {
+                    let bounds = layer.bounds();
+                    let min = origin + Vec2::new(bounds.x as f32, bounds.y as f32) * scale;
+                    let size = Vec2::new(bounds.width as f32, bounds.height as f32) * scale;
+                    layer_painter.image(
                         texture.id(),
-                        Rect::from_min_size(canvas_rect.min + self.view.offset, texture_size),
-                        Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
-                        Color32::WHITE,
+                        Rect::from_min_size(min, size),
+                        whole,
+                        STENCIL_TINT,
                     );
                 }
             }
+
+            if let Some((_, overlay)) = &self.selection_overlay {
+                overlay.paint(ui.painter(), origin, scale);
+            }
+
+            if let Some(paste) = &self.floating_paste {
+                paste.overlay().paint(ui.painter(), origin, scale);
+            }
+
+            self.rulers
+                .paint_guides(ui.painter(), canvas_rect, origin, scale);
+            if symmetry_guides && !comparing {
+                let (width, height) = (self.canvas.state.width, self.canvas.state.height);
+                self.user
+                    .symmetry
+                    .paint_guides(ui.painter(), origin, scale, width, height);
+            }
+            let cursor = canvas_hovered.then_some(self.user.cursor_position);
+            self.rulers.show(ui, canvas_rect, origin, scale, cursor);
+
+            // Stroke preview, pinned to the top left of the visible canvas area
+            let preview_position =
+                self.screen_to_canvas(canvas_rect.min + Vec2::splat(16.0), canvas_rect);
+            if let Some((_, _, overlay)) = &mut self.stroke_preview {
+                overlay.position = preview_position;
+                overlay.paint(ui.painter(), origin, scale);
+            }
+
+            // The shape being drawn, until it's released
+            if let Some((from, to)) = self.user.shape_corners() {
+                let rect = Rect::from_two_pos(
+                    self.view.canvas_to_screen(from, canvas_rect),
+                    self.view.canvas_to_screen(to, canvas_rect),
+                );
+                let [r, g, b, a] = alpha::brush_color_to_srgba(self.user.current_color);
+                let color = Color32::from_rgba_unmultiplied(r, g, b, a);
+                let shape = self.user.shape;
+                // outlines are drawn inside the shape, so the preview's stroke is too
+                let (rect, fill, stroke) = match shape.outline {
+                    Some(outline) => {
+                        let width = outline * scale;
+                        let rect = rect.shrink((width / 2.0).min(rect.size().min_elem() / 2.0));
+                        (rect, Color32::TRANSPARENT, egui::Stroke::new(width, color))
+                    }
+                    None => (rect, color, egui::Stroke::NONE),
+                };
+                let preview = match shape.kind {
+                    ShapeKind::Rectangle => egui::Shape::rect_filled(rect, 0.0, fill),
+                    ShapeKind::Ellipse => {
+                        egui::Shape::ellipse_filled(rect.center(), rect.size() / 2.0, fill)
+                    }
+                };
+                ui.painter().add(preview);
+                let outline = match shape.kind {
+                    ShapeKind::Rectangle => egui::Shape::rect_stroke(rect, 0.0, stroke),
+                    ShapeKind::Ellipse => {
+                        egui::Shape::ellipse_stroke(rect.center(), rect.size() / 2.0, stroke)
+                    }
+                };
+                ui.painter().add(outline);
+            }
+
+            // Brush outline
+            let outline_brush = match self.user.effective_tool() {
+                Tool::Brush => Some(&self.user.current_paint_brush),
+                Tool::Eraser => Some(&self.user.current_eraser_brush),
+                Tool::DodgeBurn => Some(&self.user.current_dodge_burn_brush),
+                Tool::Noise => Some(&self.user.current_noise_brush),
+                Tool::HueShift => Some(&self.user.current_hue_shift_brush),
+                Tool::Clone => Some(&self.user.current_clone_brush),
+                Tool::Eyedropper | Tool::Move | Tool::Fill | Tool::Shape => None,
+            };
+            if let Some(brush) = outline_brush.filter(|_| canvas_hovered) {
+                let cursor = self
+                    .view
+                    .canvas_to_screen(self.user.cursor_position, canvas_rect);
+                let outline = egui::Stroke::new(1.0, Color32::from_gray(128));
+                ui.painter()
+                    .circle_stroke(cursor, brush.radius() * scale, outline);
+
+                // the line a shift-click would stroke
+                let shift = ui.input(|i| i.modifiers.shift);
+                let line_start = self.user.line_start(&self.canvas);
+                if let Some(start) = line_start.filter(|_| shift && !self.user.is_stroking()) {
+                    let start = self.view.canvas_to_screen(start, canvas_rect);
+                    ui.painter().line_segment([start, cursor], outline);
+                }
+
+                // where the clone tool copies from
+                let cloning = self.user.effective_tool() == Tool::Clone;
+                if let Some(source) = self.user.clone_source(&self.canvas).filter(|_| cloning) {
+                    let source = self.view.canvas_to_screen(source, canvas_rect);
+                    ui.painter()
+                        .circle_stroke(source, brush.radius() * scale, outline);
+                    for arm in [Vec2::new(4.0, 0.0), Vec2::new(0.0, 4.0)] {
+                        ui.painter()
+                            .line_segment([source - arm, source + arm], outline);
+                    }
+                }
+            }
         });
 
-        // Apply state updates
-        self.user.current_paint_brush.set_radius(new_brush_radius);
-        self.user.current_color = Rgba::from_rgba_premultiplied(
-            new_brush_color[RED_CHANNEL],
-            new_brush_color[GREEN_CHANNEL],
-            new_brush_color[BLUE_CHANNEL],
-            new_brush_color[ALPHA_CHANNEL],
-        );
+        // Command palette: takes over the keyboard while it's open
+        let enabled: Vec<_> = (self.actions.actions().iter())
+            .map(|action| action.is_enabled(self))
+            .collect();
+        if let Some(index) = self.command_palette.show(ctx, &self.actions, &enabled) {
+            self.run_action(ctx, index);
+        }
+        if self.command_palette.is_open() {
+            return;
+        }
+
+        // Floating paste: takes over the canvas until it's committed or cancelled
+        if self.floating_paste.is_some() {
+            if let Some(pointer_pos) = ctx.pointer_hover_pos() {
+                self.user.cursor_position = self.screen_to_canvas(pointer_pos, canvas_rect);
+            }
+        }
+        if let Some(paste) = &mut self.floating_paste {
+            let cursor_position = self.user.cursor_position;
+            let (commit, cancel) = ctx.input(|i| {
+                let step = if i.modifiers.shift { 10 } else { 1 };
+                for (key, dx, dy) in [
+                    (egui::Key::ArrowLeft, -step, 0),
+                    (egui::Key::ArrowRight, step, 0),
+                    (egui::Key::ArrowUp, 0, -step),
+                    (egui::Key::ArrowDown, 0, step),
+                ] {
+                    if i.key_pressed(key) {
+                        paste.nudge(dx, dy);
+                    }
+                }
+
+                if i.pointer.primary_pressed() && canvas_hovered {
+                    paste.grab(cursor_position);
+                }
+                if i.pointer.primary_down() {
+                    paste.drag_to(cursor_position);
+                }
+                let clicked = i.pointer.primary_released() && paste.release();
+                (
+                    clicked || i.key_pressed(egui::Key::Enter),
+                    i.key_pressed(egui::Key::Escape),
+                )
+            });
+
+            if commit {
+                if let Some(paste) = self.floating_paste.take() {
+                    let result = paste.commit(&mut self.canvas, &mut self.user, self.paste_target);
+                    self.report_edit_error(result);
+                }
+            } else if cancel {
+                self.floating_paste = None;
+            }
+            return;
+        }
+
+        // text fields keep their own shortcuts, such as ctrl+Z
+        if !ctx.wants_keyboard_input() {
+            if let Some(index) = self.actions.pressed(ctx) {
+                self.run_action(ctx, index);
+            }
+        }
 
         // Handle painting
+        let mut paste_request = None;
         if let Some(pointer_pos) = ctx.pointer_hover_pos() {
             if !self.dragging_canvas {
                 self.user.cursor_position = self.screen_to_canvas(pointer_pos, canvas_rect);
 
                 ctx.input(|i| {
-                    if i.modifiers.ctrl || i.modifiers.command {
-                        if i.key_pressed(egui::Key::Z) {
-                            self.user.undo(&mut self.canvas);
-                        }
-                        if i.key_pressed(egui::Key::Y) {
-                            self.user.redo(&mut self.canvas);
-                        }
-                        if i.key_pressed(egui::Key::S) {
-                            let now_str = std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs()
-                                .to_string();
-                            if let Err(e) = self
-                                .canvas
-                                .save_as_png(format!("painting_{}.png", now_str).as_str())
-                            {
-                                error!("Error saving canvas as PNG: {:?}", e);
-                            }
+                    self.user.update_tool_override(i.modifiers);
+
+                    // egui turns ctrl+C and ctrl+V into copy and paste events rather than key
+                    // presses, and only sends the paste event when the system clipboard holds
+                    // text, so the Edit menu is the reliable way to paste
+                    for event in &i.events {
+                        match event {
+                            egui::Event::Copy => self.copy(),
+                            // shift pastes in place
+                            egui::Event::Paste(_) => paste_request = Some(i.modifiers.shift),
+                            _ => {}
                         }
                     }
 
-                    if i.pointer.primary_pressed() {
-                        self.user.holding_pointer_primary = true;
-                        self.user.start_brush_stroke(user::BrushStrokeKind::Paint);
+                    // nothing to draw with while viewing
+                    let can_edit = !self.canvas.is_read_only();
+                    if i.pointer.primary_pressed() && canvas_hovered && can_edit {
+                        match self.user.press_primary() {
+                            Tool::Clone if i.modifiers.alt => {
+                                self.user.set_clone_anchor(&self.canvas)
+                            }
+                            tool @ (Tool::Brush
+                            | Tool::Eraser
+                            | Tool::DodgeBurn
+                            | Tool::Noise
+                            | Tool::HueShift
+                            | Tool::Clone) => {
+                                let kind = match tool {
+                                    Tool::Brush => Some(BrushStrokeKind::Paint),
+                                    Tool::Eraser => {
+                                        Some(self.user.eraser_stroke_kind(&self.canvas))
+                                    }
+                                    Tool::DodgeBurn => Some(self.user.dodge_burn_stroke_kind()),
+                                    Tool::Noise => Some(self.user.noise_stroke_kind()),
+                                    Tool::HueShift => Some(BrushStrokeKind::HueShift {
+                                        shift: self.user.hue_shift,
+                                    }),
+                                    _ => self.user.clone_stroke_kind(&self.canvas),
+                                };
+                                // shift strokes a straight line on from the last stroke
+                                let result = match (kind, i.modifiers.shift) {
+                                    (Some(kind), true) => {
+                                        self.user.stroke_line(kind, &mut self.canvas)
+                                    }
+                                    (Some(kind), false) => {
+                                        self.user.start_brush_stroke(kind, &mut self.canvas)
+                                    }
+                                    (None, _) => {
+                                        self.status_message = Some(
+                                            "Alt-click to set where to clone from".to_string(),
+                                        );
+                                        Ok(())
+                                    }
+                                };
+                                self.report_stroke_error(result);
+                            }
+                            Tool::Move => self.user.start_move(&self.canvas),
+                            Tool::Shape => self.user.start_shape(),
+                            Tool::Fill => {
+                                let result = self.user.fill(&mut self.canvas);
+                                self.report_edit_error(result);
+                            }
+                            Tool::Eyedropper => {}
+                        }
                     }
 
-                    if i.pointer.secondary_pressed() {
+                    if i.pointer.secondary_pressed()
+                        && canvas_hovered
+                        && can_edit
+                        && !self.user.is_tool_overridden()
+                    {
                         self.user.holding_pointer_right = true;
-                        self.user.start_brush_stroke(user::BrushStrokeKind::Smudge);
+                        let kind = BrushStrokeKind::Smudge {
+                            sample_merged: self.user.smudge_sample_merged,
+                            pickup_rate: self.user.smudge_pickup_rate,
+                        };
+                        let result = self.user.start_brush_stroke(kind, &mut self.canvas);
+                        self.report_stroke_error(result);
                     }
 
                     if i.pointer.primary_released() {
-                        self.user.holding_pointer_primary = false;
+                        match self.user.release_primary() {
+                            Some(
+                                Tool::Brush
+                                | Tool::Eraser
+                                | Tool::DodgeBurn
+                                | Tool::Noise
+                                | Tool::HueShift
+                                | Tool::Clone,
+                            ) => {
+                                let result = self.user.finish_brush_stroke(&mut self.canvas);
+                                self.report_stroke_error(result);
+                            }
+                            Some(Tool::Move) => self.user.finish_move(&self.canvas),
+                            Some(Tool::Shape) => {
+                                let result = self.user.finish_shape(&mut self.canvas);
+                                self.report_edit_error(result);
+                            }
+                            _ => {}
+                        }
                     }
 
                     if i.pointer.secondary_released() {
+                        if self.user.holding_pointer_right {
+                            let result = self.user.finish_brush_stroke(&mut self.canvas);
+                            self.report_stroke_error(result);
+                        }
                         self.user.holding_pointer_right = false;
                     }
                 });
 
-                if self.user.holding_pointer_primary || self.user.holding_pointer_right {
+                if canvas_hovered {
+                    match self.user.effective_tool() {
+                        Tool::Eyedropper | Tool::Fill | Tool::Shape => {
+                            ctx.set_cursor_icon(egui::CursorIcon::Crosshair)
+                        }
+                        Tool::Move => ctx.set_cursor_icon(egui::CursorIcon::Move),
+                        _ => {}
+                    }
+                }
+
+                if self.user.holding_pointer_primary
+                    && self.user.effective_tool() == Tool::Eyedropper
+                {
+                    self.user.current_color = self.sample_at_cursor(self.user.current_color);
+                } else if self.user.holding_pointer_primary
+                    && self.user.effective_tool() == Tool::Move
+                {
+                    self.user.continue_move(&mut self.canvas);
+                } else if self.user.is_stroking() {
                     match self.user.continue_brush_stroke() {
                         Ok((layer_idx, brush_stroke_kind, brush_stroke_frame)) => {
-                            self.canvas.process_brush_stroke_frame(
+                            let result = self.canvas.process_brush_stroke_frame(
                                 layer_idx,
                                 brush_stroke_kind,
                                 brush_stroke_frame,
                             );
+                            self.report_stroke_error(result);
                         }
                         Err(e) => error!("Error processing brush stroke: {:?}", e),
                     }
@@ -248,6 +2088,10 @@ impl eframe::App for App {
                 self.user.last_cursor_position = self.user.cursor_position;
             }
         }
+
+        if let Some(in_place) = paste_request {
+            self.paste(ctx, in_place);
+        }
     }
 }
 
@@ -260,10 +2104,35 @@ fn main() -> Result<(), eframe::Error> {
         .with_line_number(true)
         .init();
 
-    let native_options = eframe::NativeOptions::default();
+    // `--view <file>` opens the file read-only, for looking at without editing, and any
+    // other arguments are files to open
+    let mut args = std::env::args().skip(1);
+    let mut view = None;
+    let mut open = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--view" {
+            view = args.next().map(PathBuf::from);
+        } else {
+            open.push(PathBuf::from(arg));
+        }
+    }
+    // a viewer is always a window of its own
+    if view.is_none() && single_instance::forward(&open) {
+        return Ok(());
+    }
+
+    let mut native_options = eframe::NativeOptions::default();
+    if let Some(path) = &view {
+        let name = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy();
+        native_options.viewport =
+            egui::ViewportBuilder::default().with_title(format!("{} (read-only) - Brushy", name));
+    }
     eframe::run_native(
         "Brushy",
         native_options,
-        Box::new(|_cc| Ok(Box::new(App::default()))),
+        Box::new(|cc| Ok(Box::new(App::new(cc, view, open)))),
     )
 }
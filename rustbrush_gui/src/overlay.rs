@@ -0,0 +1,64 @@
+use eframe::egui::{self, Color32, Pos2, Rect};
+use rustbrush_utils::selection::SelectionMask;
+
+/// An image drawn above the layers that isn't part of the document, such as a preview. It's
+/// placed in canvas pixels and goes through the same view transform as the layers.
+pub struct CanvasOverlay {
+    pub texture: egui::TextureHandle,
+    /// Top left corner, in canvas pixels.
+    pub position: Pos2,
+    options: egui::TextureOptions,
+}
+
+impl CanvasOverlay {
+    pub fn new(ctx: &egui::Context, name: &str, image: egui::ColorImage, position: Pos2) -> Self {
+        Self::with_options(ctx, name, image, position, egui::TextureOptions::default())
+    }
+
+    /// Like [`CanvasOverlay::new`], filtered with `options` when zoomed, such as to match the
+    /// layers.
+    pub fn with_options(
+        ctx: &egui::Context,
+        name: &str,
+        image: egui::ColorImage,
+        position: Pos2,
+        options: egui::TextureOptions,
+    ) -> Self {
+        Self {
+            texture: ctx.load_texture(name, image, options),
+            position,
+            options,
+        }
+    }
+
+    /// Replaces the image, keeping the position.
+    pub fn set_image(&mut self, image: egui::ColorImage) {
+        self.texture.set(image, self.options);
+    }
+
+    /// Draws the overlay given the screen position of the canvas origin and the zoom.
+    pub fn paint(&self, painter: &egui::Painter, canvas_origin: Pos2, zoom: f32) {
+        let min = canvas_origin + self.position.to_vec2() * zoom;
+        painter.image(
+            self.texture.id(),
+            Rect::from_min_size(min, self.texture.size_vec2() * zoom),
+            Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+            Color32::WHITE,
+        );
+    }
+}
+
+const SELECTION_TINT: Color32 = Color32::from_rgb(60, 140, 255);
+
+/// Shows a selection as a translucent tint, stronger the more a pixel is selected.
+pub fn selection_tint(mask: &SelectionMask) -> egui::ColorImage {
+    let pixels = mask
+        .values()
+        .iter()
+        .map(|&value| SELECTION_TINT.gamma_multiply(value as f32 / 255.0 * 0.35))
+        .collect();
+    egui::ColorImage {
+        size: [mask.width() as usize, mask.height() as usize],
+        pixels,
+    }
+}
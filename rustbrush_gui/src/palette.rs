@@ -0,0 +1,374 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use eframe::egui::{self, Color32, Rgba};
+use serde::{Deserialize, Serialize};
+
+/// The keyword of the iTXt chunk the document palette is kept in, in the document's PNG.
+/// Other apps leave chunks they don't know alone, or drop them, and the image is unaffected
+/// either way.
+pub const DOCUMENT_PALETTE_KEYWORD: &str = "brushy:palette";
+
+/// The name of the group "add to document palette" adds to, created when it's first needed.
+const DOCUMENT_GROUP_NAME: &str = "Document";
+
+/// Where a group of swatches is kept between sessions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwatchScope {
+    /// With the app's settings, so it's there for every document.
+    #[default]
+    Global,
+    /// In the document, so it goes wherever the document goes and only comes with it.
+    Document,
+}
+
+/// A named group of swatches.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SwatchGroup {
+    pub name: String,
+    pub scope: SwatchScope,
+    /// Straight linear RGBA, in the order they're shown.
+    pub colors: Vec<[f32; 4]>,
+}
+
+impl SwatchGroup {
+    pub fn new(name: String, scope: SwatchScope) -> Self {
+        Self {
+            name,
+            scope,
+            colors: Vec::new(),
+        }
+    }
+
+    /// Adds `color` at the end. A color the group already has is added again, so adding
+    /// never moves or drops what's there.
+    pub fn add(&mut self, color: Rgba) {
+        self.colors.push(color.to_rgba_unmultiplied());
+    }
+
+    pub fn color(&self, index: usize) -> Option<Rgba> {
+        let [r, g, b, a] = *self.colors.get(index)?;
+        Some(Rgba::from_rgba_unmultiplied(r, g, b, a))
+    }
+
+    /// Moves the swatch at `from` to `to`, shifting the ones in between. Does nothing if
+    /// either is past the end.
+    pub fn move_color(&mut self, from: usize, to: usize) {
+        if from < self.colors.len() && to < self.colors.len() {
+            let color = self.colors.remove(from);
+            self.colors.insert(to, color);
+        }
+    }
+}
+
+/// Every swatch group, global and the document's, in the order they're shown.
+#[derive(Default)]
+pub struct Palette {
+    pub groups: Vec<SwatchGroup>,
+}
+
+impl Palette {
+    /// The groups of `scope`, as they're saved.
+    pub fn groups_in(&self, scope: SwatchScope) -> Vec<SwatchGroup> {
+        self.groups
+            .iter()
+            .filter(|group| group.scope == scope)
+            .cloned()
+            .collect()
+    }
+
+    /// Replaces the document's groups with `groups`, such as those of a document just
+    /// opened, which are taken to be the document's whatever they say. The global groups
+    /// stay as they are, even where the two have the same names or colors: both are kept
+    /// and shown, each under its own scope, rather than merged.
+    pub fn set_document_groups(&mut self, groups: Vec<SwatchGroup>) {
+        self.groups
+            .retain(|group| group.scope != SwatchScope::Document);
+        self.groups
+            .extend(groups.into_iter().map(|group| SwatchGroup {
+                scope: SwatchScope::Document,
+                ..group
+            }));
+    }
+
+    /// The document's first group, which is added to if there's nowhere more specific.
+    /// Created if the document has no groups yet.
+    pub fn document_group_mut(&mut self) -> &mut SwatchGroup {
+        let index = match self
+            .groups
+            .iter()
+            .position(|group| group.scope == SwatchScope::Document)
+        {
+            Some(index) => index,
+            None => {
+                let name = DOCUMENT_GROUP_NAME.to_string();
+                self.groups
+                    .push(SwatchGroup::new(name, SwatchScope::Document));
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[index]
+    }
+
+    /// The document's groups as the text of the chunk they're saved in, see
+    /// [`DOCUMENT_PALETTE_KEYWORD`]. `None` when the document has none, so nothing is added
+    /// to the file.
+    pub fn document_chunk(&self) -> Option<(&'static str, String)> {
+        let groups = self.groups_in(SwatchScope::Document);
+        if groups.is_empty() {
+            return None;
+        }
+        let text = serde_json::to_string(&groups).ok()?;
+        Some((DOCUMENT_PALETTE_KEYWORD, text))
+    }
+}
+
+/// The document palette kept in the PNG at `path`, empty if it has none. Only chunks before
+/// the image data are read, which is where this app writes them.
+pub fn read_document_palette(path: &Path) -> Result<Vec<SwatchGroup>, Box<dyn std::error::Error>> {
+    let reader = png::Decoder::new(BufReader::new(File::open(path)?)).read_info()?;
+    let Some(chunk) = reader
+        .info()
+        .utf8_text
+        .iter()
+        .find(|chunk| chunk.keyword == DOCUMENT_PALETTE_KEYWORD)
+    else {
+        return Ok(Vec::new());
+    };
+    Ok(serde_json::from_str(&chunk.get_text()?)?)
+}
+
+/// The swatches menu: every group in turn, the document's marked as such. Clicking a
+/// swatch picks its color, and right-clicking it moves or removes it.
+#[derive(Default)]
+pub struct SwatchPicker {
+    /// What to call the next global group.
+    new_group: String,
+}
+
+impl SwatchPicker {
+    /// Shows the picker in `ui`, returning the color of the swatch that was picked, if any.
+    /// Adding `current` to a group, reordering and removing change `palette` in place.
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        palette: &mut Palette,
+        current: Rgba,
+    ) -> Option<Rgba> {
+        let mut picked = None;
+        let mut removed_group = None;
+        ui.set_min_width(240.0);
+        if ui.button("Add Current Color to Document Palette").clicked() {
+            palette.document_group_mut().add(current);
+        }
+        if palette.groups.is_empty() {
+            ui.weak("No swatches yet");
+        }
+
+        for (index, group) in palette.groups.iter_mut().enumerate() {
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.strong(&group.name);
+                if group.scope == SwatchScope::Document {
+                    ui.weak("in document");
+                }
+                if ui
+                    .small_button("+")
+                    .on_hover_text("Add the current color")
+                    .clicked()
+                {
+                    group.add(current);
+                }
+                if ui
+                    .small_button("✖")
+                    .on_hover_text("Delete the group")
+                    .clicked()
+                {
+                    removed_group = Some(index);
+                }
+            });
+            if let Some(color) = swatches(ui, group) {
+                picked = Some(color);
+            }
+        }
+        if let Some(index) = removed_group {
+            palette.groups.remove(index);
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.new_group).hint_text("Group name"));
+            let name = self.new_group.trim();
+            if ui
+                .add_enabled(!name.is_empty(), egui::Button::new("New Group"))
+                .clicked()
+            {
+                let group = SwatchGroup::new(name.to_string(), SwatchScope::Global);
+                palette.groups.push(group);
+                self.new_group.clear();
+            }
+        });
+
+        if picked.is_some() {
+            ui.close_menu();
+        }
+        picked
+    }
+}
+
+/// The swatches of `group`, returning the color of the one clicked, if any.
+fn swatches(ui: &mut egui::Ui, group: &mut SwatchGroup) -> Option<Rgba> {
+    let mut picked = None;
+    let mut moved = None;
+    let mut removed = None;
+    let last = group.colors.len().saturating_sub(1);
+    ui.horizontal_wrapped(|ui| {
+        for index in 0..group.colors.len() {
+            let Some(color) = group.color(index) else {
+                continue;
+            };
+            let swatch = egui::Button::new("")
+                .fill(Color32::from(color))
+                .min_size(egui::vec2(18.0, 18.0));
+            let response = ui.add(swatch);
+            if response.clicked() {
+                picked = Some(color);
+            }
+            response.context_menu(|ui| {
+                if ui
+                    .add_enabled(index > 0, egui::Button::new("Move Left"))
+                    .clicked()
+                {
+                    moved = Some((index, index - 1));
+                    ui.close_menu();
+                }
+                if ui
+                    .add_enabled(index < last, egui::Button::new("Move Right"))
+                    .clicked()
+                {
+                    moved = Some((index, index + 1));
+                    ui.close_menu();
+                }
+                if ui.button("Remove").clicked() {
+                    removed = Some(index);
+                    ui.close_menu();
+                }
+            });
+        }
+        if group.colors.is_empty() {
+            ui.weak("Empty");
+        }
+    });
+    if let Some((from, to)) = moved {
+        group.move_color(from, to);
+    }
+    if let Some(index) = removed {
+        group.colors.remove(index);
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::canvas;
+
+    fn group(name: &str, scope: SwatchScope, colors: &[Rgba]) -> SwatchGroup {
+        let mut group = SwatchGroup::new(name.to_string(), scope);
+        for &color in colors {
+            group.add(color);
+        }
+        group
+    }
+
+    #[test]
+    fn groups_round_trip_through_json() {
+        let red = Rgba::from_rgba_unmultiplied(1.0, 0.0, 0.0, 0.5);
+        let groups = vec![
+            group("Skin", SwatchScope::Global, &[red, Rgba::WHITE]),
+            group("Document", SwatchScope::Document, &[]),
+        ];
+        let json = serde_json::to_string(&groups).unwrap();
+        let loaded: Vec<SwatchGroup> = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, groups);
+        // a straight color stays straight, however faint
+        assert_eq!(loaded[0].color(0), Some(red));
+
+        // fields that are missing are the defaults
+        let loaded: SwatchGroup = serde_json::from_str(r#"{"name": "Old"}"#).unwrap();
+        assert_eq!(loaded, group("Old", SwatchScope::Global, &[]));
+    }
+
+    #[test]
+    fn moving_a_swatch_shifts_the_ones_in_between() {
+        let colors = [Rgba::RED, Rgba::GREEN, Rgba::BLUE, Rgba::WHITE];
+        let mut group = group("Group", SwatchScope::Global, &colors);
+        group.move_color(0, 2);
+        let order: Vec<_> = (0..4).map(|i| group.color(i).unwrap()).collect();
+        assert_eq!(order, [Rgba::GREEN, Rgba::BLUE, Rgba::RED, Rgba::WHITE]);
+        group.move_color(1, 4);
+        assert_eq!(group.color(1), Some(Rgba::BLUE));
+    }
+
+    #[test]
+    fn loading_a_document_palette_keeps_colliding_global_groups() {
+        let mut palette = Palette {
+            groups: vec![
+                group("Skin", SwatchScope::Global, &[Rgba::RED]),
+                group("Old", SwatchScope::Document, &[Rgba::GREEN]),
+            ],
+        };
+        // the same name and colors as the global group, saved as global by mistake
+        palette.set_document_groups(vec![group("Skin", SwatchScope::Global, &[Rgba::RED])]);
+        assert_eq!(
+            palette.groups,
+            [
+                group("Skin", SwatchScope::Global, &[Rgba::RED]),
+                group("Skin", SwatchScope::Document, &[Rgba::RED]),
+            ]
+        );
+        assert_eq!(palette.groups_in(SwatchScope::Global).len(), 1);
+    }
+
+    #[test]
+    fn adding_to_the_document_palette_creates_it_once() {
+        let mut palette = Palette::default();
+        assert!(palette.document_chunk().is_none());
+        palette.document_group_mut().add(Rgba::RED);
+        palette.document_group_mut().add(Rgba::BLUE);
+        assert_eq!(
+            palette.groups,
+            [group(
+                DOCUMENT_GROUP_NAME,
+                SwatchScope::Document,
+                &[Rgba::RED, Rgba::BLUE]
+            )]
+        );
+    }
+
+    #[test]
+    fn the_document_palette_round_trips_through_the_png() {
+        let mut palette = Palette {
+            groups: vec![group("Global", SwatchScope::Global, &[Rgba::WHITE])],
+        };
+        let path =
+            std::env::temp_dir().join(format!("rustbrush-palette-{}.png", std::process::id()));
+
+        // a document without a palette has nothing to read back
+        let canvas = canvas(8, 8, 1);
+        canvas.save_as_png(&path, &[]).unwrap();
+        assert!(read_document_palette(&path).unwrap().is_empty());
+
+        palette.document_group_mut().add(Rgba::RED);
+        palette
+            .groups
+            .push(group("Sky", SwatchScope::Document, &[Rgba::BLUE]));
+        let chunk = palette.document_chunk().unwrap();
+        canvas.save_as_png(&path, &[chunk]).unwrap();
+        let loaded = read_document_palette(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), palette.groups_in(SwatchScope::Document));
+    }
+}
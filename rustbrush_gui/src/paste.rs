@@ -0,0 +1,338 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::canvas::Canvas;
+use crate::overlay::CanvasOverlay;
+use crate::user::User;
+use eframe::egui::{self, Pos2};
+use image::codecs::png::PngDecoder;
+use image::{DynamicImage, ImageDecoder};
+use rustbrush_utils::alpha;
+use rustbrush_utils::canvas::{CanvasError, CanvasLimits, LayerBounds, PasteImage};
+use rustbrush_utils::color_profile::ColorProfile;
+use tracing::warn;
+
+/// Loads a PNG. Images with a Display P3 profile are converted to sRGB; any other
+/// embedded profile is ignored and the image is treated as sRGB, in which case a warning
+/// is returned along with the image. Images too large for a layer, see [`CanvasLimits`],
+/// are refused before they're decoded.
+pub fn load_png(path: &Path) -> Result<(PasteImage, Option<String>), Box<dyn std::error::Error>> {
+    let mut decoder = PngDecoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions();
+    CanvasLimits::default().check(width, height)?;
+    let icc = decoder.icc_profile()?;
+    let mut image = DynamicImage::from_decoder(decoder)?.to_rgba8();
+
+    let mut warning = None;
+    let profile = match icc {
+        // no profile, or just an sRGB chunk
+        None => ColorProfile::Srgb,
+        Some(icc) => ColorProfile::from_icc(&icc).unwrap_or_else(|| {
+            let message = format!(
+                "{} has an unsupported color profile, assuming sRGB",
+                path.display()
+            );
+            warn!("{}", message);
+            warning = Some(message);
+            ColorProfile::Srgb
+        }),
+    };
+    profile.convert_to_srgb(&mut image);
+
+    let image = PasteImage {
+        width: image.width(),
+        height: image.height(),
+        rgba: image.into_raw(),
+    };
+    Ok((image, warning))
+}
+
+fn to_color_image(image: &PasteImage) -> egui::ColorImage {
+    egui::ColorImage {
+        size: [image.width as usize, image.height as usize],
+        pixels: alpha::premultiply_rgba8(&image.rgba),
+    }
+}
+
+/// Where a floating paste ends up when it's committed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PasteTarget {
+    #[default]
+    NewLayer,
+    ActiveLayer,
+}
+
+impl PasteTarget {
+    pub const ALL: [PasteTarget; 2] = [PasteTarget::NewLayer, PasteTarget::ActiveLayer];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PasteTarget::NewLayer => "New Layer",
+            PasteTarget::ActiveLayer => "Active Layer",
+        }
+    }
+}
+
+/// Pasted or imported pixels that float above the canvas, and can be moved, until they're
+/// committed or cancelled. Nothing touches the document until [`FloatingPaste::commit`].
+pub struct FloatingPaste {
+    image: PasteImage,
+    /// Top left corner, in canvas pixels.
+    offset: (i32, i32),
+    /// What the pixels came from, which names the layer they're committed to.
+    source: String,
+    overlay: CanvasOverlay,
+    /// The pointer position and offset when the paste was grabbed, while it's being dragged.
+    grab: Option<(Pos2, (i32, i32))>,
+    moved: bool,
+}
+
+impl FloatingPaste {
+    pub fn new(ctx: &egui::Context, image: PasteImage, offset: (i32, i32), source: String) -> Self {
+        let overlay = CanvasOverlay::new(
+            ctx,
+            "floating_paste",
+            to_color_image(&image),
+            Pos2::new(offset.0 as f32, offset.1 as f32),
+        );
+        Self {
+            image,
+            offset,
+            source,
+            overlay,
+            grab: None,
+            moved: false,
+        }
+    }
+
+    pub fn overlay(&self) -> &CanvasOverlay {
+        &self.overlay
+    }
+
+    pub fn nudge(&mut self, dx: i32, dy: i32) {
+        self.set_offset((self.offset.0 + dx, self.offset.1 + dy));
+    }
+
+    fn set_offset(&mut self, offset: (i32, i32)) {
+        self.offset = offset;
+        self.overlay.position = Pos2::new(offset.0 as f32, offset.1 as f32);
+    }
+
+    /// Starts dragging the paste from `position`, in canvas pixels.
+    pub fn grab(&mut self, position: Pos2) {
+        self.grab = Some((position, self.offset));
+        self.moved = false;
+    }
+
+    pub fn drag_to(&mut self, position: Pos2) {
+        if let Some((start, offset)) = self.grab {
+            let delta = position - start;
+            let offset = (
+                offset.0 + delta.x.round() as i32,
+                offset.1 + delta.y.round() as i32,
+            );
+            self.moved |= offset != self.offset;
+            self.set_offset(offset);
+        }
+    }
+
+    /// Stops dragging, returning true if the pointer was clicked without moving the paste.
+    pub fn release(&mut self) -> bool {
+        self.grab.take().is_some() && !self.moved
+    }
+
+    /// Composites the paste into the document as a single undoable action.
+    pub fn commit(
+        self,
+        canvas: &mut Canvas,
+        user: &mut User,
+        target: PasteTarget,
+    ) -> Result<(), CanvasError> {
+        canvas.check_editable()?;
+        let layer = match target {
+            PasteTarget::NewLayer => {
+                // just big enough for the paste, wherever it is
+                let bounds = LayerBounds::new(
+                    self.offset.0,
+                    self.offset.1,
+                    self.image.width,
+                    self.image.height,
+                );
+                let layer = canvas.add_layer_at(self.source, bounds)?;
+                user.current_layer = layer;
+                layer
+            }
+            PasteTarget::ActiveLayer => user.current_layer,
+        };
+        canvas.paste(layer, &self.image, self.offset);
+        user.record_paste(layer, self.image, self.offset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::canvas;
+    use eframe::egui::{Color32, Rgba};
+    use rustbrush_utils::canvas::BrushStrokeKind;
+    use rustbrush_utils::testing::{hard_brush, stroke};
+
+    /// A 16x16 canvas whose one layer is opaque blue all over, put there the way opening a
+    /// document does, so it's in the history.
+    fn opened() -> (Canvas, User) {
+        let (mut canvas, mut user) = (canvas(16, 16, 1), User::default());
+        let blue = PasteImage {
+            width: 16,
+            height: 16,
+            rgba: [0, 0, 255, 255].repeat(16 * 16),
+        };
+        canvas.paste(0, &blue, (0, 0));
+        user.record_paste(0, blue, (0, 0));
+        (canvas, user)
+    }
+
+    /// A 4x4 image of red at half alpha.
+    fn half_red() -> PasteImage {
+        PasteImage {
+            width: 4,
+            height: 4,
+            rgba: [255, 0, 0, 128].repeat(16),
+        }
+    }
+
+    fn composite(canvas: &Canvas) -> Vec<Color32> {
+        canvas.composite_rect(LayerBounds::canvas(16, 16))
+    }
+
+    #[test]
+    fn a_half_transparent_dab_survives_a_png_round_trip() {
+        let mut canvas = canvas(24, 24, 1);
+        let red = alpha::brush_color_from_srgba([255, 0, 0, 128]);
+        let brush = hard_brush(6.0);
+        let center = Pos2::new(12.0, 12.0);
+        stroke(
+            &mut canvas,
+            0,
+            BrushStrokeKind::Paint,
+            &brush,
+            red,
+            &[center],
+        );
+        let painted = canvas.snapshot().merged();
+        assert!(painted.iter().any(|pixel| pixel.a() == 128));
+
+        let path = std::env::temp_dir().join(format!("rustbrush-{}.png", std::process::id()));
+        canvas.save_as_png(&path, &[]).unwrap();
+        let loaded = load_png(&path);
+        std::fs::remove_file(&path).unwrap();
+        let (image, warning) = loaded.unwrap();
+        assert!(warning.is_none());
+
+        let reloaded = alpha::premultiply_rgba8(&image.rgba);
+        assert_eq!(reloaded.len(), painted.len());
+        for (before, after) in painted.iter().zip(&reloaded) {
+            for c in 0..4 {
+                assert!(
+                    before[c].abs_diff(after[c]) <= 1,
+                    "{before:?} came back as {after:?}"
+                );
+            }
+        }
+        // and the straight color in the file is the red that was painted
+        let straight = image.rgba.chunks_exact(4).find(|pixel| pixel[3] == 128);
+        assert_eq!(straight, Some(&[255, 0, 0, 128][..]));
+    }
+
+    #[test]
+    fn an_image_too_wide_for_a_layer_is_refused() {
+        let path = std::env::temp_dir().join(format!("rustbrush-wide-{}.png", std::process::id()));
+        let mut encoder = png::Encoder::new(std::fs::File::create(&path).unwrap(), 9000, 1);
+        encoder.set_color(png::ColorType::Grayscale);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0; 9000]).unwrap();
+        writer.finish().unwrap();
+
+        let loaded = load_png(&path);
+        std::fs::remove_file(&path).unwrap();
+        let error = loaded.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CanvasError>(),
+            Some(CanvasError::BadSize {
+                width: 9000,
+                height: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn a_cancelled_paste_leaves_the_document_as_it_was() {
+        let ctx = egui::Context::default();
+        let (canvas, user) = opened();
+        let (pixels, before) = (
+            canvas.state.layers[0].pixels().into_owned(),
+            composite(&canvas),
+        );
+
+        let mut paste = FloatingPaste::new(&ctx, half_red(), (2, 2), "Pasted".into());
+        paste.nudge(3, 1);
+        paste.grab(Pos2::new(6.0, 4.0));
+        paste.drag_to(Pos2::new(9.0, 9.0));
+        assert!(!paste.release());
+        assert_eq!(paste.overlay().position, Pos2::new(8.0, 8.0));
+        // cancelling drops the paste, without it ever touching the canvas
+        drop(paste);
+
+        assert_eq!(canvas.state.layers.len(), 1);
+        assert!(canvas.state.layers[0].pixels() == pixels);
+        assert!(composite(&canvas) == before);
+        assert_eq!(user.action_history.len(), 1);
+    }
+
+    #[test]
+    fn a_committed_paste_composites_over_what_was_there() {
+        let ctx = egui::Context::default();
+        let red = Rgba::from(Color32::from_rgba_unmultiplied(255, 0, 0, 128));
+        for target in PasteTarget::ALL {
+            let (mut canvas, mut user) = opened();
+            let under = composite(&canvas);
+            // hanging off the right edge, which is cut off
+            let paste = FloatingPaste::new(&ctx, half_red(), (14, 6), "Pasted".into());
+            paste.commit(&mut canvas, &mut user, target).unwrap();
+            let layers = if target == PasteTarget::NewLayer {
+                2
+            } else {
+                1
+            };
+            assert_eq!(canvas.state.layers.len(), layers);
+
+            let over = composite(&canvas);
+            for y in 0..16 {
+                for x in 0..16 {
+                    let i = y * 16 + x;
+                    let pasted = x >= 14 && (6..10).contains(&y);
+                    // red at half alpha over what's under it, in linear light like the
+                    // rest of the compositing
+                    let expected = match pasted {
+                        true => Color32::from(red + Rgba::from(under[i]) * (1.0 - red.a())),
+                        false => under[i],
+                    };
+                    for c in 0..4 {
+                        assert!(
+                            over[i][c].abs_diff(expected[c]) <= 1,
+                            "{target:?} at {x}, {y}: {:?} != {expected:?}",
+                            over[i]
+                        );
+                    }
+                }
+            }
+
+            // one action, and undoing it takes the paste away again
+            assert_eq!(user.action_history.len(), 2);
+            user.undo(&mut canvas);
+            assert!(composite(&canvas) == under, "{target:?}");
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Instant;
+
+use eframe::egui;
+use rustbrush_utils::operations::OpStats;
+
+use crate::document_info::format_bytes;
+use rustbrush_utils::canvas::MemoryStats;
+
+/// How much each new frame moves the averages, so the numbers settle enough to read.
+const SMOOTHING: f32 = 0.1;
+
+/// Frame timings for the performance overlay, in milliseconds.
+#[derive(Default)]
+pub struct PerfStats {
+    /// Time spent in `update`, which is where input is turned into pixels.
+    cpu: f32,
+    /// Time between frames.
+    frame: f32,
+    /// What the paint and smudge operations did in the last frame that painted anything,
+    /// so it stays up between strokes.
+    ops: OpStats,
+}
+
+impl PerfStats {
+    /// Records a frame whose update began at `started`, with frames `frame_interval` seconds
+    /// apart.
+    pub fn record(&mut self, started: Instant, frame_interval: f32) {
+        let cpu = started.elapsed().as_secs_f32() * 1000.0;
+        let frame = frame_interval * 1000.0;
+        if self.frame == 0.0 {
+            (self.cpu, self.frame) = (cpu, frame);
+        } else {
+            self.cpu += (cpu - self.cpu) * SMOOTHING;
+            self.frame += (frame - self.frame) * SMOOTHING;
+        }
+    }
+
+    /// Records what the operations did in a frame, if they painted anything.
+    pub fn record_ops(&mut self, ops: OpStats) {
+        if ops.dabs > 0 {
+            self.ops = ops;
+        }
+    }
+
+    /// An estimate of how long it takes for pointer input to show up on screen. Input waits
+    /// half a frame on average to be read at the start of a frame, is painted and uploaded
+    /// during `update`, and then waits for the frame to be presented at the next refresh.
+    pub fn input_to_present(&self) -> f32 {
+        self.frame / 2.0 + self.cpu + self.frame
+    }
+
+    /// Shows the timings, along with the canvas's `memory` for anything besides the layers.
+    pub fn show(&self, ctx: &egui::Context, memory: &MemoryStats) {
+        egui::Area::new(egui::Id::new("perf"))
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-8.0, -32.0))
+            .order(egui::Order::Foreground)
+            .interactable(false)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.monospace(format!("update    {:5.1} ms", self.cpu));
+                    ui.monospace(format!("frame     {:5.1} ms", self.frame));
+                    ui.monospace(format!("input→screen ≈ {:4.1} ms", self.input_to_present()));
+                    ui.monospace(format!("scratch   {}", format_bytes(memory.total())));
+                    let ops = &self.ops;
+                    let ops_ms = ops.duration.as_secs_f32() * 1000.0;
+                    ui.monospace(format!("dabs      {:5} in {:.1} ms", ops.dabs, ops_ms));
+                    ui.monospace(format!("written   {:5} px", ops.pixels_written));
+                    ui.monospace(format!("off edge  {:5} px", ops.pixels_skipped_oob));
+                });
+            });
+    }
+}
@@ -0,0 +1,253 @@
+use std::path::PathBuf;
+
+use eframe::egui;
+use rustbrush_utils::library::BrushLibrary;
+use rustbrush_utils::presets::{self, PresetInfo};
+use rustbrush_utils::Brush;
+use tracing::error;
+
+/// A paint brush saved under a name, to be picked again from the presets menu.
+#[derive(Clone)]
+pub struct BrushPreset {
+    pub info: PresetInfo,
+    pub brush: Brush,
+}
+
+impl AsRef<PresetInfo> for BrushPreset {
+    fn as_ref(&self) -> &PresetInfo {
+        &self.info
+    }
+}
+
+impl AsMut<PresetInfo> for BrushPreset {
+    fn as_mut(&mut self) -> &mut PresetInfo {
+        &mut self.info
+    }
+}
+
+/// The presets menu: a search box over names and tags, a row of favorites, and every preset
+/// matching the search, most recently used first. Right-clicking a preset edits its tags.
+#[derive(Default)]
+pub struct PresetPicker {
+    query: String,
+    /// What to save the current brush as.
+    new_name: String,
+    /// The tags to save it with, separated by commas.
+    new_tags: String,
+    /// The tag being typed into a preset's context menu.
+    new_tag: String,
+}
+
+impl PresetPicker {
+    /// Shows the picker in `ui`, returning the brush of the preset that was picked, if any.
+    /// Saving `current` as a preset, favoriting, tagging and deleting change `presets` in
+    /// place.
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        presets: &mut Vec<BrushPreset>,
+        current: &Brush,
+    ) -> Option<Brush> {
+        let mut picked = None;
+        let mut deleted = None;
+        ui.set_min_width(240.0);
+        ui.add(egui::TextEdit::singleline(&mut self.query).hint_text("Search names and tags"));
+
+        let favorites = presets::favorites(presets);
+        if !favorites.is_empty() {
+            ui.horizontal_wrapped(|ui| {
+                ui.label("★");
+                for index in favorites {
+                    if ui.button(&presets[index].info.name).clicked() {
+                        picked = Some(index);
+                    }
+                }
+            });
+        }
+        ui.separator();
+
+        let found = presets::search(presets, &self.query);
+        if presets.is_empty() {
+            ui.weak("No presets yet");
+        } else if found.is_empty() {
+            ui.weak("No presets match");
+        }
+        let new_tag = &mut self.new_tag;
+        egui::ScrollArea::vertical()
+            .max_height(240.0)
+            .show(ui, |ui| {
+                for index in found {
+                    let info = &mut presets[index].info;
+                    ui.horizontal(|ui| {
+                        let star = if info.favorite { "★" } else { "☆" };
+                        if ui.button(star).on_hover_text("Favorite").clicked() {
+                            info.favorite = !info.favorite;
+                        }
+                        let response = ui.selectable_label(false, &info.name);
+                        if !info.tags.is_empty() {
+                            ui.weak(info.tags.join(", "));
+                        }
+                        if response.clicked() {
+                            picked = Some(index);
+                        }
+                        response.context_menu(|ui| {
+                            if tag_menu(ui, info, new_tag) {
+                                deleted = Some(index);
+                                ui.close_menu();
+                            }
+                        });
+                    });
+                }
+            });
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("Name:");
+            ui.text_edit_singleline(&mut self.new_name);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Tags:");
+            ui.add(egui::TextEdit::singleline(&mut self.new_tags).hint_text("ink, dry, sketch"));
+        });
+        let name = self.new_name.trim();
+        if ui
+            .add_enabled(!name.is_empty(), egui::Button::new("Save Current Brush"))
+            .clicked()
+        {
+            let mut info = PresetInfo::new(name.to_string());
+            for tag in self.new_tags.split(',') {
+                info.add_tag(tag);
+            }
+            presets.push(BrushPreset {
+                info,
+                brush: current.clone(),
+            });
+            self.new_name.clear();
+            self.new_tags.clear();
+        }
+
+        if let Some(index) = deleted {
+            presets.remove(index);
+        }
+        let index = picked?;
+        presets::mark_used(presets, index);
+        ui.close_menu();
+        Some(presets[index].brush.clone())
+    }
+}
+
+/// Where the brush library is kept, `rustbrush/brushes` in the user's config directory, or
+/// `None` if there's no telling where that is.
+pub fn library_dir() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+    Some(config.join("rustbrush").join("brushes"))
+}
+
+/// The library menu: the brushes in the library by id, and saving the current brush to it.
+/// Unlike presets, library brushes are files that can be shared, and are saved as soon as
+/// they change.
+#[derive(Default)]
+pub struct LibraryPicker {
+    /// What to save the current brush as.
+    new_id: String,
+}
+
+impl LibraryPicker {
+    /// Shows the picker in `ui`, returning the brush that was picked, if any.
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        library: &mut BrushLibrary,
+        current: &Brush,
+    ) -> Option<Brush> {
+        let mut picked = None;
+        let mut deleted = None;
+        ui.set_min_width(240.0);
+        if library.is_empty() {
+            ui.weak("No brushes in the library yet");
+        }
+        egui::ScrollArea::vertical()
+            .max_height(240.0)
+            .show(ui, |ui| {
+                for brush in library.iter() {
+                    let response = ui.selectable_label(false, brush.id());
+                    if response.clicked() {
+                        picked = Some(brush.clone());
+                    }
+                    response.context_menu(|ui| {
+                        if ui.button("Delete").clicked() {
+                            deleted = Some(brush.id().to_string());
+                            ui.close_menu();
+                        }
+                    });
+                }
+            });
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("Id:");
+            ui.text_edit_singleline(&mut self.new_id);
+        });
+        let id = self.new_id.trim();
+        let replacing = library.get(id).is_some();
+        let label = if replacing {
+            "Replace With Current Brush"
+        } else {
+            "Save Current Brush"
+        };
+        let mut changed = false;
+        if let Some(id) = deleted {
+            changed |= library.remove(&id).is_some();
+        }
+        if ui
+            .add_enabled(!id.is_empty(), egui::Button::new(label))
+            .clicked()
+        {
+            library.add(current.clone().with_id(id.to_string()));
+            self.new_id.clear();
+            changed = true;
+        }
+        if changed {
+            if let Err(e) = library.save_all() {
+                error!("Error saving the brush library: {}", e);
+            }
+        }
+
+        if picked.is_some() {
+            ui.close_menu();
+        }
+        picked
+    }
+}
+
+/// The context menu of a preset in the list: its tags, each removable, a field to add
+/// another, and deleting the preset, which is what returning true means.
+fn tag_menu(ui: &mut egui::Ui, info: &mut PresetInfo, new_tag: &mut String) -> bool {
+    let mut removed = None;
+    for (i, tag) in info.tags.iter().enumerate() {
+        if ui
+            .button(format!("{} ✖", tag))
+            .on_hover_text("Remove tag")
+            .clicked()
+        {
+            removed = Some(i);
+        }
+    }
+    if let Some(i) = removed {
+        info.tags.remove(i);
+    }
+    ui.horizontal(|ui| {
+        let response = ui.add(egui::TextEdit::singleline(new_tag).hint_text("New tag"));
+        let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        if ui.button("Add Tag").clicked() || entered {
+            info.add_tag(new_tag);
+            new_tag.clear();
+        }
+    });
+    ui.separator();
+    ui.button("Delete Preset").clicked()
+}
@@ -0,0 +1,275 @@
+use std::f32::consts::FRAC_PI_2;
+
+use eframe::egui::{self, Color32, FontId, Pos2, Rect, Sense, Stroke, Vec2};
+
+/// How thick the rulers along the top and left of the canvas are, in screen points.
+pub const RULER_SIZE: f32 = 18.0;
+/// The closest unlabelled ticks are allowed to get, in screen points.
+const MIN_TICK_SPACING: f32 = 6.0;
+/// Space kept between the widest label and the next one, in screen points.
+const LABEL_PADDING: f32 = 12.0;
+const LABEL_FONT_SIZE: f32 = 9.0;
+
+const RULER_BACKGROUND: Color32 = Color32::from_gray(40);
+const RULER_INK: Color32 = Color32::from_gray(170);
+const CURSOR_MARKER: Color32 = Color32::from_rgb(255, 120, 60);
+const GUIDE_COLOR: Color32 = Color32::from_rgb(0, 200, 220);
+
+/// A line across the whole canvas to line strokes up against, at a canvas coordinate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Guide {
+    Horizontal(f32),
+    Vertical(f32),
+}
+
+impl Guide {
+    /// Moves the guide to the canvas position, along its own axis only.
+    fn place_at(&mut self, position: Pos2) {
+        match self {
+            Guide::Horizontal(y) => *y = position.y.round(),
+            Guide::Vertical(x) => *x = position.x.round(),
+        }
+    }
+}
+
+/// The rulers around the canvas and the guides dragged out of them. The view has no
+/// rotation, so the rulers always line up with the canvas axes.
+#[derive(Default)]
+pub struct Rulers {
+    pub visible: bool,
+    pub guides: Vec<Guide>,
+    /// The guide being dragged out of a ruler, not placed yet.
+    new_guide: Option<Guide>,
+}
+
+impl Rulers {
+    /// The part of the central panel left for the canvas once the rulers take their strips.
+    pub fn canvas_area(&self, panel: Rect) -> Rect {
+        if self.visible {
+            Rect::from_min_max(panel.min + Vec2::splat(RULER_SIZE), panel.max)
+        } else {
+            panel
+        }
+    }
+
+    /// Draws the guides over the canvas, given the screen position of the canvas origin and
+    /// the zoom.
+    pub fn paint_guides(
+        &self,
+        painter: &egui::Painter,
+        canvas_rect: Rect,
+        origin: Pos2,
+        zoom: f32,
+    ) {
+        let stroke = Stroke::new(1.0, GUIDE_COLOR);
+        for guide in self.guides.iter().chain(&self.new_guide) {
+            match *guide {
+                Guide::Horizontal(y) => {
+                    painter.hline(canvas_rect.x_range(), origin.y + y * zoom, stroke);
+                }
+                Guide::Vertical(x) => {
+                    painter.vline(origin.x + x * zoom, canvas_rect.y_range(), stroke);
+                }
+            }
+        }
+    }
+
+    /// Draws the rulers in the strips around `canvas_rect` and handles dragging guides out of
+    /// them. A guide dragged from the top ruler is horizontal, one from the left ruler is
+    /// vertical, and it's only kept if it's let go over the canvas. `cursor` is the pointer
+    /// in canvas pixels, marked on both rulers.
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        canvas_rect: Rect,
+        origin: Pos2,
+        zoom: f32,
+        cursor: Option<Pos2>,
+    ) {
+        if !self.visible {
+            return;
+        }
+
+        let top = Rect::from_min_max(
+            Pos2::new(canvas_rect.min.x, canvas_rect.min.y - RULER_SIZE),
+            Pos2::new(canvas_rect.max.x, canvas_rect.min.y),
+        );
+        let left = Rect::from_min_max(
+            Pos2::new(canvas_rect.min.x - RULER_SIZE, canvas_rect.min.y),
+            Pos2::new(canvas_rect.min.x, canvas_rect.max.y),
+        );
+        let corner = Rect::from_min_max(top.min - Vec2::new(RULER_SIZE, 0.0), left.min);
+
+        let painter = ui.painter();
+        painter.rect_filled(corner, 0.0, RULER_BACKGROUND);
+        paint_ruler(painter, top, origin.x, zoom, cursor.map(|c| c.x), false);
+        paint_ruler(painter, left, origin.y, zoom, cursor.map(|c| c.y), true);
+
+        let to_canvas = |screen: Pos2| ((screen - origin) / zoom).to_pos2();
+        for (rect, id, guide) in [
+            (top, "horizontal_ruler", Guide::Horizontal(0.0)),
+            (left, "vertical_ruler", Guide::Vertical(0.0)),
+        ] {
+            let response = ui.interact(rect, ui.id().with(id), Sense::drag());
+            if response.drag_started() {
+                self.new_guide = Some(guide);
+            }
+            let pointer = ui.ctx().pointer_latest_pos();
+            if let (Some(new_guide), Some(pointer)) = (&mut self.new_guide, pointer) {
+                if response.dragged() {
+                    new_guide.place_at(to_canvas(pointer));
+                }
+            }
+            if response.drag_stopped() {
+                if let Some(new_guide) = self.new_guide.take() {
+                    if pointer.is_some_and(|pointer| canvas_rect.contains(pointer)) {
+                        self.guides.push(new_guide);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The smallest 1, 2 or 5 × 10ⁿ canvas pixels that puts ticks at least `min_spacing` screen
+/// points apart at this zoom. Never less than a whole pixel.
+pub fn tick_spacing(zoom: f32, min_spacing: f32) -> f32 {
+    let min_canvas_spacing = (min_spacing / zoom).max(1.0);
+    let magnitude = 10f32.powf(min_canvas_spacing.log10().floor());
+    [1.0, 2.0, 5.0]
+        .into_iter()
+        .map(|step| step * magnitude)
+        .find(|&spacing| spacing >= min_canvas_spacing)
+        .unwrap_or(10.0 * magnitude)
+}
+
+/// Draws one ruler. `origin` is the screen coordinate of canvas coordinate 0 along the
+/// ruler, and `vertical` rulers run down the left edge with their labels turned to match.
+fn paint_ruler(
+    painter: &egui::Painter,
+    rect: Rect,
+    origin: f32,
+    zoom: f32,
+    cursor: Option<f32>,
+    vertical: bool,
+) {
+    let painter = painter.with_clip_rect(rect);
+    painter.rect_filled(rect, 0.0, RULER_BACKGROUND);
+
+    let (start, end) = if vertical {
+        (rect.min.y, rect.max.y)
+    } else {
+        (rect.min.x, rect.max.x)
+    };
+    let to_canvas = |screen: f32| (screen - origin) / zoom;
+    let to_screen = |canvas: f32| origin + canvas * zoom;
+    // a tick `length` long, measured from the edge touching the canvas
+    let tick = |at: f32, length: f32| {
+        let stroke = Stroke::new(1.0, RULER_INK);
+        if vertical {
+            painter.hline(rect.max.x - length..=rect.max.x, at, stroke);
+        } else {
+            painter.vline(at, rect.max.y - length..=rect.max.y, stroke);
+        }
+    };
+
+    // labels are spaced for the widest one that could show, the most negative or largest
+    // number in view, so none of them overlap
+    let font = FontId::monospace(LABEL_FONT_SIZE);
+    let widest = to_canvas(start).abs().max(to_canvas(end).abs()).ceil();
+    let widest_label = painter.layout_no_wrap(format!("-{}", widest), font.clone(), RULER_INK);
+    let label_spacing = tick_spacing(zoom, widest_label.size().x + LABEL_PADDING);
+    let minor_spacing = tick_spacing(zoom, MIN_TICK_SPACING);
+
+    let first = (to_canvas(start) / minor_spacing).floor() as i64;
+    let last = (to_canvas(end) / minor_spacing).ceil() as i64;
+    for i in first..=last {
+        tick(to_screen(i as f32 * minor_spacing), RULER_SIZE * 0.25);
+    }
+
+    let first = (to_canvas(start) / label_spacing).floor() as i64;
+    let last = (to_canvas(end) / label_spacing).ceil() as i64;
+    for i in first..=last {
+        let value = i as f32 * label_spacing;
+        let at = to_screen(value);
+        tick(at, RULER_SIZE);
+
+        let label = painter.layout_no_wrap(format!("{}", value), font.clone(), RULER_INK);
+        let shape = if vertical {
+            // reads bottom to top, running up from the tick
+            egui::epaint::TextShape::new(Pos2::new(rect.min.x + 1.0, at - 2.0), label, RULER_INK)
+                .with_angle(-FRAC_PI_2)
+        } else {
+            egui::epaint::TextShape::new(Pos2::new(at + 2.0, rect.min.y + 1.0), label, RULER_INK)
+        };
+        painter.add(shape);
+    }
+
+    if let Some(cursor) = cursor {
+        let at = to_screen(cursor);
+        let marker = if vertical {
+            [
+                Pos2::new(rect.max.x, at),
+                Pos2::new(rect.max.x - 5.0, at - 4.0),
+                Pos2::new(rect.max.x - 5.0, at + 4.0),
+            ]
+        } else {
+            [
+                Pos2::new(at, rect.max.y),
+                Pos2::new(at - 4.0, rect.max.y - 5.0),
+                Pos2::new(at + 4.0, rect.max.y - 5.0),
+            ]
+        };
+        painter.add(egui::Shape::convex_polygon(
+            marker.to_vec(),
+            CURSOR_MARKER,
+            Stroke::NONE,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= b * 1e-4
+    }
+
+    #[test]
+    fn tick_spacing_picks_round_steps() {
+        for (zoom, min_spacing, expected) in [
+            (1.0, 6.0, 10.0),
+            (1.0, 40.0, 50.0),
+            (0.25, 40.0, 200.0),
+            (0.05, 50.0, 1000.0),
+            (0.05, 6.0, 200.0),
+            (3.0, 40.0, 20.0),
+            // never under a pixel, however far in
+            (32.0, 6.0, 1.0),
+            (32.0, 40.0, 2.0),
+        ] {
+            let spacing = tick_spacing(zoom, min_spacing);
+            assert!(close(spacing, expected), "{zoom} {min_spacing}: {spacing}");
+        }
+    }
+
+    #[test]
+    fn tick_spacing_is_the_smallest_step_far_enough_apart() {
+        let steps: Vec<f32> = (0..8)
+            .flat_map(|power| [1.0, 2.0, 5.0].map(|step| step * 10f32.powi(power)))
+            .collect();
+        // 5% to 3200%
+        for zoom in (0..=200).map(|i| 0.05 * 1.0328f32.powi(i)) {
+            for min_spacing in [MIN_TICK_SPACING, 30.0, 75.0] {
+                let spacing = tick_spacing(zoom, min_spacing);
+                let index = steps.iter().position(|&step| close(spacing, step));
+                let index = index.unwrap_or_else(|| panic!("{spacing} isn't a round step"));
+                assert!(spacing * zoom >= min_spacing * 0.9999 || spacing == 1.0);
+                if index > 0 {
+                    assert!(steps[index - 1] * zoom < min_spacing, "{zoom}: {spacing}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,508 @@
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use eframe::egui::Rgba;
+use rustbrush_utils::presets::PresetInfo;
+use rustbrush_utils::stroke::StrokeAccumulation;
+use rustbrush_utils::Brush;
+use serde::{Deserialize, Serialize};
+
+use crate::export::ExportSettings;
+use crate::palette::{SwatchGroup, SwatchScope};
+use crate::presets::BrushPreset;
+use crate::symmetry::Symmetry;
+use crate::user::{EraserMode, Tool, User};
+
+/// The key the session is stored under in eframe's storage.
+pub const SESSION_KEY: &str = "session";
+
+/// What to do about the last document on startup.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ReopenLastDocument {
+    /// Ask whether to reopen it.
+    #[default]
+    Ask,
+    Always,
+    Never,
+}
+
+impl ReopenLastDocument {
+    pub const ALL: [ReopenLastDocument; 3] = [
+        ReopenLastDocument::Ask,
+        ReopenLastDocument::Always,
+        ReopenLastDocument::Never,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReopenLastDocument::Ask => "Ask",
+            ReopenLastDocument::Always => "Always",
+            ReopenLastDocument::Never => "Never",
+        }
+    }
+}
+
+/// The settings of one brush that are kept between sessions.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedBrush {
+    pub radius: f32,
+    pub spacing: f32,
+    pub strength: f32,
+    pub opacity: f32,
+    pub size_jitter: f32,
+    pub opacity_jitter: f32,
+    pub taper_in: f32,
+    pub taper_out: f32,
+    pub wash: bool,
+    pub pixel_snap: bool,
+    pub float_buffer: bool,
+    pub hard: bool,
+    /// For a soft brush, see [`Brush::hardness`].
+    pub hardness: Option<f32>,
+    /// Width, height and edge softness, for a rectangular brush.
+    pub rectangle: Option<[f32; 3]>,
+}
+
+impl Default for SavedBrush {
+    fn default() -> Self {
+        Self::from(&Brush::default())
+    }
+}
+
+impl From<&Brush> for SavedBrush {
+    fn from(brush: &Brush) -> Self {
+        Self {
+            radius: brush.radius(),
+            spacing: brush.spacing(),
+            strength: brush.strength(),
+            opacity: brush.opacity(),
+            size_jitter: brush.size_jitter(),
+            opacity_jitter: brush.opacity_jitter(),
+            taper_in: brush.taper_in(),
+            taper_out: brush.taper_out(),
+            wash: brush.accumulation() == StrokeAccumulation::Wash,
+            pixel_snap: brush.pixel_snap(),
+            float_buffer: brush.float_buffer(),
+            hard: brush.falloff().is_none(),
+            hardness: brush.hardness(),
+            rectangle: brush
+                .rectangle()
+                .map(|(width, height, softness)| [width, height, softness]),
+        }
+    }
+}
+
+impl SavedBrush {
+    /// Applies the saved settings to `brush`, skipping any that are out of range. The radius
+    /// is kept to `radii`, the sizes the brush's tool can be set to.
+    fn restore(&self, brush: &mut Brush, radii: RangeInclusive<f32>) {
+        if self.radius.is_finite() {
+            brush.set_radius(self.radius.clamp(*radii.start(), *radii.end()));
+        }
+        if self.spacing.is_finite() && self.spacing > 0.0 {
+            brush.set_spacing(self.spacing);
+        }
+        if self.strength.is_finite() {
+            brush.set_strength(self.strength.clamp(0.0, 1.0));
+        }
+        if self.opacity.is_finite() {
+            brush.set_opacity(self.opacity.clamp(0.0, 1.0));
+        }
+        if self.size_jitter.is_finite() {
+            brush.set_size_jitter(self.size_jitter.clamp(0.0, 1.0));
+        }
+        if self.opacity_jitter.is_finite() {
+            brush.set_opacity_jitter(self.opacity_jitter.clamp(0.0, 1.0));
+        }
+        if self.taper_in.is_finite() {
+            brush.set_taper_in(self.taper_in.clamp(0.0, 200.0));
+        }
+        if self.taper_out.is_finite() {
+            brush.set_taper_out(self.taper_out.clamp(0.0, 200.0));
+        }
+        brush.set_accumulation(if self.wash {
+            StrokeAccumulation::Wash
+        } else {
+            StrokeAccumulation::BuildUp
+        });
+        brush.set_pixel_snap(self.pixel_snap);
+        brush.set_float_buffer(self.float_buffer);
+        if self.hard {
+            brush.set_shape(None);
+        }
+        if let Some(hardness) = self.hardness.filter(|h| h.is_finite()) {
+            brush.set_hardness(hardness);
+        }
+        if let Some([width, height, softness]) = self.rectangle {
+            if [width, height, softness].iter().all(|v| v.is_finite()) {
+                brush.set_rectangle(
+                    width.clamp(1.0, 40.0),
+                    height.clamp(1.0, 40.0),
+                    softness.clamp(0.0, 10.0),
+                );
+            }
+        }
+    }
+}
+
+/// A brush preset as it's kept between sessions.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedPreset {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub favorite: bool,
+    /// See [`PresetInfo::last_used`].
+    pub last_used: u64,
+    pub brush: SavedBrush,
+}
+
+impl From<&BrushPreset> for SavedPreset {
+    fn from(preset: &BrushPreset) -> Self {
+        Self {
+            name: preset.info.name.clone(),
+            tags: preset.info.tags.clone(),
+            favorite: preset.info.favorite,
+            last_used: preset.info.last_used,
+            brush: SavedBrush::from(&preset.brush),
+        }
+    }
+}
+
+impl SavedPreset {
+    fn restore(&self) -> BrushPreset {
+        let mut brush = Brush::default();
+        self.brush.restore(&mut brush, User::BRUSH_RADIUS);
+        BrushPreset {
+            info: PresetInfo {
+                name: self.name.clone(),
+                tags: self.tags.clone(),
+                favorite: self.favorite,
+                last_used: self.last_used,
+            },
+            brush,
+        }
+    }
+}
+
+/// Tool state kept between sessions, along with the last document and what to do about it.
+/// Every field is optional in storage, so a session saved by an older version, or one that
+/// lost some fields, still restores what it has.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedSession {
+    pub paint_brush: Option<SavedBrush>,
+    pub eraser_brush: Option<SavedBrush>,
+    pub smudge_brush: Option<SavedBrush>,
+    pub dodge_burn_brush: Option<SavedBrush>,
+    pub noise_brush: Option<SavedBrush>,
+    pub hue_shift_brush: Option<SavedBrush>,
+    pub clone_brush: Option<SavedBrush>,
+    /// Straight linear RGBA.
+    pub color: Option<[f32; 4]>,
+    /// Linear RGB.
+    pub background_color: Option<[f32; 3]>,
+    pub tool: Option<Tool>,
+    pub eraser_mode: Option<EraserMode>,
+    pub last_document: Option<PathBuf>,
+    pub reopen_last_document: ReopenLastDocument,
+    /// The settings of the last export, so exporting again carries on where it left off.
+    pub export: Option<ExportSettings>,
+    /// The symmetry of the last document. There's no project file to keep it in, so it's
+    /// kept with the document here.
+    pub symmetry: Option<Symmetry>,
+    pub show_symmetry_guides: Option<bool>,
+    /// There's no preset file, so the presets are kept with the session.
+    pub presets: Vec<SavedPreset>,
+    /// The global swatch groups. The document's are kept in the document, see
+    /// [`crate::palette::DOCUMENT_PALETTE_KEYWORD`].
+    pub swatches: Vec<SwatchGroup>,
+    /// Whether files opened while the app is already running go to the running window,
+    /// see [`crate::single_instance`].
+    pub single_instance: Option<bool>,
+}
+
+impl SavedSession {
+    /// The session kept in `storage`, or the defaults if there's none. Fields it doesn't have
+    /// get their defaults, but a session that was cut short or garbled can't be read at all.
+    pub fn load(storage: &dyn eframe::Storage) -> Result<Self, ron::error::SpannedError> {
+        match storage.get_string(SESSION_KEY) {
+            Some(text) => ron::from_str(&text),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Captures the tool state of `user`.
+    pub fn capture(
+        user: &User,
+        last_document: Option<PathBuf>,
+        reopen_last_document: ReopenLastDocument,
+        export: Option<ExportSettings>,
+        show_symmetry_guides: bool,
+        presets: &[BrushPreset],
+        single_instance: bool,
+    ) -> Self {
+        let color = user.current_color;
+        let background = user.background_color;
+        Self {
+            paint_brush: Some(SavedBrush::from(&user.current_paint_brush)),
+            eraser_brush: Some(SavedBrush::from(&user.current_eraser_brush)),
+            smudge_brush: Some(SavedBrush::from(&user.current_smudge_brush)),
+            dodge_burn_brush: Some(SavedBrush::from(&user.current_dodge_burn_brush)),
+            noise_brush: Some(SavedBrush::from(&user.current_noise_brush)),
+            hue_shift_brush: Some(SavedBrush::from(&user.current_hue_shift_brush)),
+            clone_brush: Some(SavedBrush::from(&user.current_clone_brush)),
+            color: Some([color.r(), color.g(), color.b(), color.a()]),
+            background_color: Some([background.r(), background.g(), background.b()]),
+            tool: Some(user.current_tool),
+            eraser_mode: Some(user.eraser_mode),
+            last_document,
+            reopen_last_document,
+            export,
+            symmetry: Some(user.symmetry),
+            show_symmetry_guides: Some(show_symmetry_guides),
+            presets: presets.iter().map(SavedPreset::from).collect(),
+            swatches: Vec::new(),
+            single_instance: Some(single_instance),
+        }
+    }
+
+    /// A user with the saved tool state, and defaults for whatever is missing or unusable.
+    pub fn restore_user(&self) -> User {
+        let mut user = User::default();
+        let (brush, retouch) = (User::BRUSH_RADIUS, User::RETOUCH_RADIUS);
+        let brushes = [
+            (
+                &self.paint_brush,
+                &mut user.current_paint_brush,
+                brush.clone(),
+            ),
+            (
+                &self.eraser_brush,
+                &mut user.current_eraser_brush,
+                brush.clone(),
+            ),
+            (&self.smudge_brush, &mut user.current_smudge_brush, brush),
+            (
+                &self.dodge_burn_brush,
+                &mut user.current_dodge_burn_brush,
+                retouch.clone(),
+            ),
+            (
+                &self.noise_brush,
+                &mut user.current_noise_brush,
+                retouch.clone(),
+            ),
+            (
+                &self.hue_shift_brush,
+                &mut user.current_hue_shift_brush,
+                retouch.clone(),
+            ),
+            (&self.clone_brush, &mut user.current_clone_brush, retouch),
+        ];
+        for (saved, brush, radii) in brushes {
+            if let Some(saved) = saved {
+                saved.restore(brush, radii);
+            }
+        }
+        if let Some([r, g, b, a]) = self.color.filter(|c| in_unit_range(c)) {
+            user.current_color = Rgba::from_rgba_unmultiplied(r, g, b, a);
+        }
+        if let Some([r, g, b]) = self.background_color.filter(|c| in_unit_range(c)) {
+            user.background_color = Rgba::from_rgb(r, g, b);
+        }
+        if let Some(tool) = self.tool {
+            user.current_tool = tool;
+        }
+        if let Some(eraser_mode) = self.eraser_mode {
+            user.eraser_mode = eraser_mode;
+        }
+        user
+    }
+
+    /// The saved symmetry on a `width` by `height` canvas, or symmetry centered on it if
+    /// none was saved. A center off the canvas is brought back onto it.
+    pub fn restore_symmetry(&self, width: u32, height: u32) -> Symmetry {
+        let Some(mut symmetry) = self.symmetry else {
+            return Symmetry::centered(width, height);
+        };
+        if !symmetry.center.iter().all(|c| c.is_finite()) {
+            symmetry.center = Symmetry::centered(width, height).center;
+        }
+        symmetry.move_center(symmetry.center(), width, height);
+        symmetry.segments = symmetry
+            .segments
+            .clamp(*Symmetry::SEGMENTS.start(), *Symmetry::SEGMENTS.end());
+        symmetry
+    }
+
+    /// The saved presets, with unusable brush settings restored to the defaults.
+    pub fn restore_presets(&self) -> Vec<BrushPreset> {
+        self.presets.iter().map(SavedPreset::restore).collect()
+    }
+
+    /// The saved swatch groups, all of them global whatever they say.
+    pub fn restore_swatches(&self) -> Vec<SwatchGroup> {
+        self.swatches
+            .iter()
+            .map(|group| SwatchGroup {
+                scope: SwatchScope::Global,
+                ..group.clone()
+            })
+            .collect()
+    }
+
+    /// The last document, if there is one and it's still there.
+    pub fn document_to_reopen(&self) -> Option<&Path> {
+        self.last_document.as_deref().filter(|path| path.is_file())
+    }
+}
+
+fn in_unit_range(components: &[f32]) -> bool {
+    components.iter().all(|c| (0.0..=1.0).contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::{self, DOCUMENT_PALETTE_KEYWORD};
+    use crate::paste;
+    use crate::test_support::{canvas, MemoryStorage};
+
+    fn saved_radius(radius: f32) -> Option<SavedBrush> {
+        Some(SavedBrush {
+            radius,
+            ..SavedBrush::default()
+        })
+    }
+
+    #[test]
+    fn each_brush_is_restored_within_its_own_tools_sizes() {
+        let session = SavedSession {
+            paint_brush: saved_radius(40.0),
+            eraser_brush: saved_radius(0.2),
+            dodge_burn_brush: saved_radius(40.0),
+            noise_brush: saved_radius(80.0),
+            hue_shift_brush: saved_radius(f32::NAN),
+            clone_brush: saved_radius(35.5),
+            ..SavedSession::default()
+        };
+        let user = session.restore_user();
+        assert_eq!(user.current_paint_brush.radius(), 20.0);
+        assert_eq!(user.current_eraser_brush.radius(), 1.0);
+        assert_eq!(user.current_dodge_burn_brush.radius(), 40.0);
+        assert_eq!(user.current_noise_brush.radius(), 50.0);
+        let default = User::default().current_hue_shift_brush.radius();
+        assert_eq!(user.current_hue_shift_brush.radius(), default);
+        assert_eq!(user.current_clone_brush.radius(), 35.5);
+    }
+
+    #[test]
+    fn a_captured_session_restores_every_brush_as_it_was() {
+        let mut user = User::default();
+        user.current_paint_brush.set_radius(12.0);
+        user.current_dodge_burn_brush.set_radius(45.0);
+        user.current_clone_brush.set_radius(30.0);
+        let session = SavedSession::capture(
+            &user,
+            None,
+            ReopenLastDocument::default(),
+            None,
+            false,
+            &[],
+            false,
+        );
+        let restored = session.restore_user();
+        assert_eq!(restored.current_paint_brush.radius(), 12.0);
+        assert_eq!(restored.current_dodge_burn_brush.radius(), 45.0);
+        assert_eq!(restored.current_clone_brush.radius(), 30.0);
+    }
+
+    #[test]
+    fn a_cut_short_or_garbled_session_is_refused_rather_than_half_read() {
+        let mut user = User::default();
+        user.current_tool = Tool::Eraser;
+        let session = SavedSession::capture(
+            &user,
+            Some(PathBuf::from("art.png")),
+            ReopenLastDocument::Always,
+            None,
+            true,
+            &[],
+            true,
+        );
+        let mut storage = MemoryStorage::default();
+        eframe::set_value(&mut storage, SESSION_KEY, &session);
+        let text = storage.0[SESSION_KEY].clone();
+        assert!(SavedSession::load(&storage).is_ok());
+
+        for end in (0..text.len()).step_by(7) {
+            storage
+                .0
+                .insert(SESSION_KEY.into(), text[..end].to_string());
+            assert!(SavedSession::load(&storage).is_err(), "cut at {}", end);
+        }
+        for garbage in [
+            "not a session",
+            "(tool: Some(Laser))",
+            "\0\u{ff}(((",
+            "[1, 2",
+        ] {
+            storage.0.insert(SESSION_KEY.into(), garbage.to_string());
+            assert!(SavedSession::load(&storage).is_err(), "{:?}", garbage);
+        }
+
+        // nothing stored is a first run, not an error
+        storage.0.clear();
+        assert!(SavedSession::load(&storage).unwrap().tool.is_none());
+    }
+
+    #[test]
+    fn a_session_missing_fields_restores_what_it_has() {
+        let mut storage = MemoryStorage::default();
+        // from a version that had a setting since dropped, and before most were added
+        let text = "(tool: Some(Eraser), color: Some((1.0, 0.0, 0.0, 2.0)), retired: 3)";
+        storage.0.insert(SESSION_KEY.into(), text.to_string());
+        let session = SavedSession::load(&storage).unwrap();
+        let user = session.restore_user();
+        assert_eq!(user.current_tool, Tool::Eraser);
+        // out of range, so the default is kept
+        assert_eq!(user.current_color, User::default().current_color);
+        assert!(session.document_to_reopen().is_none());
+        assert!(session.restore_presets().is_empty());
+    }
+
+    #[test]
+    fn a_damaged_last_document_fails_to_open_or_opens_without_its_palette() {
+        let folder = std::env::temp_dir().join(format!("rustbrush-session-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("art.png");
+        let chunk = (DOCUMENT_PALETTE_KEYWORD, "[{\"name\": \"Sk".to_string());
+        canvas(16, 16, 1).save_as_png(&path, &[chunk]).unwrap();
+        let png = std::fs::read(&path).unwrap();
+
+        // the image is fine, so it opens, and only the palette is lost
+        assert!(paste::load_png(&path).is_ok());
+        assert!(palette::read_document_palette(&path).is_err());
+
+        let session = SavedSession {
+            last_document: Some(path.clone()),
+            ..SavedSession::default()
+        };
+        for end in [0, 8, 40, png.len() / 2, png.len() - 12] {
+            std::fs::write(&path, &png[..end]).unwrap();
+            // still offered, since there's a file, but it can't be opened
+            assert_eq!(session.document_to_reopen(), Some(path.as_path()));
+            assert!(paste::load_png(&path).is_err(), "cut at {}", end);
+        }
+        let mut garbled = png.clone();
+        for byte in garbled.iter_mut().skip(40).step_by(3) {
+            *byte ^= 0x5a;
+        }
+        std::fs::write(&path, &garbled).unwrap();
+        let loaded = paste::load_png(&path);
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert!(loaded.is_err());
+    }
+}
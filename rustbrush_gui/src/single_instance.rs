@@ -0,0 +1,344 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use eframe::egui;
+use tracing::{debug, warn};
+
+use endpoint::{Listener, Stream};
+
+/// Where the running instance listens for others. Each user gets their own, so one user's
+/// second instance never hands its files to another user's window.
+///
+/// On Unix it's a socket in the user's runtime directory, which only they can reach. Where
+/// there's no such thing, it's a fixed port on the loopback interface, so nothing off the
+/// machine can connect, and the user's name in the greeting keeps users apart.
+#[cfg(unix)]
+mod endpoint {
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    pub use std::os::unix::net::{UnixListener as Listener, UnixStream as Stream};
+
+    fn path() -> PathBuf {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join("brushy.sock"),
+            None => std::env::temp_dir().join(format!("brushy-{}.sock", super::user())),
+        }
+    }
+
+    pub fn connect() -> io::Result<Stream> {
+        Stream::connect(path())
+    }
+
+    /// Binds the socket, taking it over if it was left behind by an instance that's gone.
+    pub fn bind() -> io::Result<Listener> {
+        let path = path();
+        let listener = match Listener::bind(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && Stream::connect(&path).is_err() => {
+                std::fs::remove_file(&path)?;
+                Listener::bind(&path)?
+            }
+            bound => bound?,
+        };
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+}
+
+#[cfg(not(unix))]
+mod endpoint {
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    pub use std::net::{TcpListener as Listener, TcpStream as Stream};
+
+    const ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::LOCALHOST, 47219);
+
+    pub fn connect() -> io::Result<Stream> {
+        Stream::connect_timeout(&SocketAddr::from(ADDRESS), super::TIMEOUT)
+    }
+
+    pub fn bind() -> io::Result<Listener> {
+        Listener::bind(ADDRESS)
+    }
+}
+
+/// What the first line of every request starts with, followed by the user's name. A
+/// listener ignores connections that don't start with its own greeting, and an instance
+/// that doesn't get [`ACK`] back knows it reached some other program, or another user's
+/// instance, and starts as usual.
+const PROTOCOL: &str = "brushy-open 2";
+const ACK: &str = "ok";
+
+/// How long either side waits on the other before giving up.
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The most a request can be, so a stray connection can't make the listener read forever.
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+/// The name of the user running the program, or nothing if it can't be told.
+fn user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+fn greeting() -> String {
+    format!("{} {}", PROTOCOL, user())
+}
+
+/// Writes a request to open `paths`: the greeting, then how many paths there are, then
+/// each path as its length in bytes on a line of its own followed by the bytes, so paths
+/// can hold any character, line breaks included. Where paths aren't bytes, those that
+/// aren't valid UTF-8 are sent as close as they can be.
+pub fn write_request(mut writer: impl Write, paths: &[PathBuf]) -> io::Result<()> {
+    writeln!(writer, "{}", greeting())?;
+    writeln!(writer, "{}", paths.len())?;
+    for path in paths {
+        let bytes = path_bytes(path);
+        writeln!(writer, "{}", bytes.len())?;
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()
+}
+
+/// Reads a request written by [`write_request`]. Anything else, including a request cut
+/// short, is an error.
+pub fn read_request(mut reader: impl BufRead) -> io::Result<Vec<PathBuf>> {
+    if read_line(&mut reader)? != greeting() {
+        return Err(invalid("not a request from another instance"));
+    }
+    let count = read_number(&mut reader)?;
+    let mut paths = Vec::new();
+    for _ in 0..count {
+        let length = read_number(&mut reader)?;
+        let mut bytes = Vec::new();
+        (&mut reader).take(length).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != length {
+            return Err(ended_early());
+        }
+        paths.push(path_from_bytes(bytes));
+    }
+    Ok(paths)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn ended_early() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "the request ended early")
+}
+
+/// Reads a line, without its line break. Unlike [`BufRead::read_line`], a line that's cut
+/// off before its line break is an error.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    match line.strip_suffix('\n') {
+        Some(line) => Ok(line.to_string()),
+        None => Err(ended_early()),
+    }
+}
+
+fn read_number(reader: &mut impl BufRead) -> io::Result<u64> {
+    read_line(reader)?
+        .parse()
+        .map_err(|_| invalid("expected a number"))
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Hands `paths` to an instance that's already running, for it to open. Returns whether
+/// one took them. If there isn't one, or anything goes wrong talking to it, this instance
+/// should just start as usual.
+pub fn forward(paths: &[PathBuf]) -> bool {
+    // the running instance has a different working directory
+    let paths: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| std::path::absolute(path).unwrap_or_else(|_| path.clone()))
+        .collect();
+    match try_forward(&paths) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Not forwarding to a running instance: {}", e);
+            false
+        }
+    }
+}
+
+fn try_forward(paths: &[PathBuf]) -> io::Result<()> {
+    let stream = endpoint::connect()?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write_request(&stream, paths)?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    match reply.trim_end() == ACK {
+        true => Ok(()),
+        false => Err(invalid("something other than an instance answered")),
+    }
+}
+
+/// Listens on a background thread for other instances forwarding files to open, for as
+/// long as the program runs.
+pub struct InstanceListener {
+    receiver: mpsc::Receiver<Vec<PathBuf>>,
+}
+
+impl InstanceListener {
+    /// Starts listening, repainting `ctx` whenever a request comes in so it's handled right
+    /// away. `None` if something else is already listening, such as another instance that
+    /// started at the same moment.
+    pub fn start(ctx: egui::Context) -> Option<Self> {
+        let listener = match endpoint::bind() {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Couldn't listen for other instances: {}", e);
+                return None;
+            }
+        };
+        match Self::spawn(listener, move || ctx.request_repaint()) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                warn!("Couldn't listen for other instances: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Serves requests from `listener` on a background thread, calling `received` after
+    /// each one is handed over.
+    fn spawn(listener: Listener, received: impl Fn() + Send + 'static) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("single instance".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream.and_then(serve) {
+                        Ok(paths) => {
+                            if sender.send(paths).is_err() {
+                                return;
+                            }
+                            received();
+                        }
+                        Err(e) => warn!("Ignoring a request from another instance: {}", e),
+                    }
+                }
+            })?;
+        Ok(Self { receiver })
+    }
+
+    /// Whether another instance has been started since the last call, along with the files
+    /// it forwarded, which may be none.
+    pub fn received(&self) -> Option<Vec<PathBuf>> {
+        self.receiver.try_iter().reduce(|mut all, paths| {
+            all.extend(paths);
+            all
+        })
+    }
+}
+
+fn serve(stream: Stream) -> io::Result<Vec<PathBuf>> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let paths = read_request(BufReader::new((&stream).take(MAX_REQUEST_BYTES)))?;
+    writeln!(&stream, "{}", ACK)?;
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(paths: &[PathBuf]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_request(&mut bytes, paths).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let paths = [
+            PathBuf::from("/home/me/cat.brushy"),
+            PathBuf::from("/home/me/with spaces/ünïcode.brushy"),
+            PathBuf::from("/home/me/two\nlines.brushy"),
+            PathBuf::from(""),
+        ];
+        assert_eq!(read_request(&request(&paths)[..]).unwrap(), paths);
+        assert!(read_request(&request(&[])[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn anything_but_this_users_greeting_is_refused() {
+        let mut bytes = request(&[PathBuf::from("a.brushy")]);
+        bytes[0] = b'B';
+        let error = read_request(&bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // another user's instance, or an older one
+        for greeting in [format!("{} someone-else", PROTOCOL), "brushy-open 1".into()] {
+            let other = format!("{}\n1\n8\na.brushy", greeting);
+            assert!(read_request(other.as_bytes()).is_err());
+        }
+        assert!(read_request(&b"GET / HTTP/1.1\r\n\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn a_truncated_request_is_refused() {
+        let bytes = request(&[PathBuf::from("a.brushy"), PathBuf::from("b.brushy")]);
+        for end in 0..bytes.len() {
+            assert!(
+                read_request(&bytes[..end]).is_err(),
+                "cut off after {end} bytes"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_listener_hands_over_forwarded_paths() {
+        let path = std::env::temp_dir().join(format!("brushy-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (notify, notified) = mpsc::channel();
+        let listener = InstanceListener::spawn(Listener::bind(&path).unwrap(), move || {
+            notify.send(()).unwrap();
+        })
+        .unwrap();
+
+        let paths = vec![PathBuf::from("/tmp/a.brushy"), PathBuf::from("b\nc")];
+        let client = Stream::connect(&path).unwrap();
+        write_request(&client, &paths).unwrap();
+        let mut reply = String::new();
+        BufReader::new(&client).read_line(&mut reply).unwrap();
+        assert_eq!(reply, "ok\n");
+
+        notified.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(listener.received(), Some(paths));
+        assert_eq!(listener.received(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustbrush_utils::canvas::{self, CanvasLimits, LayerKind, PasteImage};
+use serde::{Deserialize, Serialize};
+
+use crate::canvas::Canvas;
+use crate::user::User;
+
+/// The keyword of the iTXt chunk the document's stencil layers are kept in, in the
+/// document's PNG. They're never part of the image, so this is the only place they're kept;
+/// other apps see the image without them.
+pub const DOCUMENT_STENCILS_KEYWORD: &str = "brushy:stencils";
+
+/// A stencil layer as it's saved: where it is and the alpha of each of its pixels, row by
+/// row, which is all a stencil holds.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedStencil {
+    pub name: String,
+    pub visible: bool,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    /// Base64, so it fits in a text chunk.
+    pub alpha: String,
+}
+
+impl SavedStencil {
+    fn new(layer: &canvas::CanvasLayer) -> Self {
+        let bounds = layer.bounds();
+        Self {
+            name: layer.name.clone(),
+            visible: layer.visible,
+            x: bounds.x,
+            y: bounds.y,
+            width: bounds.width,
+            height: bounds.height,
+            alpha: BASE64.encode(layer.alpha()),
+        }
+    }
+
+    /// The stencil as white straight-alpha pixels, to paste onto a new stencil layer. Fails
+    /// if it's too big for a layer, see [`CanvasLimits`], or its alpha isn't its size.
+    fn image(&self) -> Result<PasteImage, Box<dyn std::error::Error>> {
+        CanvasLimits::default().check(self.width, self.height)?;
+        let alpha = BASE64.decode(&self.alpha)?;
+        if alpha.len() != self.width as usize * self.height as usize {
+            return Err(format!("stencil \"{}\" isn't the size it says", self.name).into());
+        }
+        Ok(PasteImage {
+            width: self.width,
+            height: self.height,
+            rgba: alpha.iter().flat_map(|&a| [255, 255, 255, a]).collect(),
+        })
+    }
+}
+
+/// The stencil layers of `canvas` as the text of the chunk they're saved in, see
+/// [`DOCUMENT_STENCILS_KEYWORD`]. `None` when there are none, so nothing is added to the
+/// file.
+pub fn document_chunk(canvas: &canvas::Canvas) -> Option<(&'static str, String)> {
+    let stencils: Vec<SavedStencil> = canvas
+        .state
+        .layers
+        .iter()
+        .filter(|layer| layer.kind() == LayerKind::Stencil)
+        .map(SavedStencil::new)
+        .collect();
+    if stencils.is_empty() {
+        return None;
+    }
+    let text = serde_json::to_string(&stencils).ok()?;
+    Some((DOCUMENT_STENCILS_KEYWORD, text))
+}
+
+/// The stencil layers kept in the PNG at `path`, bottom to top, empty if it has none. Only
+/// chunks before the image data are read, which is where this app writes them.
+pub fn read_document_stencils(
+    path: &Path,
+) -> Result<Vec<SavedStencil>, Box<dyn std::error::Error>> {
+    let reader = png::Decoder::new(BufReader::new(File::open(path)?)).read_info()?;
+    let Some(chunk) = reader
+        .info()
+        .utf8_text
+        .iter()
+        .find(|chunk| chunk.keyword == DOCUMENT_STENCILS_KEYWORD)
+    else {
+        return Ok(Vec::new());
+    };
+    Ok(serde_json::from_str(&chunk.get_text()?)?)
+}
+
+/// Adds `stencils` to the document as stencil layers on top, each painted in with a paste
+/// recorded like the one that opens the image, so undoing back past it empties them like
+/// the rest of the document. The part of a stencil off the canvas is cropped, as pastes
+/// are. Stops at the first one that can't be added.
+pub fn open_stencils(
+    canvas: &mut Canvas,
+    user: &mut User,
+    stencils: &[SavedStencil],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for stencil in stencils {
+        let image = stencil.image()?;
+        let layer = canvas.add_stencil_layer()?;
+        let target = &mut canvas.layers()[layer];
+        target.name = stencil.name.clone();
+        target.visible = stencil.visible;
+        let offset = (stencil.x, stencil.y);
+        canvas.paste(layer, &image, offset);
+        user.record_paste(layer, image, offset);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::canvas;
+    use eframe::egui::{Pos2, Rgba};
+    use rustbrush_utils::canvas::BrushStrokeKind;
+    use rustbrush_utils::testing::{hard_brush, stroke};
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rustbrush-{}-{}.png", name, std::process::id()))
+    }
+
+    #[test]
+    fn stencils_round_trip_through_the_png() {
+        let mut canvas = canvas(24, 16, 1);
+        let path = path("stencils");
+
+        // a document without stencils has nothing to read back
+        assert!(document_chunk(&canvas).is_none());
+        canvas.save_as_png(&path, &[]).unwrap();
+        assert!(read_document_stencils(&path).unwrap().is_empty());
+
+        let layer = canvas.add_stencil_layer().unwrap();
+        let points = [Pos2::new(4.0, 8.0), Pos2::new(18.0, 8.0)];
+        let (kind, brush) = (BrushStrokeKind::Paint, hard_brush(3.0));
+        stroke(&mut canvas, layer, kind, &brush, Rgba::RED, &points);
+        canvas.layers()[layer].visible = false;
+        let chunk = document_chunk(&canvas).unwrap();
+        canvas.save_as_png(&path, &[chunk]).unwrap();
+        let loaded = read_document_stencils(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded, [SavedStencil::new(&canvas.state.layers[layer])]);
+
+        let mut opened = crate::test_support::canvas(24, 16, 1);
+        let mut user = User::default();
+        open_stencils(&mut opened, &mut user, &loaded).unwrap();
+        let (saved, restored) = (&canvas.state.layers[layer], &opened.state.layers[1]);
+        assert_eq!(restored.kind(), LayerKind::Stencil);
+        assert_eq!((&restored.name, restored.visible), (&saved.name, false));
+        assert!(restored.alpha() == saved.alpha());
+        assert_eq!(user.action_history.len(), 1);
+    }
+
+    #[test]
+    fn a_stencil_that_isnt_its_size_is_refused() {
+        let mut canvas = canvas(8, 8, 1);
+        let stencil = SavedStencil {
+            name: "Stencil 1".into(),
+            visible: true,
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 8,
+            alpha: BASE64.encode([255; 8 * 7]),
+        };
+        let mut user = User::default();
+        assert!(open_stencils(&mut canvas, &mut user, &[stencil]).is_err());
+        assert_eq!(canvas.state.layers.len(), 1);
+        assert!(user.action_history.is_empty());
+    }
+}
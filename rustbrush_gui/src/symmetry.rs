@@ -0,0 +1,170 @@
+use std::f32::consts::TAU;
+
+use eframe::egui::{self, Color32, Pos2, Rect, Stroke};
+use rustbrush_utils::symmetry::Symmetry as DabSymmetry;
+use serde::{Deserialize, Serialize};
+
+/// How symmetry repeats what's painted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SymmetryMode {
+    /// Mirrored across a vertical line through the center, left to right.
+    #[default]
+    Vertical,
+    /// Mirrored across a horizontal line through the center, top to bottom.
+    Horizontal,
+    /// Mirrored across both lines, into all four quarters.
+    Both,
+    /// Turned around the center into equal segments, like a kaleidoscope without the mirror.
+    Radial,
+}
+
+impl SymmetryMode {
+    pub const ALL: [SymmetryMode; 4] = [
+        SymmetryMode::Vertical,
+        SymmetryMode::Horizontal,
+        SymmetryMode::Both,
+        SymmetryMode::Radial,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SymmetryMode::Vertical => "Vertical",
+            SymmetryMode::Horizontal => "Horizontal",
+            SymmetryMode::Both => "Both",
+            SymmetryMode::Radial => "Radial",
+        }
+    }
+}
+
+/// Symmetry painting settings. They belong to the document, and every stroke keeps a copy of
+/// the ones it was painted with, so moving the center later doesn't move what's already
+/// painted when the history is replayed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Symmetry {
+    pub enabled: bool,
+    pub mode: SymmetryMode,
+    /// Where the axes cross, in canvas pixels.
+    pub center: [f32; 2],
+    /// How many segments radial symmetry has, including the one painted in.
+    pub segments: u32,
+}
+
+impl Default for Symmetry {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mode: SymmetryMode::default(),
+            center: [0.0, 0.0],
+            segments: 6,
+        }
+    }
+}
+
+impl Symmetry {
+    pub const SEGMENTS: std::ops::RangeInclusive<u32> = 2..=16;
+    /// The size of the center handle on screen.
+    pub const HANDLE_RADIUS: f32 = 5.0;
+
+    /// Symmetry centered on a `width` by `height` canvas.
+    pub fn centered(width: u32, height: u32) -> Self {
+        Self {
+            center: [width as f32 / 2.0, height as f32 / 2.0],
+            ..Self::default()
+        }
+    }
+
+    pub fn center(&self) -> Pos2 {
+        Pos2::new(self.center[0], self.center[1])
+    }
+
+    /// Where `pos` is repeated, not counting `pos` itself. The images of two points come out
+    /// in the same order, so a segment maps to a segment.
+    pub fn images(&self, pos: Pos2) -> Vec<Pos2> {
+        self.dabs()
+            .images((pos.x, pos.y))
+            .into_iter()
+            .map(|(x, y)| Pos2::new(x, y))
+            .collect()
+    }
+
+    /// The symmetry the brush operations repeat dabs with, in the same coordinates as the
+    /// center. Mirroring across the vertical line flips left to right, which the operations
+    /// call horizontal, and the other way round.
+    pub fn dabs(&self) -> DabSymmetry {
+        let [x, y] = self.center;
+        match self.mode {
+            SymmetryMode::Vertical => DabSymmetry::Horizontal { axis_x: x },
+            SymmetryMode::Horizontal => DabSymmetry::Vertical { axis_y: y },
+            SymmetryMode::Both => DabSymmetry::Both {
+                axis_x: x,
+                axis_y: y,
+            },
+            SymmetryMode::Radial => DabSymmetry::Radial {
+                cx: x,
+                cy: y,
+                segments: self.segments,
+            },
+        }
+    }
+
+    /// Draws the axes across a `width` by `height` canvas, with a handle on the center. Kept
+    /// faint so it doesn't get in the way of judging the painting.
+    pub fn paint_guides(
+        &self,
+        painter: &egui::Painter,
+        origin: Pos2,
+        scale: f32,
+        width: u32,
+        height: u32,
+    ) {
+        let color = Color32::from_rgba_unmultiplied(0, 200, 255, 110);
+        let stroke = Stroke::new(1.0, color);
+        let (width, height) = (width as f32, height as f32);
+        let center = self.center();
+        let to_screen = |pos: Pos2| origin + pos.to_vec2() * scale;
+        let line = |from: Pos2, to: Pos2| {
+            painter.line_segment([to_screen(from), to_screen(to)], stroke);
+        };
+
+        match self.mode {
+            SymmetryMode::Vertical => line(Pos2::new(center.x, 0.0), Pos2::new(center.x, height)),
+            SymmetryMode::Horizontal => line(Pos2::new(0.0, center.y), Pos2::new(width, center.y)),
+            SymmetryMode::Both => {
+                line(Pos2::new(center.x, 0.0), Pos2::new(center.x, height));
+                line(Pos2::new(0.0, center.y), Pos2::new(width, center.y));
+            }
+            SymmetryMode::Radial => {
+                // spokes long enough to leave the canvas from anywhere on it
+                let canvas = Rect::from_min_max(Pos2::ZERO, Pos2::new(width, height));
+                let reach = canvas.size().length() + center.distance(canvas.center());
+                let segments = self.segments.max(1);
+                for i in 0..segments {
+                    let (sin, cos) = (TAU * i as f32 / segments as f32 - TAU / 4.0).sin_cos();
+                    line(center, center + egui::vec2(cos, sin) * reach);
+                }
+            }
+        }
+
+        painter.circle(
+            to_screen(center),
+            Self::HANDLE_RADIUS,
+            Color32::from_black_alpha(60),
+            Stroke::new(1.5, color),
+        );
+    }
+
+    /// Whether `screen_pos` is on the center handle, as drawn by [`Symmetry::paint_guides`].
+    pub fn handle_hit(&self, origin: Pos2, scale: f32, screen_pos: Pos2) -> bool {
+        let handle = origin + self.center().to_vec2() * scale;
+        handle.distance(screen_pos) <= Self::HANDLE_RADIUS + 3.0
+    }
+
+    /// Moves the center to `pos` on a `width` by `height` canvas. The center stays on the
+    /// canvas and on a whole or half pixel, so mirrored dabs land on pixel centers like the
+    /// ones they mirror.
+    pub fn move_center(&mut self, pos: Pos2, width: u32, height: u32) {
+        let snap = |value: f32, max: u32| ((value * 2.0).round() / 2.0).clamp(0.0, max as f32);
+        self.center = [snap(pos.x, width), snap(pos.y, height)];
+    }
+}
@@ -0,0 +1,28 @@
+//! What the tests need besides the canvases and strokes shared with rustbrush_utils, see
+//! [`rustbrush_utils::testing`].
+
+use crate::canvas::Canvas;
+use rustbrush_utils::testing::canvas_state;
+use std::collections::HashMap;
+
+/// A `width` by `height` canvas with `layers` empty layers covering it, with the textures
+/// the app draws it with.
+pub fn canvas(width: u32, height: u32, layers: usize) -> Canvas {
+    Canvas::new(canvas_state(width, height, layers)).unwrap()
+}
+
+/// Keeps what eframe stores in memory, the way it's kept on disk between runs.
+#[derive(Default)]
+pub struct MemoryStorage(pub HashMap<String, String>);
+
+impl eframe::Storage for MemoryStorage {
+    fn get_string(&self, key: &str) -> Option<String> {
+        self.0.get(key).cloned()
+    }
+
+    fn set_string(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+
+    fn flush(&mut self) {}
+}
@@ -1,20 +1,175 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::canvas::Canvas;
-use eframe::egui::{Color32, Pos2, Rgba};
-use rustbrush_utils::Brush;
+use crate::symmetry::Symmetry;
+use eframe::egui::{Color32, Modifiers, Pos2, Rgba, Vec2};
+use rustbrush_utils::{
+    alpha,
+    canvas::{
+        BrushStrokeFrame, BrushStrokeKind, CanvasError, CanvasLayer, FillOptions, LayerBounds,
+        LayerContents, PasteImage, ShapeOptions,
+    },
+    filters::Adjustment,
+    jitter::StrokeRng,
+    operations::{DodgeBurnMode, HsvShift, NoiseMode, TonalRange},
+    path,
+    pixel_buffer::DirtyRect,
+    sampling::SampleKernel,
+    selection::SelectionMask,
+    Brush, ALPHA_CHANNEL,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 pub type LayerIdx = usize;
 
+/// The tool used when the primary pointer button is pressed on the canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Tool {
+    Brush,
+    Eraser,
+    Eyedropper,
+    /// Drags the current layer around the canvas.
+    Move,
+    /// Flood fills the current layer, see [`FillOptions`].
+    Fill,
+    /// Draws a rectangle or ellipse on the current layer by dragging out its corners, see
+    /// [`ShapeOptions`].
+    Shape,
+    /// Lightens or darkens the current layer, see [`BrushStrokeKind::Dodge`].
+    DodgeBurn,
+    /// Adds grain to the current layer, see [`BrushStrokeKind::Noise`].
+    Noise,
+    /// Shifts the hue, saturation and value of the current layer, see
+    /// [`BrushStrokeKind::HueShift`].
+    HueShift,
+    /// Copies the current layer from where alt-click anchored it, see
+    /// [`BrushStrokeKind::Clone`].
+    Clone,
+}
+
+/// What the eraser leaves behind.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum EraserMode {
+    /// Erases to transparency.
+    Transparent,
+    /// Paints the background color, as if the paint were scraped off down to the canvas.
+    Background,
+    /// Erases to the background color on an opaque background layer, where transparency
+    /// would punch a hole through the document, and to transparency everywhere else.
+    #[default]
+    Auto,
+}
+
+impl EraserMode {
+    pub const ALL: [EraserMode; 3] = [
+        EraserMode::Auto,
+        EraserMode::Transparent,
+        EraserMode::Background,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            EraserMode::Transparent => "Erase to Transparent",
+            EraserMode::Background => "Erase to Background",
+            EraserMode::Auto => "Erase (Auto)",
+        }
+    }
+}
+
+/// Temporary tool switches, like holding Alt for the eyedropper. The tool a primary press
+/// uses is decided when the press starts and kept until it's released, so pressing or
+/// releasing the override key mid-press never turns a sample into a stroke or vice versa.
+#[derive(Default)]
+pub struct ToolOverride {
+    /// The tool to use instead of the current one while the override key is held.
+    pub held: Option<Tool>,
+    pressed: Option<Tool>,
+}
+
 pub struct User {
+    pub current_tool: Tool,
+    pub tool_override: ToolOverride,
+    /// Holding these modifiers temporarily switches to the eyedropper.
+    pub eyedropper_modifiers: Modifiers,
     pub current_color: Rgba,
+    /// The color the eraser paints with when erasing to the background. Opaque.
+    pub background_color: Rgba,
+    pub eraser_mode: EraserMode,
     pub current_paint_brush: Brush,
     pub current_eraser_brush: Brush,
     pub current_smudge_brush: Brush,
+    pub current_dodge_burn_brush: Brush,
+    pub current_noise_brush: Brush,
+    pub current_hue_shift_brush: Brush,
+    pub current_clone_brush: Brush,
     pub current_layer: LayerIdx,
     pub current_action_id: usize,
     pub action_history: Vec<UserAction>,
 
+    // eyedropper settings
+    /// What the eyedropper averages over, see
+    /// [`rustbrush_utils::operations::EyedropperOperation`].
+    pub eyedropper_kernel: SampleKernel,
+    pub eyedropper_sample_merged: bool,
+
+    /// Whether smudging picks up color from every visible layer, see
+    /// [`BrushStrokeKind::Smudge`].
+    pub smudge_sample_merged: bool,
+    /// See [`rustbrush_utils::operations::SmudgeOperation::pickup_rate`].
+    pub smudge_pickup_rate: f32,
+
+    // dodge and burn settings
+    pub dodge_burn_mode: DodgeBurnMode,
+    pub tonal_range: TonalRange,
+
+    // noise settings
+    pub noise_mode: NoiseMode,
+    /// See [`rustbrush_utils::operations::NoiseOperation::scale`].
+    pub noise_scale: f32,
+
+    /// What the hue shift tool shifts the pixels it fully covers by.
+    pub hue_shift: HsvShift,
+
+    /// Where alt-click with the clone tool anchored the source, in document coordinates.
+    clone_anchor: Option<Pos2>,
+    /// How far clone strokes copy from, fixed by the first stroke after anchoring. Later
+    /// strokes keep it, so the source moves along with them.
+    clone_offset: Option<(i32, i32)>,
+
+    /// When set, strokes are refit into a smooth curve on release, simplifying the raw
+    /// path with this tolerance in pixels.
+    pub post_smoothing: Option<f32>,
+    /// When set, each frame of a stroke curves on from the one before it while it's being
+    /// painted, rather than joining the cursor positions with straight lines.
+    pub curve_between_frames: bool,
+
+    /// Symmetry for new strokes. Strokes already painted keep their own.
+    pub symmetry: Symmetry,
+
+    /// The stencil layer new strokes are painted through, see
+    /// [`rustbrush_utils::canvas::LayerKind::Stencil`]. Strokes already painted keep their own.
+    pub stencil: Option<LayerIdx>,
+
+    pub fill: FillOptions,
+
+    pub shape: ShapeOptions,
+
+    /// The layer being moved, the cursor position the move started from and where the layer
+    /// was then, while the move tool is held.
+    moving: Option<(LayerIdx, Pos2, (i32, i32))>,
+    /// The layer a shape is being drawn on and the corner it was started from, in canvas
+    /// coordinates, while the shape tool is held.
+    shape_start: Option<(LayerIdx, Pos2)>,
+    /// The layer of the stroke in progress, if there is one.
+    stroke_layer: Option<LayerIdx>,
+    /// How far the canvas was from the document origin when the stroke in progress started,
+    /// to record its frames in document coordinates.
+    stroke_origin: Vec2,
+    /// Where the last brush stroke ended, in document coordinates, which a shift-click
+    /// strokes a straight line from.
+    line_start: Option<Pos2>,
+
     // all of these are set by the App struct
     pub cursor_position: Pos2,
     pub last_cursor_position: Pos2,
@@ -25,14 +180,60 @@ pub struct User {
 impl Default for User {
     fn default() -> Self {
         Self {
+            current_tool: Tool::Brush,
+            tool_override: ToolOverride::default(),
+            eyedropper_modifiers: Modifiers::ALT,
             current_color: Rgba::WHITE,
+            background_color: Rgba::WHITE,
+            eraser_mode: EraserMode::default(),
             current_paint_brush: Brush::default().with_strength(1.0),
             current_eraser_brush: Brush::default().with_strength(1.0),
             current_smudge_brush: Brush::default().with_strength(1.0),
+            current_dodge_burn_brush: Brush::default().with_strength(0.25),
+            current_noise_brush: Brush::default().with_strength(0.25),
+            current_hue_shift_brush: Brush::default().with_strength(1.0),
+            current_clone_brush: Brush::default().with_strength(1.0),
             current_layer: 0,
             current_action_id: 0,
             action_history: Vec::new(),
 
+            eyedropper_kernel: SampleKernel::default(),
+            eyedropper_sample_merged: true,
+
+            smudge_sample_merged: false,
+            smudge_pickup_rate: 0.25,
+
+            dodge_burn_mode: DodgeBurnMode::default(),
+            tonal_range: TonalRange::default(),
+
+            noise_mode: NoiseMode::default(),
+            noise_scale: 1.0,
+
+            hue_shift: HsvShift {
+                hue: 30.0,
+                ..HsvShift::default()
+            },
+
+            clone_anchor: None,
+            clone_offset: None,
+
+            post_smoothing: None,
+            curve_between_frames: true,
+
+            symmetry: Symmetry::default(),
+
+            stencil: None,
+
+            fill: FillOptions::default(),
+
+            shape: ShapeOptions::default(),
+
+            moving: None,
+            shape_start: None,
+            stroke_layer: None,
+            stroke_origin: Vec2::ZERO,
+            line_start: None,
+
             cursor_position: Pos2::ZERO,
             last_cursor_position: Pos2::ZERO,
             holding_pointer_primary: false,
@@ -42,76 +243,555 @@ impl Default for User {
 }
 
 impl User {
+    /// The sizes the paint, eraser and smudge brushes can be set to.
+    pub const BRUSH_RADIUS: std::ops::RangeInclusive<f32> = 1.0..=20.0;
+    /// The sizes the dodge and burn, noise, hue shift and clone brushes can be set to.
+    pub const RETOUCH_RADIUS: std::ops::RangeInclusive<f32> = 1.0..=50.0;
+
+    /// The tool the primary pointer acts with: the tool of the press in progress, if any,
+    /// otherwise the override tool while its key is held, otherwise the current tool.
+    pub fn effective_tool(&self) -> Tool {
+        self.tool_override
+            .pressed
+            .or(self.tool_override.held)
+            .unwrap_or(self.current_tool)
+    }
+
+    /// Whether a temporary override is in effect, during which no strokes may start.
+    pub fn is_tool_overridden(&self) -> bool {
+        self.tool_override.held.is_some()
+            || self
+                .tool_override
+                .pressed
+                .is_some_and(|tool| tool != self.current_tool)
+    }
+
+    /// Called when the modifiers change; holding `eyedropper_modifiers` overrides the tool,
+    /// except for the clone tool, whose alt-click anchors its source.
+    pub fn update_tool_override(&mut self, modifiers: Modifiers) {
+        self.tool_override.held = (modifiers.contains(self.eyedropper_modifiers)
+            && self.current_tool != Tool::Clone)
+            .then_some(Tool::Eyedropper);
+    }
+
+    /// Starts a primary press, returning the tool it will use until it's released.
+    pub fn press_primary(&mut self) -> Tool {
+        let tool = self.effective_tool();
+        self.tool_override.pressed = Some(tool);
+        self.holding_pointer_primary = true;
+        tool
+    }
+
+    /// Ends a primary press, returning the tool it used.
+    pub fn release_primary(&mut self) -> Option<Tool> {
+        self.holding_pointer_primary = false;
+        self.tool_override.pressed.take()
+    }
+
     pub fn undo(&mut self, canvas: &mut Canvas) {
-        if self.current_action_id > 0 {
-            self.current_action_id -= 1;
-            canvas.clear();
-            for action in self
-                .action_history
-                .iter()
-                .filter(|a| a.id <= self.current_action_id)
-            {
-                match &action.data {
-                    UserActionData::BrushStroke(stroke) => {
-                        for frame in &stroke.frames {
-                            canvas.process_brush_stroke_frame(
-                                self.current_layer,
-                                stroke.kind.clone(),
-                                frame,
-                            );
-                        }
-                    }
-                }
-            }
-            canvas.layers()[self.current_layer].mark_dirty();
+        if self.current_action_id > 0 && !canvas.is_read_only() {
+            self.set_current_action(canvas, self.current_action_id - 1);
         }
     }
 
     pub fn redo(&mut self, canvas: &mut Canvas) {
+        if canvas.is_read_only() {
+            return;
+        }
         if let Some(next_action) = self
             .action_history
             .iter()
             .find(|a| a.id > self.current_action_id)
         {
-            self.current_action_id = next_action.id;
-            canvas.clear();
-            for action in self
-                .action_history
-                .iter()
-                .filter(|a| a.id <= self.current_action_id)
-            {
-                match &action.data {
-                    UserActionData::BrushStroke(stroke) => {
-                        for frame in &stroke.frames {
-                            canvas.process_brush_stroke_frame(
-                                self.current_layer,
-                                stroke.kind.clone(),
-                                frame,
-                            );
+            self.set_current_action(canvas, next_action.id);
+        }
+    }
+
+    /// Undoes or redoes up to the action with `id`, taking out the layers of the merges
+    /// being undone and putting back those being redone, then replays the history.
+    fn set_current_action(&mut self, canvas: &mut Canvas, id: usize) {
+        let (from, to) = (self.current_action_id, id);
+        // the latest first, so each layer is taken out of the stack it was put into
+        for i in (0..self.action_history.len()).rev() {
+            let action = &mut self.action_history[i];
+            let undone = to < action.id && action.id <= from;
+            if let UserActionData::MergeVisible { layer, removed, .. } = &mut action.data {
+                if undone {
+                    let (id, layer) = (action.id, *layer);
+                    *removed = canvas.remove_layer(layer);
+                    self.shift_layer_indices(id, layer, false);
+                }
+            }
+        }
+        for i in 0..self.action_history.len() {
+            let action = &mut self.action_history[i];
+            let redone = from < action.id && action.id <= to;
+            if let UserActionData::MergeVisible { layer, removed, .. } = &mut action.data {
+                if let Some(merged) = removed.take_if(|_| redone) {
+                    let (id, layer) = (action.id, *layer);
+                    // only refused on a read-only document, which isn't redone in the first place
+                    if canvas.insert_layer(layer, merged).is_ok() {
+                        self.shift_layer_indices(id, layer, true);
+                    }
+                }
+            }
+        }
+        self.current_action_id = id;
+        self.replay_history(canvas);
+    }
+
+    /// Keeps the layers of the actions before the one with `id`, and the user's own layers,
+    /// pointing at the same layers once one's been inserted into the stack at `index`, or
+    /// removed from it. Actions from `id` on were recorded with the stack as it is with the
+    /// layer in it, and keep their layers as they are.
+    fn shift_layer_indices(&mut self, id: usize, index: LayerIdx, inserted: bool) {
+        let shift = |layer: &mut LayerIdx| match inserted {
+            true if *layer >= index => *layer += 1,
+            false if *layer > index => *layer -= 1,
+            _ => {}
+        };
+        for action in self.action_history.iter_mut().filter(|a| a.id < id) {
+            action.data.layers_mut().into_iter().for_each(shift);
+        }
+        if !inserted && self.current_layer == index {
+            // back to the layer it was added above
+            self.current_layer = index.saturating_sub(1);
+        } else {
+            shift(&mut self.current_layer);
+        }
+        if !inserted && self.fill.reference == Some(index) {
+            self.fill.reference = None;
+        }
+        self.fill.reference.iter_mut().for_each(shift);
+        self.stencil.iter_mut().for_each(shift);
+    }
+
+    /// Rebuilds the canvas from scratch by replaying every action up to the current one.
+    fn replay_history(&self, canvas: &mut Canvas) {
+        canvas.clear();
+        for action in self
+            .action_history
+            .iter()
+            .filter(|a| a.id <= self.current_action_id)
+        {
+            match &action.data {
+                UserActionData::BrushStroke(stroke) => {
+                    canvas.begin_brush_stroke(
+                        stroke.symmetry,
+                        stroke.stencil,
+                        stroke.seed,
+                        stroke.length,
+                    );
+                    for frame in &stroke.frames {
+                        let result = canvas.process_brush_stroke_frame(
+                            stroke.layer,
+                            stroke.kind.clone(),
+                            frame,
+                        );
+                        if let Err(e) = result {
+                            warn!("Skipping stroke {} while replaying: {}", action.id, e);
+                            break;
                         }
                     }
+                    canvas.end_brush_stroke();
+                }
+                UserActionData::Adjustment { layers, adjustment } => {
+                    for layer in layers {
+                        canvas.apply_adjustment(*layer, adjustment);
+                    }
+                }
+                UserActionData::Selection(selection) => {
+                    canvas.set_selection(selection.clone());
+                }
+                UserActionData::Paste {
+                    layer,
+                    image,
+                    offset,
+                } => {
+                    canvas.paste(*layer, image, *offset);
+                }
+                UserActionData::Filter {
+                    layer,
+                    rect,
+                    pixels,
+                } => {
+                    canvas.restore_rect(*layer, *rect, pixels);
+                }
+                UserActionData::Move { layer, offset } => {
+                    canvas.move_layer(*layer, *offset);
+                }
+                UserActionData::MergeVisible {
+                    layer, contents, ..
+                } => {
+                    canvas.restore_layer(*layer, contents.clone());
+                }
+                UserActionData::ResizeCanvas { rect } => {
+                    if let Err(e) = canvas.resize(*rect) {
+                        warn!("Skipping resize {} while replaying: {}", action.id, e);
+                    }
                 }
             }
-            canvas.layers()[self.current_layer].mark_dirty();
+        }
+        if let Some(layer) = canvas.layers().get_mut(self.current_layer) {
+            layer.mark_dirty();
+        }
+    }
+
+    /// Records an adjustment that has already been applied to `layers`, as a single action.
+    pub fn record_adjustment(&mut self, layers: Vec<LayerIdx>, adjustment: Adjustment) {
+        self.truncate_action_history();
+        self.current_action_id += 1;
+        self.action_history.push(UserAction {
+            kind: UserActionKind::Adjustment,
+            id: self.current_action_id,
+            metadata: ActionMetadata::default(),
+            data: UserActionData::Adjustment { layers, adjustment },
+        });
+    }
+
+    /// Records a filter that has already been applied to `layer`, as a snapshot of the
+    /// `rect` it changed. Filters can't be replayed like adjustments, so undo and redo put
+    /// the snapshot back instead.
+    pub fn record_filter(&mut self, layer: LayerIdx, rect: DirtyRect, pixels: Vec<Color32>) {
+        self.record_snapshot(UserActionKind::Filter, layer, rect, pixels);
+    }
+
+    /// Flood fills the current layer from the cursor with the current color, see
+    /// [`Canvas::fill`]. Recorded like a filter, as a snapshot of what it changed.
+    pub fn fill(&mut self, canvas: &mut Canvas) -> Result<(), CanvasError> {
+        let position = self.cursor_position;
+        if position.x < 0.0 || position.y < 0.0 {
+            return Ok(());
+        }
+        let seed = (position.x as u32, position.y as u32);
+        let layer = self.current_layer;
+        let rect = canvas.fill(layer, seed, self.current_color, &self.fill)?;
+        if let Some(pixels) = canvas
+            .snapshot_rect(layer, rect)
+            .filter(|_| !rect.is_empty())
+        {
+            self.record_snapshot(UserActionKind::Fill, layer, rect, pixels);
+        }
+        Ok(())
+    }
+
+    /// Starts a shape on the current layer, with one corner at the cursor.
+    pub fn start_shape(&mut self) {
+        self.shape_start = Some((self.current_layer, self.cursor_position));
+    }
+
+    /// The corners of the shape being drawn, in canvas coordinates, for previewing it until
+    /// it's finished.
+    pub fn shape_corners(&self) -> Option<(Pos2, Pos2)> {
+        self.shape_start
+            .map(|(_, start)| (start, self.cursor_position))
+    }
+
+    /// Draws the shape being drawn with its other corner at the cursor, in the current color,
+    /// see [`Canvas::draw_shape`]. Recorded like a fill, as a snapshot of what it changed.
+    pub fn finish_shape(&mut self, canvas: &mut Canvas) -> Result<(), CanvasError> {
+        let Some((layer, start)) = self.shape_start.take() else {
+            return Ok(());
+        };
+        let rect = canvas.draw_shape(
+            layer,
+            (start.x, start.y),
+            (self.cursor_position.x, self.cursor_position.y),
+            self.current_color,
+            &self.shape,
+        )?;
+        if let Some(pixels) = canvas
+            .snapshot_rect(layer, rect)
+            .filter(|_| !rect.is_empty())
+        {
+            self.record_snapshot(UserActionKind::Shape, layer, rect, pixels);
+        }
+        Ok(())
+    }
+
+    fn record_snapshot(
+        &mut self,
+        kind: UserActionKind,
+        layer: LayerIdx,
+        rect: DirtyRect,
+        pixels: Vec<Color32>,
+    ) {
+        self.truncate_action_history();
+        self.current_action_id += 1;
+        self.action_history.push(UserAction {
+            kind,
+            id: self.current_action_id,
+            metadata: ActionMetadata::default(),
+            data: UserActionData::Filter {
+                layer,
+                rect,
+                pixels,
+            },
+        });
+    }
+
+    /// Replaces the canvas selection (`None` deselects) as an undoable action.
+    pub fn select(
+        &mut self,
+        canvas: &mut Canvas,
+        selection: Option<SelectionMask>,
+    ) -> Result<(), CanvasError> {
+        canvas.check_editable()?;
+        canvas.set_selection(selection.clone());
+        self.truncate_action_history();
+        self.current_action_id += 1;
+        self.action_history.push(UserAction {
+            kind: UserActionKind::Selection,
+            id: self.current_action_id,
+            metadata: ActionMetadata::default(),
+            data: UserActionData::Selection(selection),
+        });
+        Ok(())
+    }
+
+    /// Merges the visible layers into a new layer just above the current one, which becomes
+    /// the current layer, as an undoable action. Undoing takes the layer out again.
+    pub fn merge_visible(&mut self, canvas: &mut Canvas) -> Result<(), CanvasError> {
+        let layer = canvas.merge_visible(self.current_layer)?;
+        let contents = canvas
+            .snapshot_layer(layer)
+            .ok_or(CanvasError::NoSuchLayer(layer))?;
+        self.truncate_action_history();
+        self.current_action_id += 1;
+        self.shift_layer_indices(self.current_action_id, layer, true);
+        self.current_layer = layer;
+        self.action_history.push(UserAction {
+            kind: UserActionKind::MergeVisible,
+            id: self.current_action_id,
+            metadata: ActionMetadata::default(),
+            data: UserActionData::MergeVisible {
+                layer,
+                contents,
+                removed: None,
+            },
+        });
+        Ok(())
+    }
+
+    /// Crops or expands the canvas to `rect`, see [`Canvas::resize`], as an undoable action.
+    /// The symmetry center stays over the same part of the painting, as far as the new
+    /// canvas allows.
+    pub fn resize_canvas(
+        &mut self,
+        canvas: &mut Canvas,
+        rect: LayerBounds,
+    ) -> Result<(), CanvasError> {
+        if rect.is_empty() || rect == LayerBounds::canvas(canvas.state.width, canvas.state.height) {
+            return Ok(());
+        }
+        canvas.resize(rect)?;
+        let center = self.symmetry.center() - Vec2::new(rect.x as f32, rect.y as f32);
+        self.symmetry
+            .move_center(center, canvas.state.width, canvas.state.height);
+        self.truncate_action_history();
+        self.current_action_id += 1;
+        self.action_history.push(UserAction {
+            kind: UserActionKind::ResizeCanvas,
+            id: self.current_action_id,
+            metadata: ActionMetadata::default(),
+            data: UserActionData::ResizeCanvas { rect },
+        });
+        Ok(())
+    }
+
+    /// Records a paste that has already been composited into `layer`.
+    pub fn record_paste(&mut self, layer: LayerIdx, image: PasteImage, offset: (i32, i32)) {
+        self.truncate_action_history();
+        self.current_action_id += 1;
+        self.action_history.push(UserAction {
+            kind: UserActionKind::Paste,
+            id: self.current_action_id,
+            metadata: ActionMetadata::default(),
+            data: UserActionData::Paste {
+                layer,
+                image,
+                offset,
+            },
+        });
+    }
+
+    /// The kind of stroke the eraser makes on the current layer, resolving the eraser mode.
+    pub fn eraser_stroke_kind(&self, canvas: &Canvas) -> BrushStrokeKind {
+        let to_background = match self.eraser_mode {
+            EraserMode::Transparent => false,
+            EraserMode::Background => true,
+            EraserMode::Auto => canvas.is_opaque_background(self.current_layer),
+        };
+        if to_background {
+            BrushStrokeKind::EraseToBackground
+        } else {
+            BrushStrokeKind::Erase
+        }
+    }
+
+    /// The kind of stroke the dodge and burn tool paints, in its current mode and range.
+    pub fn dodge_burn_stroke_kind(&self) -> BrushStrokeKind {
+        let range = self.tonal_range;
+        match self.dodge_burn_mode {
+            DodgeBurnMode::Dodge => BrushStrokeKind::Dodge { range },
+            DodgeBurnMode::Burn => BrushStrokeKind::Burn { range },
+        }
+    }
+
+    /// The kind of stroke the noise tool paints, with its current settings.
+    pub fn noise_stroke_kind(&self) -> BrushStrokeKind {
+        BrushStrokeKind::Noise {
+            mode: self.noise_mode,
+            scale: self.noise_scale,
+        }
+    }
+
+    /// Anchors the clone tool's source at the cursor. The next clone stroke copies from
+    /// here, wherever it starts.
+    pub fn set_clone_anchor(&mut self, canvas: &Canvas) {
+        self.clone_anchor = Some(canvas.to_document(self.cursor_position));
+        self.clone_offset = None;
+    }
+
+    /// The kind of stroke the clone tool paints from the cursor, or `None` if no source has
+    /// been anchored yet. The first stroke after anchoring fixes the offset to the anchor.
+    pub fn clone_stroke_kind(&mut self, canvas: &Canvas) -> Option<BrushStrokeKind> {
+        let anchor = self.clone_anchor?;
+        let offset = *self.clone_offset.get_or_insert_with(|| {
+            let from = anchor - canvas.to_document(self.cursor_position);
+            (from.x.round() as i32, from.y.round() as i32)
+        });
+        Some(BrushStrokeKind::Clone { offset })
+    }
+
+    /// Where the clone tool would copy from at the cursor, in canvas coordinates, if it has a
+    /// source.
+    pub fn clone_source(&self, canvas: &Canvas) -> Option<Pos2> {
+        match self.clone_offset {
+            Some((x, y)) => Some(self.cursor_position + Vec2::new(x as f32, y as f32)),
+            None => self.clone_anchor.map(|anchor| canvas.to_canvas(anchor)),
+        }
+    }
+
+    /// Starts moving the current layer with the cursor.
+    pub fn start_move(&mut self, canvas: &Canvas) {
+        if let Some(layer) = canvas.state.layers.get(self.current_layer) {
+            self.moving = Some((self.current_layer, self.cursor_position, layer.offset()));
+        }
+    }
+
+    /// Moves the layer being moved along with the cursor, by whole pixels.
+    pub fn continue_move(&mut self, canvas: &mut Canvas) {
+        if let Some((layer, start, offset)) = self.moving {
+            let delta = self.cursor_position - start;
+            let offset = (
+                offset.0 + delta.x.round() as i32,
+                offset.1 + delta.y.round() as i32,
+            );
+            canvas.move_layer(layer, offset);
         }
     }
 
-    pub fn start_brush_stroke(&mut self, kind: BrushStrokeKind) {
+    /// Ends the move, recording it if the layer ended up somewhere else.
+    pub fn finish_move(&mut self, canvas: &Canvas) {
+        let Some((layer, _, from)) = self.moving.take() else {
+            return;
+        };
+        let Some(offset) = canvas.state.layers.get(layer).map(|l| l.offset()) else {
+            return;
+        };
+        if offset == from {
+            return;
+        }
         self.truncate_action_history();
         self.current_action_id += 1;
+        self.action_history.push(UserAction {
+            kind: UserActionKind::Move,
+            id: self.current_action_id,
+            metadata: ActionMetadata::default(),
+            data: UserActionData::Move { layer, offset },
+        });
+    }
+
+    /// Starts a stroke on the current layer, failing if there's no such layer. The layer is
+    /// checked once here, and the whole stroke goes to it.
+    pub fn start_brush_stroke(
+        &mut self,
+        kind: BrushStrokeKind,
+        canvas: &mut Canvas,
+    ) -> Result<(), CanvasError> {
+        canvas.check_editable()?;
+        canvas.check_layer(self.current_layer)?;
+        self.truncate_action_history();
+        self.current_action_id += 1;
+
+        let tapers = kind.tapers(self.stroke_brush(&kind));
+        let mut stroke = BrushStroke::new(kind, self.current_layer);
+        if self.post_smoothing.is_some() || tapers {
+            stroke.rollback = canvas.snapshot_layer(self.current_layer);
+        }
+        // the stroke and its symmetry are kept in document coordinates
+        self.stroke_origin = canvas.to_document(Pos2::ZERO).to_vec2();
+        stroke.symmetry = self.symmetry.enabled.then(|| {
+            let mut symmetry = self.symmetry;
+            let center = symmetry.center() + self.stroke_origin;
+            symmetry.center = [center.x, center.y];
+            symmetry
+        });
+        stroke.stencil = self.stencil;
+        canvas.begin_brush_stroke(stroke.symmetry, stroke.stencil, stroke.seed, None);
+        self.stroke_layer = Some(self.current_layer);
+
         self.action_history.push(UserAction {
             kind: UserActionKind::BrushStroke,
             id: self.current_action_id,
-            timestamp: Instant::now(),
-            data: UserActionData::BrushStroke(BrushStroke::new(kind)),
+            metadata: ActionMetadata::default(),
+            data: UserActionData::BrushStroke(stroke),
         });
+        Ok(())
+    }
+
+    /// Strokes a straight line from where the last stroke ended to the cursor, as a stroke
+    /// of its own painted in one frame, so it's undone like any other. With no stroke to
+    /// start from it starts an ordinary stroke at the cursor instead.
+    pub fn stroke_line(
+        &mut self,
+        kind: BrushStrokeKind,
+        canvas: &mut Canvas,
+    ) -> Result<(), CanvasError> {
+        let start = self.line_start(canvas);
+        self.start_brush_stroke(kind, canvas)?;
+        let Some(start) = start else {
+            return Ok(());
+        };
+        self.last_cursor_position = start;
+        let painted = match self.continue_brush_stroke() {
+            Ok((layer, kind, frame)) => canvas.process_brush_stroke_frame(layer, kind, frame),
+            Err(e) => {
+                warn!("Error processing line: {:?}", e);
+                Ok(())
+            }
+        };
+        let finished = self.finish_brush_stroke(canvas);
+        painted.and(finished)
+    }
+
+    /// Where a shift-click would stroke a line from, in canvas coordinates, if anywhere.
+    pub fn line_start(&self, canvas: &Canvas) -> Option<Pos2> {
+        self.line_start.map(|start| canvas.to_canvas(start))
+    }
+
+    /// Whether a stroke was started and hasn't been finished yet.
+    pub fn is_stroking(&self) -> bool {
+        self.stroke_layer.is_some()
     }
 
     pub fn continue_brush_stroke(
         &mut self,
     ) -> Result<(LayerIdx, BrushStrokeKind, &BrushStrokeFrame), Box<dyn std::error::Error>> {
-        let layer = self.current_layer;
-        let color = self.current_color;
+        let Some(layer) = self.stroke_layer else {
+            return Err("No stroke in progress".into());
+        };
 
         let current_brush_stroke_kind: BrushStrokeKind = match self.current_action() {
             Some(action) => match &action.data {
@@ -121,14 +801,15 @@ impl User {
             None => return Err("No current action".into()),
         };
 
-        let brush = match current_brush_stroke_kind {
-            BrushStrokeKind::Paint => self.current_paint_brush.clone(),
-            BrushStrokeKind::Erase => self.current_eraser_brush.clone(),
-            BrushStrokeKind::Smudge => self.current_smudge_brush.clone(),
+        let brush = self.stroke_brush(&current_brush_stroke_kind).clone();
+        let color = match current_brush_stroke_kind {
+            BrushStrokeKind::EraseToBackground => self.background_color,
+            _ => self.current_color,
         };
 
-        let cursor_position = self.cursor_position;
-        let last_cursor_position = self.last_cursor_position;
+        let cursor_position = self.cursor_position + self.stroke_origin;
+        let last_cursor_position = self.last_cursor_position + self.stroke_origin;
+        let curve_between_frames = self.curve_between_frames;
 
         if let Some((layer, current_action_kind, action)) = self
             .current_action()
@@ -136,28 +817,147 @@ impl User {
         {
             match &mut action.data {
                 UserActionData::BrushStroke(stroke) => {
+                    let timestamp = Instant::now();
+                    let elapsed = stroke.frames.last().map_or(0.0, |previous| {
+                        (timestamp - previous.timestamp).as_secs_f32()
+                    });
+                    let previous_cursor_position = stroke
+                        .frames
+                        .last()
+                        .map(|previous| previous.last_cursor_position)
+                        .filter(|&previous| {
+                            curve_between_frames && previous != last_cursor_position
+                        });
                     stroke.add_frame(BrushStrokeFrame {
                         brush,
                         color,
                         cursor_position,
                         last_cursor_position,
+                        previous_cursor_position,
+                        timestamp,
+                        elapsed,
                     });
 
-                    return Ok((layer, current_action_kind, &stroke.frames.last().unwrap()));
+                    return Ok((layer, current_action_kind, stroke.frames.last().unwrap()));
                 }
+                UserActionData::Adjustment { .. }
+                | UserActionData::Selection(_)
+                | UserActionData::Paste { .. }
+                | UserActionData::Filter { .. }
+                | UserActionData::Move { .. }
+                | UserActionData::MergeVisible { .. }
+                | UserActionData::ResizeCanvas { .. } => {}
             }
         }
 
         Err("I have absolutely no idea how you ended up here. You will have to read the code, sorry.".into())
     }
 
-    fn current_action(&mut self) -> Option<&mut UserAction> {
-        for action in self.action_history.iter_mut().rev() {
-            if action.id == self.current_action_id {
-                return Some(action);
+    /// The brush strokes of `kind` are painted with.
+    fn stroke_brush(&self, kind: &BrushStrokeKind) -> &Brush {
+        match kind {
+            BrushStrokeKind::Paint => &self.current_paint_brush,
+            BrushStrokeKind::Erase | BrushStrokeKind::EraseToBackground => {
+                &self.current_eraser_brush
             }
+            BrushStrokeKind::Smudge { .. } => &self.current_smudge_brush,
+            BrushStrokeKind::Dodge { .. } | BrushStrokeKind::Burn { .. } => {
+                &self.current_dodge_burn_brush
+            }
+            BrushStrokeKind::Noise { .. } => &self.current_noise_brush,
+            BrushStrokeKind::HueShift { .. } => &self.current_hue_shift_brush,
+            BrushStrokeKind::Clone { .. } => &self.current_clone_brush,
+        }
+    }
+
+    /// Called when the pointer is released to end the current brush stroke.
+    ///
+    /// The fade tail (if the brush has one and the pointer was still moving fast enough) is
+    /// appended to the stroke and painted, so undo removes it together with the stroke. With
+    /// post-smoothing on, or a brush that tapers, the stroke's pixels are then rolled back
+    /// and the whole stroke is re-rendered: along a smoothed path that replaces the raw
+    /// frames, and with its end tapered now that its length is known.
+    pub fn finish_brush_stroke(&mut self, canvas: &mut Canvas) -> Result<(), CanvasError> {
+        let post_smoothing = self.post_smoothing;
+        if self.stroke_layer.take().is_none() {
+            return Ok(());
+        }
+        self.line_start = Some(self.cursor_position + self.stroke_origin);
+        let Some(action) = self.current_action() else {
+            return Ok(());
+        };
+        let UserActionData::BrushStroke(stroke) = &mut action.data else {
+            return Ok(());
+        };
+        let layer = stroke.layer;
+
+        let rollback = stroke.rollback.take();
+        if let (Some(tolerance), Some(_)) = (post_smoothing, &rollback) {
+            stroke.frames = stroke.smoothed_frames(tolerance);
         }
-        None
+        // only the tail is left to paint, unless the stroke is painted again from the start
+        let painted = match rollback {
+            Some(_) => 0,
+            None => stroke.frames.len(),
+        };
+        for frame in stroke.fade_tail_frames() {
+            stroke.add_frame(frame);
+        }
+        stroke.length = Some(stroke.path_length());
+        let layer_name = canvas
+            .state
+            .layers
+            .get(layer)
+            .map_or("", |l| l.name.as_str());
+        action.metadata = ActionMetadata {
+            name: action.metadata.name.take(),
+            ..stroke.metadata(layer_name)
+        };
+
+        if let Some(rollback) = rollback {
+            canvas.restore_layer(layer, rollback);
+            canvas.begin_brush_stroke(stroke.symmetry, stroke.stencil, stroke.seed, stroke.length);
+        }
+        let painted = stroke.frames[painted..].iter().try_for_each(|frame| {
+            canvas.process_brush_stroke_frame(layer, stroke.kind.clone(), frame)
+        });
+        canvas.end_brush_stroke();
+        painted
+    }
+
+    /// Undoes or redoes every action up to and including the one with `id`, or back to the
+    /// start with 0.
+    pub fn go_to_action(&mut self, canvas: &mut Canvas, id: usize) {
+        if canvas.is_read_only() || id == self.current_action_id {
+            return;
+        }
+        if id == 0 || self.action_history.iter().any(|a| a.id == id) {
+            self.set_current_action(canvas, id);
+        }
+    }
+
+    /// Names the action with `id`, marking it as a milestone that the history pins. A blank
+    /// name takes the name away, and the action goes back to its generated label. Returns
+    /// whether there's such an action.
+    pub fn rename_action(&mut self, id: usize, label: &str) -> bool {
+        let Some(action) = self.action_history.iter_mut().find(|a| a.id == id) else {
+            return false;
+        };
+        let label = label.trim();
+        action.metadata.name = (!label.is_empty()).then(|| label.to_string());
+        true
+    }
+
+    /// Keeps the current layer pointing at a layer when there are `layer_count` of them.
+    pub fn clamp_current_layer(&mut self, layer_count: usize) {
+        self.current_layer = self.current_layer.min(layer_count.saturating_sub(1));
+    }
+
+    fn current_action(&mut self) -> Option<&mut UserAction> {
+        self.action_history
+            .iter_mut()
+            .rev()
+            .find(|action| action.id == self.current_action_id)
     }
 
     /// Remove all actions from the history that are older than the current action.
@@ -172,47 +972,763 @@ impl User {
 #[derive(Clone)]
 pub enum UserActionKind {
     BrushStroke,
+    Adjustment,
+    Selection,
+    Paste,
+    Filter,
+    Fill,
+    Shape,
+    Move,
+    MergeVisible,
+    ResizeCanvas,
+}
+
+impl UserActionKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            UserActionKind::BrushStroke => "Brush Stroke",
+            UserActionKind::Adjustment => "Adjustment",
+            UserActionKind::Selection => "Selection",
+            UserActionKind::Paste => "Paste",
+            UserActionKind::Filter => "Filter",
+            UserActionKind::Fill => "Fill",
+            UserActionKind::Shape => "Shape",
+            UserActionKind::Move => "Move Layer",
+            UserActionKind::MergeVisible => "Merge Visible",
+            UserActionKind::ResizeCanvas => "Resize Canvas",
+        }
+    }
+}
+
+/// What the history shows about an action. A stroke's is worked out when it's finished, see
+/// [`User::finish_brush_stroke`]; other actions only have their kind to go by.
+#[derive(Clone, Default)]
+pub struct ActionMetadata {
+    /// Generated from what the action did. Empty if there's nothing to say beyond its kind.
+    pub label: String,
+    /// Given with [`User::rename_action`] to mark a milestone, which the history pins. Shown
+    /// instead of the generated label.
+    pub name: Option<String>,
+    /// The part of the document the action changed, in document coordinates, if it's known.
+    pub bounds: Option<LayerBounds>,
+    /// From the action's first frame to its last.
+    pub duration: Duration,
+    pub frame_count: usize,
 }
 
 pub struct UserAction {
     pub id: usize,
     pub kind: UserActionKind,
-    pub timestamp: Instant,
+    pub metadata: ActionMetadata,
     pub data: UserActionData,
 }
 
+impl UserAction {
+    /// What the action is called in the history: its name if it has one, otherwise its
+    /// generated label, otherwise its kind.
+    pub fn label(&self) -> &str {
+        match &self.metadata.name {
+            Some(name) => name,
+            None if self.metadata.label.is_empty() => self.kind.label(),
+            None => &self.metadata.label,
+        }
+    }
+
+    /// Whether the action has been named as a milestone.
+    pub fn is_pinned(&self) -> bool {
+        self.metadata.name.is_some()
+    }
+}
+
 pub enum UserActionData {
     BrushStroke(BrushStroke),
+    Adjustment {
+        layers: Vec<LayerIdx>,
+        adjustment: Adjustment,
+    },
+    Selection(Option<SelectionMask>),
+    Paste {
+        layer: LayerIdx,
+        image: PasteImage,
+        offset: (i32, i32),
+    },
+    /// The pixels a filter or fill left in `rect` of `layer`.
+    Filter {
+        layer: LayerIdx,
+        rect: DirtyRect,
+        pixels: Vec<Color32>,
+    },
+    /// Where `layer` was moved to.
+    Move {
+        layer: LayerIdx,
+        offset: (i32, i32),
+    },
+    /// What the visible layers merged into `layer`, a layer the merge added. Shared with the
+    /// layer until it's edited.
+    MergeVisible {
+        layer: LayerIdx,
+        contents: LayerContents,
+        /// The layer itself, taken out of the stack while the merge is undone, to put back
+        /// when it's redone.
+        removed: Option<CanvasLayer>,
+    },
+    /// The part of the canvas, as it was, that it was cropped or expanded to.
+    ResizeCanvas {
+        rect: LayerBounds,
+    },
 }
 
-#[derive(Clone)]
-pub enum BrushStrokeKind {
-    Paint,
-    Erase,
-    Smudge,
+impl UserActionData {
+    /// The layers the action refers to.
+    fn layers_mut(&mut self) -> Vec<&mut LayerIdx> {
+        match self {
+            UserActionData::BrushStroke(stroke) => std::iter::once(&mut stroke.layer)
+                .chain(&mut stroke.stencil)
+                .collect(),
+            UserActionData::Adjustment { layers, .. } => layers.iter_mut().collect(),
+            UserActionData::Paste { layer, .. }
+            | UserActionData::Filter { layer, .. }
+            | UserActionData::Move { layer, .. }
+            | UserActionData::MergeVisible { layer, .. } => vec![layer],
+            UserActionData::Selection(_) | UserActionData::ResizeCanvas { .. } => Vec::new(),
+        }
+    }
 }
 
 pub struct BrushStroke {
     pub kind: BrushStrokeKind,
+    /// The layer the stroke is painted on.
+    pub layer: LayerIdx,
+    /// In document coordinates, so the stroke replays over the same part of the painting
+    /// however the canvas has been resized since.
     pub frames: Vec<BrushStrokeFrame>,
+    /// The symmetry the stroke was painted with, if it was on, centered in document
+    /// coordinates like the frames. Kept with the stroke so the history replays it where it
+    /// was painted, wherever the symmetry has moved since.
+    pub symmetry: Option<Symmetry>,
+    /// The stencil layer the stroke was painted through, if there was one.
+    pub stencil: Option<LayerIdx>,
+    /// What the stroke's dabs were jittered with, so replaying it jitters them the same way.
+    pub seed: u64,
+    /// How long the stroke's path is, once it's finished, so replaying it tapers its end.
+    /// `None` while it's in progress.
+    pub length: Option<f32>,
+    /// The layer's pixels from before the stroke, kept while the stroke is in progress when
+    /// it needs to be re-rendered on release.
+    pub rollback: Option<LayerContents>,
 }
 
 impl BrushStroke {
-    pub fn new(kind: BrushStrokeKind) -> Self {
+    pub fn new(kind: BrushStrokeKind, layer: LayerIdx) -> Self {
         Self {
             kind,
+            layer,
             frames: Vec::new(),
+            symmetry: None,
+            stencil: None,
+            seed: StrokeRng::seed(),
+            length: None,
+            rollback: None,
         }
     }
 
     pub fn add_frame(&mut self, frame: BrushStrokeFrame) {
         self.frames.push(frame);
     }
+
+    /// The metadata of the finished stroke on a layer called `layer_name`: its label, see
+    /// [`stroke_label`], the part of the document its dabs can reach, its duration and how
+    /// many frames it has.
+    pub fn metadata(&self, layer_name: &str) -> ActionMetadata {
+        let (Some(first), Some(last)) = (self.frames.first(), self.frames.last()) else {
+            return ActionMetadata::default();
+        };
+        ActionMetadata {
+            label: stroke_label(&self.kind, &first.brush, first.color, layer_name),
+            name: None,
+            bounds: self.bounds(),
+            duration: last.timestamp.duration_since(first.timestamp),
+            frame_count: self.frames.len(),
+        }
+    }
+
+    /// The part of the document the stroke's dabs can reach, including their symmetric
+    /// images, at the largest their size jitter makes them.
+    fn bounds(&self) -> Option<LayerBounds> {
+        let (mut min, mut max) = (Pos2::new(f32::MAX, f32::MAX), Pos2::new(f32::MIN, f32::MIN));
+        for frame in &self.frames {
+            let reach = frame.brush.radius() * (1.0 + frame.brush.size_jitter()) + 1.0;
+            let mut points = vec![frame.last_cursor_position, frame.cursor_position];
+            if let Some(symmetry) = &self.symmetry {
+                points.extend(symmetry.images(frame.last_cursor_position));
+                points.extend(symmetry.images(frame.cursor_position));
+            }
+            for point in points {
+                min = min.min(point - Vec2::splat(reach));
+                max = max.max(point + Vec2::splat(reach));
+            }
+        }
+        (min.x <= max.x).then(|| {
+            let (x, y) = (min.x.floor() as i32, min.y.floor() as i32);
+            let width = (max.x.ceil() as i32 - x) as u32;
+            let height = (max.y.ceil() as i32 - y) as u32;
+            LayerBounds::new(x, y, width, height)
+        })
+    }
+
+    /// How far the stroke's frames travel in all.
+    pub fn path_length(&self) -> f32 {
+        self.frames
+            .iter()
+            .map(|f| f.last_cursor_position.distance(f.cursor_position))
+            .sum()
+    }
+
+    /// Refits the stroke's path: the raw cursor positions are simplified with `tolerance` and
+    /// a Catmull-Rom spline is sampled through what's left, at the brush spacing. Each new
+    /// frame takes its brush, color and timestamp from the raw frame at the same fraction of
+    /// the path length.
+    pub fn smoothed_frames(&self, tolerance: f32) -> Vec<BrushStrokeFrame> {
+        let Some(first) = self.frames.first() else {
+            return Vec::new();
+        };
+
+        let mut raw = vec![(first.last_cursor_position.x, first.last_cursor_position.y)];
+        raw.extend(
+            self.frames
+                .iter()
+                .map(|f| (f.cursor_position.x, f.cursor_position.y)),
+        );
+        raw.dedup();
+
+        let smoothed =
+            path::catmull_rom(&path::simplify(&raw, tolerance), first.brush.dab_spacing());
+        if smoothed.len() < 2 {
+            // a click without movement has no path to refit
+            return self.frames.clone();
+        }
+        let total_length = path::length(&smoothed).max(f32::EPSILON);
+
+        let last_frame = self.frames.len() - 1;
+        let mut travelled = 0.0;
+        smoothed
+            .windows(2)
+            .map(|w| {
+                travelled += path::distance(w[0], w[1]);
+                let fraction = travelled / total_length;
+                let source_index = (fraction * last_frame as f32).round() as usize;
+                let source = &self.frames[source_index.min(last_frame)];
+                BrushStrokeFrame {
+                    brush: source.brush.clone(),
+                    color: source.color,
+                    cursor_position: Pos2::new(w[1].0, w[1].1),
+                    last_cursor_position: Pos2::new(w[0].0, w[0].1),
+                    // the spline is already sampled finely enough
+                    previous_cursor_position: None,
+                    timestamp: source.timestamp,
+                    elapsed: 0.0,
+                }
+            })
+            .collect()
+    }
+
+    /// Synthesizes the fade tail frames that continue the stroke past its last frame, based
+    /// on the pointer velocity over the last few frames. Empty when the brush has no fade
+    /// tail or the pointer was moving too slowly.
+    pub fn fade_tail_frames(&self) -> Vec<BrushStrokeFrame> {
+        let Some(last) = self.frames.last() else {
+            return Vec::new();
+        };
+        let Some(fade_tail) = last.brush.fade_tail() else {
+            return Vec::new();
+        };
+        let first = &self.frames[self.frames.len().saturating_sub(VELOCITY_WINDOW)];
+
+        let elapsed = last.timestamp.duration_since(first.timestamp).as_secs_f32();
+        let delta = last.cursor_position - first.last_cursor_position;
+        if elapsed <= 0.0 {
+            return Vec::new();
+        }
+        let speed = delta.length() / elapsed;
+
+        let mut previous = last.cursor_position;
+        fade_tail
+            .points((previous.x, previous.y), (delta.x, delta.y), speed)
+            .into_iter()
+            .map(|((x, y), scale)| {
+                let cursor_position = Pos2::new(x, y);
+                let brush = last.brush.clone().with_radius(last.brush.radius() * scale);
+                let mut color = last.color;
+                color[ALPHA_CHANNEL] *= scale;
+                let frame = BrushStrokeFrame {
+                    brush,
+                    color,
+                    cursor_position,
+                    last_cursor_position: previous,
+                    previous_cursor_position: None,
+                    timestamp: last.timestamp,
+                    elapsed: 0.0,
+                };
+                previous = cursor_position;
+                frame
+            })
+            .collect()
+    }
 }
 
-pub struct BrushStrokeFrame {
-    pub brush: Brush,
-    pub color: Rgba,
-    pub cursor_position: Pos2,
-    pub last_cursor_position: Pos2,
+/// What a stroke of `kind` with `brush` and `color` on the layer called `layer_name` is
+/// called in the history, such as "Paint, soft-circle 24px, #FF8040, layer 'Shading'". The
+/// size is the brush's radius, as the brush size slider shows it, and only painting, which
+/// lays the color down as it is, shows the color.
+pub fn stroke_label(
+    kind: &BrushStrokeKind,
+    brush: &Brush,
+    color: Rgba,
+    layer_name: &str,
+) -> String {
+    let mut label = format!("{}, {} {:.0}px", kind.label(), brush.id(), brush.radius());
+    if let BrushStrokeKind::Paint = kind {
+        let [r, g, b, _] = alpha::brush_color_to_srgba(color);
+        label.push_str(&format!(", #{:02X}{:02X}{:02X}", r, g, b));
+    }
+    label.push_str(&format!(", layer '{}'", layer_name));
+    label
+}
+
+/// Number of trailing frames used to estimate the pointer velocity at the end of a stroke.
+const VELOCITY_WINDOW: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::canvas;
+    use rustbrush_utils::stroke::FadeTail;
+    use rustbrush_utils::testing::{frame, hard_brush};
+
+    /// Fills the whole of `layer` with `color`, as the current color, as an undoable action.
+    fn fill(user: &mut User, canvas: &mut Canvas, layer: LayerIdx, color: Rgba) {
+        user.current_layer = layer;
+        user.current_color = color;
+        user.fill = FillOptions {
+            tolerance: 255,
+            contiguous: false,
+            ..Default::default()
+        };
+        user.fill(canvas).unwrap();
+    }
+
+    fn pixels(canvas: &mut Canvas, layer: LayerIdx) -> Vec<Color32> {
+        canvas.layers()[layer].pixels().into_owned()
+    }
+
+    #[test]
+    fn stroke_labels_name_the_kind_brush_color_and_layer() {
+        let brush = Brush::default().with_radius(24.0);
+        let orange = alpha::brush_color_from_srgba([0xFF, 0x80, 0x40, 0x80]);
+        let label = |kind| stroke_label(&kind, &brush, orange, "Shading");
+        assert_eq!(
+            label(BrushStrokeKind::Paint),
+            "Paint, soft-circle 24px, #FF8040, layer 'Shading'"
+        );
+        // only painting lays the color down as it is
+        assert_eq!(
+            label(BrushStrokeKind::Erase),
+            "Erase, soft-circle 24px, layer 'Shading'"
+        );
+        let smudge = BrushStrokeKind::Smudge {
+            sample_merged: false,
+            pickup_rate: 0.5,
+        };
+        assert_eq!(label(smudge), "Smudge, soft-circle 24px, layer 'Shading'");
+
+        let brush = hard_brush(12.6).with_id("pencil".to_string());
+        let label = stroke_label(&BrushStrokeKind::Paint, &brush, Rgba::BLACK, "");
+        assert_eq!(label, "Paint, pencil 13px, #000000, layer ''");
+    }
+
+    #[test]
+    fn a_finished_stroke_knows_how_long_it_took_and_where_it_reached() {
+        let brush = hard_brush(4.0);
+        let mut stroke = BrushStroke::new(BrushStrokeKind::Paint, 0);
+        assert_eq!(stroke.metadata("Layer").frame_count, 0);
+
+        let points = [
+            Pos2::new(10.0, 10.0),
+            Pos2::new(20.0, 10.0),
+            Pos2::new(20.0, 30.0),
+        ];
+        let start = Instant::now();
+        for (i, pair) in points.windows(2).enumerate() {
+            let mut frame = frame(&brush, Rgba::WHITE, pair[0], pair[1], 0.1);
+            frame.timestamp = start + Duration::from_millis(250 * i as u64);
+            stroke.add_frame(frame);
+        }
+        let metadata = stroke.metadata("Layer");
+        assert_eq!(
+            metadata.label,
+            "Paint, soft-circle 4px, #FFFFFF, layer 'Layer'"
+        );
+        assert_eq!(metadata.name, None);
+        assert_eq!(metadata.frame_count, 2);
+        assert_eq!(metadata.duration, Duration::from_millis(250));
+        // the brush's reach, and a pixel to spare, around every point
+        assert_eq!(metadata.bounds, Some(LayerBounds::new(5, 5, 20, 30)));
+    }
+
+    #[test]
+    fn named_actions_are_milestones_until_the_name_is_taken_away() {
+        let mut canvas = canvas(8, 8, 1);
+        let mut user = User::default();
+        fill(&mut user, &mut canvas, 0, Rgba::WHITE);
+        let id = user.current_action_id;
+
+        assert!(user.rename_action(id, "  final lineart "));
+        let action = user.action_history.iter().find(|a| a.id == id).unwrap();
+        assert!(action.is_pinned());
+        assert_eq!(action.label(), "final lineart");
+
+        assert!(user.rename_action(id, " "));
+        let action = user.action_history.iter().find(|a| a.id == id).unwrap();
+        assert!(!action.is_pinned());
+        assert_eq!(action.label(), "Fill");
+
+        assert!(!user.rename_action(id + 1, "nothing there"));
+    }
+
+    #[test]
+    fn merge_visible_matches_the_export_and_leaves_the_sources_alone() {
+        let mut canvas = canvas(16, 16, 3);
+        let mut user = User::default();
+        fill(&mut user, &mut canvas, 0, Rgba::from_rgb(1.0, 0.0, 0.0));
+        let blue = Rgba::from_rgba_unmultiplied(0.0, 0.0, 1.0, 0.5);
+        fill(&mut user, &mut canvas, 1, blue);
+        fill(&mut user, &mut canvas, 2, Rgba::from_rgb(0.0, 1.0, 0.0));
+        canvas.layers()[2].visible = false;
+        let export = canvas.snapshot().merged();
+        let sources: Vec<_> = (0..3).map(|layer| pixels(&mut canvas, layer)).collect();
+
+        user.current_layer = 0;
+        user.merge_visible(&mut canvas).unwrap();
+
+        assert_eq!(user.current_layer, 1);
+        assert_eq!(canvas.layers().len(), 4);
+        assert_eq!(canvas.layers()[1].name, "Merged");
+        assert!(pixels(&mut canvas, 1) == export);
+        for (layer, source) in [0, 2, 3].into_iter().zip(&sources) {
+            assert!(
+                pixels(&mut canvas, layer) == *source,
+                "layer {layer} changed"
+            );
+        }
+    }
+
+    #[test]
+    fn undoing_a_merge_takes_its_layer_out() {
+        let mut canvas = canvas(16, 16, 2);
+        let mut user = User::default();
+        let green = Rgba::from_rgb(0.0, 1.0, 0.0);
+        fill(&mut user, &mut canvas, 1, green);
+        let filled = pixels(&mut canvas, 1);
+        assert!(filled.iter().all(|p| p.a() == 255));
+        user.current_layer = 0;
+        user.merge_visible(&mut canvas).unwrap();
+        let merged = pixels(&mut canvas, 1);
+
+        for _ in 0..3 {
+            user.undo(&mut canvas);
+            assert_eq!(canvas.layers().len(), 2);
+            assert_eq!(user.current_layer, 0);
+            assert!(pixels(&mut canvas, 1) == filled);
+
+            user.redo(&mut canvas);
+            assert_eq!(canvas.layers().len(), 3);
+            assert!(pixels(&mut canvas, 1) == merged);
+            // the fill, recorded before the merge, still replays onto the layer it was on
+            assert!(pixels(&mut canvas, 2) == filled);
+        }
+
+        // a new action drops the undone merge for good, along with its layer
+        user.undo(&mut canvas);
+        fill(&mut user, &mut canvas, 0, green);
+        user.undo(&mut canvas);
+        user.redo(&mut canvas);
+        assert_eq!(canvas.layers().len(), 2);
+        assert!(pixels(&mut canvas, 1) == filled);
+    }
+
+    #[test]
+    fn a_fast_stroke_fades_out_past_its_release() {
+        const TAIL: f32 = 30.0;
+        let mut canvas = canvas(100, 16, 1);
+        let brush = hard_brush(3.0).with_fade_tail(Some(FadeTail {
+            length: TAIL,
+            min_speed: 300.0,
+        }));
+        let red = Rgba::from_rgb(1.0, 0.0, 0.0);
+        // 4 pixels a millisecond to the right, released at x = 40
+        let start = Instant::now();
+        let mut stroke = BrushStroke::new(BrushStrokeKind::Paint, 0);
+        for i in 0..10 {
+            let (from, to) = (4.0 * i as f32, 4.0 * (i + 1) as f32);
+            let mut frame = frame(&brush, red, Pos2::new(from, 8.0), Pos2::new(to, 8.0), 0.0);
+            frame.timestamp = start + Duration::from_millis(i);
+            stroke.add_frame(frame);
+        }
+        let tail = stroke.fade_tail_frames();
+        assert!(!tail.is_empty());
+        for frame in tail {
+            stroke.add_frame(frame);
+        }
+
+        canvas.begin_brush_stroke(None, None, stroke.seed, None);
+        for frame in &stroke.frames {
+            canvas
+                .process_brush_stroke_frame(0, stroke.kind.clone(), frame)
+                .unwrap();
+        }
+        let layer = &canvas.layers()[0];
+        let alpha = |x: i32| layer.pixel_at(x, 8).a();
+
+        // never stronger the further past the release, fading out well before the end
+        let past: Vec<u8> = (40..40 + TAIL as i32).map(alpha).collect();
+        assert!(past.windows(2).all(|w| w[0] >= w[1]), "{past:?}");
+        assert!((1..200).contains(&alpha(60)), "{past:?}");
+        // and nothing past the end of the tail
+        let end = 40 + TAIL as i32;
+        assert!((end + 1..100).all(|x| alpha(x) == 0));
+    }
+
+    /// Strokes through `points` on the current layer as the user would, as one action.
+    fn user_stroke(user: &mut User, canvas: &mut Canvas, kind: BrushStrokeKind, points: &[Pos2]) {
+        (user.last_cursor_position, user.cursor_position) = (points[0], points[0]);
+        user.start_brush_stroke(kind, canvas).unwrap();
+        for &point in points {
+            user.cursor_position = point;
+            let (layer, kind, frame) = user.continue_brush_stroke().unwrap();
+            canvas
+                .process_brush_stroke_frame(layer, kind, frame)
+                .unwrap();
+            user.last_cursor_position = point;
+        }
+        user.finish_brush_stroke(canvas).unwrap();
+    }
+
+    fn composite(canvas: &Canvas) -> Vec<Color32> {
+        canvas.composite_rect(LayerBounds::canvas(canvas.state.width, canvas.state.height))
+    }
+
+    #[test]
+    fn strokes_replay_where_they_were_painted_after_a_crop() {
+        let mut canvas = canvas(32, 32, 1);
+        let mut user = User {
+            current_paint_brush: hard_brush(3.0),
+            current_color: Rgba::from_rgb(1.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        user_stroke(
+            &mut user,
+            &mut canvas,
+            BrushStrokeKind::Paint,
+            &[Pos2::new(4.0, 16.0), Pos2::new(28.0, 20.0)],
+        );
+        let before = composite(&canvas);
+
+        user.resize_canvas(&mut canvas, LayerBounds::new(8, 8, 16, 16))
+            .unwrap();
+        let cropped = composite(&canvas);
+        assert!(cropped.iter().any(|p| p.a() > 0));
+        for y in 0..16 {
+            for x in 0..16 {
+                assert_eq!(cropped[y * 16 + x], before[(y + 8) * 32 + x + 8]);
+            }
+        }
+        // painted on the cropped canvas, where the first stroke is 8 pixels up and left
+        user.current_color = Rgba::from_rgb(0.0, 0.0, 1.0);
+        user_stroke(
+            &mut user,
+            &mut canvas,
+            BrushStrokeKind::Paint,
+            &[Pos2::new(8.0, 0.0), Pos2::new(8.0, 16.0)],
+        );
+        let painted = composite(&canvas);
+        assert!(painted != cropped);
+
+        user.undo(&mut canvas);
+        assert!(composite(&canvas) == cropped);
+        user.redo(&mut canvas);
+        assert!(composite(&canvas) == painted);
+        for _ in 0..3 {
+            user.undo(&mut canvas);
+        }
+        assert_eq!((canvas.state.width, canvas.state.height), (32, 32));
+        for _ in 0..3 {
+            user.redo(&mut canvas);
+        }
+        assert!(composite(&canvas) == painted);
+    }
+
+    #[test]
+    fn a_stroke_whose_layer_is_deleted_stops_painting() {
+        let mut canvas = canvas(16, 16, 3);
+        let mut user = User::default();
+        fill(&mut user, &mut canvas, 0, Rgba::from_rgb(1.0, 0.0, 0.0));
+        fill(&mut user, &mut canvas, 2, Rgba::from_rgb(0.0, 0.0, 1.0));
+        let (under, over) = (pixels(&mut canvas, 0), pixels(&mut canvas, 2));
+
+        user.current_layer = 1;
+        user.current_paint_brush = hard_brush(3.0);
+        (user.last_cursor_position, user.cursor_position) =
+            (Pos2::new(4.0, 8.0), Pos2::new(8.0, 8.0));
+        user.start_brush_stroke(BrushStrokeKind::Paint, &mut canvas)
+            .unwrap();
+        let (layer, kind, frame) = user.continue_brush_stroke().unwrap();
+        canvas
+            .process_brush_stroke_frame(layer, kind, frame)
+            .unwrap();
+        assert!(pixels(&mut canvas, 1).iter().any(|p| p.a() > 0));
+
+        canvas.remove_layer(1).unwrap();
+        user.last_cursor_position = user.cursor_position;
+        user.cursor_position = Pos2::new(12.0, 8.0);
+        let (layer, kind, frame) = user.continue_brush_stroke().unwrap();
+        // the layer that took its index isn't painted instead
+        let result = canvas.process_brush_stroke_frame(layer, kind, frame);
+        assert!(
+            matches!(result, Err(CanvasError::NoSuchLayer(1))),
+            "{result:?}"
+        );
+        let _ = user.finish_brush_stroke(&mut canvas);
+        assert_eq!(pixels(&mut canvas, 0), under);
+        assert_eq!(pixels(&mut canvas, 1), over);
+    }
+
+    /// Presses or lets go of the eyedropper override key.
+    fn hold_override(user: &mut User, held: bool) {
+        let modifiers = match held {
+            true => user.eyedropper_modifiers,
+            false => Modifiers::NONE,
+        };
+        user.update_tool_override(modifiers);
+    }
+
+    #[test]
+    fn releasing_the_override_key_mid_press_still_samples() {
+        let mut user = User::default();
+        hold_override(&mut user, true);
+        assert_eq!(user.press_primary(), Tool::Eyedropper);
+        hold_override(&mut user, false);
+        // the press keeps sampling, and no stroke may start from it
+        assert_eq!(user.effective_tool(), Tool::Eyedropper);
+        assert!(user.is_tool_overridden());
+        assert_eq!(user.release_primary(), Some(Tool::Eyedropper));
+        assert_eq!(user.effective_tool(), Tool::Brush);
+        assert!(!user.is_tool_overridden());
+    }
+
+    #[test]
+    fn pressing_the_override_key_mid_stroke_keeps_painting() {
+        let mut user = User::default();
+        assert_eq!(user.press_primary(), Tool::Brush);
+        hold_override(&mut user, true);
+        assert_eq!(user.effective_tool(), Tool::Brush);
+        hold_override(&mut user, false);
+        assert_eq!(user.release_primary(), Some(Tool::Brush));
+
+        // held across the release, the next press samples
+        assert_eq!(user.press_primary(), Tool::Brush);
+        hold_override(&mut user, true);
+        assert_eq!(user.release_primary(), Some(Tool::Brush));
+        assert_eq!(user.effective_tool(), Tool::Eyedropper);
+        assert_eq!(user.press_primary(), Tool::Eyedropper);
+    }
+
+    #[test]
+    fn the_override_lasts_as_long_as_the_key() {
+        let mut user = User::default();
+        hold_override(&mut user, true);
+        assert_eq!(user.press_primary(), Tool::Eyedropper);
+        assert_eq!(user.release_primary(), Some(Tool::Eyedropper));
+        // still held
+        assert_eq!(user.effective_tool(), Tool::Eyedropper);
+        assert!(user.is_tool_overridden());
+        hold_override(&mut user, false);
+        assert_eq!(user.effective_tool(), Tool::Brush);
+        // a release without a press uses nothing
+        assert_eq!(user.release_primary(), None);
+    }
+
+    #[test]
+    fn the_clone_tool_keeps_its_alt_click() {
+        let mut user = User {
+            current_tool: Tool::Clone,
+            ..Default::default()
+        };
+        hold_override(&mut user, true);
+        assert_eq!(user.press_primary(), Tool::Clone);
+        assert!(!user.is_tool_overridden());
+    }
+
+    #[test]
+    fn erasing_to_the_background_keeps_an_opaque_background_opaque() {
+        let beige = Rgba::from_rgb(0.8, 0.7, 0.5);
+        let at = [Pos2::new(16.0, 16.0)];
+        // whether the layer is a background, what it's filled with, and what the eraser
+        // leaves in the middle of it
+        let cases = [
+            (
+                true,
+                Some(Rgba::WHITE),
+                EraserMode::Auto,
+                Color32::from(beige),
+            ),
+            (
+                true,
+                Some(Rgba::WHITE),
+                EraserMode::Background,
+                Color32::from(beige),
+            ),
+            (
+                true,
+                Some(Rgba::WHITE),
+                EraserMode::Transparent,
+                Color32::TRANSPARENT,
+            ),
+            (
+                false,
+                Some(Rgba::WHITE),
+                EraserMode::Auto,
+                Color32::TRANSPARENT,
+            ),
+            // a transparent background would show through anyway
+            (true, None, EraserMode::Auto, Color32::TRANSPARENT),
+            (false, None, EraserMode::Background, Color32::from(beige)),
+        ];
+        for (background, filled, mode, expected) in cases {
+            let mut canvas = canvas(32, 32, 1);
+            canvas.layers()[0].background = background;
+            let mut user = User {
+                current_eraser_brush: hard_brush(4.0),
+                background_color: beige,
+                eraser_mode: mode,
+                ..Default::default()
+            };
+            if let Some(color) = filled {
+                fill(&mut user, &mut canvas, 0, color);
+            }
+            let before = pixels(&mut canvas, 0);
+            let kind = user.eraser_stroke_kind(&canvas);
+            user_stroke(&mut user, &mut canvas, kind, &at);
+
+            let what = format!(
+                "background {background}, filled {filled:?}, {}",
+                mode.label()
+            );
+            let after = pixels(&mut canvas, 0);
+            assert_eq!(after[16 * 32 + 16], expected, "{what}");
+            // only the middle is touched
+            assert_eq!(after[0], before[0], "{what}");
+            let opaque = after.iter().all(|pixel| pixel.a() == 255);
+            assert_eq!(opaque, expected.a() == 255 && filled.is_some(), "{what}");
+        }
+    }
 }
@@ -0,0 +1,136 @@
+use eframe::egui::{Pos2, Rect, Vec2};
+
+/// Where the canvas is shown and how big. All conversions between screen points and canvas
+/// pixels go through here, so they agree with each other about the display's scaling.
+pub struct ViewState {
+    /// How far the canvas origin is panned from the top left of the canvas area, in points.
+    pub offset: Vec2,
+    /// Physical pixels per canvas pixel, so at 1.0 every canvas pixel covers exactly one
+    /// pixel of the display however the display is scaled.
+    pub zoom: f32,
+    /// The display scaling: physical pixels per egui point. Updated every frame.
+    pub pixels_per_point: f32,
+    /// Whether zoomed canvas pixels are smoothed rather than shown as hard squares.
+    pub smooth: bool,
+}
+
+impl Default for ViewState {
+    fn default() -> Self {
+        Self {
+            offset: Vec2::ZERO,
+            zoom: 1.0,
+            pixels_per_point: 1.0,
+            smooth: false,
+        }
+    }
+}
+
+impl ViewState {
+    /// Points per canvas pixel.
+    pub fn scale(&self) -> f32 {
+        self.zoom / self.pixels_per_point
+    }
+
+    /// Where the canvas origin is on screen, rounded to a physical pixel so canvas pixels
+    /// line up with display pixels instead of straddling them.
+    pub fn origin(&self, canvas_rect: Rect) -> Pos2 {
+        let origin = canvas_rect.min + self.offset;
+        (origin.to_vec2() * self.pixels_per_point).round().to_pos2() / self.pixels_per_point
+    }
+
+    pub fn screen_to_canvas(&self, screen_pos: Pos2, canvas_rect: Rect) -> Pos2 {
+        ((screen_pos - self.origin(canvas_rect)) / self.scale()).to_pos2()
+    }
+
+    pub fn canvas_to_screen(&self, canvas_pos: Pos2, canvas_rect: Rect) -> Pos2 {
+        self.origin(canvas_rect) + canvas_pos.to_vec2() * self.scale()
+    }
+
+    /// The on-screen size of a `width` by `height` canvas.
+    pub fn canvas_size(&self, width: u32, height: u32) -> Vec2 {
+        Vec2::new(width as f32, height as f32) * self.scale()
+    }
+
+    /// The texture filtering layers are drawn with.
+    pub fn texture_options(&self) -> eframe::egui::TextureOptions {
+        if self.smooth {
+            eframe::egui::TextureOptions::LINEAR
+        } else {
+            eframe::egui::TextureOptions::NEAREST
+        }
+    }
+
+    /// Zooms by `factor`, keeping the canvas point under `anchor` in place.
+    pub fn zoom_around(&mut self, anchor: Pos2, factor: f32, canvas_rect: Rect) {
+        let old_scale = self.scale();
+        self.zoom = (self.zoom * factor).clamp(0.1, 10.0);
+        let anchor_offset = anchor - canvas_rect.min - self.offset;
+        self.offset += anchor_offset - anchor_offset * (self.scale() / old_scale);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISPLAY_SCALES: [f32; 3] = [1.0, 1.5, 2.0];
+
+    fn close(a: Pos2, b: Pos2) -> bool {
+        (a - b).length() < 1e-3
+    }
+
+    /// Views at each display scale and a few zooms, panned by a fraction of a point.
+    fn views() -> impl Iterator<Item = ViewState> {
+        DISPLAY_SCALES.into_iter().flat_map(|pixels_per_point| {
+            [0.5, 1.0, 2.5].map(|zoom| ViewState {
+                offset: Vec2::new(13.3, -7.7),
+                zoom,
+                pixels_per_point,
+                smooth: false,
+            })
+        })
+    }
+
+    #[test]
+    fn screen_and_canvas_positions_round_trip_at_every_display_scale() {
+        let canvas_rect = Rect::from_min_size(Pos2::new(40.2, 25.0), Vec2::new(800.0, 600.0));
+        for view in views() {
+            for canvas_pos in [Pos2::ZERO, Pos2::new(10.0, 20.0), Pos2::new(511.5, 3.25)] {
+                let screen = view.canvas_to_screen(canvas_pos, canvas_rect);
+                let back = view.screen_to_canvas(screen, canvas_rect);
+                assert!(
+                    close(back, canvas_pos),
+                    "{canvas_pos:?} came back as {back:?}"
+                );
+            }
+            for screen in [
+                canvas_rect.min,
+                canvas_rect.center(),
+                Pos2::new(99.9, 333.3),
+            ] {
+                let canvas_pos = view.screen_to_canvas(screen, canvas_rect);
+                let back = view.canvas_to_screen(canvas_pos, canvas_rect);
+                assert!(close(back, screen), "{screen:?} came back as {back:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn canvas_pixels_line_up_with_display_pixels() {
+        let canvas_rect = Rect::from_min_size(Pos2::new(40.2, 25.0), Vec2::new(800.0, 600.0));
+        for view in views() {
+            let ppp = view.pixels_per_point;
+            let origin = view.origin(canvas_rect).to_vec2() * ppp;
+            assert!(
+                close(origin.round().to_pos2(), origin.to_pos2()),
+                "{origin:?}"
+            );
+            // a canvas pixel is `zoom` physical pixels across, whatever the display scale
+            let from = view.canvas_to_screen(Pos2::new(3.0, 3.0), canvas_rect);
+            let to = view.canvas_to_screen(Pos2::new(4.0, 4.0), canvas_rect);
+            let across = (to - from) * ppp;
+            assert!((across.x - view.zoom).abs() < 1e-4, "{across:?}");
+            assert!((across.y - view.zoom).abs() < 1e-4, "{across:?}");
+        }
+    }
+}
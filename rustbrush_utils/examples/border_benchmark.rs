@@ -0,0 +1,88 @@
+//! Times strokes along the canvas border with a big brush, where most of every dab hangs
+//! off the edge, and prints a checksum of what they painted.
+//!
+//! Run with `cargo run -p rustbrush_utils --release --example border_benchmark`.
+//!
+//! Dabs are clipped to the canvas once each rather than pixel by pixel, which is where the
+//! time goes here. The checksum pins down the pixels, so changes to the clipping can be
+//! checked against it.
+
+use std::time::Instant;
+
+use rustbrush_utils::jitter::StrokeRng;
+use rustbrush_utils::operations::PaintOperation;
+use rustbrush_utils::pixel_buffer::PixelSlice;
+use rustbrush_utils::stamp_cache::StampCache;
+use rustbrush_utils::stroke::{StrokeAccumulation, StrokeSegment, StrokeState};
+use rustbrush_utils::symmetry::Symmetry;
+use rustbrush_utils::{Brush, Color32, Rgba};
+
+const WIDTH: u32 = 1024;
+const HEIGHT: u32 = 768;
+const ROUNDS: usize = 20;
+
+fn main() {
+    let brush = Brush::default().with_radius(120.0).with_strength(0.3);
+    let color = Rgba::from_rgba_premultiplied(0.1, 0.4, 0.9, 1.0);
+    let mut pixels = vec![Color32::TRANSPARENT; (WIDTH * HEIGHT) as usize];
+    let mut stamp_cache = StampCache::default();
+    let mut rng = StrokeRng::new(0);
+
+    // around the canvas just outside its edges, a little further out each time and off
+    // whole pixels, so only a sliver of each dab lands
+    let corners = |round: usize| {
+        let out = 60.0 + round as f32 * 2.3 + 0.37;
+        let (right, bottom) = (WIDTH as f32 + out, HEIGHT as f32 + out);
+        [
+            (-out, -out),
+            (right, -out),
+            (right, bottom),
+            (-out, bottom),
+            (-out, -out),
+        ]
+    };
+
+    let started = Instant::now();
+    for round in 0..ROUNDS {
+        let mut stroke_state = StrokeState::default();
+        for side in corners(round).windows(2) {
+            PaintOperation {
+                pixels: &mut PixelSlice::new(&mut pixels, WIDTH, HEIGHT),
+                brush: &brush,
+                color: color.into(),
+                segment: StrokeSegment {
+                    from: side[0],
+                    to: side[1],
+                },
+                previous_cursor_position: None,
+                is_eraser: false,
+                accumulation: StrokeAccumulation::BuildUp,
+                stroke_buffer: None,
+                elapsed: 0.0,
+                stamp_cache: &mut stamp_cache,
+                rng: &mut rng,
+                stroke_state: &mut stroke_state,
+                taper_in: 0.0,
+                taper_out: 0.0,
+                stroke_length: None,
+                mask: None,
+                alpha_lock: false,
+                symmetry: Symmetry::None,
+                stats: None,
+            }
+            .process();
+        }
+    }
+    let elapsed = started.elapsed();
+
+    // FNV-1a over every channel of every pixel
+    let checksum = pixels
+        .iter()
+        .flat_map(|pixel| pixel.to_array())
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+
+    println!("time:     {:.1} ms", elapsed.as_secs_f64() * 1000.0);
+    println!("checksum: {checksum:016x}");
+}
@@ -0,0 +1,86 @@
+//! Times a fast stroke with a big brush, which is where painting a dab's rows in parallel
+//! pays off, and prints a checksum of what it painted.
+//!
+//! Run it both ways and compare:
+//!
+//! `cargo run -p rustbrush_utils --release --example dab_benchmark`
+//! `cargo run -p rustbrush_utils --release --features parallel --example dab_benchmark`
+//!
+//! The times show the speedup, and the checksums must match, as the rows painted in
+//! parallel get exactly the paint they would one at a time.
+
+use std::time::Instant;
+
+use rustbrush_utils::jitter::StrokeRng;
+use rustbrush_utils::operations::PaintOperation;
+use rustbrush_utils::pixel_buffer::PixelSlice;
+use rustbrush_utils::stamp_cache::StampCache;
+use rustbrush_utils::stroke::{StrokeAccumulation, StrokeSegment, StrokeState};
+use rustbrush_utils::symmetry::Symmetry;
+use rustbrush_utils::{Brush, Color32, Rgba};
+
+const WIDTH: u32 = 1024;
+const HEIGHT: u32 = 1024;
+const SEGMENTS: usize = 200;
+
+fn main() {
+    let brush = Brush::default().with_radius(150.0).with_strength(0.5);
+    let color = Rgba::from_rgba_premultiplied(0.8, 0.3, 0.1, 1.0);
+    let mut pixels = vec![Color32::TRANSPARENT; (WIDTH * HEIGHT) as usize];
+    let mut stamp_cache = StampCache::default();
+    let mut rng = StrokeRng::new(0);
+    let mut stroke_state = StrokeState::default();
+
+    // a loop around the middle of the canvas, running off its edges
+    let point = |i: usize| {
+        let t = i as f32 / SEGMENTS as f32 * std::f32::consts::TAU * 3.0;
+        let reach = WIDTH as f32 * 0.45;
+        (
+            WIDTH as f32 / 2.0 + reach * t.cos(),
+            HEIGHT as f32 / 2.0 + reach * (t * 1.5).sin(),
+        )
+    };
+
+    let started = Instant::now();
+    for i in 1..=SEGMENTS {
+        PaintOperation {
+            pixels: &mut PixelSlice::new(&mut pixels, WIDTH, HEIGHT),
+            brush: &brush,
+            color: color.into(),
+            segment: StrokeSegment {
+                from: point(i - 1),
+                to: point(i),
+            },
+            previous_cursor_position: None,
+            is_eraser: false,
+            accumulation: StrokeAccumulation::BuildUp,
+            stroke_buffer: None,
+            elapsed: 0.0,
+            stamp_cache: &mut stamp_cache,
+            rng: &mut rng,
+            stroke_state: &mut stroke_state,
+            taper_in: 0.0,
+            taper_out: 0.0,
+            stroke_length: None,
+            mask: None,
+            alpha_lock: false,
+            symmetry: Symmetry::None,
+            stats: None,
+        }
+        .process();
+    }
+    let elapsed = started.elapsed();
+
+    // FNV-1a over every channel of every pixel
+    let checksum = pixels
+        .iter()
+        .flat_map(|pixel| pixel.to_array())
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+
+    let parallel = cfg!(feature = "parallel");
+    println!("parallel: {parallel}");
+    println!("time:     {:.1} ms", elapsed.as_secs_f64() * 1000.0);
+    println!("checksum: {checksum:016x}");
+}
@@ -0,0 +1,93 @@
+//! A filter defined outside of rustbrush, registered next to the built-in ones and applied
+//! through the same glue the Filters menu uses.
+//!
+//! Run with `cargo run -p rustbrush_utils --example invert_filter`.
+
+use rustbrush_utils::filter_registry::{apply_filter, Filter, FilterRegistry};
+use rustbrush_utils::pixel_buffer::{DirtyRect, PixelBuffer, PixelSlice};
+use rustbrush_utils::selection::SelectionMask;
+use rustbrush_utils::Color32;
+
+/// Inverts the color of every pixel, blending `amount` of the inverted color in.
+struct Invert {
+    amount: f32,
+}
+
+impl Filter for Invert {
+    fn name(&self) -> &str {
+        "Invert"
+    }
+
+    fn apply(
+        &self,
+        pixels: &mut dyn PixelBuffer,
+        region: Option<DirtyRect>,
+        _mask: Option<&SelectionMask>,
+    ) -> DirtyRect {
+        let region = region.unwrap_or(DirtyRect::full(pixels.width(), pixels.height()));
+        let width = pixels.width();
+        let buffer = pixels.pixels_mut();
+        for row in region.rows(width) {
+            for pixel in &mut buffer[row] {
+                // premultiplied, so the inverse of a channel is alpha minus the channel
+                let [r, g, b, a] = pixel.to_array();
+                let invert = |c: u8| {
+                    let inverted = (a - c) as f32;
+                    (c as f32 + (inverted - c as f32) * self.amount).round() as u8
+                };
+                *pixel = Color32::from_rgba_premultiplied(invert(r), invert(g), invert(b), a);
+            }
+        }
+        region
+    }
+
+    #[cfg(feature = "gui")]
+    fn params_ui(&mut self, ui: &mut egui::Ui) -> bool {
+        ui.add(egui::Slider::new(&mut self.amount, 0.0..=1.0).text("Amount"))
+            .changed()
+    }
+}
+
+fn main() {
+    let mut registry = FilterRegistry::with_builtins();
+    registry
+        .register("Invert", || Box::new(Invert { amount: 1.0 }))
+        .expect("none of the built-in filters is named Invert");
+    for entry in registry.entries() {
+        println!("Filters menu: {}", entry.name());
+    }
+
+    // a 4x2 image, half black and half white, with only the left column selected
+    let (width, height) = (4, 2);
+    let mut pixels = vec![
+        Color32::BLACK,
+        Color32::BLACK,
+        Color32::WHITE,
+        Color32::WHITE,
+    ];
+    pixels.extend_from_within(..);
+    let selection = SelectionMask::from_alpha(
+        &[
+            Color32::WHITE,
+            Color32::TRANSPARENT,
+            Color32::TRANSPARENT,
+            Color32::TRANSPARENT,
+        ]
+        .repeat(2),
+        width,
+        height,
+        None,
+    );
+
+    let invert = registry.create("Invert").expect("registered above");
+    let mut buffer = PixelSlice::new(&mut pixels, width, height);
+    let dirty = apply_filter(&*invert, &mut buffer, None, Some(&selection));
+
+    println!("{} changed {:?}", invert.name(), dirty);
+    for row in pixels.chunks(width as usize) {
+        println!(
+            "{:?}",
+            row.iter().map(|pixel| pixel.r()).collect::<Vec<_>>()
+        );
+    }
+}
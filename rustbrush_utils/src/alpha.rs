@@ -0,0 +1,184 @@
+//! Conversions between the two ways pixels are stored.
+//!
+//! Layers, and everything that paints into them, hold [`Color32`]: sRGB-encoded, with the
+//! color premultiplied by alpha in linear space the way egui does it. Image files, the
+//! clipboard and imported pixels are straight (unmultiplied) sRGB RGBA8. Brush colors are
+//! [`Rgba`] with straight, linear components, and only become premultiplied when they're
+//! blended into a layer.
+//!
+//! Every crossing between the premultiplied and straight forms should go through these, so
+//! there's one definition of the conversion to keep round trips consistent. [`BrushColor`]
+//! wraps them up as `From` conversions, for code that takes a brush color from any of them.
+
+use ecolor::{gamma_u8_from_linear_f32, linear_f32_from_gamma_u8, Color32, Rgba};
+
+use crate::RgbaExtensions;
+
+/// Premultiplies one straight sRGB RGBA8 pixel.
+pub fn premultiply([r, g, b, a]: [u8; 4]) -> Color32 {
+    Color32::from_rgba_unmultiplied(r, g, b, a)
+}
+
+/// The straight sRGB RGBA8 of a layer pixel. Fully transparent pixels have no color left and
+/// come back as transparent black.
+pub fn unpremultiply(pixel: Color32) -> [u8; 4] {
+    pixel.to_srgba_unmultiplied()
+}
+
+/// Premultiplies a buffer of straight sRGB RGBA8, four bytes to a pixel.
+pub fn premultiply_rgba8(rgba: &[u8]) -> Vec<Color32> {
+    rgba.chunks_exact(4)
+        .map(|pixel| premultiply([pixel[0], pixel[1], pixel[2], pixel[3]]))
+        .collect()
+}
+
+/// The straight sRGB RGBA8 of layer pixels, four bytes to a pixel, as image files want it.
+pub fn unpremultiply_rgba8(pixels: &[Color32]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|&pixel| unpremultiply(pixel))
+        .collect()
+}
+
+/// The brush color of a layer pixel, such as one picked up from the canvas. Fully
+/// transparent pixels have no color left and come back as transparent black.
+pub fn brush_color(pixel: Color32) -> Rgba {
+    Rgba::from(pixel).unpremultiply()
+}
+
+/// The brush color of straight sRGB RGBA8, as color pickers and files give it. Unlike going
+/// through a layer pixel, the color survives however faint it is.
+pub fn brush_color_from_srgba([r, g, b, a]: [u8; 4]) -> Rgba {
+    // the raw constructor, as brush colors keep straight components
+    Rgba::from_rgba_premultiplied(
+        linear_f32_from_gamma_u8(r),
+        linear_f32_from_gamma_u8(g),
+        linear_f32_from_gamma_u8(b),
+        a as f32 / 255.0,
+    )
+}
+
+/// The straight sRGB RGBA8 of a brush color, for showing it or writing it out.
+pub fn brush_color_to_srgba(color: Rgba) -> [u8; 4] {
+    [
+        gamma_u8_from_linear_f32(color.r()),
+        gamma_u8_from_linear_f32(color.g()),
+        gamma_u8_from_linear_f32(color.b()),
+        (color.a() * 255.0).round().clamp(0.0, 255.0) as u8,
+    ]
+}
+
+/// A brush color: straight, linear [`Rgba`], built from whichever form the color comes in.
+/// Arrays are straight sRGB, as color pickers and files give them, and a [`Color32`] is a
+/// premultiplied layer pixel, see [`brush_color`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BrushColor(Rgba);
+
+impl BrushColor {
+    /// The straight, linear components.
+    pub fn rgba(self) -> Rgba {
+        self.0
+    }
+
+    /// The straight sRGB RGBA8, see [`brush_color_to_srgba`].
+    pub fn to_srgba(self) -> [u8; 4] {
+        brush_color_to_srgba(self.0)
+    }
+}
+
+/// Already straight and linear, the way brush colors are kept.
+impl From<Rgba> for BrushColor {
+    fn from(color: Rgba) -> Self {
+        Self(color)
+    }
+}
+
+impl From<BrushColor> for Rgba {
+    fn from(color: BrushColor) -> Self {
+        color.0
+    }
+}
+
+/// Opaque sRGB.
+impl From<[u8; 3]> for BrushColor {
+    fn from([r, g, b]: [u8; 3]) -> Self {
+        Self::from([r, g, b, 255])
+    }
+}
+
+impl From<[u8; 4]> for BrushColor {
+    fn from(srgba: [u8; 4]) -> Self {
+        Self(brush_color_from_srgba(srgba))
+    }
+}
+
+impl From<Color32> for BrushColor {
+    fn from(pixel: Color32) -> Self {
+        Self(brush_color(pixel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn premultiplying_round_trips() {
+        for value in (0..=255).step_by(5) {
+            let color = [value, 255 - value, 128];
+            // opaque pixels come back exactly
+            let [r, g, b] = color;
+            assert_eq!(unpremultiply(premultiply([r, g, b, 255])), [r, g, b, 255]);
+            // and half transparent ones within a level
+            let back = unpremultiply(premultiply([r, g, b, 128]));
+            for (got, want) in back.iter().zip([r, g, b, 128]) {
+                assert!(got.abs_diff(want) <= 1, "{color:?} came back as {back:?}");
+            }
+        }
+        assert_eq!(unpremultiply(premultiply([200, 100, 50, 0])), [0, 0, 0, 0]);
+
+        let rgba = [255, 0, 0, 128, 10, 20, 30, 255, 0, 0, 0, 0];
+        let pixels = premultiply_rgba8(&rgba);
+        assert_eq!(pixels.len(), 3);
+        assert_eq!(pixels[1], Color32::from_rgb(10, 20, 30));
+        assert_eq!(unpremultiply_rgba8(&pixels), rgba);
+    }
+
+    #[test]
+    fn srgba_round_trips_through_a_brush_color() {
+        for value in 0..=255 {
+            let srgba = [value, 255 - value, value / 2, value];
+            assert_eq!(BrushColor::from(srgba).to_srgba(), srgba);
+        }
+        let opaque = BrushColor::from([12, 200, 99]);
+        assert_eq!(opaque, BrushColor::from([12, 200, 99, 255]));
+        assert_eq!(opaque.to_srgba(), [12, 200, 99, 255]);
+    }
+
+    #[test]
+    fn a_layer_pixel_comes_back_unpremultiplied() {
+        for alpha in [255, 200, 128, 60, 17] {
+            let srgba = [200, 100, 50, alpha];
+            let color = BrushColor::from(premultiply(srgba));
+            // the same color as its straight form, rather than darkened by its alpha, up to
+            // the rounding premultiplying 8 bits loses
+            for (got, want) in color.to_srgba().iter().zip(srgba) {
+                assert!(got.abs_diff(want) <= 2, "{srgba:?} came back as {color:?}");
+            }
+            assert_eq!(
+                Color32::from(color.rgba().premultiply()),
+                premultiply(srgba)
+            );
+        }
+        assert_eq!(
+            BrushColor::from(Color32::TRANSPARENT).to_srgba(),
+            [0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn rgba_is_kept_as_it_is() {
+        let color = Rgba::from_rgba_premultiplied(0.8, 0.3, 0.1, 0.5);
+        assert_eq!(Rgba::from(BrushColor::from(color)), color);
+    }
+}
@@ -0,0 +1,3430 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use ecolor::{Color32, Rgba};
+use emath::Pos2;
+
+use crate::alpha;
+use crate::composite::{composite, CompositeLayer, LayerBlendMode};
+use crate::fill::FillOperation;
+use crate::filter_registry::{apply_filter, Filter};
+use crate::filters::Adjustment;
+use crate::jitter::StrokeRng;
+use crate::operations::{
+    preserve_alpha, CloneOperation, DodgeBurnMode, DodgeBurnOperation, EyedropperOperation,
+    HsvShift, HueShiftOperation, MergedSource, NoiseMode, NoiseOperation, OpStats, PaintOperation,
+    SmudgeOperation, SmudgePickup, TonalRange,
+};
+use crate::pixel_buffer::{expand, DirtyRect, PixelBuffer, PixelSlice};
+use crate::resample::downscale;
+use crate::sampling::SampleKernel;
+use crate::selection::SelectionMask;
+use crate::shape::{ShapeKind, ShapeOperation};
+use crate::stamp_cache::StampCache;
+use crate::stats::{self, ColorCount, Coverage};
+use crate::stroke::{StrokeAccumulation, StrokeBuffer, StrokeSegment, StrokeState};
+use crate::symmetry::Symmetry;
+use crate::task::TaskContext;
+use crate::Brush;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LayerBounds {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl LayerBounds {
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The whole of a `width` by `height` canvas.
+    pub fn canvas(width: u32, height: u32) -> Self {
+        Self::new(0, 0, width, height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The same bounds moved by `dx` and `dy`.
+    pub fn translated(self, dx: i32, dy: i32) -> Self {
+        Self::new(self.x + dx, self.y + dy, self.width, self.height)
+    }
+
+    fn right(&self) -> i32 {
+        self.x + self.width as i32
+    }
+
+    fn bottom(&self) -> i32 {
+        self.y + self.height as i32
+    }
+
+    /// The index of canvas pixel (`x`, `y`) in the layer's pixels, if the layer has one there.
+    pub fn index(&self, x: i32, y: i32) -> Option<usize> {
+        let inside = x >= self.x && y >= self.y && x < self.right() && y < self.bottom();
+        inside.then(|| (y - self.y) as usize * self.width as usize + (x - self.x) as usize)
+    }
+
+    /// The smallest bounds covering both.
+    pub fn union(self, other: Self) -> Self {
+        if self.is_empty() {
+            return other;
+        }
+        if other.is_empty() {
+            return self;
+        }
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Self::new(x, y, (right - x) as u32, (bottom - y) as u32)
+    }
+
+    /// The part covered by both, empty if they don't overlap.
+    pub fn intersect(self, other: Self) -> Self {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            return Self::default();
+        }
+        Self::new(x, y, (right - x) as u32, (bottom - y) as u32)
+    }
+
+    /// The pixels within `reach` of any of `points`.
+    fn around(points: &[(f32, f32)], reach: f32) -> Self {
+        let (mut min_x, mut min_y) = (f32::MAX, f32::MAX);
+        let (mut max_x, mut max_y) = (f32::MIN, f32::MIN);
+        for &(x, y) in points {
+            min_x = min_x.min(x - reach);
+            min_y = min_y.min(y - reach);
+            max_x = max_x.max(x + reach);
+            max_y = max_y.max(y + reach);
+        }
+        let (x, y) = (min_x.floor() as i32, min_y.floor() as i32);
+        let (right, bottom) = (max_x.ceil() as i32 + 1, max_y.ceil() as i32 + 1);
+        Self::new(x, y, (right - x).max(0) as u32, (bottom - y).max(0) as u32)
+    }
+}
+
+/// What a layer holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LayerKind {
+    /// Colors, composited into the image.
+    #[default]
+    Color,
+    /// A mask that's never part of the image. The alpha of each pixel is how much paint
+    /// gets through it onto other layers while it's a stroke's stencil, see
+    /// [`Canvas::begin_brush_stroke`]. It's painted white whatever the color, and shown as a
+    /// translucent tint over the canvas.
+    Stencil,
+}
+
+impl LayerKind {
+    /// The color that painting `color` onto a layer of this kind lays down.
+    fn paint_color(self, color: Rgba) -> Rgba {
+        match self {
+            LayerKind::Color => color,
+            LayerKind::Stencil => Rgba::WHITE,
+        }
+    }
+}
+
+/// What a layer's pixels are kept as. Color layers keep premultiplied colors; stencils only
+/// ever hold white, so they keep just the alpha, a quarter of the memory. A stencil is
+/// edited as colors, the alpha standing for white, and packed back once the edit or stroke
+/// is done, see [`CanvasLayer::pack`].
+#[derive(Clone)]
+pub enum LayerPixels {
+    Color(Arc<Vec<Color32>>),
+    Alpha(Arc<Vec<u8>>),
+}
+
+impl LayerPixels {
+    /// `len` transparent pixels, kept the way a layer of `kind` keeps them.
+    fn transparent(kind: LayerKind, len: usize) -> Self {
+        match kind {
+            LayerKind::Color => LayerPixels::Color(Arc::new(vec![Color32::TRANSPARENT; len])),
+            LayerKind::Stencil => LayerPixels::Alpha(Arc::new(vec![0; len])),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            LayerPixels::Color(pixels) => pixels.len(),
+            LayerPixels::Alpha(alpha) => alpha.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The pixel at `index`.
+    pub fn get(&self, index: usize) -> Color32 {
+        match self {
+            LayerPixels::Color(pixels) => pixels[index],
+            LayerPixels::Alpha(alpha) => white(alpha[index]),
+        }
+    }
+
+    /// The pixels as colors. Only copies if they're kept as alpha.
+    pub fn colors(&self) -> Cow<'_, [Color32]> {
+        match self {
+            LayerPixels::Color(pixels) => Cow::Borrowed(pixels),
+            LayerPixels::Alpha(alpha) => Cow::Owned(alpha.iter().map(|&a| white(a)).collect()),
+        }
+    }
+
+    /// The alpha of each pixel. Only copies if they're kept as colors.
+    pub fn alpha(&self) -> Cow<'_, [u8]> {
+        match self {
+            LayerPixels::Color(pixels) => Cow::Owned(pixels.iter().map(|p| p.a()).collect()),
+            LayerPixels::Alpha(alpha) => Cow::Borrowed(alpha),
+        }
+    }
+
+    /// The memory the pixels take, in bytes.
+    pub fn bytes(&self) -> usize {
+        match self {
+            LayerPixels::Color(pixels) => pixels.len() * std::mem::size_of::<Color32>(),
+            LayerPixels::Alpha(alpha) => alpha.len(),
+        }
+    }
+
+    /// The colors of a color layer, which always keeps them as colors.
+    fn composited(&self) -> &[Color32] {
+        match self {
+            LayerPixels::Color(pixels) => pixels,
+            LayerPixels::Alpha(_) => {
+                unreachable!("only stencils keep alpha and they aren't composited")
+            }
+        }
+    }
+
+    /// Whether `self` and `other` are the same pixels rather than a copy.
+    fn ptr_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LayerPixels::Color(a), LayerPixels::Color(b)) => Arc::ptr_eq(a, b),
+            (LayerPixels::Alpha(a), LayerPixels::Alpha(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// The pixels kept the way a layer of `kind` keeps them.
+    fn kept_as(self, kind: LayerKind) -> Self {
+        match (self, kind) {
+            (LayerPixels::Color(pixels), LayerKind::Stencil) => {
+                LayerPixels::Alpha(Arc::new(pixels.iter().map(|p| p.a()).collect()))
+            }
+            (LayerPixels::Alpha(alpha), LayerKind::Color) => {
+                LayerPixels::Color(Arc::new(alpha.iter().map(|&a| white(a)).collect()))
+            }
+            (pixels, _) => pixels,
+        }
+    }
+}
+
+/// Premultiplied white with alpha `a`, what a stencil's alpha stands for.
+fn white(a: u8) -> Color32 {
+    Color32::from_rgba_premultiplied(a, a, a, a)
+}
+
+/// A layer's pixels along with where they are, from [`Canvas::snapshot_layer`].
+#[derive(Clone)]
+pub struct LayerContents {
+    pub bounds: LayerBounds,
+    pub pixels: LayerPixels,
+}
+
+#[derive(Clone)]
+pub struct CanvasLayer {
+    /// Shared with any snapshots taken of the layer, and copied on the first write after.
+    pixels: LayerPixels,
+    bounds: LayerBounds,
+    /// The bounds the layer was created with, in document coordinates, which clearing it
+    /// goes back to.
+    home: LayerBounds,
+    /// Whether painting outside the layer grows it to fit, up to the edges of the canvas.
+    /// Otherwise painting is clipped to the layer.
+    pub auto_grow: bool,
+    pub visible: bool,
+    /// How strongly the layer shows over the ones below, from 0 to 1. At 0 it's left out like
+    /// a hidden layer.
+    pub opacity: f32,
+    /// How the layer's colors combine with the layers below.
+    pub blend_mode: LayerBlendMode,
+    pub name: String,
+    /// No edits of any kind are allowed on the layer.
+    pub lock_pixels: bool,
+    /// Edits are allowed but the alpha of every pixel is preserved.
+    pub lock_alpha: bool,
+    /// The layer is the document's background, which the eraser can paint the background
+    /// color onto rather than erase through.
+    pub background: bool,
+    kind: LayerKind,
+    /// The part of the layer that's changed since it was last shown, in the layer's own
+    /// pixels. Empty once it's been shown.
+    dirty: DirtyRect,
+    /// Goes up with every change to the pixels, so anything worked out from them can tell
+    /// whether it's stale.
+    revision: u64,
+    /// The layer's coverage and colors, along with the revision they were counted at.
+    stats: Option<(u64, Coverage, ColorCount)>,
+}
+
+impl CanvasLayer {
+    /// Fails if the size is outside the default [`CanvasLimits`], before anything is
+    /// allocated.
+    pub fn new(width: u32, height: u32, name: String) -> Result<Self, CanvasError> {
+        Self::new_at(LayerBounds::canvas(width, height), name)
+    }
+
+    /// A layer with its own size and position rather than the canvas's. Fails like
+    /// [`CanvasLayer::new`].
+    pub fn new_at(bounds: LayerBounds, name: String) -> Result<Self, CanvasError> {
+        CanvasLimits::default().check(bounds.width, bounds.height)?;
+        Ok(Self::allocate(bounds, name))
+    }
+
+    /// A layer of a size that's already been checked against the limits.
+    fn allocate(bounds: LayerBounds, name: String) -> Self {
+        Self {
+            pixels: LayerPixels::transparent(
+                LayerKind::Color,
+                bounds.width as usize * bounds.height as usize,
+            ),
+            bounds,
+            home: bounds,
+            auto_grow: true,
+            visible: true,
+            opacity: 1.0,
+            blend_mode: LayerBlendMode::Normal,
+            name,
+            lock_pixels: false,
+            lock_alpha: false,
+            background: false,
+            kind: LayerKind::Color,
+            dirty: DirtyRect::full(bounds.width, bounds.height),
+            revision: 0,
+            stats: None,
+        }
+    }
+
+    /// Marks the whole layer as changed.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = DirtyRect::full(self.bounds.width, self.bounds.height);
+        self.revision += 1;
+    }
+
+    /// Marks `rect` of the layer's own pixels as changed, on top of what already has been.
+    /// Does nothing for an empty `rect`, as nothing changed.
+    pub fn mark_dirty_rect(&mut self, rect: DirtyRect) {
+        let rect = rect.intersect(DirtyRect::full(self.bounds.width, self.bounds.height));
+        if rect.is_empty() {
+            return;
+        }
+        self.dirty = self.dirty.union(rect);
+        self.revision += 1;
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = DirtyRect::default();
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// The part of the layer that's changed since it was last shown, see
+    /// [`CanvasLayer::mark_dirty_rect`].
+    pub fn dirty_rect(&self) -> DirtyRect {
+        self.dirty
+    }
+
+    /// The layer's own pixels, [`CanvasLayer::bounds`] in size. Only copies for a stencil,
+    /// which keeps just the alpha, see [`LayerPixels`].
+    pub fn pixels(&self) -> Cow<'_, [Color32]> {
+        self.pixels.colors()
+    }
+
+    /// The alpha of the layer's own pixels, which is all a stencil keeps.
+    pub fn alpha(&self) -> Cow<'_, [u8]> {
+        self.pixels.alpha()
+    }
+
+    pub fn bounds(&self) -> LayerBounds {
+        self.bounds
+    }
+
+    pub fn offset(&self) -> (i32, i32) {
+        (self.bounds.x, self.bounds.y)
+    }
+
+    /// The layer's own pixels in `rect`, row by row. `rect` has to be within the layer.
+    pub fn rect_pixels(&self, rect: DirtyRect) -> Vec<Color32> {
+        let width = self.bounds.width as usize;
+        (rect.y as usize..(rect.y + rect.height) as usize)
+            .flat_map(|y| {
+                let start = y * width + rect.x as usize;
+                (start..start + rect.width as usize).map(|index| self.pixels.get(index))
+            })
+            .collect()
+    }
+
+    /// The pixel at canvas position (`x`, `y`), transparent where the layer has none.
+    pub fn pixel_at(&self, x: i32, y: i32) -> Color32 {
+        self.bounds
+            .index(x, y)
+            .map_or(Color32::TRANSPARENT, |index| self.pixels.get(index))
+    }
+
+    /// The layer as it covers a `width` by `height` canvas, for things that work in canvas
+    /// coordinates. Only copies if the layer isn't exactly the canvas.
+    pub fn canvas_pixels(&self, width: u32, height: u32) -> Cow<'_, [Color32]> {
+        if self.bounds == LayerBounds::canvas(width, height) {
+            return self.pixels.colors();
+        }
+        let width = width as i32;
+        Cow::Owned(
+            (0..width * height as i32)
+                .map(|i| self.pixel_at(i % width, i / width))
+                .collect(),
+        )
+    }
+
+    /// Writes `source`, the pixels of canvas `rect` row by row, wherever the layer has
+    /// pixels.
+    fn write_canvas_rect(&mut self, rect: DirtyRect, source: &[Color32]) {
+        let bounds = self.bounds;
+        let pixels = self.pixels_mut();
+        for (y, row) in (rect.y as i32..).zip(source.chunks_exact(rect.width as usize)) {
+            for (x, &pixel) in (rect.x as i32..).zip(row) {
+                if let Some(index) = bounds.index(x, y) {
+                    pixels[index] = pixel;
+                }
+            }
+        }
+        self.mark_dirty();
+    }
+
+    /// Grows the layer to cover `bounds` as well, returning its bounds from before if it
+    /// had to grow.
+    fn grow(&mut self, bounds: LayerBounds) -> Option<LayerBounds> {
+        let old = self.bounds;
+        let new = old.union(bounds);
+        if new == old {
+            return None;
+        }
+        let at = ((old.x - new.x) as u32, (old.y - new.y) as u32);
+        let (width, height) = (new.width, new.height);
+        self.pixels = match &self.pixels {
+            LayerPixels::Color(pixels) => LayerPixels::Color(Arc::new(expand(
+                pixels,
+                old.width,
+                width,
+                height,
+                at,
+                Color32::TRANSPARENT,
+            ))),
+            LayerPixels::Alpha(alpha) => {
+                LayerPixels::Alpha(Arc::new(expand(alpha, old.width, width, height, at, 0)))
+            }
+        };
+        self.bounds = new;
+        self.mark_dirty();
+        Some(old)
+    }
+
+    /// The pixels for writing. If a snapshot still shares them, they're copied first so the
+    /// snapshot keeps what it saw. Call once per edit rather than per pixel.
+    ///
+    /// A stencil is unpacked to colors, which it stays as until it's packed again.
+    fn pixels_mut(&mut self) -> &mut Vec<Color32> {
+        self.revision += 1;
+        if let LayerPixels::Alpha(_) = self.pixels {
+            let pixels = std::mem::replace(&mut self.pixels, LayerPixels::Color(Arc::default()));
+            self.pixels = pixels.kept_as(LayerKind::Color);
+        }
+        match &mut self.pixels {
+            LayerPixels::Color(pixels) => Arc::make_mut(pixels),
+            LayerPixels::Alpha(_) => unreachable!("unpacked above"),
+        }
+    }
+
+    /// Puts a stencil unpacked for editing back to just its alpha, see [`LayerPixels`].
+    /// Does nothing to anything else.
+    fn pack(&mut self) {
+        if self.kind == LayerKind::Stencil {
+            let pixels = std::mem::replace(&mut self.pixels, LayerPixels::Alpha(Arc::default()));
+            self.pixels = pixels.kept_as(LayerKind::Stencil);
+        }
+    }
+
+    /// What's in the layer, counted again only if it's changed since the last time.
+    pub fn stats(&mut self) -> LayerStats {
+        let (coverage, distinct_colors) = match self.stats {
+            Some((revision, coverage, colors)) if revision == self.revision => (coverage, colors),
+            _ => {
+                let (width, height) = (self.bounds.width, self.bounds.height);
+                let pixels = self.pixels.colors();
+                let coverage = stats::coverage(&pixels, width, height);
+                let colors = stats::distinct_colors(&pixels, STATS_COLOR_LIMIT);
+                self.stats = Some((self.revision, coverage, colors));
+                (coverage, colors)
+            }
+        };
+        let painted = coverage.bounds;
+        LayerStats {
+            name: self.name.clone(),
+            bounds: self.bounds,
+            painted_pixels: coverage.painted_pixels,
+            painted_bounds: LayerBounds::new(
+                self.bounds.x + painted.x as i32,
+                self.bounds.y + painted.y as i32,
+                painted.width,
+                painted.height,
+            ),
+            distinct_colors,
+            bytes: self.pixels.bytes(),
+            revision: self.revision,
+        }
+    }
+
+    /// Empties the layer and puts it back where it was created.
+    fn reset(&mut self) {
+        let size = self.home.width as usize * self.home.height as usize;
+        self.pixels = LayerPixels::transparent(self.kind, size);
+        self.bounds = self.home;
+        self.mark_dirty();
+    }
+
+    pub fn is_editable(&self) -> bool {
+        !self.lock_pixels
+    }
+
+    pub fn with_background(mut self, background: bool) -> Self {
+        self.background = background;
+        self
+    }
+
+    pub fn kind(&self) -> LayerKind {
+        self.kind
+    }
+
+    /// Whether the layer is part of the image as it's shown and exported: it's visible, not
+    /// fully transparent, and not a stencil.
+    pub fn is_composited(&self) -> bool {
+        self.visible && self.opacity > 0.0 && self.kind == LayerKind::Color
+    }
+
+    /// How the layer shows in the composite besides its pixels, see [`LayerLook`].
+    pub fn look(&self) -> LayerLook {
+        let opacity = self.opacity.to_bits();
+        (self.bounds, self.is_composited(), opacity, self.blend_mode)
+    }
+}
+
+pub struct CanvasState {
+    pub layers: Vec<CanvasLayer>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How many rows of the canvas are merged between checks for cancellation while saving.
+const SAVE_CHUNK_ROWS: u32 = 64;
+
+/// Why saving failed when it was cancelled, see [`CanvasSnapshot::save_rect_as_png`].
+pub const SAVE_CANCELLED: &str = "the save was cancelled";
+
+/// A consistent, read-only copy of the canvas at one moment, from [`Canvas::snapshot`], for
+/// readers like exporters that shouldn't see a stroke half painted. It can be sent to
+/// another thread.
+#[derive(Clone)]
+pub struct CanvasSnapshot {
+    pub width: u32,
+    pub height: u32,
+    pub layers: Vec<LayerSnapshot>,
+}
+
+#[derive(Clone)]
+pub struct LayerSnapshot {
+    pub name: String,
+    pub visible: bool,
+    pub opacity: f32,
+    pub blend_mode: LayerBlendMode,
+    pub kind: LayerKind,
+    pub bounds: LayerBounds,
+    pub pixels: LayerPixels,
+}
+
+impl CanvasSnapshot {
+    /// Saves the canvas, with `chunks` of text kept in the file, see
+    /// [`CanvasSnapshot::save_rect_as_png`].
+    pub fn save_as_png(
+        &self,
+        path: impl AsRef<Path>,
+        chunks: &[(&str, String)],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let canvas = LayerBounds::canvas(self.width, self.height);
+        let task = TaskContext::detached();
+        self.save_rect_as_png(path, canvas, 1.0, None, chunks, &task)
+    }
+
+    /// Saves `rect` of the canvas shrunk by `scale`, which is at most 1, tagged with a
+    /// resolution of `dpi` if there is one. Shrinking is done in linear light, see
+    /// [`downscale`]. Each of `chunks` is a keyword and its text, kept in an iTXt chunk ahead
+    /// of the image data for the app to read back.
+    ///
+    /// Progress goes to `task`. If it's cancelled before the file is written, the file is
+    /// left alone and this fails with [`SAVE_CANCELLED`].
+    pub fn save_rect_as_png(
+        &self,
+        path: impl AsRef<Path>,
+        rect: LayerBounds,
+        scale: f32,
+        dpi: Option<u32>,
+        chunks: &[(&str, String)],
+        task: &TaskContext,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (width, height) = (rect.width, rect.height);
+        let mut merged = Vec::with_capacity(width as usize * height as usize);
+        for top in (0..height).step_by(SAVE_CHUNK_ROWS as usize) {
+            if task.is_cancelled() {
+                return Err(SAVE_CANCELLED.into());
+            }
+            let rows = SAVE_CHUNK_ROWS.min(height - top);
+            let strip = LayerBounds::new(rect.x, rect.y + top as i32, width, rows);
+            merged.extend(self.merged_rect(strip));
+            task.report(0.8 * (top + rows) as f32 / height as f32, "Merging layers");
+        }
+        let (scaled_width, scaled_height) = scaled_size(width, height, scale);
+        if (scaled_width, scaled_height) != (width, height) {
+            merged = downscale(&merged, width, height, scaled_width, scaled_height);
+        }
+        if task.is_cancelled() {
+            return Err(SAVE_CANCELLED.into());
+        }
+        task.report(0.9, "Writing");
+        // PNG stores straight alpha
+        let merged = alpha::unpremultiply_rgba8(&merged);
+
+        // tag the file as sRGB so other apps don't have to guess
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, scaled_width, scaled_height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+        if let Some(dpi) = dpi {
+            // PNG keeps the resolution in pixels per meter
+            let per_meter = (dpi as f32 / 0.0254).round() as u32;
+            encoder.set_pixel_dims(Some(png::PixelDimensions {
+                xppu: per_meter,
+                yppu: per_meter,
+                unit: png::Unit::Meter,
+            }));
+        }
+        for (keyword, text) in chunks {
+            encoder.add_itxt_chunk(keyword.to_string(), text.clone())?;
+        }
+        encoder.write_header()?.write_image_data(&merged)?;
+        task.report(1.0, "Writing");
+        Ok(())
+    }
+
+    /// The visible layers composited the same way as they're shown, see [`composite_region`].
+    pub fn merged(&self) -> Vec<Color32> {
+        self.merged_rect(LayerBounds::canvas(self.width, self.height))
+    }
+
+    /// [`CanvasSnapshot::merged`] over just `rect` of the canvas, row by row.
+    pub fn merged_rect(&self, rect: LayerBounds) -> Vec<Color32> {
+        let rect = rect.intersect(LayerBounds::canvas(self.width, self.height));
+        let layers = self
+            .layers
+            .iter()
+            .filter(|layer| layer.visible && layer.opacity > 0.0 && layer.kind == LayerKind::Color)
+            .map(|layer| {
+                let pixels = layer.pixels.composited();
+                composite_layer(pixels, layer.bounds, layer.opacity, layer.blend_mode)
+            });
+        composite_region(layers, rect)
+    }
+
+    /// The smallest part of the canvas holding everything visible on it, empty if it's all
+    /// transparent. This is what trimming crops to, whether it's the canvas or an export.
+    pub fn content_bounds(&self) -> LayerBounds {
+        let bounds = stats::coverage(&self.merged(), self.width, self.height).bounds;
+        LayerBounds::new(
+            bounds.x as i32,
+            bounds.y as i32,
+            bounds.width,
+            bounds.height,
+        )
+    }
+}
+
+impl CanvasLayer {
+    fn composite_layer(&self) -> CompositeLayer<'_> {
+        composite_layer(
+            self.pixels.composited(),
+            self.bounds,
+            self.opacity,
+            self.blend_mode,
+        )
+    }
+}
+
+fn composite_layer(
+    pixels: &[Color32],
+    bounds: LayerBounds,
+    opacity: f32,
+    blend_mode: LayerBlendMode,
+) -> CompositeLayer<'_> {
+    CompositeLayer {
+        pixels,
+        x: bounds.x,
+        y: bounds.y,
+        width: bounds.width,
+        height: bounds.height,
+        opacity,
+        blend_mode,
+    }
+}
+
+/// `layers` composited over `rect` of the canvas with the one compositor, see
+/// [`composite`]. What's shown, saved, merged and picked from all goes through here, so they
+/// all agree.
+fn composite_region<'a>(
+    layers: impl IntoIterator<Item = CompositeLayer<'a>>,
+    rect: LayerBounds,
+) -> Vec<Color32> {
+    composite(layers, (rect.x, rect.y), rect.width, rect.height)
+}
+
+/// The visible `layers` merged over `rect` of the canvas, see [`composite_region`].
+fn merge_region(layers: &[CanvasLayer], rect: LayerBounds) -> Vec<Color32> {
+    let layers = layers.iter().filter(|layer| layer.is_composited());
+    composite_region(layers.map(CanvasLayer::composite_layer), rect)
+}
+
+/// A layer's revision and how it looks in the composite: everything the merged canvas
+/// depends on.
+type LayerState = (u64, LayerLook);
+
+/// Everything about how a layer shows in the composite besides its pixels: where it is,
+/// whether it's composited at all, its opacity (as bits, to compare exactly) and its blend
+/// mode, see [`Canvas::layer_looks`].
+pub type LayerLook = (LayerBounds, bool, u32, LayerBlendMode);
+
+/// The stencil layer a stroke is painted through, if any, with its revision and bounds, and
+/// the bounds of the layer being painted: everything a stroke mask depends on besides the
+/// selection.
+type StrokeMaskKey = (Option<(usize, u64, LayerBounds)>, LayerBounds);
+
+/// How many colors [`Canvas::stats`] counts before giving up.
+pub const STATS_COLOR_LIMIT: usize = 1 << 16;
+
+/// What's in one layer, from [`CanvasLayer::stats`].
+#[derive(Clone, Debug)]
+pub struct LayerStats {
+    pub name: String,
+    pub bounds: LayerBounds,
+    /// How many pixels aren't fully transparent.
+    pub painted_pixels: usize,
+    /// The smallest rectangle holding every painted pixel, in canvas pixels. Empty if
+    /// nothing's painted.
+    pub painted_bounds: LayerBounds,
+    /// Counted up to [`STATS_COLOR_LIMIT`], see [`stats::distinct_colors`].
+    pub distinct_colors: ColorCount,
+    /// The memory the layer's pixels take up.
+    pub bytes: usize,
+    /// Goes up with every change to the layer's pixels, so a caller can tell whether the
+    /// layer has changed since it last looked.
+    pub revision: u64,
+}
+
+/// An overview of the document, from [`Canvas::stats`].
+#[derive(Clone, Debug)]
+pub struct CanvasStats {
+    pub width: u32,
+    pub height: u32,
+    pub layers: Vec<LayerStats>,
+    /// The colors of the visible layers merged, as an export would have them.
+    pub distinct_colors: ColorCount,
+    /// The memory the layers and the selection take up.
+    pub bytes: usize,
+}
+
+/// Memory the canvas holds besides the layers, from [`Canvas::memory_stats`], in bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryStats {
+    /// Wash stroke state, including what's kept for reuse by the next stroke.
+    pub stroke_buffer: usize,
+    pub stamp: usize,
+    pub selection: usize,
+    /// The layers a preview keeps to start over from, once editing has copied them.
+    pub preview: usize,
+}
+
+impl MemoryStats {
+    pub fn total(&self) -> usize {
+        self.stroke_buffer + self.stamp + self.selection + self.preview
+    }
+}
+
+/// The sizes a canvas and its layers may be, checked before their pixels are allocated so
+/// a size that's zero, or that wrapped around from a negative number, or that's too large
+/// to hold or show, is refused rather than panicking or running out of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CanvasLimits {
+    /// The shortest a side may be.
+    pub min_side: u32,
+    /// The longest a side may be. Layers are shown as single textures, which the graphics
+    /// card limits the sides of, typically to 8192 pixels or more.
+    pub max_side: u32,
+    /// The most pixels there may be in all.
+    pub max_pixels: u64,
+}
+
+impl Default for CanvasLimits {
+    fn default() -> Self {
+        Self {
+            min_side: 1,
+            max_side: 8192,
+            max_pixels: 8192 * 8192,
+        }
+    }
+}
+
+impl CanvasLimits {
+    /// Fails if a `width` by `height` canvas or layer is outside the limits.
+    pub fn check(&self, width: u32, height: u32) -> Result<(), CanvasError> {
+        let fits = (self.min_side..=self.max_side).contains(&width)
+            && (self.min_side..=self.max_side).contains(&height)
+            && width as u64 * height as u64 <= self.max_pixels;
+        match fits {
+            true => Ok(()),
+            false => Err(CanvasError::BadSize {
+                width,
+                height,
+                limits: *self,
+            }),
+        }
+    }
+}
+
+/// An operation the canvas can't carry out.
+#[derive(Debug)]
+pub enum CanvasError {
+    /// There's no layer with this index, for example because the layer has been removed
+    /// since an action referring to it was recorded.
+    NoSuchLayer(usize),
+    /// The document is open for viewing only, see [`Canvas::set_read_only`].
+    ReadOnly,
+    /// A canvas or layer of this size is outside the limits.
+    BadSize {
+        width: u32,
+        height: u32,
+        limits: CanvasLimits,
+    },
+}
+
+impl std::fmt::Display for CanvasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CanvasError::NoSuchLayer(layer) => write!(f, "there is no layer {}", layer),
+            CanvasError::ReadOnly => write!(f, "the document is read-only"),
+            CanvasError::BadSize {
+                width,
+                height,
+                limits,
+            } => write!(
+                f,
+                "{} × {} pixels is not a usable size, sides must be {} to {} pixels and there \
+                 can be at most {} pixels in all",
+                width, height, limits.min_side, limits.max_side, limits.max_pixels
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CanvasError {}
+
+/// The size of a `width` by `height` image shrunk by `scale`, never less than a pixel.
+pub fn scaled_size(width: u32, height: u32, scale: f32) -> (u32, u32) {
+    let scale = scale.clamp(0.0, 1.0);
+    let scaled = |size: u32| ((size as f32 * scale).round() as u32).clamp(1, size.max(1));
+    (scaled(width), scaled(height))
+}
+
+/// How the fill tool finds the region it fills.
+#[derive(Clone, Copy)]
+pub struct FillOptions {
+    /// The layer whose pixels outline the region, or `None` for the visible layers merged.
+    /// The fill always goes on the current layer, so with line art on another layer the
+    /// region inside the lines is filled without touching them.
+    pub reference: Option<usize>,
+    /// How far a pixel's color can be from the clicked one and still be filled.
+    pub tolerance: u8,
+    /// The widest gap in the outline, in pixels, that the fill closes rather than leaks
+    /// through.
+    pub max_gap: u32,
+    /// Only the region connected to the clicked pixel is filled, rather than every pixel of
+    /// its color.
+    pub contiguous: bool,
+    /// How far the fill reaches past the region, in pixels, under soft edges around it.
+    pub grow: u32,
+}
+
+impl Default for FillOptions {
+    fn default() -> Self {
+        Self {
+            reference: None,
+            tolerance: 32,
+            max_gap: 0,
+            contiguous: true,
+            grow: 0,
+        }
+    }
+}
+
+/// What the shape tool draws.
+#[derive(Clone, Copy)]
+pub struct ShapeOptions {
+    pub kind: ShapeKind,
+    /// How wide an outline to draw, in pixels, or `None` to fill the shape.
+    pub outline: Option<f32>,
+}
+
+impl Default for ShapeOptions {
+    fn default() -> Self {
+        Self {
+            kind: ShapeKind::Rectangle,
+            outline: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum BrushStrokeKind {
+    Paint,
+    Erase,
+    /// Erasing that paints the frame color, the background color, with the eraser brush.
+    EraseToBackground,
+    Smudge {
+        /// Pick up color from every visible layer, as it's seen, rather than from the
+        /// stroke's layer alone. The smudged color still goes on the stroke's layer.
+        sample_merged: bool,
+        /// How quickly the stroke picks up what it passes over, see
+        /// [`SmudgeOperation::pickup_rate`].
+        pickup_rate: f32,
+    },
+    /// Lightens the layer's pixels, mostly those in `range`, leaving their alpha alone.
+    Dodge {
+        range: TonalRange,
+    },
+    /// Darkens the layer's pixels, like [`BrushStrokeKind::Dodge`] lightens them.
+    Burn {
+        range: TonalRange,
+    },
+    /// Adds grain of `scale` pixels to the layer's pixels, seeded with the stroke's seed so
+    /// replaying the stroke adds the same grain.
+    Noise {
+        mode: NoiseMode,
+        scale: f32,
+    },
+    /// Shifts the hue, saturation and value of the layer's pixels by as much of `shift` as
+    /// the stroke covers them, leaving their alpha alone.
+    HueShift {
+        shift: HsvShift,
+    },
+    /// Copies the layer's pixels from `offset` away, as they were when the stroke started.
+    Clone {
+        offset: (i32, i32),
+    },
+}
+
+impl BrushStrokeKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            BrushStrokeKind::Paint => "Paint",
+            BrushStrokeKind::Erase => "Erase",
+            BrushStrokeKind::EraseToBackground => "Erase to Background",
+            BrushStrokeKind::Smudge { .. } => "Smudge",
+            BrushStrokeKind::Dodge { .. } => "Dodge",
+            BrushStrokeKind::Burn { .. } => "Burn",
+            BrushStrokeKind::Noise { .. } => "Noise",
+            BrushStrokeKind::HueShift { .. } => "Hue Shift",
+            BrushStrokeKind::Clone { .. } => "Clone",
+        }
+    }
+
+    /// How the dabs of this kind of stroke accumulate. Painting and erasing follow the
+    /// brush setting, except that pixel-snapped strokes always wash so no pixel is painted
+    /// twice; smudging, dodging, burning and adding grain read back their own output, so
+    /// they always build up, and so does cloning, which keeps copying the same source. Hue
+    /// shifts wash, shifting each pixel from how it was before the stroke.
+    pub fn accumulation(&self, brush: &Brush) -> StrokeAccumulation {
+        match self {
+            BrushStrokeKind::Paint
+            | BrushStrokeKind::Erase
+            | BrushStrokeKind::EraseToBackground
+                if brush.pixel_snap() =>
+            {
+                StrokeAccumulation::Wash
+            }
+            BrushStrokeKind::Paint
+            | BrushStrokeKind::Erase
+            | BrushStrokeKind::EraseToBackground => brush.accumulation(),
+            BrushStrokeKind::Smudge { .. }
+            | BrushStrokeKind::Dodge { .. }
+            | BrushStrokeKind::Burn { .. }
+            | BrushStrokeKind::Noise { .. }
+            | BrushStrokeKind::Clone { .. } => StrokeAccumulation::BuildUp,
+            BrushStrokeKind::HueShift { .. } => StrokeAccumulation::Wash,
+        }
+    }
+
+    /// Whether this kind of stroke tapers with `brush`. Painting and erasing do if the
+    /// brush does, except pixel-snapped, and smudging never does.
+    pub fn tapers(&self, brush: &Brush) -> bool {
+        match self {
+            BrushStrokeKind::Paint
+            | BrushStrokeKind::Erase
+            | BrushStrokeKind::EraseToBackground => brush.tapers() && !brush.pixel_snap(),
+            BrushStrokeKind::Smudge { .. }
+            | BrushStrokeKind::Dodge { .. }
+            | BrushStrokeKind::Burn { .. }
+            | BrushStrokeKind::Noise { .. }
+            | BrushStrokeKind::HueShift { .. }
+            | BrushStrokeKind::Clone { .. } => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BrushStrokeFrame {
+    pub brush: Brush,
+    pub color: Rgba,
+    pub cursor_position: Pos2,
+    pub last_cursor_position: Pos2,
+    /// Where the cursor was before `last_cursor_position`, when the frame curves on from
+    /// the one before it.
+    pub previous_cursor_position: Option<Pos2>,
+    pub timestamp: Instant,
+    /// Seconds since the stroke's previous frame, 0 for its first. Kept rather than worked
+    /// out from the timestamps so replaying the stroke paints it the same way.
+    pub elapsed: f32,
+}
+
+/// Straight (unmultiplied) sRGB RGBA8 pixels, the way the clipboard and image files hold
+/// them. Converting to the premultiplied layer format happens when they're committed.
+#[derive(Clone)]
+pub struct PasteImage {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+/// A live preview of an edit whose settings are still changing, such as a filter in its
+/// dialog. Every update starts again from the layers as they were when the preview began, so
+/// dragging a slider never applies the edit on top of itself.
+struct PreviewSession {
+    /// The untouched contents of every layer the preview might edit.
+    originals: Vec<(usize, LayerContents)>,
+    /// The layers the edit is applied to, with what it changed on each last time.
+    targets: Vec<(usize, DirtyRect)>,
+}
+
+/// The time the airbrush pays out at once while held still, see
+/// [`Canvas::airbrush_elapsed`].
+const AIRBRUSH_STEP: f32 = 1.0 / 30.0;
+
+pub struct Canvas {
+    pub state: CanvasState,
+    selection: Option<SelectionMask>,
+    /// The wash buffer of the stroke in progress, kept between strokes so its memory is
+    /// reused.
+    stroke_buffer: StrokeBuffer,
+    /// The colors of the merged canvas, along with the revision, bounds and visibility of
+    /// every layer they were counted from.
+    merged_colors: Option<(Vec<LayerState>, ColorCount)>,
+    /// How long the airbrush has been held still without painting, see
+    /// [`Canvas::airbrush_elapsed`].
+    airbrush_time: f32,
+    /// The symmetry of the stroke in progress.
+    symmetry: Symmetry,
+    /// The stencil layer the stroke in progress is masked by.
+    stencil: Option<usize>,
+    /// How much of each pixel of the layer last painted the stroke in progress can paint,
+    /// through its stencil and the selection, one byte per pixel of the layer, along with
+    /// what it was worked out for. Kept between frames, since none of it changes while a
+    /// stroke is painted unless the layer grows, and dropped when the selection changes.
+    stroke_mask: Option<(StrokeMaskKey, Vec<u8>)>,
+    /// Jitters the dabs of the stroke in progress, seeded with the stroke's seed.
+    stroke_rng: StrokeRng,
+    /// The seed of the stroke in progress, which its grain is laid out from, see
+    /// [`NoiseOperation::seed`].
+    stroke_seed: u64,
+    /// Where the last dab of the stroke in progress landed. Its symmetry images are painted
+    /// along with each dab, so they land in step.
+    stroke_state: StrokeState,
+    /// The color a smudge stroke in progress is carrying, see [`SmudgePickup`].
+    smudge_pickup: SmudgePickup,
+    /// How long the stroke in progress is, if it's a finished one being painted again, so
+    /// its end can taper.
+    stroke_length: Option<f32>,
+    /// The layer a clone stroke in progress copies from, as it covered the canvas when the
+    /// stroke started.
+    clone_source: Option<Vec<Color32>>,
+    /// The layer a stroke in progress is painting with its alpha locked, as it was when the
+    /// stroke started, with where it was, for putting the alpha back after each frame.
+    alpha_lock_before: Option<(usize, LayerBounds, Vec<Color32>)>,
+    /// Whether a layer was put into or taken out of the stack since the stroke in progress
+    /// began, so its layer index may no longer be the layer it was painting.
+    layers_moved: bool,
+    stamp_cache: StampCache,
+    /// The work paint and smudge strokes have done since it was last taken, while it's
+    /// being counted, see [`Canvas::take_op_stats`].
+    op_stats: Option<OpStats>,
+    preview: Option<PreviewSession>,
+    /// Where the canvas's top left corner is in document coordinates. Layers are placed in
+    /// canvas coordinates, but stroke frames are recorded in document coordinates, which stay
+    /// put when the canvas is cropped or expanded around them.
+    origin: (i32, i32),
+    /// The size the canvas was created with, which clearing it goes back to.
+    initial_size: (u32, u32),
+    /// See [`Canvas::set_read_only`].
+    read_only: bool,
+    /// What size the canvas and its layers may grow or be cropped to.
+    limits: CanvasLimits,
+}
+
+impl Canvas {
+    /// Fails if the canvas, or any of its layers, is outside the default [`CanvasLimits`].
+    pub fn new(state: CanvasState) -> Result<Self, CanvasError> {
+        Self::with_limits(state, CanvasLimits::default())
+    }
+
+    /// A canvas whose size, and the sizes of its layers, are kept within `limits`, from
+    /// the start and through every resize and new layer.
+    pub fn with_limits(state: CanvasState, limits: CanvasLimits) -> Result<Self, CanvasError> {
+        limits.check(state.width, state.height)?;
+        for layer in &state.layers {
+            limits.check(layer.bounds.width, layer.bounds.height)?;
+        }
+        let initial_size = (state.width, state.height);
+        Ok(Self {
+            state,
+            selection: None,
+            stroke_buffer: StrokeBuffer::default(),
+            merged_colors: None,
+            airbrush_time: 0.0,
+            symmetry: Symmetry::None,
+            stencil: None,
+            stroke_mask: None,
+            stroke_rng: StrokeRng::new(0),
+            stroke_seed: 0,
+            stroke_state: StrokeState::default(),
+            smudge_pickup: SmudgePickup::default(),
+            stroke_length: None,
+            clone_source: None,
+            alpha_lock_before: None,
+            layers_moved: false,
+            stamp_cache: StampCache::default(),
+            op_stats: None,
+            preview: None,
+            origin: (0, 0),
+            initial_size,
+            read_only: false,
+            limits,
+        })
+    }
+
+    /// Opens the document for viewing only. Every edit of the document is refused here, in
+    /// the canvas, rather than by whatever is driving it: edits of the pixels go through the
+    /// same check as the layer locks, and everything else that changes the document fails
+    /// with [`CanvasError::ReadOnly`] or does nothing. Showing and hiding layers still works,
+    /// as that's part of looking at a document.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        if read_only {
+            self.cancel_preview();
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails if the document can't be edited, see [`Canvas::set_read_only`].
+    pub fn check_editable(&self) -> Result<(), CanvasError> {
+        match self.read_only {
+            true => Err(CanvasError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Canvas position `pos` in document coordinates.
+    pub fn to_document(&self, pos: Pos2) -> Pos2 {
+        pos + emath::vec2(self.origin.0 as f32, self.origin.1 as f32)
+    }
+
+    /// Document position `pos` in canvas coordinates.
+    pub fn to_canvas(&self, pos: Pos2) -> Pos2 {
+        pos - emath::vec2(self.origin.0 as f32, self.origin.1 as f32)
+    }
+
+    /// Crops or expands the canvas to `rect`, given in canvas coordinates, which may reach
+    /// past the edges to expand it. Layers keep their pixels and stay where they are in the
+    /// document, so whatever is cropped away is still there if the canvas grows back. The
+    /// selection doesn't fit the new canvas and is dropped. Fails if `rect` is outside the
+    /// canvas's limits, other than an empty `rect`, which does nothing.
+    pub fn resize(&mut self, rect: LayerBounds) -> Result<(), CanvasError> {
+        self.check_editable()?;
+        if rect.is_empty() {
+            return Ok(());
+        }
+        self.limits.check(rect.width, rect.height)?;
+        self.cancel_preview();
+        for layer in self.state.layers.iter_mut() {
+            layer.bounds = layer.bounds.translated(-rect.x, -rect.y);
+            layer.mark_dirty();
+        }
+        self.origin = (self.origin.0 + rect.x, self.origin.1 + rect.y);
+        (self.state.width, self.state.height) = (rect.width, rect.height);
+        self.selection = None;
+        self.stroke_mask = None;
+        Ok(())
+    }
+
+    /// Resets the per-stroke state. Must be called before the first frame of every stroke,
+    /// including strokes replayed from the history, with the symmetry, stencil and jitter
+    /// seed the stroke was painted with, and its `length` if it's finished. Painting and
+    /// erasing through a `stencil` layer is scaled by its alpha, other than on the stencil
+    /// itself; it's ignored if it isn't a stencil. Ends the last stroke if it wasn't ended.
+    pub fn begin_brush_stroke(
+        &mut self,
+        symmetry: Symmetry,
+        stencil: Option<usize>,
+        seed: u64,
+        length: Option<f32>,
+    ) {
+        self.stroke_buffer.clear();
+        self.airbrush_time = 0.0;
+        self.symmetry = symmetry;
+        self.stencil = stencil;
+        self.stroke_rng = StrokeRng::new(seed);
+        self.stroke_seed = seed;
+        self.stroke_state.clear();
+        self.smudge_pickup.clear();
+        self.stroke_length = length;
+        self.clone_source = None;
+        self.alpha_lock_before = None;
+        self.layers_moved = false;
+        self.end_brush_stroke();
+    }
+
+    /// Packs a stencil the stroke painted back to just its alpha, see [`LayerPixels`]. Call
+    /// once the last frame of a stroke is in; until then the stencil is kept as colors, so
+    /// each frame doesn't have to unpack it again.
+    pub fn end_brush_stroke(&mut self) {
+        for layer in &mut self.state.layers {
+            layer.pack();
+        }
+    }
+
+    /// Fails if `layer` doesn't exist, or if a layer was put into or taken out of the stack
+    /// since the stroke began, as the layer it was painting may be gone or at another index.
+    /// Locked layers aren't an error; the frame just doesn't paint anything.
+    pub fn process_brush_stroke_frame(
+        &mut self,
+        layer: usize,
+        kind: BrushStrokeKind,
+        frame: &BrushStrokeFrame,
+    ) -> Result<(), CanvasError> {
+        self.check_layer(layer)?;
+        if self.layers_moved {
+            return Err(CanvasError::NoSuchLayer(layer));
+        }
+        let elapsed = match kind {
+            BrushStrokeKind::Smudge { .. }
+            | BrushStrokeKind::Dodge { .. }
+            | BrushStrokeKind::Burn { .. }
+            | BrushStrokeKind::Noise { .. }
+            | BrushStrokeKind::HueShift { .. }
+            | BrushStrokeKind::Clone { .. } => frame.elapsed,
+            _ => match self.airbrush_elapsed(frame) {
+                Some(elapsed) => elapsed,
+                None => return Ok(()),
+            },
+        };
+        // painting and erasing keep to the alpha lock themselves, see `PaintOperation`, and
+        // dodging, burning, color grain and hue shifts never change alpha
+        let keeps_alpha = !matches!(
+            kind,
+            BrushStrokeKind::Smudge { .. }
+                | BrushStrokeKind::Clone { .. }
+                | BrushStrokeKind::Noise {
+                    mode: NoiseMode::Alpha,
+                    ..
+                }
+        );
+        self.with_stroke_locks(layer, keeps_alpha, |canvas| match kind {
+            BrushStrokeKind::Paint => canvas.paint(layer, frame, elapsed),
+            BrushStrokeKind::Erase => canvas.erase(layer, frame, elapsed),
+            BrushStrokeKind::EraseToBackground => canvas.paint(layer, frame, elapsed),
+            BrushStrokeKind::Smudge {
+                sample_merged,
+                pickup_rate,
+            } => canvas.smudge(layer, frame, sample_merged, pickup_rate),
+            BrushStrokeKind::Dodge { range } => {
+                canvas.dodge_burn(layer, frame, DodgeBurnMode::Dodge, range)
+            }
+            BrushStrokeKind::Burn { range } => {
+                canvas.dodge_burn(layer, frame, DodgeBurnMode::Burn, range)
+            }
+            BrushStrokeKind::Noise { mode, scale } => canvas.noise(layer, frame, mode, scale),
+            BrushStrokeKind::HueShift { shift } => canvas.hue_shift(layer, frame, shift),
+            BrushStrokeKind::Clone { offset } => canvas.clone_stamp(layer, frame, offset),
+        });
+        Ok(())
+    }
+
+    /// Fails if there's no layer with index `layer`.
+    pub fn check_layer(&self, layer: usize) -> Result<(), CanvasError> {
+        match layer < self.state.layers.len() {
+            true => Ok(()),
+            false => Err(CanvasError::NoSuchLayer(layer)),
+        }
+    }
+
+    /// Runs `edit` against the canvas while enforcing the locks of `layer`, and the
+    /// document being read-only. This and [`Canvas::with_stroke_locks`] are the one place
+    /// the locks are checked; every operation that edits layer pixels goes through them.
+    fn with_layer_locks(&mut self, layer: usize, edit: impl FnOnce(&mut Self)) {
+        if !self.is_layer_editable(layer) {
+            return;
+        }
+        let target = &self.state.layers[layer];
+        let before = target.lock_alpha.then(|| target.pixels.clone());
+        edit(self);
+        let target = &mut self.state.layers[layer];
+        if let Some(before) = before {
+            preserve_alpha(target.pixels_mut(), &before.colors());
+        }
+        target.pack();
+    }
+
+    /// [`Canvas::with_layer_locks`] for a frame of a stroke, where `keeps_alpha` says the
+    /// frame keeps to the alpha lock itself, so the alpha doesn't have to be put back after
+    /// it.
+    ///
+    /// Otherwise the layer is copied once, on the stroke's first frame, and each frame only
+    /// puts the alpha back over the part of the layer it changed, so a long stroke doesn't
+    /// copy and scan the whole layer on every frame. As the alpha can't change, it's the same
+    /// at the start of the stroke as before each frame.
+    fn with_stroke_locks(&mut self, layer: usize, keeps_alpha: bool, edit: impl FnOnce(&mut Self)) {
+        if !self.is_layer_editable(layer) {
+            return;
+        }
+        let target = &mut self.state.layers[layer];
+        if !target.lock_alpha || keeps_alpha {
+            edit(self);
+            return;
+        }
+
+        let before = match self.alpha_lock_before.take() {
+            Some(before) if before.0 == layer => before,
+            _ => (layer, target.bounds, target.pixels.colors().into_owned()),
+        };
+        // what the frame changes, kept apart from what's waiting to be shown
+        let shown = std::mem::take(&mut target.dirty);
+        edit(self);
+        let target = &mut self.state.layers[layer];
+        let changed = std::mem::replace(&mut target.dirty, shown);
+        target.dirty = target.dirty.union(changed);
+
+        let (_, before_bounds, before_pixels) = &before;
+        let bounds = target.bounds;
+        let pixels = target.pixels_mut();
+        for y in changed.y..changed.y + changed.height {
+            let row = y as usize * bounds.width as usize;
+            let xs = changed.x as usize..(changed.x + changed.width) as usize;
+            // the layer may have grown since the stroke started, and what it grew into was
+            // transparent then
+            let old: Vec<Color32> = xs
+                .clone()
+                .map(|x| {
+                    let (x, y) = (bounds.x + x as i32, bounds.y + y as i32);
+                    before_bounds
+                        .index(x, y)
+                        .map_or(Color32::TRANSPARENT, |index| before_pixels[index])
+                })
+                .collect();
+            preserve_alpha(&mut pixels[row + xs.start..row + xs.end], &old);
+        }
+        self.alpha_lock_before = Some(before);
+    }
+
+    /// Whether `layer` exists and its pixels can be edited: it isn't locked and the document
+    /// isn't read-only.
+    fn is_layer_editable(&self, layer: usize) -> bool {
+        let target = self.state.layers.get(layer);
+        !self.read_only && target.is_some_and(|target| target.is_editable())
+    }
+
+    /// Empties every layer and puts it back where it was created, on the canvas as it was
+    /// created, ready for the history to be replayed.
+    pub fn clear(&mut self) {
+        if self.read_only {
+            return;
+        }
+        self.origin = (0, 0);
+        (self.state.width, self.state.height) = self.initial_size;
+        for layer in self.state.layers.iter_mut() {
+            layer.reset();
+        }
+        self.selection = None;
+        self.stroke_mask = None;
+    }
+
+    /// Whether `layer` is a background layer with no transparency anywhere on the canvas.
+    pub fn is_opaque_background(&self, layer: usize) -> bool {
+        let (width, height) = (self.state.width, self.state.height);
+        self.state.layers.get(layer).is_some_and(|layer| {
+            layer.background
+                && layer
+                    .canvas_pixels(width, height)
+                    .iter()
+                    .all(|pixel| pixel.a() == 255)
+        })
+    }
+
+    pub fn selection(&self) -> Option<&SelectionMask> {
+        self.selection.as_ref()
+    }
+
+    /// Replaces the selection. An empty mask is the same as no selection.
+    pub fn set_selection(&mut self, selection: Option<SelectionMask>) {
+        self.selection = selection.filter(|mask| !mask.is_empty());
+        self.stroke_mask = None;
+    }
+
+    /// A selection of the opaque pixels of `layer`, see [`SelectionMask::from_alpha`].
+    pub fn select_opaque(&self, layer: usize, threshold: Option<u8>) -> Option<SelectionMask> {
+        let layer = self.state.layers.get(layer)?;
+        Some(SelectionMask::from_alpha(
+            &layer.canvas_pixels(self.state.width, self.state.height),
+            self.state.width,
+            self.state.height,
+            threshold,
+        ))
+    }
+
+    pub fn clear_layer(&mut self, layer: usize) {
+        self.with_layer_locks(layer, |canvas| {
+            let layer = &mut canvas.state.layers[layer];
+            layer.pixels_mut().fill(Color32::TRANSPARENT);
+            layer.mark_dirty();
+        });
+    }
+
+    pub fn apply_adjustment(&mut self, layer: usize, adjustment: &Adjustment) {
+        self.apply_filter(layer, adjustment);
+    }
+
+    /// Filters `layer` within the selection, returning the part of it that changed.
+    pub fn apply_filter(&mut self, layer: usize, filter: &dyn Filter) -> DirtyRect {
+        let mut dirty = DirtyRect::default();
+        self.with_layer_locks(layer, |canvas| {
+            let (width, height) = (canvas.state.width, canvas.state.height);
+            let selection = canvas.selection.as_ref();
+            let target = &mut canvas.state.layers[layer];
+            if target.bounds == LayerBounds::canvas(width, height) {
+                let mut pixels = PixelSlice::new(target.pixels_mut(), width, height);
+                dirty = apply_filter(filter, &mut pixels, None, selection);
+                if !dirty.is_empty() {
+                    target.mark_dirty();
+                }
+            } else {
+                // filter the part on the canvas, where the selection is
+                let mut on_canvas = target.canvas_pixels(width, height).into_owned();
+                let mut pixels = PixelSlice::new(&mut on_canvas, width, height);
+                dirty = apply_filter(filter, &mut pixels, None, selection);
+                if !dirty.is_empty() {
+                    target.write_canvas_rect(dirty, &pixels.copy_rect(dirty));
+                }
+            }
+        });
+        dirty
+    }
+
+    /// A copy of what [`Canvas::apply_filter`] filters on `layer`, its pixels on the canvas,
+    /// for filtering where the canvas can't be borrowed, such as on a background thread. The
+    /// result goes back with [`Canvas::write_filtered`].
+    pub fn filter_source(&self, layer: usize) -> Option<Vec<Color32>> {
+        let layer = self.state.layers.get(layer)?;
+        Some(
+            layer
+                .canvas_pixels(self.state.width, self.state.height)
+                .into_owned(),
+        )
+    }
+
+    /// Writes `pixels`, canvas `rect` of a copy from [`Canvas::filter_source`] once it's
+    /// been filtered, onto `layer` the way [`Canvas::apply_filter`] would have, returning
+    /// what changed.
+    pub fn write_filtered(
+        &mut self,
+        layer: usize,
+        rect: DirtyRect,
+        pixels: &[Color32],
+    ) -> DirtyRect {
+        let mut dirty = DirtyRect::default();
+        self.with_layer_locks(layer, |canvas| {
+            if !rect.is_empty() {
+                canvas.state.layers[layer].write_canvas_rect(rect, pixels);
+                dirty = rect;
+            }
+        });
+        dirty
+    }
+
+    /// Flood fills `layer` from canvas pixel `seed` with `color`, finding the region in the
+    /// reference the options name rather than in the layer itself, so flats can be filled in
+    /// under line art without touching it. The layer grows to take in the fill, and the
+    /// selection limits it. Returns the canvas rect that changed.
+    pub fn fill(
+        &mut self,
+        layer: usize,
+        seed: (u32, u32),
+        color: Rgba,
+        options: &FillOptions,
+    ) -> Result<DirtyRect, CanvasError> {
+        self.check_editable()?;
+        self.check_layer(layer)?;
+        let (width, height) = (self.state.width, self.state.height);
+        let mut reference = match options.reference {
+            Some(reference) => self
+                .state
+                .layers
+                .get(reference)
+                .ok_or(CanvasError::NoSuchLayer(reference))?
+                .canvas_pixels(width, height)
+                .into_owned(),
+            None => self.snapshot().merged(),
+        };
+
+        let color = self.state.layers[layer].kind.paint_color(color);
+        let mut dirty = DirtyRect::default();
+        self.with_layer_locks(layer, |canvas| {
+            let target = &canvas.state.layers[layer];
+            let mut on_canvas = target.canvas_pixels(width, height).into_owned();
+            dirty = FillOperation {
+                reference: &PixelSlice::new(&mut reference, width, height),
+                pixels: &mut PixelSlice::new(&mut on_canvas, width, height),
+                seed,
+                color,
+                tolerance: options.tolerance,
+                max_gap: options.max_gap,
+                contiguous: options.contiguous,
+                grow: options.grow,
+                selection: canvas.selection.as_ref(),
+            }
+            .process();
+            if dirty.is_empty() {
+                return;
+            }
+            let bounds =
+                LayerBounds::new(dirty.x as i32, dirty.y as i32, dirty.width, dirty.height);
+            canvas.grow_layer(layer, bounds);
+            let pixels = PixelSlice::new(&mut on_canvas, width, height).copy_rect(dirty);
+            canvas.state.layers[layer].write_canvas_rect(dirty, &pixels);
+        });
+        Ok(dirty)
+    }
+
+    /// Draws a shape between the canvas points `from` and `to` on `layer`, within the
+    /// selection, returning the canvas rect it changed.
+    pub fn draw_shape(
+        &mut self,
+        layer: usize,
+        from: (f32, f32),
+        to: (f32, f32),
+        color: Rgba,
+        options: &ShapeOptions,
+    ) -> Result<DirtyRect, CanvasError> {
+        self.check_editable()?;
+        self.check_layer(layer)?;
+        let (width, height) = (self.state.width, self.state.height);
+        let color = self.state.layers[layer].kind.paint_color(color);
+        let mut dirty = DirtyRect::default();
+        self.with_layer_locks(layer, |canvas| {
+            let target = &canvas.state.layers[layer];
+            let mut on_canvas = target.canvas_pixels(width, height).into_owned();
+            dirty = ShapeOperation {
+                pixels: &mut PixelSlice::new(&mut on_canvas, width, height),
+                kind: options.kind,
+                from,
+                to,
+                outline: options.outline,
+                color,
+                selection: canvas.selection.as_ref(),
+            }
+            .process();
+            if dirty.is_empty() {
+                return;
+            }
+            let bounds =
+                LayerBounds::new(dirty.x as i32, dirty.y as i32, dirty.width, dirty.height);
+            canvas.grow_layer(layer, bounds);
+            let pixels = PixelSlice::new(&mut on_canvas, width, height).copy_rect(dirty);
+            canvas.state.layers[layer].write_canvas_rect(dirty, &pixels);
+        });
+        Ok(dirty)
+    }
+
+    /// Copies the pixels of `layer` within canvas `rect`, row by row.
+    pub fn snapshot_rect(&self, layer: usize, rect: DirtyRect) -> Option<Vec<Color32>> {
+        let layer = self.state.layers.get(layer)?;
+        let (x, y) = (rect.x as i32, rect.y as i32);
+        Some(
+            (y..y + rect.height as i32)
+                .flat_map(|y| (x..x + rect.width as i32).map(move |x| layer.pixel_at(x, y)))
+                .collect(),
+        )
+    }
+
+    /// Puts back pixels taken with [`Canvas::snapshot_rect`], ignoring the layer locks like
+    /// [`Canvas::restore_layer`]. The layer grows to take them in first, as it may have for
+    /// the edit they were taken after, such as a fill.
+    pub fn restore_rect(&mut self, layer: usize, rect: DirtyRect, pixels: &[Color32]) {
+        if self.read_only {
+            return;
+        }
+        let bounds = LayerBounds::new(rect.x as i32, rect.y as i32, rect.width, rect.height);
+        self.grow_layer(layer, bounds);
+        if let Some(layer) = self.state.layers.get_mut(layer) {
+            layer.write_canvas_rect(rect, pixels);
+            layer.pack();
+        }
+    }
+
+    /// Takes a layer's pixels and position so they can be restored later with
+    /// [`Canvas::restore_layer`]. This is cheap: the pixels are only copied once the layer is
+    /// next edited.
+    pub fn snapshot_layer(&self, layer: usize) -> Option<LayerContents> {
+        self.state.layers.get(layer).map(|layer| LayerContents {
+            bounds: layer.bounds,
+            pixels: layer.pixels.clone(),
+        })
+    }
+
+    /// Puts back what was taken with [`Canvas::snapshot_layer`]. This rolls back edits rather
+    /// than making new ones, so it ignores the layer locks.
+    pub fn restore_layer(&mut self, layer: usize, contents: LayerContents) {
+        if self.read_only {
+            return;
+        }
+        if let Some(layer) = self.state.layers.get_mut(layer) {
+            layer.bounds = contents.bounds;
+            layer.pixels = contents.pixels.kept_as(layer.kind);
+            layer.mark_dirty();
+        }
+    }
+
+    /// Starts previewing an edit on `layers`, all of which are targeted until
+    /// [`Canvas::set_preview_targets`] says otherwise. Snapshot every layer the edit might
+    /// ever target up front. A preview that's already open is cancelled first. Fails, and
+    /// leaves nothing open, if any of the layers doesn't exist.
+    pub fn begin_preview(&mut self, layers: Vec<usize>) -> Result<(), CanvasError> {
+        self.check_editable()?;
+        self.cancel_preview();
+        let originals = layers
+            .iter()
+            .map(|&layer| {
+                let contents = self.snapshot_layer(layer);
+                contents
+                    .map(|contents| (layer, contents))
+                    .ok_or(CanvasError::NoSuchLayer(layer))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.preview = Some(PreviewSession {
+            targets: layers
+                .into_iter()
+                .map(|layer| (layer, DirtyRect::default()))
+                .collect(),
+            originals,
+        });
+        Ok(())
+    }
+
+    /// The contents `layer` had when the open preview began.
+    pub fn preview_original(&self, layer: usize) -> Option<&LayerContents> {
+        let session = self.preview.as_ref()?;
+        session
+            .originals
+            .iter()
+            .find(|(index, _)| *index == layer)
+            .map(|(_, contents)| contents)
+    }
+
+    /// Changes which layers the open preview edits. Layers that weren't snapshotted when it
+    /// began are left out. Takes effect on the next [`Canvas::update_preview`].
+    pub fn set_preview_targets(&mut self, targets: Vec<usize>) {
+        if let Some(session) = &mut self.preview {
+            session.targets = targets
+                .into_iter()
+                .filter(|layer| session.originals.iter().any(|(index, _)| index == layer))
+                .map(|layer| (layer, DirtyRect::default()))
+                .collect();
+        }
+    }
+
+    /// Puts the previewed layers back as they were, then runs `edit` on each target,
+    /// returning the part of it that changed.
+    pub fn update_preview(&mut self, mut edit: impl FnMut(&mut Self, usize) -> DirtyRect) {
+        let Some(mut session) = self.preview.take() else {
+            return;
+        };
+        for (layer, contents) in &session.originals {
+            self.restore_layer(*layer, contents.clone());
+        }
+        for (layer, changed) in &mut session.targets {
+            *changed = edit(self, *layer);
+        }
+        self.preview = Some(session);
+    }
+
+    /// Ends the open preview, keeping the layers as they are, and returns each target with
+    /// what the last update changed on it, for recording as an undoable action.
+    pub fn commit_preview(&mut self) -> Vec<(usize, DirtyRect)> {
+        self.preview
+            .take()
+            .map(|session| session.targets)
+            .unwrap_or_default()
+    }
+
+    /// Ends the open preview, putting the layers back as they were when it began.
+    pub fn cancel_preview(&mut self) {
+        if let Some(session) = self.preview.take() {
+            for (layer, contents) in session.originals {
+                self.restore_layer(layer, contents);
+            }
+        }
+    }
+
+    /// Moves `layer` so its top left corner is at `offset`. The pixels stay as they are, so
+    /// moving is instant and nothing is lost off the edges of the canvas.
+    pub fn move_layer(&mut self, layer: usize, offset: (i32, i32)) {
+        self.with_layer_locks(layer, |canvas| {
+            let layer = &mut canvas.state.layers[layer];
+            (layer.bounds.x, layer.bounds.y) = offset;
+        });
+    }
+
+    pub fn add_layer(&mut self) -> Result<usize, CanvasError> {
+        let layer_num = self.layers().len() + 1;
+        self.add_named_layer(format!("Layer {}", layer_num))
+    }
+
+    /// Adds an empty layer on top, returning its index.
+    pub fn add_named_layer(&mut self, name: String) -> Result<usize, CanvasError> {
+        let bounds = LayerBounds::canvas(self.state.width, self.state.height);
+        self.add_layer_at(name, bounds)
+    }
+
+    /// Adds an empty stencil layer on top, see [`LayerKind::Stencil`], returning its index.
+    pub fn add_stencil_layer(&mut self) -> Result<usize, CanvasError> {
+        let stencils = self.state.layers.iter();
+        let count = stencils.filter(|l| l.kind == LayerKind::Stencil).count();
+        let layer = self.add_named_layer(format!("Stencil {}", count + 1))?;
+        let stencil = &mut self.state.layers[layer];
+        stencil.kind = LayerKind::Stencil;
+        stencil.pack();
+        Ok(layer)
+    }
+
+    /// Adds a layer just above `layer` holding the visible layers merged, as an export would
+    /// have them, returning its index. The merged layers are left as they are.
+    pub fn merge_visible(&mut self, layer: usize) -> Result<usize, CanvasError> {
+        self.check_editable()?;
+        let (width, height) = (self.state.width, self.state.height);
+        self.limits.check(width, height)?;
+        let bounds = LayerBounds::canvas(width, height);
+        let mut merged = CanvasLayer::allocate(bounds, "Merged".to_string());
+        merged.home = bounds.translated(self.origin.0, self.origin.1);
+        merged.pixels = LayerPixels::Color(Arc::new(self.snapshot().merged()));
+        let index = (layer + 1).min(self.state.layers.len());
+        self.insert_layer(index, merged)?;
+        Ok(index)
+    }
+
+    /// Puts `layer` into the stack at `index`, moving the layers from there up by one.
+    pub fn insert_layer(
+        &mut self,
+        index: usize,
+        mut layer: CanvasLayer,
+    ) -> Result<(), CanvasError> {
+        self.check_editable()?;
+        layer.mark_dirty();
+        self.state.layers.insert(index, layer);
+        self.forget_layer_indices();
+        Ok(())
+    }
+
+    /// Takes the layer at `index` out of the stack, moving the layers above it down by one.
+    /// Nothing is taken out of a read-only document.
+    pub fn remove_layer(&mut self, index: usize) -> Option<CanvasLayer> {
+        if self.read_only || index >= self.state.layers.len() {
+            return None;
+        }
+        let layer = self.state.layers.remove(index);
+        self.forget_layer_indices();
+        Some(layer)
+    }
+
+    /// Drops what's kept between frames for a layer by its index, once the indices change.
+    fn forget_layer_indices(&mut self) {
+        self.stroke_mask = None;
+        self.alpha_lock_before = None;
+        self.layers_moved = true;
+    }
+
+    /// Adds an empty layer on top covering just `bounds`, returning its index. Fails if
+    /// `bounds` is outside the canvas's limits.
+    pub fn add_layer_at(
+        &mut self,
+        name: String,
+        bounds: LayerBounds,
+    ) -> Result<usize, CanvasError> {
+        self.check_editable()?;
+        self.limits.check(bounds.width, bounds.height)?;
+        let mut layer = CanvasLayer::allocate(bounds, name);
+        layer.home = bounds.translated(self.origin.0, self.origin.1);
+        self.layers().push(layer);
+        Ok(self.state.layers.len() - 1)
+    }
+
+    pub fn layers(&mut self) -> &mut Vec<CanvasLayer> {
+        &mut self.state.layers
+    }
+
+    /// How each layer shows in the composite besides its pixels, bottom to top. While these
+    /// stay the same, only what the layers mark dirty needs compositing again.
+    pub fn layer_looks(&self) -> Vec<LayerLook> {
+        self.state.layers.iter().map(CanvasLayer::look).collect()
+    }
+
+    /// The part of the canvas the composited layers have changed since they were last
+    /// shown, see [`CanvasLayer::dirty_rect`].
+    pub fn composite_dirty_rect(&self) -> LayerBounds {
+        let layers = self
+            .state
+            .layers
+            .iter()
+            .filter(|layer| layer.is_composited());
+        layers
+            .map(|layer| {
+                let dirty = layer.dirty;
+                let (x, y) = (
+                    layer.bounds.x + dirty.x as i32,
+                    layer.bounds.y + dirty.y as i32,
+                );
+                LayerBounds::new(x, y, dirty.width, dirty.height)
+            })
+            .fold(LayerBounds::default(), LayerBounds::union)
+            .intersect(LayerBounds::canvas(self.state.width, self.state.height))
+    }
+
+    /// The visible layers composited over `rect` of the canvas, as they're shown and saved.
+    pub fn composite_rect(&self, rect: LayerBounds) -> Vec<Color32> {
+        let rect = rect.intersect(LayerBounds::canvas(self.state.width, self.state.height));
+        merge_region(&self.state.layers, rect)
+    }
+
+    /// Picks the color around `position` with `kernel`, either from a single layer or, when
+    /// `layer` is `None`, from the merge of all visible layers. Picking from somewhere fully
+    /// transparent, or a layer that doesn't exist, keeps `previous`.
+    pub fn sample_color(
+        &self,
+        layer: Option<usize>,
+        position: (f32, f32),
+        kernel: SampleKernel,
+        previous: Rgba,
+    ) -> Rgba {
+        let layer = match layer {
+            Some(layer) => match self.state.layers.get(layer) {
+                Some(layer) => Some(layer),
+                None => return previous,
+            },
+            None => None,
+        };
+        // only the pixels the kernel can reach, in canvas coordinates, see `reachable_part`
+        let rect = LayerBounds::around(&[position], kernel.reach())
+            .intersect(LayerBounds::canvas(self.state.width, self.state.height));
+        let mut pixels: Vec<Color32> = match layer {
+            Some(layer) => (rect.y..rect.bottom())
+                .flat_map(|y| (rect.x..rect.right()).map(move |x| (x, y)))
+                .map(|(x, y)| layer.pixel_at(x, y))
+                .collect(),
+            None => merge_region(&self.state.layers, rect),
+        };
+        EyedropperOperation {
+            pixels: &PixelSlice::new(&mut pixels, rect.width, rect.height),
+            position: (position.0 - rect.x as f32, position.1 - rect.y as f32),
+            kernel,
+            composited: layer.is_none(),
+            previous_color: previous,
+        }
+        .process()
+    }
+
+    pub fn save_as_png(
+        &self,
+        path: impl AsRef<Path>,
+        chunks: &[(&str, String)],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.snapshot().save_as_png(path, chunks)
+    }
+
+    /// Statistics on the document and each of its layers. Only what has changed since the
+    /// last call is counted again.
+    pub fn stats(&mut self) -> CanvasStats {
+        let layers: Vec<LayerStats> = self
+            .state
+            .layers
+            .iter_mut()
+            .map(|layer| layer.stats())
+            .collect();
+
+        let key: Vec<_> = self
+            .state
+            .layers
+            .iter()
+            .map(|layer| (layer.revision, layer.look()))
+            .collect();
+        let distinct_colors = match &self.merged_colors {
+            Some((counted, colors)) if *counted == key => *colors,
+            _ => {
+                let merged = self.snapshot().merged();
+                let colors = stats::distinct_colors(&merged, STATS_COLOR_LIMIT);
+                self.merged_colors = Some((key, colors));
+                colors
+            }
+        };
+
+        let selection_bytes = self
+            .selection
+            .as_ref()
+            .map_or(0, |mask| mask.values().len());
+        CanvasStats {
+            width: self.state.width,
+            height: self.state.height,
+            bytes: layers.iter().map(|layer| layer.bytes).sum::<usize>() + selection_bytes,
+            layers,
+            distinct_colors,
+        }
+    }
+
+    /// Starts or stops counting the work paint and smudge strokes do, for profiling. Only
+    /// worth doing while it's shown, as every dab is counted.
+    pub fn count_op_stats(&mut self, count: bool) {
+        if count != self.op_stats.is_some() {
+            self.op_stats = count.then(OpStats::default);
+        }
+    }
+
+    /// The work counted since this was last called, if it's being counted, see
+    /// [`Canvas::count_op_stats`].
+    pub fn take_op_stats(&mut self) -> Option<OpStats> {
+        self.op_stats.as_mut().map(std::mem::take)
+    }
+
+    /// The memory held for painting besides the layers themselves.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let preview = self.preview.as_ref().map_or(0, |session| {
+            session
+                .originals
+                .iter()
+                .filter(|(layer, original)| {
+                    // until the layer is edited, the original is the layer
+                    self.state
+                        .layers
+                        .get(*layer)
+                        .is_none_or(|layer| !layer.pixels.ptr_eq(&original.pixels))
+                })
+                .map(|(_, original)| original.pixels.bytes())
+                .sum()
+        });
+        MemoryStats {
+            stroke_buffer: self.stroke_buffer.bytes(),
+            stamp: self.stamp_cache.bytes(),
+            selection: self
+                .selection
+                .as_ref()
+                .map_or(0, |mask| mask.values().len()),
+            preview,
+        }
+    }
+
+    /// Captures every layer as it is now, without copying any pixels. The canvas keeps
+    /// painting as usual; a layer is only copied when it's next edited, and only if the
+    /// snapshot is still alive.
+    pub fn snapshot(&self) -> CanvasSnapshot {
+        CanvasSnapshot {
+            width: self.state.width,
+            height: self.state.height,
+            layers: self
+                .state
+                .layers
+                .iter()
+                .map(|layer| LayerSnapshot {
+                    name: layer.name.clone(),
+                    visible: layer.visible,
+                    opacity: layer.opacity,
+                    blend_mode: layer.blend_mode,
+                    kind: layer.kind,
+                    bounds: layer.bounds,
+                    pixels: layer.pixels.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Composites straight-alpha `image` over `layer` with its top left corner at `offset`,
+    /// growing the layer to take in the part on the canvas if it grows, and cropping whatever
+    /// still falls outside the layer.
+    pub fn paste(&mut self, layer: usize, image: &PasteImage, offset: (i32, i32)) {
+        let image_bounds = LayerBounds::new(offset.0, offset.1, image.width, image.height);
+        self.grow_layer(layer, image_bounds);
+        self.with_layer_locks(layer, |canvas| {
+            let layer = &mut canvas.state.layers[layer];
+            let bounds = layer.bounds;
+            let pixels = layer.pixels_mut();
+            for (i, pixel) in image.rgba.chunks_exact(4).enumerate() {
+                let x = offset.0 + (i as u32 % image.width) as i32;
+                let y = offset.1 + (i as u32 / image.width) as i32;
+                let Some(index) = bounds.index(x, y).filter(|_| pixel[3] != 0) else {
+                    continue;
+                };
+                let src = Rgba::from(alpha::premultiply([pixel[0], pixel[1], pixel[2], pixel[3]]));
+                let dst = Rgba::from(pixels[index]);
+                pixels[index] = Color32::from(src + dst * (1.0 - src.a()));
+            }
+            layer.mark_dirty();
+        });
+    }
+
+    /// Copies the opaque part of `layer` as straight-alpha pixels, limited to the selection
+    /// if there is one, along with where the copied area's top left corner was.
+    pub fn copy(&self, layer: usize) -> Option<(PasteImage, (i32, i32))> {
+        let layer = self.state.layers.get(layer)?;
+        let pixels = layer.canvas_pixels(self.state.width, self.state.height);
+        let width = self.state.width as usize;
+        let selection = self.selection.as_ref().map(|mask| mask.values());
+        let alpha = |index: usize| {
+            let alpha = pixels[index].a() as u32;
+            match selection {
+                Some(selection) => (alpha * selection[index] as u32 / 255) as u8,
+                None => alpha as u8,
+            }
+        };
+
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        for index in (0..pixels.len()).filter(|&index| alpha(index) > 0) {
+            let (x, y) = (index % width, index / width);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+        if min_x > max_x {
+            return None;
+        }
+
+        let mut rgba = Vec::with_capacity((max_x - min_x + 1) * (max_y - min_y + 1) * 4);
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let index = y * width + x;
+                let [r, g, b, _] = alpha::unpremultiply(pixels[index]);
+                rgba.extend_from_slice(&[r, g, b, alpha(index)]);
+            }
+        }
+        let image = PasteImage {
+            width: (max_x - min_x + 1) as u32,
+            height: (max_y - min_y + 1) as u32,
+            rgba,
+        };
+        Some((image, (min_x as i32, min_y as i32)))
+    }
+
+    /// The stroke buffer for the current stroke, or `None` for strokes that don't need one,
+    /// see [`StrokeAccumulation::needs_buffer`]. `masked` strokes always get one, so the mask
+    /// scales the stroke as a whole, rather than each dab that then builds up past it, and
+    /// so do brushes that paint through a float copy, see [`Brush::float_buffer`].
+    fn stroke_buffer<'a>(
+        stroke_buffer: &'a mut StrokeBuffer,
+        accumulation: StrokeAccumulation,
+        brush: &Brush,
+        masked: bool,
+    ) -> Option<&'a mut StrokeBuffer> {
+        let needed = masked || accumulation.needs_buffer(brush.opacity()) || brush.float_buffer();
+        needed.then_some(stroke_buffer)
+    }
+
+    /// Grows `layer` to take in the part of `bounds` on the canvas, if the layer grows.
+    /// Alpha-locked layers don't, since anything painted on the new pixels would be locked
+    /// out anyway, and nor do layers that would outgrow the canvas's limits, which can
+    /// happen to a layer that reaches well off the canvas. Painting is clipped to them.
+    fn grow_layer(&mut self, layer: usize, bounds: LayerBounds) {
+        let canvas = LayerBounds::canvas(self.state.width, self.state.height);
+        let Some(target) = self.state.layers.get_mut(layer) else {
+            return;
+        };
+        if self.read_only || !target.auto_grow || target.lock_alpha || !target.is_editable() {
+            return;
+        }
+        let bounds = bounds.intersect(canvas);
+        let grown = target.bounds.union(bounds);
+        if self.limits.check(grown.width, grown.height).is_err() {
+            return;
+        }
+        if let Some(old) = target.grow(bounds) {
+            // a wash stroke in progress has to keep up
+            let new = target.bounds;
+            let at = ((old.x - new.x) as u32, (old.y - new.y) as u32);
+            self.stroke_buffer.expand(at);
+        }
+    }
+
+    /// The last and current cursor positions of `frame`, which are in document coordinates,
+    /// and the stroke's symmetry, in the layer's own pixels, after growing the layer to take
+    /// in the frame's dabs and their images if `grow` is set.
+    fn frame_in_layer(
+        &mut self,
+        layer: usize,
+        frame: &BrushStrokeFrame,
+        grow: bool,
+    ) -> ((f32, f32), (f32, f32), Symmetry) {
+        let from = self.to_canvas(frame.last_cursor_position);
+        let to = self.to_canvas(frame.cursor_position);
+        let (from, to) = ((from.x, from.y), (to.x, to.y));
+        // the symmetry is placed in document coordinates, like the frames
+        let (origin_x, origin_y) = (self.origin.0 as f32, self.origin.1 as f32);
+        let symmetry = self.symmetry.translated(-origin_x, -origin_y);
+        if grow {
+            let reach = frame.brush.radius() + 1.0;
+            let mut points = vec![from, to];
+            // a curved frame stays within reach of the position it curves on from
+            if let Some(previous) = frame.previous_cursor_position {
+                let previous = self.to_canvas(previous);
+                points.push((previous.x, previous.y));
+            }
+            points.extend(points.clone().into_iter().flat_map(|p| symmetry.images(p)));
+            self.grow_layer(layer, LayerBounds::around(&points, reach));
+        }
+        let bounds = self.state.layers[layer].bounds;
+        let (x, y) = (bounds.x as f32, bounds.y as f32);
+        let to_layer = |(px, py): (f32, f32)| (px - x, py - y);
+        (to_layer(from), to_layer(to), symmetry.translated(-x, -y))
+    }
+
+    /// Where the cursor was before `frame`'s last position, if the frame curves on from the
+    /// one before it, in the layer's own pixels.
+    fn previous_in_layer(&self, layer: usize, frame: &BrushStrokeFrame) -> Option<(f32, f32)> {
+        let bounds = self.state.layers[layer].bounds;
+        frame.previous_cursor_position.map(|previous| {
+            let previous = self.to_canvas(previous);
+            (previous.x - bounds.x as f32, previous.y - bounds.y as f32)
+        })
+    }
+
+    /// How long a frame that holds the airbrush still should paint for, or `None` to leave
+    /// it out. Held-still time is paid out in whole [`AIRBRUSH_STEP`]s, so the layer gets the
+    /// same deposits, rounded to 8-bit pixels the same way, whatever the frame rate. At high
+    /// frame rates each frame's deposit would otherwise be small enough to round away.
+    fn airbrush_elapsed(&mut self, frame: &BrushStrokeFrame) -> Option<f32> {
+        let held_still = frame.cursor_position == frame.last_cursor_position;
+        if frame.brush.flow_per_second().is_none() || !held_still || frame.elapsed <= 0.0 {
+            self.airbrush_time = 0.0;
+            return Some(frame.elapsed);
+        }
+        self.airbrush_time += frame.elapsed;
+        // frame times add up to a step a little short of it
+        let steps = (self.airbrush_time / AIRBRUSH_STEP + 1e-3).floor();
+        if steps < 1.0 {
+            return None;
+        }
+        self.airbrush_time = (self.airbrush_time - steps * AIRBRUSH_STEP).max(0.0);
+        Some(steps * AIRBRUSH_STEP)
+    }
+
+    fn paint(&mut self, layer: usize, frame: &BrushStrokeFrame, elapsed: f32) {
+        let (from, to, symmetry) = self.frame_in_layer(layer, frame, true);
+        let previous = self.previous_in_layer(layer, frame);
+        self.update_stroke_mask(layer);
+        let bounds = self.state.layers[layer].bounds;
+        let accumulation = BrushStrokeKind::Paint.accumulation(&frame.brush);
+        let masked = self.stroke_mask.is_some();
+        let stroke_buffer =
+            Self::stroke_buffer(&mut self.stroke_buffer, accumulation, &frame.brush, masked);
+        let dirty = PaintOperation {
+            accumulation,
+            brush: &frame.brush,
+            color: self.state.layers[layer]
+                .kind
+                .paint_color(frame.color)
+                .into(),
+            segment: StrokeSegment { from, to },
+            previous_cursor_position: previous,
+            is_eraser: false,
+            stroke_buffer,
+            elapsed,
+            stamp_cache: &mut self.stamp_cache,
+            rng: &mut self.stroke_rng,
+            stroke_state: &mut self.stroke_state,
+            taper_in: frame.brush.taper_in(),
+            taper_out: frame.brush.taper_out(),
+            stroke_length: self.stroke_length,
+            mask: self.stroke_mask.as_ref().map(|(_, mask)| mask.as_slice()),
+            alpha_lock: self.state.layers[layer].lock_alpha,
+            symmetry,
+            stats: self.op_stats.as_mut(),
+            pixels: &mut PixelSlice::new(
+                self.state.layers[layer].pixels_mut(),
+                bounds.width,
+                bounds.height,
+            ),
+        }
+        .process();
+        self.layers()[layer].mark_dirty_rect(dirty);
+    }
+
+    fn erase(&mut self, layer: usize, frame: &BrushStrokeFrame, elapsed: f32) {
+        // there's nothing to erase outside the layer
+        let (from, to, symmetry) = self.frame_in_layer(layer, frame, false);
+        let previous = self.previous_in_layer(layer, frame);
+        self.update_stroke_mask(layer);
+        let bounds = self.state.layers[layer].bounds;
+        let accumulation = BrushStrokeKind::Erase.accumulation(&frame.brush);
+        let masked = self.stroke_mask.is_some();
+        let stroke_buffer =
+            Self::stroke_buffer(&mut self.stroke_buffer, accumulation, &frame.brush, masked);
+        let dirty = PaintOperation {
+            accumulation,
+            brush: &frame.brush,
+            color: Rgba::WHITE.into(),
+            segment: StrokeSegment { from, to },
+            previous_cursor_position: previous,
+            is_eraser: true,
+            stroke_buffer,
+            elapsed,
+            stamp_cache: &mut self.stamp_cache,
+            rng: &mut self.stroke_rng,
+            stroke_state: &mut self.stroke_state,
+            taper_in: frame.brush.taper_in(),
+            taper_out: frame.brush.taper_out(),
+            stroke_length: self.stroke_length,
+            mask: self.stroke_mask.as_ref().map(|(_, mask)| mask.as_slice()),
+            alpha_lock: self.state.layers[layer].lock_alpha,
+            symmetry,
+            stats: self.op_stats.as_mut(),
+            pixels: &mut PixelSlice::new(
+                self.state.layers[layer].pixels_mut(),
+                bounds.width,
+                bounds.height,
+            ),
+        }
+        .process();
+        self.layers()[layer].mark_dirty_rect(dirty);
+    }
+
+    /// Works out how much of each pixel of `layer` the stroke can paint: as much as the
+    /// stroke's stencil covers it, if the stroke has one and it isn't the layer itself, and
+    /// as much as it's selected, if anything is. Drops the mask if neither limits the stroke.
+    fn update_stroke_mask(&mut self, layer: usize) {
+        let target = &self.state.layers[layer];
+        let stencil = self
+            .stencil
+            .filter(|&stencil| stencil != layer)
+            .and_then(|stencil| Some((stencil, self.state.layers.get(stencil)?)))
+            .filter(|(_, stencil)| stencil.kind == LayerKind::Stencil);
+        let selection = self.selection.as_ref();
+        if stencil.is_none() && selection.is_none() {
+            self.stroke_mask = None;
+            return;
+        }
+        let key = (
+            stencil.map(|(index, stencil)| (index, stencil.revision, stencil.bounds)),
+            target.bounds,
+        );
+        if self
+            .stroke_mask
+            .as_ref()
+            .is_some_and(|(cached, _)| *cached == key)
+        {
+            return;
+        }
+        let bounds = target.bounds;
+        let width = bounds.width.max(1) as i32;
+        let mask = (0..bounds.width as i32 * bounds.height as i32)
+            .map(|i| {
+                let (x, y) = (bounds.x + i % width, bounds.y + i / width);
+                let stencil = stencil.map_or(255, |(_, stencil)| stencil.pixel_at(x, y).a());
+                let selected = selection.map_or(255, |selection| match x < 0 || y < 0 {
+                    true => 0,
+                    false => selection.value(x as u32, y as u32),
+                });
+                (stencil as u32 * selected as u32 / 255) as u8
+            })
+            .collect();
+        self.stroke_mask = Some((key, mask));
+    }
+
+    fn smudge(
+        &mut self,
+        layer: usize,
+        frame: &BrushStrokeFrame,
+        sample_merged: bool,
+        pickup_rate: f32,
+    ) {
+        let (from, to, symmetry) = self.frame_in_layer(layer, frame, true);
+        let bounds = self.state.layers[layer].bounds;
+        // the layers around this one don't change during the segment, so they're merged
+        // once, over as far as its dabs reach
+        let merged = sample_merged.then(|| {
+            let reach = frame.brush.radius() + 2.0;
+            let rect = LayerBounds::around(&[from, to], reach)
+                .intersect(LayerBounds::canvas(bounds.width, bounds.height));
+            let on_canvas = LayerBounds::new(
+                bounds.x + rect.x,
+                bounds.y + rect.y,
+                rect.width,
+                rect.height,
+            );
+            let (below, above) = self.state.layers.split_at(layer);
+            (
+                merge_region(below, on_canvas),
+                merge_region(&above[1..], on_canvas),
+                DirtyRect::new(rect.x as u32, rect.y as u32, rect.width, rect.height),
+            )
+        });
+        self.update_stroke_mask(layer);
+        let dirty = SmudgeOperation {
+            brush: &frame.brush,
+            segment: StrokeSegment { from, to },
+            smudge_strength: 1.0, // @todo: doesn't belong here, infact can probably just use opacity
+            pickup_rate,
+            pixels: &mut PixelSlice::new(
+                self.state.layers[layer].pixels_mut(),
+                bounds.width,
+                bounds.height,
+            ),
+            stamp_cache: &mut self.stamp_cache,
+            stroke_state: &mut self.stroke_state,
+            pickup: &mut self.smudge_pickup,
+            symmetry,
+            mask: self.stroke_mask.as_ref().map(|(_, mask)| mask.as_slice()),
+            merged: merged.as_ref().map(|(below, above, rect)| MergedSource {
+                below,
+                above,
+                rect: *rect,
+            }),
+            stats: self.op_stats.as_mut(),
+        }
+        .process();
+        self.layers()[layer].mark_dirty_rect(dirty);
+    }
+
+    fn dodge_burn(
+        &mut self,
+        layer: usize,
+        frame: &BrushStrokeFrame,
+        mode: DodgeBurnMode,
+        range: TonalRange,
+    ) {
+        let (from, to, symmetry) = self.frame_in_layer(layer, frame, false);
+        let bounds = self.state.layers[layer].bounds;
+        self.update_stroke_mask(layer);
+        let dirty = DodgeBurnOperation {
+            pixels: &mut PixelSlice::new(
+                self.state.layers[layer].pixels_mut(),
+                bounds.width,
+                bounds.height,
+            ),
+            brush: &frame.brush,
+            segment: StrokeSegment { from, to },
+            mode,
+            range,
+            stamp_cache: &mut self.stamp_cache,
+            stroke_state: &mut self.stroke_state,
+            mask: self.stroke_mask.as_ref().map(|(_, mask)| mask.as_slice()),
+            symmetry,
+        }
+        .process();
+        self.layers()[layer].mark_dirty_rect(dirty);
+    }
+
+    fn noise(&mut self, layer: usize, frame: &BrushStrokeFrame, mode: NoiseMode, scale: f32) {
+        let (from, to, symmetry) = self.frame_in_layer(layer, frame, false);
+        let bounds = self.state.layers[layer].bounds;
+        self.update_stroke_mask(layer);
+        let dirty = NoiseOperation {
+            pixels: &mut PixelSlice::new(
+                self.state.layers[layer].pixels_mut(),
+                bounds.width,
+                bounds.height,
+            ),
+            // laid out on the canvas, so the grain doesn't move if the layer grows
+            origin: (bounds.x, bounds.y),
+            brush: &frame.brush,
+            segment: StrokeSegment { from, to },
+            mode,
+            scale,
+            seed: self.stroke_seed,
+            stamp_cache: &mut self.stamp_cache,
+            stroke_state: &mut self.stroke_state,
+            mask: self.stroke_mask.as_ref().map(|(_, mask)| mask.as_slice()),
+            symmetry,
+        }
+        .process();
+        self.layers()[layer].mark_dirty_rect(dirty);
+    }
+
+    fn hue_shift(&mut self, layer: usize, frame: &BrushStrokeFrame, shift: HsvShift) {
+        let (from, to, symmetry) = self.frame_in_layer(layer, frame, false);
+        let bounds = self.state.layers[layer].bounds;
+        self.update_stroke_mask(layer);
+        let dirty = HueShiftOperation {
+            pixels: &mut PixelSlice::new(
+                self.state.layers[layer].pixels_mut(),
+                bounds.width,
+                bounds.height,
+            ),
+            brush: &frame.brush,
+            segment: StrokeSegment { from, to },
+            shift,
+            stroke_buffer: &mut self.stroke_buffer,
+            stamp_cache: &mut self.stamp_cache,
+            stroke_state: &mut self.stroke_state,
+            mask: self.stroke_mask.as_ref().map(|(_, mask)| mask.as_slice()),
+            symmetry,
+        }
+        .process();
+        self.layers()[layer].mark_dirty_rect(dirty);
+    }
+
+    /// `offset` is how far from each pixel it's copied from, in pixels.
+    fn clone_stamp(&mut self, layer: usize, frame: &BrushStrokeFrame, offset: (i32, i32)) {
+        let (from, to, symmetry) = self.frame_in_layer(layer, frame, true);
+        self.update_stroke_mask(layer);
+        let (width, height) = (self.state.width, self.state.height);
+        let target = &self.state.layers[layer];
+        // only what's on the canvas is copied, from before the stroke touched it
+        let source = self
+            .clone_source
+            .get_or_insert_with(|| target.canvas_pixels(width, height).into_owned());
+        let bounds = target.bounds;
+        let dirty = CloneOperation {
+            pixels: &mut PixelSlice::new(
+                self.state.layers[layer].pixels_mut(),
+                bounds.width,
+                bounds.height,
+            ),
+            source: &PixelSlice::new(source, width, height),
+            // from the layer's pixels to the canvas's
+            offset: (offset.0 + bounds.x, offset.1 + bounds.y),
+            brush: &frame.brush,
+            segment: StrokeSegment { from, to },
+            stamp_cache: &mut self.stamp_cache,
+            stroke_state: &mut self.stroke_state,
+            mask: self.stroke_mask.as_ref().map(|(_, mask)| mask.as_slice()),
+            symmetry,
+        }
+        .process();
+        self.layers()[layer].mark_dirty_rect(dirty);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::{Hash, Hasher};
+
+    use crate::filters::GaussianBlur;
+    use crate::testing::{canvas, fill_layer, hard_brush, paint_frames, stroke, stroke_through};
+
+    const RED: Color32 = Color32::from_rgb(200, 30, 30);
+
+    /// A 32x32 canvas whose one layer is red on the left half and clear on the right, so
+    /// anything that moves color across the middle changes the alpha there.
+    fn half_painted() -> Canvas {
+        let mut canvas = canvas(32, 32, 1);
+        fill_layer(&mut canvas, 0, RED);
+        let bounds = canvas.layers()[0].bounds();
+        let mut pixels = canvas.layers()[0].pixels().into_owned();
+        for row in pixels.chunks_exact_mut(32) {
+            row[16..].fill(Color32::TRANSPARENT);
+        }
+        canvas.restore_layer(
+            0,
+            LayerContents {
+                bounds,
+                pixels: LayerPixels::Color(Arc::new(pixels)),
+            },
+        );
+        canvas
+    }
+
+    fn alphas(canvas: &mut Canvas) -> Vec<u8> {
+        canvas.layers()[0].pixels().iter().map(|p| p.a()).collect()
+    }
+
+    type Edit<'a> = (&'static str, Box<dyn Fn(&mut Canvas) + 'a>);
+
+    /// Every kind of edit, each run on a fresh `canvas` that's set up by `setup`.
+    fn every_edit(setup: impl Fn(&mut Canvas), check: impl Fn(&str, &Canvas, &mut Canvas)) {
+        let across = [Pos2::new(8.0, 16.0), Pos2::new(24.0, 16.0)];
+        let edits: [Edit; 5] = [
+            (
+                "paint",
+                Box::new(|c| {
+                    let blue = Rgba::from_rgb(0.0, 0.0, 1.0);
+                    stroke(
+                        c,
+                        0,
+                        BrushStrokeKind::Paint,
+                        &hard_brush(6.0),
+                        blue,
+                        &across,
+                    )
+                }),
+            ),
+            (
+                "erase",
+                Box::new(|c| {
+                    let brush = hard_brush(6.0);
+                    stroke(c, 0, BrushStrokeKind::Erase, &brush, Rgba::WHITE, &across)
+                }),
+            ),
+            (
+                "smudge",
+                Box::new(|c| {
+                    let kind = BrushStrokeKind::Smudge {
+                        sample_merged: false,
+                        pickup_rate: 1.0,
+                    };
+                    let points: Vec<Pos2> = (0..8)
+                        .map(|i| Pos2::new(10.0 + 2.0 * i as f32, 16.0))
+                        .collect();
+                    stroke(c, 0, kind, &hard_brush(6.0), Rgba::WHITE, &points)
+                }),
+            ),
+            (
+                "fill",
+                Box::new(|c| {
+                    let options = FillOptions {
+                        tolerance: 255,
+                        contiguous: false,
+                        ..Default::default()
+                    };
+                    // a read-only document refuses it, which the checks see as nothing done
+                    let _ = c.fill(0, (20, 20), Rgba::from_rgb(0.0, 1.0, 0.0), &options);
+                }),
+            ),
+            (
+                "filter",
+                Box::new(|c| {
+                    c.apply_filter(0, &GaussianBlur { radius: 6.0 });
+                }),
+            ),
+        ];
+        for (name, edit) in edits {
+            let mut original = half_painted();
+            setup(&mut original);
+            let mut edited = half_painted();
+            setup(&mut edited);
+            edit(&mut edited);
+            check(name, &original, &mut edited);
+        }
+    }
+
+    #[test]
+    fn a_committed_preview_is_one_application_of_the_last_parameters() {
+        let mut expected = half_painted();
+        expected.apply_filter(0, &GaussianBlur { radius: 2.0 });
+
+        let mut previewed = half_painted();
+        previewed.begin_preview(vec![0]).unwrap();
+        for radius in [6.0, 4.0, 2.0] {
+            previewed.update_preview(|c, layer| c.apply_filter(layer, &GaussianBlur { radius }));
+        }
+        let committed = previewed.commit_preview();
+        assert_eq!(committed.len(), 1);
+        assert!(!committed[0].1.is_empty());
+        assert!(previewed.layers()[0].pixels() == expected.layers()[0].pixels());
+    }
+
+    #[test]
+    fn a_cancelled_preview_leaves_the_layer_as_it_was() {
+        let mut canvas = half_painted();
+        let original = canvas.layers()[0].pixels().into_owned();
+        canvas.begin_preview(vec![0]).unwrap();
+        canvas.update_preview(|c, layer| c.apply_filter(layer, &GaussianBlur { radius: 6.0 }));
+        assert!(*canvas.layers()[0].pixels() != original);
+        canvas.cancel_preview();
+        assert!(*canvas.layers()[0].pixels() == original);
+        assert!(canvas.commit_preview().is_empty());
+    }
+
+    #[test]
+    fn stats_are_counted_again_after_an_edit() {
+        let mut canvas = canvas(32, 32, 1);
+        let before = canvas.stats();
+        assert_eq!(before.layers[0].painted_pixels, 0);
+        assert_eq!(before.distinct_colors, ColorCount::Exact(0));
+
+        let blue = Rgba::from_rgb(0.0, 0.0, 1.0);
+        let points = [Pos2::new(8.0, 16.0), Pos2::new(24.0, 16.0)];
+        stroke(
+            &mut canvas,
+            0,
+            BrushStrokeKind::Paint,
+            &hard_brush(4.0),
+            blue,
+            &points,
+        );
+        let after = canvas.stats();
+        let layer = &after.layers[0];
+        assert!(layer.revision > before.layers[0].revision);
+        assert!(layer.painted_pixels > 0);
+        assert_eq!(layer.painted_bounds, LayerBounds::new(4, 12, 25, 9));
+        // the only visible layer is what an export would hold, edges and all
+        assert_eq!(after.distinct_colors, layer.distinct_colors);
+        assert!(!after.distinct_colors.within(0));
+    }
+
+    #[test]
+    fn a_noise_stroke_replays_with_the_same_grain() {
+        let kind = BrushStrokeKind::Noise {
+            mode: NoiseMode::Color,
+            scale: 3.0,
+        };
+        let brush = Brush::default().with_radius(6.0);
+        let points = [Pos2::new(4.0, 16.0), Pos2::new(28.0, 16.0)];
+        let replay = || {
+            let mut canvas = half_painted();
+            stroke(&mut canvas, 0, kind.clone(), &brush, Rgba::WHITE, &points);
+            canvas.layers()[0].pixels().into_owned()
+        };
+        let first = replay();
+        assert!(first != *half_painted().layers()[0].pixels());
+        assert!(first == replay());
+    }
+
+    /// A 32x12 canvas with one color layer and a stencil over it whose alpha goes up by 8
+    /// with each column, from nothing on the left.
+    fn with_gradient_stencil() -> (Canvas, usize) {
+        let mut canvas = canvas(32, 12, 1);
+        let stencil = canvas.add_stencil_layer().unwrap();
+        let bounds = canvas.layers()[stencil].bounds();
+        let alpha = (0..32 * 12).map(|i| (i % 32 * 8) as u8).collect();
+        canvas.restore_layer(
+            stencil,
+            LayerContents {
+                bounds,
+                pixels: LayerPixels::Alpha(Arc::new(alpha)),
+            },
+        );
+        (canvas, stencil)
+    }
+
+    /// The alphas along the middle row of the first layer, which a radius 5 stroke along it
+    /// covers completely.
+    fn middle_row(canvas: &mut Canvas) -> Vec<u8> {
+        let row = &canvas.layers()[0].pixels()[6 * 32..7 * 32];
+        row.iter().map(|p| p.a()).collect()
+    }
+
+    const ALONG_THE_MIDDLE: [Pos2; 2] = [Pos2::new(-8.0, 6.0), Pos2::new(40.0, 6.0)];
+
+    #[test]
+    fn painting_through_a_stencil_lays_down_as_much_as_it_lets_through() {
+        let (mut canvas, stencil) = with_gradient_stencil();
+        let red = Rgba::from_rgb(1.0, 0.0, 0.0);
+        let kind = BrushStrokeKind::Paint;
+        let brush = hard_brush(5.0);
+        stroke_through(
+            &mut canvas,
+            0,
+            Some(stencil),
+            kind,
+            &brush,
+            red,
+            &ALONG_THE_MIDDLE,
+        );
+        for (x, alpha) in middle_row(&mut canvas).into_iter().enumerate() {
+            let expected = x as i32 * 8;
+            assert!((alpha as i32 - expected).abs() <= 1, "{alpha} at x={x}");
+        }
+        // the stencil itself isn't painted or masked by it
+        assert_eq!(canvas.layers()[stencil].pixel_at(31, 6).a(), 248);
+    }
+
+    #[test]
+    fn stencils_keep_just_their_alpha_once_an_edit_is_done() {
+        let (mut canvas, stencil) = with_gradient_stencil();
+        let bytes = |canvas: &mut Canvas| canvas.layers()[stencil].stats().bytes;
+        assert_eq!(bytes(&mut canvas), 32 * 12);
+        let before = canvas.snapshot_layer(stencil).unwrap();
+
+        // kept as colors while the stroke's being painted, so its frames don't unpack it
+        let (kind, brush, red) = (BrushStrokeKind::Paint, hard_brush(5.0), RED.into());
+        canvas.begin_brush_stroke(Symmetry::None, None, 0, None);
+        paint_frames(&mut canvas, stencil, kind, &brush, red, &ALONG_THE_MIDDLE);
+        assert_eq!(bytes(&mut canvas), 32 * 12 * 4);
+        canvas.end_brush_stroke();
+        assert_eq!(bytes(&mut canvas), 32 * 12);
+        // painted white whatever the color, so only the alpha was ever there to keep
+        assert_eq!(canvas.layers()[stencil].pixel_at(2, 6), Color32::WHITE);
+        assert_eq!(canvas.layers()[stencil].pixel_at(2, 0).a(), 16);
+
+        canvas.clear_layer(stencil);
+        assert_eq!(bytes(&mut canvas), 32 * 12);
+        assert!(canvas.layers()[stencil].alpha().iter().all(|&a| a == 0));
+        canvas.restore_layer(stencil, before.clone());
+        assert_eq!(bytes(&mut canvas), 32 * 12);
+        assert!(canvas.layers()[stencil].alpha() == before.pixels.alpha());
+    }
+
+    #[test]
+    fn erasing_through_a_stencil_takes_away_as_much_as_it_lets_through() {
+        let (mut canvas, stencil) = with_gradient_stencil();
+        fill_layer(&mut canvas, 0, RED);
+        let (kind, brush) = (BrushStrokeKind::Erase, hard_brush(5.0));
+        let white = Rgba::WHITE;
+        stroke_through(
+            &mut canvas,
+            0,
+            Some(stencil),
+            kind,
+            &brush,
+            white,
+            &ALONG_THE_MIDDLE,
+        );
+        for (x, alpha) in middle_row(&mut canvas).into_iter().enumerate() {
+            let expected = 255 - x as i32 * 8;
+            assert!((alpha as i32 - expected).abs() <= 1, "{alpha} at x={x}");
+        }
+    }
+
+    #[test]
+    fn without_the_stencil_painting_lays_down_everything() {
+        let (mut canvas, _) = with_gradient_stencil();
+        let red = Rgba::from_rgb(1.0, 0.0, 0.0);
+        let kind = BrushStrokeKind::Paint;
+        stroke(
+            &mut canvas,
+            0,
+            kind,
+            &hard_brush(5.0),
+            red,
+            &ALONG_THE_MIDDLE,
+        );
+        assert!(middle_row(&mut canvas).iter().all(|&alpha| alpha == 255));
+
+        // nor does a color layer named as the stencil mask anything
+        let (mut canvas, _) = with_gradient_stencil();
+        let kind = BrushStrokeKind::Paint;
+        let brush = hard_brush(5.0);
+        let color = canvas.add_layer().unwrap();
+        stroke_through(
+            &mut canvas,
+            0,
+            Some(color),
+            kind,
+            &brush,
+            red,
+            &ALONG_THE_MIDDLE,
+        );
+        assert!(middle_row(&mut canvas).iter().all(|&alpha| alpha == 255));
+    }
+
+    fn is_bad_size(result: Result<impl Sized, CanvasError>) -> bool {
+        matches!(result, Err(CanvasError::BadSize { .. }))
+    }
+
+    #[test]
+    fn sizes_outside_the_limits_are_refused() {
+        let limits = CanvasLimits {
+            min_side: 2,
+            max_side: 100,
+            max_pixels: 50 * 50,
+        };
+        assert!(limits.check(2, 100).is_ok());
+        assert!(limits.check(50, 50).is_ok());
+        // a negative size wraps around to a huge one
+        let negative = -5i32 as u32;
+        for (width, height) in [(0, 10), (10, 1), (101, 2), (51, 50), (negative, 10)] {
+            assert!(
+                is_bad_size(limits.check(width, height)),
+                "{width} x {height}"
+            );
+        }
+    }
+
+    #[test]
+    fn new_canvases_and_layers_check_their_size_first() {
+        let negative = -1i32 as u32;
+        for (width, height) in [(0, 16), (16, 0), (negative, 16), (100_000, 100_000)] {
+            assert!(is_bad_size(CanvasLayer::new(width, height, "Layer".into())));
+            let state = CanvasState {
+                layers: Vec::new(),
+                width,
+                height,
+            };
+            assert!(is_bad_size(Canvas::new(state)));
+        }
+
+        let limits = CanvasLimits {
+            max_side: 16,
+            ..CanvasLimits::default()
+        };
+        let state = |layer_width| CanvasState {
+            layers: vec![CanvasLayer::new(layer_width, 16, "Layer".into()).unwrap()],
+            width: 16,
+            height: 16,
+        };
+        assert!(Canvas::with_limits(state(16), limits).is_ok());
+        assert!(is_bad_size(Canvas::with_limits(state(17), limits)));
+    }
+
+    #[test]
+    fn resizing_and_adding_layers_keep_within_the_limits() {
+        let limits = CanvasLimits {
+            max_side: 64,
+            ..CanvasLimits::default()
+        };
+        let state = CanvasState {
+            layers: vec![CanvasLayer::new(32, 32, "Layer".into()).unwrap()],
+            width: 32,
+            height: 32,
+        };
+        let mut canvas = Canvas::with_limits(state, limits).unwrap();
+
+        assert!(is_bad_size(canvas.resize(LayerBounds::new(-40, 0, 72, 32))));
+        assert!(is_bad_size(canvas.resize(LayerBounds::new(
+            0,
+            0,
+            -3i32 as u32,
+            32
+        ))));
+        assert_eq!((canvas.state.width, canvas.state.height), (32, 32));
+        canvas.resize(LayerBounds::new(-32, 0, 64, 32)).unwrap();
+        assert_eq!((canvas.state.width, canvas.state.height), (64, 32));
+
+        let too_wide = LayerBounds::new(0, 0, 65, 8);
+        assert!(is_bad_size(canvas.add_layer_at("Wide".into(), too_wide)));
+        let empty = LayerBounds::new(0, 0, 0, 8);
+        assert!(is_bad_size(canvas.add_layer_at("Empty".into(), empty)));
+        assert_eq!(canvas.layers().len(), 1);
+    }
+
+    #[test]
+    fn a_layer_stops_growing_at_the_limits() {
+        let limits = CanvasLimits {
+            max_side: 32,
+            ..CanvasLimits::default()
+        };
+        let mut canvas = Canvas::with_limits(
+            CanvasState {
+                layers: Vec::new(),
+                width: 32,
+                height: 32,
+            },
+            limits,
+        )
+        .unwrap();
+        // hanging off the left, so taking in the right edge would make it 52 wide
+        let bounds = LayerBounds::new(-20, 0, 24, 8);
+        let layer = canvas.add_layer_at("Small".into(), bounds).unwrap();
+        let points = [Pos2::new(28.0, 4.0), Pos2::new(30.0, 4.0)];
+        stroke(
+            &mut canvas,
+            layer,
+            BrushStrokeKind::Paint,
+            &hard_brush(2.0),
+            Rgba::WHITE,
+            &points,
+        );
+        assert_eq!(canvas.layers()[layer].bounds(), bounds);
+
+        // one that can grow does
+        let bounds = LayerBounds::new(0, 0, 8, 8);
+        let layer = canvas.add_layer_at("Grows".into(), bounds).unwrap();
+        stroke(
+            &mut canvas,
+            layer,
+            BrushStrokeKind::Paint,
+            &hard_brush(2.0),
+            Rgba::WHITE,
+            &points,
+        );
+        assert!(canvas.layers()[layer].bounds().width > 8);
+    }
+
+    #[test]
+    fn locked_pixels_stop_every_edit() {
+        every_edit(
+            |c| c.layers()[0].lock_pixels = true,
+            |name, original, edited| {
+                let original = original.state.layers[0].pixels();
+                assert!(
+                    edited.layers()[0].pixels() == original,
+                    "{name} edited the layer"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn locked_alpha_survives_every_edit() {
+        every_edit(
+            |c| c.layers()[0].lock_alpha = true,
+            |name, original, edited| {
+                let original: Vec<u8> = original.state.layers[0]
+                    .pixels()
+                    .iter()
+                    .map(|p| p.a())
+                    .collect();
+                assert_eq!(alphas(edited), original, "{name} changed the alpha");
+            },
+        );
+    }
+
+    #[test]
+    fn every_edit_changes_an_unlocked_layer() {
+        every_edit(
+            |_| {},
+            |name, original, edited| {
+                let original = original.state.layers[0].pixels();
+                assert!(
+                    edited.layers()[0].pixels() != original,
+                    "{name} did nothing"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn locked_alpha_holds_on_a_layer_smaller_than_the_canvas() {
+        // the smudge picks up red and drags it past the layer's edge, which an alpha locked
+        // layer doesn't grow over
+        let mut canvas = canvas(32, 32, 0);
+        let bounds = LayerBounds::new(0, 0, 16, 32);
+        let index = canvas.add_layer_at("Small".to_string(), bounds).unwrap();
+        fill_layer(&mut canvas, index, RED);
+        canvas.layers()[index].lock_alpha = true;
+        let kind = BrushStrokeKind::Smudge {
+            sample_merged: false,
+            pickup_rate: 1.0,
+        };
+        let points: Vec<Pos2> = (0..10)
+            .map(|i| Pos2::new(8.0 + 2.0 * i as f32, 16.0))
+            .collect();
+        stroke(
+            &mut canvas,
+            index,
+            kind,
+            &hard_brush(6.0),
+            Rgba::WHITE,
+            &points,
+        );
+        let layer = &canvas.layers()[index];
+        for y in 0..32 {
+            for x in 0..32 {
+                let expected = if x < 16 { 255 } else { 0 };
+                assert_eq!(layer.pixel_at(x, y).a(), expected, "at {x}, {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn a_stroke_across_an_offset_layers_edge_paints_on_both_sides_or_stops_at_it() {
+        let mut canvas = canvas(32, 32, 0);
+        let bounds = LayerBounds::new(8, 8, 8, 8);
+        let grows = canvas.add_layer_at("Grows".into(), bounds).unwrap();
+        fill_layer(&mut canvas, grows, RED);
+        let clipped = canvas.add_layer_at("Clipped".into(), bounds).unwrap();
+        canvas.layers()[clipped].auto_grow = false;
+        let points = [Pos2::new(4.0, 12.0), Pos2::new(24.0, 12.0)];
+        for layer in [grows, clipped] {
+            stroke(
+                &mut canvas,
+                layer,
+                BrushStrokeKind::Paint,
+                &hard_brush(2.0),
+                Rgba::WHITE,
+                &points,
+            );
+        }
+
+        // the stroke is taken in whole, and what was there stays where it was on the canvas
+        let layer = &canvas.layers()[grows];
+        assert!(layer.bounds().x < 5 && layer.bounds().right() > 24);
+        for x in 5..24 {
+            assert_eq!(layer.pixel_at(x, 12), Color32::WHITE, "at {x}");
+        }
+        for (x, y) in [(8, 8), (15, 8), (8, 15), (15, 15)] {
+            assert_eq!(layer.pixel_at(x, y), RED, "at {x}, {y}");
+        }
+
+        // or it's painted up to the edge and no further
+        let layer = &canvas.layers()[clipped];
+        assert_eq!(layer.bounds(), bounds);
+        for x in 0..32 {
+            let expected = if (8..16).contains(&x) {
+                Color32::WHITE
+            } else {
+                Color32::TRANSPARENT
+            };
+            assert_eq!(layer.pixel_at(x, 12), expected, "at {x}");
+        }
+    }
+
+    #[test]
+    fn offset_layers_hanging_off_the_canvas_composite_where_they_sit() {
+        let mut canvas = canvas(16, 16, 0);
+        let corner = LayerBounds::new(12, -2, 6, 6);
+        let corner = canvas.add_layer_at("Corner".into(), corner).unwrap();
+        fill_layer(&mut canvas, corner, RED);
+        let edge = LayerBounds::new(-3, 13, 5, 5);
+        let edge = canvas.add_layer_at("Edge".into(), edge).unwrap();
+        fill_layer(&mut canvas, edge, Color32::WHITE);
+
+        let composite = canvas.composite_rect(LayerBounds::canvas(16, 16));
+        for y in 0..16 {
+            for x in 0..16 {
+                let expected = if x >= 12 && y < 4 {
+                    RED
+                } else if x < 2 && y >= 13 {
+                    Color32::WHITE
+                } else {
+                    Color32::TRANSPARENT
+                };
+                assert_eq!(composite[(y * 16 + x) as usize], expected, "at {x}, {y}");
+            }
+        }
+        assert_eq!(canvas.snapshot().merged(), composite);
+
+        // a rect reaching past the canvas is cut down to it, like the layers
+        let rect = LayerBounds::new(10, -4, 10, 10);
+        let part = canvas.composite_rect(rect);
+        assert_eq!(part.len(), 6 * 6);
+        let expected: Vec<Color32> = (0..6)
+            .flat_map(|y| &composite[y * 16 + 10..y * 16 + 16])
+            .copied()
+            .collect();
+        assert_eq!(part, expected);
+        // and only the part of a repainted layer on the canvas is left to upload
+        for layer in canvas.layers() {
+            layer.mark_clean();
+        }
+        fill_layer(&mut canvas, corner, Color32::WHITE);
+        assert_eq!(canvas.composite_dirty_rect(), LayerBounds::new(12, 0, 4, 4));
+    }
+
+    #[test]
+    fn selecting_soft_paint_selects_its_edge_partly_or_cuts_it_at_the_threshold() {
+        let mut canvas = canvas(32, 32, 0);
+        let layer = canvas
+            .add_layer_at("Small".into(), LayerBounds::new(4, 4, 24, 24))
+            .unwrap();
+        let soft = Brush::default()
+            .with_radius(8.0)
+            .with_hardness(0.0)
+            .with_opacity(1.0);
+        let at = [Pos2::new(16.0, 16.0)];
+        stroke(
+            &mut canvas,
+            layer,
+            BrushStrokeKind::Paint,
+            &soft,
+            Rgba::WHITE,
+            &at,
+        );
+
+        let mask = canvas.select_opaque(layer, None).unwrap();
+        assert_eq!((mask.width(), mask.height()), (32, 32));
+        let painted = &canvas.layers()[layer];
+        let mut partial = 0;
+        for y in 0..32 {
+            for x in 0..32 {
+                let alpha = painted.pixel_at(x, y).a();
+                assert_eq!(mask.value(x as u32, y as u32), alpha, "at {x}, {y}");
+                partial += (0 < alpha && alpha < 255) as usize;
+            }
+        }
+        // fading out from the middle, with no step down to nothing at the rim
+        assert!(partial > 100, "only {partial} partly selected");
+        let row: Vec<u8> = (16..32).map(|x| mask.value(x, 16)).collect();
+        assert!(row.windows(2).all(|w| w[1] <= w[0]), "{row:?}");
+        assert!(row[0] > 200 && row[7] < 60 && row[9] == 0, "{row:?}");
+
+        let cut = canvas.select_opaque(layer, Some(128)).unwrap();
+        for y in 0..32 {
+            for x in 0..32 {
+                let alpha = mask.value(x, y);
+                let expected = if alpha >= 128 { 255 } else { 0 };
+                assert_eq!(cut.value(x, y), expected, "at {x}, {y}");
+            }
+        }
+        assert!(cut.bounds().width < mask.bounds().width);
+    }
+
+    #[test]
+    fn a_snapshot_keeps_what_was_there_and_shares_what_was_not_painted() {
+        let mut canvas = canvas(32, 16, 3);
+        fill_layer(&mut canvas, 0, RED);
+        let snapshot = canvas.snapshot();
+        let merged = snapshot.merged();
+        let before: Vec<Vec<Color32>> = canvas
+            .state
+            .layers
+            .iter()
+            .map(|l| l.pixels().into_owned())
+            .collect();
+        // nothing is copied until something's painted
+        for (layer, snapshotted) in canvas.state.layers.iter().zip(&snapshot.layers) {
+            assert!(layer.pixels.ptr_eq(&snapshotted.pixels));
+        }
+
+        let points = [Pos2::new(4.0, 8.0), Pos2::new(28.0, 8.0)];
+        let blue = Rgba::from_rgb(0.0, 0.0, 1.0);
+        stroke(
+            &mut canvas,
+            1,
+            BrushStrokeKind::Paint,
+            &hard_brush(3.0),
+            blue,
+            &points,
+        );
+        assert!(canvas.state.layers[1].pixels() != before[1]);
+
+        // the painted layer was copied before it was painted, the others are still shared
+        let layers = canvas.state.layers.iter().zip(&snapshot.layers);
+        for (index, (layer, snapshotted)) in layers.enumerate() {
+            assert_eq!(
+                layer.pixels.ptr_eq(&snapshotted.pixels),
+                index != 1,
+                "{index}"
+            );
+            assert!(snapshotted.pixels.colors() == before[index], "{index}");
+        }
+        assert!(snapshot.merged() == merged);
+        assert!(canvas.snapshot().merged() != merged);
+    }
+
+    #[test]
+    fn an_airbrush_held_still_lays_down_the_same_paint_at_any_frame_rate() {
+        let brush = Brush::default()
+            .with_radius(6.0)
+            .with_flow_per_second(Some(8.0));
+        let faint_red = alpha::brush_color_from_srgba([255, 0, 0, 24]);
+        let at = Pos2::new(16.0, 16.0);
+        // held for half a second
+        let held = |fps: u32| {
+            let mut canvas = canvas(32, 32, 1);
+            canvas.begin_brush_stroke(Symmetry::None, None, 0, None);
+            for i in 0..=fps / 2 {
+                let frame = BrushStrokeFrame {
+                    brush: brush.clone(),
+                    color: faint_red,
+                    cursor_position: at,
+                    last_cursor_position: at,
+                    previous_cursor_position: None,
+                    timestamp: Instant::now(),
+                    elapsed: if i == 0 { 0.0 } else { 1.0 / fps as f32 },
+                };
+                canvas
+                    .process_brush_stroke_frame(0, BrushStrokeKind::Paint, &frame)
+                    .unwrap();
+            }
+            alphas(&mut canvas)
+        };
+
+        let expected = held(30);
+        let center = expected[16 * 32 + 16];
+        // built up well past a single dab, but not to full
+        assert!((48..200).contains(&center), "{center}");
+        for fps in [60, 120, 144] {
+            let alphas = held(fps);
+            for (index, (&a, &b)) in alphas.iter().zip(&expected).enumerate() {
+                let (x, y) = (index % 32, index / 32);
+                assert!(a.abs_diff(b) <= 2, "{fps} fps: {a} at {x}, {y} against {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn smudging_all_layers_drags_color_from_below_onto_the_empty_layer() {
+        let smudged = |sample_merged| {
+            let mut canvas = canvas(32, 16, 2);
+            let mut pixels = vec![Color32::TRANSPARENT; 32 * 16];
+            for row in pixels.chunks_exact_mut(32) {
+                row[..12].fill(RED);
+            }
+            let bounds = LayerBounds::canvas(32, 16);
+            let pixels = LayerPixels::Color(Arc::new(pixels));
+            let below = LayerContents { bounds, pixels };
+            canvas.restore_layer(0, below.clone());
+            let kind = BrushStrokeKind::Smudge {
+                sample_merged,
+                pickup_rate: 1.0,
+            };
+            let points: Vec<Pos2> = (0..8)
+                .map(|i| Pos2::new(8.0 + 2.0 * i as f32, 8.0))
+                .collect();
+            stroke(&mut canvas, 1, kind, &hard_brush(4.0), Rgba::WHITE, &points);
+            assert!(canvas.layers()[0].pixels() == below.pixels.colors());
+            canvas
+        };
+
+        let canvas = smudged(true);
+        let dragged = canvas.state.layers[1].pixel_at(18, 8);
+        assert!(dragged.a() > 64, "{dragged:?}");
+        assert!(dragged.r() > 0 && dragged.g() == dragged.b(), "{dragged:?}");
+        // past where the smudge reached
+        assert_eq!(canvas.state.layers[1].pixel_at(30, 8), Color32::TRANSPARENT);
+        // with only its own layer to pick up from, there's nothing to drag
+        let canvas = smudged(false);
+        let layer = &canvas.state.layers[1];
+        assert!(layer.pixels().iter().all(|&p| p == Color32::TRANSPARENT));
+    }
+
+    #[test]
+    fn a_symmetric_stroke_paints_its_mirror_image() {
+        let mut canvas = canvas(32, 16, 1);
+        canvas.begin_brush_stroke(Symmetry::Horizontal { axis_x: 16.0 }, None, 0, None);
+        let points = [Pos2::new(4.0, 8.0), Pos2::new(10.0, 8.0)];
+        let white = Rgba::WHITE;
+        paint_frames(
+            &mut canvas,
+            0,
+            BrushStrokeKind::Paint,
+            &hard_brush(3.0),
+            white,
+            &points,
+        );
+        let layer = &canvas.state.layers[0];
+        // dabs land on whole pixels, so the image can be a pixel out, but no more
+        let painted = |xs: std::ops::Range<i32>| {
+            let pixels = xs.flat_map(|x| (0..16).map(move |y| (x, y)));
+            pixels
+                .map(|(x, y)| layer.pixel_at(x, y).a() as u32)
+                .sum::<u32>()
+        };
+        let (left, right) = (painted(0..16), painted(16..32));
+        assert!(
+            left.abs_diff(right) * 10 < left,
+            "{left} on the left, {right} on the right"
+        );
+        for x in [4, 7, 10] {
+            assert_eq!(layer.pixel_at(x, 8).a(), 255);
+            assert_eq!(layer.pixel_at(31 - x, 8).a(), 255);
+        }
+        assert_eq!(layer.pixel_at(16, 8).a(), 0);
+    }
+
+    #[test]
+    fn clearing_puts_the_canvas_back_as_it_was_created() {
+        let mut canvas = canvas(32, 32, 1);
+        let white = Rgba::WHITE;
+        let points = [Pos2::new(8.0, 8.0), Pos2::new(24.0, 24.0)];
+        stroke(
+            &mut canvas,
+            0,
+            BrushStrokeKind::Paint,
+            &hard_brush(4.0),
+            white,
+            &points,
+        );
+        canvas.resize(LayerBounds::new(-8, -8, 48, 40)).unwrap();
+        let small = LayerBounds::new(4, 4, 8, 8);
+        let added = canvas.add_layer_at("Small".into(), small).unwrap();
+        fill_layer(&mut canvas, added, RED);
+        canvas.set_selection(canvas.select_opaque(added, None));
+
+        canvas.clear();
+        assert_eq!((canvas.state.width, canvas.state.height), (32, 32));
+        assert_eq!(canvas.to_document(Pos2::ZERO), Pos2::ZERO);
+        assert!(canvas.selection().is_none());
+        let layers = &canvas.state.layers;
+        assert_eq!(layers[0].bounds(), LayerBounds::canvas(32, 32));
+        // the added layer goes back to where it was added in the document
+        assert_eq!(layers[added].bounds(), small.translated(-8, -8));
+        for layer in layers {
+            assert!(layer.pixels().iter().all(|&p| p == Color32::TRANSPARENT));
+        }
+    }
+
+    #[test]
+    fn clearing_a_layer_leaves_the_others_and_keeps_to_the_lock() {
+        let mut canvas = canvas(8, 8, 2);
+        fill_layer(&mut canvas, 0, RED);
+        fill_layer(&mut canvas, 1, RED);
+        canvas.clear_layer(1);
+        assert!(canvas.layers()[0].pixels().iter().all(|&p| p == RED));
+        let cleared = canvas.layers()[1].pixels();
+        assert!(cleared.iter().all(|&p| p == Color32::TRANSPARENT));
+
+        fill_layer(&mut canvas, 1, RED);
+        canvas.layers()[1].lock_pixels = true;
+        canvas.clear_layer(1);
+        assert!(canvas.layers()[1].pixels().iter().all(|&p| p == RED));
+    }
+
+    /// A hash of everything a document saves: its size and each layer's name, kind, place
+    /// and pixels.
+    fn document_hash(canvas: &Canvas) -> u64 {
+        let mut hasher = std::hash::DefaultHasher::new();
+        (canvas.state.width, canvas.state.height).hash(&mut hasher);
+        for layer in &canvas.state.layers {
+            let bounds = layer.bounds();
+            (&layer.name, layer.kind() == LayerKind::Stencil).hash(&mut hasher);
+            (bounds.x, bounds.y, bounds.width, bounds.height).hash(&mut hasher);
+            bytemuck::cast_slice::<Color32, u8>(&layer.pixels()).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn a_read_only_document_refuses_every_edit() {
+        every_edit(
+            |canvas| canvas.set_read_only(true),
+            |name, original, edited| {
+                let hash = document_hash(original);
+                assert_eq!(document_hash(edited), hash, "{name} edited the document");
+            },
+        );
+
+        let mut canvas = half_painted();
+        canvas.add_layer().unwrap();
+        canvas.set_read_only(true);
+        let hash = document_hash(&canvas);
+        assert!(matches!(canvas.add_layer(), Err(CanvasError::ReadOnly)));
+        assert!(canvas.remove_layer(1).is_none());
+        // reordering is taking a layer out and putting it back elsewhere
+        let layer = CanvasLayer::new(32, 32, "Moved".into()).unwrap();
+        assert!(matches!(
+            canvas.insert_layer(0, layer),
+            Err(CanvasError::ReadOnly)
+        ));
+        let rect = LayerBounds::new(4, 4, 16, 16);
+        assert!(matches!(canvas.resize(rect), Err(CanvasError::ReadOnly)));
+        assert_eq!(document_hash(&canvas), hash);
+    }
+
+    #[test]
+    fn added_layers_go_on_top_covering_the_canvas() {
+        let mut canvas = canvas(16, 8, 1);
+        assert_eq!(canvas.add_layer().unwrap(), 1);
+        assert_eq!(canvas.add_layer().unwrap(), 2);
+        let layer = &canvas.state.layers[2];
+        assert_eq!(layer.name, "Layer 3");
+        assert_eq!(layer.bounds(), LayerBounds::canvas(16, 8));
+        assert_eq!(layer.kind(), LayerKind::Color);
+        assert!(layer.is_dirty());
+
+        canvas.set_read_only(true);
+        assert!(matches!(canvas.add_layer(), Err(CanvasError::ReadOnly)));
+        assert_eq!(canvas.state.layers.len(), 3);
+    }
+
+    #[test]
+    fn a_copy_pastes_back_as_it_was() {
+        let mut canvas = canvas(16, 16, 2);
+        let half_red = alpha::brush_color_from_srgba([255, 0, 0, 128]);
+        let points = [Pos2::new(4.0, 8.0), Pos2::new(12.0, 8.0)];
+        let brush = hard_brush(3.0);
+        stroke(
+            &mut canvas,
+            0,
+            BrushStrokeKind::Paint,
+            &brush,
+            half_red,
+            &points,
+        );
+
+        let (image, offset) = canvas.copy(0).unwrap();
+        assert_eq!(offset, (1, 5));
+        canvas.paste(1, &image, offset);
+        let (copied, pasted) = (&canvas.state.layers[0], &canvas.state.layers[1]);
+        for (before, after) in copied.pixels().iter().zip(pasted.pixels().iter()) {
+            for c in 0..4 {
+                assert!(
+                    before[c].abs_diff(after[c]) <= 1,
+                    "{before:?} came back as {after:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_saved_png_holds_the_merged_layers_with_straight_alpha() {
+        let mut canvas = canvas(16, 16, 2);
+        fill_layer(&mut canvas, 0, RED);
+        let half_white = alpha::brush_color_from_srgba([255, 255, 255, 128]);
+        let points = [Pos2::new(0.0, 8.0), Pos2::new(16.0, 8.0)];
+        let brush = hard_brush(3.0);
+        stroke(
+            &mut canvas,
+            1,
+            BrushStrokeKind::Paint,
+            &brush,
+            half_white,
+            &points,
+        );
+        canvas.layers()[1].opacity = 0.5;
+        let path =
+            std::env::temp_dir().join(format!("rustbrush-canvas-{}.png", std::process::id()));
+        canvas
+            .save_as_png(&path, &[("Software", "rustbrush".into())])
+            .unwrap();
+        let file = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let decoder = png::Decoder::new(std::io::Cursor::new(file.unwrap()));
+        let mut reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (16, 16));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        assert!(info.srgb.is_some());
+        let software = info
+            .utf8_text
+            .iter()
+            .find(|chunk| chunk.keyword == "Software");
+        assert_eq!(software.unwrap().get_text().unwrap(), "rustbrush");
+
+        let mut saved = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut saved).unwrap();
+        let merged = canvas.snapshot().merged();
+        assert_eq!(saved, alpha::unpremultiply_rgba8(&merged));
+        // the stroke shows over the red, faded by the layer's opacity
+        assert!(merged[8 * 16 + 8] != RED && merged[0] == RED);
+    }
+}
@@ -0,0 +1,200 @@
+/// The color spaces images can be converted from on import. Layers are always sRGB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorProfile {
+    Srgb,
+    DisplayP3,
+}
+
+/// Linear Display P3 to linear sRGB. Both are D65, so this is just a change of primaries.
+const DISPLAY_P3_TO_SRGB: [[f32; 3]; 3] = [
+    [1.224_940_2, -0.224_940_4, 0.0],
+    [-0.042_056_955, 1.042_057_1, 0.0],
+    [-0.019_637_555, -0.078_636_05, 1.098_273_6],
+];
+
+/// The red, green and blue colorants (D50-adapted XYZ) ICC profiles use for each space.
+const SRGB_COLORANTS: [[f32; 3]; 3] = [
+    [0.4361, 0.2225, 0.0139],
+    [0.3851, 0.7169, 0.0971],
+    [0.1431, 0.0606, 0.7141],
+];
+const DISPLAY_P3_COLORANTS: [[f32; 3]; 3] = [
+    [0.5151, 0.2412, -0.0011],
+    [0.2920, 0.6922, 0.0419],
+    [0.1571, 0.0666, 0.7841],
+];
+const COLORANT_TOLERANCE: f32 = 0.005;
+
+impl ColorProfile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorProfile::Srgb => "sRGB",
+            ColorProfile::DisplayP3 => "Display P3",
+        }
+    }
+
+    /// Identifies an embedded ICC profile by its colorant tags. Both supported spaces use the
+    /// sRGB transfer curve, so the primaries are all that tell them apart. Returns `None`
+    /// for anything else, including malformed profiles.
+    pub fn from_icc(icc: &[u8]) -> Option<Self> {
+        let colorants = [b"rXYZ", b"gXYZ", b"bXYZ"].map(|tag| icc_xyz_tag(icc, tag));
+        let matches = |reference: &[[f32; 3]; 3]| {
+            colorants
+                .iter()
+                .zip(reference)
+                .all(|(colorant, reference)| {
+                    colorant.is_some_and(|colorant| {
+                        colorant
+                            .iter()
+                            .zip(reference)
+                            .all(|(a, b)| (a - b).abs() <= COLORANT_TOLERANCE)
+                    })
+                })
+        };
+
+        if matches(&SRGB_COLORANTS) {
+            Some(ColorProfile::Srgb)
+        } else if matches(&DISPLAY_P3_COLORANTS) {
+            Some(ColorProfile::DisplayP3)
+        } else {
+            None
+        }
+    }
+
+    /// Converts straight (unmultiplied) RGBA8 pixels in this space to sRGB in place. Colors
+    /// outside the sRGB gamut are clipped.
+    pub fn convert_to_srgb(&self, rgba: &mut [u8]) {
+        let matrix = match self {
+            ColorProfile::Srgb => return,
+            ColorProfile::DisplayP3 => &DISPLAY_P3_TO_SRGB,
+        };
+
+        for pixel in rgba.chunks_exact_mut(4) {
+            let linear = [pixel[0], pixel[1], pixel[2]].map(|c| srgb_to_linear(c as f32 / 255.0));
+            for (channel, row) in pixel.iter_mut().zip(matrix) {
+                let value = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+                *channel = (linear_to_srgb(value.clamp(0.0, 1.0)) * 255.0).round() as u8;
+            }
+        }
+    }
+}
+
+/// The sRGB transfer curve, from encoded (0..=1) to linear.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The inverse of [`srgb_to_linear`].
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Reads an `XYZ ` type tag from an ICC profile.
+fn icc_xyz_tag(icc: &[u8], signature: &[u8; 4]) -> Option<[f32; 3]> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(
+            icc.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    // the tag table follows the 128 byte header
+    let tag_count = read_u32(128)? as usize;
+    let offset = (0..tag_count).find_map(|i| {
+        let entry = 132 + i * 12;
+        (icc.get(entry..entry + 4)? == signature).then(|| read_u32(entry + 4))?
+    })? as usize;
+
+    if icc.get(offset..offset + 4)? != b"XYZ " {
+        return None;
+    }
+    let mut xyz = [0.0; 3];
+    for (i, value) in xyz.iter_mut().enumerate() {
+        // s15Fixed16Number
+        *value = read_u32(offset + 8 + i * 4)? as i32 as f32 / 65536.0;
+    }
+    Some(xyz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A profile holding nothing but the three colorant tags, as [`ColorProfile::from_icc`]
+    /// reads them.
+    fn icc(colorants: &[[f32; 3]; 3]) -> Vec<u8> {
+        let mut icc = vec![0; 128];
+        icc.extend(3u32.to_be_bytes());
+        let data = 132 + 3 * 12;
+        for (i, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].into_iter().enumerate() {
+            icc.extend(signature);
+            icc.extend(((data + i * 20) as u32).to_be_bytes());
+            icc.extend(20u32.to_be_bytes());
+        }
+        for colorant in colorants {
+            icc.extend(b"XYZ \0\0\0\0");
+            for value in colorant {
+                icc.extend(((value * 65536.0).round() as i32).to_be_bytes());
+            }
+        }
+        icc
+    }
+
+    #[test]
+    fn transfer_curve_matches_reference_values() {
+        for (encoded, linear) in [
+            (0.0, 0.0),
+            (0.04045, 0.003_130_8),
+            (0.5, 0.214_041),
+            (1.0, 1.0),
+        ] {
+            assert!((srgb_to_linear(encoded) - linear).abs() < 1e-5);
+            assert!((linear_to_srgb(linear) - encoded).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn display_p3_converts_to_the_srgb_it_shows() {
+        // sRGB red, green and grey, as Display P3 encodes them
+        let mut rgba = [234, 51, 35, 255, 117, 251, 76, 128, 128, 128, 128, 0];
+        ColorProfile::DisplayP3.convert_to_srgb(&mut rgba);
+        let expected = [255, 0, 0, 255, 0, 255, 0, 128, 128, 128, 128, 0];
+        for (got, want) in rgba.iter().zip(expected) {
+            // the 8-bit P3 values are rounded, which the steep foot of the curve magnifies
+            assert!(got.abs_diff(want) <= 3, "{rgba:?}");
+        }
+
+        let mut untouched = [234, 51, 35, 255];
+        ColorProfile::Srgb.convert_to_srgb(&mut untouched);
+        assert_eq!(untouched, [234, 51, 35, 255]);
+    }
+
+    #[test]
+    fn profiles_are_told_apart_by_their_colorants() {
+        assert_eq!(
+            ColorProfile::from_icc(&icc(&SRGB_COLORANTS)),
+            Some(ColorProfile::Srgb)
+        );
+        assert_eq!(
+            ColorProfile::from_icc(&icc(&DISPLAY_P3_COLORANTS)),
+            Some(ColorProfile::DisplayP3)
+        );
+        let adobe_rgb = [
+            [0.6097, 0.3111, 0.0195],
+            [0.2053, 0.6257, 0.0609],
+            [0.1492, 0.0632, 0.7446],
+        ];
+        assert_eq!(ColorProfile::from_icc(&icc(&adobe_rgb)), None);
+        // cut short
+        let profile = icc(&SRGB_COLORANTS);
+        assert_eq!(ColorProfile::from_icc(&profile[..profile.len() - 4]), None);
+        assert_eq!(ColorProfile::from_icc(&[]), None);
+    }
+}
@@ -0,0 +1,284 @@
+use ecolor::{Color32, Rgba};
+
+/// How a layer's colors combine with the layers below it.
+///
+/// Layers are composited in linear light, like everything else, so the modes that lighten or
+/// darken do so by the light the colors give off rather than by their sRGB values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LayerBlendMode {
+    /// The layer covers what's below as far as it's opaque.
+    #[default]
+    Normal,
+    /// Darkens by the layer's colors, like stacked filters. White leaves what's below alone.
+    Multiply,
+    /// Lightens by the layer's colors, the opposite of multiply. Black leaves what's below
+    /// alone.
+    Screen,
+    /// Multiplies the dark parts of what's below and screens the light parts, raising the
+    /// contrast.
+    Overlay,
+    /// Whichever of the two is darker, channel by channel.
+    Darken,
+    /// Whichever of the two is lighter, channel by channel.
+    Lighten,
+    /// The two added together, up to white.
+    Add,
+}
+
+impl LayerBlendMode {
+    pub const ALL: [LayerBlendMode; 7] = [
+        LayerBlendMode::Normal,
+        LayerBlendMode::Multiply,
+        LayerBlendMode::Screen,
+        LayerBlendMode::Overlay,
+        LayerBlendMode::Darken,
+        LayerBlendMode::Lighten,
+        LayerBlendMode::Add,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LayerBlendMode::Normal => "Normal",
+            LayerBlendMode::Multiply => "Multiply",
+            LayerBlendMode::Screen => "Screen",
+            LayerBlendMode::Overlay => "Overlay",
+            LayerBlendMode::Darken => "Darken",
+            LayerBlendMode::Lighten => "Lighten",
+            LayerBlendMode::Add => "Add",
+        }
+    }
+
+    /// Composites premultiplied `src` over premultiplied `dst`.
+    ///
+    /// Where both are opaque the result is the mode's mix of their colors. Where either is
+    /// partly transparent, the mix is weighted by how much they overlap, and the rest of each
+    /// shows through as with [`LayerBlendMode::Normal`], so a mode never changes a layer's
+    /// coverage, only its color.
+    pub fn blend(self, src: Rgba, dst: Rgba) -> Rgba {
+        let (src_a, dst_a) = (src.a(), dst.a());
+        if self == LayerBlendMode::Normal {
+            // the usual over, done directly so it's exactly what it's always been
+            return src + dst * (1.0 - src_a);
+        }
+        let unmultiply = |c: f32, a: f32| if a > 0.0 { c / a } else { 0.0 };
+        let channel = |s: f32, d: f32| {
+            let mixed = self.mix(unmultiply(s, src_a), unmultiply(d, dst_a));
+            s * (1.0 - dst_a) + d * (1.0 - src_a) + src_a * dst_a * mixed
+        };
+        Rgba::from_rgba_premultiplied(
+            channel(src.r(), dst.r()),
+            channel(src.g(), dst.g()),
+            channel(src.b(), dst.b()),
+            src_a + dst_a * (1.0 - src_a),
+        )
+    }
+
+    /// The mode's mix of one channel of straight `src` and `dst`, where both are opaque.
+    fn mix(self, src: f32, dst: f32) -> f32 {
+        match self {
+            LayerBlendMode::Normal => src,
+            LayerBlendMode::Multiply => src * dst,
+            LayerBlendMode::Screen => src + dst - src * dst,
+            LayerBlendMode::Overlay => {
+                if dst <= 0.5 {
+                    2.0 * src * dst
+                } else {
+                    1.0 - 2.0 * (1.0 - src) * (1.0 - dst)
+                }
+            }
+            LayerBlendMode::Darken => src.min(dst),
+            LayerBlendMode::Lighten => src.max(dst),
+            LayerBlendMode::Add => (src + dst).min(1.0),
+        }
+    }
+}
+
+/// One layer as [`composite`] takes it: its premultiplied pixels, where they are on the
+/// canvas, and how they show.
+#[derive(Clone, Copy)]
+pub struct CompositeLayer<'a> {
+    /// `width` by `height`, row by row.
+    pub pixels: &'a [Color32],
+    /// Where the layer's top left pixel is on the canvas, which can be off its edges.
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    /// From 0 to 1.
+    pub opacity: f32,
+    pub blend_mode: LayerBlendMode,
+}
+
+/// `layers`, bottom to top, composited over the `width` by `height` part of the canvas with
+/// its top left corner at `origin`, premultiplied, row by row. Each layer is faded by its
+/// opacity, scaled in gamma space, then blended with its mode.
+///
+/// Leaving out hidden layers is up to the caller; every layer passed in is composited.
+pub fn composite<'a>(
+    layers: impl IntoIterator<Item = CompositeLayer<'a>>,
+    origin: (i32, i32),
+    width: u32,
+    height: u32,
+) -> Vec<Color32> {
+    let mut merged = vec![Rgba::TRANSPARENT; width as usize * height as usize];
+    for layer in layers {
+        assert_eq!(
+            layer.pixels.len(),
+            layer.width as usize * layer.height as usize
+        );
+        let opacity = layer.opacity.clamp(0.0, 1.0);
+        // where the layer and the region overlap, in canvas coordinates
+        let (left, top) = (layer.x.max(origin.0), layer.y.max(origin.1));
+        let right = (layer.x + layer.width as i32).min(origin.0 + width as i32);
+        let bottom = (layer.y + layer.height as i32).min(origin.1 + height as i32);
+        if right <= left {
+            continue;
+        }
+        let across = (right - left) as usize;
+        for y in top..bottom {
+            let from = (y - layer.y) as usize * layer.width as usize + (left - layer.x) as usize;
+            let to = (y - origin.1) as usize * width as usize + (left - origin.0) as usize;
+            let sources = &layer.pixels[from..from + across];
+            for (target, &pixel) in merged[to..to + across].iter_mut().zip(sources) {
+                let src = Rgba::from(pixel.gamma_multiply(opacity));
+                *target = layer.blend_mode.blend(src, *target);
+            }
+        }
+    }
+    merged.into_iter().map(Color32::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Rgba, expected: [f32; 4]) {
+        let actual = actual.to_array();
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(a, e)| (a - e).abs() < 1e-6),
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    /// `pixels` as one layer `width` wide at the canvas's corner, at full opacity, blended
+    /// with `blend_mode`.
+    fn layer(pixels: &[Color32], width: u32, blend_mode: LayerBlendMode) -> CompositeLayer<'_> {
+        CompositeLayer {
+            pixels,
+            x: 0,
+            y: 0,
+            width,
+            height: pixels.len() as u32 / width,
+            opacity: 1.0,
+            blend_mode,
+        }
+    }
+
+    /// Half the light of white, in linear light.
+    const GRAY: Color32 = Color32::from_rgb(188, 188, 188);
+    /// Half, all and none of the light of each channel.
+    const MIXED: Color32 = Color32::from_rgb(188, 255, 0);
+
+    #[test]
+    fn multiply_darkens_by_the_layers_colors() {
+        let multiply = LayerBlendMode::Multiply;
+        let dst = Rgba::from_rgba_premultiplied(0.4, 0.3, 1.0, 1.0);
+        let src = Rgba::from_rgba_premultiplied(0.5, 1.0, 0.2, 1.0);
+        assert_close(multiply.blend(src, dst), [0.2, 0.3, 0.2, 1.0]);
+
+        // half covering, half of what's below is multiplied and the rest shows through:
+        // 0.4 * 0.5 + 0.5 * (0.5 * 0.4)
+        let faint = Rgba::from_rgba_premultiplied(0.25, 0.5, 0.1, 0.5);
+        assert_close(multiply.blend(faint, dst), [0.3, 0.3, 0.6, 1.0]);
+        // both half covering: a quarter each alone and a quarter mixed, so red is
+        // 0.25 * 0.5 + 0.2 * 0.5 + 0.25 * (0.5 * 0.4)
+        let faint_dst = Rgba::from_rgba_premultiplied(0.2, 0.15, 0.5, 0.5);
+        assert_close(multiply.blend(faint, faint_dst), [0.275, 0.4, 0.35, 0.75]);
+        // over nothing, the layer is as it was
+        assert_close(multiply.blend(faint, Rgba::TRANSPARENT), faint.to_array());
+
+        // white leaves what's below alone, black makes it black, and 188 is half the light
+        // of white, so multiplying it by itself gives a quarter, which is 138 in sRGB
+        let below = [Color32::RED, GRAY, GRAY];
+        let above = [Color32::WHITE, Color32::BLACK, GRAY];
+        let merged = composite(
+            [
+                layer(&below, 3, LayerBlendMode::Normal),
+                layer(&above, 3, multiply),
+            ],
+            (0, 0),
+            3,
+            1,
+        );
+        assert_eq!(
+            merged,
+            [Color32::RED, Color32::BLACK, Color32::from_gray(138)]
+        );
+    }
+
+    #[test]
+    fn screen_lightens_by_the_layers_colors() {
+        let screen = LayerBlendMode::Screen;
+        let dst = Rgba::from_rgba_premultiplied(0.4, 0.3, 1.0, 1.0);
+        let src = Rgba::from_rgba_premultiplied(0.5, 1.0, 0.2, 1.0);
+        // 0.5 + 0.4 - 0.5 * 0.4 and so on
+        assert_close(screen.blend(src, dst), [0.7, 1.0, 1.0, 1.0]);
+        let faint = Rgba::from_rgba_premultiplied(0.25, 0.5, 0.1, 0.5);
+        assert_close(screen.blend(faint, dst), [0.55, 0.65, 1.0, 1.0]);
+        assert_close(screen.blend(faint, Rgba::TRANSPARENT), faint.to_array());
+
+        // black leaves what's below alone, white makes it white, and half the light
+        // screened with half gives three quarters, which is 225 in sRGB
+        let below = [Color32::RED, GRAY, GRAY, MIXED];
+        let above = [Color32::BLACK, Color32::WHITE, GRAY, GRAY];
+        let merged = composite(
+            [
+                layer(&below, 2, LayerBlendMode::Normal),
+                layer(&above, 2, screen),
+            ],
+            (0, 0),
+            2,
+            2,
+        );
+        assert_eq!(
+            merged,
+            [
+                Color32::RED,
+                Color32::WHITE,
+                Color32::from_gray(225),
+                Color32::from_rgb(225, 255, 188),
+            ]
+        );
+    }
+
+    /// A layer's opacity fades it before it's blended, so a half opaque multiply layer
+    /// darkens half as much, and a mode only ever changes color, not coverage.
+    #[test]
+    fn modes_respect_opacity_and_coverage() {
+        let below = [Color32::WHITE, Color32::TRANSPARENT];
+        let above = [Color32::BLACK, Color32::BLACK];
+        for mode in [LayerBlendMode::Multiply, LayerBlendMode::Screen] {
+            let mut top = layer(&above, 2, mode);
+            top.opacity = 0.5;
+            let merged = composite(
+                [layer(&below, 2, LayerBlendMode::Normal), top],
+                (0, 0),
+                2,
+                1,
+            );
+            let expected = match mode {
+                // white covered 128/255 by black, that much of it multiplied to black
+                LayerBlendMode::Multiply => Color32::from(Rgba::from_gray(127.0 / 255.0)),
+                _ => Color32::WHITE,
+            };
+            assert_eq!(merged[0], expected, "{mode:?}");
+            // over nothing, half opaque black either way
+            assert_eq!(merged[1], Color32::BLACK.gamma_multiply(0.5), "{mode:?}");
+        }
+    }
+}
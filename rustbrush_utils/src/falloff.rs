@@ -0,0 +1,353 @@
+use std::borrow::Cow;
+
+/// Number of entries in the LUT a control-point curve is baked into for stamp generation.
+pub const FALLOFF_LUT_SIZE: usize = 256;
+
+/// How a brush's alpha falls off between its inner (fully opaque) radius and its outer radius.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FalloffCurve {
+    #[default]
+    Cosine,
+    Linear,
+    Gaussian,
+    Smoothstep,
+    /// Alpha values sampled evenly from the inner radius (first value) to the outer radius
+    /// (last value), linearly interpolated in between.
+    Custom(Vec<f32>),
+    /// A user-edited curve through control points, see [`CurvePoints`].
+    Points(CurvePoints),
+}
+
+/// The gaussian's sigma as a fraction of the falloff width. Three sigmas land on the outer
+/// radius, where the curve is down to ~1% and the visible edge sits at the nominal radius.
+const GAUSSIAN_SIGMA: f32 = 1.0 / 3.0;
+
+impl FalloffCurve {
+    /// The named curves, in the order they're offered in the brush settings.
+    pub const PRESETS: [FalloffCurve; 4] = [
+        FalloffCurve::Cosine,
+        FalloffCurve::Linear,
+        FalloffCurve::Gaussian,
+        FalloffCurve::Smoothstep,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            FalloffCurve::Cosine => "Cosine",
+            FalloffCurve::Linear => "Linear",
+            FalloffCurve::Gaussian => "Gaussian",
+            FalloffCurve::Smoothstep => "Smoothstep",
+            FalloffCurve::Custom(_) => "Custom",
+            FalloffCurve::Points(_) => "Curve",
+        }
+    }
+
+    /// Returns the curve in the form the stamp generator evaluates per pixel. Control-point
+    /// curves are baked into a LUT once instead of running the spline for every pixel.
+    pub fn baked(&self) -> Cow<'_, FalloffCurve> {
+        match self {
+            FalloffCurve::Points(points) => {
+                Cow::Owned(FalloffCurve::Custom(points.to_lut(FALLOFF_LUT_SIZE)))
+            }
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    /// Alpha at `t`, where 0 is the inner radius and 1 is the outer radius.
+    pub fn evaluate(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            FalloffCurve::Cosine => 0.5 * (1.0 + f32::cos(t * std::f32::consts::PI)),
+            FalloffCurve::Linear => 1.0 - t,
+            FalloffCurve::Gaussian => (-(t * t) / (2.0 * GAUSSIAN_SIGMA * GAUSSIAN_SIGMA)).exp(),
+            FalloffCurve::Smoothstep => 1.0 - t * t * (3.0 - 2.0 * t),
+            FalloffCurve::Custom(lut) => sample_lut(lut, t),
+            FalloffCurve::Points(points) => points.evaluate(t),
+        }
+    }
+}
+
+/// A falloff curve defined by control points, with distance (0..=1) on x and alpha (0..=1)
+/// on y. The points are always sorted by x, span the full 0..=1 range and have distinct x
+/// values; [`CurvePoints::new`] sanitizes any input into that shape.
+///
+/// The curve is evaluated with monotone cubic (Fritsch-Carlson) interpolation, so it never
+/// overshoots between points: a plateau between two equal points stays flat.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(from = "Vec<(f32, f32)>", into = "Vec<(f32, f32)>")
+)]
+pub struct CurvePoints {
+    points: Vec<(f32, f32)>,
+}
+
+impl Default for CurvePoints {
+    fn default() -> Self {
+        Self {
+            points: vec![(0.0, 1.0), (1.0, 0.0)],
+        }
+    }
+}
+
+/// Loaded points are sanitized like any others.
+impl From<Vec<(f32, f32)>> for CurvePoints {
+    fn from(points: Vec<(f32, f32)>) -> Self {
+        Self::new(points)
+    }
+}
+
+impl From<CurvePoints> for Vec<(f32, f32)> {
+    fn from(curve: CurvePoints) -> Self {
+        curve.points
+    }
+}
+
+impl CurvePoints {
+    pub fn new(points: Vec<(f32, f32)>) -> Self {
+        let mut curve = Self { points };
+        curve.sanitize();
+        curve
+    }
+
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    /// Moves the point at `index`, keeping it between its neighbours. The end points can
+    /// only move vertically so the curve always covers the full distance range.
+    pub fn move_point(&mut self, index: usize, x: f32, y: f32) {
+        let last = self.points.len() - 1;
+        let Some(point) = self.points.get(index) else {
+            return;
+        };
+        let x = if index == 0 || index == last {
+            point.0
+        } else {
+            let min = self.points[index - 1].0 + MIN_POINT_GAP;
+            let max = self.points[index + 1].0 - MIN_POINT_GAP;
+            x.clamp(min, max.max(min))
+        };
+        self.points[index] = (x, y.clamp(0.0, 1.0));
+    }
+
+    /// Inserts a point, returning its index once sorted into place.
+    pub fn insert(&mut self, x: f32, y: f32) -> usize {
+        let x = x.clamp(0.0, 1.0);
+        self.points.push((x, y.clamp(0.0, 1.0)));
+        self.sanitize();
+        self.points
+            .iter()
+            .position(|p| p.0 == x)
+            .unwrap_or(self.points.len() - 1)
+    }
+
+    /// Removes an interior point. The end points are never removed.
+    pub fn remove(&mut self, index: usize) {
+        if index > 0 && index + 1 < self.points.len() {
+            self.points.remove(index);
+        }
+    }
+
+    /// Bakes the curve into `size` evenly spaced samples for [`FalloffCurve::Custom`].
+    pub fn to_lut(&self, size: usize) -> Vec<f32> {
+        let steps = size.max(2) - 1;
+        (0..=steps)
+            .map(|i| self.evaluate(i as f32 / steps as f32))
+            .collect()
+    }
+
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let points = &self.points;
+        let x = x.clamp(0.0, 1.0);
+        let k = points
+            .windows(2)
+            .position(|w| x <= w[1].0)
+            .unwrap_or(points.len() - 2);
+
+        let (x0, y0) = points[k];
+        let (x1, y1) = points[k + 1];
+        let h = x1 - x0;
+        let t = (x - x0) / h;
+        let tangents = self.tangents();
+        let (m0, m1) = (tangents[k], tangents[k + 1]);
+
+        let t2 = t * t;
+        let t3 = t2 * t;
+        let value = (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+            + (t3 - 2.0 * t2 + t) * h * m0
+            + (-2.0 * t3 + 3.0 * t2) * y1
+            + (t3 - t2) * h * m1;
+        value.clamp(0.0, 1.0)
+    }
+
+    /// Fritsch-Carlson tangents: secant averages, zeroed at extrema and scaled down where
+    /// they would overshoot.
+    fn tangents(&self) -> Vec<f32> {
+        let points = &self.points;
+        let n = points.len();
+        let secants: Vec<f32> = points
+            .windows(2)
+            .map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0))
+            .collect();
+
+        let mut tangents = vec![0.0; n];
+        tangents[0] = secants[0];
+        tangents[n - 1] = secants[n - 2];
+        for k in 1..n - 1 {
+            tangents[k] = if secants[k - 1] * secants[k] <= 0.0 {
+                0.0
+            } else {
+                (secants[k - 1] + secants[k]) * 0.5
+            };
+        }
+
+        for k in 0..n - 1 {
+            if secants[k] == 0.0 {
+                tangents[k] = 0.0;
+                tangents[k + 1] = 0.0;
+                continue;
+            }
+            let a = tangents[k] / secants[k];
+            let b = tangents[k + 1] / secants[k];
+            let magnitude = a * a + b * b;
+            if magnitude > 9.0 {
+                let tau = 3.0 / magnitude.sqrt();
+                tangents[k] = tau * a * secants[k];
+                tangents[k + 1] = tau * b * secants[k];
+            }
+        }
+
+        tangents
+    }
+
+    /// Clamps, sorts and deduplicates the points and makes sure they span 0..=1.
+    fn sanitize(&mut self) {
+        self.points.retain(|p| p.0.is_finite() && p.1.is_finite());
+        for point in self.points.iter_mut() {
+            point.0 = point.0.clamp(0.0, 1.0);
+            point.1 = point.1.clamp(0.0, 1.0);
+        }
+        self.points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.points
+            .dedup_by(|later, earlier| later.0 - earlier.0 < MIN_POINT_GAP);
+
+        match (self.points.first().copied(), self.points.last().copied()) {
+            (Some(first), Some(last)) => {
+                if first.0 > 0.0 {
+                    self.points.insert(0, (0.0, first.1));
+                }
+                if last.0 < 1.0 {
+                    self.points.push((1.0, last.1));
+                }
+            }
+            _ => *self = Self::default(),
+        }
+    }
+}
+
+/// The smallest x distance kept between two control points.
+const MIN_POINT_GAP: f32 = 0.001;
+
+/// Linearly interpolates `lut` at `t` in 0..=1. An empty LUT falls back to a linear ramp.
+fn sample_lut(lut: &[f32], t: f32) -> f32 {
+    match lut.len() {
+        0 => 1.0 - t,
+        1 => lut[0].clamp(0.0, 1.0),
+        len => {
+            let position = t * (len - 1) as f32;
+            let index = (position.floor() as usize).min(len - 2);
+            let frac = position - index as f32;
+            let value = lut[index] * (1.0 - frac) + lut[index + 1] * frac;
+            value.clamp(0.0, 1.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Brush;
+    use std::f32::consts::PI;
+
+    /// The alpha of a radius 10 soft circle with no hard center, `distance` pixels right of
+    /// its center, where each pixel is wholly inside and the falloff alone sets it.
+    fn profile(falloff: FalloffCurve, distance: i32) -> f32 {
+        let brush = Brush::default()
+            .with_radius(10.0)
+            .with_hardness(0.0)
+            .with_falloff(falloff);
+        brush.compute_stamp().alpha_at(distance, 0)
+    }
+
+    #[test]
+    fn each_curve_falls_off_as_its_formula() {
+        let custom = FalloffCurve::Custom(vec![1.0, 0.8, 0.0]);
+        let expected = |curve: &FalloffCurve, t: f32| match curve {
+            FalloffCurve::Cosine => 0.5 + 0.5 * (PI * t).cos(),
+            FalloffCurve::Linear => 1.0 - t,
+            // three sigmas to the edge
+            FalloffCurve::Gaussian => (-4.5 * t * t).exp(),
+            FalloffCurve::Smoothstep => 1.0 - 3.0 * t * t + 2.0 * t * t * t,
+            _ if t < 0.5 => 1.0 - 0.4 * t,
+            _ => 0.8 - 1.6 * (t - 0.5),
+        };
+        for curve in FalloffCurve::PRESETS.into_iter().chain([custom]) {
+            for distance in 0..=9 {
+                let t = distance as f32 / 10.0;
+                let alpha = profile(curve.clone(), distance);
+                assert!(
+                    (alpha - expected(&curve, t)).abs() < 1e-5,
+                    "{curve:?} at {t}: {alpha}, expected {}",
+                    expected(&curve, t)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gaussian_is_faint_by_the_edge() {
+        assert!(FalloffCurve::Gaussian.evaluate(1.0) < 0.02);
+        assert!(profile(FalloffCurve::Gaussian, 10) < 0.02);
+    }
+
+    #[test]
+    fn a_plateau_holds_across_the_middle_of_the_stamp() {
+        let plateau = CurvePoints::new(vec![(0.0, 1.0), (0.3, 0.6), (0.7, 0.6), (1.0, 0.0)]);
+        // baked into the stamp generator's LUT on the way
+        let falloff = FalloffCurve::Points(plateau);
+        for distance in 3..=7 {
+            let alpha = profile(falloff.clone(), distance);
+            assert!((alpha - 0.6).abs() < 1e-3, "{distance}: {alpha}");
+        }
+        // and doesn't overshoot either side of it
+        assert!(profile(falloff.clone(), 2) > 0.6);
+        assert!(profile(falloff, 8) < 0.6);
+    }
+
+    #[test]
+    fn degenerate_points_are_sanitized() {
+        // too few points to make a curve
+        assert_eq!(CurvePoints::new(vec![]), CurvePoints::default());
+        assert_eq!(
+            CurvePoints::new(vec![(0.4, 0.5)]).points(),
+            [(0.0, 0.5), (0.4, 0.5), (1.0, 0.5)]
+        );
+        // out of order, out of range, repeated and not numbers
+        let points = CurvePoints::new(vec![
+            (0.8, 0.2),
+            (f32::NAN, 0.5),
+            (-1.0, 2.0),
+            (0.3, 0.7),
+            (0.3, 0.1),
+            (1.5, -1.0),
+        ]);
+        assert_eq!(
+            points.points(),
+            [(0.0, 1.0), (0.3, 0.7), (0.8, 0.2), (1.0, 0.0)]
+        );
+    }
+}
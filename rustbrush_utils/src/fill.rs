@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+
+use ecolor::{Color32, Rgba};
+
+use crate::pixel_buffer::{DirtyRect, PixelBuffer};
+use crate::selection::SelectionMask;
+
+/// A flood fill whose region is found in one buffer and filled in another, so a region
+/// outlined on one layer, or in the merged layers, can be filled on another layer without
+/// touching the outline. Both buffers are the same size, and `reference` can be a copy of
+/// `pixels` for an ordinary fill.
+pub struct FillOperation<'a> {
+    /// Where the region is found.
+    pub reference: &'a dyn PixelBuffer,
+    /// What's filled.
+    pub pixels: &'a mut dyn PixelBuffer,
+    /// Where the fill starts. The region is every pixel connected to it, up, down, left or
+    /// right, that's within `tolerance` of its color in `reference`.
+    pub seed: (u32, u32),
+    pub color: Rgba,
+    /// How far any channel of a pixel can be from the seed's, premultiplied, and still be
+    /// filled.
+    pub tolerance: u8,
+    /// The widest gap in the region's outline, in pixels, that the fill doesn't leak
+    /// through, see [`fill_region`]. Only contiguous fills have an outline to close.
+    pub max_gap: u32,
+    /// Whether the region is only the pixels connected to the seed, or every pixel on the
+    /// buffer within `tolerance` of its color.
+    pub contiguous: bool,
+    /// How many pixels the region is grown by all around, whatever their color, so the fill
+    /// reaches under the soft edges of line art rather than leaving a halo next to them.
+    pub grow: u32,
+    /// Limits the fill to the selection, which also softens it where partly selected.
+    pub selection: Option<&'a SelectionMask>,
+}
+
+impl FillOperation<'_> {
+    /// Fills the region with the color laid over what's there, grown by `grow`, returning
+    /// the rectangle it covers. Does nothing if the seed is off the buffers.
+    pub fn process(self) -> DirtyRect {
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        assert_eq!(
+            (self.reference.width(), self.reference.height()),
+            (width, height)
+        );
+        let (w, h) = (width as usize, height as usize);
+        let reference = self.reference.pixels();
+        let mut region = match self.contiguous {
+            true => fill_region(
+                reference,
+                width,
+                height,
+                self.seed,
+                self.tolerance,
+                self.max_gap,
+            ),
+            false => fillable(reference, width, height, self.seed, self.tolerance)
+                .unwrap_or_else(|| vec![false; w * h]),
+        };
+        if self.grow > 0 {
+            region = dilate(&region, w, h, self.grow as usize);
+        }
+
+        let width = w.max(1);
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        for (index, pixel) in self.pixels.pixels_mut().iter_mut().enumerate() {
+            if !region[index] {
+                continue;
+            }
+            let coverage = self
+                .selection
+                .map_or(1.0, |mask| mask.values()[index] as f32 / 255.0);
+            if coverage <= 0.0 {
+                continue;
+            }
+            let src = self.color * coverage;
+            *pixel = Color32::from(src + Rgba::from(*pixel) * (1.0 - src.a()));
+            let (x, y) = (index % width, index / width);
+            (min_x, min_y) = (min_x.min(x), min_y.min(y));
+            (max_x, max_y) = (max_x.max(x), max_y.max(y));
+        }
+        if min_x > max_x {
+            return DirtyRect::default();
+        }
+        DirtyRect::new(
+            min_x as u32,
+            min_y as u32,
+            (max_x - min_x + 1) as u32,
+            (max_y - min_y + 1) as u32,
+        )
+    }
+}
+
+/// Which pixels of `pixels`, a `width` by `height` buffer, a fill from `seed` covers: those
+/// connected to it that are within `tolerance` of its color, see [`FillOperation`].
+///
+/// With a `max_gap`, gaps in the outline up to that wide are closed first: the outline, the
+/// pixels that aren't filled, is thickened by half the gap on each side, so the gap is shut,
+/// and the region is flooded inside the thickened outline. The region is then grown back out
+/// by as much, through fillable pixels only, so it still reaches the outline everywhere, but
+/// not so far it gets through the gap. If the seed is inside the thickened outline, in a
+/// space narrower than the gap, the gap isn't closed.
+pub fn fill_region(
+    pixels: &[Color32],
+    width: u32,
+    height: u32,
+    seed: (u32, u32),
+    tolerance: u8,
+    max_gap: u32,
+) -> Vec<bool> {
+    let (w, h) = (width as usize, height as usize);
+    let Some(fillable) = fillable(pixels, width, height, seed, tolerance) else {
+        return vec![false; w * h];
+    };
+    let seed = seed.1 as usize * w + seed.0 as usize;
+
+    let reach = max_gap.div_ceil(2) as usize;
+    if reach > 0 {
+        let outline: Vec<bool> = fillable.iter().map(|&f| !f).collect();
+        let closed = dilate(&outline, w, h, reach);
+        if !closed[seed] {
+            let open: Vec<bool> = closed.iter().map(|&c| !c).collect();
+            let flooded = flood(&open, w, h, seed);
+            let grown = dilate(&flooded, w, h, reach);
+            return grown
+                .iter()
+                .zip(&fillable)
+                .map(|(&grown, &fillable)| grown && fillable)
+                .collect();
+        }
+    }
+    flood(&fillable, w, h, seed)
+}
+
+/// Which pixels of `pixels`, a `width` by `height` buffer, are within `tolerance` of the
+/// color at `seed` in every channel, premultiplied. `None` if the seed is off the buffer.
+fn fillable(
+    pixels: &[Color32],
+    width: u32,
+    height: u32,
+    seed: (u32, u32),
+    tolerance: u8,
+) -> Option<Vec<bool>> {
+    if seed.0 >= width || seed.1 >= height {
+        return None;
+    }
+    let seed_color = pixels[seed.1 as usize * width as usize + seed.0 as usize].to_array();
+    let fillable = pixels
+        .iter()
+        .map(|pixel| {
+            let color = pixel.to_array();
+            (0..4).all(|c| color[c].abs_diff(seed_color[c]) <= tolerance)
+        })
+        .collect();
+    Some(fillable)
+}
+
+/// The pixels connected to `seed`, up, down, left or right, through `passable` ones.
+fn flood(passable: &[bool], width: usize, height: usize, seed: usize) -> Vec<bool> {
+    let mut filled = vec![false; passable.len()];
+    if !passable[seed] {
+        return filled;
+    }
+    filled[seed] = true;
+    let mut queue = VecDeque::from([seed]);
+    while let Some(index) = queue.pop_front() {
+        let (x, y) = (index % width, index / width);
+        let neighbors = [
+            (x > 0).then(|| index - 1),
+            (x + 1 < width).then(|| index + 1),
+            (y > 0).then(|| index - width),
+            (y + 1 < height).then(|| index + width),
+        ];
+        for neighbor in neighbors.into_iter().flatten() {
+            if passable[neighbor] && !filled[neighbor] {
+                filled[neighbor] = true;
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    filled
+}
+
+/// `mask` grown by `reach` pixels in every direction, as a square, first along the rows and
+/// then down the columns.
+fn dilate(mask: &[bool], width: usize, height: usize, reach: usize) -> Vec<bool> {
+    let mut rows = vec![false; mask.len()];
+    for y in 0..height {
+        let row = &mask[y * width..(y + 1) * width];
+        for x in 0..width {
+            let (from, to) = (x.saturating_sub(reach), (x + reach).min(width - 1));
+            rows[y * width + x] = row[from..=to].iter().any(|&m| m);
+        }
+    }
+    let mut grown = vec![false; mask.len()];
+    for x in 0..width {
+        for y in 0..height {
+            let (from, to) = (y.saturating_sub(reach), (y + reach).min(height - 1));
+            grown[y * width + x] = (from..=to).any(|y| rows[y * width + x]);
+        }
+    }
+    grown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_buffer::PixelSlice;
+
+    const WIDTH: u32 = 24;
+    const HEIGHT: u32 = 16;
+    const INK: Color32 = Color32::from_gray(0x60);
+
+    /// White paper with a box inked from (4, 4) to (12, 11), one pixel thick, whose right
+    /// side is open for the two pixels at (12, 7) and (12, 8).
+    fn line_art() -> Vec<Color32> {
+        let mut pixels = vec![Color32::WHITE; (WIDTH * HEIGHT) as usize];
+        for y in 4..=11 {
+            for x in 4..=12 {
+                let edge = x == 4 || x == 12 || y == 4 || y == 11;
+                let gap = x == 12 && (y == 7 || y == 8);
+                if edge && !gap {
+                    pixels[(y * WIDTH + x) as usize] = INK;
+                }
+            }
+        }
+        pixels
+    }
+
+    /// The line art filled blue from inside the box, and which pixels turned blue.
+    fn filled(tolerance: u8, max_gap: u32) -> impl Fn(u32, u32) -> bool {
+        let mut reference = line_art();
+        let mut pixels = reference.clone();
+        FillOperation {
+            reference: &PixelSlice::new(&mut reference, WIDTH, HEIGHT),
+            pixels: &mut PixelSlice::new(&mut pixels, WIDTH, HEIGHT),
+            seed: (8, 8),
+            color: Rgba::BLUE,
+            tolerance,
+            max_gap,
+            contiguous: true,
+            grow: 0,
+            selection: None,
+        }
+        .process();
+        move |x, y| pixels[(y * WIDTH + x) as usize] == Color32::BLUE
+    }
+
+    #[test]
+    fn a_gap_leaks_until_it_is_closed() {
+        let inside = [(5, 5), (11, 10), (8, 8)];
+        let gap = [(12, 7), (12, 8)];
+        let outside = [(18, 8), (0, 0), (23, 15)];
+        let ink = [(4, 4), (12, 6), (8, 11)];
+
+        let open = filled(0, 0);
+        for (x, y) in inside.into_iter().chain(gap).chain(outside) {
+            assert!(open(x, y), "an open gap kept the fill from ({x}, {y})");
+        }
+        for (x, y) in ink {
+            assert!(!open(x, y), "filled the ink at ({x}, {y})");
+        }
+
+        for max_gap in [2, 4] {
+            let closed = filled(0, max_gap);
+            for (x, y) in inside {
+                assert!(closed(x, y), "gap {max_gap} didn't fill ({x}, {y})");
+            }
+            for (x, y) in gap.into_iter().chain(outside).chain(ink) {
+                assert!(!closed(x, y), "gap {max_gap} leaked to ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn a_tolerance_past_the_ink_fills_through_it() {
+        // the ink is 0x9f from the paper in each color channel
+        let under = filled(0x9e, 2);
+        assert!(under(11, 10) && !under(12, 6) && !under(18, 8));
+        for max_gap in [0, 2] {
+            let over = filled(0x9f, max_gap);
+            for (x, y) in [(8, 8), (12, 6), (12, 7), (18, 8), (0, 0)] {
+                assert!(over(x, y), "gap {max_gap} didn't fill ({x}, {y})");
+            }
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use ecolor::Color32;
+
+use crate::filters::{Adjustment, ColorBalance, GaussianBlur, Levels, ReplaceColor};
+use crate::pixel_buffer::{DirtyRect, PixelBuffer};
+use crate::selection::SelectionMask;
+use crate::task::TaskContext;
+
+/// An image filter that can be plugged into the Filters menu through a [`FilterRegistry`].
+///
+/// Filters only transform pixels. Previewing, masking by the selection, undo and dirty
+/// tracking are handled by [`apply_filter`] and the GUI around it, so a filter doesn't have
+/// to do anything about them.
+pub trait Filter {
+    fn name(&self) -> &str;
+
+    /// Filters the pixels in `region` (the whole buffer for `None`), returning the part of
+    /// the buffer that was changed. `region` is always within the buffer when called through
+    /// [`apply_filter`]. The result is blended with the original pixels by `mask`
+    /// afterwards, so filters only need to look at it to skip work.
+    fn apply(
+        &self,
+        pixels: &mut dyn PixelBuffer,
+        region: Option<DirtyRect>,
+        mask: Option<&SelectionMask>,
+    ) -> DirtyRect;
+
+    /// Shows the filter's settings, returning whether any of them changed so the preview
+    /// can be updated. Filters without settings can leave this out.
+    #[cfg(feature = "gui")]
+    fn params_ui(&mut self, _ui: &mut egui::Ui) -> bool {
+        false
+    }
+
+    /// A copy of the filter with its current settings that can be run on a background
+    /// thread, for filters that take long enough on a large layer to freeze the UI. Filters
+    /// that are quick can leave this out, and are run where they're called.
+    fn background(&self) -> Option<Box<dyn BackgroundFilter>> {
+        None
+    }
+}
+
+/// A filter that can be run on a background thread, see [`Filter::background`].
+pub trait BackgroundFilter: Send {
+    /// Like [`Filter::apply`], reporting its progress to `task` and giving up with `None`
+    /// as soon as it can once `task` is cancelled. What's in `region` is undefined after
+    /// giving up, so it should be filtering a copy of the pixels.
+    fn apply_in_task(
+        &self,
+        pixels: &mut dyn PixelBuffer,
+        region: Option<DirtyRect>,
+        mask: Option<&SelectionMask>,
+        task: &TaskContext,
+    ) -> Option<DirtyRect>;
+}
+
+/// Applies `filter` to `region` of the buffer (all of it for `None`), limited to the
+/// selection when there is one: partially selected pixels get a blend of the filtered and
+/// original pixels. Returns what changed, which is empty if nothing could.
+pub fn apply_filter(
+    filter: &dyn Filter,
+    pixels: &mut dyn PixelBuffer,
+    region: Option<DirtyRect>,
+    mask: Option<&SelectionMask>,
+) -> DirtyRect {
+    apply_masked(pixels, region, mask, |pixels, region| {
+        Some(filter.apply(pixels, Some(region), mask))
+    })
+    .unwrap_or_default()
+}
+
+/// [`apply_filter`] for a filter on a background thread, returning `None` if `task` was
+/// cancelled before it finished.
+pub fn apply_filter_in_task(
+    filter: &dyn BackgroundFilter,
+    pixels: &mut dyn PixelBuffer,
+    region: Option<DirtyRect>,
+    mask: Option<&SelectionMask>,
+    task: &TaskContext,
+) -> Option<DirtyRect> {
+    apply_masked(pixels, region, mask, |pixels, region| {
+        filter.apply_in_task(pixels, Some(region), mask, task)
+    })
+}
+
+/// Runs `filter` on the selected part of `region`, then blends what it changed with the
+/// original pixels by the selection, see [`apply_filter`].
+fn apply_masked(
+    pixels: &mut dyn PixelBuffer,
+    region: Option<DirtyRect>,
+    mask: Option<&SelectionMask>,
+    filter: impl FnOnce(&mut dyn PixelBuffer, DirtyRect) -> Option<DirtyRect>,
+) -> Option<DirtyRect> {
+    let full = DirtyRect::full(pixels.width(), pixels.height());
+    let mut region = region.unwrap_or(full).intersect(full);
+    if let Some(mask) = mask {
+        region = region.intersect(mask.bounds());
+    }
+    if region.is_empty() {
+        return Some(DirtyRect::default());
+    }
+
+    let before = mask.map(|_| pixels.copy_rect(region));
+    let dirty = filter(pixels, region)?.intersect(region);
+
+    if let (Some(mask), Some(before)) = (mask, before) {
+        let width = pixels.width();
+        let buffer = pixels.pixels_mut();
+        for (row, y) in dirty.rows(width).zip(dirty.y..) {
+            for (index, x) in row.zip(dirty.x..) {
+                let selected = mask.value(x, y) as f32 / 255.0;
+                let original =
+                    before[((y - region.y) * region.width + (x - region.x)) as usize].to_array();
+                let filtered = buffer[index].to_array();
+                let [r, g, b, a] = std::array::from_fn(|c| {
+                    let (from, to) = (original[c] as f32, filtered[c] as f32);
+                    (from + (to - from) * selected).round() as u8
+                });
+                buffer[index] = Color32::from_rgba_premultiplied(r, g, b, a);
+            }
+        }
+    }
+    Some(dirty)
+}
+
+/// A filter in the registry: its menu name and how to make one with default settings.
+pub struct FilterEntry {
+    name: String,
+    create: Box<dyn Fn() -> Box<dyn Filter>>,
+}
+
+impl FilterEntry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn create(&self) -> Box<dyn Filter> {
+        (self.create)()
+    }
+}
+
+/// A filter was registered under a name that's already taken, see
+/// [`FilterRegistry::register`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateFilter(pub String);
+
+impl std::fmt::Display for DuplicateFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "there is already a filter named '{}'", self.0)
+    }
+}
+
+impl std::error::Error for DuplicateFilter {}
+
+/// The filters the Filters menu offers, in menu order. The built-in adjustments are
+/// registered the same way as any other filter.
+#[derive(Default)]
+pub struct FilterRegistry {
+    entries: Vec<FilterEntry>,
+}
+
+impl FilterRegistry {
+    /// A registry with nothing in it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry with the built-in adjustments and filters.
+    pub fn with_builtins() -> Self {
+        // the built-in names are all different, so there's nothing to check
+        let mut registry = Self::new();
+        registry.push(ColorBalance::NAME, || {
+            Box::new(Adjustment::ColorBalance(ColorBalance::default()))
+        });
+        registry.push(Levels::NAME, || {
+            Box::new(Adjustment::Levels(Levels::default()))
+        });
+        registry.push(ReplaceColor::NAME, || {
+            Box::new(Adjustment::ReplaceColor(ReplaceColor::default()))
+        });
+        registry.push(GaussianBlur::NAME, || Box::new(GaussianBlur::default()));
+        registry
+    }
+
+    /// Adds a filter to the end of the menu. Fails if there's already a filter with the same
+    /// name, which would be impossible to tell apart in the menu.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        create: impl Fn() -> Box<dyn Filter> + 'static,
+    ) -> Result<(), DuplicateFilter> {
+        let name = name.into();
+        if self.entries.iter().any(|entry| entry.name == name) {
+            return Err(DuplicateFilter(name));
+        }
+        self.push(name, create);
+        Ok(())
+    }
+
+    fn push(&mut self, name: impl Into<String>, create: impl Fn() -> Box<dyn Filter> + 'static) {
+        self.entries.push(FilterEntry {
+            name: name.into(),
+            create: Box::new(create),
+        });
+    }
+
+    pub fn entries(&self) -> &[FilterEntry] {
+        &self.entries
+    }
+
+    /// A new instance of the filter named `name`, with default settings.
+    pub fn create(&self, name: &str) -> Option<Box<dyn Filter>> {
+        self.entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(FilterEntry::create)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_buffer::PixelSlice;
+
+    /// Turns every pixel it's applied to white.
+    struct Whiten;
+
+    impl Filter for Whiten {
+        fn name(&self) -> &str {
+            "Whiten"
+        }
+
+        fn apply(
+            &self,
+            pixels: &mut dyn PixelBuffer,
+            region: Option<DirtyRect>,
+            _mask: Option<&SelectionMask>,
+        ) -> DirtyRect {
+            let region = region.unwrap_or(DirtyRect::full(pixels.width(), pixels.height()));
+            let width = pixels.width();
+            let buffer = pixels.pixels_mut();
+            for row in region.rows(width) {
+                for index in row {
+                    buffer[index] = Color32::WHITE;
+                }
+            }
+            region
+        }
+    }
+
+    fn names(registry: &FilterRegistry) -> Vec<&str> {
+        registry.entries().iter().map(FilterEntry::name).collect()
+    }
+
+    #[test]
+    fn registered_filters_are_looked_up_by_name() {
+        let mut registry = FilterRegistry::new();
+        assert!(registry.create("Whiten").is_none());
+        registry.register("Whiten", || Box::new(Whiten)).unwrap();
+
+        let filter = registry.create("Whiten").unwrap();
+        assert_eq!(filter.name(), "Whiten");
+        let mut pixels = vec![Color32::BLACK; 4];
+        let dirty = apply_filter(
+            filter.as_ref(),
+            &mut PixelSlice::new(&mut pixels, 2, 2),
+            None,
+            None,
+        );
+        assert_eq!(dirty, DirtyRect::full(2, 2));
+        assert!(pixels.iter().all(|&p| p == Color32::WHITE));
+        // names are matched exactly
+        assert!(registry.create("whiten").is_none());
+    }
+
+    #[test]
+    fn a_name_can_only_be_registered_once() {
+        let mut registry = FilterRegistry::with_builtins();
+        let before = names(&registry).len();
+        let taken = registry.register(GaussianBlur::NAME, || Box::new(Whiten));
+        assert_eq!(taken, Err(DuplicateFilter(GaussianBlur::NAME.to_string())));
+        assert_eq!(names(&registry).len(), before);
+        // the filter that was there first is kept
+        let blur = registry.create(GaussianBlur::NAME).unwrap();
+        assert!(blur.background().is_some());
+    }
+
+    #[test]
+    fn filters_are_listed_in_the_order_they_were_registered() {
+        let mut registry = FilterRegistry::with_builtins();
+        assert_eq!(
+            names(&registry),
+            [
+                ColorBalance::NAME,
+                Levels::NAME,
+                ReplaceColor::NAME,
+                GaussianBlur::NAME
+            ]
+        );
+        registry.register("Whiten", || Box::new(Whiten)).unwrap();
+        registry.register("Another", || Box::new(Whiten)).unwrap();
+        assert_eq!(names(&registry)[4..], ["Whiten", "Another"]);
+    }
+}
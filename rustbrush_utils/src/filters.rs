@@ -0,0 +1,696 @@
+use std::ops::RangeInclusive;
+
+use ecolor::Color32;
+
+use crate::alpha;
+use crate::filter_registry::{BackgroundFilter, Filter};
+use crate::pixel_buffer::{DirtyRect, PixelBuffer};
+use crate::selection::SelectionMask;
+use crate::task::TaskContext;
+
+/// Rec. 709 luma weights.
+pub const LUMA_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// An adjustment applied to a whole layer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Adjustment {
+    ColorBalance(ColorBalance),
+    Levels(Levels),
+    ReplaceColor(ReplaceColor),
+}
+
+impl Adjustment {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Adjustment::ColorBalance(_) => ColorBalance::NAME,
+            Adjustment::Levels(_) => Levels::NAME,
+            Adjustment::ReplaceColor(_) => ReplaceColor::NAME,
+        }
+    }
+
+    /// Applies the adjustment to a layer's pixels, `width` pixels to a row.
+    pub fn apply(&self, pixels: &mut [Color32], width: usize) {
+        match self {
+            Adjustment::ColorBalance(color_balance) => color_balance.apply(pixels),
+            Adjustment::Levels(levels) => levels.apply(pixels),
+            Adjustment::ReplaceColor(replace_color) => replace_color.apply(pixels, width),
+        }
+    }
+}
+
+/// The adjustments have dedicated dialogs in the GUI, so they don't provide a settings UI.
+impl Filter for Adjustment {
+    fn name(&self) -> &str {
+        Adjustment::name(self)
+    }
+
+    fn apply(
+        &self,
+        pixels: &mut dyn PixelBuffer,
+        region: Option<DirtyRect>,
+        _mask: Option<&SelectionMask>,
+    ) -> DirtyRect {
+        let full = DirtyRect::full(pixels.width(), pixels.height());
+        let region = region.unwrap_or(full);
+        if region == full {
+            Adjustment::apply(self, pixels.pixels_mut(), full.width as usize);
+        } else {
+            // replace color looks at neighbouring pixels, which stop at the region's edge
+            let mut cropped = pixels.copy_rect(region);
+            Adjustment::apply(self, &mut cropped, region.width as usize);
+            pixels.write_rect(region, &cropped);
+        }
+        region
+    }
+}
+
+/// The tonal ranges color balance offsets are applied to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToneRange {
+    Shadows,
+    Midtones,
+    Highlights,
+}
+
+impl ToneRange {
+    pub const ALL: [ToneRange; 3] = [
+        ToneRange::Shadows,
+        ToneRange::Midtones,
+        ToneRange::Highlights,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ToneRange::Shadows => "Shadows",
+            ToneRange::Midtones => "Midtones",
+            ToneRange::Highlights => "Highlights",
+        }
+    }
+
+    /// How much a pixel with luma `luma` (0..=1) belongs to this range. Shadows fade out by
+    /// mid-grey and highlights only start there, so a mid-grey pixel is purely a midtone.
+    pub fn weight(&self, luma: f32) -> f32 {
+        let luma = luma.clamp(0.0, 1.0);
+        let linear = match self {
+            ToneRange::Shadows => 1.0 - 2.0 * luma,
+            ToneRange::Midtones => 1.0 - (2.0 * luma - 1.0).abs(),
+            ToneRange::Highlights => 2.0 * luma - 1.0,
+        }
+        .clamp(0.0, 1.0);
+        linear * linear * (3.0 - 2.0 * linear)
+    }
+}
+
+/// Shifts the RGB channels by separate offsets in the shadows, midtones and highlights.
+/// Offsets are in channel units (-1..=1) and alpha is never touched.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColorBalance {
+    pub shadows: [f32; 3],
+    pub midtones: [f32; 3],
+    pub highlights: [f32; 3],
+    /// Shifts the result back to the original luma so only the hue changes.
+    pub preserve_luminosity: bool,
+}
+
+impl ColorBalance {
+    pub const NAME: &'static str = "Color Balance";
+
+    pub fn offsets(&self, range: ToneRange) -> &[f32; 3] {
+        match range {
+            ToneRange::Shadows => &self.shadows,
+            ToneRange::Midtones => &self.midtones,
+            ToneRange::Highlights => &self.highlights,
+        }
+    }
+
+    pub fn offsets_mut(&mut self, range: ToneRange) -> &mut [f32; 3] {
+        match range {
+            ToneRange::Shadows => &mut self.shadows,
+            ToneRange::Midtones => &mut self.midtones,
+            ToneRange::Highlights => &mut self.highlights,
+        }
+    }
+
+    /// Applies the balance to a single straight (unmultiplied) RGB color in 0..=1.
+    pub fn apply_rgb(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let original_luma = luma(rgb);
+        let mut out = rgb;
+        for range in ToneRange::ALL {
+            let weight = range.weight(original_luma);
+            let offsets = self.offsets(range);
+            for channel in 0..3 {
+                out[channel] += offsets[channel] * weight;
+            }
+        }
+
+        if self.preserve_luminosity {
+            let shift = original_luma - luma(out);
+            for value in out.iter_mut() {
+                *value += shift;
+            }
+        }
+
+        out.map(|value| value.clamp(0.0, 1.0))
+    }
+
+    pub fn apply(&self, pixels: &mut [Color32]) {
+        for pixel in pixels.iter_mut() {
+            if pixel.a() == 0 {
+                continue;
+            }
+            let [r, g, b, a] = alpha::unpremultiply(*pixel);
+            let rgb = [r, g, b].map(|c| c as f32 / 255.0);
+            let [r, g, b] = self.apply_rgb(rgb).map(|c| (c * 255.0).round() as u8);
+            *pixel = alpha::premultiply([r, g, b, a]);
+        }
+    }
+}
+
+/// Rec. 709 luma of a straight RGB color.
+pub fn luma(rgb: [f32; 3]) -> f32 {
+    rgb[0] * LUMA_WEIGHTS[0] + rgb[1] * LUMA_WEIGHTS[1] + rgb[2] * LUMA_WEIGHTS[2]
+}
+
+/// Per-channel counts of the straight (unmultiplied) values of every non-transparent pixel.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    pub channels: [[u32; 256]; 3],
+}
+
+impl Histogram {
+    pub fn from_pixels(pixels: &[Color32]) -> Self {
+        let mut channels = [[0; 256]; 3];
+        for pixel in pixels.iter().filter(|p| p.a() > 0) {
+            let [r, g, b, _] = alpha::unpremultiply(*pixel);
+            channels[0][r as usize] += 1;
+            channels[1][g as usize] += 1;
+            channels[2][b as usize] += 1;
+        }
+        Self { channels }
+    }
+
+    /// Counts for `channel`, or the sum of all three channels when `channel` is `None`.
+    pub fn counts(&self, channel: Option<usize>) -> [u32; 256] {
+        match channel {
+            Some(channel) => self.channels[channel],
+            None => std::array::from_fn(|i| self.channels.iter().map(|c| c[i]).sum()),
+        }
+    }
+
+    /// The lowest value at or below which `fraction` (0..=1) of the counted values fall.
+    pub fn percentile(&self, channel: Option<usize>, fraction: f32) -> u8 {
+        let counts = self.counts(channel);
+        let total: u64 = counts.iter().map(|&c| c as u64).sum();
+        let target = (total as f64 * fraction.clamp(0.0, 1.0) as f64).ceil() as u64;
+        let mut seen = 0;
+        for (value, &count) in counts.iter().enumerate() {
+            seen += count as u64;
+            if seen >= target.max(1) {
+                return value as u8;
+            }
+        }
+        255
+    }
+}
+
+/// Levels for one channel: input values between `input_black` and `input_white` are
+/// stretched to the full range, bent by `gamma`, then compressed into
+/// `output_black..=output_white`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LevelsChannel {
+    pub input_black: u8,
+    pub input_white: u8,
+    pub gamma: f32,
+    pub output_black: u8,
+    pub output_white: u8,
+}
+
+impl Default for LevelsChannel {
+    fn default() -> Self {
+        Self {
+            input_black: 0,
+            input_white: 255,
+            gamma: 1.0,
+            output_black: 0,
+            output_white: 255,
+        }
+    }
+}
+
+impl LevelsChannel {
+    pub fn lut(&self) -> [u8; 256] {
+        let black = self.input_black as f32;
+        let range = (self.input_white as f32 - black).max(1.0);
+        let gamma = self.gamma.max(0.01);
+        let output_black = self.output_black as f32;
+        let output_range = self.output_white as f32 - output_black;
+
+        std::array::from_fn(|value| {
+            let t = ((value as f32 - black) / range).clamp(0.0, 1.0);
+            let t = t.powf(1.0 / gamma);
+            (output_black + t * output_range).round().clamp(0.0, 255.0) as u8
+        })
+    }
+
+    /// Sets the input points to the 0.5% and 99.5% percentiles of `channel`.
+    pub fn auto(&mut self, histogram: &Histogram, channel: Option<usize>) {
+        let black = histogram.percentile(channel, AUTO_LEVELS_CLIP);
+        let white = histogram.percentile(channel, 1.0 - AUTO_LEVELS_CLIP);
+        if black < white {
+            self.input_black = black;
+            self.input_white = white;
+        }
+    }
+}
+
+/// Fraction of the histogram clipped at each end by auto levels.
+const AUTO_LEVELS_CLIP: f32 = 0.005;
+
+/// Levels applied per channel, then to all three channels combined. Alpha is untouched.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Levels {
+    pub rgb: LevelsChannel,
+    pub channels: [LevelsChannel; 3],
+}
+
+impl Levels {
+    pub const NAME: &'static str = "Levels";
+
+    /// The combined lookup table for each of the red, green and blue channels.
+    pub fn luts(&self) -> [[u8; 256]; 3] {
+        let rgb = self.rgb.lut();
+        self.channels.map(|channel| {
+            let lut = channel.lut();
+            std::array::from_fn(|value| rgb[lut[value] as usize])
+        })
+    }
+
+    pub fn apply(&self, pixels: &mut [Color32]) {
+        let [red, green, blue] = self.luts();
+        for pixel in pixels.iter_mut() {
+            if pixel.a() == 0 {
+                continue;
+            }
+            let [r, g, b, a] = alpha::unpremultiply(*pixel);
+            *pixel = alpha::premultiply([red[r as usize], green[g as usize], blue[b as usize], a]);
+        }
+    }
+}
+
+/// Replaces one color with another, for recoloring flat artwork. Alpha is never touched, so
+/// edges anti-aliased against transparency keep their coverage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplaceColor {
+    /// Only the RGB of the two colors is used.
+    pub from: Color32,
+    pub to: Color32,
+    /// How far (0..=1, the largest difference of any channel) a pixel can be from `from`
+    /// and still count as that color.
+    pub tolerance: f32,
+}
+
+impl Default for ReplaceColor {
+    fn default() -> Self {
+        Self {
+            from: Color32::WHITE,
+            to: Color32::WHITE,
+            tolerance: 0.0,
+        }
+    }
+}
+
+impl ReplaceColor {
+    pub const NAME: &'static str = "Replace Color";
+
+    pub fn apply(&self, pixels: &mut [Color32], width: usize) {
+        if width == 0 {
+            return;
+        }
+        let height = pixels.len() / width;
+        let from = straight_rgb(self.from);
+        let to = straight_rgb(self.to);
+        let shift: [f32; 3] = std::array::from_fn(|c| to[c] - from[c]);
+
+        let matches: Vec<bool> = pixels
+            .iter()
+            .map(|pixel| pixel.a() > 0 && self.distance(straight_rgb(*pixel)) <= self.tolerance)
+            .collect();
+        let next_to_match = |x: usize, y: usize| {
+            (y.saturating_sub(1)..=(y + 1).min(height - 1)).any(|ny| {
+                (x.saturating_sub(1)..=(x + 1).min(width - 1)).any(|nx| matches[ny * width + nx])
+            })
+        };
+
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            if pixel.a() == 0 {
+                continue;
+            }
+            let rgb = straight_rgb(*pixel);
+            // pixels bordering the color may be anti-aliased blends of it and a neighbour,
+            // so they take as much of the new color as they had of the old one
+            let amount = if matches[index] {
+                1.0
+            } else if next_to_match(index % width, index / width) {
+                self.blend_amount(rgb)
+            } else {
+                continue;
+            };
+            if amount <= 0.0 {
+                continue;
+            }
+
+            let [r, g, b] = std::array::from_fn(|c| {
+                ((rgb[c] + shift[c] * amount).clamp(0.0, 1.0) * 255.0).round() as u8
+            });
+            *pixel = alpha::premultiply([r, g, b, pixel.a()]);
+        }
+    }
+
+    fn distance(&self, rgb: [f32; 3]) -> f32 {
+        let from = straight_rgb(self.from);
+        (0..3).map(|c| (rgb[c] - from[c]).abs()).fold(0.0, f32::max)
+    }
+
+    /// The largest fraction of `from` that `rgb` could be a blend of, whatever the other
+    /// color is: a pixel 60% `from` and 40% anything else gives at most 0.6.
+    pub fn blend_amount(&self, rgb: [f32; 3]) -> f32 {
+        let from = straight_rgb(self.from);
+        let other = (0..3)
+            .map(|c| {
+                if rgb[c] > from[c] {
+                    (rgb[c] - from[c]) / (1.0 - from[c])
+                } else if rgb[c] < from[c] {
+                    (from[c] - rgb[c]) / from[c]
+                } else {
+                    0.0
+                }
+            })
+            .fold(0.0, f32::max);
+        1.0 - other.clamp(0.0, 1.0)
+    }
+}
+
+/// The straight (unmultiplied) RGB of a pixel, in 0..=1.
+fn straight_rgb(pixel: Color32) -> [f32; 3] {
+    let [r, g, b, _] = alpha::unpremultiply(pixel);
+    [r, g, b].map(|c| c as f32 / 255.0)
+}
+
+/// Blurs by a gaussian that fades out at `radius` pixels, in premultiplied alpha so
+/// transparent pixels don't darken their neighbours. Pixels past the edge of the layer are
+/// taken to be the same as the edge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GaussianBlur {
+    /// How far each pixel spreads, in pixels. The gaussian's sigma is a third of it.
+    pub radius: f32,
+}
+
+impl Default for GaussianBlur {
+    fn default() -> Self {
+        Self { radius: 4.0 }
+    }
+}
+
+impl GaussianBlur {
+    pub const NAME: &'static str = "Gaussian Blur";
+    pub const RADIUS: RangeInclusive<f32> = 0.0..=50.0;
+    /// How many rows are blurred between checks for cancellation.
+    const CHUNK_ROWS: usize = 16;
+
+    /// The weights of the pixels from `reach` before to `reach` after, adding up to 1.
+    fn kernel(&self) -> Vec<f32> {
+        let radius = match self.radius.is_finite() {
+            true => self
+                .radius
+                .clamp(*Self::RADIUS.start(), *Self::RADIUS.end()),
+            false => 0.0,
+        };
+        let reach = radius.ceil() as i32;
+        if reach == 0 {
+            return vec![1.0];
+        }
+        let sigma = radius / 3.0;
+        let weights: Vec<f32> = (-reach..=reach)
+            .map(|offset| (-((offset * offset) as f32) / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let total: f32 = weights.iter().sum();
+        weights.into_iter().map(|weight| weight / total).collect()
+    }
+
+    /// Blurs `region` of `pixels`, first along the rows and then down the columns, giving up
+    /// with `None` once `task` is cancelled. The pixels are only written once it's done, so
+    /// giving up leaves them as they were.
+    fn blur(
+        &self,
+        pixels: &mut dyn PixelBuffer,
+        region: DirtyRect,
+        task: &TaskContext,
+    ) -> Option<DirtyRect> {
+        let full = DirtyRect::full(pixels.width(), pixels.height());
+        let region = region.intersect(full);
+        if region.is_empty() {
+            return Some(DirtyRect::default());
+        }
+        let kernel = self.kernel();
+        let reach = kernel.len() / 2;
+        let (width, height) = (full.width as usize, full.height as usize);
+        let (left, right) = (region.x as usize, (region.x + region.width) as usize);
+        let (top, bottom) = (region.y as usize, (region.y + region.height) as usize);
+        // the columns need the rows within reach of the region, as far as there are any
+        let (first_row, last_row) = (top.saturating_sub(reach), (bottom + reach).min(height));
+        let columns = right - left;
+        let total = (last_row - first_row + bottom - top) as f32;
+        let source = pixels.pixels();
+
+        let mut across = vec![[0.0f32; 4]; columns * (last_row - first_row)];
+        for (chunk, rows) in across.chunks_mut(columns * Self::CHUNK_ROWS).enumerate() {
+            if task.is_cancelled() {
+                return None;
+            }
+            for (row, blurred) in rows.chunks_exact_mut(columns).enumerate() {
+                let y = first_row + chunk * Self::CHUNK_ROWS + row;
+                let line = &source[y * width..(y + 1) * width];
+                for (x, sum) in (left..right).zip(blurred) {
+                    for (offset, weight) in kernel.iter().enumerate() {
+                        let sample = (x + offset).saturating_sub(reach).min(width - 1);
+                        let color = line[sample].to_array();
+                        for c in 0..4 {
+                            sum[c] += color[c] as f32 * weight;
+                        }
+                    }
+                }
+            }
+            let done = ((chunk + 1) * Self::CHUNK_ROWS).min(last_row - first_row);
+            task.report(done as f32 / total, "Blurring rows");
+        }
+
+        let mut blurred = vec![Color32::TRANSPARENT; columns * (bottom - top)];
+        for (chunk, rows) in blurred.chunks_mut(columns * Self::CHUNK_ROWS).enumerate() {
+            if task.is_cancelled() {
+                return None;
+            }
+            for (row, line) in rows.chunks_exact_mut(columns).enumerate() {
+                let y = top + chunk * Self::CHUNK_ROWS + row;
+                for (column, pixel) in line.iter_mut().enumerate() {
+                    let mut sum = [0.0f32; 4];
+                    for (offset, weight) in kernel.iter().enumerate() {
+                        let sample = (y + offset).saturating_sub(reach).min(height - 1);
+                        let color = across[(sample - first_row) * columns + column];
+                        for c in 0..4 {
+                            sum[c] += color[c] * weight;
+                        }
+                    }
+                    let [r, g, b, a] = sum.map(|channel| channel.round().clamp(0.0, 255.0) as u8);
+                    *pixel = Color32::from_rgba_premultiplied(r, g, b, a);
+                }
+            }
+            let done = (last_row - first_row) + ((chunk + 1) * Self::CHUNK_ROWS).min(bottom - top);
+            task.report(done as f32 / total, "Blurring columns");
+        }
+        pixels.write_rect(region, &blurred);
+        Some(region)
+    }
+}
+
+impl Filter for GaussianBlur {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn apply(
+        &self,
+        pixels: &mut dyn PixelBuffer,
+        region: Option<DirtyRect>,
+        _mask: Option<&SelectionMask>,
+    ) -> DirtyRect {
+        let region = region.unwrap_or(DirtyRect::full(pixels.width(), pixels.height()));
+        self.blur(pixels, region, &TaskContext::detached())
+            .unwrap_or_default()
+    }
+
+    #[cfg(feature = "gui")]
+    fn params_ui(&mut self, ui: &mut egui::Ui) -> bool {
+        ui.add(egui::Slider::new(&mut self.radius, Self::RADIUS).text("Radius"))
+            .changed()
+    }
+
+    fn background(&self) -> Option<Box<dyn BackgroundFilter>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+impl BackgroundFilter for GaussianBlur {
+    fn apply_in_task(
+        &self,
+        pixels: &mut dyn PixelBuffer,
+        region: Option<DirtyRect>,
+        _mask: Option<&SelectionMask>,
+        task: &TaskContext,
+    ) -> Option<DirtyRect> {
+        let region = region.unwrap_or(DirtyRect::full(pixels.width(), pixels.height()));
+        self.blur(pixels, region, task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mid_grey_only_responds_to_the_midtones() {
+        let grey = [0.5; 3];
+        let mut balance = ColorBalance {
+            shadows: [0.3, -0.2, 0.1],
+            highlights: [-0.1, 0.4, -0.3],
+            ..Default::default()
+        };
+        assert_eq!(balance.apply_rgb(grey), grey);
+        balance.midtones = [0.2, 0.0, -0.2];
+        let [r, g, b] = balance.apply_rgb(grey);
+        assert!((r - 0.7).abs() < 1e-6 && g == 0.5 && (b - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn preserving_luminosity_keeps_the_luma() {
+        let balance = ColorBalance {
+            shadows: [0.1, 0.0, -0.1],
+            midtones: [-0.15, 0.05, 0.2],
+            highlights: [0.0, -0.1, 0.1],
+            preserve_luminosity: true,
+        };
+        for rgb in [
+            [0.2, 0.3, 0.4],
+            [0.5, 0.45, 0.3],
+            [0.7, 0.6, 0.65],
+            [0.4; 3],
+        ] {
+            let out = balance.apply_rgb(rgb);
+            assert_ne!(out, rgb);
+            assert!(
+                (luma(out) - luma(rgb)).abs() < 0.005,
+                "{rgb:?} became {out:?}"
+            );
+        }
+
+        // and leaves alpha alone
+        let mut pixels = [alpha::premultiply([120, 90, 60, 130])];
+        balance.apply(&mut pixels);
+        assert_eq!(pixels[0].a(), 130);
+    }
+
+    #[test]
+    fn default_levels_leave_every_value_alone() {
+        let identity: [u8; 256] = std::array::from_fn(|value| value as u8);
+        assert_eq!(LevelsChannel::default().lut(), identity);
+        assert_eq!(Levels::default().luts(), [identity; 3]);
+    }
+
+    #[test]
+    fn levels_stretch_bend_and_compress() {
+        let lut = LevelsChannel {
+            input_black: 50,
+            input_white: 150,
+            gamma: 2.0,
+            output_black: 20,
+            output_white: 220,
+        }
+        .lut();
+        // clipped below and above the input points
+        assert_eq!((lut[0], lut[50], lut[150], lut[255]), (20, 20, 220, 220));
+        // halfway in comes out at the square root of halfway, between the output points
+        assert_eq!(lut[100], (20.0 + 0.5f32.sqrt() * 200.0).round() as u8);
+        assert!(lut.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn auto_levels_stretch_to_the_percentiles() {
+        // mostly 40 to 200, with a few stray pixels at either end
+        let mut pixels: Vec<Color32> = (0..1000)
+            .map(|i| {
+                let value = 40 + (i % 161) as u8;
+                Color32::from_rgb(value, value, value)
+            })
+            .collect();
+        pixels[0] = Color32::BLACK;
+        pixels[1] = Color32::WHITE;
+        let mut channel = LevelsChannel::default();
+        channel.auto(&Histogram::from_pixels(&pixels), None);
+        assert_eq!((channel.input_black, channel.input_white), (40, 200));
+    }
+
+    #[test]
+    fn replacing_a_color_matches_it_exactly_or_within_tolerance() {
+        let red = Color32::from_rgb(200, 40, 40);
+        let near = Color32::from_rgb(210, 40, 30);
+        let green = Color32::from_rgb(40, 160, 60);
+        let mut replace = ReplaceColor {
+            from: red,
+            to: Color32::from_rgb(30, 60, 220),
+            tolerance: 0.0,
+        };
+        // only the first green borders the match, and could be a blend with it
+        let mut pixels = [red, green, green, near];
+        replace.apply(&mut pixels, 4);
+        assert_eq!(pixels[0], replace.to);
+        assert_eq!(pixels[2..], [green, near]);
+
+        // within tolerance, including a faint pixel whose color premultiplying rounded off
+        let faint = alpha::premultiply([200, 40, 40, 90]);
+        replace.tolerance = 0.05;
+        let mut pixels = [near, green, green, green, faint];
+        replace.apply(&mut pixels, 5);
+        // shifted as far as the color it matched, so it stays as different from the rest
+        assert_eq!(pixels[0], Color32::from_rgb(40, 60, 210));
+        assert_eq!(pixels[2], green);
+        // the faint pixel is recolored too, keeping its alpha
+        assert_eq!(alpha::unpremultiply(pixels[4])[3], 90);
+        assert_ne!(pixels[4], faint);
+    }
+
+    #[test]
+    fn anti_aliased_edges_take_the_new_color_in_proportion() {
+        let (red, white) = ([1.0, 0.0, 0.0], [1.0; 3]);
+        let mix = |amount: f32, a: [f32; 3], b: [f32; 3]| -> Color32 {
+            let [r, g, b] = std::array::from_fn(|c| {
+                ((a[c] * amount + b[c] * (1.0 - amount)) * 255.0).round() as u8
+            });
+            Color32::from_rgb(r, g, b)
+        };
+        let blue = [0.0, 0.0, 1.0];
+        let replace = ReplaceColor {
+            from: Color32::RED,
+            to: Color32::BLUE,
+            tolerance: 0.0,
+        };
+        // red, then 60% red on white next to it, then white
+        let mut pixels = [Color32::RED, mix(0.6, red, white), Color32::WHITE];
+        replace.apply(&mut pixels, 3);
+        assert_eq!(pixels[0], Color32::BLUE);
+        let expected = mix(0.6, blue, white);
+        for c in 0..3 {
+            assert!(pixels[1][c].abs_diff(expected[c]) <= 1, "{:?}", pixels[1]);
+        }
+        // white has none of the old color in it
+        assert_eq!(pixels[2], Color32::WHITE);
+    }
+}
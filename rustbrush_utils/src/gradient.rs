@@ -0,0 +1,360 @@
+use ecolor::{gamma_from_linear, Color32, Rgba};
+
+use crate::pixel_buffer::{DirtyRect, PixelBuffer};
+use crate::selection::SelectionMask;
+
+/// A 4 by 4 ordered dither, the order pixels of each 4 by 4 block round up in.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// How a gradient spreads out from its start point.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GradientShape {
+    /// In bands at right angles to the line from the start to the end.
+    #[default]
+    Linear,
+    /// In rings around the start, reaching the end color at the end point's distance.
+    Radial,
+}
+
+/// What a gradient does past its end, and before its start.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GradientRepeat {
+    /// Carries on with the first and last colors.
+    #[default]
+    Clamp,
+    /// Starts over from the first color.
+    Repeat,
+    /// Runs back to the first color, then forwards again.
+    Mirror,
+}
+
+impl GradientRepeat {
+    /// Where `t`, a position along the gradient, falls between its start at 0 and its end
+    /// at 1.
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            GradientRepeat::Clamp => t.clamp(0.0, 1.0),
+            GradientRepeat::Repeat => t.rem_euclid(1.0),
+            GradientRepeat::Mirror => 1.0 - (t.rem_euclid(2.0) - 1.0).abs(),
+        }
+    }
+}
+
+/// A color along a gradient, straight and linear like brush colors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GradientStop {
+    /// Where along the gradient the color is, from 0 at its start to 1 at its end.
+    pub position: f32,
+    pub color: Rgba,
+}
+
+impl GradientStop {
+    pub fn new(position: f32, color: Rgba) -> Self {
+        Self { position, color }
+    }
+}
+
+/// The color at `t` along a gradient through `stops`, which are in order of position,
+/// premultiplied. Colors are blended in linear light and premultiplied, so a stop fading to
+/// transparent doesn't darken on the way. Before the first stop it's the first color and after the
+/// last the last, and with no stops it's transparent.
+pub fn sample(stops: &[GradientStop], t: f32) -> Rgba {
+    let premultiplied = |stop: &GradientStop| {
+        let color = stop.color;
+        Rgba::from_rgba_unmultiplied(color.r(), color.g(), color.b(), color.a())
+    };
+    let Some(after) = stops.iter().position(|stop| stop.position > t) else {
+        return stops.last().map_or(Rgba::TRANSPARENT, premultiplied);
+    };
+    if after == 0 {
+        return premultiplied(&stops[0]);
+    }
+    let (from, to) = (&stops[after - 1], &stops[after]);
+    let span = to.position - from.position;
+    let mix = match span > 0.0 {
+        true => (t - from.position) / span,
+        false => 1.0,
+    };
+    premultiplied(from) * (1.0 - mix) + premultiplied(to) * mix
+}
+
+/// Fills a buffer with a gradient from `start` to `end`, in the buffer's pixels.
+pub struct GradientOperation<'a> {
+    pub pixels: &'a mut dyn PixelBuffer,
+    pub start: (f32, f32),
+    pub end: (f32, f32),
+    /// The colors along the gradient, in order of position, see [`sample`].
+    pub stops: &'a [GradientStop],
+    pub shape: GradientShape,
+    pub repeat: GradientRepeat,
+    /// Whether the gradient replaces what's there, or is laid over it.
+    pub replace: bool,
+    /// Rounds each pixel to 8 bits up or down in an ordered pattern rather than to the
+    /// nearest, which breaks up the bands a slow gradient would otherwise show.
+    pub dither: bool,
+    /// Limits the gradient to the selection, which also softens it where partly selected.
+    pub selection: Option<&'a SelectionMask>,
+}
+
+impl GradientOperation<'_> {
+    /// Draws the gradient, returning the rectangle it covers. Does nothing without stops.
+    pub fn process(self) -> DirtyRect {
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        if self.stops.is_empty() {
+            return DirtyRect::default();
+        }
+        let (dx, dy) = (self.end.0 - self.start.0, self.end.1 - self.start.1);
+        let length_squared = dx * dx + dy * dy;
+
+        let row = width as usize;
+        for (index, pixel) in self.pixels.pixels_mut().iter_mut().enumerate() {
+            let coverage = self
+                .selection
+                .map_or(1.0, |mask| mask.values()[index] as f32 / 255.0);
+            if coverage <= 0.0 {
+                continue;
+            }
+            let (x, y) = (index % row, index / row);
+            // from the pixel's center
+            let (px, py) = (x as f32 + 0.5 - self.start.0, y as f32 + 0.5 - self.start.1);
+            // with the end on the start there's no way to go, and it's all past the end
+            let t = match (self.shape, length_squared > 0.0) {
+                (_, false) => 1.0,
+                (GradientShape::Linear, true) => (px * dx + py * dy) / length_squared,
+                (GradientShape::Radial, true) => ((px * px + py * py) / length_squared).sqrt(),
+            };
+            let color = sample(self.stops, self.repeat.apply(t));
+            let below = Rgba::from(*pixel);
+            let color = match self.replace {
+                true => color * coverage + below * (1.0 - coverage),
+                false => color * coverage + below * (1.0 - color.a() * coverage),
+            };
+            let threshold = match self.dither {
+                true => (BAYER[y % 4][x % 4] as f32 + 0.5) / 16.0,
+                false => 0.5,
+            };
+            *pixel = quantize(color, threshold);
+        }
+        match self.selection {
+            Some(selection) => selection.bounds(),
+            None => DirtyRect::full(width, height),
+        }
+    }
+}
+
+/// `color`, premultiplied, in 8 bits, with each channel rounded up from `threshold` of the
+/// way to the next level rather than from halfway. A color that rounds to no alpha at all is
+/// transparent.
+fn quantize(color: Rgba, threshold: f32) -> Color32 {
+    let level = |value: f32| (value * 255.0 + 1.0 - threshold).floor().clamp(0.0, 255.0) as u8;
+    let alpha = level(color.a());
+    if alpha == 0 {
+        return Color32::TRANSPARENT;
+    }
+    let [r, g, b] = [color.r(), color.g(), color.b()].map(|c| level(gamma_from_linear(c)));
+    Color32::from_rgba_premultiplied(r, g, b, alpha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alpha;
+    use crate::pixel_buffer::PixelSlice;
+
+    const BLACK_TO_WHITE: [GradientStop; 2] = [
+        GradientStop {
+            position: 0.0,
+            color: Rgba::BLACK,
+        },
+        GradientStop {
+            position: 1.0,
+            color: Rgba::WHITE,
+        },
+    ];
+
+    /// `pixels` of a `width` buffer with a gradient through `stops` drawn over them,
+    /// across the middle of the first and last columns.
+    fn draw(
+        pixels: &mut [Color32],
+        width: u32,
+        stops: &[GradientStop],
+        shape: GradientShape,
+        repeat: GradientRepeat,
+        replace: bool,
+        dither: bool,
+    ) -> DirtyRect {
+        let height = pixels.len() as u32 / width;
+        GradientOperation {
+            pixels: &mut PixelSlice::new(pixels, width, height),
+            start: (0.5, 0.5),
+            end: (width as f32 - 0.5, 0.5),
+            stops,
+            shape,
+            repeat,
+            replace,
+            dither,
+            selection: None,
+        }
+        .process()
+    }
+
+    fn linear(width: u32, stops: &[GradientStop]) -> Vec<Color32> {
+        let mut pixels = vec![Color32::TRANSPARENT; width as usize];
+        let (shape, repeat) = (GradientShape::Linear, GradientRepeat::Clamp);
+        draw(&mut pixels, width, stops, shape, repeat, true, false);
+        pixels
+    }
+
+    #[test]
+    fn the_ends_are_the_end_colors_and_the_middle_is_halfway_in_linear_light() {
+        let pixels = linear(65, &BLACK_TO_WHITE);
+        assert_eq!(pixels[0], Color32::BLACK);
+        assert_eq!(pixels[64], Color32::WHITE);
+        assert_eq!(pixels[32], Color32::from(Rgba::from_gray(0.5)));
+
+        // stops in between, with the ends carried on past the first and last
+        let stops = [
+            GradientStop::new(0.25, Rgba::from_rgb(1.0, 0.0, 0.0)),
+            GradientStop::new(0.75, Rgba::from_rgb(0.0, 0.0, 1.0)),
+        ];
+        let pixels = linear(65, &stops);
+        assert_eq!(pixels[0], Color32::RED);
+        assert_eq!(pixels[16], Color32::RED);
+        assert_eq!(pixels[48], Color32::BLUE);
+        assert_eq!(pixels[64], Color32::BLUE);
+        assert_eq!(pixels[32], Color32::from(Rgba::from_rgb(0.5, 0.0, 0.5)));
+    }
+
+    #[test]
+    fn past_the_end_it_clamps_repeats_or_mirrors() {
+        let ts = [-0.25, 0.25, 1.0, 1.25, 2.5];
+        let wrapped = |repeat: GradientRepeat| ts.map(|t| repeat.apply(t));
+        assert_eq!(wrapped(GradientRepeat::Clamp), [0.0, 0.25, 1.0, 1.0, 1.0]);
+        assert_eq!(
+            wrapped(GradientRepeat::Repeat),
+            [0.75, 0.25, 0.0, 0.25, 0.5]
+        );
+        assert_eq!(
+            wrapped(GradientRepeat::Mirror),
+            [0.25, 0.25, 1.0, 0.75, 0.5]
+        );
+    }
+
+    #[test]
+    fn a_radial_gradient_reaches_the_end_color_at_the_end_points_distance() {
+        let mut pixels = vec![Color32::TRANSPARENT; 9 * 9];
+        GradientOperation {
+            pixels: &mut PixelSlice::new(&mut pixels, 9, 9),
+            start: (4.5, 4.5),
+            end: (8.5, 4.5),
+            stops: &BLACK_TO_WHITE,
+            shape: GradientShape::Radial,
+            repeat: GradientRepeat::Clamp,
+            replace: true,
+            dither: false,
+            selection: None,
+        }
+        .process();
+        assert_eq!(pixels[4 * 9 + 4], Color32::BLACK);
+        for (x, y) in [(8, 4), (0, 4), (4, 0), (4, 8)] {
+            assert_eq!(pixels[y * 9 + x], Color32::WHITE, "at {x}, {y}");
+        }
+        assert_eq!(pixels[2 * 9 + 4], pixels[4 * 9 + 2]);
+    }
+
+    #[test]
+    fn fading_to_transparent_keeps_the_color() {
+        let stops = [
+            GradientStop::new(0.0, Rgba::from_rgb(1.0, 0.0, 0.0)),
+            GradientStop::new(1.0, Rgba::from_rgba_unmultiplied(1.0, 0.0, 0.0, 0.0)),
+        ];
+        let pixels = linear(64, &stops);
+        assert_eq!(pixels[63], Color32::TRANSPARENT);
+        for pixel in pixels.iter().filter(|pixel| pixel.a() >= 16) {
+            let [r, g, b, _] = alpha::unpremultiply(*pixel);
+            assert!(r >= 250 && g == 0 && b == 0, "{pixel:?}");
+        }
+    }
+
+    #[test]
+    fn laid_over_keeps_what_shows_through_and_replacing_does_not() {
+        let stops = [GradientStop::new(
+            0.0,
+            Rgba::from_rgba_unmultiplied(1.0, 0.0, 0.0, 0.5),
+        )];
+        let (shape, repeat) = (GradientShape::Linear, GradientRepeat::Clamp);
+        let mut over = vec![Color32::BLUE; 4];
+        draw(&mut over, 4, &stops, shape, repeat, false, false);
+        let mut replaced = vec![Color32::BLUE; 4];
+        draw(&mut replaced, 4, &stops, shape, repeat, true, false);
+        assert_eq!(over[0].a(), 255);
+        assert!(over[0].b() > 0 && over[0].r() > 0);
+        assert_eq!(replaced[0].a(), 128);
+        assert_eq!(replaced[0].b(), 0);
+
+        // nothing to draw
+        let mut untouched = vec![Color32::BLUE; 4];
+        let dirty = draw(&mut untouched, 4, &[], shape, repeat, true, false);
+        assert!(dirty.is_empty() && untouched == [Color32::BLUE; 4]);
+    }
+
+    #[test]
+    fn the_selection_limits_and_softens_the_gradient() {
+        let white = [GradientStop::new(0.0, Rgba::WHITE)];
+        let draw = |selection: &SelectionMask| {
+            let mut pixels = vec![Color32::TRANSPARENT; 8];
+            let dirty = GradientOperation {
+                pixels: &mut PixelSlice::new(&mut pixels, 8, 1),
+                start: (0.0, 0.0),
+                end: (8.0, 0.0),
+                stops: &white,
+                shape: GradientShape::Linear,
+                repeat: GradientRepeat::Clamp,
+                replace: true,
+                dither: false,
+                selection: Some(selection),
+            }
+            .process();
+            (pixels, dirty)
+        };
+        let (pixels, dirty) = draw(&SelectionMask::from_rect(8, 1, DirtyRect::new(0, 0, 4, 1)));
+        assert_eq!(dirty, DirtyRect::new(0, 0, 4, 1));
+        assert!(pixels[..4].iter().all(|&pixel| pixel == Color32::WHITE));
+        assert!(pixels[4..]
+            .iter()
+            .all(|&pixel| pixel == Color32::TRANSPARENT));
+
+        // half of a pixel selected
+        let half = [(4.0, 0.0), (4.5, 0.0), (4.5, 1.0), (4.0, 1.0)];
+        let (pixels, _) = draw(&SelectionMask::from_polygon(8, 1, &half));
+        assert_eq!(pixels[4].a(), 128);
+    }
+
+    #[test]
+    fn dithering_breaks_up_the_bands_and_keeps_the_mean() {
+        // five levels of gray spread over 128 pixels, in eight rows
+        let stops = [
+            GradientStop::new(0.0, Rgba::from_gray(0.2)),
+            GradientStop::new(1.0, Rgba::from_gray(0.2 + 5.0 / 255.0)),
+        ];
+        let (shape, repeat) = (GradientShape::Linear, GradientRepeat::Clamp);
+        let gradient = |dither: bool| {
+            let mut pixels = vec![Color32::TRANSPARENT; 128 * 8];
+            draw(&mut pixels, 128, &stops, shape, repeat, true, dither);
+            pixels
+        };
+        let (banded, dithered) = (gradient(false), gradient(true));
+        let changes = |pixels: &[Color32]| {
+            let row = &pixels[..128];
+            row.windows(2).filter(|pair| pair[0] != pair[1]).count()
+        };
+        assert!(changes(&banded) <= 6);
+        assert!(changes(&dithered) > 30);
+
+        let mean = |pixels: &[Color32]| {
+            let levels = pixels.iter().map(|&pixel| pixel.r() as f32);
+            levels.sum::<f32>() / pixels.len() as f32
+        };
+        assert!((mean(&dithered) - mean(&banded)).abs() < 0.25);
+    }
+}
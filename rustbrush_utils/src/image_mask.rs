@@ -0,0 +1,193 @@
+use std::io::Cursor;
+
+use png::{ColorType, Decoder, Transformations};
+
+/// A grayscale image used as a brush tip, such as a splatter, a leaf or a chalk texture.
+/// Each value is how much of the stroke color lands there, from 0 to 1, row by row.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ImageMask {
+    width: u32,
+    height: u32,
+    alpha: Vec<f32>,
+}
+
+/// A mask is only loaded if it has a value for every pixel, rather than failing
+/// later when it's sampled.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ImageMask {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Fields {
+            width: u32,
+            height: u32,
+            alpha: Vec<f32>,
+        }
+        let Fields {
+            width,
+            height,
+            alpha,
+        } = Fields::deserialize(deserializer)?;
+        if alpha.len() != width as usize * height as usize {
+            return Err(serde::de::Error::custom(format!(
+                "a {}x{} mask needs {} values, not {}",
+                width,
+                height,
+                width as usize * height as usize,
+                alpha.len()
+            )));
+        }
+        Ok(Self::new(width, height, alpha))
+    }
+}
+
+impl ImageMask {
+    /// Panics if there aren't `width * height` values.
+    pub fn new(width: u32, height: u32, alpha: Vec<f32>) -> Self {
+        assert_eq!(alpha.len(), width as usize * height as usize);
+        Self {
+            width,
+            height,
+            alpha,
+        }
+    }
+
+    /// Reads a mask from a PNG. Images with transparency are masked by their alpha, so a
+    /// shape cut out of a transparent background paints where the shape is. Opaque images
+    /// are masked by how dark they are, so black ink on white paper paints where the ink is.
+    pub fn from_png(png: &[u8]) -> Result<Self, png::DecodingError> {
+        let mut decoder = Decoder::new(Cursor::new(png));
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size().unwrap_or_default()];
+        let info = reader.next_frame(&mut buffer)?;
+        let (width, height) = (info.width, info.height);
+
+        let darkness = |value: u8| 1.0 - value as f32 / 255.0;
+        let opacity = |value: u8| value as f32 / 255.0;
+        let rows = buffer[..info.buffer_size()].chunks_exact(info.line_size);
+        let channels = info.color_type.samples();
+        let alpha = rows
+            .flat_map(|row| row[..width as usize * channels].chunks_exact(channels))
+            .map(|pixel| match info.color_type {
+                ColorType::GrayscaleAlpha => opacity(pixel[1]),
+                ColorType::Rgba => opacity(pixel[3]),
+                ColorType::Rgb => {
+                    darkness(((pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3) as u8)
+                }
+                ColorType::Grayscale | ColorType::Indexed => darkness(pixel[0]),
+            })
+            .collect();
+        Ok(Self::new(width, height, alpha))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The mask resized to `width` by `height` with bilinear sampling. Shrinking by a whole
+    /// factor of 2 averages each 2x2 block exactly.
+    pub fn scaled(&self, width: u32, height: u32) -> Vec<f32> {
+        let (step_x, step_y) = (
+            self.width as f32 / width as f32,
+            self.height as f32 / height as f32,
+        );
+        (0..height)
+            .flat_map(|y| {
+                (0..width).map(move |x| {
+                    // sample at the source position of the target pixel's center
+                    self.sample(
+                        (x as f32 + 0.5) * step_x - 0.5,
+                        (y as f32 + 0.5) * step_y - 0.5,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// The bilinearly interpolated value at pixel position `(x, y)`, where whole numbers are
+    /// pixel centers. Positions past the edges take the edge value.
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let max_x = self.width as f32 - 1.0;
+        let max_y = self.height as f32 - 1.0;
+        let (x, y) = (x.clamp(0.0, max_x), y.clamp(0.0, max_y));
+        let (x0, y0) = (x.floor(), y.floor());
+        let (x1, y1) = ((x0 + 1.0).min(max_x), (y0 + 1.0).min(max_y));
+        let (tx, ty) = (x - x0, y - y0);
+        let at = |x: f32, y: f32| self.alpha[y as usize * self.width as usize + x as usize];
+        let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+        let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Brush;
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-5,
+                "{actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    /// A grayscale PNG of `width` by `height` pixels.
+    fn grayscale_png(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(pixels).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    #[test]
+    fn halving_averages_each_two_by_two_block() {
+        let rows = [
+            [0.0, 0.2, 0.4, 0.6],
+            [0.8, 1.0, 0.2, 0.4],
+            [1.0, 1.0, 0.0, 0.0],
+            [0.0, 0.6, 0.0, 0.4],
+        ];
+        let mask = ImageMask::new(4, 4, rows.concat());
+        assert_close(&mask.scaled(2, 2), &[0.5, 0.4, 0.65, 0.1]);
+    }
+
+    #[test]
+    fn halving_a_wide_mask_keeps_its_aspect() {
+        let rows = [
+            [0.0, 0.2, 0.4, 0.6, 1.0, 1.0],
+            [0.8, 1.0, 0.2, 0.4, 0.0, 1.0],
+        ];
+        let mask = ImageMask::new(6, 2, rows.concat());
+        assert_close(&mask.scaled(3, 1), &[0.5, 0.4, 0.75]);
+    }
+
+    #[test]
+    fn an_opaque_png_is_masked_by_its_darkness() {
+        let png = grayscale_png(4, 2, &[255, 204, 153, 102, 51, 0, 204, 153]);
+        let mask = ImageMask::from_png(&png).unwrap();
+        assert_eq!((mask.width(), mask.height()), (4, 2));
+        assert_close(&mask.alpha, &[0.0, 0.2, 0.4, 0.6, 0.8, 1.0, 0.2, 0.4]);
+
+        // a radius of 1 fits the longer side into 2 pixels, halving the mask
+        let stamp = Brush::from_image_mask(&png)
+            .unwrap()
+            .with_radius(1.0)
+            .compute_stamp();
+        assert_eq!((stamp.width, stamp.height), (2, 1));
+        assert_eq!((stamp.left, stamp.top), (-1, 0));
+        assert_close(&stamp.alpha, &[0.5, 0.4]);
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// How many sizes a dab of a size-jittered brush comes in, from the full size down to the
+/// smallest the jitter allows. Each size has its own stamp, so the sizes are kept to a few
+/// that can all stay in the [`crate::stamp_cache::StampCache`].
+pub const SIZE_JITTER_STEPS: usize = 8;
+
+/// The random numbers a stroke's dabs are jittered with. It's seeded once per stroke, and
+/// the seed is kept with the stroke, so replaying the stroke draws the same numbers in the
+/// same order and jitters every dab the same way.
+#[derive(Clone, Debug)]
+pub struct StrokeRng {
+    state: u64,
+}
+
+impl StrokeRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// A seed for a new stroke, different every time.
+    pub fn seed() -> u64 {
+        RandomState::new().build_hasher().finish()
+    }
+
+    /// The next number, evenly spread from 0 up to but not including 1.
+    pub fn next_f32(&mut self) -> f32 {
+        // splitmix64
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 40) as f32 / (1u32 << 24) as f32
+    }
+}
@@ -1,6 +1,40 @@
 pub use ecolor::{Color32, Rgba};
 
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use falloff::FalloffCurve;
+use image_mask::ImageMask;
+use stroke::{FadeTail, StrokeAccumulation};
+
+pub mod alpha;
+pub mod canvas;
+pub mod color_profile;
+pub mod composite;
+pub mod falloff;
+pub mod fill;
+pub mod filter_registry;
+pub mod filters;
+pub mod gradient;
+pub mod image_mask;
+pub mod jitter;
+#[cfg(feature = "serde")]
+pub mod library;
 pub mod operations;
+pub mod path;
+pub mod pixel_buffer;
+pub mod presets;
+pub mod resample;
+pub mod sampling;
+pub mod selection;
+pub mod shape;
+pub mod stamp_cache;
+pub mod stats;
+pub mod stroke;
+pub mod symmetry;
+pub mod task;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
 
 pub const RED_CHANNEL: usize = 0;
 pub const GREEN_CHANNEL: usize = 1;
@@ -14,46 +48,427 @@ pub struct Pixel {
     pub color: Rgba,
 }
 
-/// A stamp is a collection of pixels that represent a brush shape.
+/// A stamp is the shape of a brush: how much of a dab lands on each pixel around its
+/// center, as a dense mask over the rectangle the shape covers.
 pub struct Stamp {
-    pub pixels: Vec<Pixel>,
+    /// Where the mask's top left pixel is, relative to the dab's center.
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+    /// Coverage from 0 to 1, row by row. Pixels the shape doesn't reach are 0.
+    pub alpha: Vec<f32>,
 }
 
-#[derive(Clone)]
+impl Stamp {
+    /// A `width` by `height` stamp with its top left pixel at `(left, top)` from the
+    /// center, taking the coverage of each pixel from `alpha(x, y)`, relative to the center.
+    fn from_fn(
+        left: i32,
+        top: i32,
+        width: u32,
+        height: u32,
+        alpha: impl Fn(i32, i32) -> f32,
+    ) -> Self {
+        let alpha = (top..top + height as i32)
+            .flat_map(|y| (left..left + width as i32).map(move |x| (x, y)))
+            .map(|(x, y)| alpha(x, y))
+            .collect();
+        Self {
+            left,
+            top,
+            width,
+            height,
+            alpha,
+        }
+    }
+
+    /// A stamp reaching `reach_x` pixels either side of the center and `reach_y` above and
+    /// below it.
+    fn centered(reach_x: i32, reach_y: i32, alpha: impl Fn(i32, i32) -> f32) -> Self {
+        let (width, height) = ((reach_x * 2 + 1) as u32, (reach_y * 2 + 1) as u32);
+        Self::from_fn(-reach_x, -reach_y, width, height, alpha)
+    }
+
+    /// The coverage of the pixel at `(x, y)` from the center, which is 0 past the mask.
+    pub fn alpha_at(&self, x: i32, y: i32) -> f32 {
+        let (column, row) = (x - self.left, y - self.top);
+        if !(0..self.width as i32).contains(&column) || !(0..self.height as i32).contains(&row) {
+            return 0.0;
+        }
+        self.alpha[row as usize * self.width as usize + column as usize]
+    }
+
+    /// The rows of the mask, each with its offset from the center.
+    pub fn rows(&self) -> impl Iterator<Item = (i32, &[f32])> {
+        (self.top..).zip(self.alpha.chunks_exact(self.width.max(1) as usize))
+    }
+
+    /// The pixels the stamp covers at all, one at a time, for code that wants them that way
+    /// rather than row by row.
+    pub fn pixels(&self) -> impl Iterator<Item = Pixel> + '_ {
+        self.rows().flat_map(move |(y, row)| {
+            (self.left..)
+                .zip(row)
+                .filter(|(_, &alpha)| alpha > 0.0)
+                .map(move |(x, &alpha)| Pixel {
+                    x,
+                    y,
+                    color: Rgba::WHITE.set_alpha(alpha),
+                })
+        })
+    }
+}
+
+/// Settings every kind of brush has. Loaded from a preset, any that are missing are the
+/// default brush's.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct BrushBaseSettings {
     pub id: String,
     pub radius: f32,
+    /// How far apart dabs are along a stroke, as a fraction of the radius. Soft tips need
+    /// their dabs to overlap well, or strokes come out as a string of beads.
     pub spacing: f32,
+    /// How much of the color a single dab lays down, the brush's flow. Overlapping dabs
+    /// build up past it.
     pub strength: f32,
+    /// The most a single stroke lays down anywhere, however many of its dabs overlap, as a
+    /// fraction of the color's alpha. Only one stroke at a time is capped; the next stroke
+    /// builds up on top of it.
+    pub opacity: f32,
+    pub accumulation: StrokeAccumulation,
+    pub fade_tail: Option<FadeTail>,
+    /// Airbrush mode: while the cursor is held still, paint keeps building up at this many
+    /// dabs' worth per second, see [`operations::PaintOperation::elapsed`].
+    pub flow_per_second: Option<f32>,
+    /// The least time between two dabs of a moving stroke, in seconds, on top of the
+    /// spacing. Meant for airbrush brushes, so a fast stroke lays paint down thinner than a
+    /// slow one the way it would from a can. See [`stroke::StrokeState`].
+    pub min_dab_interval: Option<f32>,
+    /// Pixel art mode: dabs land on whole pixels with a hard square footprint, see
+    /// [`Brush::compute_stamp`].
+    pub pixel_snap: bool,
+    /// Paint strokes that would go straight onto the layer through a floating-point copy
+    /// of the pixels they cover instead, rounded to the layer's 8 bits as each dab lands.
+    /// Faint dabs then build up smoothly rather than in the steps of the 8-bit pixels, at
+    /// the cost of the copy, which only covers the tiles the stroke touches. See
+    /// [`stroke::StrokeBuffer::color`].
+    pub float_buffer: bool,
+    /// How much the size of each painted or erased dab varies at random, from 0 for not at
+    /// all up to 1 for anywhere from the full size down to nothing. See
+    /// [`jitter::StrokeRng`] for how it stays the same when the stroke is replayed.
+    pub size_jitter: f32,
+    /// How much the opacity of each painted or erased dab varies at random, likewise.
+    pub opacity_jitter: f32,
+    /// Turn each dab to face the way the stroke is heading. The tip's own angle is then
+    /// relative to the stroke, so an ellipse at angle 0 lies along it. The stroke's first
+    /// dab lands before it's heading anywhere, so it keeps the tip's own angle. Only
+    /// elliptical tips can be turned so far.
+    pub follow_direction: bool,
+    /// Over how many pixels of its length a painted or erased stroke grows from nothing to
+    /// the brush's full size, see [`stroke::taper`].
+    pub taper_in: f32,
+    /// Over how many pixels before its end the stroke shrinks back to nothing. The end isn't
+    /// known until the stroke is finished, so this only shows once it is.
+    pub taper_out: f32,
+    /// A second tip that breaks up this one, making it a dual brush. The radius, strength
+    /// and every other setting are still this tip's.
+    pub secondary: Option<SecondaryTip>,
 }
 
-#[derive(Clone)]
+/// The second tip of a dual brush. Where a dab of the brush lands, its coverage is
+/// multiplied by the secondary tip's, so the stroke is broken up into the secondary's
+/// texture, and nothing is painted where the secondary doesn't reach.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SecondaryTip {
+    /// Only the shape of this brush is used. It can't have a secondary tip of its own.
+    pub brush: Box<Brush>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub placement: SecondaryPlacement,
+}
+
+/// Where a [`SecondaryTip`] lands relative to each dab.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SecondaryPlacement {
+    /// Repeated edge to edge across the canvas, so the texture stays where it is as strokes
+    /// pass over it.
+    #[default]
+    Tiled,
+    /// On each dab, moved at random by up to the secondary's radius either way. The offsets
+    /// are drawn like the rest of a stroke's jitter, see [`jitter::StrokeRng`], so the
+    /// stroke comes out the same when it's replayed.
+    Scattered,
+}
+
+/// A brush tip and its settings. In a preset, see [`presets::PRESET_VERSION`], the kind of
+/// tip is named by a `tip` field alongside the rest. The tip's size is required, and any
+/// other setting that's missing is the default brush's.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "tip", rename_all = "snake_case"))]
 pub enum Brush {
     SoftCircle {
+        #[cfg_attr(feature = "serde", serde(default = "default_inner_radius"))]
         inner_radius: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        falloff: FalloffCurve,
+        #[cfg_attr(feature = "serde", serde(default))]
+        base: BrushBaseSettings,
+    },
+    /// Full strength everywhere within the radius and nothing outside it, with no
+    /// antialiasing, so strokes have crisp, aliased edges.
+    HardCircle {
+        #[cfg_attr(feature = "serde", serde(default))]
+        base: BrushBaseSettings,
+    },
+    /// A `width` by `height` rectangle centered on the cursor, for flat shapes, hatching
+    /// and, when long and thin, chisel tips. The outer `softness` pixels fade out, like the
+    /// falloff outside `inner_radius` on a soft circle. `base.radius` is kept at half the
+    /// longer side, which is how far the tip reaches from its center.
+    Square {
+        width: f32,
+        height: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        softness: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        base: BrushBaseSettings,
+    },
+    /// An ellipse turned `angle` radians clockwise from the x axis, for calligraphic strokes.
+    /// It fades out toward its edge along `falloff` like a soft circle with no inner radius.
+    /// `base.radius` is kept at the longer of the two radii.
+    Ellipse {
+        radius_x: f32,
+        radius_y: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        angle: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        falloff: FalloffCurve,
+        #[cfg_attr(feature = "serde", serde(default))]
+        base: BrushBaseSettings,
+    },
+    /// A custom tip from an image, see [`Brush::from_image_mask`]. The mask is scaled so its
+    /// longer side spans the diameter. It's shared, since every stroke frame keeps a copy of
+    /// the brush.
+    Stamp {
+        mask: Arc<ImageMask>,
+        #[cfg_attr(feature = "serde", serde(default))]
         base: BrushBaseSettings,
     },
 }
 
+impl Default for BrushBaseSettings {
+    fn default() -> Self {
+        Self {
+            id: "soft-circle".to_string(),
+            radius: 10.0,
+            spacing: 0.25,
+            strength: 1.0,
+            opacity: 1.0,
+            accumulation: StrokeAccumulation::default(),
+            fade_tail: None,
+            flow_per_second: None,
+            min_dab_interval: None,
+            pixel_snap: false,
+            float_buffer: false,
+            size_jitter: 0.0,
+            opacity_jitter: 0.0,
+            follow_direction: false,
+            taper_in: 0.0,
+            taper_out: 0.0,
+            secondary: None,
+        }
+    }
+}
+
 impl Default for Brush {
     fn default() -> Self {
         Brush::SoftCircle {
-            inner_radius: 1.0,
-            base: BrushBaseSettings {
-                id: "soft-circle".to_string(),
-                radius: 10.0,
-                spacing: 1.0,
-                strength: 1.0,
-            },
+            inner_radius: default_inner_radius(),
+            falloff: FalloffCurve::default(),
+            base: BrushBaseSettings::default(),
         }
     }
 }
 
+fn default_inner_radius() -> f32 {
+    1.0
+}
+
 impl Brush {
-    /// Gets a stamp for the current brush settings
+    /// The smallest radius a brush can be set to. A stamp any smaller would cover nothing.
+    pub const MIN_RADIUS: f32 = 0.5;
+    /// The smallest spacing, as a fraction of the radius. Dabs any closer would take forever
+    /// to paint for no difference that shows.
+    pub const MIN_SPACING: f32 = 0.01;
+    /// The closest dabs ever are along a stroke, in pixels, however small the brush.
+    pub const MIN_DAB_SPACING: f32 = 0.5;
+
+    /// A brush with a custom tip read from a PNG, see [`ImageMask::from_png`] for how the
+    /// image becomes a mask. The rest of the settings are the defaults.
+    pub fn from_image_mask(png: &[u8]) -> Result<Self, png::DecodingError> {
+        let mask = ImageMask::from_png(png)?;
+        let mut base = Brush::default().base().clone();
+        base.id = "image-mask".to_string();
+        Ok(Brush::Stamp {
+            mask: Arc::new(mask),
+            base,
+        })
+    }
+
+    /// Gets a stamp for the current brush settings. Pixel-snapped brushes get a hard square
+    /// covering every pixel within `radius - 1` of the center, so a radius of 1 is a single
+    /// pixel.
+    ///
+    /// A dual brush's stamp is multiplied by its secondary tip's, centered on it. Strokes
+    /// place the secondary tip for each dab, see [`SecondaryPlacement`], so this is what a
+    /// dab looks like without that, for previews and smudging.
     pub fn compute_stamp(&self) -> Stamp {
+        let mut stamp = self.tip_stamp();
+        if let Some(secondary) = self.secondary() {
+            let secondary = secondary.brush.tip_stamp();
+            for (y, row) in (stamp.top..).zip(stamp.alpha.chunks_exact_mut(stamp.width as usize)) {
+                for (x, alpha) in (stamp.left..).zip(row) {
+                    *alpha *= secondary.alpha_at(x, y);
+                }
+            }
+        }
+        stamp
+    }
+
+    /// [`Brush::compute_stamp`] in `color`, a straight color: each pixel is the color at the
+    /// stamp's coverage there, premultiplied, row by row. The stamp itself holds coverage
+    /// alone, so one cached stamp serves strokes of any color; this is for callers that want
+    /// a dab's actual pixels, such as to draw it.
+    pub fn compute_colored_stamp(&self, color: Rgba) -> Vec<Color32> {
+        self.compute_stamp()
+            .alpha
+            .iter()
+            .map(|&alpha| Color32::from(color.set_alpha(color.a() * alpha).premultiply()))
+            .collect()
+    }
+
+    /// The stamp of the brush's own tip, leaving out any secondary tip. Sizes below the
+    /// least a setter allows, or that aren't numbers at all, are drawn at that least, so a
+    /// brush built without the setters still gets a stamp it can paint with.
+    fn tip_stamp(&self) -> Stamp {
+        let radius = at_least(self.radius(), Self::MIN_RADIUS);
+        if self.pixel_snap() {
+            return match self {
+                Brush::Square { width, height, .. } => {
+                    rectangle(at_least(*width, 1.0), at_least(*height, 1.0), 0.0)
+                }
+                Brush::Stamp { mask, .. } => image_stamp(mask, radius, true),
+                _ => pixel_square(radius),
+            };
+        }
+
         match self {
-            Brush::SoftCircle { inner_radius, base } => soft_circle(base.radius, *inner_radius),
+            Brush::SoftCircle {
+                inner_radius,
+                falloff,
+                ..
+            } => {
+                let inner_radius = at_least(*inner_radius, 0.0).min(radius);
+                soft_circle(radius, inner_radius, &falloff.baked())
+            }
+            Brush::HardCircle { .. } => hard_circle(radius),
+            Brush::Square {
+                width,
+                height,
+                softness,
+                ..
+            } => rectangle(
+                at_least(*width, 1.0),
+                at_least(*height, 1.0),
+                at_least(*softness, 0.0),
+            ),
+            Brush::Ellipse {
+                radius_x,
+                radius_y,
+                angle,
+                falloff,
+                ..
+            } => ellipse(
+                at_least(*radius_x, Self::MIN_RADIUS),
+                at_least(*radius_y, Self::MIN_RADIUS),
+                if angle.is_finite() { *angle } else { 0.0 },
+                &falloff.baked(),
+            ),
+            Brush::Stamp { mask, .. } => image_stamp(mask, radius, false),
+        }
+    }
+
+    /// Whether `other` gets the same stamp as this brush, which holds when they have the
+    /// same tip, whatever their other settings. Image tips are the same when they share a
+    /// mask, which is cheaper than comparing the masks and holds for clones of one brush.
+    pub fn same_stamp(&self, other: &Brush) -> bool {
+        if self.radius() != other.radius()
+            || self.pixel_snap() != other.pixel_snap()
+            || self.secondary() != other.secondary()
+        {
+            return false;
+        }
+        match (self, other) {
+            (
+                Brush::SoftCircle {
+                    inner_radius,
+                    falloff,
+                    ..
+                },
+                Brush::SoftCircle {
+                    inner_radius: other_inner_radius,
+                    falloff: other_falloff,
+                    ..
+                },
+            ) => inner_radius == other_inner_radius && falloff == other_falloff,
+            (Brush::HardCircle { .. }, Brush::HardCircle { .. }) => true,
+            (
+                Brush::Square {
+                    width,
+                    height,
+                    softness,
+                    ..
+                },
+                Brush::Square {
+                    width: other_width,
+                    height: other_height,
+                    softness: other_softness,
+                    ..
+                },
+            ) => width == other_width && height == other_height && softness == other_softness,
+            (
+                Brush::Ellipse {
+                    radius_x,
+                    radius_y,
+                    angle,
+                    falloff,
+                    ..
+                },
+                Brush::Ellipse {
+                    radius_x: other_radius_x,
+                    radius_y: other_radius_y,
+                    angle: other_angle,
+                    falloff: other_falloff,
+                    ..
+                },
+            ) => {
+                radius_x == other_radius_x
+                    && radius_y == other_radius_y
+                    && angle == other_angle
+                    && falloff == other_falloff
+            }
+            (
+                Brush::Stamp { mask, .. },
+                Brush::Stamp {
+                    mask: other_mask, ..
+                },
+            ) => Arc::ptr_eq(mask, other_mask),
+            _ => false,
         }
     }
 
@@ -61,80 +476,749 @@ impl Brush {
     // accessor methods
     //==========================================================================
 
+    /// What the brush is known by, such as in a brush library, where no two brushes have
+    /// the same id.
+    pub fn id(&self) -> &str {
+        &self.base().id
+    }
+
     pub fn spacing(&self) -> f32 {
         match self {
-            Brush::SoftCircle { base, .. } => base.spacing,
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.spacing,
         }
     }
 
+    /// How far apart dabs are along a stroke, in pixels. Never less than the least radius
+    /// and spacing allow, even if the settings were set to less without the setters, nor
+    /// than [`Brush::MIN_DAB_SPACING`].
+    pub fn dab_spacing(&self) -> f32 {
+        let spacing = self.radius().max(Self::MIN_RADIUS) * self.spacing().max(Self::MIN_SPACING);
+        spacing.max(Self::MIN_DAB_SPACING)
+    }
+
     pub fn radius(&self) -> f32 {
         match self {
-            Brush::SoftCircle { base, .. } => base.radius,
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.radius,
         }
     }
 
+    /// Between 0 and 1, even if the setting was set outside that without the setters.
     pub fn strength(&self) -> f32 {
         match self {
-            Brush::SoftCircle { base, .. } => base.strength,
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => unit(base.strength),
+        }
+    }
+
+    /// Between 0 and 1, like [`Brush::strength`].
+    pub fn opacity(&self) -> f32 {
+        unit(self.base().opacity)
+    }
+
+    pub fn accumulation(&self) -> StrokeAccumulation {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.accumulation,
+        }
+    }
+
+    pub fn flow_per_second(&self) -> Option<f32> {
+        self.base().flow_per_second
+    }
+
+    pub fn min_dab_interval(&self) -> Option<f32> {
+        self.base().min_dab_interval
+    }
+
+    pub fn fade_tail(&self) -> Option<FadeTail> {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.fade_tail,
+        }
+    }
+
+    pub fn pixel_snap(&self) -> bool {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.pixel_snap,
+        }
+    }
+
+    pub fn float_buffer(&self) -> bool {
+        self.base().float_buffer
+    }
+
+    pub fn size_jitter(&self) -> f32 {
+        self.base().size_jitter
+    }
+
+    pub fn opacity_jitter(&self) -> f32 {
+        self.base().opacity_jitter
+    }
+
+    pub fn follow_direction(&self) -> bool {
+        self.base().follow_direction
+    }
+
+    pub fn taper_in(&self) -> f32 {
+        self.base().taper_in
+    }
+
+    pub fn taper_out(&self) -> f32 {
+        self.base().taper_out
+    }
+
+    /// The second tip of a dual brush.
+    pub fn secondary(&self) -> Option<&SecondaryTip> {
+        self.base().secondary.as_ref()
+    }
+
+    /// Whether strokes of the brush taper at either end.
+    pub fn tapers(&self) -> bool {
+        self.taper_in() > 0.0 || self.taper_out() > 0.0
+    }
+
+    fn base(&self) -> &BrushBaseSettings {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base,
+        }
+    }
+
+    /// How much of a soft circle is at full strength before its falloff starts, as a fraction
+    /// of its radius: 0 fades out from the very center, 1 is hard all the way to the edge.
+    /// Other brushes don't have one.
+    pub fn hardness(&self) -> Option<f32> {
+        match self {
+            Brush::SoftCircle {
+                inner_radius, base, ..
+            } if base.radius > 0.0 => Some((inner_radius / base.radius).clamp(0.0, 1.0)),
+            Brush::SoftCircle { .. } => Some(0.0),
+            Brush::HardCircle { .. }
+            | Brush::Square { .. }
+            | Brush::Ellipse { .. }
+            | Brush::Stamp { .. } => None,
+        }
+    }
+
+    /// Whether the tip looks the same however it's turned or mirrored, so it never needs
+    /// turning to match the way a dab faces.
+    pub fn is_round(&self) -> bool {
+        matches!(self, Brush::SoftCircle { .. } | Brush::HardCircle { .. })
+    }
+
+    /// The falloff of soft and elliptical brushes. Hard, rectangular and image brushes don't
+    /// have one.
+    pub fn falloff(&self) -> Option<&FalloffCurve> {
+        match self {
+            Brush::SoftCircle { falloff, .. } | Brush::Ellipse { falloff, .. } => Some(falloff),
+            Brush::HardCircle { .. } | Brush::Square { .. } | Brush::Stamp { .. } => None,
+        }
+    }
+
+    /// The width, height and edge softness of rectangular brushes.
+    pub fn rectangle(&self) -> Option<(f32, f32, f32)> {
+        match self {
+            Brush::Square {
+                width,
+                height,
+                softness,
+                ..
+            } => Some((*width, *height, *softness)),
+            Brush::SoftCircle { .. }
+            | Brush::HardCircle { .. }
+            | Brush::Ellipse { .. }
+            | Brush::Stamp { .. } => None,
+        }
+    }
+
+    /// How far elliptical brushes are turned, in radians. Every other brush looks the same
+    /// whichever way it's turned, or can't be turned, so it's 0 for them.
+    pub fn angle(&self) -> f32 {
+        match self {
+            Brush::Ellipse { angle, .. } => *angle,
+            Brush::SoftCircle { .. }
+            | Brush::HardCircle { .. }
+            | Brush::Square { .. }
+            | Brush::Stamp { .. } => 0.0,
         }
     }
 
     //==========================================================================
     // mutator methods
     //==========================================================================
+    pub fn set_id(&mut self, id: String) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.id = id,
+        }
+    }
+
+    /// Sets the spacing, as a fraction of the radius, no less than [`Brush::MIN_SPACING`].
+    /// Does nothing if `spacing` isn't a number.
     pub fn set_spacing(&mut self, spacing: f32) {
+        if !spacing.is_finite() {
+            return;
+        }
+        let spacing = spacing.max(Self::MIN_SPACING);
         match self {
-            Brush::SoftCircle { base, .. } => base.spacing = spacing,
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.spacing = spacing,
         }
     }
 
+    /// Sets the radius, no less than [`Brush::MIN_RADIUS`]. Soft circles keep their hardness,
+    /// and rectangular and elliptical brushes are scaled to it, keeping their proportions.
+    /// Does nothing if `radius` isn't a number.
     pub fn set_radius(&mut self, radius: f32) {
+        if !radius.is_finite() {
+            return;
+        }
+        let radius = radius.max(Self::MIN_RADIUS);
         match self {
-            Brush::SoftCircle { base, .. } => base.radius = radius,
+            Brush::SoftCircle {
+                inner_radius, base, ..
+            } => {
+                if base.radius > 0.0 {
+                    *inner_radius *= radius / base.radius;
+                }
+                base.radius = radius;
+            }
+            Brush::HardCircle { base } | Brush::Stamp { base, .. } => base.radius = radius,
+            Brush::Square {
+                width,
+                height,
+                base,
+                ..
+            } => {
+                let scale = radius / base.radius.max(Self::MIN_RADIUS);
+                *width *= scale;
+                *height *= scale;
+                base.radius = radius;
+            }
+            Brush::Ellipse {
+                radius_x,
+                radius_y,
+                base,
+                ..
+            } => {
+                let scale = radius / base.radius.max(Self::MIN_RADIUS);
+                *radius_x *= scale;
+                *radius_y *= scale;
+                base.radius = radius;
+            }
         }
     }
 
+    /// Sets the strength, clamped to 0..=1. Does nothing if `strength` isn't a number.
     pub fn set_strength(&mut self, strength: f32) {
+        if strength.is_nan() {
+            return;
+        }
+        let strength = strength.clamp(0.0, 1.0);
         match self {
-            Brush::SoftCircle { base, .. } => base.strength = strength,
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.strength = strength,
         }
     }
 
-    //==========================================================================
-    // builder methods
-    //==========================================================================
+    /// Sets the opacity, clamped to 0..=1. Does nothing if `opacity` isn't a number.
+    pub fn set_opacity(&mut self, opacity: f32) {
+        if opacity.is_nan() {
+            return;
+        }
+        let opacity = opacity.clamp(0.0, 1.0);
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.opacity = opacity,
+        }
+    }
 
-    pub fn with_spacing(self, spacing: f32) -> Self {
+    pub fn set_accumulation(&mut self, accumulation: StrokeAccumulation) {
         match self {
-            Brush::SoftCircle { inner_radius, mut base } => {
-                base.spacing = spacing;
-                Brush::SoftCircle { inner_radius, base }
-            }
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.accumulation = accumulation,
         }
     }
 
-    pub fn with_radius(self, radius: f32) -> Self {
+    pub fn set_flow_per_second(&mut self, flow_per_second: Option<f32>) {
         match self {
-            Brush::SoftCircle { inner_radius, mut base } => {
-                base.radius = radius;
-                Brush::SoftCircle { inner_radius, base }
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.flow_per_second = flow_per_second,
+        }
+    }
+
+    pub fn set_min_dab_interval(&mut self, min_dab_interval: Option<f32>) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.min_dab_interval = min_dab_interval,
+        }
+    }
+
+    pub fn set_fade_tail(&mut self, fade_tail: Option<FadeTail>) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.fade_tail = fade_tail,
+        }
+    }
+
+    /// Makes the brush a dual brush with `secondary` as its second tip, or an ordinary one
+    /// for `None`. A secondary tip can't have one of its own, so any it has is dropped.
+    pub fn set_secondary(&mut self, secondary: Option<SecondaryTip>) {
+        let secondary = secondary.map(|mut tip| {
+            tip.brush.set_secondary(None);
+            tip
+        });
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.secondary = secondary,
+        }
+    }
+
+    pub fn set_pixel_snap(&mut self, pixel_snap: bool) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.pixel_snap = pixel_snap,
+        }
+    }
+
+    pub fn set_float_buffer(&mut self, float_buffer: bool) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.float_buffer = float_buffer,
+        }
+    }
+
+    pub fn set_size_jitter(&mut self, size_jitter: f32) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.size_jitter = size_jitter,
+        }
+    }
+
+    pub fn set_opacity_jitter(&mut self, opacity_jitter: f32) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.opacity_jitter = opacity_jitter,
+        }
+    }
+
+    pub fn set_follow_direction(&mut self, follow_direction: bool) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.follow_direction = follow_direction,
+        }
+    }
+
+    pub fn set_taper_in(&mut self, taper_in: f32) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.taper_in = taper_in,
+        }
+    }
+
+    pub fn set_taper_out(&mut self, taper_out: f32) {
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base.taper_out = taper_out,
+        }
+    }
+
+    /// Sets the falloff of soft brushes. Hard and rectangular brushes are left as they are.
+    pub fn set_falloff(&mut self, new_falloff: FalloffCurve) {
+        match self {
+            Brush::SoftCircle { falloff, .. } | Brush::Ellipse { falloff, .. } => {
+                *falloff = new_falloff
             }
+            Brush::HardCircle { .. } | Brush::Square { .. } | Brush::Stamp { .. } => {}
+        }
+    }
+
+    /// Sets the hardness of a soft circle, see [`Brush::hardness`], clamped to 0..=1. Does
+    /// nothing to other brushes.
+    pub fn set_hardness(&mut self, hardness: f32) {
+        match self {
+            Brush::SoftCircle {
+                inner_radius, base, ..
+            } if !hardness.is_nan() => *inner_radius = hardness.clamp(0.0, 1.0) * base.radius,
+            Brush::SoftCircle { .. }
+            | Brush::HardCircle { .. }
+            | Brush::Square { .. }
+            | Brush::Ellipse { .. }
+            | Brush::Stamp { .. } => {}
+        }
+    }
+
+    /// Makes the brush a soft circle with `falloff`, or a hard circle for `None`, keeping
+    /// the rest of its settings.
+    pub fn set_shape(&mut self, falloff: Option<FalloffCurve>) {
+        let (inner_radius, base) = match self {
+            Brush::SoftCircle {
+                inner_radius, base, ..
+            } => (*inner_radius, base.clone()),
+            Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => (1.0, base.clone()),
+        };
+        *self = match falloff {
+            Some(falloff) => Brush::SoftCircle {
+                inner_radius,
+                falloff,
+                base,
+            },
+            None => Brush::HardCircle { base },
+        };
+    }
+
+    /// Makes the brush a `width` by `height` rectangle whose outer `softness` pixels fade
+    /// out, keeping the rest of its settings. The radius becomes half the longer side. Does
+    /// nothing if any of them isn't a number.
+    pub fn set_rectangle(&mut self, width: f32, height: f32, softness: f32) {
+        if ![width, height, softness].iter().all(|v| v.is_finite()) {
+            return;
         }
+        let (width, height) = (width.max(1.0), height.max(1.0));
+        let mut base = self.base().clone();
+        base.radius = width.max(height) / 2.0;
+        *self = Brush::Square {
+            width,
+            height,
+            softness: softness.max(0.0),
+            base,
+        };
     }
 
-    pub fn with_strength(self, strength: f32) -> Self {
+    /// Makes the brush an ellipse with radii `radius_x` and `radius_y`, keeping its angle,
+    /// its falloff if it has one, and the rest of its settings. The radius becomes the longer
+    /// of the two. Neither radius can be less than [`Brush::MIN_RADIUS`], and if either isn't
+    /// a number this does nothing.
+    pub fn set_ellipse(&mut self, radius_x: f32, radius_y: f32) {
+        if !radius_x.is_finite() || !radius_y.is_finite() {
+            return;
+        }
+        let (radius_x, radius_y) = (
+            radius_x.max(Self::MIN_RADIUS),
+            radius_y.max(Self::MIN_RADIUS),
+        );
+        let angle = self.angle();
+        let falloff = self.falloff().cloned().unwrap_or_default();
+        let mut base = self.base().clone();
+        base.radius = radius_x.max(radius_y);
+        *self = Brush::Ellipse {
+            radius_x,
+            radius_y,
+            angle,
+            falloff,
+            base,
+        };
+    }
+
+    /// Turns elliptical brushes to `angle` radians. Other brushes are left as they are.
+    pub fn set_angle(&mut self, new_angle: f32) {
+        match self {
+            Brush::Ellipse { angle, .. } => *angle = new_angle,
+            Brush::SoftCircle { .. }
+            | Brush::HardCircle { .. }
+            | Brush::Square { .. }
+            | Brush::Stamp { .. } => {}
+        }
+    }
+
+    /// Brings every setting that's out of range, or isn't a number, back into range, such
+    /// as those of a brush loaded from a file or built without the setters. Returns what was
+    /// corrected, which is nothing for a brush that was fine.
+    pub fn validate(&mut self) -> Vec<BrushCorrection> {
+        let mut corrections = Vec::new();
+        let defaults = BrushBaseSettings::default();
+        let mut correct = |setting, value: &mut f32, range: RangeInclusive<f32>, fallback| {
+            let corrected = if value.is_finite() {
+                value.clamp(*range.start(), *range.end())
+            } else {
+                fallback
+            };
+            if *value != corrected {
+                corrections.push(BrushCorrection {
+                    setting,
+                    was: *value,
+                    now: corrected,
+                });
+                *value = corrected;
+            }
+        };
+
+        match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => {
+                let radius = Self::MIN_RADIUS..=f32::MAX;
+                correct("radius", &mut base.radius, radius, defaults.radius);
+            }
+            // kept at half the longer side, below
+            Brush::Square { .. } => {}
+        }
+        let base = match self {
+            Brush::SoftCircle { base, .. }
+            | Brush::HardCircle { base }
+            | Brush::Square { base, .. }
+            | Brush::Ellipse { base, .. }
+            | Brush::Stamp { base, .. } => base,
+        };
+        let spacing = Self::MIN_SPACING..=f32::MAX;
+        correct("spacing", &mut base.spacing, spacing, defaults.spacing);
+        let fractions = [
+            ("strength", &mut base.strength, defaults.strength),
+            ("opacity", &mut base.opacity, defaults.opacity),
+            ("size jitter", &mut base.size_jitter, defaults.size_jitter),
+            (
+                "opacity jitter",
+                &mut base.opacity_jitter,
+                defaults.opacity_jitter,
+            ),
+        ];
+        for (setting, value, fallback) in fractions {
+            correct(setting, value, 0.0..=1.0, fallback);
+        }
+        correct("taper in", &mut base.taper_in, 0.0..=f32::MAX, 0.0);
+        correct("taper out", &mut base.taper_out, 0.0..=f32::MAX, 0.0);
+
         match self {
-            Brush::SoftCircle { inner_radius, mut base } => {
-                base.strength = strength;
-                Brush::SoftCircle { inner_radius, base }
+            Brush::SoftCircle {
+                inner_radius, base, ..
+            } => correct("inner radius", inner_radius, 0.0..=base.radius, 0.0),
+            Brush::Square {
+                width,
+                height,
+                softness,
+                base,
+            } => {
+                correct("width", width, 1.0..=f32::MAX, 1.0);
+                correct("height", height, 1.0..=f32::MAX, 1.0);
+                correct("softness", softness, 0.0..=f32::MAX, 0.0);
+                let radius = width.max(*height) / 2.0;
+                correct("radius", &mut base.radius, radius..=radius, radius);
             }
+            Brush::Ellipse {
+                radius_x,
+                radius_y,
+                angle,
+                base,
+                ..
+            } => {
+                let radius = base.radius;
+                correct("x radius", radius_x, Self::MIN_RADIUS..=f32::MAX, radius);
+                correct("y radius", radius_y, Self::MIN_RADIUS..=f32::MAX, radius);
+                correct("angle", angle, f32::MIN..=f32::MAX, 0.0);
+                let radius = radius_x.max(*radius_y);
+                correct("radius", &mut base.radius, radius..=radius, radius);
+            }
+            Brush::HardCircle { .. } | Brush::Stamp { .. } => {}
         }
+        corrections
+    }
+
+    //==========================================================================
+    // builder methods
+    //==========================================================================
+
+    pub fn with_id(mut self, id: String) -> Self {
+        self.set_id(id);
+        self
+    }
+
+    pub fn with_spacing(mut self, spacing: f32) -> Self {
+        self.set_spacing(spacing);
+        self
     }
+
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.set_radius(radius);
+        self
+    }
+
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.set_strength(strength);
+        self
+    }
+
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.set_opacity(opacity);
+        self
+    }
+
+    pub fn with_accumulation(mut self, accumulation: StrokeAccumulation) -> Self {
+        self.set_accumulation(accumulation);
+        self
+    }
+
+    pub fn with_fade_tail(mut self, fade_tail: Option<FadeTail>) -> Self {
+        self.set_fade_tail(fade_tail);
+        self
+    }
+
+    pub fn with_flow_per_second(mut self, flow_per_second: Option<f32>) -> Self {
+        self.set_flow_per_second(flow_per_second);
+        self
+    }
+
+    pub fn with_min_dab_interval(mut self, min_dab_interval: Option<f32>) -> Self {
+        self.set_min_dab_interval(min_dab_interval);
+        self
+    }
+
+    pub fn with_pixel_snap(mut self, pixel_snap: bool) -> Self {
+        self.set_pixel_snap(pixel_snap);
+        self
+    }
+
+    pub fn with_float_buffer(mut self, float_buffer: bool) -> Self {
+        self.set_float_buffer(float_buffer);
+        self
+    }
+
+    pub fn with_hardness(mut self, hardness: f32) -> Self {
+        self.set_hardness(hardness);
+        self
+    }
+
+    pub fn with_falloff(mut self, falloff: FalloffCurve) -> Self {
+        self.set_falloff(falloff);
+        self
+    }
+
+    pub fn with_rectangle(mut self, width: f32, height: f32, softness: f32) -> Self {
+        self.set_rectangle(width, height, softness);
+        self
+    }
+
+    pub fn with_ellipse(mut self, radius_x: f32, radius_y: f32) -> Self {
+        self.set_ellipse(radius_x, radius_y);
+        self
+    }
+
+    pub fn with_angle(mut self, angle: f32) -> Self {
+        self.set_angle(angle);
+        self
+    }
+
+    pub fn with_shape(mut self, falloff: Option<FalloffCurve>) -> Self {
+        self.set_shape(falloff);
+        self
+    }
+
+    pub fn with_secondary(mut self, secondary: Option<SecondaryTip>) -> Self {
+        self.set_secondary(secondary);
+        self
+    }
+}
+
+/// A setting [`Brush::validate`] brought back into range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BrushCorrection {
+    pub setting: &'static str,
+    pub was: f32,
+    pub now: f32,
 }
 
+impl std::fmt::Display for BrushCorrection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} was {}, now {}", self.setting, self.was, self.now)
+    }
+}
+
+/// Blending for brush colors, which hold straight components (see [`alpha`]). Operations
+/// blend through these rather than working the formulas out again, so every brush composites
+/// the same way.
 pub trait RgbaExtensions {
+    /// Composites this straight color over the premultiplied `other`, giving a premultiplied
+    /// color: `a = a_s + a_d * (1 - a_s)`, and each component `c = c_s * k + c_d * (1 - k)`
+    /// with `k = min(a_s * 1.3, 1)`, capped at `a`. The bias keeps more of the brush's color
+    /// than plain source-over would. A transparent color leaves `other` as it was, and an
+    /// opaque one replaces it.
     fn overlay(&self, other: &Self) -> Self;
+    /// Replaces the alpha, keeping the straight color components.
     fn set_alpha(&self, alpha: f32) -> Self;
+    /// This straight color premultiplied: each component `c * a`, alpha kept.
+    fn premultiply(&self) -> Self;
+    /// This premultiplied color straight: each component `c / a`, capped at 1, alpha kept.
+    /// A transparent color has no components left to recover and comes back transparent
+    /// black.
+    fn unpremultiply(&self) -> Self;
+    /// Each channel `t` of the way from this color to `other`: `c * (1 - t) + c_o * t`. Both
+    /// should be premultiplied, or the color of a faint one counts as much as an opaque one.
+    fn lerp(&self, other: &Self, t: f32) -> Self;
 }
 
 impl RgbaExtensions for Rgba {
@@ -147,50 +1231,579 @@ impl RgbaExtensions for Rgba {
         let new_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
 
         // bias the blend slightly to preserve more color
-        let blend = src_alpha * BIAS;
+        let blend = (src_alpha * BIAS).min(1.0);
 
         let r = self.r() * blend + other.r() * (1.0 - blend);
         let g = self.g() * blend + other.g() * (1.0 - blend);
         let b = self.b() * blend + other.b() * (1.0 - blend);
 
+        // the bias can push the color past the alpha over transparent pixels, which isn't a
+        // valid premultiplied color, so cap it there
+        let new_alpha = new_alpha.min(1.0);
         Rgba::from_rgba_premultiplied(
-            r.min(1.0),
-            g.min(1.0),
-            b.min(1.0),
-            new_alpha.min(1.0),
+            r.clamp(0.0, new_alpha),
+            g.clamp(0.0, new_alpha),
+            b.clamp(0.0, new_alpha),
+            new_alpha,
         )
     }
 
     fn set_alpha(&self, alpha: f32) -> Self {
         Rgba::from_rgba_premultiplied(self.r(), self.g(), self.b(), alpha)
     }
+
+    fn premultiply(&self) -> Self {
+        let a = self.a();
+        Rgba::from_rgba_premultiplied(self.r() * a, self.g() * a, self.b() * a, a)
+    }
+
+    fn unpremultiply(&self) -> Self {
+        let a = self.a();
+        if a <= 0.0 {
+            return Rgba::TRANSPARENT;
+        }
+        Rgba::from_rgba_premultiplied(
+            (self.r() / a).min(1.0),
+            (self.g() / a).min(1.0),
+            (self.b() / a).min(1.0),
+            a,
+        )
+    }
+
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        *self * (1.0 - t) + *other * t
+    }
+}
+
+/// A circle that's full strength out to `inner_radius` and fades out along `falloff` from
+/// there to `radius`. With no room to fade it's full strength all the way out. Pixels on the
+/// edge are as strong as the share of them inside the circle, so small brushes keep their
+/// shape rather than snapping to whole pixels. A circle smaller than a pixel is the center
+/// pixel alone, likewise.
+fn soft_circle(radius: f32, inner_radius: f32, falloff: &FalloffCurve) -> Stamp {
+    if radius < 1.0 {
+        let coverage = (std::f32::consts::PI * radius * radius).clamp(0.0, 1.0);
+        return Stamp::centered(0, 0, |_, _| coverage);
+    }
+    // pixels whose centers are within half a pixel of the edge are partly inside
+    let reach = (radius + 0.5).ceil() as i32;
+
+    Stamp::centered(reach, reach, |x, y| {
+        let distance = ((x * x + y * y) as f32).sqrt();
+        let coverage = (radius + 0.5 - distance).clamp(0.0, 1.0);
+        if coverage <= 0.0 {
+            return 0.0;
+        }
+        let strength = if distance <= inner_radius || inner_radius >= radius {
+            1.0
+        } else {
+            let t = ((distance - inner_radius) / (radius - inner_radius)).min(1.0);
+            falloff.evaluate(t)
+        };
+        strength * coverage
+    })
 }
 
-fn soft_circle(radius: f32, inner_radius: f32) -> Stamp {
-    let mut pixels = Vec::new();
+fn hard_circle(radius: f32) -> Stamp {
     let radius_squared = radius * radius;
-    let inner_radius_squared = inner_radius * inner_radius;
-
-    for x in -radius as i32..=radius as i32 {
-        for y in -radius as i32..=radius as i32 {
-            let distance_squared = (x * x + y * y) as f32;
-            if distance_squared <= radius_squared {
-                let distance = distance_squared.sqrt();
-                let alpha = if distance_squared <= inner_radius_squared {
-                    1.0
-                } else {
-                    let t = ((distance - inner_radius) / (radius - inner_radius)).min(1.0);
-                    0.5 * (1.0 + f32::cos(t * std::f32::consts::PI))
-                };
-
-                pixels.push(Pixel {
-                    x,
-                    y,
-                    color: Rgba::WHITE.set_alpha(alpha),
+    let reach = radius as i32;
+
+    Stamp::centered(reach, reach, |x, y| {
+        if (x * x + y * y) as f32 <= radius_squared {
+            1.0
+        } else {
+            0.0
+        }
+    })
+}
+
+/// An ellipse with radii `radius_x` and `radius_y` turned `angle` radians, fading out along
+/// `falloff` from its center to its edge.
+fn ellipse(radius_x: f32, radius_y: f32, angle: f32, falloff: &FalloffCurve) -> Stamp {
+    // an axis thinner than a pixel is drawn as a line a pixel wide along the other one,
+    // rather than the scattered pixels, or nothing, the ellipse itself would cover
+    let (thin_x, thin_y) = (radius_x < 1.0, radius_y < 1.0);
+    let (radius_x, radius_y) = (radius_x.max(0.5), radius_y.max(0.5));
+    let (sin, cos) = angle.sin_cos();
+    // quarter turns come out exact, so they match an ellipse that was never turned
+    let exact = |v: f32| match v {
+        v if v.abs() < 1e-6 => 0.0,
+        v if (v.abs() - 1.0).abs() < 1e-6 => v.signum(),
+        v => v,
+    };
+    let (sin, cos) = (exact(sin), exact(cos));
+    let reach_x = (radius_x * cos).hypot(radius_y * sin).ceil() as i32;
+    let reach_y = (radius_x * sin).hypot(radius_y * cos).ceil() as i32;
+
+    Stamp::centered(reach_x, reach_y, |x, y| {
+        // into the ellipse's own axes, scaled so its edge is at 1
+        let (x, y) = (x as f32, y as f32);
+        let u = (x * cos + y * sin) / radius_x;
+        let v = (y * cos - x * sin) / radius_y;
+        let distance = match (thin_x, thin_y) {
+            (false, false) => (u * u + v * v).sqrt(),
+            (false, true) if (v * radius_y).abs() <= 0.5 => u.abs(),
+            (true, false) if (u * radius_x).abs() <= 0.5 => v.abs(),
+            (true, true) if x == 0.0 && y == 0.0 => 0.0,
+            _ => return 0.0,
+        };
+        if distance <= 1.0 {
+            falloff.evaluate(distance)
+        } else {
+            0.0
+        }
+    })
+}
+
+/// `mask` scaled so its longer side spans `radius * 2` pixels, keeping its proportions.
+/// Hard stamps cover the pixels at least half covered at full strength, and nothing else.
+fn image_stamp(mask: &ImageMask, radius: f32, hard: bool) -> Stamp {
+    let longest = mask.width().max(mask.height());
+    if longest == 0 {
+        return Stamp::from_fn(0, 0, 0, 0, |_, _| 0.0);
+    }
+    let scale = (radius * 2.0).max(1.0) / longest as f32;
+    let scaled = |size: u32| ((size as f32 * scale).round() as u32).max(1);
+    let (width, height) = (scaled(mask.width()), scaled(mask.height()));
+    let mut alpha = mask.scaled(width, height);
+    if hard {
+        for alpha in &mut alpha {
+            *alpha = if *alpha < 0.5 { 0.0 } else { 1.0 };
+        }
+    }
+
+    Stamp {
+        left: -((width / 2) as i32),
+        top: -((height / 2) as i32),
+        width,
+        height,
+        alpha,
+    }
+}
+
+/// A `width` by `height` rectangle of whole pixels centered on the origin; even sizes put the
+/// extra pixel on the negative side. Pixels within `softness` of the edge fade out.
+fn rectangle(width: f32, height: f32, softness: f32) -> Stamp {
+    let (width, height) = (
+        width.round().max(1.0) as i32,
+        height.round().max(1.0) as i32,
+    );
+    let (left, top) = (-(width / 2), -(height / 2));
+    Stamp::from_fn(left, top, width as u32, height as u32, |x, y| {
+        // how many pixels in from the nearest edge this pixel's center is
+        let inset = (x - left)
+            .min((width - 1) / 2 - x)
+            .min(y - top)
+            .min((height - 1) / 2 - y) as f32
+            + 0.5;
+        if inset >= softness {
+            1.0
+        } else {
+            0.5 - 0.5 * (inset / softness * std::f32::consts::PI).cos()
+        }
+    })
+}
+
+/// `size`, or `least` if it's less or isn't a number.
+fn at_least(size: f32, least: f32) -> f32 {
+    match size.is_finite() {
+        true => size.max(least),
+        false => least,
+    }
+}
+
+/// `value` brought into 0 to 1, or 1 if it isn't a number.
+fn unit(value: f32) -> f32 {
+    match value.is_nan() {
+        true => 1.0,
+        false => value.clamp(0.0, 1.0),
+    }
+}
+
+fn pixel_square(radius: f32) -> Stamp {
+    let reach = (radius.round() as i32 - 1).max(0);
+    Stamp::centered(reach, reach, |_, _| 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The soft circle before falloff curves were configurable: pixels whose centers are
+    /// within `radius` fall off along a raised cosine from `inner_radius` out.
+    fn cosine_baseline(radius: f32, inner_radius: f32, x: i32, y: i32) -> f32 {
+        let distance = ((x * x + y * y) as f32).sqrt();
+        if distance > radius {
+            return 0.0;
+        }
+        if distance <= inner_radius {
+            return 1.0;
+        }
+        let t = ((distance - inner_radius) / (radius - inner_radius)).min(1.0);
+        0.5 * (1.0 + f32::cos(t * std::f32::consts::PI))
+    }
+
+    fn soft_stamp(radius: f32, hardness: f32) -> Stamp {
+        Brush::default()
+            .with_radius(radius)
+            .with_hardness(hardness)
+            .with_falloff(FalloffCurve::Cosine)
+            .compute_stamp()
+    }
+
+    /// Inside the rim the stamp is the baseline. Across it, the baseline is scaled by how
+    /// much of the pixel the circle covers, and past it there's nothing.
+    #[test]
+    fn the_cosine_falloff_matches_the_baseline_out_to_its_reach() {
+        for radius in [3.0, 5.5, 10.0, 17.3] {
+            for hardness in [0.0, 0.3, 0.8] {
+                let stamp = soft_stamp(radius, hardness);
+                // one past the stamp's reach
+                let reach = (radius + 0.5).ceil() as i32 + 1;
+                for y in -reach..=reach {
+                    for x in -reach..=reach {
+                        let distance = ((x * x + y * y) as f32).sqrt();
+                        let coverage = (radius + 0.5 - distance).clamp(0.0, 1.0);
+                        let expected = cosine_baseline(radius, hardness * radius, x, y) * coverage;
+                        let actual = stamp.alpha_at(x, y);
+                        assert!(
+                            (actual - expected).abs() < 1e-6,
+                            "radius {radius}, hardness {hardness}, at {x}, {y}: \
+                             {actual} != {expected}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Soft circles of radius 1 to 5 at full, half and no hardness: the same across both
+    /// axes and the diagonal, and never more opaque further out.
+    #[test]
+    fn small_soft_circles_are_symmetric_and_fade_outward() {
+        for radius in 1..=5 {
+            for hardness in [1.0, 0.5, 0.0] {
+                let stamp = soft_stamp(radius as f32, hardness);
+                let what = format!("radius {radius}, hardness {hardness}");
+                let reach = radius + 1;
+                for y in -reach..=reach {
+                    for x in -reach..=reach {
+                        let alpha = stamp.alpha_at(x, y);
+                        for (mx, my) in [(-x, y), (x, -y), (y, x)] {
+                            assert_eq!(alpha, stamp.alpha_at(mx, my), "{what} at {x}, {y}");
+                        }
+                    }
+                }
+                for step in 0..=reach {
+                    let along = [(step, 0), (step, step), (step, step / 2)];
+                    let further = [(step + 1, 0), (step + 1, step + 1), (step + 1, step / 2)];
+                    for ((x, y), (fx, fy)) in along.into_iter().zip(further) {
+                        assert!(
+                            stamp.alpha_at(fx, fy) <= stamp.alpha_at(x, y),
+                            "{what}: {fx}, {fy} is more opaque than {x}, {y}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_hard_soft_circle_steps_across_its_rim() {
+        let stamp = soft_stamp(3.0, 1.0);
+        let axis: Vec<f32> = (0..=4).map(|x| stamp.alpha_at(x, 0)).collect();
+        assert_eq!(axis, [1.0, 1.0, 1.0, 0.5, 0.0]);
+        let diagonal: Vec<f32> = (0..=3).map(|i| stamp.alpha_at(i, i)).collect();
+        assert_eq!(diagonal[..2], [1.0, 1.0]);
+        assert!((diagonal[2] - (3.5 - 8f32.sqrt())).abs() < 1e-6);
+        assert_eq!(diagonal[3], 0.0);
+    }
+
+    fn assert_alphas_in_range(stamp: &Stamp, what: &str) {
+        assert!(!stamp.alpha.is_empty(), "{what}: empty stamp");
+        for &alpha in &stamp.alpha {
+            assert!(
+                alpha.is_finite() && (0.0..=1.0).contains(&alpha),
+                "{what}: alpha {alpha}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_soft_circle_as_hard_as_it_is_wide_is_solid_inside() {
+        for radius in [1.0, 4.0, 7.5] {
+            for curve in [FalloffCurve::Cosine, FalloffCurve::Linear] {
+                let stamp = soft_circle(radius, radius, &curve);
+                assert_alphas_in_range(&stamp, &format!("radius {radius}"));
+                let reach = radius as i32;
+                for y in -reach..=reach {
+                    for x in -reach..=reach {
+                        if ((x * x + y * y) as f32).sqrt() <= radius - 0.5 {
+                            assert_eq!(stamp.alpha_at(x, y), 1.0, "radius {radius} at {x}, {y}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_soft_circle_under_a_pixel_is_one_pixel_as_much_as_it_covers() {
+        for inner_radius in [0.0, 0.2, 0.4] {
+            let stamp = soft_circle(0.4, inner_radius, &FalloffCurve::Cosine);
+            assert_alphas_in_range(&stamp, &format!("inner radius {inner_radius}"));
+            assert_eq!((stamp.width, stamp.height), (1, 1));
+            let coverage = std::f32::consts::PI * 0.4 * 0.4;
+            assert!((stamp.alpha_at(0, 0) - coverage).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn a_soft_circle_with_no_inner_radius_falls_off_from_its_center() {
+        for radius in [1.0, 2.5, 6.0] {
+            let stamp = soft_circle(radius, 0.0, &FalloffCurve::Cosine);
+            assert_alphas_in_range(&stamp, &format!("radius {radius}"));
+            assert_eq!(stamp.alpha_at(0, 0), 1.0);
+        }
+    }
+
+    /// Unturned and turned a quarter, an elliptical stamp is the ellipse worked out pixel by
+    /// pixel the plain way, with its radii swapped for the quarter turn.
+    #[test]
+    fn ellipses_turned_by_quarters_match_the_plain_ellipse() {
+        use std::f32::consts::FRAC_PI_2;
+        for (radius_x, radius_y) in [(6.0, 3.0), (4.5, 2.0), (2.0, 7.25)] {
+            for curve in [FalloffCurve::Cosine, FalloffCurve::Linear] {
+                for angle in [0.0, FRAC_PI_2] {
+                    let stamp = Brush::default()
+                        .with_ellipse(radius_x, radius_y)
+                        .with_angle(angle)
+                        .with_falloff(curve.clone())
+                        .compute_stamp();
+                    let (across, down) = if angle == 0.0 {
+                        (radius_x, radius_y)
+                    } else {
+                        (radius_y, radius_x)
+                    };
+                    let what = format!("{radius_x} by {radius_y} turned {angle}");
+                    let (reach_x, reach_y) = (across.ceil() as i32, down.ceil() as i32);
+                    assert_eq!(stamp.left, -reach_x, "{what}");
+                    assert_eq!(stamp.top, -reach_y, "{what}");
+                    for y in -reach_y - 1..=reach_y + 1 {
+                        for x in -reach_x - 1..=reach_x + 1 {
+                            let (u, v) = (x as f32 / across, y as f32 / down);
+                            let distance = (u * u + v * v).sqrt();
+                            let expected = if distance <= 1.0 {
+                                curve.evaluate(distance)
+                            } else {
+                                0.0
+                            };
+                            let actual = stamp.alpha_at(x, y);
+                            assert!(
+                                (actual - expected).abs() < 1e-6,
+                                "{what} at {x}, {y}: {actual} != {expected}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Brushes with settings no setter would allow, as a file or a caller building the
+    /// variants by hand might give them.
+    pub(crate) fn pathological_brushes() -> Vec<Brush> {
+        let base = |radius: f32, spacing: f32| BrushBaseSettings {
+            radius,
+            spacing,
+            strength: 5.0,
+            opacity: -1.0,
+            ..BrushBaseSettings::default()
+        };
+        let mut brushes = Vec::new();
+        for radius in [0.0, -3.0, 0.2, f32::NAN, f32::INFINITY] {
+            for spacing in [0.0, -1.0, f32::NAN] {
+                brushes.push(Brush::SoftCircle {
+                    inner_radius: 20.0,
+                    falloff: FalloffCurve::Custom(Vec::new()),
+                    base: base(radius, spacing),
+                });
+                brushes.push(Brush::SoftCircle {
+                    inner_radius: f32::NAN,
+                    falloff: FalloffCurve::Gaussian,
+                    base: base(radius, spacing),
+                });
+                brushes.push(Brush::HardCircle {
+                    base: base(radius, spacing),
+                });
+                brushes.push(Brush::Ellipse {
+                    radius_x: 0.0,
+                    radius_y: -2.0,
+                    angle: f32::NAN,
+                    falloff: FalloffCurve::Cosine,
+                    base: base(radius, spacing),
                 });
             }
         }
+        brushes
     }
 
-    Stamp { pixels }
-}
\ No newline at end of file
+    #[test]
+    fn setters_keep_settings_in_range() {
+        let mut brush = Brush::default();
+        brush.set_radius(0.0);
+        assert_eq!(brush.radius(), Brush::MIN_RADIUS);
+        brush.set_radius(f32::NAN);
+        assert_eq!(brush.radius(), Brush::MIN_RADIUS);
+        brush.set_spacing(-1.0);
+        assert_eq!(brush.spacing(), Brush::MIN_SPACING);
+        brush.set_strength(5.0);
+        assert_eq!(brush.strength(), 1.0);
+        brush.set_strength(-5.0);
+        assert_eq!(brush.strength(), 0.0);
+
+        let brush = Brush::default().with_radius(10.0).with_hardness(3.0);
+        let Brush::SoftCircle { inner_radius, .. } = brush else {
+            unreachable!();
+        };
+        assert_eq!(inner_radius, 10.0);
+        assert!(brush.clone().validate().is_empty());
+    }
+
+    #[test]
+    fn validating_reports_what_it_corrected() {
+        let mut brush = Brush::SoftCircle {
+            inner_radius: 20.0,
+            falloff: FalloffCurve::Cosine,
+            base: BrushBaseSettings {
+                radius: 0.0,
+                spacing: f32::NAN,
+                strength: 1.5,
+                ..BrushBaseSettings::default()
+            },
+        };
+        let corrections = brush.validate();
+        let corrected: Vec<_> = corrections.iter().map(|c| (c.setting, c.now)).collect();
+        assert_eq!(
+            corrected,
+            [
+                ("radius", Brush::MIN_RADIUS),
+                ("spacing", BrushBaseSettings::default().spacing),
+                ("strength", 1.0),
+                ("inner radius", Brush::MIN_RADIUS),
+            ]
+        );
+        assert_eq!(corrections[0].to_string(), "radius was 0, now 0.5");
+        assert!(brush.validate().is_empty());
+
+        for mut brush in pathological_brushes() {
+            brush.validate();
+            assert!(brush.validate().is_empty());
+            assert!(brush.radius() >= Brush::MIN_RADIUS);
+        }
+    }
+
+    #[test]
+    fn pathological_brushes_still_make_usable_stamps() {
+        for brush in pathological_brushes() {
+            let stamp = brush.compute_stamp();
+            assert_eq!(stamp.alpha.len(), (stamp.width * stamp.height) as usize);
+            assert!(stamp.width < 1000 && stamp.height < 1000);
+            assert!(stamp.alpha.iter().all(|a| (0.0..=1.0).contains(a)));
+            assert!(brush.dab_spacing() >= Brush::MIN_DAB_SPACING);
+        }
+    }
+
+    fn assert_close(actual: Rgba, expected: Rgba, what: &str) {
+        let (actual, expected) = (actual.to_array(), expected.to_array());
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(a, e)| (a - e).abs() < 1e-6),
+            "{what}: {actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn overlaying_a_transparent_color_keeps_what_was_under_it() {
+        let under = Rgba::from_rgba_premultiplied(0.2, 0.1, 0.3, 0.5);
+        let clear = Rgba::from_rgba_premultiplied(1.0, 0.0, 0.0, 0.0);
+        assert_close(clear.overlay(&under), under, "over half transparent blue");
+        assert_close(
+            clear.overlay(&Rgba::TRANSPARENT),
+            Rgba::TRANSPARENT,
+            "over nothing",
+        );
+    }
+
+    #[test]
+    fn overlaying_an_opaque_color_replaces_what_was_under_it() {
+        let red = Rgba::from_rgba_premultiplied(1.0, 0.0, 0.0, 1.0);
+        for under in [
+            Rgba::TRANSPARENT,
+            Rgba::WHITE,
+            Rgba::from_rgba_premultiplied(0.0, 0.2, 0.4, 0.4),
+        ] {
+            assert_close(red.overlay(&under), red, &format!("over {under:?}"));
+        }
+    }
+
+    /// A faint color over transparency is as faint as it was, and the bias on its components
+    /// never pushes them past its alpha.
+    #[test]
+    fn overlaying_stays_premultiplied() {
+        let faint = Rgba::from_rgba_premultiplied(1.0, 0.5, 0.0, 0.4);
+        let over = faint.overlay(&Rgba::TRANSPARENT);
+        assert!((over.a() - 0.4).abs() < 1e-6);
+        assert!(over.r() <= over.a() && over.g() <= over.a() && over.b() <= over.a());
+
+        let under = Rgba::from_rgba_premultiplied(0.0, 0.0, 0.5, 0.5);
+        let over = faint.overlay(&under);
+        assert!((over.a() - (0.4 + 0.5 * 0.6)).abs() < 1e-6);
+        assert!(over.r() <= over.a() && over.g() <= over.a() && over.b() <= over.a());
+    }
+
+    #[test]
+    fn premultiplying_and_back_round_trips() {
+        let straight = Rgba::from_rgba_premultiplied(0.8, 0.4, 0.2, 0.5);
+        let premultiplied = straight.premultiply();
+        assert_close(
+            premultiplied,
+            Rgba::from_rgba_premultiplied(0.4, 0.2, 0.1, 0.5),
+            "premultiplied",
+        );
+        assert_close(premultiplied.unpremultiply(), straight, "back");
+
+        let opaque = Rgba::from_rgba_premultiplied(0.8, 0.4, 0.2, 1.0);
+        assert_close(opaque.premultiply(), opaque, "opaque premultiplied");
+        assert_close(opaque.unpremultiply(), opaque, "opaque back");
+    }
+
+    #[test]
+    fn a_transparent_color_premultiplies_to_nothing_and_back_to_transparent_black() {
+        let clear = Rgba::from_rgba_premultiplied(0.8, 0.4, 0.2, 0.0);
+        assert_close(clear.premultiply(), Rgba::TRANSPARENT, "premultiplied");
+        assert_close(clear.unpremultiply(), Rgba::TRANSPARENT, "back");
+        // out of range components are capped rather than blowing up
+        let over = Rgba::from_rgba_premultiplied(0.5, 0.0, 0.0, 0.25);
+        assert_close(
+            over.unpremultiply(),
+            Rgba::from_rgba_premultiplied(1.0, 0.0, 0.0, 0.25),
+            "capped",
+        );
+    }
+
+    #[test]
+    fn lerping_goes_from_one_color_to_the_other() {
+        let from = Rgba::TRANSPARENT;
+        let to = Rgba::from_rgba_premultiplied(0.6, 0.2, 0.0, 0.8);
+        assert_close(from.lerp(&to, 0.0), from, "at the start");
+        assert_close(from.lerp(&to, 1.0), to, "at the end");
+        assert_close(
+            from.lerp(&to, 0.5),
+            Rgba::from_rgba_premultiplied(0.3, 0.1, 0.0, 0.4),
+            "halfway",
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::presets::{PresetFileError, PRESET_EXTENSION};
+use crate::Brush;
+
+/// Brushes kept as preset files in a directory, one file per brush, no two with the same id.
+/// Changes are only made to the files by [`BrushLibrary::save_all`].
+pub struct BrushLibrary {
+    dir: PathBuf,
+    /// The brushes in the order they were loaded or added, each with the file it was loaded
+    /// from, if it was.
+    brushes: Vec<(Brush, Option<PathBuf>)>,
+    /// The files of brushes removed since the library was loaded or last saved.
+    removed: Vec<PathBuf>,
+}
+
+/// A file that was skipped while loading a library.
+#[derive(Debug)]
+pub struct LoadError {
+    pub path: PathBuf,
+    pub kind: LoadErrorKind,
+}
+
+#[derive(Debug)]
+pub enum LoadErrorKind {
+    /// The file isn't a preset that can be loaded.
+    Preset(PresetFileError),
+    /// A brush with the same id was loaded from a file earlier in the directory.
+    DuplicateId(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            LoadErrorKind::Preset(error) => write!(f, "{}: {}", self.path.display(), error),
+            LoadErrorKind::DuplicateId(id) => write!(
+                f,
+                "{}: there's already a brush called {:?}",
+                self.path.display(),
+                id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl BrushLibrary {
+    /// An empty library to be saved in `dir`.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            brushes: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// Loads every preset file in `dir`, by file name. Files that can't be loaded, and
+    /// those with the id of a brush loaded before them, are skipped and returned as errors
+    /// rather than failing the whole library. A directory that isn't there yet is an empty
+    /// library, created when it's saved.
+    pub fn load(dir: PathBuf) -> (Self, Vec<LoadError>) {
+        let mut library = Self::new(dir);
+        let mut errors = Vec::new();
+        let mut paths: Vec<PathBuf> = match std::fs::read_dir(&library.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| is_preset_file(path))
+                .collect(),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => {
+                errors.push(LoadError {
+                    path: library.dir.clone(),
+                    kind: LoadErrorKind::Preset(PresetFileError::Io(error)),
+                });
+                Vec::new()
+            }
+        };
+        paths.sort();
+
+        for path in paths {
+            match Brush::load_preset(&path) {
+                Ok(brush) if library.get(brush.id()).is_some() => errors.push(LoadError {
+                    kind: LoadErrorKind::DuplicateId(brush.id().to_string()),
+                    path,
+                }),
+                Ok(brush) => library.brushes.push((brush, Some(path))),
+                Err(error) => errors.push(LoadError {
+                    path,
+                    kind: LoadErrorKind::Preset(error),
+                }),
+            }
+        }
+        (library, errors)
+    }
+
+    /// The directory the library is kept in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The brushes in the order they were loaded or added.
+    pub fn iter(&self) -> impl Iterator<Item = &Brush> {
+        self.brushes.iter().map(|(brush, _)| brush)
+    }
+
+    pub fn len(&self) -> usize {
+        self.brushes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.brushes.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Brush> {
+        self.iter().find(|brush| brush.id() == id)
+    }
+
+    /// Adds `brush`, replacing the brush with the same id if there is one, which is
+    /// returned. A replaced brush's file is saved over with the new one.
+    pub fn add(&mut self, brush: Brush) -> Option<Brush> {
+        match self.position(brush.id()) {
+            Some(index) => Some(std::mem::replace(&mut self.brushes[index].0, brush)),
+            None => {
+                self.brushes.push((brush, None));
+                None
+            }
+        }
+    }
+
+    /// Removes the brush with `id`, returning it, or `None` if there's no such brush. Its
+    /// file is deleted when the library is next saved.
+    pub fn remove(&mut self, id: &str) -> Option<Brush> {
+        let (brush, path) = self.brushes.remove(self.position(id)?);
+        self.removed.extend(path);
+        Some(brush)
+    }
+
+    /// Saves every brush to its file, creating the directory if need be, and deletes the
+    /// files of brushes that were removed. A brush that wasn't loaded from a file is saved
+    /// to one named after its id, with any characters that can't be in a file name
+    /// replaced. Stops at the first brush that can't be saved.
+    pub fn save_all(&mut self) -> Result<(), PresetFileError> {
+        std::fs::create_dir_all(&self.dir).map_err(PresetFileError::Io)?;
+        for path in std::mem::take(&mut self.removed) {
+            match std::fs::remove_file(&path) {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(PresetFileError::Io(error));
+                }
+                _ => {}
+            }
+        }
+
+        let mut taken: HashSet<PathBuf> = self
+            .brushes
+            .iter()
+            .filter_map(|(_, path)| path.clone())
+            .collect();
+        for (brush, path) in &mut self.brushes {
+            let path = path.get_or_insert_with(|| {
+                let path = free_file_name(&self.dir, brush.id(), &taken);
+                taken.insert(path.clone());
+                path
+            });
+            brush.save_preset(path)?;
+        }
+        Ok(())
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.brushes.iter().position(|(brush, _)| brush.id() == id)
+    }
+}
+
+fn is_preset_file(path: &Path) -> bool {
+    path.is_file() && path.extension().is_some_and(|ext| ext == PRESET_EXTENSION)
+}
+
+/// A path in `dir` for a brush with `id` that isn't in `taken` and isn't another file
+/// already there, numbered if the id alone would be.
+fn free_file_name(dir: &Path, id: &str, taken: &HashSet<PathBuf>) -> PathBuf {
+    let stem: String = id
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let stem = if stem.is_empty() { "brush" } else { &stem };
+    (1..)
+        .map(|n| match n {
+            1 => dir.join(format!("{}.{}", stem, PRESET_EXTENSION)),
+            n => dir.join(format!("{}-{}.{}", stem, n, PRESET_EXTENSION)),
+        })
+        .find(|path| !taken.contains(path) && !path.exists())
+        .expect("there's always a free number")
+}
@@ -1,148 +1,2781 @@
-use ecolor::{Color32, Rgba};
+use std::f32::consts::TAU;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::{Brush, RgbaExtensions};
+use ecolor::{gamma_from_linear, gamma_u8_from_linear_f32, linear_from_gamma, Color32, Rgba};
+
+use crate::{
+    alpha::{self, BrushColor},
+    jitter::{StrokeRng, SIZE_JITTER_STEPS},
+    path::{self, Polyline},
+    pixel_buffer::{DirtyRect, PixelBuffer},
+    sampling::{average_color, SampleKernel},
+    stamp_cache::StampCache,
+    stroke::{self, StrokeAccumulation, StrokeBuffer, StrokeSegment, StrokeState, TAPER_STEPS},
+    symmetry::Symmetry,
+    Brush, RgbaExtensions, SecondaryPlacement, Stamp,
+};
+
+/// How many directions a dab of a brush that follows the stroke can face, evenly spread
+/// around a full turn, see [`Brush::follow_direction`].
+const DIRECTION_STEPS: usize = 64;
+
+/// How many rows a dab painted straight onto the buffer needs before its rows are painted
+/// in parallel, with the `parallel` feature. Smaller dabs aren't worth handing out.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_ROWS: u32 = 32;
+
+/// The stamps of a segment's dabs, by their size, taper and direction steps, see
+/// [`PaintOperation::dab_stamp`].
+type SegmentStamps = Vec<((usize, usize, usize), Arc<Stamp>)>;
+
+/// How much work an operation did, for profiling. Operations given one add to it, so one
+/// can total every segment of a frame, or of a whole stroke.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpStats {
+    /// Dabs laid down, each symmetry image counting as a dab of its own.
+    pub dabs: u32,
+    /// Pixels of the buffer the dabs covered, whether or not they changed.
+    pub pixels_written: u64,
+    /// Pixels the dabs covered off the edges of the buffer, which were skipped.
+    pub pixels_skipped_oob: u64,
+    /// Time spent in `process`.
+    pub duration: Duration,
+}
+
+impl OpStats {
+    /// Counts a dab of `stamp` that covered `written` pixels of the buffer.
+    fn record_dab(&mut self, stamp: &Stamp, written: u64) {
+        let covered = stamp.alpha.iter().filter(|&&alpha| alpha > 0.0).count() as u64;
+        self.dabs += 1;
+        self.pixels_written += written;
+        self.pixels_skipped_oob += covered.saturating_sub(written);
+    }
+}
+
+/// The second tip of a dual brush, ready to break up the dabs of a segment, see
+/// [`crate::SecondaryTip`].
+struct Secondary {
+    stamp: Arc<Stamp>,
+    placement: SecondaryPlacement,
+    /// How far a scattered tip can move from the dab, either way.
+    reach: f32,
+}
+
+impl Secondary {
+    /// How much of the dab centered on `center` gets through at `pixel`, with the tip moved
+    /// by `offset` if it's scattered.
+    fn coverage(&self, pixel: (i32, i32), center: (f32, f32), offset: (f32, f32)) -> f32 {
+        let stamp = &self.stamp;
+        match self.placement {
+            SecondaryPlacement::Tiled => stamp.alpha_at(
+                stamp.left + pixel.0.rem_euclid(stamp.width.max(1) as i32),
+                stamp.top + pixel.1.rem_euclid(stamp.height.max(1) as i32),
+            ),
+            SecondaryPlacement::Scattered => stamp.alpha_at(
+                pixel.0 - (center.0 + offset.0).floor() as i32,
+                pixel.1 - (center.1 + offset.1).floor() as i32,
+            ),
+        }
+    }
+}
 
 pub struct PaintOperation<'a> {
-    pub pixel_buffer: &'a mut Vec<Color32>,
-    pub canvas_width: u32,
-    pub canvas_height: u32,
+    pub pixels: &'a mut dyn PixelBuffer,
     pub brush: &'a Brush,
-    pub color: Rgba,
-    pub cursor_position: (f32, f32),
-    pub last_cursor_position: (f32, f32),
+    /// Built from whichever form the color comes in, see [`BrushColor`].
+    pub color: BrushColor,
+    pub segment: StrokeSegment,
+    /// Where the cursor was before the segment's start, to curve the segment: its dabs
+    /// are then placed along a Catmull-Rom curve through the three positions rather than
+    /// straight. Pixel-snapped segments stay straight.
+    pub previous_cursor_position: Option<(f32, f32)>,
+    /// Erasing removes alpha where the brush touches instead of painting `color`, keeping
+    /// only its alpha as the eraser's opacity.
     pub is_eraser: bool,
+    /// How the stroke's dabs combine with each other.
+    pub accumulation: StrokeAccumulation,
+    /// The stroke's coverage so far, for strokes that need one, see
+    /// [`StrokeAccumulation::needs_buffer`]. Dabs accumulate into the buffer and the stroke
+    /// is composited over the pre-stroke pixels, capped at the brush's opacity. Without one,
+    /// dabs composite straight onto the pixels, uncapped.
+    pub stroke_buffer: Option<&'a mut StrokeBuffer>,
+    /// Seconds since the previous segment of the stroke, 0 for its first. An airbrush held
+    /// still deposits in proportion to this rather than a full dab per segment, so how much
+    /// builds up doesn't depend on how often segments arrive.
+    pub elapsed: f32,
+    /// Where the brush's stamp comes from. Keep one for as long as the brush is in use so
+    /// its stamp is only computed once.
+    pub stamp_cache: &'a mut StampCache,
+    /// The random numbers jittered dabs are drawn with, see [`Brush::size_jitter`]. Keep one
+    /// for the whole stroke, seeded with the stroke's seed.
+    pub rng: &'a mut StrokeRng,
+    /// Where the stroke's last dab landed. Keep one for the whole stroke, so its dabs keep
+    /// to the spacing from one segment to the next.
+    pub stroke_state: &'a mut StrokeState,
+    /// Over how many pixels of stroke length dabs grow from nothing to full size at the
+    /// start of the stroke, and shrink back to nothing before its end, see
+    /// [`stroke::taper`]. Pixel-snapped dabs, and the airbrush held still, aren't tapered.
+    pub taper_in: f32,
+    pub taper_out: f32,
+    /// How long the whole stroke is, which tapering out needs. `None` while the stroke is
+    /// still being drawn and its end isn't known yet.
+    pub stroke_length: Option<f32>,
+    /// How much of the paint gets through at each pixel, one byte per pixel of the buffer,
+    /// such as a stencil's. What the stroke lays down at a pixel, or erases, is scaled by it.
+    pub mask: Option<&'a [u8]>,
+    /// Alpha lock: the alpha of every pixel stays as it was, and the stroke only recolors
+    /// it, by as much as it covers the pixel whatever the pixel's alpha. This keeps shading
+    /// inside what's already painted. Transparent pixels stay exactly transparent, and
+    /// erasing does nothing at all.
+    pub alpha_lock: bool,
+    /// Where each dab is repeated. Tips that aren't round are turned to match each image,
+    /// which mirrors them too, other than image brushes, which come out turned rather than
+    /// mirrored.
+    pub symmetry: Symmetry,
+    /// Where to add up the work the segment does, when profiling. Without it, nothing is
+    /// counted or timed.
+    pub stats: Option<&'a mut OpStats>,
 }
 
 impl PaintOperation<'_> {
-    pub fn process(self) {
-        let (x0, y0) = (self.last_cursor_position.0, self.last_cursor_position.1);
-        let (x1, y1) = (self.cursor_position.0, self.cursor_position.1);
+    /// Paints the segment, returning the part of the canvas it could have changed.
+    pub fn process(mut self) -> DirtyRect {
+        let started = self.stats.is_some().then(Instant::now);
+        let dirty = self.paint();
+        if let (Some(stats), Some(started)) = (self.stats, started) {
+            stats.duration += started.elapsed();
+        }
+        dirty
+    }
+
+    fn paint(&mut self) -> DirtyRect {
+        if self.is_eraser && self.alpha_lock {
+            return DirtyRect::default();
+        }
+        let StrokeSegment {
+            from: (x0, y0),
+            to: (x1, y1),
+        } = self.segment;
 
         let dx = x1 - x0;
         let dy = y1 - y0;
-        let distance = (dx * dx + dy * dy).sqrt();
+        let path = self.path();
+        let distance = path.length();
 
-        let min_spacing = self.brush.radius() * self.brush.spacing();
-        let steps = (distance / min_spacing).max(1.0) as i32;
+        // placed before any of the segment is skipped, so the spacing carries on past parts
+        // of the stroke that are off the canvas
+        let min_spacing = self.brush.dab_spacing();
+        let start = self.stroke_state.travelled();
+        let dabs = self.stroke_state.place(
+            distance,
+            self.elapsed,
+            min_spacing,
+            self.brush.min_dab_interval(),
+        );
 
-        let stamp = self.brush.compute_stamp();
+        let Some((range, dirty)) = reachable_path(
+            &path,
+            self.brush,
+            self.pixels.width(),
+            self.pixels.height(),
+            &self.symmetry,
+        ) else {
+            return DirtyRect::default();
+        };
 
-        for i in 0..=steps {
-            let t = i as f32 / steps as f32;
-            let x = x0 + dx * t;
-            let y = y0 + dy * t;
+        if self.brush.pixel_snap() {
+            self.process_pixel_snapped(range);
+            return dirty;
+        }
 
-            for stamp_pixel in &stamp.pixels {
-                let px = (x + stamp_pixel.x as f32) as i32;
-                let py = (y + stamp_pixel.y as f32) as i32;
+        let flow = self.brush.strength();
+        let heading = (distance > 0.0).then(|| dy.atan2(dx));
+        let directions = match self.brush.follow_direction() {
+            true => self.stroke_state.steer(heading),
+            false => None,
+        };
+        let mut stamps = Vec::new();
+        let secondary = self.secondary();
 
-                if stamp_pixel.color.a() <= 0.0 {
-                    continue;
+        if let Some(flow) = self.brush.flow_per_second() {
+            if distance == 0.0 && self.elapsed > 0.0 {
+                let direction = directions.map(|(from, _)| from);
+                let images = self.dab_images(&mut stamps, (x0, y0), 0, TAPER_STEPS, direction);
+                for ((x, y), stamp) in images {
+                    self.airbrush(x, y, &stamp, secondary.as_ref(), flow);
                 }
+                return dirty;
+            }
+        }
+
+        // only the dabs that can reach the canvas
+        for t in dabs.into_iter().filter(|t| (range.0..=range.1).contains(t)) {
+            let (x, y) = path.at(t);
+
+            let (size, opacity) = self.jitter();
+            let offset = self.scatter(secondary.as_ref());
+            let taper = self.taper_step(start + distance * t);
+            if taper == 0 {
+                continue;
+            }
+            // turning from the way the stroke was heading, so sharp turns don't snap
+            let direction = directions.map(|(from, to)| from + (to - from) * t);
+            for (center, stamp) in self.dab_images(&mut stamps, (x, y), size, taper, direction) {
+                let written =
+                    self.paint_dab(&stamp, center, secondary.as_ref(), offset, flow * opacity);
+                self.record_dab(&stamp, written);
+            }
+        }
+        dirty
+    }
+
+    /// The path the segment's dabs are placed along: curved through the previous cursor
+    /// position if there is one, otherwise straight.
+    fn path(&self) -> Polyline {
+        let StrokeSegment { from, to } = self.segment;
+        match self.previous_cursor_position {
+            Some(previous) if !self.brush.pixel_snap() && from != to => {
+                // the curve ends where the stroke is known to, so it carries straight on
+                Polyline::new(path::catmull_rom_segment(previous, from, to, to))
+            }
+            _ => Polyline::new(vec![from, to]),
+        }
+    }
+
+    /// The dab centered on `center` and each of its symmetry images, with their stamps, see
+    /// [`PaintOperation::dab_stamp`]. Images of a round tip share the dab's stamp, while
+    /// other tips are turned to face the way the image of the dab faces.
+    fn dab_images(
+        &mut self,
+        stamps: &mut SegmentStamps,
+        center: (f32, f32),
+        size: usize,
+        taper: usize,
+        direction: Option<f32>,
+    ) -> Vec<((f32, f32), Arc<Stamp>)> {
+        let stamp = self.dab_stamp(stamps, size, taper, direction);
+        let mut images = vec![(center, Arc::clone(&stamp))];
+        let centers = self.symmetry.images(center);
+        if self.brush.is_round() {
+            images.extend(
+                centers
+                    .into_iter()
+                    .map(|center| (center, Arc::clone(&stamp))),
+            );
+            return images;
+        }
+        let angle = self.brush.angle();
+        let facing = angle + direction.unwrap_or(0.0);
+        for (center, facing) in centers.into_iter().zip(self.symmetry.image_angles(facing)) {
+            let stamp = self.dab_stamp(stamps, size, taper, Some(facing - angle));
+            images.push((center, stamp));
+        }
+        images
+    }
+
+    /// The stamp of a dab `size` steps down from the brush's full size, see
+    /// [`SIZE_JITTER_STEPS`], tapered to `taper` of [`TAPER_STEPS`], and turned to face
+    /// `direction` if it follows the stroke. Turned dabs face one of [`DIRECTION_STEPS`]
+    /// directions, each with its own stamp. Stamps are kept in `stamps` once they're looked
+    /// up, for the rest of the segment.
+    fn dab_stamp(
+        &mut self,
+        stamps: &mut SegmentStamps,
+        size: usize,
+        taper: usize,
+        direction: Option<f32>,
+    ) -> Arc<Stamp> {
+        let step = direction.map_or(0, |direction| {
+            let turns = (direction / TAU).rem_euclid(1.0);
+            (turns * DIRECTION_STEPS as f32).round() as usize % DIRECTION_STEPS
+        });
+        let key = (size, taper, step);
+        if let Some((_, stamp)) = stamps.iter().find(|(k, _)| *k == key) {
+            return Arc::clone(stamp);
+        }
+
+        // a dual brush's secondary tip is placed separately, see `PaintOperation::secondary`
+        let plain = size == 0 && taper == TAPER_STEPS && direction.is_none();
+        let stamp = if plain && self.brush.secondary().is_none() {
+            self.stamp_cache.get(self.brush)
+        } else {
+            let mut brush = self.brush.clone();
+            brush.set_secondary(None);
+            let jitter = self.brush.size_jitter().clamp(0.0, 1.0);
+            let scale = (1.0 - jitter * size as f32 / (SIZE_JITTER_STEPS - 1) as f32)
+                * taper as f32
+                / TAPER_STEPS as f32;
+            brush.set_radius((self.brush.radius() * scale).max(0.5));
+            if direction.is_some() {
+                let turn = step as f32 / DIRECTION_STEPS as f32 * TAU;
+                brush.set_angle(self.brush.angle() + turn);
+            }
+            self.stamp_cache.get(&brush)
+        };
+        stamps.push((key, Arc::clone(&stamp)));
+        stamp
+    }
+
+    /// The brush's secondary tip if it's a dual brush, with its stamp.
+    fn secondary(&mut self) -> Option<Secondary> {
+        let tip = self.brush.secondary()?;
+        Some(Secondary {
+            stamp: self.stamp_cache.get(&tip.brush),
+            placement: tip.placement,
+            reach: tip.brush.radius(),
+        })
+    }
+
+    /// Draws how far the secondary tip is moved for the next dab, if it's scattered. Like
+    /// [`PaintOperation::jitter`], random numbers are only drawn for a scattered tip.
+    fn scatter(&mut self, secondary: Option<&Secondary>) -> (f32, f32) {
+        match secondary {
+            Some(secondary) if secondary.placement == SecondaryPlacement::Scattered => {
+                let x = (self.rng.next_f32() - 0.5) * 2.0 * secondary.reach;
+                let y = (self.rng.next_f32() - 0.5) * 2.0 * secondary.reach;
+                (x, y)
+            }
+            _ => (0.0, 0.0),
+        }
+    }
+
+    /// How big a dab `at` pixels along the stroke is, in [`TAPER_STEPS`] up to the brush's
+    /// full size. 0 is too small to paint at all.
+    fn taper_step(&self, at: f32) -> usize {
+        let scale = stroke::taper(at, self.stroke_length, self.taper_in, self.taper_out);
+        (scale * TAPER_STEPS as f32).round() as usize
+    }
+
+    /// Draws the size of the next dab, as steps down from the brush's full size, and what to
+    /// scale its opacity by. Random numbers are only drawn for the jitter the brush has, so a
+    /// brush without any paints exactly as it would without jitter at all.
+    fn jitter(&mut self) -> (usize, f32) {
+        let size = match self.brush.size_jitter() > 0.0 {
+            true => ((self.rng.next_f32() * SIZE_JITTER_STEPS as f32) as usize)
+                .min(SIZE_JITTER_STEPS - 1),
+            false => 0,
+        };
+        let jitter = self.brush.opacity_jitter().clamp(0.0, 1.0);
+        let opacity = match jitter > 0.0 {
+            true => 1.0 - jitter * self.rng.next_f32(),
+            false => 1.0,
+        };
+        (size, opacity)
+    }
 
-                if target_px_in_bounds((px, py), self.canvas_width, self.canvas_height) {
-                    let index = (py * self.canvas_width as i32 + px) as usize;
-                    let current_color = Rgba::from(self.pixel_buffer[index]);
-
-                    // NOTE: we could just simply multiply self.color by stamp_pixel.color.a()
-                    // here but it gives a "3d" effect since it multiplies all components.
-                    // Leaving note here because it may be useful in the future to do that.
-                    let brush_color = self.color.set_alpha(stamp_pixel.color.a() * self.color.a());
-                    let final_color = Color32::from(brush_color.overlay(&current_color));
-                    if final_color.a() > 0 {
-                        self.pixel_buffer[index] = final_color;
+    /// Pixel art mode: the stroke steps through every pixel between the two positions with
+    /// no gaps or doubled pixels, stamping the brush's hard square footprint at each one.
+    /// Spacing, tapering and the stroke's direction are ignored. Pixel-snapped strokes
+    /// always have a stroke buffer, so a pixel that's covered again, by a later segment or
+    /// the stroke crossing itself, is unchanged. Only the `range` of the segment that can
+    /// reach the canvas is drawn.
+    fn process_pixel_snapped(&mut self, range: (f32, f32)) {
+        let StrokeSegment { from, to } = self.segment;
+        let at = |t: f32| {
+            let (x, y) = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+            (x.floor() as i32, y.floor() as i32)
+        };
+        let mut stamps = Vec::new();
+        let secondary = self.secondary();
+        let flow = self.brush.strength();
+
+        for (x, y) in path::bresenham(at(range.0), at(range.1)) {
+            let center = (x as f32, y as f32);
+            let (size, opacity) = self.jitter();
+            let offset = self.scatter(secondary.as_ref());
+            let stamp = self.dab_stamp(&mut stamps, size, TAPER_STEPS, None);
+            // images land on whole pixels too, the ones whose centers mirror the pixel's,
+            // with the same square footprint
+            let images = self
+                .symmetry
+                .images((center.0 + 0.5, center.1 + 0.5))
+                .into_iter()
+                .map(|(x, y)| (x.floor(), y.floor()));
+            for center in std::iter::once(center).chain(images) {
+                let written =
+                    self.paint_dab(&stamp, center, secondary.as_ref(), offset, flow * opacity);
+                self.record_dab(&stamp, written);
+            }
+        }
+    }
+
+    /// Lays down a dab of `stamp` centered on `center`, at `strength` of its coverage and
+    /// broken up by the secondary tip moved by `offset`, returning how many pixels of the
+    /// buffer it covered.
+    fn paint_dab(
+        &mut self,
+        stamp: &Stamp,
+        center: (f32, f32),
+        secondary: Option<&Secondary>,
+        offset: (f32, f32),
+        strength: f32,
+    ) -> u64 {
+        #[cfg(feature = "parallel")]
+        if self.stroke_buffer.is_none() && stamp.height >= PARALLEL_MIN_ROWS {
+            return self.paint_dab_rows(stamp, center, secondary, offset, strength);
+        }
+        self.paint_dab_pixels(stamp, center, secondary, offset, strength)
+    }
+
+    /// [`PaintOperation::paint_dab`] one pixel at a time, the way every dab is painted
+    /// without the `parallel` feature.
+    fn paint_dab_pixels(
+        &mut self,
+        stamp: &Stamp,
+        center: (f32, f32),
+        secondary: Option<&Secondary>,
+        offset: (f32, f32),
+        strength: f32,
+    ) -> u64 {
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let mut written = 0;
+        for (index, pixel, alpha) in dab(stamp, center, width, height) {
+            let coverage =
+                secondary.map_or(1.0, |secondary| secondary.coverage(pixel, center, offset));
+            self.deposit(index, alpha * coverage * strength);
+            written += 1;
+        }
+        written
+    }
+
+    /// [`PaintOperation::paint_dab`] with the buffer's rows painted in parallel, for dabs
+    /// painted straight onto the buffer. Every pixel gets the same paint, in the same order,
+    /// as it would one row at a time.
+    #[cfg(feature = "parallel")]
+    fn paint_dab_rows(
+        &mut self,
+        stamp: &Stamp,
+        center: (f32, f32),
+        secondary: Option<&Secondary>,
+        offset: (f32, f32),
+        strength: f32,
+    ) -> u64 {
+        use rayon::prelude::*;
+
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let center = snapped(center);
+        let (rows, columns) = dab_clip(stamp, center, width, height);
+        // the rows of the stamp on the buffer, with the row of the buffer each lands on,
+        // which two rows can share at the buffer's top edge
+        let rows: Vec<(i32, &[f32])> = rows
+            .map(|row| {
+                let py = (center.1 + (stamp.top + row as i32) as f32) as i32;
+                let start = row * stamp.width as usize;
+                (py, &stamp.alpha[start + columns.start..start + columns.end])
+            })
+            .collect();
+        let (Some(&(first, _)), Some(&(last, _))) = (rows.first(), rows.last()) else {
+            return 0;
+        };
+        let (blend, mask) = (self.blend(), self.mask);
+        let width = width as usize;
+        let pixels =
+            &mut self.pixels.pixels_mut()[first as usize * width..(last as usize + 1) * width];
+        pixels
+            .par_chunks_mut(width)
+            .enumerate()
+            .map(|(i, buffer_row)| {
+                let py = first + i as i32;
+                let start = rows.partition_point(|&(y, _)| y < py);
+                let mut written = 0;
+                for &(_, row) in rows[start..].iter().take_while(|&&(y, _)| y == py) {
+                    for (column, &alpha) in columns.clone().zip(row) {
+                        if alpha <= 0.0 {
+                            continue;
+                        }
+                        let px = (center.0 + (stamp.left + column as i32) as f32) as i32;
+                        written += 1;
+                        let coverage = secondary.map_or(1.0, |secondary| {
+                            secondary.coverage((px, py), center, offset)
+                        });
+                        let alpha = alpha * coverage * strength;
+                        if alpha.is_nan() || alpha <= 0.0 {
+                            continue;
+                        }
+                        let coverage = match mask {
+                            Some(mask) => {
+                                alpha * mask[py as usize * width + px as usize] as f32 / 255.0
+                            }
+                            None => alpha,
+                        };
+                        let pixel = &mut buffer_row[px as usize];
+                        blend.apply(pixel, Rgba::from(*pixel), coverage);
                     }
                 }
-            }
+                written
+            })
+            .sum()
+    }
+
+    /// How the brush color goes onto a pixel, see [`Blend`].
+    fn blend(&self) -> Blend {
+        Blend {
+            color: self.color.rgba(),
+            is_eraser: self.is_eraser,
+            alpha_lock: self.alpha_lock,
+        }
+    }
+
+    /// The airbrush held still at `(x, y)` for `elapsed` seconds: as much paint as `flow *
+    /// elapsed` dabs would deposit. Dabs combine as `1 - (1 - a)^n`, which takes a fractional
+    /// `n`, so any number of short segments add up to the same as one long one, up to the
+    /// rounding of the 8-bit pixels. In wash mode the stroke's coverage is capped at a single
+    /// dab, so there's nothing to build up. Held still, there are no separate dabs to jitter,
+    /// so the airbrush paints at full size and opacity, and a scattered secondary tip stays
+    /// centered.
+    fn airbrush(
+        &mut self,
+        x: f32,
+        y: f32,
+        stamp: &Stamp,
+        secondary: Option<&Secondary>,
+        flow: f32,
+    ) {
+        let color_alpha = self.color.rgba().a();
+        if color_alpha <= 0.0 {
+            return;
+        }
+        let dabs = flow * self.elapsed;
+        let strength = self.brush.strength();
+        let mut written = 0;
+        for (index, pixel, alpha) in dab(stamp, (x, y), self.pixels.width(), self.pixels.height()) {
+            let coverage = secondary.map_or(1.0, |secondary| {
+                secondary.coverage(pixel, (x, y), (0.0, 0.0))
+            });
+            let alpha = (alpha * coverage * strength * color_alpha).min(1.0);
+            let deposited = 1.0 - (1.0 - alpha).powf(dabs);
+            // `deposit` applies the color's alpha itself
+            self.deposit(index, deposited / color_alpha);
+            written += 1;
+        }
+        self.record_dab(stamp, written);
+    }
+
+    /// Counts a dab of `stamp` that covered `written` pixels, if the work is being counted.
+    fn record_dab(&mut self, stamp: &Stamp, written: u64) {
+        if let Some(stats) = self.stats.as_deref_mut() {
+            stats.record_dab(stamp, written);
+        }
+    }
+
+    /// Paints the brush color into the pixel at `index` with the dab's `alpha`, or erases
+    /// that much of the pixel.
+    fn deposit(&mut self, index: usize, alpha: f32) {
+        // a brush built without its setters can have a strength that's negative, or not a
+        // number, which would otherwise get into the blend
+        if alpha.is_nan() || alpha <= 0.0 {
+            return;
+        }
+        if self.paints_float() {
+            self.deposit_float(index, alpha);
+            return;
         }
+        // with a stroke buffer the dab only adds to the stroke's coverage, and the stroke
+        // as a whole is composited over what was there before it started
+        let (coverage, current_color) = match self.stroke_buffer.as_deref_mut() {
+            Some(buffer) => {
+                let (pixels, width) = (self.pixels.pixels(), self.pixels.width());
+                let (coverage, before) = match self.accumulation {
+                    StrokeAccumulation::BuildUp => buffer.build_up(pixels, width, index, alpha),
+                    StrokeAccumulation::Wash => buffer.accumulate(pixels, width, index, alpha),
+                };
+                // the airbrush can take coverage past 1, building up beyond the color's
+                // alpha, which only an opacity below 1 caps
+                let opacity = self.brush.opacity();
+                let coverage = if opacity < 1.0 {
+                    coverage.min(opacity)
+                } else {
+                    coverage
+                };
+                (coverage, Rgba::from(before))
+            }
+            None => (alpha, Rgba::from(self.pixels.pixels()[index])),
+        };
+        let coverage = match self.mask {
+            Some(mask) => coverage * mask[index] as f32 / 255.0,
+            None => coverage,
+        };
+        let blend = self.blend();
+        blend.apply(
+            &mut self.pixels.pixels_mut()[index],
+            current_color,
+            coverage,
+        );
+    }
+
+    /// Whether the stroke paints through the float copy of the layer in its buffer, see
+    /// [`Brush::float_buffer`]. Only strokes that would otherwise paint straight onto the
+    /// layer do, as the others are already laid over the layer as it was before the stroke,
+    /// rather than over their own rounded dabs.
+    fn paints_float(&self) -> bool {
+        self.brush.float_buffer()
+            && self.stroke_buffer.is_some()
+            && self.mask.is_none()
+            && !self.accumulation.needs_buffer(self.brush.opacity())
+    }
+
+    /// [`PaintOperation::deposit`] into the float copy, writing the pixel from it rounded.
+    fn deposit_float(&mut self, index: usize, alpha: f32) {
+        let blend = self.blend();
+        let Some(buffer) = self.stroke_buffer.as_deref_mut() else {
+            return;
+        };
+        let color = buffer.color(self.pixels.pixels(), self.pixels.width(), index);
+        let [r, g, b, a] = *color;
+        let Some(blended) = blend.blended(Rgba::from_rgba_premultiplied(r, g, b, a), alpha) else {
+            return;
+        };
+        *color = blended.to_array();
+        // rounded rather than truncated, see `Color32::from`
+        self.pixels.pixels_mut()[index] = Color32::from(blended);
     }
 }
 
+/// How a [`PaintOperation`] lays its color onto a pixel, once it knows how much of the pixel
+/// the stroke covers.
+#[derive(Clone, Copy)]
+struct Blend {
+    color: Rgba,
+    is_eraser: bool,
+    alpha_lock: bool,
+}
+
+impl Blend {
+    /// Lays the color onto `pixel` with `coverage`, over `under`: the pixel as it is, or as
+    /// it was before the stroke if the stroke has a buffer.
+    fn apply(&self, pixel: &mut Color32, under: Rgba, coverage: f32) {
+        let Some(blended) = self.blended(under, coverage) else {
+            return;
+        };
+        let blended = Color32::from(blended);
+        *pixel = match (self.alpha_lock, self.is_eraser) {
+            // the alpha stays exactly as it was, whatever the rounding
+            (true, _) => {
+                let [r, g, b, _] = blended.to_array();
+                Color32::from_rgba_premultiplied(r, g, b, pixel.a())
+            }
+            (false, true) => blended,
+            (false, false) if blended.a() > 0 => blended,
+            (false, false) => return,
+        };
+    }
+
+    /// What laying the color over `under` with `coverage` makes of it, or `None` if it's
+    /// left as it is.
+    fn blended(&self, under: Rgba, coverage: f32) -> Option<Rgba> {
+        if self.alpha_lock {
+            let alpha = under.a();
+            if alpha <= 0.0 {
+                return None;
+            }
+            // the color at the pixel's own alpha, blended in as a straight color would be
+            let mix = (coverage * self.color.a()).min(1.0);
+            let color = self.color.set_alpha(alpha).premultiply();
+            let [r, g, b, _] = under.lerp(&color, mix).to_array();
+            return Some(Rgba::from_rgba_premultiplied(r, g, b, alpha));
+        }
+
+        if self.is_eraser {
+            // premultiplied, so scaling every channel fades the pixel out evenly
+            let remaining = 1.0 - (coverage * self.color.a()).min(1.0);
+            // a pixel faded under one level of alpha is cleared outright, otherwise rounding
+            // would leave the faintest pixels stuck at that level however often they're
+            // erased, along with a trace of their color
+            return Some(match under.a() * remaining < 1.0 / 255.0 {
+                true => Rgba::TRANSPARENT,
+                false => under * remaining,
+            });
+        }
 
+        // NOTE: we could just simply multiply self.color by alpha
+        // here but it gives a "3d" effect since it multiplies all components.
+        // Leaving note here because it may be useful in the future to do that.
+        let brush_color = self.color.set_alpha(coverage * self.color.a());
+        Some(brush_color.overlay(&under))
+    }
+}
+
+/// Drags color along a stroke: each dab lays down the color the stroke has picked up so far
+/// and then picks up some of what was under it, so the color streaks along the stroke and
+/// fades out as it mixes with what it passes over.
 pub struct SmudgeOperation<'a> {
-    pub pixel_buffer: &'a mut Vec<Color32>,
-    pub pixel_buffer_width: u32,
-    pub pixel_buffer_height: u32,
+    pub pixels: &'a mut dyn PixelBuffer,
     pub brush: &'a Brush,
-    pub cursor_position: (f32, f32),
-    pub last_cursor_position: (f32, f32),
+    pub segment: StrokeSegment,
     pub smudge_strength: f32,
+    /// How much of the color picked up is replaced by what's under the brush over each
+    /// radius the stroke travels, from 0 to 1. Low rates drag color further.
+    pub pickup_rate: f32,
+    /// See [`PaintOperation::stamp_cache`].
+    pub stamp_cache: &'a mut StampCache,
+    /// See [`PaintOperation::stroke_state`].
+    pub stroke_state: &'a mut StrokeState,
+    /// The color the stroke is carrying. Like `stroke_state`, it's kept for the whole
+    /// stroke and cleared before the next one.
+    pub pickup: &'a mut SmudgePickup,
+    /// Where each dab is repeated, each image carrying color of its own. The tip is
+    /// repeated as it is, not turned.
+    pub symmetry: Symmetry,
+    /// How much of each pixel of the buffer can be smudged, one byte per pixel, such as a
+    /// selection's. Color is still picked up from anywhere.
+    pub mask: Option<&'a [u8]>,
+    /// When set, color is picked up from the buffer merged with the layers around it, as
+    /// it's seen, rather than from the buffer alone. Smudged color still only goes into the
+    /// buffer.
+    pub merged: Option<MergedSource<'a>>,
+    /// See [`PaintOperation::stats`].
+    pub stats: Option<&'a mut OpStats>,
+}
+
+/// The color a smudge stroke has picked up, premultiplied, for each pixel of its stamp and
+/// each of its symmetry images. It's taken from under the stroke's first dab, and pixels
+/// that were off the buffer then carry nothing.
+#[derive(Clone, Debug, Default)]
+pub struct SmudgePickup {
+    /// The dab's own first, then its images'. Empty before the first dab.
+    images: Vec<Vec<Option<[f32; 4]>>>,
+}
+
+impl SmudgePickup {
+    /// Forgets the color picked up, so the next dab picks up afresh.
+    pub fn clear(&mut self) {
+        self.images.clear();
+    }
+}
+
+/// The visible layers below and above the buffer being smudged, each merged into one, over
+/// `rect` of the buffer. Smudging reads the buffer composited between the two, so color on
+/// other layers gets picked up while what's already been smudged still counts.
+pub struct MergedSource<'a> {
+    /// Premultiplied, `rect.width` pixels to a row.
+    pub below: &'a [Color32],
+    pub above: &'a [Color32],
+    pub rect: DirtyRect,
 }
 
+impl MergedSource<'_> {
+    /// `pixel`, at `(x, y)` in the buffer, as it's seen between the layers below and above,
+    /// or `None` outside `rect`.
+    fn composite(&self, x: i32, y: i32, pixel: Color32) -> Option<Color32> {
+        let rect = self.rect;
+        let inside = (rect.x as i32..(rect.x + rect.width) as i32).contains(&x)
+            && (rect.y as i32..(rect.y + rect.height) as i32).contains(&y);
+        if !inside {
+            return None;
+        }
+        let index =
+            (y as u32 - rect.y) as usize * rect.width as usize + (x as u32 - rect.x) as usize;
+        let over = |src: Rgba, dst: Rgba| src + dst * (1.0 - src.a());
+        let seen = over(Rgba::from(pixel), Rgba::from(self.below[index]));
+        Some(Color32::from(over(Rgba::from(self.above[index]), seen)))
+    }
+}
+
+/// The smudge operation under the name it had before it became [`SmudgeOperation`].
+#[deprecated(note = "renamed to `SmudgeOperation`, with `smear_strength` as `smudge_strength`")]
+pub type SmearOperation<'a> = SmudgeOperation<'a>;
+
 impl SmudgeOperation<'_> {
-    pub fn process(self) {
-        let (x0, y0) = (self.last_cursor_position.0, self.last_cursor_position.1);
-        let (x1, y1) = (self.cursor_position.0, self.cursor_position.1);
+    /// Smudges the segment, returning the part of the buffer it could have changed.
+    pub fn process(mut self) -> DirtyRect {
+        let started = self.stats.is_some().then(Instant::now);
+        let dirty = self.smudge();
+        if let (Some(stats), Some(started)) = (self.stats, started) {
+            stats.duration += started.elapsed();
+        }
+        dirty
+    }
+
+    fn smudge(&mut self) -> DirtyRect {
+        let StrokeSegment {
+            from: (x0, y0),
+            to: (x1, y1),
+        } = self.segment;
 
         let dx = x1 - x0;
         let dy = y1 - y0;
         let distance = (dx * dx + dy * dy).sqrt();
 
-        let min_spacing = self.brush.radius() * self.brush.spacing();
-        let steps = (distance / min_spacing).max(1.0) as i32;
+        // a smudge only drags color along the way it's moving, so holding still does nothing
+        if distance <= 0.0 {
+            return DirtyRect::default();
+        }
+
+        // placed before any of the segment is skipped, see `PaintOperation::process`
+        let spacing = self.brush.dab_spacing();
+        let dabs = self.stroke_state.place(distance, 0.0, spacing, None);
 
-        let stamp = self.brush.compute_stamp();
+        let Some((range, dirty)) = reachable_part(
+            self.segment.from,
+            self.segment.to,
+            self.brush,
+            self.pixels.width(),
+            self.pixels.height(),
+            &self.symmetry,
+        ) else {
+            return DirtyRect::default();
+        };
 
-        for i in 0..=steps {
-            let t = i as f32 / steps as f32;
-            let x = x0 + dx * t;
-            let y = y0 + dy * t;
+        // The rate is per radius travelled rather than per dab, so how far color is dragged
+        // doesn't depend on the brush's spacing.
+        let radius = self.brush.radius().max(Brush::MIN_RADIUS);
+        let kept = (1.0 - self.pickup_rate.clamp(0.0, 1.0)).powf(spacing / radius);
+        let pickup_rate = 1.0 - kept;
 
-            for stamp_pixel in &stamp.pixels {
-                let px = (x + stamp_pixel.x as f32) as i32;
-                let py = (y + stamp_pixel.y as f32) as i32;
+        let stamp = self.stamp_cache.get(self.brush);
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let mut pickup = std::mem::take(&mut self.pickup.images);
+        // only the dabs that can reach the canvas
+        for t in dabs.into_iter().filter(|t| (range.0..=range.1).contains(t)) {
+            let center = (x0 + dx * t, y0 + dy * t);
+            let centers: Vec<_> = std::iter::once(center)
+                .chain(self.symmetry.images(center))
+                .collect();
 
-                if target_px_in_bounds((px, py), self.pixel_buffer_width, self.pixel_buffer_height) {
-                    let smudge_dx = -dx * self.smudge_strength;
-                    let smudge_dy = -dy * self.smudge_strength;
+            // the first dab only picks up what's under it
+            let picked_up = pickup.len() == centers.len()
+                && pickup.iter().all(|image| image.len() == stamp.alpha.len());
+            if !picked_up {
+                pickup = centers
+                    .iter()
+                    .map(|&center| {
+                        let mut picked = vec![None; stamp.alpha.len()];
+                        for (i, index, (px, py), _) in dab_indexed(&stamp, center, width, height) {
+                            picked[i] = Some(self.seen(px, py, index));
+                        }
+                        picked
+                    })
+                    .collect();
+                continue;
+            }
 
-                    let target_px = (px as f32 + smudge_dx) as i32;
-                    let target_py = (py as f32 + smudge_dy) as i32;
+            for (pickup, center) in pickup.iter_mut().zip(centers) {
+                self.smudge_dab(&stamp, center, pickup, pickup_rate);
+            }
+        }
+        self.pickup.images = pickup;
+        dirty
+    }
 
-                    if target_px_in_bounds(
-                        (target_px, target_py),
-                        self.pixel_buffer_width,
-                        self.pixel_buffer_height,
-                    ) {
-                        let stamp_alpha = stamp_pixel.color.a();
-                        let blend_strength = stamp_alpha * self.smudge_strength;
+    /// Lays down the color `pickup` is carrying with a dab of `stamp` centered on `center`,
+    /// then picks up `pickup_rate` of what was under it.
+    fn smudge_dab(
+        &mut self,
+        stamp: &Stamp,
+        center: (f32, f32),
+        pickup: &mut [Option<[f32; 4]>],
+        pickup_rate: f32,
+    ) {
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let mut written = 0;
+        for (i, index, (px, py), alpha) in dab_indexed(stamp, center, width, height) {
+            let Some(carried) = &mut pickup[i] else {
+                continue;
+            };
+            written += 1;
+            // what was there before the dab, as what the dab lays down would just be
+            // picked up again where it covers the pixel fully
+            let under = self.seen(px, py, index);
 
-                        if blend_strength > 0.0 {
-                            let index = (py * self.pixel_buffer_width as i32 + px) as usize;
-                            let target_index =
-                                (target_py * self.pixel_buffer_width as i32 + target_px) as usize;
-                            let current_color = self.pixel_buffer[index];
-                            let target_color = self.pixel_buffer[target_index];
+            let blend_strength = (alpha * self.smudge_strength).min(1.0);
+            let blend_strength = match self.mask {
+                Some(mask) => blend_strength * mask[index] as f32 / 255.0,
+                None => blend_strength,
+            };
+            if blend_strength > 0.0 {
+                let current_color = self.pixels.pixels()[index].to_array();
+                let [r, g, b, a] = std::array::from_fn(|c| {
+                    let current = current_color[c] as f32;
+                    (current + (carried[c] - current) * blend_strength).round() as u8
+                });
+                self.pixels.pixels_mut()[index] = Color32::from_rgba_premultiplied(r, g, b, a);
+            }
 
-                            let blend = |c1: u8, c2: u8, t: f32| -> u8 {
-                                ((c1 as f32) * (1.0 - t) + (c2 as f32) * t) as u8
-                            };
+            for (carried, under) in carried.iter_mut().zip(under) {
+                *carried += (under - *carried) * pickup_rate;
+            }
+        }
+        if let Some(stats) = self.stats.as_deref_mut() {
+            stats.record_dab(stamp, written);
+        }
+    }
 
-                            let src_alpha = target_color.a() as f32 / 255.0;
-                            let dst_alpha = current_color.a() as f32 / 255.0;
-                            let mix_factor = blend_strength;
+    /// The premultiplied channels of the buffer's pixel at `(x, y)`, `index` in the buffer,
+    /// as color is picked up from it. With a merged source, it's the merged pixel.
+    fn seen(&self, x: i32, y: i32, index: usize) -> [f32; 4] {
+        let pixel = self.pixels.pixels()[index];
+        let pixel = match &self.merged {
+            Some(merged) => merged.composite(x, y, pixel).unwrap_or(pixel),
+            None => pixel,
+        };
+        pixel.to_array().map(|c| c as f32)
+    }
+}
 
-                            let out_alpha = src_alpha * mix_factor + dst_alpha * (1.0 - mix_factor);
+/// Whether a [`DodgeBurnOperation`] lightens or darkens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DodgeBurnMode {
+    #[default]
+    Dodge,
+    Burn,
+}
 
-                            let new_color = Color32::from_rgba_premultiplied(
-                                blend(current_color.r(), target_color.r(), mix_factor),
-                                blend(current_color.g(), target_color.g(), mix_factor),
-                                blend(current_color.b(), target_color.b(), mix_factor),
-                                (out_alpha * 255.0) as u8,
-                            );
+impl DodgeBurnMode {
+    pub const ALL: [DodgeBurnMode; 2] = [DodgeBurnMode::Dodge, DodgeBurnMode::Burn];
 
-                            self.pixel_buffer[index] = new_color;
-                        }
-                    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            DodgeBurnMode::Dodge => "Dodge",
+            DodgeBurnMode::Burn => "Burn",
+        }
+    }
+}
+
+/// Which tones a [`DodgeBurnOperation`] mostly works on. The others are changed less the
+/// further they are from them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TonalRange {
+    Shadows,
+    #[default]
+    Midtones,
+    Highlights,
+}
+
+impl TonalRange {
+    pub const ALL: [TonalRange; 3] = [
+        TonalRange::Shadows,
+        TonalRange::Midtones,
+        TonalRange::Highlights,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TonalRange::Shadows => "Shadows",
+            TonalRange::Midtones => "Midtones",
+            TonalRange::Highlights => "Highlights",
+        }
+    }
+
+    /// How much a pixel of sRGB `luminance`, from 0 to 1, is changed, from 0 to 1. Midtones
+    /// are changed fully at 50% gray and not at all at black or white.
+    fn weight(self, luminance: f32) -> f32 {
+        let luminance = luminance.clamp(0.0, 1.0);
+        match self {
+            TonalRange::Shadows => (1.0 - luminance) * (1.0 - luminance),
+            TonalRange::Midtones => 4.0 * luminance * (1.0 - luminance),
+            TonalRange::Highlights => luminance * luminance,
+        }
+    }
+}
+
+/// Lightens or darkens what's under the stroke without changing its alpha, by as much as
+/// the stamp covers it, scaled by the brush's strength and how much the pixel's tone is in
+/// `range`.
+pub struct DodgeBurnOperation<'a> {
+    pub pixels: &'a mut dyn PixelBuffer,
+    pub brush: &'a Brush,
+    pub segment: StrokeSegment,
+    pub mode: DodgeBurnMode,
+    pub range: TonalRange,
+    /// See [`PaintOperation::stamp_cache`].
+    pub stamp_cache: &'a mut StampCache,
+    /// See [`PaintOperation::stroke_state`].
+    pub stroke_state: &'a mut StrokeState,
+    /// How much of each pixel of the buffer can be changed, one byte per pixel, such as a
+    /// selection's.
+    pub mask: Option<&'a [u8]>,
+    /// Where each dab is repeated. The tip is repeated as it is, not turned.
+    pub symmetry: Symmetry,
+}
+
+impl DodgeBurnOperation<'_> {
+    /// Dodges or burns the segment, returning the part of the buffer it could have changed.
+    pub fn process(self) -> DirtyRect {
+        let StrokeSegment {
+            from: (x0, y0),
+            to: (x1, y1),
+        } = self.segment;
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        // placed before any of the segment is skipped, see `PaintOperation::process`
+        let dabs = self
+            .stroke_state
+            .place(distance, 0.0, self.brush.dab_spacing(), None);
+
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let Some((range, dirty)) = reachable_part(
+            self.segment.from,
+            self.segment.to,
+            self.brush,
+            width,
+            height,
+            &self.symmetry,
+        ) else {
+            return DirtyRect::default();
+        };
+
+        let strength = self.brush.strength();
+        let stamp = self.stamp_cache.get(self.brush);
+        // only the dabs that can reach the canvas
+        for t in dabs.into_iter().filter(|t| (range.0..=range.1).contains(t)) {
+            let center = (x0 + dx * t, y0 + dy * t);
+            let images = self.symmetry.images(center);
+            let pixels = std::iter::once(center)
+                .chain(images)
+                .flat_map(|center| dab(&stamp, center, width, height));
+            for (index, _, alpha) in pixels {
+                let amount = match self.mask {
+                    Some(mask) => alpha * strength * mask[index] as f32 / 255.0,
+                    None => alpha * strength,
+                };
+                let pixel = self.pixels.pixels()[index];
+                if amount > 0.0 && pixel.a() > 0 {
+                    self.pixels.pixels_mut()[index] = self.adjust(pixel, amount.min(1.0));
+                }
+            }
+        }
+        dirty
+    }
+
+    /// `pixel` dodged or burned by `amount`, from 0 to 1, of the way to white or black,
+    /// weighted by its tone. The color is adjusted straight and in sRGB, so the tones are
+    /// spread the way they look, and premultiplied again by the pixel's own alpha.
+    fn adjust(&self, pixel: Color32, amount: f32) -> Color32 {
+        let [r, g, b, a] = Rgba::from(pixel).to_array();
+        let straight = [r, g, b].map(|c| gamma_from_linear((c / a).min(1.0)));
+        let luminance = 0.2126 * straight[0] + 0.7152 * straight[1] + 0.0722 * straight[2];
+        let amount = amount * self.range.weight(luminance);
+        let [r, g, b] = straight.map(|c| {
+            let c = match self.mode {
+                DodgeBurnMode::Dodge => c + (1.0 - c) * amount,
+                DodgeBurnMode::Burn => c * (1.0 - amount),
+            };
+            gamma_u8_from_linear_f32(linear_from_gamma(c) * a)
+        });
+        Color32::from_rgba_premultiplied(r, g, b, pixel.a())
+    }
+}
+
+/// What a [`NoiseOperation`] adds grain to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoiseMode {
+    /// The same noise in every color channel, so only the lightness varies.
+    #[default]
+    Monochrome,
+    /// Different noise in each color channel.
+    Color,
+    /// Noise in the alpha, leaving the color as it is.
+    Alpha,
+}
+
+impl NoiseMode {
+    pub const ALL: [NoiseMode; 3] = [NoiseMode::Monochrome, NoiseMode::Color, NoiseMode::Alpha];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            NoiseMode::Monochrome => "Monochrome",
+            NoiseMode::Color => "Color",
+            NoiseMode::Alpha => "Alpha",
+        }
+    }
+}
+
+/// Adds random grain to what's under the stroke, by as much as the stamp covers it and the
+/// brush's strength. Transparent pixels are left alone, as there's nothing to add grain to.
+///
+/// The grain is worked out from `seed` and where each pixel is rather than drawn in order,
+/// so a stroke replayed with the same seed gets the same grain, and dabs that overlap agree
+/// on it.
+pub struct NoiseOperation<'a> {
+    pub pixels: &'a mut dyn PixelBuffer,
+    /// Where the buffer's top left pixel is in the space the grain is laid out in, so the
+    /// grain stays put when the buffer grows at its top or left.
+    pub origin: (i32, i32),
+    pub brush: &'a Brush,
+    pub segment: StrokeSegment,
+    pub mode: NoiseMode,
+    /// How big the grain is, in pixels. At 1 or less every pixel gets its own noise, and
+    /// larger grain is smooth value noise with a random value every `scale` pixels.
+    pub scale: f32,
+    /// The stroke's seed, see [`StrokeRng`].
+    pub seed: u64,
+    /// See [`PaintOperation::stamp_cache`].
+    pub stamp_cache: &'a mut StampCache,
+    /// See [`PaintOperation::stroke_state`].
+    pub stroke_state: &'a mut StrokeState,
+    /// How much of each pixel of the buffer can be changed, one byte per pixel, such as a
+    /// selection's.
+    pub mask: Option<&'a [u8]>,
+    /// Where each dab is repeated. The tip is repeated as it is, not turned.
+    pub symmetry: Symmetry,
+}
+
+impl NoiseOperation<'_> {
+    /// Adds grain along the segment, returning the part of the buffer it could have changed.
+    pub fn process(self) -> DirtyRect {
+        let StrokeSegment {
+            from: (x0, y0),
+            to: (x1, y1),
+        } = self.segment;
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        // placed before any of the segment is skipped, see `PaintOperation::process`
+        let dabs = self
+            .stroke_state
+            .place(distance, 0.0, self.brush.dab_spacing(), None);
+
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let Some((range, dirty)) = reachable_part(
+            self.segment.from,
+            self.segment.to,
+            self.brush,
+            width,
+            height,
+            &self.symmetry,
+        ) else {
+            return DirtyRect::default();
+        };
+
+        // every dab adds the same grain again, so it's shared out between the dabs that
+        // cover a pixel as the stroke passes over it, making a pass add the brush's strength
+        let diameter = (self.brush.radius() * 2.0).max(1.0);
+        let strength = self.brush.strength() * (self.brush.dab_spacing() / diameter).min(1.0);
+        let stamp = self.stamp_cache.get(self.brush);
+        // only the dabs that can reach the canvas
+        for t in dabs.into_iter().filter(|t| (range.0..=range.1).contains(t)) {
+            let center = (x0 + dx * t, y0 + dy * t);
+            let images = self.symmetry.images(center);
+            let pixels = std::iter::once(center)
+                .chain(images)
+                .flat_map(|center| dab(&stamp, center, width, height));
+            for (index, (px, py), alpha) in pixels {
+                let amount = match self.mask {
+                    Some(mask) => alpha * strength * mask[index] as f32 / 255.0,
+                    None => alpha * strength,
+                };
+                let pixel = self.pixels.pixels()[index];
+                if amount > 0.0 && pixel.a() > 0 {
+                    let at = (px + self.origin.0, py + self.origin.1);
+                    self.pixels.pixels_mut()[index] = self.grain(pixel, at, amount.min(1.0));
+                }
+            }
+        }
+        dirty
+    }
+
+    /// `pixel`, at `at` in the grain's space, with `amount` of the grain added, from 0 to 1.
+    /// Color grain is added to the straight sRGB color, like
+    /// [`DodgeBurnOperation`] adjusts it, so it's as strong in the shadows as the highlights.
+    fn grain(&self, pixel: Color32, at: (i32, i32), amount: f32) -> Color32 {
+        let [r, g, b, a] = Rgba::from(pixel).to_array();
+        if self.mode == NoiseMode::Alpha {
+            let alpha = (a * (1.0 + self.noise(at, 0) * amount)).clamp(0.0, 1.0);
+            // premultiplied, so scaling every channel keeps the straight color
+            return Color32::from(Rgba::from_rgba_premultiplied(r, g, b, a) * (alpha / a));
+        }
+        let straight = [r, g, b].map(|c| gamma_from_linear((c / a).min(1.0)));
+        let [r, g, b] = std::array::from_fn(|channel| {
+            let noise = match self.mode {
+                NoiseMode::Color => self.noise(at, channel as u64),
+                _ => self.noise(at, 0),
+            };
+            let c = (straight[channel] + noise * amount).clamp(0.0, 1.0);
+            gamma_u8_from_linear_f32(linear_from_gamma(c) * a)
+        });
+        Color32::from_rgba_premultiplied(r, g, b, pixel.a())
+    }
+
+    /// The grain at `at` for `channel`, from -1 to 1.
+    fn noise(&self, (x, y): (i32, i32), channel: u64) -> f32 {
+        let lattice = |x: i32, y: i32| {
+            // each point of the lattice seeds its own generator, so it doesn't matter in
+            // which order they're asked for
+            let key = (x as u32 as u64) << 32 | y as u32 as u64;
+            let seed = self.seed ^ key.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ channel << 61;
+            StrokeRng::new(seed).next_f32() * 2.0 - 1.0
+        };
+        if self.scale <= 1.0 {
+            return lattice(x, y);
+        }
+        let (fx, fy) = (x as f32 / self.scale, y as f32 / self.scale);
+        let (cx, cy) = (fx.floor(), fy.floor());
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+        let (tx, ty) = (smooth(fx - cx), smooth(fy - cy));
+        let (cx, cy) = (cx as i32, cy as i32);
+        let top = lattice(cx, cy) + (lattice(cx + 1, cy) - lattice(cx, cy)) * tx;
+        let bottom = lattice(cx, cy + 1) + (lattice(cx + 1, cy + 1) - lattice(cx, cy + 1)) * tx;
+        top + (bottom - top) * ty
+    }
+}
+
+/// How much a [`HueShiftOperation`] changes the pixels it fully covers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HsvShift {
+    /// Degrees around the color wheel.
+    pub hue: f32,
+    /// Added to the saturation, from -1 to 1.
+    pub saturation: f32,
+    /// Added to the value, from -1 to 1.
+    pub value: f32,
+}
+
+/// Below this saturation a pixel's hue is mostly rounding, see
+/// [`HueShiftOperation::adjust`].
+const NEAR_GRAY_SATURATION: f32 = 0.08;
+
+/// Shifts the hue, saturation and value of what's under the stroke without changing its
+/// alpha, by as much of `shift` as the stamp covers it and the brush's strength.
+///
+/// The stroke doesn't build up: each pixel is shifted from how it was before the stroke by
+/// the most any one dab covered it, so going over it again doesn't shift it further, and a
+/// full turn of hue leaves it as it was.
+pub struct HueShiftOperation<'a> {
+    pub pixels: &'a mut dyn PixelBuffer,
+    pub brush: &'a Brush,
+    pub segment: StrokeSegment,
+    pub shift: HsvShift,
+    /// The layer as it was before the stroke, and how much the stroke has covered it. Like
+    /// `stroke_state`, it's kept for the whole stroke and cleared before the next one.
+    pub stroke_buffer: &'a mut StrokeBuffer,
+    /// See [`PaintOperation::stamp_cache`].
+    pub stamp_cache: &'a mut StampCache,
+    /// See [`PaintOperation::stroke_state`].
+    pub stroke_state: &'a mut StrokeState,
+    /// How much of each pixel of the buffer can be changed, one byte per pixel, such as a
+    /// selection's.
+    pub mask: Option<&'a [u8]>,
+    /// Where each dab is repeated. The tip is repeated as it is, not turned.
+    pub symmetry: Symmetry,
+}
+
+impl HueShiftOperation<'_> {
+    /// Shifts the segment, returning the part of the buffer it could have changed.
+    pub fn process(self) -> DirtyRect {
+        let StrokeSegment {
+            from: (x0, y0),
+            to: (x1, y1),
+        } = self.segment;
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        // placed before any of the segment is skipped, see `PaintOperation::process`
+        let dabs = self
+            .stroke_state
+            .place(distance, 0.0, self.brush.dab_spacing(), None);
+
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let Some((range, dirty)) = reachable_part(
+            self.segment.from,
+            self.segment.to,
+            self.brush,
+            width,
+            height,
+            &self.symmetry,
+        ) else {
+            return DirtyRect::default();
+        };
+
+        let strength = self.brush.strength();
+        let stamp = self.stamp_cache.get(self.brush);
+        // only the dabs that can reach the canvas
+        for t in dabs.into_iter().filter(|t| (range.0..=range.1).contains(t)) {
+            let center = (x0 + dx * t, y0 + dy * t);
+            let images = self.symmetry.images(center);
+            let pixels = std::iter::once(center)
+                .chain(images)
+                .flat_map(|center| dab(&stamp, center, width, height));
+            for (index, _, alpha) in pixels {
+                let alpha = match self.mask {
+                    Some(mask) => alpha * mask[index] as f32 / 255.0,
+                    None => alpha,
+                };
+                if alpha <= 0.0 {
+                    continue;
+                }
+                let (coverage, before) =
+                    self.stroke_buffer
+                        .accumulate(self.pixels.pixels(), width, index, alpha);
+                if before.a() > 0 {
+                    let shifted = self.adjust(before, (coverage * strength).min(1.0));
+                    self.pixels.pixels_mut()[index] = shifted;
                 }
             }
         }
+        dirty
+    }
+
+    /// `pixel` mixed `amount`, from 0 to 1, of the way to it shifted by all of `shift`. The
+    /// color is shifted and mixed straight and in sRGB, so hue and value change the way they
+    /// look, and premultiplied again by the pixel's own alpha. Mixing rather than shifting
+    /// by less keeps soft edges from running through the hues in between.
+    ///
+    /// A pixel that's nearly gray has little hue of its own, only what rounding left it, so
+    /// it gets less of any added saturation the grayer it is, falling off with the square
+    /// of its own: raising it fully, or even in proportion, would turn the rounding of
+    /// neighbouring gray pixels into speckles of every color.
+    fn adjust(&self, pixel: Color32, amount: f32) -> Color32 {
+        let [r, g, b, a] = Rgba::from(pixel).to_array();
+        let straight = [r, g, b].map(|c| gamma_from_linear((c / a).min(1.0)));
+        let (h, s, v) = ecolor::hsv_from_rgb(straight);
+        let saturation = match self.shift.saturation > 0.0 {
+            true => self.shift.saturation * (s / NEAR_GRAY_SATURATION).min(1.0).powi(2),
+            false => self.shift.saturation,
+        };
+        let shifted = ecolor::rgb_from_hsv((
+            h + self.shift.hue / 360.0,
+            (s + saturation).clamp(0.0, 1.0),
+            (v + self.shift.value).clamp(0.0, 1.0),
+        ));
+        let [r, g, b] = std::array::from_fn(|c| {
+            let c = straight[c] + (shifted[c] - straight[c]) * amount;
+            gamma_u8_from_linear_f32(linear_from_gamma(c) * a)
+        });
+        Color32::from_rgba_premultiplied(r, g, b, pixel.a())
     }
 }
 
+/// Copies pixels from `offset` away onto what's under the stroke, by as much as the stamp
+/// covers it and the brush's strength, like a clone stamp.
+pub struct CloneOperation<'a> {
+    pub pixels: &'a mut dyn PixelBuffer,
+    /// What's copied from. Take it before the stroke starts, so cloning over the part being
+    /// cloned from doesn't copy the stroke into itself.
+    pub source: &'a dyn PixelBuffer,
+    /// Where in the source each pixel of the buffer is copied from, relative to the pixel.
+    /// Pixels whose source is outside it are left alone.
+    pub offset: (i32, i32),
+    pub brush: &'a Brush,
+    pub segment: StrokeSegment,
+    /// See [`PaintOperation::stamp_cache`].
+    pub stamp_cache: &'a mut StampCache,
+    /// See [`PaintOperation::stroke_state`].
+    pub stroke_state: &'a mut StrokeState,
+    /// How much of each pixel of the buffer can be cloned over, one byte per pixel, such as
+    /// a selection's.
+    pub mask: Option<&'a [u8]>,
+    /// Where each dab is repeated, each image copying from `offset` away from itself. The
+    /// tip is repeated as it is, not turned.
+    pub symmetry: Symmetry,
+}
+
+impl CloneOperation<'_> {
+    /// Clones along the segment, returning the part of the buffer it could have changed.
+    pub fn process(self) -> DirtyRect {
+        let StrokeSegment {
+            from: (x0, y0),
+            to: (x1, y1),
+        } = self.segment;
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        // placed before any of the segment is skipped, see `PaintOperation::process`
+        let dabs = self
+            .stroke_state
+            .place(distance, 0.0, self.brush.dab_spacing(), None);
+
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let Some((range, dirty)) = reachable_part(
+            self.segment.from,
+            self.segment.to,
+            self.brush,
+            width,
+            height,
+            &self.symmetry,
+        ) else {
+            return DirtyRect::default();
+        };
+
+        let strength = self.brush.strength();
+        let stamp = self.stamp_cache.get(self.brush);
+        let (source_width, source_height) = (self.source.width(), self.source.height());
+        // only the dabs that can reach the canvas
+        for t in dabs.into_iter().filter(|t| (range.0..=range.1).contains(t)) {
+            let center = (x0 + dx * t, y0 + dy * t);
+            let images = self.symmetry.images(center);
+            let pixels = std::iter::once(center)
+                .chain(images)
+                .flat_map(|center| dab(&stamp, center, width, height));
+            for (index, (px, py), alpha) in pixels {
+                let source = (px + self.offset.0, py + self.offset.1);
+                if !target_px_in_bounds(source, source_width, source_height) {
+                    continue;
+                }
+                let amount = match self.mask {
+                    Some(mask) => alpha * strength * mask[index] as f32 / 255.0,
+                    None => alpha * strength,
+                };
+                if amount <= 0.0 {
+                    continue;
+                }
+                let amount = amount.min(1.0);
+                let source = self.source.pixels()
+                    [source.1 as usize * source_width as usize + source.0 as usize]
+                    .to_array();
+                let current = self.pixels.pixels()[index].to_array();
+                let [r, g, b, a] = std::array::from_fn(|c| {
+                    let (from, to) = (current[c] as f32, source[c] as f32);
+                    (from + (to - from) * amount).round() as u8
+                });
+                self.pixels.pixels_mut()[index] = Color32::from_rgba_premultiplied(r, g, b, a);
+            }
+        }
+        dirty
+    }
+}
+
+/// Picks the color around `position` like an eyedropper, averaging the pixels under the
+/// kernel weighted by their alpha. A soft circle also weights each pixel by how much the
+/// circle covers it.
+pub struct EyedropperOperation<'a> {
+    pub pixels: &'a dyn PixelBuffer,
+    pub position: (f32, f32),
+    /// A square is centered on the pixel under `position`. A soft circle with a radius under
+    /// a pixel samples that pixel alone.
+    pub kernel: SampleKernel,
+    /// Whether the buffer is the visible layers already composited rather than a single
+    /// layer. A composited buffer is what's seen, so the color picked from it is opaque,
+    /// while one picked from a single layer keeps how much the layer covers there.
+    pub composited: bool,
+    /// Returned when every sampled pixel is fully transparent, so picking from an empty
+    /// part of the canvas doesn't turn the color black.
+    pub previous_color: Rgba,
+}
+
+impl EyedropperOperation<'_> {
+    /// The averaged color, straight (unmultiplied) the same way the brush color is stored.
+    pub fn process(self) -> Rgba {
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let radius = match self.kernel {
+            SampleKernel::Square(size) => {
+                let (x, y) = self.position;
+                let center = (x.floor() as i32, y.floor() as i32);
+                let pixels = self.pixels.pixels();
+                let color = average_color(width, height, center, size, |index| pixels[index]);
+                return match (color, self.composited) {
+                    (None, _) => self.previous_color,
+                    (Some(color), true) => color.set_alpha(1.0),
+                    (Some(color), false) => color,
+                };
+            }
+            SampleKernel::SoftCircle { radius } => radius,
+        };
+        let mut kernel = Brush::default();
+        kernel.set_radius(radius);
+        let stamp = kernel.compute_stamp();
+
+        let mut sum = [0.0f32; 4];
+        let mut weight = 0.0;
+        for (index, _, alpha) in dab(&stamp, self.position, width, height) {
+            let color = Rgba::from(self.pixels.pixels()[index]);
+            // summed premultiplied, so transparent pixels at an edge carry no color
+            for (sum, c) in sum.iter_mut().zip(color.to_array()) {
+                *sum += c * alpha;
+            }
+            weight += alpha;
+        }
+
+        if weight <= 0.0 || sum[3] <= 0.0 {
+            return self.previous_color;
+        }
+        let [r, g, b, a] = sum.map(|c| c / weight);
+        let color = Rgba::from_rgba_premultiplied(r, g, b, a.min(1.0)).unpremultiply();
+        match self.composited {
+            true => color.set_alpha(1.0),
+            false => color,
+        }
+    }
+}
+
+/// Restores the alpha of every pixel that changed since `before` was captured, keeping the
+/// newly painted color. Pixels that were fully transparent stay transparent, and pixels
+/// whose new alpha is zero (so no color is left to keep) are reverted entirely.
+pub fn preserve_alpha(pixel_buffer: &mut [Color32], before: &[Color32]) {
+    for (pixel, old) in pixel_buffer.iter_mut().zip(before) {
+        if *pixel == *old {
+            continue;
+        }
+        if old.a() == 0 || pixel.a() == 0 {
+            *pixel = *old;
+            continue;
+        }
+        let [r, g, b, _] = alpha::unpremultiply(*pixel);
+        *pixel = alpha::premultiply([r, g, b, old.a()]);
+    }
+}
+
+/// The range of `t` along the segment where a dab of `brush`, or one of its `symmetry`
+/// images, can touch the canvas, and the part of the canvas those dabs can reach. `None`
+/// when the segment and its images are all too far off the canvas to touch it, so it can be
+/// skipped without visiting a single pixel.
+fn reachable_part(
+    from: (f32, f32),
+    to: (f32, f32),
+    brush: &Brush,
+    canvas_width: u32,
+    canvas_height: u32,
+    symmetry: &Symmetry,
+) -> Option<((f32, f32), DirtyRect)> {
+    // the images of a segment are segments, their points at the same `t`
+    let images = symmetry.images(from).into_iter().zip(symmetry.images(to));
+    std::iter::once((from, to))
+        .chain(images)
+        .filter_map(|(from, to)| reachable_segment(from, to, brush, canvas_width, canvas_height))
+        .reduce(|(a, a_dirty), (b, b_dirty)| ((a.0.min(b.0), a.1.max(b.1)), a_dirty.union(b_dirty)))
+}
+
+/// [`reachable_part`] for each piece of `path`, with `t` the fraction of the way along the
+/// whole path by distance, as [`Polyline::at`] takes it.
+fn reachable_path(
+    path: &Polyline,
+    brush: &Brush,
+    canvas_width: u32,
+    canvas_height: u32,
+    symmetry: &Symmetry,
+) -> Option<((f32, f32), DirtyRect)> {
+    let length = path.length();
+    path.points()
+        .windows(2)
+        .enumerate()
+        .filter_map(|(i, piece)| {
+            let ((t0, t1), dirty) = reachable_part(
+                piece[0],
+                piece[1],
+                brush,
+                canvas_width,
+                canvas_height,
+                symmetry,
+            )?;
+            let (start, end) = (path.distance_at(i), path.distance_at(i + 1));
+            let along = |t: f32| match length > 0.0 {
+                true => (start + (end - start) * t) / length,
+                false => t,
+            };
+            Some(((along(t0), along(t1)), dirty))
+        })
+        .reduce(|(a, a_dirty), (b, b_dirty)| ((a.0.min(b.0), a.1.max(b.1)), a_dirty.union(b_dirty)))
+}
+
+/// [`reachable_part`] for the segment alone.
+fn reachable_segment(
+    from: (f32, f32),
+    to: (f32, f32),
+    brush: &Brush,
+    canvas_width: u32,
+    canvas_height: u32,
+) -> Option<((f32, f32), DirtyRect)> {
+    // a pixel past the radius, since stamp offsets are truncated onto the pixel grid, and
+    // half a pixel more for the antialiased rim of soft circles
+    let reach = brush.radius() + 1.5;
+    let (t0, t1) = path::clip_segment(
+        from,
+        to,
+        (-reach, -reach),
+        (canvas_width as f32 + reach, canvas_height as f32 + reach),
+    )?;
+
+    let at = |t: f32| (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+    let (start, end) = (at(t0), at(t1));
+    let min_x = (start.0.min(end.0) - reach).floor().max(0.0) as u32;
+    let min_y = (start.1.min(end.1) - reach).floor().max(0.0) as u32;
+    let max_x = ((start.0.max(end.0) + reach).ceil().max(0.0) as u32).min(canvas_width);
+    let max_y = ((start.1.max(end.1) + reach).ceil().max(0.0) as u32).min(canvas_height);
+    let dirty = DirtyRect::new(
+        min_x,
+        min_y,
+        max_x.saturating_sub(min_x),
+        max_y.saturating_sub(min_y),
+    );
+    Some(((t0, t1), dirty))
+}
+
+/// The pixels of a `width` by `height` buffer that a dab of `stamp` centered on `center`
+/// covers, each with its index in the buffer, its position and its coverage. Pixels the
+/// stamp leaves uncovered are skipped, and the dab is clipped to the buffer up front, see
+/// [`dab_clip`].
+fn dab(
+    stamp: &Stamp,
+    center: (f32, f32),
+    width: u32,
+    height: u32,
+) -> impl Iterator<Item = (usize, (i32, i32), f32)> + '_ {
+    dab_indexed(stamp, center, width, height)
+        .map(|(_, index, position, alpha)| (index, position, alpha))
+}
+
+/// [`dab`], with the index in the stamp of each pixel first, for operations that keep
+/// something for each pixel of the stamp.
+fn dab_indexed(
+    stamp: &Stamp,
+    center: (f32, f32),
+    width: u32,
+    height: u32,
+) -> impl Iterator<Item = (usize, usize, (i32, i32), f32)> + '_ {
+    let center = snapped(center);
+    let (rows, columns) = dab_clip(stamp, center, width, height);
+    rows.flat_map(move |row| {
+        let py = (center.1 + (stamp.top + row as i32) as f32) as i32;
+        let row_start = py as usize * width as usize;
+        let stamp_row_start = row * stamp.width as usize;
+        let alphas = &stamp.alpha[stamp_row_start + columns.start..stamp_row_start + columns.end];
+        (columns.clone())
+            .zip(alphas)
+            .filter(|&(_, &alpha)| alpha > 0.0)
+            .map(move |(column, &alpha)| {
+                let px = (center.0 + (stamp.left + column as i32) as f32) as i32;
+                (
+                    stamp_row_start + column,
+                    row_start + px as usize,
+                    (px, py),
+                    alpha,
+                )
+            })
+    })
+}
+
+/// `center` moved to the nearest 256th of a pixel. Dabs are placed along a stroke by
+/// fractions of its length, and one meant to land right on a pixel's edge can come out a
+/// hair short of it, which would truncate it into the pixel before, so a stroke would paint
+/// differently depending on how it was split into segments.
+fn snapped(center: (f32, f32)) -> (f32, f32) {
+    let snap = |v: f32| (v * 256.0).round() / 256.0;
+    (snap(center.0), snap(center.1))
+}
+
+/// The rows and columns of `stamp` that land on a `width` by `height` buffer when it's
+/// centered on `center`, so a dab only goes over those, with no check at each pixel. A row
+/// or column lands where its offset from the center does, truncated, so they only move one
+/// way down or across the stamp, and the ones on the buffer are a run of them.
+fn dab_clip(
+    stamp: &Stamp,
+    center: (f32, f32),
+    width: u32,
+    height: u32,
+) -> (Range<usize>, Range<usize>) {
+    let on_buffer = |first: i32, count: u32, center: f32, size: u32| {
+        let lands = |i: usize| (center + (first + i as i32) as f32) as i32;
+        let start = partition_point(count as usize, |i| lands(i) < 0);
+        let end = partition_point(count as usize, |i| lands(i) < size as i32);
+        start..end.max(start)
+    };
+    (
+        on_buffer(stamp.top, stamp.height, center.1, height),
+        on_buffer(stamp.left, stamp.width, center.0, width),
+    )
+}
+
+/// How many of `0..len` come before the first that `pred` is false for, where it's true up
+/// to some point and false after, found by halving.
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match pred(middle) {
+            true => low = middle + 1,
+            false => high = middle,
+        }
+    }
+    low
+}
+
 fn target_px_in_bounds(target_px: (i32, i32), buffer_width: u32, buffer_height: u32) -> bool {
     target_px.0 >= 0
         && target_px.0 < buffer_width as i32
         && target_px.1 >= 0
         && target_px.1 < buffer_height as i32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_buffer::PixelSlice;
+    use crate::sampling::SampleSize;
+
+    /// What a paint stroke keeps from one segment to the next, set up the way the canvas
+    /// sets it up for its brush.
+    struct Stroke {
+        brush: Brush,
+        color: Rgba,
+        stamp_cache: StampCache,
+        rng: StrokeRng,
+        stroke_state: StrokeState,
+        stroke_buffer: Option<StrokeBuffer>,
+        is_eraser: bool,
+        alpha_lock: bool,
+    }
+
+    impl Stroke {
+        fn new(brush: &Brush, color: Rgba) -> Self {
+            let needs_buffer = brush.accumulation().needs_buffer(brush.opacity())
+                || brush.float_buffer()
+                || brush.pixel_snap();
+            Self {
+                brush: brush.clone(),
+                color,
+                stamp_cache: StampCache::default(),
+                rng: StrokeRng::new(0),
+                stroke_state: StrokeState::default(),
+                stroke_buffer: needs_buffer.then(StrokeBuffer::default),
+                is_eraser: false,
+                alpha_lock: false,
+            }
+        }
+
+        /// Paints the segment from `from` to `to`, masked by `mask` if there is one.
+        fn segment(
+            &mut self,
+            pixels: &mut dyn PixelBuffer,
+            from: (f32, f32),
+            to: (f32, f32),
+            mask: Option<&[u8]>,
+        ) -> DirtyRect {
+            PaintOperation {
+                pixels,
+                brush: &self.brush,
+                color: self.color.into(),
+                segment: StrokeSegment { from, to },
+                previous_cursor_position: None,
+                is_eraser: self.is_eraser,
+                accumulation: self.brush.accumulation(),
+                stroke_buffer: self.stroke_buffer.as_mut(),
+                elapsed: 0.0,
+                stamp_cache: &mut self.stamp_cache,
+                rng: &mut self.rng,
+                stroke_state: &mut self.stroke_state,
+                taper_in: 0.0,
+                taper_out: 0.0,
+                stroke_length: None,
+                mask,
+                alpha_lock: self.alpha_lock,
+                symmetry: Symmetry::None,
+                stats: None,
+            }
+            .process()
+        }
+
+        /// Paints through `points`, a segment between each pair, the first starting the
+        /// stroke with a dab.
+        fn through(&mut self, pixels: &mut dyn PixelBuffer, points: &[(f32, f32)]) {
+            self.segment(pixels, points[0], points[0], None);
+            for pair in points.windows(2) {
+                self.segment(pixels, pair[0], pair[1], None);
+            }
+        }
+    }
+
+    /// Smudges through `points` with `brush`, a segment between each pair, masked by `mask`
+    /// if there is one.
+    fn smudge(
+        pixels: &mut dyn PixelBuffer,
+        brush: &Brush,
+        points: &[(f32, f32)],
+        mask: Option<&[u8]>,
+    ) {
+        let mut stamp_cache = StampCache::default();
+        let mut stroke_state = StrokeState::default();
+        let mut pickup = SmudgePickup::default();
+        for pair in points.windows(2) {
+            SmudgeOperation {
+                pixels: &mut *pixels,
+                brush,
+                segment: StrokeSegment {
+                    from: pair[0],
+                    to: pair[1],
+                },
+                smudge_strength: 0.6,
+                pickup_rate: 0.3,
+                stamp_cache: &mut stamp_cache,
+                stroke_state: &mut stroke_state,
+                pickup: &mut pickup,
+                symmetry: Symmetry::None,
+                mask,
+                merged: None,
+                stats: None,
+            }
+            .process();
+        }
+    }
+
+    /// A straight brush color from sRGB components, the way the color picker gives it.
+    fn srgb(r: u8, g: u8, b: u8, a: u8) -> Rgba {
+        alpha::brush_color_from_srgba([r, g, b, a])
+    }
+
+    /// How far alpha dips along a row `offset` pixels off the middle of a straight 200px
+    /// stroke of `brush`, as a fraction of the most it reaches there, away from the ends.
+    fn row_dip(brush: &Brush, offset: u32) -> f32 {
+        let (width, height) = (240, 40);
+        let mut pixels = vec![Color32::TRANSPARENT; (width * height) as usize];
+        let mut buffer = PixelSlice::new(&mut pixels, width, height);
+        Stroke::new(brush, Rgba::from_rgb(0.0, 0.0, 0.0))
+            .through(&mut buffer, &[(20.0, 20.0), (220.0, 20.0)]);
+        let row = (20 + offset) * width;
+        let middle = &pixels[(row + 60) as usize..(row + 180) as usize];
+        let alphas = middle.iter().map(|pixel| pixel.a() as f32);
+        let (least, most) = alphas.fold((255.0f32, 0.0f32), |(least, most), a| {
+            (least.min(a), most.max(a))
+        });
+        (most - least) / most
+    }
+
+    #[test]
+    fn the_default_brush_paints_straight_strokes_without_beads() {
+        let brush = Brush::default();
+        for offset in [0, 3] {
+            let dip = row_dip(&brush, offset);
+            assert!(dip <= 0.03, "dips {dip} {offset}px off the middle");
+        }
+        // a full radius apart, dabs bead even along the middle
+        assert!(row_dip(&brush.with_spacing(1.0), 0) > 0.1);
+    }
+
+    #[test]
+    fn a_stroke_in_many_frames_is_the_same_as_one_in_a_single_frame() {
+        let (width, height) = (240, 40);
+        let brush = Brush::default().with_radius(6.0).with_strength(0.1);
+        let color = Rgba::from_rgb(0.0, 0.0, 0.0);
+        let paint = |points: &[(f32, f32)]| {
+            let mut pixels = vec![Color32::TRANSPARENT; (width * height) as usize];
+            let mut buffer = PixelSlice::new(&mut pixels, width, height);
+            Stroke::new(&brush, color).through(&mut buffer, points);
+            pixels
+        };
+        let whole = paint(&[(20.0, 20.0), (220.0, 20.0)]);
+        let frames: Vec<(f32, f32)> = (0..=137)
+            .map(|i| (20.0 + 200.0 * i as f32 / 137.0, 20.0))
+            .collect();
+        assert!(paint(&frames) == whole);
+
+        // no darker dots where frames meet
+        let middle = &whole[(20 * width + 40) as usize..(20 * width + 200) as usize];
+        let alphas: Vec<u8> = middle.iter().map(|pixel| pixel.a()).collect();
+        let (least, most) = (alphas.iter().min().unwrap(), alphas.iter().max().unwrap());
+        assert!(*least > 0 && most - least <= 1, "{alphas:?}");
+    }
+
+    /// Stamps are coverage alone, so the stamp cached for a red stroke serves a blue one
+    /// with the same shape, and each comes out as if it had a cache of its own.
+    #[test]
+    fn a_cached_stamp_serves_strokes_of_any_color() {
+        let (width, height) = (60, 30);
+        let brush = Brush::default().with_radius(6.0).with_hardness(0.5);
+        let points = [(10.0, 15.0), (50.0, 15.0)];
+        let paint = |stroke: &mut Stroke| {
+            let mut pixels = vec![Color32::TRANSPARENT; (width * height) as usize];
+            stroke.through(&mut PixelSlice::new(&mut pixels, width, height), &points);
+            pixels
+        };
+
+        let mut stamp_cache = StampCache::default();
+        for color in [srgb(255, 0, 0, 255), srgb(0, 0, 255, 255)] {
+            let mut stroke = Stroke::new(&brush, color);
+            stroke.stamp_cache = std::mem::take(&mut stamp_cache);
+            let cached = paint(&mut stroke);
+            stamp_cache = stroke.stamp_cache;
+
+            assert!(cached == paint(&mut Stroke::new(&brush, color)));
+            let middle = cached[(15 * width + 30) as usize];
+            assert_eq!(alpha::brush_color(middle), color);
+        }
+        assert_eq!(stamp_cache.computed(), 1);
+    }
+
+    #[test]
+    fn erasing_clears_the_middle_and_fades_the_edges_by_the_stamp() {
+        const SIZE: u32 = 40;
+        let brush = Brush::default().with_radius(8.0).with_hardness(0.5);
+        let red = Color32::from_rgb(200, 40, 40);
+        let mut pixels = vec![red; (SIZE * SIZE) as usize];
+        let mut buffer = PixelSlice::new(&mut pixels, SIZE, SIZE);
+        let mut eraser = Stroke::new(&brush, Rgba::WHITE);
+        eraser.is_eraser = true;
+        eraser.segment(&mut buffer, (20.0, 20.0), (20.0, 20.0), None);
+
+        let stamp = brush.compute_stamp();
+        for (index, pixel) in pixels.iter().enumerate() {
+            let (x, y) = ((index % 40) as i32 - 20, (index / 40) as i32 - 20);
+            let covered = stamp.alpha_at(x, y);
+            // faded under one level of alpha, it's cleared outright
+            if 255.0 * (1.0 - covered) < 1.0 {
+                assert_eq!(*pixel, Color32::TRANSPARENT, "at {x}, {y}");
+            } else {
+                // premultiplied, so the color fades along with the alpha
+                let expected = Color32::from(Rgba::from(red) * (1.0 - covered));
+                let near = |a: u8, b: u8| a.abs_diff(b) <= 1;
+                let channels = pixel.to_array().into_iter().zip(expected.to_array());
+                assert!(channels.into_iter().all(|(a, b)| near(a, b)), "at {x}, {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn erasing_a_faint_pixel_lightly_clears_it_rather_than_sticking() {
+        let brush = Brush::default().with_radius(2.0).with_strength(0.3);
+        let mut pixels = vec![Color32::from_rgba_premultiplied(2, 1, 1, 2); 9];
+        let mut buffer = PixelSlice::new(&mut pixels, 3, 3);
+        // a click at a time, as a segment that doesn't move lands no more dabs
+        for _ in 0..5 {
+            let mut eraser = Stroke::new(&brush, Rgba::WHITE);
+            eraser.is_eraser = true;
+            eraser.segment(&mut buffer, (1.0, 1.0), (1.0, 1.0), None);
+        }
+        assert_eq!(pixels[4], Color32::TRANSPARENT);
+    }
+
+    #[test]
+    fn alpha_lock_recolors_without_changing_any_alpha() {
+        // red fading from transparent on the left to half opaque on the right
+        let (width, height) = (32, 16);
+        let gradient: Vec<Color32> = (0..width * height)
+            .map(|i| alpha::premultiply([220, 30, 30, (i % width * 4) as u8]))
+            .collect();
+        let brush = Brush::default().with_radius(6.0).with_hardness(1.0);
+        let mut pixels = gradient.clone();
+        let mut buffer = PixelSlice::new(&mut pixels, width, height);
+        let mut stroke = Stroke::new(&brush, srgb(20, 40, 230, 255));
+        stroke.alpha_lock = true;
+        stroke.through(&mut buffer, &[(-4.0, 8.0), (36.0, 8.0)]);
+
+        for (index, (pixel, before)) in pixels.iter().zip(&gradient).enumerate() {
+            assert_eq!(pixel.a(), before.a(), "at {index}");
+        }
+        // transparent pixels stay exactly transparent
+        assert!((0..height).all(|y| pixels[(y * width) as usize] == Color32::TRANSPARENT));
+        // and the rest are recolored evenly, however faint
+        for x in 8..width {
+            let [r, _, b, _] = alpha::unpremultiply(pixels[(8 * width + x) as usize]);
+            assert!(r < 40 && b > 200, "at {x}: {r}, {b}");
+        }
+
+        // nor does erasing change anything
+        let mut eraser = Stroke::new(&brush, Rgba::WHITE);
+        (eraser.is_eraser, eraser.alpha_lock) = (true, true);
+        let before = pixels.clone();
+        let mut buffer = PixelSlice::new(&mut pixels, width, height);
+        eraser.through(&mut buffer, &[(-4.0, 8.0), (36.0, 8.0)]);
+        assert!(pixels == before);
+    }
+
+    #[test]
+    fn strokes_across_the_selection_only_touch_what_is_selected() {
+        // the left half selected, a column half selected, and the rest not at all
+        let (width, height) = (32, 16);
+        let mask: Vec<u8> = (0..width * height)
+            .map(|i| match i % width {
+                0..=15 => 255,
+                16 => 128,
+                _ => 0,
+            })
+            .collect();
+        let grey = Color32::from_gray(90);
+        let brush = Brush::default().with_radius(5.0).with_hardness(1.0);
+        let across = [(4.0, 8.0), (28.0, 8.0)];
+        let row = |pixels: &[Color32], x: u32| pixels[(8 * width + x) as usize];
+
+        let paint = |mask: Option<&[u8]>| {
+            let mut pixels = vec![Color32::TRANSPARENT; (width * height) as usize];
+            let mut buffer = PixelSlice::new(&mut pixels, width, height);
+            let mut stroke = Stroke::new(&brush, Rgba::from_rgb(1.0, 0.0, 0.0));
+            // as the canvas does, so the mask scales the stroke rather than each dab
+            stroke.stroke_buffer = Some(StrokeBuffer::default());
+            stroke.segment(&mut buffer, across[0], across[0], mask);
+            stroke.segment(&mut buffer, across[0], across[1], mask);
+            pixels
+        };
+        let (painted, unmasked) = (paint(Some(&mask)), paint(None));
+        for (index, pixel) in painted.iter().enumerate() {
+            match index as u32 % width {
+                0..=15 => assert_eq!(*pixel, unmasked[index], "at {index}"),
+                16 => {}
+                _ => assert_eq!(*pixel, Color32::TRANSPARENT, "at {index}"),
+            }
+        }
+        // half selected, half painted
+        assert_eq!(row(&unmasked, 16).a(), 255);
+        assert!(row(&painted, 16).a().abs_diff(128) <= 1);
+
+        let mut smudged: Vec<Color32> = (0..width * height)
+            .map(|i| match i % width < 8 {
+                true => Color32::RED,
+                false => grey,
+            })
+            .collect();
+        let before = smudged.clone();
+        let mut buffer = PixelSlice::new(&mut smudged, width, height);
+        smudge(&mut buffer, &brush, &across, Some(&mask));
+        let changed = |x: u32| row(&smudged, x) != row(&before, x);
+        assert!(changed(10));
+        for (index, pixel) in smudged.iter().enumerate() {
+            if index as u32 % width > 16 {
+                assert_eq!(*pixel, before[index], "at {index}");
+            }
+        }
+    }
+
+    /// `pixel` after a click of a hard brush of `strength` dodging or burning it.
+    fn dodge_burned(
+        pixel: Color32,
+        strength: f32,
+        mode: DodgeBurnMode,
+        range: TonalRange,
+    ) -> Color32 {
+        let mut pixels = [pixel; 9 * 9];
+        DodgeBurnOperation {
+            pixels: &mut PixelSlice::new(&mut pixels, 9, 9),
+            brush: &Brush::default()
+                .with_radius(3.0)
+                .with_hardness(1.0)
+                .with_strength(strength),
+            segment: StrokeSegment {
+                from: (4.0, 4.0),
+                to: (4.0, 4.0),
+            },
+            mode,
+            range,
+            stamp_cache: &mut StampCache::default(),
+            stroke_state: &mut StrokeState::default(),
+            mask: None,
+            symmetry: Symmetry::None,
+        }
+        .process();
+        pixels[4 * 9 + 4]
+    }
+
+    #[test]
+    fn dodging_midtones_lightens_gray_by_the_strength() {
+        let gray = Color32::from_gray(128);
+        for strength in [0.25, 0.5, 1.0] {
+            let (dodge, burn) = (DodgeBurnMode::Dodge, DodgeBurnMode::Burn);
+            let lighter = dodge_burned(gray, strength, dodge, TonalRange::Midtones);
+            let expected = 128.0 + 127.0 * strength;
+            assert!(
+                (lighter.r() as f32 - expected).abs() <= 1.0,
+                "{strength}: {lighter:?}"
+            );
+            assert!(lighter.r() == lighter.g() && lighter.g() == lighter.b());
+            let darker = dodge_burned(gray, strength, burn, TonalRange::Midtones);
+            let expected = 128.0 * (1.0 - strength);
+            assert!(
+                (darker.r() as f32 - expected).abs() <= 1.0,
+                "{strength}: {darker:?}"
+            );
+        }
+
+        // the alpha stays as it was, and the color is lightened straight
+        let faint = alpha::premultiply([128, 128, 128, 100]);
+        let lighter = dodge_burned(faint, 0.5, DodgeBurnMode::Dodge, TonalRange::Midtones);
+        assert_eq!(lighter.a(), 100);
+        assert!(alpha::unpremultiply(lighter)[0].abs_diff(192) <= 2);
+        // and only midtones are touched by a midtone dodge
+        let white = dodge_burned(
+            Color32::WHITE,
+            1.0,
+            DodgeBurnMode::Burn,
+            TonalRange::Midtones,
+        );
+        assert_eq!(white, Color32::WHITE);
+    }
+
+    /// A 32x16 buffer of `pixel` after a noise stroke of `mode` and `scale` along its middle
+    /// row, seeded with `seed`, through `points`.
+    fn noised(
+        pixel: Color32,
+        mode: NoiseMode,
+        scale: f32,
+        seed: u64,
+        points: &[(f32, f32)],
+    ) -> Vec<Color32> {
+        let (width, height) = (32, 16);
+        let mut pixels = vec![pixel; (width * height) as usize];
+        let brush = Brush::default().with_radius(6.0).with_strength(0.8);
+        let mut stamp_cache = StampCache::default();
+        let mut stroke_state = StrokeState::default();
+        let frames = std::iter::once((points[0], points[0]))
+            .chain(points.windows(2).map(|pair| (pair[0], pair[1])));
+        for (from, to) in frames {
+            NoiseOperation {
+                pixels: &mut PixelSlice::new(&mut pixels, width, height),
+                origin: (0, 0),
+                brush: &brush,
+                segment: StrokeSegment { from, to },
+                mode,
+                scale,
+                seed,
+                stamp_cache: &mut stamp_cache,
+                stroke_state: &mut stroke_state,
+                mask: None,
+                symmetry: Symmetry::None,
+            }
+            .process();
+        }
+        pixels
+    }
+
+    const ACROSS: [(f32, f32); 2] = [(4.0, 8.0), (28.0, 8.0)];
+
+    #[test]
+    fn replaying_a_noise_stroke_adds_the_same_grain() {
+        let gray = Color32::from_gray(128);
+        let frames: Vec<(f32, f32)> = (0..=9)
+            .map(|i| (4.0 + 24.0 * i as f32 / 9.0, 8.0))
+            .collect();
+        for mode in NoiseMode::ALL {
+            for scale in [1.0, 4.0] {
+                let first = noised(gray, mode, scale, 7, &ACROSS);
+                assert!(first.iter().any(|&p| p != gray), "{mode:?} at {scale}");
+                assert!(first == noised(gray, mode, scale, 7, &ACROSS));
+                // however the stroke was split into frames
+                assert!(first == noised(gray, mode, scale, 7, &frames));
+                assert!(first != noised(gray, mode, scale, 8, &ACROSS));
+            }
+        }
+    }
+
+    #[test]
+    fn noise_only_changes_what_its_mode_is_for() {
+        let gray = Color32::from_gray(128);
+        let mono = noised(gray, NoiseMode::Monochrome, 1.0, 7, &ACROSS);
+        assert!(mono
+            .iter()
+            .all(|p| p.a() == 255 && p.r() == p.g() && p.g() == p.b()));
+        let color = noised(gray, NoiseMode::Color, 1.0, 7, &ACROSS);
+        assert!(color.iter().all(|p| p.a() == 255));
+        assert!(color.iter().any(|p| p.r() != p.g() || p.g() != p.b()));
+        let alpha = noised(gray, NoiseMode::Alpha, 1.0, 7, &ACROSS);
+        assert!(alpha.iter().any(|p| p.a() < 255));
+        for pixel in alpha.iter().filter(|p| p.a() > 64) {
+            let [r, g, b, _] = alpha::unpremultiply(*pixel);
+            assert!(r.abs_diff(128) <= 2 && r == g && g == b, "{pixel:?}");
+        }
+
+        // nothing to add grain to
+        for mode in NoiseMode::ALL {
+            let clear = noised(Color32::TRANSPARENT, mode, 1.0, 7, &ACROSS);
+            assert!(clear.iter().all(|&p| p == Color32::TRANSPARENT), "{mode:?}");
+        }
+    }
+
+    /// `pixels`, 16 wide, after a hard hue shift stroke of `shift` through `points` that
+    /// covers the middle of them.
+    fn hue_shifted(
+        mut pixels: Vec<Color32>,
+        shift: HsvShift,
+        points: &[(f32, f32)],
+    ) -> Vec<Color32> {
+        let height = pixels.len() as u32 / 16;
+        let brush = Brush::default().with_radius(24.0).with_hardness(1.0);
+        let mut stroke_buffer = StrokeBuffer::default();
+        let mut stamp_cache = StampCache::default();
+        let mut stroke_state = StrokeState::default();
+        let frames = std::iter::once((points[0], points[0]))
+            .chain(points.windows(2).map(|pair| (pair[0], pair[1])));
+        for (from, to) in frames {
+            HueShiftOperation {
+                pixels: &mut PixelSlice::new(&mut pixels, 16, height),
+                brush: &brush,
+                segment: StrokeSegment { from, to },
+                shift,
+                stroke_buffer: &mut stroke_buffer,
+                stamp_cache: &mut stamp_cache,
+                stroke_state: &mut stroke_state,
+                mask: None,
+                symmetry: Symmetry::None,
+            }
+            .process();
+        }
+        pixels
+    }
+
+    /// Colors of every hue, lightness and alpha, gray and nearly gray ones among them.
+    fn colorful() -> Vec<Color32> {
+        (0..16 * 16u32)
+            .map(|i| {
+                let [r, g, b] = [i * 37, i * 91 + 40, i * 13 + 200].map(|c| (c % 256) as u8);
+                let a = [255, 200, 128, 40][i as usize % 4];
+                match i % 5 {
+                    0 => alpha::premultiply([r, r, r, a]),
+                    1 => alpha::premultiply([r, r.saturating_add(1), r, a]),
+                    _ => alpha::premultiply([r, g, b, a]),
+                }
+            })
+            .collect()
+    }
+
+    const OVER_THE_MIDDLE: [(f32, f32); 1] = [(8.0, 8.0)];
+
+    #[test]
+    fn a_full_turn_of_hue_changes_nothing() {
+        let before = colorful();
+        for hue in [360.0, -360.0, 720.0] {
+            let shift = HsvShift {
+                hue,
+                ..HsvShift::default()
+            };
+            let after = hue_shifted(before.clone(), shift, &OVER_THE_MIDDLE);
+            for (was, now) in before.iter().zip(&after) {
+                assert_eq!(was.a(), now.a());
+                let [was, now] = [was, now].map(|&p| alpha::unpremultiply(p));
+                // straight, where the rounding of faint pixels shows most
+                let limit = if now[3] < 64 { 8 } else { 2 };
+                assert!(
+                    (0..3).all(|c| was[c].abs_diff(now[c]) <= limit),
+                    "{hue}: {was:?} became {now:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shifting_hue_turns_the_color_and_keeps_the_alpha() {
+        let red = alpha::premultiply([255, 0, 0, 128]);
+        let shift = HsvShift {
+            hue: 120.0,
+            ..HsvShift::default()
+        };
+        let after = hue_shifted(vec![red; 16 * 16], shift, &OVER_THE_MIDDLE);
+        for pixel in after {
+            assert_eq!(pixel.a(), 128);
+            let [r, g, b, _] = alpha::unpremultiply(pixel);
+            assert!(r <= 1 && g >= 254 && b <= 1, "{pixel:?}");
+        }
+
+        // going back over the stroke doesn't shift it any further
+        let back_and_forth = [(8.0, 8.0), (12.0, 8.0), (8.0, 8.0)];
+        let once = hue_shifted(colorful(), shift, &OVER_THE_MIDDLE);
+        assert!(hue_shifted(colorful(), shift, &back_and_forth) == once);
+    }
+
+    /// Gray pixels only have the hue rounding left them, which saturating them must not turn
+    /// into speckles of color.
+    #[test]
+    fn saturating_nearly_gray_pixels_keeps_them_nearly_gray() {
+        let speckled: Vec<Color32> = (0..16 * 16u32)
+            .map(|i| {
+                let g = 120 + (i % 3) as u8;
+                Color32::from_rgb(121, g, 121 + (i % 2) as u8)
+            })
+            .collect();
+        let shift = HsvShift {
+            saturation: 1.0,
+            ..HsvShift::default()
+        };
+        for pixel in hue_shifted(speckled, shift, &OVER_THE_MIDDLE) {
+            let [r, g, b, _] = pixel.to_array();
+            let spread = r.max(g).max(b) - r.min(g).min(b);
+            // fully saturated, the difference of a level or two would be all of it
+            assert!(spread <= 8, "{pixel:?}");
+        }
+
+        let dull = Color32::from_rgb(160, 120, 120);
+        let saturated = hue_shifted(vec![dull; 16 * 16], shift, &OVER_THE_MIDDLE);
+        assert!(saturated
+            .iter()
+            .all(|p| p.r() == 160 && p.g() == 0 && p.b() == 0));
+    }
+
+    #[test]
+    fn dabs_are_never_closer_than_half_a_pixel() {
+        let brush = Brush::default()
+            .with_radius(Brush::MIN_RADIUS)
+            .with_spacing(Brush::MIN_SPACING);
+        assert_eq!(brush.dab_spacing(), Brush::MIN_DAB_SPACING);
+        assert_eq!(Brush::MIN_DAB_SPACING, 0.5);
+    }
+
+    #[test]
+    fn painting_bytes_matches_painting_colors() {
+        const SIZE: u32 = 48;
+        let brush = Brush::default().with_radius(6.0).with_strength(0.4);
+        let color = srgb(200, 60, 20, 180);
+        let points = [(4.0, 20.0), (30.5, 8.25), (44.0, 40.0), (10.0, 30.0)];
+        let across = [(2.0, 24.0), (20.0, 24.0), (40.0, 26.0)];
+
+        let mut colors = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+        let mut buffer = PixelSlice::new(&mut colors, SIZE, SIZE);
+        Stroke::new(&brush, color).through(&mut buffer, &points);
+        smudge(&mut buffer, &brush, &across, None);
+
+        let mut bytes = vec![0; (SIZE * SIZE * 4) as usize];
+        let mut buffer = PixelSlice::from_bytes(&mut bytes, SIZE, SIZE);
+        Stroke::new(&brush, color).through(&mut buffer, &points);
+        smudge(&mut buffer, &brush, &across, None);
+
+        assert!(colors.iter().any(|pixel| pixel.a() > 0));
+        let expected: Vec<u8> = colors.iter().flat_map(|pixel| pixel.to_array()).collect();
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn sampling_an_edge_keeps_its_color() {
+        // red on the left half, clear on the right
+        let mut pixels: Vec<Color32> = (0..8 * 8)
+            .map(|index| match index % 8 < 4 {
+                true => Color32::RED,
+                false => Color32::TRANSPARENT,
+            })
+            .collect();
+        let sample = |pixels: &mut Vec<Color32>, kernel, composited| {
+            EyedropperOperation {
+                pixels: &PixelSlice::new(pixels, 8, 8),
+                // about 3x3 pixels around the first clear column, one of them red
+                position: (4.0, 4.5),
+                kernel,
+                composited,
+                previous_color: Rgba::BLUE,
+            }
+            .process()
+        };
+
+        let soft = SampleKernel::SoftCircle { radius: 1.5 };
+        let square = SampleKernel::Square(SampleSize::Square3);
+        for kernel in [soft, square] {
+            let color = sample(&mut pixels, kernel, false);
+            assert!(
+                color.r() > 0.99,
+                "{color:?} is darker than the red it was picked from"
+            );
+            assert_eq!((color.g(), color.b()), (0.0, 0.0));
+            assert!(color.a() > 0.0 && color.a() < 0.5, "{color:?}");
+            // what's seen of it is opaque
+            let color = sample(&mut pixels, kernel, true);
+            assert!(color.r() > 0.99 && color.a() == 1.0, "{color:?}");
+        }
+        // nothing to pick keeps the color there was
+        let color = sample(&mut vec![Color32::TRANSPARENT; 64], square, false);
+        assert_eq!(color, Rgba::BLUE);
+    }
+
+    #[test]
+    fn wash_strokes_stay_at_one_pass_where_they_cross() {
+        const SIZE: u32 = 48;
+        // crosses itself at (24, 24)
+        let points = [(8.0, 8.0), (40.0, 40.0), (40.0, 8.0), (8.0, 40.0)];
+        let alpha = |accumulation| {
+            let brush = Brush::default()
+                .with_radius(6.0)
+                .with_strength(0.1)
+                .with_accumulation(accumulation);
+            let mut pixels = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+            let mut buffer = PixelSlice::new(&mut pixels, SIZE, SIZE);
+            Stroke::new(&brush, Rgba::RED).through(&mut buffer, &points);
+            let at = |x: u32, y: u32| pixels[(y * SIZE + x) as usize].a();
+            let max = pixels.iter().map(|pixel| pixel.a()).max().unwrap();
+            // where the stroke crosses itself, where it passes once, and the most anywhere
+            (at(24, 24), at(14, 14), max)
+        };
+
+        // no darker anywhere than one dab at the brush's strength
+        assert_eq!(alpha(StrokeAccumulation::Wash), (26, 26, 26));
+        // while overlapping dabs build up, the more where the stroke crosses itself
+        let (crossing, once, max) = alpha(StrokeAccumulation::BuildUp);
+        assert!(once > 26 * 3 && crossing > once, "{crossing} {once}");
+        assert!(max >= crossing);
+    }
+
+    #[test]
+    fn smudging_is_the_same_however_finely_the_stroke_is_sampled() {
+        const WIDTH: u32 = 64;
+        let brush = Brush::default().with_radius(6.0);
+        let smudged = |steps: usize| {
+            // red on the left, blue on the right
+            let mut pixels: Vec<Color32> = (0..WIDTH * 32)
+                .map(|index| match index % WIDTH < WIDTH / 2 {
+                    true => Color32::RED,
+                    false => Color32::BLUE,
+                })
+                .collect();
+            let points: Vec<(f32, f32)> = (0..=steps)
+                .map(|step| (8.0 + 48.0 * step as f32 / steps as f32, 16.0))
+                .collect();
+            smudge(
+                &mut PixelSlice::new(&mut pixels, WIDTH, 32),
+                &brush,
+                &points,
+                None,
+            );
+            pixels
+        };
+
+        let (coarse, fine) = (smudged(5), smudged(50));
+        let difference = coarse
+            .iter()
+            .zip(&fine)
+            .flat_map(|(a, b)| (0..4).map(|c| a[c].abs_diff(b[c])))
+            .max()
+            .unwrap();
+        // the dabs land in the same places, so only rounding could tell them apart
+        assert!(difference <= 1, "{difference}");
+        // and it did smudge
+        assert_ne!(coarse[16 * WIDTH as usize + 40], Color32::BLUE);
+    }
+
+    #[test]
+    fn painting_is_the_same_at_any_frame_rate() {
+        const SIZE: u32 = 64;
+        // faint dabs that build up, so an extra one anywhere would show
+        let brush = Brush::default().with_radius(5.0).with_strength(0.3);
+        let painted = |frames: usize| {
+            let mut pixels = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+            let points: Vec<(f32, f32)> = (0..=frames)
+                .map(|frame| {
+                    let t = frame as f32 / frames as f32;
+                    (8.0 + 47.0 * t, 10.0 + 41.0 * t)
+                })
+                .collect();
+            Stroke::new(&brush, Rgba::from_rgb(0.0, 0.0, 1.0))
+                .through(&mut PixelSlice::new(&mut pixels, SIZE, SIZE), &points);
+            pixels
+        };
+
+        // a second-long stroke sampled at 30, 60 and 240 frames a second
+        let expected = painted(30);
+        for frames in [60, 240] {
+            let difference = painted(frames)
+                .iter()
+                .zip(&expected)
+                .flat_map(|(a, b)| (0..4).map(|c| a[c].abs_diff(b[c])))
+                .max()
+                .unwrap();
+            assert!(difference <= 1, "{frames} frames: {difference}");
+        }
+        assert!(expected[30 * SIZE as usize + 30].a() > 0);
+    }
+
+    /// Without jitter no random numbers are drawn, so whatever the stroke is seeded with it
+    /// paints byte for byte what it did before brushes had jitter.
+    #[test]
+    fn strokes_without_jitter_are_the_same_whatever_the_seed() {
+        const SIZE: u32 = 48;
+        let points = [(6.0, 8.0), (30.0, 20.0), (40.0, 41.0)];
+        let paint = |brush: &Brush, seed: u64| {
+            let mut pixels = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+            let mut stroke = Stroke::new(brush, Rgba::from_rgb(0.2, 0.5, 0.1));
+            stroke.rng = StrokeRng::new(seed);
+            stroke.through(&mut PixelSlice::new(&mut pixels, SIZE, SIZE), &points);
+            (pixels, stroke.rng.next_f32())
+        };
+        let brushes = [
+            Brush::default().with_radius(6.0).with_strength(0.4),
+            Brush::default().with_radius(4.0).with_hardness(1.0),
+            Brush::default().with_rectangle(8.0, 3.0, 1.0),
+            Brush::default().with_ellipse(6.0, 2.0).with_angle(0.6),
+            Brush::default().with_radius(2.0).with_pixel_snap(true),
+        ];
+        for (i, brush) in brushes.iter().enumerate() {
+            let (expected, _) = paint(brush, 0);
+            assert!(expected.iter().any(|pixel| pixel.a() > 0), "brush {i}");
+            for seed in [1, 0xdead_beef, u64::MAX] {
+                let (pixels, next) = paint(brush, seed);
+                assert!(pixels == expected, "brush {i}, seed {seed}");
+                assert_eq!(
+                    next,
+                    StrokeRng::new(seed).next_f32(),
+                    "brush {i}, seed {seed}"
+                );
+            }
+
+            // and with jitter, the seed is what makes each stroke its own
+            let mut jittery = brush.clone();
+            jittery.set_size_jitter(0.5);
+            jittery.set_opacity_jitter(0.5);
+            assert!(paint(&jittery, 1).0 != paint(&jittery, 2).0, "brush {i}");
+        }
+    }
+
+    #[test]
+    fn pathological_brushes_paint_without_hanging() {
+        const SIZE: u32 = 32;
+        let started = std::time::Instant::now();
+        for brush in crate::tests::pathological_brushes() {
+            let mut pixels = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+            let points = [(2.0, 2.0), (30.0, 20.0), (30.0, 20.0), (4.0, 28.0)];
+            Stroke::new(&brush, Rgba::from_rgb(1.0, 0.5, 0.0))
+                .through(&mut PixelSlice::new(&mut pixels, SIZE, SIZE), &points);
+            // premultiplied pixels never have more color than alpha
+            for pixel in pixels {
+                assert!(
+                    pixel.r().max(pixel.g()).max(pixel.b()) <= pixel.a(),
+                    "{pixel:?}"
+                );
+            }
+        }
+        assert!(started.elapsed().as_secs() < 5, "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn pixel_snapped_strokes_paint_each_pixel_of_the_staircase_once() {
+        const SIZE: u32 = 24;
+        let brush = Brush::default().with_radius(1.0).with_pixel_snap(true);
+        // half opaque, so a pixel painted twice would show it
+        let color = srgb(0, 0, 0, 128);
+        // crosses back over its own first segment
+        let points = [(2.3, 2.7), (17.6, 8.2), (5.1, 20.9), (9.5, 1.5)];
+        let mut pixels = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+        Stroke::new(&brush, color).through(&mut PixelSlice::new(&mut pixels, SIZE, SIZE), &points);
+
+        let mut expected = vec![false; pixels.len()];
+        for pair in points.windows(2) {
+            let floor = |(x, y): (f32, f32)| (x.floor() as i32, y.floor() as i32);
+            for (x, y) in path::bresenham(floor(pair[0]), floor(pair[1])) {
+                expected[(y as u32 * SIZE + x as u32) as usize] = true;
+            }
+        }
+        for (pixel, expected) in pixels.iter().zip(expected) {
+            assert_eq!(pixel.a(), if expected { 128 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn segments_off_the_canvas_paint_nothing() {
+        const SIZE: u32 = 32;
+        let brush = Brush::default().with_radius(4.0);
+        let mut pixels = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+        let mut buffer = PixelSlice::new(&mut pixels, SIZE, SIZE);
+        let mut stroke = Stroke::new(&brush, Rgba::RED);
+        // far out, and running along just past the brush's reach of the bottom edge
+        for (from, to) in [
+            ((-5000.0, -300.0), (9000.0, -250.0)),
+            ((-40.0, 37.5), (80.0, 37.5)),
+        ] {
+            assert!(stroke.segment(&mut buffer, from, to, None).is_empty());
+        }
+        assert!(pixels.iter().all(|pixel| *pixel == Color32::TRANSPARENT));
+
+        // coming in from far away only paints where it reaches
+        let mut buffer = PixelSlice::new(&mut pixels, SIZE, SIZE);
+        let dirty = stroke.segment(&mut buffer, (-9000.0, 16.0), (8.0, 16.0), None);
+        assert_eq!(dirty.x, 0);
+        assert!(
+            dirty.y >= 16 - 6 && dirty.y + dirty.height <= 16 + 7,
+            "{dirty:?}"
+        );
+        assert!(dirty.x + dirty.width <= 8 + 7, "{dirty:?}");
+        assert!(pixels[16 * SIZE as usize] != Color32::TRANSPARENT);
+    }
+
+    /// What [`dab_indexed`] gives for a dab of `stamp` centered on `center`, worked out the
+    /// way dabs were before they were clipped up front: every pixel of the stamp, checked
+    /// for whether it lands on the buffer.
+    fn dab_checking_each_pixel(
+        stamp: &Stamp,
+        center: (f32, f32),
+        width: u32,
+        height: u32,
+    ) -> Vec<(usize, usize, (i32, i32), f32)> {
+        let center = snapped(center);
+        let mut covered = Vec::new();
+        for row in 0..stamp.height as usize {
+            for column in 0..stamp.width as usize {
+                let index = row * stamp.width as usize + column;
+                let px = (center.0 + (stamp.left + column as i32) as f32) as i32;
+                let py = (center.1 + (stamp.top + row as i32) as f32) as i32;
+                let alpha = stamp.alpha[index];
+                if target_px_in_bounds((px, py), width, height) && alpha > 0.0 {
+                    let pixel = py as usize * width as usize + px as usize;
+                    covered.push((index, pixel, (px, py), alpha));
+                }
+            }
+        }
+        covered
+    }
+
+    /// Dabs along all four edges of the buffer, at and past them, from fractional and
+    /// negative centers, cover the same pixels as checking each one, in the same order.
+    #[test]
+    fn dabs_clipped_up_front_match_checking_each_pixel() {
+        let (width, height) = (40, 30);
+        let brushes = [
+            Brush::default().with_radius(6.0),
+            Brush::default().with_radius(2.5).with_hardness(1.0),
+            Brush::default().with_radius(9.0).with_shape(None),
+            Brush::default().with_radius(0.4),
+        ];
+        // from a stamp's reach outside each edge to its reach inside, in uneven steps
+        let across =
+            |size: u32| (0..=70).map(move |i| -12.3 + i as f32 * (size as f32 + 24.6) / 70.0);
+        let mut centers = Vec::new();
+        for edge in [-0.5, 0.0, 0.25] {
+            centers.extend(across(width).map(|x| (x, edge)));
+            centers.extend(across(width).map(|x| (x, height as f32 - edge)));
+            centers.extend(across(height).map(|y| (edge, y)));
+            centers.extend(across(height).map(|y| (width as f32 - edge, y)));
+        }
+        centers.extend([
+            (-0.999, -0.001),
+            (-3.5, -7.75),
+            (-0.0, 15.0),
+            (-100.0, 15.0),
+        ]);
+
+        for brush in &brushes {
+            let stamp = brush.compute_stamp();
+            let mut clipped = vec![0.0; (width * height) as usize];
+            let mut checked = clipped.clone();
+            for &center in &centers {
+                let expected = dab_checking_each_pixel(&stamp, center, width, height);
+                let actual: Vec<_> = dab_indexed(&stamp, center, width, height).collect();
+                assert_eq!(actual, expected, "at {center:?}");
+                for (_, index, _, alpha) in actual {
+                    clipped[index] += alpha;
+                }
+                for (_, index, _, alpha) in expected {
+                    checked[index] += alpha;
+                }
+            }
+            // painted along every edge, every edge pixel is covered
+            assert!(clipped == checked);
+            let (width, height) = (width as usize, height as usize);
+            let edges = (0..width)
+                .flat_map(|x| [x, (height - 1) * width + x])
+                .chain((0..height).flat_map(|y| [y * width, y * width + width - 1]));
+            for index in edges {
+                let at = (index % width, index / width);
+                assert!(clipped[index] > 0.0, "radius {} at {at:?}", brush.radius());
+            }
+        }
+    }
+
+    #[test]
+    fn hard_circles_have_no_partly_covered_pixels() {
+        const SIZE: u32 = 20;
+        let brush = Brush::default().with_radius(5.0).with_shape(None);
+        assert!(matches!(brush, Brush::HardCircle { .. }));
+        for center in [(10.0, 10.0), (10.4, 9.7)] {
+            let mut pixels = vec![Color32::TRANSPARENT; (SIZE * SIZE) as usize];
+            let mut buffer = PixelSlice::new(&mut pixels, SIZE, SIZE);
+            Stroke::new(&brush, Rgba::RED).segment(&mut buffer, center, center, None);
+            assert!(pixels.iter().all(|pixel| [0, 255].contains(&pixel.a())));
+            // every pixel within 5 of the one the dab is centered on
+            let painted = pixels.iter().filter(|pixel| pixel.a() == 255).count();
+            assert_eq!(painted, 81);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod parallel_tests {
+    use super::*;
+    use crate::pixel_buffer::PixelSlice;
+    use crate::SecondaryTip;
+
+    const SIZE: u32 = 96;
+
+    /// A layer with something on it everywhere, of colors and alphas that vary across it.
+    fn painted() -> Vec<Color32> {
+        (0..SIZE * SIZE)
+            .map(|i| {
+                let (x, y) = (i % SIZE, i / SIZE);
+                alpha::premultiply([(x * 5) as u8, (y * 3) as u8, 90, (x + y) as u8 | 1])
+            })
+            .collect()
+    }
+
+    /// A dab of `brush` centered on `center`, its rows painted in parallel or not, with the
+    /// secondary tip moved by `offset`, through a mask that fades in across the layer.
+    /// Returns the layer and how many pixels the dab covered.
+    fn dab_painted(
+        brush: &Brush,
+        center: (f32, f32),
+        offset: (f32, f32),
+        parallel: bool,
+    ) -> (Vec<Color32>, u64) {
+        let mask: Vec<u8> = (0..SIZE * SIZE)
+            .map(|i| (64 + i % SIZE * 191 / SIZE) as u8)
+            .collect();
+        let mut pixels = painted();
+        let mut stamp_cache = StampCache::default();
+        let mut operation = PaintOperation {
+            pixels: &mut PixelSlice::new(&mut pixels, SIZE, SIZE),
+            brush,
+            color: Rgba::from_rgba_premultiplied(0.9, 0.2, 0.1, 0.8).into(),
+            segment: StrokeSegment {
+                from: center,
+                to: center,
+            },
+            previous_cursor_position: None,
+            is_eraser: false,
+            accumulation: brush.accumulation(),
+            stroke_buffer: None,
+            elapsed: 0.0,
+            stamp_cache: &mut stamp_cache,
+            rng: &mut StrokeRng::new(0),
+            stroke_state: &mut StrokeState::default(),
+            taper_in: 0.0,
+            taper_out: 0.0,
+            stroke_length: None,
+            mask: Some(&mask),
+            alpha_lock: false,
+            symmetry: Symmetry::None,
+            stats: None,
+        };
+        // the secondary tip is placed for each dab, see `PaintOperation::dab_stamp`
+        let stamp = operation
+            .stamp_cache
+            .get(&brush.clone().with_secondary(None));
+        let secondary = operation.secondary();
+        let (secondary, strength) = (secondary.as_ref(), brush.strength());
+        let written = match parallel {
+            true => operation.paint_dab_rows(&stamp, center, secondary, offset, strength),
+            false => operation.paint_dab_pixels(&stamp, center, secondary, offset, strength),
+        };
+        (pixels, written)
+    }
+
+    /// Painted a row at a time in parallel, a big dab comes out exactly as it does a pixel
+    /// at a time, wherever it lands, through a secondary tip and a mask.
+    #[test]
+    fn dabs_painted_in_parallel_match_those_painted_serially() {
+        let tip = |placement| SecondaryTip {
+            brush: Box::new(Brush::default().with_radius(9.0).with_hardness(0.2)),
+            placement,
+        };
+        let brushes = [
+            None,
+            Some(tip(SecondaryPlacement::Tiled)),
+            Some(tip(SecondaryPlacement::Scattered)),
+        ]
+        .map(|secondary| {
+            Brush::default()
+                .with_radius(30.0)
+                .with_hardness(0.4)
+                .with_strength(0.7)
+                .with_secondary(secondary)
+        });
+        let centers = [
+            (48.0, 48.0),
+            (20.3, 61.7),
+            (0.0, 0.0),
+            (-12.6, 40.2),
+            (95.9, 95.5),
+            (50.0, -20.25),
+            (140.0, 48.0),
+        ];
+        for brush in &brushes {
+            assert!(brush.compute_stamp().height >= PARALLEL_MIN_ROWS);
+            for center in centers {
+                let offset = (3.5, -6.0);
+                let serial = dab_painted(brush, center, offset, false);
+                let parallel = dab_painted(brush, center, offset, true);
+                assert!(
+                    serial.0 == parallel.0,
+                    "{:?} at {center:?}",
+                    brush.secondary().map(|tip| tip.placement)
+                );
+                assert_eq!(
+                    serial.1,
+                    parallel.1,
+                    "{:?} at {center:?}",
+                    brush.secondary().map(|tip| tip.placement)
+                );
+                // only off the layer does the dab cover nothing
+                assert_eq!(serial.1 == 0, center.0 > 100.0, "at {center:?}");
+                if center == (48.0, 48.0) {
+                    assert!(serial.0 != painted());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,342 @@
+/// Simplifies a polyline with the Ramer-Douglas-Peucker algorithm, dropping every point that
+/// lies within `tolerance` pixels of the simplified path. The end points are always kept.
+pub fn simplify(points: &[(f32, f32)], tolerance: f32) -> Vec<(f32, f32)> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // iterative so long strokes can't overflow the stack
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let mut max_distance = 0.0;
+        let mut max_index = start;
+        for i in start + 1..end {
+            let distance = distance_to_segment(points[i], points[start], points[end]);
+            if distance > max_distance {
+                max_distance = distance;
+                max_index = i;
+            }
+        }
+
+        if max_distance > tolerance {
+            keep[max_index] = true;
+            ranges.push((start, max_index));
+            ranges.push((max_index, end));
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter_map(|(point, keep)| keep.then_some(*point))
+        .collect()
+}
+
+/// Samples a uniform Catmull-Rom spline through `points`, roughly every `spacing` pixels.
+/// The spline passes through every input point; the end segments reuse the end points as
+/// their outer control points.
+pub fn catmull_rom(points: &[(f32, f32)], spacing: f32) -> Vec<(f32, f32)> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    let spacing = spacing.max(0.5);
+    let last = points.len() - 1;
+    let mut samples = vec![points[0]];
+
+    for i in 0..last {
+        let p0 = points[i.saturating_sub(1)];
+        let p1 = points[i];
+        let p2 = points[i + 1];
+        let p3 = points[(i + 2).min(last)];
+
+        let length = distance(p1, p2);
+        let steps = (length / spacing).ceil().max(1.0) as usize;
+        for step in 1..=steps {
+            let t = step as f32 / steps as f32;
+            samples.push(catmull_rom_point(p0, p1, p2, p3, t));
+        }
+    }
+
+    samples
+}
+
+/// How long a piece of a curve is at most once it's flattened into straight pieces, in
+/// pixels, see [`catmull_rom_segment`].
+const FLATTEN_STEP: f32 = 2.0;
+
+/// The Catmull-Rom curve from `p1` to `p2`, with `p0` before and `p3` after as its outer
+/// control points, flattened into a polyline from `p1` to `p2` fine enough to look round.
+pub fn catmull_rom_segment(
+    p0: (f32, f32),
+    p1: (f32, f32),
+    p2: (f32, f32),
+    p3: (f32, f32),
+) -> Vec<(f32, f32)> {
+    // the curve is no longer than its control polygon
+    let reach = distance(p0, p1) + distance(p1, p2) + distance(p2, p3);
+    let steps = (reach / FLATTEN_STEP).ceil().clamp(1.0, 256.0) as usize;
+    (0..=steps)
+        .map(|step| catmull_rom_point(p0, p1, p2, p3, step as f32 / steps as f32))
+        .collect()
+}
+
+/// A polyline that can be walked by the distance along it, so things spaced along it stay
+/// evenly spaced however unevenly its points are.
+pub struct Polyline {
+    points: Vec<(f32, f32)>,
+    /// How far along the line each point is.
+    distances: Vec<f32>,
+}
+
+impl Polyline {
+    /// The line through `points`, of which there has to be at least one.
+    pub fn new(points: Vec<(f32, f32)>) -> Self {
+        assert!(!points.is_empty());
+        let mut travelled = 0.0;
+        let distances = std::iter::once(0.0)
+            .chain(points.windows(2).map(|w| {
+                travelled += distance(w[0], w[1]);
+                travelled
+            }))
+            .collect();
+        Self { points, distances }
+    }
+
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    pub fn length(&self) -> f32 {
+        self.distances[self.distances.len() - 1]
+    }
+
+    /// How far along the line its `index`th point is.
+    pub fn distance_at(&self, index: usize) -> f32 {
+        self.distances[index]
+    }
+
+    /// The point `fraction` of the way along the line, by distance. A line with no length
+    /// is all at its first point.
+    pub fn at(&self, fraction: f32) -> (f32, f32) {
+        let length = self.length();
+        if length <= 0.0 {
+            return self.points[0];
+        }
+        let target = fraction.clamp(0.0, 1.0) * length;
+        let end = self
+            .distances
+            .partition_point(|&distance| distance < target)
+            .clamp(1, self.points.len() - 1);
+        let (from, to) = (self.points[end - 1], self.points[end]);
+        let piece = self.distances[end] - self.distances[end - 1];
+        let t = match piece > 0.0 {
+            true => (target - self.distances[end - 1]) / piece,
+            false => 0.0,
+        };
+        (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t)
+    }
+}
+
+fn catmull_rom_point(
+    p0: (f32, f32),
+    p1: (f32, f32),
+    p2: (f32, f32),
+    p3: (f32, f32),
+    t: f32,
+) -> (f32, f32) {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let blend = |a: f32, b: f32, c: f32, d: f32| {
+        0.5 * (2.0 * b
+            + (c - a) * t
+            + (2.0 * a - 5.0 * b + 4.0 * c - d) * t2
+            + (3.0 * b - a - 3.0 * c + d) * t3)
+    };
+    (blend(p0.0, p1.0, p2.0, p3.0), blend(p0.1, p1.1, p2.1, p3.1))
+}
+
+/// The part of the segment from `from` to `to` inside the rectangle from `min` to `max`, as
+/// the range of `t` (0 at `from`, 1 at `to`) it covers, or `None` if the segment misses the
+/// rectangle. Touching an edge counts as inside.
+pub fn clip_segment(
+    from: (f32, f32),
+    to: (f32, f32),
+    min: (f32, f32),
+    max: (f32, f32),
+) -> Option<(f32, f32)> {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let (mut t0, mut t1) = (0.0f32, 1.0f32);
+    // Liang-Barsky: each edge either moves the start of the range in or the end of it
+    for (p, q) in [
+        (-dx, from.0 - min.0),
+        (dx, max.0 - from.0),
+        (-dy, from.1 - min.1),
+        (dy, max.1 - from.1),
+    ] {
+        if p == 0.0 {
+            // parallel to this edge, so entirely on one side of it
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let r = q / p;
+        if p < 0.0 {
+            t0 = t0.max(r);
+        } else {
+            t1 = t1.min(r);
+        }
+        if t0 > t1 {
+            return None;
+        }
+    }
+    Some((t0, t1))
+}
+
+/// Every pixel on the line from `from` to `to`, both included, as Bresenham's algorithm
+/// draws it: each step moves to one of the 8 neighbours, so diagonals form a connected
+/// staircase with no doubled pixels.
+pub fn bresenham(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let dx = (to.0 - from.0).abs();
+    let dy = -(to.1 - from.1).abs();
+    let step_x = if from.0 < to.0 { 1 } else { -1 };
+    let step_y = if from.1 < to.1 { 1 } else { -1 };
+
+    let mut points = Vec::with_capacity(dx.max(-dy) as usize + 1);
+    let (mut x, mut y) = from;
+    let mut error = dx + dy;
+    loop {
+        points.push((x, y));
+        if (x, y) == to {
+            return points;
+        }
+        let doubled = error * 2;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+pub fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    let dx = b.0 - a.0;
+    let dy = b.1 - a.1;
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// Shortest distance from `point` to the segment from `a` to `b`.
+pub fn distance_to_segment(point: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let dx = b.0 - a.0;
+    let dy = b.1 - a.1;
+    let length_squared = dx * dx + dy * dy;
+    if length_squared <= 0.0 {
+        return distance(point, a);
+    }
+
+    let t = (((point.0 - a.0) * dx + (point.1 - a.1) * dy) / length_squared).clamp(0.0, 1.0);
+    distance(point, (a.0 + dx * t, a.1 + dy * t))
+}
+
+/// Total length of the polyline through `points`.
+pub fn length(points: &[(f32, f32)]) -> f32 {
+    points.windows(2).map(|w| distance(w[0], w[1])).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jitter::StrokeRng;
+
+    /// How far `point` is from the nearest part of the polyline through `points`.
+    fn distance_to_polyline(point: (f32, f32), points: &[(f32, f32)]) -> f32 {
+        points
+            .windows(2)
+            .map(|w| distance_to_segment(point, w[0], w[1]))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    #[test]
+    fn refitting_a_jittery_stroke_keeps_close_to_it() {
+        const TOLERANCE: f32 = 1.5;
+        // a gentle wave drawn by a shaky hand, a point every pixel or so
+        let mut rng = StrokeRng::new(7);
+        let raw: Vec<(f32, f32)> = (0..300)
+            .map(|i| {
+                let x = i as f32;
+                let shake = (rng.next_f32() - 0.5, rng.next_f32() - 0.5);
+                (x + shake.0, 50.0 + 20.0 * (x / 40.0).sin() + shake.1)
+            })
+            .collect();
+
+        let simplified = simplify(&raw, TOLERANCE);
+        assert!(simplified.len() * 10 < raw.len(), "{}", simplified.len());
+        assert_eq!(
+            (simplified[0], simplified[simplified.len() - 1]),
+            (raw[0], raw[raw.len() - 1])
+        );
+        for &point in &raw {
+            assert!(distance_to_polyline(point, &simplified) <= TOLERANCE);
+        }
+
+        let refit = catmull_rom(&simplified, 2.0);
+        let deviation = refit
+            .iter()
+            .map(|&point| distance_to_polyline(point, &raw))
+            .fold(0.0, f32::max);
+        assert!(deviation <= TOLERANCE * 2.0, "{deviation}");
+        // as long as the stroke, give or take the shake smoothed out of it
+        let ratio = length(&refit) / length(&raw);
+        assert!((0.9..=1.0).contains(&ratio), "{ratio}");
+    }
+
+    #[test]
+    fn bresenham_draws_the_classic_staircases() {
+        assert_eq!(bresenham((0, 0), (3, 1)), [(0, 0), (1, 0), (2, 1), (3, 1)]);
+        assert_eq!(bresenham((0, 0), (1, 3)), [(0, 0), (0, 1), (1, 2), (1, 3)]);
+        assert_eq!(
+            bresenham((2, 2), (-1, -1)),
+            [(2, 2), (1, 1), (0, 0), (-1, -1)]
+        );
+        assert_eq!(
+            bresenham((5, 0), (0, 2)),
+            [(5, 0), (4, 0), (3, 1), (2, 1), (1, 2), (0, 2)]
+        );
+        assert_eq!(bresenham((3, 4), (3, 4)), [(3, 4)]);
+    }
+
+    #[test]
+    fn clipping_segments_to_a_rectangle() {
+        let (min, max) = ((0.0, 0.0), (10.0, 10.0));
+        let close =
+            |(a, b): (f32, f32), (c, d): (f32, f32)| (a - c).abs() < 1e-5 && (b - d).abs() < 1e-5;
+        // fully inside
+        assert_eq!(
+            clip_segment((2.0, 3.0), (8.0, 7.0), min, max),
+            Some((0.0, 1.0))
+        );
+        // across the top right corner, in from above and out to the right
+        let range = clip_segment((6.0, -2.0), (14.0, 6.0), min, max).unwrap();
+        assert!(close(range, (0.25, 0.5)), "{range:?}");
+        // just past the corner, missing it
+        assert_eq!(clip_segment((9.0, -2.0), (13.0, 2.0), min, max), None);
+        // parallel to an edge, just outside it and right on it
+        assert_eq!(clip_segment((-5.0, -0.5), (15.0, -0.5), min, max), None);
+        let range = clip_segment((-5.0, 10.0), (15.0, 10.0), min, max).unwrap();
+        assert!(close(range, (0.25, 0.75)), "{range:?}");
+        // a point
+        assert_eq!(
+            clip_segment((4.0, 4.0), (4.0, 4.0), min, max),
+            Some((0.0, 1.0))
+        );
+        assert_eq!(clip_segment((-4.0, 4.0), (-4.0, 4.0), min, max), None);
+    }
+}
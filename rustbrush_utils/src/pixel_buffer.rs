@@ -0,0 +1,166 @@
+use std::ops::Range;
+
+use ecolor::Color32;
+
+/// A rectangle of pixels that changed, or should be changed. Widths and heights of zero are
+/// empty rectangles, which is what nothing changing looks like.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DirtyRect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The whole of a `width` by `height` buffer.
+    pub fn full(width: u32, height: u32) -> Self {
+        Self::new(0, 0, width, height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The smallest rectangle covering both.
+    pub fn union(self, other: Self) -> Self {
+        if self.is_empty() {
+            return other;
+        }
+        if other.is_empty() {
+            return self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Self::new(x, y, right - x, bottom - y)
+    }
+
+    /// The part covered by both, empty if they don't overlap.
+    pub fn intersect(self, other: Self) -> Self {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= x || bottom <= y {
+            return Self::default();
+        }
+        Self::new(x, y, right - x, bottom - y)
+    }
+
+    /// The index range of each row of the rectangle in a buffer `buffer_width` pixels wide,
+    /// top to bottom.
+    pub fn rows(&self, buffer_width: u32) -> impl Iterator<Item = Range<usize>> {
+        let (x, width, buffer_width) =
+            (self.x as usize, self.width as usize, buffer_width as usize);
+        (self.y as usize..(self.y + self.height) as usize).map(move |y| {
+            let start = y * buffer_width + x;
+            start..start + width
+        })
+    }
+}
+
+/// Copies `data`, a buffer `width` wide, into a larger `new_width` by `new_height` buffer
+/// with its top left corner at `at`, filling the rest with `fill`. Used when a layer grows,
+/// for its pixels and anything else kept per pixel.
+pub fn expand<T: Copy>(
+    data: &[T],
+    width: u32,
+    new_width: u32,
+    new_height: u32,
+    at: (u32, u32),
+    fill: T,
+) -> Vec<T> {
+    let mut expanded = vec![fill; new_width as usize * new_height as usize];
+    if width > 0 {
+        let rect = DirtyRect::new(at.0, at.1, width, data.len() as u32 / width);
+        for (row, source) in rect.rows(new_width).zip(data.chunks_exact(width as usize)) {
+            expanded[row].copy_from_slice(source);
+        }
+    }
+    expanded
+}
+
+/// Pixels that [`Filter`](crate::filter_registry::Filter)s and brush operations can work on:
+/// premultiplied sRGB, `width` pixels to a row.
+pub trait PixelBuffer {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn pixels(&self) -> &[Color32];
+    fn pixels_mut(&mut self) -> &mut [Color32];
+
+    /// Copies out the pixels in `rect`, row by row.
+    fn copy_rect(&self, rect: DirtyRect) -> Vec<Color32> {
+        let pixels = self.pixels();
+        rect.rows(self.width())
+            .flat_map(|row| pixels[row].iter().copied())
+            .collect()
+    }
+
+    /// Writes back pixels taken with [`PixelBuffer::copy_rect`].
+    fn write_rect(&mut self, rect: DirtyRect, source: &[Color32]) {
+        let width = self.width();
+        let pixels = self.pixels_mut();
+        for (row, source) in rect
+            .rows(width)
+            .zip(source.chunks_exact(rect.width as usize))
+        {
+            pixels[row].copy_from_slice(source);
+        }
+    }
+}
+
+/// A [`PixelBuffer`] over borrowed pixels, such as a layer's.
+pub struct PixelSlice<'a> {
+    pixels: &'a mut [Color32],
+    width: u32,
+    height: u32,
+}
+
+impl<'a> PixelSlice<'a> {
+    /// Panics if there aren't exactly `width * height` pixels.
+    pub fn new(pixels: &'a mut [Color32], width: u32, height: u32) -> Self {
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        Self {
+            pixels,
+            width,
+            height,
+        }
+    }
+
+    /// Borrowed RGBA8 bytes, four to a pixel, premultiplied the same way as [`Color32`],
+    /// for pixels kept as plain bytes rather than colors. Panics if there aren't exactly
+    /// `width * height * 4` bytes.
+    pub fn from_bytes(bytes: &'a mut [u8], width: u32, height: u32) -> Self {
+        assert_eq!(bytes.len(), width as usize * height as usize * 4);
+        Self::new(bytemuck::cast_slice_mut(bytes), width, height)
+    }
+}
+
+impl PixelBuffer for PixelSlice<'_> {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn pixels(&self) -> &[Color32] {
+        self.pixels
+    }
+
+    fn pixels_mut(&mut self) -> &mut [Color32] {
+        self.pixels
+    }
+}
@@ -0,0 +1,432 @@
+use std::cmp::Ordering;
+#[cfg(feature = "serde")]
+use std::path::Path;
+
+#[cfg(feature = "serde")]
+use crate::Brush;
+
+/// What's known about a brush preset apart from the brush itself, for finding it again once
+/// there are more than fit in a list: its name, tags to search it by, whether it's a
+/// favorite, and when it was last used.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PresetInfo {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub favorite: bool,
+    /// When the preset was last picked, as a count that goes up with every pick across all
+    /// the presets, so the higher it is the more recently it was used. 0 if it never was.
+    pub last_used: u64,
+}
+
+impl PresetInfo {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            ..Self::default()
+        }
+    }
+
+    /// Whether the name or any of the tags contains `query`, ignoring case. Everything
+    /// matches an empty query.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        query.is_empty()
+            || self.name.to_lowercase().contains(&query)
+            || self
+                .tags
+                .iter()
+                .any(|tag| tag.to_lowercase().contains(&query))
+    }
+
+    /// Whether the preset has `tag`, ignoring case.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+
+    /// Adds `tag` unless the preset already has it. Blank tags aren't added.
+    pub fn add_tag(&mut self, tag: &str) {
+        let tag = tag.trim();
+        if !tag.is_empty() && !self.has_tag(tag) {
+            self.tags.push(tag.to_string());
+        }
+    }
+}
+
+impl AsRef<PresetInfo> for PresetInfo {
+    fn as_ref(&self) -> &PresetInfo {
+        self
+    }
+}
+
+impl AsMut<PresetInfo> for PresetInfo {
+    fn as_mut(&mut self) -> &mut PresetInfo {
+        self
+    }
+}
+
+// The functions below take anything holding a `PresetInfo`, so presets can be kept along
+// with their brushes and still be searched in place.
+
+/// The presets matching `query`, as indices into `presets`, most recently used first. Ties,
+/// such as presets that were never used, go by name, ignoring case, and then by where they
+/// are in `presets`, so the order never jumps around between searches.
+pub fn search<P: AsRef<PresetInfo>>(presets: &[P], query: &str) -> Vec<usize> {
+    let info = |index: usize| presets[index].as_ref();
+    let mut found: Vec<usize> = (0..presets.len())
+        .filter(|&index| info(index).matches(query))
+        .collect();
+    found.sort_by(|&a, &b| {
+        info(b)
+            .last_used
+            .cmp(&info(a).last_used)
+            .then_with(|| by_name(info(a), info(b)))
+            .then(a.cmp(&b))
+    });
+    found
+}
+
+/// The favorites among `presets`, as indices, by name like a shelf that stays put however
+/// they're used.
+pub fn favorites<P: AsRef<PresetInfo>>(presets: &[P]) -> Vec<usize> {
+    let info = |index: usize| presets[index].as_ref();
+    let mut found: Vec<usize> = (0..presets.len())
+        .filter(|&index| info(index).favorite)
+        .collect();
+    found.sort_by(|&a, &b| by_name(info(a), info(b)).then(a.cmp(&b)));
+    found
+}
+
+/// Marks the preset at `index` as the one used most recently. Does nothing if there's no
+/// preset there.
+pub fn mark_used<P: AsRef<PresetInfo> + AsMut<PresetInfo>>(presets: &mut [P], index: usize) {
+    let latest = presets.iter().map(|preset| preset.as_ref().last_used).max();
+    if let Some(preset) = presets.get_mut(index) {
+        preset.as_mut().last_used = latest.unwrap_or(0) + 1;
+    }
+}
+
+/// Adds `tag` to each of the presets at `indices`, such as a whole batch just imported
+/// from a brush pack.
+pub fn tag_all<P: AsMut<PresetInfo>>(
+    presets: &mut [P],
+    indices: impl IntoIterator<Item = usize>,
+    tag: &str,
+) {
+    for index in indices {
+        if let Some(preset) = presets.get_mut(index) {
+            preset.as_mut().add_tag(tag);
+        }
+    }
+}
+
+fn by_name(a: &PresetInfo, b: &PresetInfo) -> Ordering {
+    a.name.to_lowercase().cmp(&b.name.to_lowercase())
+}
+
+/// The version of the preset file format [`Brush::save_preset`] writes. A file of a later
+/// version isn't loaded, rather than loaded as something it may not be.
+///
+/// A preset file is JSON: the version, and the brush with its kind of tip named by `tip`.
+/// Fields that aren't known are ignored, so files from later versions that only add fields
+/// can still say they're this version, and any that are missing are the default brush's.
+#[cfg(feature = "serde")]
+pub const PRESET_VERSION: u32 = 1;
+
+/// The extension of preset files.
+#[cfg(feature = "serde")]
+pub const PRESET_EXTENSION: &str = "json";
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct PresetFile<'a> {
+    version: u32,
+    brush: &'a Brush,
+}
+
+/// Why a preset file couldn't be saved or loaded.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum PresetFileError {
+    Io(std::io::Error),
+    /// The file isn't a preset, or the brush in it isn't one that can be used.
+    Format(serde_json::Error),
+    /// The file is of a later version than [`PRESET_VERSION`].
+    UnsupportedVersion(u32),
+}
+
+#[cfg(feature = "serde")]
+impl std::fmt::Display for PresetFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresetFileError::Io(error) => write!(f, "{}", error),
+            PresetFileError::Format(error) => write!(f, "not a brush preset: {}", error),
+            PresetFileError::UnsupportedVersion(version) => write!(
+                f,
+                "the preset is version {}, and only up to {} can be loaded",
+                version, PRESET_VERSION
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for PresetFileError {}
+
+#[cfg(feature = "serde")]
+impl Brush {
+    /// Saves the brush as a preset file at `path`, see [`PRESET_VERSION`].
+    pub fn save_preset(&self, path: &Path) -> Result<(), PresetFileError> {
+        let file = PresetFile {
+            version: PRESET_VERSION,
+            brush: self,
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(PresetFileError::Format)?;
+        std::fs::write(path, json).map_err(PresetFileError::Io)
+    }
+
+    /// Loads the brush in the preset file at `path`. The version is checked before the
+    /// brush is looked at, so a later version is reported as such rather than as a brush
+    /// that doesn't make sense. Settings out of range are corrected, see [`Brush::validate`].
+    pub fn load_preset(path: &Path) -> Result<Brush, PresetFileError> {
+        #[derive(serde::Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        #[derive(serde::Deserialize)]
+        struct PresetFile {
+            brush: Brush,
+        }
+
+        let json = std::fs::read_to_string(path).map_err(PresetFileError::Io)?;
+        let Version { version } = serde_json::from_str(&json).map_err(PresetFileError::Format)?;
+        if version > PRESET_VERSION {
+            return Err(PresetFileError::UnsupportedVersion(version));
+        }
+        let mut file: PresetFile = serde_json::from_str(&json).map_err(PresetFileError::Format)?;
+        file.brush.validate();
+        Ok(file.brush)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(name: &str, tags: &[&str]) -> PresetInfo {
+        let mut preset = PresetInfo::new(name.to_string());
+        for tag in tags {
+            preset.add_tag(tag);
+        }
+        preset
+    }
+
+    #[test]
+    fn search_matches_names_and_tags_ignoring_case() {
+        let presets = [
+            preset("Soft Round", &["Basic"]),
+            preset("Chalk", &["texture", "DRY"]),
+            preset("Ink Pen", &[]),
+        ];
+        assert_eq!(search(&presets, "ROUND"), [0]);
+        assert_eq!(search(&presets, "basic"), [0]);
+        assert_eq!(search(&presets, "dry"), [1]);
+        assert_eq!(search(&presets, "  n "), [2, 0]);
+        assert!(search(&presets, "watercolor").is_empty());
+        // an empty query lists them all, by name while none have been used
+        assert_eq!(search(&presets, ""), [1, 2, 0]);
+    }
+
+    #[test]
+    fn ties_go_by_name_and_then_by_position() {
+        let presets = [
+            preset("pencil", &[]),
+            preset("Airbrush", &[]),
+            preset("Pencil", &[]),
+            preset("airbrush", &[]),
+        ];
+        assert_eq!(search(&presets, ""), [1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn the_most_recently_used_come_first() {
+        let mut presets = [
+            preset("A", &[]),
+            preset("B", &[]),
+            preset("C", &[]),
+            preset("D", &[]),
+        ];
+        mark_used(&mut presets, 2);
+        mark_used(&mut presets, 0);
+        assert_eq!(search(&presets, ""), [0, 2, 1, 3]);
+        mark_used(&mut presets, 2);
+        assert_eq!(search(&presets, ""), [2, 0, 1, 3]);
+        // picking one that isn't there changes nothing
+        mark_used(&mut presets, 10);
+        assert_eq!(search(&presets, ""), [2, 0, 1, 3]);
+    }
+
+    #[test]
+    fn favorites_stay_in_name_order_however_they_are_used() {
+        let mut presets = [
+            preset("Smudge", &[]),
+            preset("Chalk", &[]),
+            preset("Ink", &[]),
+        ];
+        presets[0].favorite = true;
+        presets[1].favorite = true;
+        mark_used(&mut presets, 0);
+        assert_eq!(favorites(&presets), [1, 0]);
+    }
+
+    #[test]
+    fn tagging_a_batch_skips_tags_they_already_have() {
+        let mut presets = [preset("A", &["Pack"]), preset("B", &[]), preset("C", &[])];
+        tag_all(&mut presets, [0, 1, 7], "pack");
+        assert_eq!(presets[0].tags, ["Pack"]);
+        assert_eq!(presets[1].tags, ["pack"]);
+        assert!(presets[2].tags.is_empty());
+        tag_all(&mut presets, [2], "  ");
+        assert!(presets[2].tags.is_empty());
+    }
+
+    /// Preset files, see [`PRESET_VERSION`].
+    #[cfg(feature = "serde")]
+    mod files {
+        use crate::falloff::{CurvePoints, FalloffCurve};
+        use crate::image_mask::ImageMask;
+        use crate::presets::*;
+        use crate::stroke::{FadeTail, StrokeAccumulation};
+        use crate::{BrushBaseSettings, SecondaryPlacement, SecondaryTip};
+        use std::sync::Arc;
+
+        /// A preset file path of its own for each test.
+        fn preset_path(name: &str) -> std::path::PathBuf {
+            std::env::temp_dir().join(format!("rustbrush-{}-{}.json", name, std::process::id()))
+        }
+
+        /// Loads `json` as a preset file.
+        fn load_json(name: &str, json: &str) -> Result<Brush, PresetFileError> {
+            let path = preset_path(name);
+            std::fs::write(&path, json).unwrap();
+            let loaded = Brush::load_preset(&path);
+            std::fs::remove_file(&path).unwrap();
+            loaded
+        }
+
+        #[test]
+        fn every_kind_of_brush_round_trips() {
+            let base = BrushBaseSettings {
+                id: "chalk".to_string(),
+                accumulation: StrokeAccumulation::Wash,
+                fade_tail: Some(FadeTail::default()),
+                flow_per_second: Some(12.0),
+                size_jitter: 0.25,
+                secondary: Some(SecondaryTip {
+                    brush: Box::new(Brush::default().with_radius(3.0)),
+                    placement: SecondaryPlacement::Scattered,
+                }),
+                ..BrushBaseSettings::default()
+            };
+            let soft = Brush::SoftCircle {
+                inner_radius: 2.0,
+                falloff: FalloffCurve::Gaussian,
+                base: base.clone(),
+            };
+            let brushes = [
+                soft.clone(),
+                soft.clone()
+                    .with_falloff(FalloffCurve::Custom(vec![1.0, 0.25, 0.0])),
+                soft.clone()
+                    .with_falloff(FalloffCurve::Points(CurvePoints::new(vec![
+                        (0.0, 1.0),
+                        (0.5, 0.8),
+                        (1.0, 0.0),
+                    ]))),
+                soft.clone().with_shape(None),
+                soft.clone().with_rectangle(12.0, 3.0, 1.5),
+                soft.clone().with_ellipse(8.0, 2.0).with_angle(0.5),
+                Brush::Stamp {
+                    mask: Arc::new(ImageMask::new(3, 2, vec![0.0, 0.5, 1.0, 1.0, 0.5, 0.0])),
+                    base,
+                },
+            ];
+            let path = preset_path("every-kind");
+            for (index, brush) in brushes.iter().enumerate() {
+                brush.save_preset(&path).unwrap();
+                let loaded = Brush::load_preset(&path).unwrap();
+                assert!(loaded == *brush, "brush {index} didn't round trip");
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn unknown_fields_are_ignored_and_missing_ones_are_the_defaults() {
+            let json = r#"{
+                "version": 1,
+                "from_the_future": true,
+                "brush": { "tip": "soft_circle", "glitter": 0.5, "base": { "radius": 4.0 } }
+            }"#;
+            let loaded = load_json("defaults", json).unwrap();
+            let expected = Brush::SoftCircle {
+                inner_radius: 1.0,
+                falloff: FalloffCurve::Cosine,
+                base: BrushBaseSettings {
+                    radius: 4.0,
+                    ..BrushBaseSettings::default()
+                },
+            };
+            assert!(loaded == expected);
+
+            let json = r#"{ "version": 1, "brush": { "tip": "square", "width": 6, "height": 2 } }"#;
+            let loaded = load_json("square", json).unwrap();
+            assert!(loaded == Brush::default().with_rectangle(6.0, 2.0, 0.0));
+        }
+
+        #[test]
+        fn presets_that_cant_be_loaded_say_why() {
+            let later = r#"{ "version": 2, "brush": { "tip": "hologram" } }"#;
+            assert!(matches!(
+                load_json("later", later),
+                Err(PresetFileError::UnsupportedVersion(2))
+            ));
+
+            let short_mask = r#"{
+                "version": 1,
+                "brush": { "tip": "stamp", "mask": { "width": 2, "height": 2, "alpha": [1.0] } }
+            }"#;
+            assert!(matches!(
+                load_json("short-mask", short_mask),
+                Err(PresetFileError::Format(_))
+            ));
+            assert!(matches!(
+                load_json("not-json", "brush"),
+                Err(PresetFileError::Format(_))
+            ));
+            let missing = preset_path("missing");
+            assert!(matches!(
+                Brush::load_preset(&missing),
+                Err(PresetFileError::Io(_))
+            ));
+        }
+
+        #[test]
+        fn loaded_settings_are_brought_into_range() {
+            let json = r#"{
+                "version": 1,
+                "brush": {
+                    "tip": "soft_circle",
+                    "falloff": { "points": [[0.5, 2.0], [0.2, -1.0]] },
+                    "base": { "radius": 5.0, "strength": 3.0 }
+                }
+            }"#;
+            let loaded = load_json("range", json).unwrap();
+            assert_eq!(loaded.strength(), 1.0);
+            let Some(FalloffCurve::Points(curve)) = loaded.falloff() else {
+                panic!("the curve wasn't loaded");
+            };
+            let points = curve.points();
+            assert_eq!((points[0].0, points[points.len() - 1].0), (0.0, 1.0));
+            assert!(points.iter().all(|&(_, y)| (0.0..=1.0).contains(&y)));
+        }
+    }
+}
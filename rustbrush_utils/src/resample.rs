@@ -0,0 +1,122 @@
+use ecolor::{Color32, Rgba};
+
+/// Shrinks layer pixels to `target_width` by `target_height`, for scaled exports, and for
+/// anything else that shows the canvas smaller. Every target pixel is the area-weighted average of the source pixels
+/// it covers, taken in linear light on premultiplied color: averaging the sRGB values would
+/// darken fine detail (a black and white checkerboard would come out 128 grey rather than
+/// 188), and averaging straight color would let transparent pixels bleed their color in.
+///
+/// The result is premultiplied like the source; use [`crate::alpha::unpremultiply`] on it
+/// for anything that wants straight color. Panics if there aren't `width * height` pixels.
+pub fn downscale(
+    pixels: &[Color32],
+    width: u32,
+    height: u32,
+    target_width: u32,
+    target_height: u32,
+) -> Vec<Color32> {
+    assert_eq!(pixels.len(), width as usize * height as usize);
+    let (target_width, target_height) = (target_width as usize, target_height as usize);
+    if width == 0 || height == 0 {
+        return vec![Color32::TRANSPARENT; target_width * target_height];
+    }
+
+    let linear: Vec<Rgba> = pixels.iter().map(|&pixel| Rgba::from(pixel)).collect();
+    let (width, height) = (width as usize, height as usize);
+    let column_weights = area_weights(width, target_width);
+    let row_weights = area_weights(height, target_height);
+
+    // columns first, then rows
+    let mut narrowed = Vec::with_capacity(target_width * height);
+    for row in linear.chunks_exact(width) {
+        narrowed.extend(
+            column_weights
+                .iter()
+                .map(|weights| weighted_sum(weights, row, 1)),
+        );
+    }
+    let mut result = Vec::with_capacity(target_width * target_height);
+    for weights in &row_weights {
+        result.extend(
+            (0..target_width)
+                .map(|x| Color32::from(weighted_sum(weights, &narrowed[x..], target_width))),
+        );
+    }
+    result
+}
+
+/// For each of `target` cells spread over `source` cells, the source cells it overlaps and
+/// how much of the target cell each one covers.
+fn area_weights(source: usize, target: usize) -> Vec<Vec<(usize, f32)>> {
+    let step = source as f32 / target as f32;
+    (0..target)
+        .map(|i| {
+            let (start, end) = (i as f32 * step, (i + 1) as f32 * step);
+            let first = start.floor() as usize;
+            let last = (end.ceil() as usize).min(source);
+            (first..last)
+                .map(|j| {
+                    let overlap = end.min((j + 1) as f32) - start.max(j as f32);
+                    (j, overlap / step)
+                })
+                .filter(|&(_, weight)| weight > 0.0)
+                .collect()
+        })
+        .collect()
+}
+
+/// Sums `values[index * stride]` by weight.
+fn weighted_sum(weights: &[(usize, f32)], values: &[Rgba], stride: usize) -> Rgba {
+    weights
+        .iter()
+        .fold(Rgba::TRANSPARENT, |sum, &(index, weight)| {
+            sum + values[index * stride] * weight
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alpha;
+
+    /// A `size` by `size` checkerboard of single pixels, alternating `a` and `b`.
+    fn checkerboard(size: u32, a: Color32, b: Color32) -> Vec<Color32> {
+        (0..size * size)
+            .map(|i| match (i % size + i / size) % 2 {
+                0 => a,
+                _ => b,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_checkerboard_averages_in_linear_light() {
+        let pixels = checkerboard(8, Color32::BLACK, Color32::WHITE);
+        let shrunk = downscale(&pixels, 8, 8, 4, 4);
+        assert_eq!(shrunk.len(), 16);
+        for pixel in shrunk {
+            // half the light, not half the sRGB value
+            assert!(
+                pixel.to_array()[..3]
+                    .iter()
+                    .all(|&c| (187..=189).contains(&c)),
+                "{pixel:?}"
+            );
+            assert_eq!(pixel.a(), 255);
+        }
+
+        // cells that don't line up with the pixels still come out even
+        let shrunk = downscale(&checkerboard(9, Color32::BLACK, Color32::WHITE), 9, 9, 2, 2);
+        assert!(shrunk.iter().all(|pixel| pixel.r().abs_diff(188) <= 3));
+    }
+
+    #[test]
+    fn transparent_pixels_dont_darken_the_average() {
+        let pixels = checkerboard(4, Color32::RED, Color32::TRANSPARENT);
+        for pixel in downscale(&pixels, 4, 4, 2, 2) {
+            let [r, g, b, a] = alpha::unpremultiply(pixel);
+            assert_eq!((r, g, b), (255, 0, 0));
+            assert!(a.abs_diff(128) <= 1);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use ecolor::{Color32, Rgba};
+
+use crate::Brush;
+
+/// The size of the square area the eyedropper averages over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleSize {
+    #[default]
+    Point,
+    Square3,
+    Square5,
+    Square11,
+}
+
+impl SampleSize {
+    pub const ALL: [SampleSize; 4] = [
+        SampleSize::Point,
+        SampleSize::Square3,
+        SampleSize::Square5,
+        SampleSize::Square11,
+    ];
+
+    /// Length of one side of the sampled square, in pixels.
+    pub fn side(&self) -> i32 {
+        match self {
+            SampleSize::Point => 1,
+            SampleSize::Square3 => 3,
+            SampleSize::Square5 => 5,
+            SampleSize::Square11 => 11,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SampleSize::Point => "1×1",
+            SampleSize::Square3 => "3×3",
+            SampleSize::Square5 => "5×5",
+            SampleSize::Square11 => "11×11",
+        }
+    }
+}
+
+/// What the eyedropper averages over around the cursor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleKernel {
+    /// Every pixel in a square of the size, equally, see [`average_color`].
+    Square(SampleSize),
+    /// A soft circle of `radius`, each pixel weighted by how much the circle covers it, see
+    /// [`crate::operations::EyedropperOperation`].
+    SoftCircle { radius: f32 },
+}
+
+impl Default for SampleKernel {
+    fn default() -> Self {
+        SampleKernel::Square(SampleSize::default())
+    }
+}
+
+impl SampleKernel {
+    /// How far from the cursor the kernel can reach a pixel, in pixels.
+    pub fn reach(&self) -> f32 {
+        match self {
+            SampleKernel::Square(size) => (size.side() / 2) as f32 + 1.0,
+            SampleKernel::SoftCircle { radius } => radius.max(Brush::MIN_RADIUS) + 1.5,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SampleKernel::Square(size) => size.label(),
+            SampleKernel::SoftCircle { .. } => "Soft Circle",
+        }
+    }
+}
+
+/// Averages the pixels in the square of `size` centered on `center`.
+///
+/// `pixel_at` is called with the buffer index of every in-bounds pixel, so the same
+/// averaging can run over a single layer or over a merged view of several layers.
+///
+/// The color channels are weighted by alpha (summed premultiplied, then divided by the
+/// total alpha) so transparent pixels at an edge don't drag the result towards black.
+/// The returned `Rgba` holds straight (unmultiplied) components, the same way the brush
+/// color is stored. Returns `None` when every sampled pixel is fully transparent.
+pub fn average_color(
+    buffer_width: u32,
+    buffer_height: u32,
+    center: (i32, i32),
+    size: SampleSize,
+    pixel_at: impl Fn(usize) -> Color32,
+) -> Option<Rgba> {
+    let half = size.side() / 2;
+    let min_x = (center.0 - half).max(0);
+    let min_y = (center.1 - half).max(0);
+    let max_x = (center.0 + half).min(buffer_width as i32 - 1);
+    let max_y = (center.1 + half).min(buffer_height as i32 - 1);
+
+    let mut sum = [0.0f32; 4];
+    let mut count = 0;
+
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let index = (y * buffer_width as i32 + x) as usize;
+            let color = Rgba::from(pixel_at(index));
+            sum[0] += color.r();
+            sum[1] += color.g();
+            sum[2] += color.b();
+            sum[3] += color.a();
+            count += 1;
+        }
+    }
+
+    if count == 0 || sum[3] <= 0.0 {
+        return None;
+    }
+
+    Some(Rgba::from_rgba_premultiplied(
+        (sum[0] / sum[3]).min(1.0),
+        (sum[1] / sum[3]).min(1.0),
+        (sum[2] / sum[3]).min(1.0),
+        sum[3] / count as f32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An 8x8 buffer, red on the left half and clear on the right.
+    fn red_edge() -> Vec<Color32> {
+        (0..8 * 8)
+            .map(|index| match index % 8 < 4 {
+                true => Color32::RED,
+                false => Color32::TRANSPARENT,
+            })
+            .collect()
+    }
+
+    fn sampled(pixels: &[Color32], center: (i32, i32), size: SampleSize) -> Option<Rgba> {
+        average_color(8, 8, center, size, |index| pixels[index])
+    }
+
+    #[test]
+    fn a_square_over_an_edge_is_red_at_partial_alpha() {
+        // the first clear column, with a red column to its left
+        let color = sampled(&red_edge(), (4, 4), SampleSize::Square3).unwrap();
+        assert!(
+            color.r() > 0.99,
+            "{color:?} is darker than the red it was picked from"
+        );
+        assert_eq!((color.g(), color.b()), (0.0, 0.0));
+        assert!((color.a() - 1.0 / 3.0).abs() < 1e-3, "{color:?}");
+    }
+
+    #[test]
+    fn each_size_averages_its_own_square() {
+        let pixels = red_edge();
+        // the red columns out of each square's, clipped to the buffer for the largest
+        for (size, alpha) in [
+            (SampleSize::Square3, 1.0 / 3.0),
+            (SampleSize::Square5, 2.0 / 5.0),
+            (SampleSize::Square11, 4.0 / 8.0),
+        ] {
+            let color = sampled(&pixels, (4, 4), size).unwrap();
+            assert!(color.r() > 0.99, "{size:?} gave {color:?}");
+            assert!((color.a() - alpha).abs() < 1e-3, "{size:?} gave {color:?}");
+        }
+        assert_eq!(sampled(&pixels, (3, 4), SampleSize::Point), Some(Rgba::RED));
+        assert_eq!(sampled(&pixels, (4, 4), SampleSize::Point), None);
+    }
+}
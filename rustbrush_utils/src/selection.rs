@@ -0,0 +1,231 @@
+use ecolor::Color32;
+
+use crate::pixel_buffer::DirtyRect;
+
+/// How many scanlines each row of pixels is sampled along when a polygon is selected, see
+/// [`SelectionMask::from_polygon`].
+const POLYGON_SUBROWS: usize = 4;
+
+/// How much of each canvas pixel is selected, from 0 (not at all) to 255 (fully).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelectionMask {
+    width: u32,
+    height: u32,
+    values: Vec<u8>,
+}
+
+impl SelectionMask {
+    /// A mask with nothing selected.
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            values: vec![0; width as usize * height as usize],
+        }
+    }
+
+    /// A mask with the pixels of `rect` selected, as much of it as is on the mask.
+    pub fn from_rect(width: u32, height: u32, rect: DirtyRect) -> Self {
+        let mut mask = Self::new(width, height);
+        let (right, bottom) = (
+            (rect.x.saturating_add(rect.width)).min(width),
+            (rect.y.saturating_add(rect.height)).min(height),
+        );
+        for y in rect.y.min(bottom)..bottom {
+            let row = (y * width) as usize;
+            mask.values[row + rect.x.min(right) as usize..row + right as usize].fill(255);
+        }
+        mask
+    }
+
+    /// A mask with the inside of the polygon through `points` selected, closed back to its
+    /// first point, such as a lasso. Where edges cross each other, the parts inside an odd
+    /// number of times are inside. Pixels the outline passes through are selected as much as
+    /// they're inside it, so the edges are antialiased.
+    pub fn from_polygon(width: u32, height: u32, points: &[(f32, f32)]) -> Self {
+        let mut mask = Self::new(width, height);
+        let points: Vec<(f32, f32)> = points
+            .iter()
+            .copied()
+            .filter(|(x, y)| x.is_finite() && y.is_finite())
+            .collect();
+        if points.len() < 3 {
+            return mask;
+        }
+        let edges: Vec<_> = points.iter().zip(points.iter().cycle().skip(1)).collect();
+        let top = points.iter().map(|p| p.1).fold(f32::INFINITY, f32::min);
+        let bottom = points.iter().map(|p| p.1).fold(f32::NEG_INFINITY, f32::max);
+        let rows = (top.max(0.0) as u32).min(height)..(bottom.max(0.0).ceil() as u32).min(height);
+
+        let mut coverage = vec![0.0; width as usize];
+        let mut crossings = Vec::new();
+        let weight = 1.0 / POLYGON_SUBROWS as f32;
+        for y in rows {
+            coverage.fill(0.0);
+            for subrow in 0..POLYGON_SUBROWS {
+                let line = y as f32 + (subrow as f32 + 0.5) * weight;
+                crossings.clear();
+                // each edge includes its top end and not its bottom, so a line through a
+                // corner crosses once, or not at all where it only touches the corner
+                crossings.extend(
+                    edges
+                        .iter()
+                        .filter(|(a, b)| (a.1 <= line) != (b.1 <= line))
+                        .map(|(a, b)| a.0 + (line - a.1) / (b.1 - a.1) * (b.0 - a.0)),
+                );
+                crossings.sort_by(f32::total_cmp);
+                for span in crossings.chunks_exact(2) {
+                    cover_span(&mut coverage, span[0], span[1], weight);
+                }
+            }
+            let row = (y * width) as usize;
+            for (value, coverage) in mask.values[row..].iter_mut().zip(&coverage) {
+                *value = (coverage.min(1.0) * 255.0).round() as u8;
+            }
+        }
+        mask
+    }
+
+    /// Selects each pixel as much as it's opaque. `Color32` is premultiplied but its alpha
+    /// isn't, so soft edges carry over as partial selection. With a `threshold`, pixels are
+    /// instead fully selected when their alpha is at least the threshold and not at all
+    /// otherwise.
+    pub fn from_alpha(pixels: &[Color32], width: u32, height: u32, threshold: Option<u8>) -> Self {
+        let values = pixels
+            .iter()
+            .map(|pixel| match threshold {
+                Some(threshold) if pixel.a() >= threshold => 255,
+                Some(_) => 0,
+                None => pixel.a(),
+            })
+            .collect();
+        Self {
+            width,
+            height,
+            values,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn values(&self) -> &[u8] {
+        &self.values
+    }
+
+    /// How much the pixel at `x`, `y` is selected, 0 outside the mask.
+    pub fn value(&self, x: u32, y: u32) -> u8 {
+        if x >= self.width || y >= self.height {
+            return 0;
+        }
+        self.values[(y * self.width + x) as usize]
+    }
+
+    /// The smallest rectangle containing every selected pixel, empty if none are.
+    pub fn bounds(&self) -> DirtyRect {
+        let width = self.width as usize;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        for (index, _) in self.values.iter().enumerate().filter(|(_, &v)| v > 0) {
+            let (x, y) = (index % width, index / width);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+        if min_x > max_x {
+            return DirtyRect::default();
+        }
+        DirtyRect::new(
+            min_x as u32,
+            min_y as u32,
+            (max_x - min_x + 1) as u32,
+            (max_y - min_y + 1) as u32,
+        )
+    }
+
+    /// True when no pixel is selected at all.
+    pub fn is_empty(&self) -> bool {
+        self.values.iter().all(|&value| value == 0)
+    }
+}
+
+/// Adds `weight` to the `coverage` of each pixel of a row between `from` and `to`, scaled by
+/// how much of the pixel is between them.
+fn cover_span(coverage: &mut [f32], from: f32, to: f32, weight: f32) {
+    let (from, to) = (from.max(0.0), to.min(coverage.len() as f32));
+    if from >= to {
+        return;
+    }
+    let (first, last) = (from as usize, to.ceil() as usize - 1);
+    if first == last {
+        coverage[first] += (to - from) * weight;
+        return;
+    }
+    coverage[first] += (first as f32 + 1.0 - from) * weight;
+    for pixel in &mut coverage[first + 1..last] {
+        *pixel += weight;
+    }
+    coverage[last] += (to - last as f32) * weight;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_rect_is_selected_as_far_as_it_is_on_the_mask() {
+        let mask = SelectionMask::from_rect(8, 6, DirtyRect::new(5, 2, 10, 2));
+        for y in 0..6 {
+            for x in 0..8 {
+                let inside = x >= 5 && (2..4).contains(&y);
+                assert_eq!(
+                    mask.value(x, y),
+                    if inside { 255 } else { 0 },
+                    "at {x}, {y}"
+                );
+            }
+        }
+        assert_eq!(mask.bounds(), DirtyRect::new(5, 2, 3, 2));
+        assert!(SelectionMask::from_rect(8, 6, DirtyRect::new(9, 9, 4, 4)).is_empty());
+    }
+
+    #[test]
+    fn a_polygon_is_antialiased_along_its_edges() {
+        // a square on half pixel bounds, so each pixel along its sides is half inside
+        let square = [(2.5, 2.5), (7.5, 2.5), (7.5, 7.5), (2.5, 7.5)];
+        let mask = SelectionMask::from_polygon(10, 10, &square);
+        for y in 0..10 {
+            for x in 0..10 {
+                let side = |v: u32| match v {
+                    2 | 7 => 0.5,
+                    3..=6 => 1.0,
+                    _ => 0.0,
+                };
+                let expected = (side(x) * side(y) * 255.0f32).round() as u8;
+                assert!(mask.value(x, y).abs_diff(expected) <= 1, "at {x}, {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn a_polygon_selects_as_much_as_its_area() {
+        let triangle = [(1.0, 1.0), (19.0, 3.0), (6.0, 17.0)];
+        let mask = SelectionMask::from_polygon(20, 20, &triangle);
+        let area = 0.5 * ((19.0 - 1.0) * (17.0 - 1.0) - (6.0 - 1.0) * (3.0 - 1.0));
+        let selected: f32 = mask.values().iter().map(|&v| v as f32 / 255.0).sum();
+        assert!((selected - area).abs() < 1.0, "{selected} != {area}");
+
+        // crossing over itself, a bow tie is two triangles meeting in the middle
+        let bow = [(0.0, 0.0), (10.0, 10.0), (10.0, 0.0), (0.0, 10.0)];
+        let mask = SelectionMask::from_polygon(10, 10, &bow);
+        assert_eq!((mask.value(1, 5), mask.value(8, 5)), (255, 255));
+        assert_eq!((mask.value(5, 1), mask.value(5, 8)), (0, 0));
+        // too few points to have an inside
+        assert!(SelectionMask::from_polygon(10, 10, &[(1.0, 1.0), (8.0, 8.0)]).is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+use ecolor::{Color32, Rgba};
+
+use crate::pixel_buffer::{DirtyRect, PixelBuffer};
+use crate::selection::SelectionMask;
+use crate::RgbaExtensions;
+
+/// How many points an ellipse's edge pixels are sampled at across, and down, to work out how
+/// much of them it covers.
+const ELLIPSE_SAMPLES: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShapeKind {
+    #[default]
+    Rectangle,
+    Ellipse,
+}
+
+impl ShapeKind {
+    pub const ALL: [ShapeKind; 2] = [ShapeKind::Rectangle, ShapeKind::Ellipse];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ShapeKind::Rectangle => "Rectangle",
+            ShapeKind::Ellipse => "Ellipse",
+        }
+    }
+}
+
+/// A rectangle or ellipse laid over a buffer in one color, with its edges as strong as the
+/// share of each pixel they cover, so they're smooth at any angle.
+pub struct ShapeOperation<'a> {
+    pub pixels: &'a mut dyn PixelBuffer,
+    pub kind: ShapeKind,
+    /// Opposite corners of the rectangle the shape fills, either way round.
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+    /// How wide an outline to draw, just inside the shape's edge, or `None` to fill it.
+    pub outline: Option<f32>,
+    /// Straight, like brush colors.
+    pub color: Rgba,
+    /// Limits the shape to the selection, which also softens it where partly selected.
+    pub selection: Option<&'a SelectionMask>,
+}
+
+impl ShapeOperation<'_> {
+    /// Draws the shape, returning the rectangle it could have changed. Does nothing if the
+    /// corners don't make a shape with any area.
+    pub fn process(self) -> DirtyRect {
+        let (width, height) = (self.pixels.width(), self.pixels.height());
+        let (left, right) = (self.from.0.min(self.to.0), self.from.0.max(self.to.0));
+        let (top, bottom) = (self.from.1.min(self.to.1), self.from.1.max(self.to.1));
+        if right <= left || bottom <= top {
+            return DirtyRect::default();
+        }
+        let dirty = DirtyRect::new(
+            left.floor().max(0.0) as u32,
+            top.floor().max(0.0) as u32,
+            (right.ceil() - left.floor().max(0.0)).max(0.0) as u32,
+            (bottom.ceil() - top.floor().max(0.0)).max(0.0) as u32,
+        )
+        .intersect(DirtyRect::full(width, height));
+
+        let color = self.color.premultiply();
+        let bounds = ((left, top), (right, bottom));
+        for (y, row) in (dirty.y..).zip(dirty.rows(width)) {
+            for (x, index) in (dirty.x..).zip(row) {
+                let coverage = match self.kind {
+                    ShapeKind::Rectangle => self.rectangle_coverage(bounds, x, y),
+                    ShapeKind::Ellipse => self.ellipse_coverage(bounds, x, y),
+                };
+                let coverage = coverage
+                    * self
+                        .selection
+                        .map_or(1.0, |mask| mask.values()[index] as f32 / 255.0);
+                if coverage <= 0.0 {
+                    continue;
+                }
+                let src = color * coverage;
+                let pixel = &mut self.pixels.pixels_mut()[index];
+                *pixel = Color32::from(src + Rgba::from(*pixel) * (1.0 - src.a()));
+            }
+        }
+        dirty
+    }
+
+    /// How much of the pixel at `(x, y)` a rectangle between `bounds` covers, worked out
+    /// exactly from how far the two overlap.
+    fn rectangle_coverage(&self, bounds: ((f32, f32), (f32, f32)), x: u32, y: u32) -> f32 {
+        let ((left, top), (right, bottom)) = bounds;
+        let (x, y) = (x as f32, y as f32);
+        let overlap = |(left, top): (f32, f32), (right, bottom): (f32, f32)| {
+            let across = (right.min(x + 1.0) - left.max(x)).max(0.0);
+            let down = (bottom.min(y + 1.0) - top.max(y)).max(0.0);
+            across * down
+        };
+        let outer = overlap((left, top), (right, bottom));
+        match self.outline {
+            Some(outline) if right - left > outline * 2.0 && bottom - top > outline * 2.0 => {
+                let hole = overlap(
+                    (left + outline, top + outline),
+                    (right - outline, bottom - outline),
+                );
+                outer - hole
+            }
+            _ => outer,
+        }
+    }
+
+    /// How much of the pixel at `(x, y)` an ellipse filling `bounds` covers, sampled at a
+    /// grid of points across it.
+    fn ellipse_coverage(&self, bounds: ((f32, f32), (f32, f32)), x: u32, y: u32) -> f32 {
+        let ((left, top), (right, bottom)) = bounds;
+        let center = ((left + right) / 2.0, (top + bottom) / 2.0);
+        let radii = ((right - left) / 2.0, (bottom - top) / 2.0);
+        let inside = |(rx, ry): (f32, f32), (px, py): (f32, f32)| {
+            let (dx, dy) = ((px - center.0) / rx, (py - center.1) / ry);
+            dx * dx + dy * dy <= 1.0
+        };
+        // no hole when the outline is wider than the ellipse is thick
+        let hole = self
+            .outline
+            .map(|outline| (radii.0 - outline, radii.1 - outline))
+            .filter(|&(rx, ry)| rx > 0.0 && ry > 0.0);
+
+        let step = 1.0 / ELLIPSE_SAMPLES as f32;
+        let covered = (0..ELLIPSE_SAMPLES * ELLIPSE_SAMPLES)
+            .map(|i| {
+                let sx = x as f32 + ((i % ELLIPSE_SAMPLES) as f32 + 0.5) * step;
+                let sy = y as f32 + ((i / ELLIPSE_SAMPLES) as f32 + 0.5) * step;
+                (sx, sy)
+            })
+            .filter(|&point| inside(radii, point) && !hole.is_some_and(|hole| inside(hole, point)))
+            .count();
+        covered as f32 / (ELLIPSE_SAMPLES * ELLIPSE_SAMPLES) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_buffer::PixelSlice;
+
+    /// A `width` by `height` buffer of `under` with a shape of `kind` drawn between `from`
+    /// and `to` over it.
+    fn drawn(
+        (width, height): (u32, u32),
+        under: Color32,
+        kind: ShapeKind,
+        (from, to): ((f32, f32), (f32, f32)),
+        outline: Option<f32>,
+        color: Rgba,
+    ) -> Vec<Color32> {
+        let mut pixels = vec![under; (width * height) as usize];
+        ShapeOperation {
+            pixels: &mut PixelSlice::new(&mut pixels, width, height),
+            kind,
+            from,
+            to,
+            outline,
+            color,
+            selection: None,
+        }
+        .process();
+        pixels
+    }
+
+    /// The alphas of a white shape drawn over nothing, which are how much of each pixel it
+    /// covers.
+    fn coverage(
+        size: (u32, u32),
+        kind: ShapeKind,
+        corners: ((f32, f32), (f32, f32)),
+        outline: Option<f32>,
+    ) -> Vec<u8> {
+        let pixels = drawn(
+            size,
+            Color32::TRANSPARENT,
+            kind,
+            corners,
+            outline,
+            Rgba::WHITE,
+        );
+        pixels.iter().map(|pixel| pixel.a()).collect()
+    }
+
+    /// Alphas from how many sixteenths of each pixel a shape covers, which is as finely as
+    /// ellipses are sampled.
+    fn sixteenths<const W: usize>(rows: &[[u8; W]]) -> Vec<u8> {
+        let alpha = |n: u8| (n as f32 / 16.0 * 255.0).round() as u8;
+        rows.iter().flatten().map(|&n| alpha(n)).collect()
+    }
+
+    /// The edge of a circle runs at 45 degrees through its diagonal pixels, which are
+    /// covered as much as they're inside it.
+    #[test]
+    fn a_circle_matches_its_golden_image() {
+        const GOLDEN: [[u8; 10]; 10] = [
+            [0, 0, 5, 12, 16, 16, 12, 5, 0, 0],
+            [0, 10, 16, 16, 16, 16, 16, 16, 10, 0],
+            [5, 16, 16, 16, 16, 16, 16, 16, 16, 5],
+            [12, 16, 16, 16, 16, 16, 16, 16, 16, 12],
+            [16, 16, 16, 16, 16, 16, 16, 16, 16, 16],
+            [16, 16, 16, 16, 16, 16, 16, 16, 16, 16],
+            [12, 16, 16, 16, 16, 16, 16, 16, 16, 12],
+            [5, 16, 16, 16, 16, 16, 16, 16, 16, 5],
+            [0, 10, 16, 16, 16, 16, 16, 16, 10, 0],
+            [0, 0, 5, 12, 16, 16, 12, 5, 0, 0],
+        ];
+        let corners = ((0.0, 0.0), (10.0, 10.0));
+        assert_eq!(
+            coverage((10, 10), ShapeKind::Ellipse, corners, None),
+            sixteenths(&GOLDEN)
+        );
+    }
+
+    /// An outlined ellipse on fractional corners, wider than it is tall.
+    #[test]
+    fn an_outlined_ellipse_matches_its_golden_image() {
+        const GOLDEN: [[u8; 14]; 10] = [
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 0, 2, 7, 11, 12, 12, 11, 7, 2, 0, 0, 0],
+            [0, 1, 12, 16, 16, 13, 12, 12, 13, 16, 16, 12, 1, 0],
+            [1, 14, 15, 6, 1, 0, 0, 0, 0, 1, 6, 15, 14, 1],
+            [7, 16, 3, 0, 0, 0, 0, 0, 0, 0, 0, 3, 16, 7],
+            [7, 16, 3, 0, 0, 0, 0, 0, 0, 0, 0, 3, 16, 7],
+            [1, 14, 15, 6, 1, 0, 0, 0, 0, 1, 6, 15, 14, 1],
+            [0, 1, 12, 16, 16, 13, 12, 12, 13, 16, 16, 12, 1, 0],
+            [0, 0, 0, 2, 7, 11, 12, 12, 11, 7, 2, 0, 0, 0],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        let corners = ((0.5, 1.25), (13.5, 8.75));
+        let outlined = coverage((14, 10), ShapeKind::Ellipse, corners, Some(1.5));
+        assert_eq!(outlined, sixteenths(&GOLDEN));
+        // the same drawn from the other pair of corners
+        let flipped = ((13.5, 1.25), (0.5, 8.75));
+        assert_eq!(
+            coverage((14, 10), ShapeKind::Ellipse, flipped, Some(1.5)),
+            sixteenths(&GOLDEN)
+        );
+    }
+
+    #[test]
+    fn rectangles_cover_exactly_their_share_of_each_pixel() {
+        let corners = ((1.25, 1.5), (4.75, 3.5));
+        let covered = coverage((6, 5), ShapeKind::Rectangle, corners, None);
+        let across = [0.0, 0.75, 1.0, 1.0, 0.75, 0.0];
+        let down = [0.0, 0.5, 1.0, 0.5, 0.0];
+        for y in 0..5 {
+            for x in 0..6 {
+                let expected = (across[x] * down[y] * 255.0f32).round() as u8;
+                assert_eq!(covered[y * 6 + x], expected, "at {x}, {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn an_outline_leaves_the_middle_alone() {
+        let corners = ((1.0, 1.0), (7.0, 7.0));
+        let outlined = coverage((8, 8), ShapeKind::Rectangle, corners, Some(1.0));
+        for y in 0..8 {
+            for x in 0..8 {
+                let edge = |v| v == 1 || v == 6;
+                let inside = (1..7).contains(&x) && (1..7).contains(&y);
+                let expected = if inside && (edge(x) || edge(y)) {
+                    255
+                } else {
+                    0
+                };
+                assert_eq!(outlined[y * 8 + x], expected, "at {x}, {y}");
+            }
+        }
+        // too wide an outline for any hole fills the shape
+        for kind in ShapeKind::ALL {
+            assert_eq!(
+                coverage((8, 8), kind, corners, Some(4.0)),
+                coverage((8, 8), kind, corners, None),
+                "{kind:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn shapes_composite_over_what_was_there() {
+        let blue = Color32::from_rgb(0, 0, 255);
+        let faint_red = Rgba::from_rgba_premultiplied(1.0, 0.0, 0.0, 0.5);
+        let corners = ((0.0, 0.0), (4.0, 4.0));
+        let over = drawn((4, 4), blue, ShapeKind::Rectangle, corners, None, faint_red);
+        let expected = Color32::from(faint_red.premultiply() + Rgba::from(blue) * 0.5);
+        assert!(over.iter().all(|&pixel| pixel == expected), "{over:?}");
+
+        // a faint ellipse's edges blend what was there in by as much as they don't cover
+        let ellipse = drawn((4, 4), blue, ShapeKind::Ellipse, corners, None, Rgba::WHITE);
+        let covered = coverage((4, 4), ShapeKind::Ellipse, corners, None);
+        for (pixel, covered) in ellipse.iter().zip(covered) {
+            let c = covered as f32 / 255.0;
+            let expected = Color32::from(Rgba::WHITE * c + Rgba::from(blue) * (1.0 - c));
+            assert_eq!(*pixel, expected);
+        }
+    }
+
+    #[test]
+    fn shapes_are_limited_to_the_buffer_and_the_selection() {
+        let mut pixels = vec![Color32::TRANSPARENT; 8 * 8];
+        let selection = SelectionMask::from_rect(8, 8, DirtyRect::new(0, 0, 4, 8));
+        let dirty = ShapeOperation {
+            pixels: &mut PixelSlice::new(&mut pixels, 8, 8),
+            kind: ShapeKind::Rectangle,
+            from: (-4.0, 2.0),
+            to: (20.0, 6.0),
+            outline: None,
+            color: Rgba::WHITE,
+            selection: Some(&selection),
+        }
+        .process();
+        assert_eq!(dirty, DirtyRect::new(0, 2, 8, 4));
+        for (index, pixel) in pixels.iter().enumerate() {
+            let (x, y) = (index % 8, index / 8);
+            let inside = x < 4 && (2..6).contains(&y);
+            assert_eq!(pixel.a(), if inside { 255 } else { 0 }, "at {x}, {y}");
+        }
+
+        // corners that don't make any area draw nothing
+        for corners in [((2.0, 2.0), (2.0, 6.0)), ((2.0, 2.0), (6.0, 2.0))] {
+            for kind in ShapeKind::ALL {
+                assert!(coverage((8, 8), kind, corners, None)
+                    .iter()
+                    .all(|&a| a == 0));
+            }
+        }
+    }
+}
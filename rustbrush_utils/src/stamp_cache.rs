@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use crate::{Brush, Stamp};
+
+/// How many stamps the cache keeps: enough for every size of a size-jittered brush, see
+/// [`crate::jitter::SIZE_JITTER_STEPS`], and then some.
+const CAPACITY: usize = 16;
+
+/// The last few stamps computed, kept until brushes with other shapes push them out. Stamps
+/// hold a pixel for every point the tip covers, tens of thousands for a large brush, so
+/// rebuilding one for every segment of a stroke adds up; with a cache only the first segment
+/// after a shape change pays for it.
+#[derive(Default)]
+pub struct StampCache {
+    /// The least recently used first.
+    cached: Vec<(Brush, Arc<Stamp>)>,
+    computed: usize,
+}
+
+impl StampCache {
+    /// The stamp of `brush`, computed only if none of the cached ones is for the same shape,
+    /// see [`Brush::same_stamp`].
+    pub fn get(&mut self, brush: &Brush) -> Arc<Stamp> {
+        let hit = self
+            .cached
+            .iter()
+            .position(|(cached_brush, _)| cached_brush.same_stamp(brush));
+        let entry = match hit {
+            Some(index) => self.cached.remove(index),
+            None => {
+                if self.cached.len() == CAPACITY {
+                    self.cached.remove(0);
+                }
+                self.computed += 1;
+                (brush.clone(), Arc::new(brush.compute_stamp()))
+            }
+        };
+        let stamp = entry.1.clone();
+        self.cached.push(entry);
+        stamp
+    }
+
+    /// How many stamps have been computed, as opposed to served from the cache.
+    pub fn computed(&self) -> usize {
+        self.computed
+    }
+
+    /// Memory held by the cached stamps, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.cached
+            .iter()
+            .map(|(_, stamp)| stamp.alpha.len() * std::mem::size_of::<f32>())
+            .sum()
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+
+use ecolor::Color32;
+
+use crate::alpha;
+use crate::pixel_buffer::DirtyRect;
+
+/// Where a buffer has been painted: how many pixels aren't fully transparent, and the
+/// smallest rectangle holding all of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub painted_pixels: usize,
+    /// Empty when nothing is painted.
+    pub bounds: DirtyRect,
+}
+
+/// The coverage of a `width` by `height` buffer. Panics if there aren't `width * height`
+/// pixels.
+pub fn coverage(pixels: &[Color32], width: u32, height: u32) -> Coverage {
+    assert_eq!(pixels.len(), width as usize * height as usize);
+    let mut painted_pixels = 0;
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (y, row) in (0..height).zip(pixels.chunks_exact(width.max(1) as usize)) {
+        for (x, pixel) in (0..width).zip(row) {
+            if pixel.a() == 0 {
+                continue;
+            }
+            painted_pixels += 1;
+            (min_x, min_y) = (min_x.min(x), min_y.min(y));
+            (max_x, max_y) = (max_x.max(x), max_y.max(y));
+        }
+    }
+    let bounds = match painted_pixels {
+        0 => DirtyRect::default(),
+        _ => DirtyRect::new(min_x, min_y, max_x - min_x + 1, max_y - min_y + 1),
+    };
+    Coverage {
+        painted_pixels,
+        bounds,
+    }
+}
+
+/// How many different colors there are, as counted by [`distinct_colors`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorCount {
+    Exact(usize),
+    /// There are more than this many, and counting stopped there.
+    MoreThan(usize),
+}
+
+impl ColorCount {
+    /// Whether there are at most `budget` colors. Only definite for counts made with a limit
+    /// of at least `budget`; past the limit there's no telling, so it's taken as over.
+    pub fn within(&self, budget: usize) -> bool {
+        match *self {
+            ColorCount::Exact(count) => count <= budget,
+            ColorCount::MoreThan(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ColorCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorCount::Exact(count) => write!(f, "{}", count),
+            ColorCount::MoreThan(count) => write!(f, "over {}", count),
+        }
+    }
+}
+
+/// The number of different colors among the painted pixels, counting no further than
+/// `limit` so a noisy image doesn't build an enormous set.
+///
+/// Colors are compared as the straight RGBA8 an exported image would hold, so faint colors
+/// that differed only by what premultiplying rounded away are one color, and pixels that
+/// differ by one step of rounding once premultiplied are two, as they are in the export.
+/// Fully transparent pixels aren't counted.
+pub fn distinct_colors(pixels: &[Color32], limit: usize) -> ColorCount {
+    let mut colors = HashSet::new();
+    for &pixel in pixels.iter().filter(|pixel| pixel.a() > 0) {
+        colors.insert(alpha::unpremultiply(pixel));
+        if colors.len() > limit {
+            return ColorCount::MoreThan(limit);
+        }
+    }
+    ColorCount::Exact(colors.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coverage_bounds_the_painted_pixels() {
+        let mut pixels = vec![Color32::TRANSPARENT; 8 * 6];
+        for (x, y) in [(2, 1), (5, 4), (3, 2)] {
+            pixels[y * 8 + x] = Color32::from_rgba_premultiplied(10, 0, 0, 10);
+        }
+        let coverage = coverage(&pixels, 8, 6);
+        assert_eq!(coverage.painted_pixels, 3);
+        assert_eq!(coverage.bounds, DirtyRect::new(2, 1, 4, 4));
+    }
+
+    #[test]
+    fn an_empty_buffer_covers_nothing() {
+        let pixels = vec![Color32::TRANSPARENT; 16];
+        assert_eq!(coverage(&pixels, 4, 4), Coverage::default());
+        assert_eq!(coverage(&[], 0, 0), Coverage::default());
+    }
+
+    #[test]
+    fn counting_stops_at_the_limit() {
+        let pixels: Vec<Color32> = (0..10).map(|i| Color32::from_gray(i * 20)).collect();
+        assert_eq!(distinct_colors(&pixels, 10), ColorCount::Exact(10));
+        assert_eq!(distinct_colors(&pixels, 4), ColorCount::MoreThan(4));
+        assert!(distinct_colors(&pixels, 10).within(10));
+        assert!(!distinct_colors(&pixels, 10).within(9));
+        assert!(!distinct_colors(&pixels, 4).within(100));
+    }
+
+    #[test]
+    fn transparent_pixels_are_not_a_color() {
+        let pixels = [Color32::TRANSPARENT, Color32::RED, Color32::TRANSPARENT];
+        assert_eq!(distinct_colors(&pixels, 10), ColorCount::Exact(1));
+    }
+
+    #[test]
+    fn colors_are_counted_as_they_export() {
+        // faint enough that premultiplying rounds the difference away
+        let faint = [
+            alpha::premultiply([200, 40, 40, 3]),
+            alpha::premultiply([201, 41, 40, 3]),
+        ];
+        assert_eq!(faint[0], faint[1]);
+        assert_eq!(distinct_colors(&faint, 10), ColorCount::Exact(1));
+
+        // one step apart premultiplied is a different straight color
+        let near = [
+            Color32::from_rgba_premultiplied(100, 0, 0, 128),
+            Color32::from_rgba_premultiplied(101, 0, 0, 128),
+        ];
+        assert_ne!(alpha::unpremultiply(near[0]), alpha::unpremultiply(near[1]));
+        assert_eq!(distinct_colors(&near, 10), ColorCount::Exact(2));
+    }
+}
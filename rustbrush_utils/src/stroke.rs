@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::f32::consts::{PI, TAU};
+
+use ecolor::{Color32, Rgba};
+
+/// How the dabs of a single stroke combine with each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StrokeAccumulation {
+    /// Every dab composites onto the layer immediately, so overlapping dabs build up, as
+    /// far as the brush's opacity.
+    #[default]
+    BuildUp,
+    /// Dabs accumulate into a per-stroke coverage buffer that is capped at the stroke's
+    /// opacity, so a stroke crossing itself never gets darker than a single pass.
+    Wash,
+}
+
+impl StrokeAccumulation {
+    pub const ALL: [StrokeAccumulation; 2] =
+        [StrokeAccumulation::BuildUp, StrokeAccumulation::Wash];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            StrokeAccumulation::BuildUp => "Build-up",
+            StrokeAccumulation::Wash => "Wash",
+        }
+    }
+
+    /// Whether strokes combining this way with a brush of `opacity` need a
+    /// [`StrokeBuffer`]. Wash strokes always do; build-up strokes only do when they're
+    /// capped below full opacity, and otherwise paint straight onto the layer.
+    pub fn needs_buffer(&self, opacity: f32) -> bool {
+        match self {
+            StrokeAccumulation::BuildUp => opacity < 1.0,
+            StrokeAccumulation::Wash => true,
+        }
+    }
+}
+
+/// The side of the square tiles a [`StrokeBuffer`] keeps its state in.
+const TILE_SIZE: i32 = 64;
+
+/// The part of a stroke buffer covering one tile of the layer.
+struct Tile {
+    before: Vec<Color32>,
+    coverage: Vec<f32>,
+    /// The stroke so far, for strokes painted through a float copy, see
+    /// [`StrokeBuffer::color`]. Empty until the first of them touches the tile.
+    color: Vec<[f32; 4]>,
+}
+
+impl Tile {
+    const BYTES: usize = (TILE_SIZE * TILE_SIZE) as usize
+        * (std::mem::size_of::<Color32>() + std::mem::size_of::<f32>());
+
+    fn bytes(&self) -> usize {
+        Self::BYTES + self.color.capacity() * std::mem::size_of::<[f32; 4]>()
+    }
+}
+
+/// Per-stroke state for strokes that are composited as a whole: the layer as it was before
+/// the stroke began, and the coverage each pixel has received during the stroke so far.
+/// Wash strokes keep the highest coverage of any one dab, and capped build-up strokes the
+/// coverage of all their dabs combined. Strokes painted through a float copy of the layer
+/// keep it here too, see [`StrokeBuffer::color`].
+///
+/// Both are kept only for the tiles the stroke has touched, each set up from the layer the
+/// first time a dab lands in it, so a small stroke on a large layer only costs as much as
+/// the area it covers. Tiles are kept for reuse when the buffer is cleared for the next
+/// stroke rather than freed.
+#[derive(Default)]
+pub struct StrokeBuffer {
+    /// Where the layer's top left pixel is in tile space, which moves when the layer grows
+    /// up or left mid-stroke.
+    origin: (i32, i32),
+    /// Which of `tiles` covers each tile position.
+    index: HashMap<(i32, i32), usize>,
+    tiles: Vec<Tile>,
+    /// Tiles left over from earlier strokes.
+    spare: Vec<Tile>,
+    /// The last tile looked up, since dabs land on neighbouring pixels one after another.
+    last: Option<((i32, i32), usize)>,
+}
+
+impl StrokeBuffer {
+    /// Forgets the stroke so the buffer can be used for the next one, keeping its tiles.
+    pub fn clear(&mut self) {
+        self.spare.append(&mut self.tiles);
+        self.index.clear();
+        self.origin = (0, 0);
+        self.last = None;
+    }
+
+    /// Whether the stroke hasn't touched any pixels yet.
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    /// Raises the coverage of the pixel at `index` to `alpha` if it's higher than what the
+    /// stroke has already deposited there. Returns the resulting coverage, and the pixel as
+    /// it was before the stroke began. `pixels` is the layer, `width` pixels to a row, which
+    /// the pixel's tile is set up from if this is the first time the stroke touches it.
+    pub fn accumulate(
+        &mut self,
+        pixels: &[Color32],
+        width: u32,
+        index: usize,
+        alpha: f32,
+    ) -> (f32, Color32) {
+        let (coverage, before) = self.pixel(pixels, width, index);
+        *coverage = coverage.max(alpha);
+        (*coverage, before)
+    }
+
+    /// Like [`StrokeBuffer::accumulate`], but a dab of `alpha` builds up on the coverage
+    /// already there the way it would on the layer, rather than replacing it.
+    pub fn build_up(
+        &mut self,
+        pixels: &[Color32],
+        width: u32,
+        index: usize,
+        alpha: f32,
+    ) -> (f32, Color32) {
+        let (coverage, before) = self.pixel(pixels, width, index);
+        *coverage = 1.0 - (1.0 - *coverage) * (1.0 - alpha.min(1.0));
+        (*coverage, before)
+    }
+
+    /// The pixel at `index` as the stroke has painted it so far, as linear premultiplied
+    /// floats, for strokes painted through a float copy of the layer rather than straight
+    /// onto it, see [`crate::Brush::float_buffer`]. It starts out as the pixel was before
+    /// the stroke, and the stroke keeps it up to date, writing it to the layer rounded.
+    /// The copy is made a tile at a time, the first time the stroke needs it there.
+    pub fn color(&mut self, pixels: &[Color32], width: u32, index: usize) -> &mut [f32; 4] {
+        let (tile, at) = self.tile(pixels, width, index);
+        let tile = &mut self.tiles[tile];
+        if tile.color.is_empty() {
+            let before = tile
+                .before
+                .iter()
+                .map(|&pixel| Rgba::from(pixel).to_array());
+            tile.color.extend(before);
+        }
+        &mut tile.color[at]
+    }
+
+    /// The stroke's coverage of the pixel at `index`, and the pixel as it was before the
+    /// stroke began, setting up its tile first if need be.
+    fn pixel(&mut self, pixels: &[Color32], width: u32, index: usize) -> (&mut f32, Color32) {
+        let (tile, at) = self.tile(pixels, width, index);
+        let tile = &mut self.tiles[tile];
+        (&mut tile.coverage[at], tile.before[at])
+    }
+
+    /// Where the tile covering the pixel at `index` is in `tiles`, setting it up first if
+    /// need be, and where the pixel is in the tile.
+    fn tile(&mut self, pixels: &[Color32], width: u32, index: usize) -> (usize, usize) {
+        let (x, y) = (
+            (index % width as usize) as i32,
+            (index / width as usize) as i32,
+        );
+        let (tx, ty) = (x + self.origin.0, y + self.origin.1);
+        let key = (tx.div_euclid(TILE_SIZE), ty.div_euclid(TILE_SIZE));
+        let tile = match self.last {
+            Some((last_key, tile)) if last_key == key => tile,
+            _ => {
+                let tile = match self.index.get(&key) {
+                    Some(&tile) => tile,
+                    None => self.add_tile(key, pixels, width),
+                };
+                self.last = Some((key, tile));
+                tile
+            }
+        };
+        let at = (ty.rem_euclid(TILE_SIZE) * TILE_SIZE + tx.rem_euclid(TILE_SIZE)) as usize;
+        (tile, at)
+    }
+
+    /// Sets up the tile at `key` from the layer, reusing a spare one if there is one.
+    /// Returns where it is in `tiles`.
+    fn add_tile(&mut self, key: (i32, i32), pixels: &[Color32], width: u32) -> usize {
+        let area = (TILE_SIZE * TILE_SIZE) as usize;
+        let mut tile = self.spare.pop().unwrap_or_else(|| Tile {
+            before: vec![Color32::TRANSPARENT; area],
+            coverage: vec![0.0; area],
+            color: Vec::new(),
+        });
+        tile.coverage.fill(0.0);
+        tile.color.clear();
+        // the tile can hang off the layer, where there's nothing yet
+        let height = (pixels.len() / width.max(1) as usize) as i32;
+        for row in 0..TILE_SIZE {
+            let y = key.1 * TILE_SIZE + row - self.origin.1;
+            for column in 0..TILE_SIZE {
+                let x = key.0 * TILE_SIZE + column - self.origin.0;
+                let inside = (0..width as i32).contains(&x) && (0..height).contains(&y);
+                tile.before[(row * TILE_SIZE + column) as usize] = match inside {
+                    true => pixels[y as usize * width as usize + x as usize],
+                    false => Color32::TRANSPARENT,
+                };
+            }
+        }
+        self.tiles.push(tile);
+        self.index.insert(key, self.tiles.len() - 1);
+        self.tiles.len() - 1
+    }
+
+    /// Keeps up with the layer growing mid-stroke, with its old pixels now at `at` in the
+    /// new ones. The new pixels were transparent before the stroke and haven't been painted
+    /// yet, which is what the tiles already have for anywhere off the old layer.
+    pub fn expand(&mut self, at: (u32, u32)) {
+        self.origin = (self.origin.0 - at.0 as i32, self.origin.1 - at.1 as i32);
+    }
+
+    /// Memory held for strokes, in bytes: the tiles in use and the ones kept for reuse.
+    pub fn bytes(&self) -> usize {
+        self.tiles.iter().chain(&self.spare).map(Tile::bytes).sum()
+    }
+}
+
+/// The part of a stroke an operation paints: the straight line from where the cursor was
+/// to where it is now, in the pixels of the buffer being painted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StrokeSegment {
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+}
+
+/// What a stroke carries from one segment to the next: where its last dab landed, and which
+/// way it was heading.
+///
+/// Dabs are placed along the path the stroke has travelled rather than per segment, so they
+/// keep to the brush spacing however finely the cursor's movement was divided into frames:
+/// a slow stroke arrives as many short segments, and would otherwise get at least a dab for
+/// each one.
+#[derive(Clone, Debug, Default)]
+pub struct StrokeState {
+    /// How far the stroke has travelled since its last dab, or `None` before its first.
+    distance: Option<f32>,
+    /// How long it's been since the last dab, in seconds.
+    time: f32,
+    /// Which way the stroke was last heading, in radians clockwise from the x axis, or
+    /// `None` if it hasn't moved yet.
+    direction: Option<f32>,
+    /// How far the stroke has travelled in all, up to the end of its last segment.
+    travelled: f32,
+}
+
+impl StrokeState {
+    /// Forgets the stroke, so the next segment starts a new one with a dab.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Where along the next segment of the stroke its dabs land, as fractions of the way
+    /// from its start to its end. The segment is `length` long and took `elapsed` seconds.
+    /// Dabs are `spacing` apart along the stroke, and at least `min_interval` seconds
+    /// apart, with the time spread evenly over the segment.
+    ///
+    /// The first segment of a stroke starts with a dab, so a click lands a single one. A
+    /// segment that doesn't move never lands any after that.
+    pub fn place(
+        &mut self,
+        length: f32,
+        elapsed: f32,
+        spacing: f32,
+        min_interval: Option<f32>,
+    ) -> Vec<f32> {
+        let mut dabs = Vec::new();
+        let (mut distance, mut time) = match self.distance {
+            Some(distance) => (distance, self.time),
+            None => {
+                dabs.push(0.0);
+                (0.0, 0.0)
+            }
+        };
+        self.travelled += length.max(0.0);
+        if length <= 0.0 {
+            self.distance = Some(distance);
+            self.time = time + elapsed.max(0.0);
+            return dabs;
+        }
+
+        // how far along the segment the stroke gets per second
+        let speed = length / elapsed.max(0.0);
+        let spacing = spacing.max(f32::EPSILON);
+        let mut last = 0.0;
+        loop {
+            let mut next = last + spacing - distance;
+            let wait = min_interval.map_or(0.0, |interval| interval - time);
+            if wait > 0.0 {
+                next = next.max(last + wait * speed);
+            }
+            // segments ending right on a dab's spot can come up a hair short of it
+            if next > length + 1e-4 {
+                break;
+            }
+            let next = next.clamp(last, length);
+            dabs.push(next / length);
+            (last, distance, time) = (next, 0.0, 0.0);
+        }
+        self.distance = Some(distance + length - last);
+        self.time = time + elapsed.max(0.0) * (length - last) / length;
+        dabs
+    }
+
+    /// How far the stroke has travelled, in pixels, up to the end of the last segment
+    /// placed.
+    pub fn travelled(&self) -> f32 {
+        self.travelled
+    }
+
+    /// Which way the dabs of the next segment face, for brushes that follow the stroke's
+    /// direction: from the way the stroke was last heading at the segment's start, turning
+    /// the short way round to the segment's own `heading` at its end. A segment that doesn't
+    /// move has no heading, and keeps facing the way the stroke was last heading. `None`
+    /// until the stroke has moved.
+    pub fn steer(&mut self, heading: Option<f32>) -> Option<(f32, f32)> {
+        let from = self.direction.or(heading)?;
+        let to = heading.unwrap_or(from);
+        self.direction = Some(to);
+        let turn = (to - from + PI).rem_euclid(TAU) - PI;
+        Some((from, from + turn))
+    }
+}
+
+/// How many sizes a tapered dab can be, evenly spread up to the brush's full size, so a
+/// tapering stroke only needs that many stamps.
+pub const TAPER_STEPS: usize = 16;
+
+/// How much of the brush's full size a dab `at` pixels along a stroke is, from 0 to 1: the
+/// stroke grows over its first `taper_in` pixels, and over its last `taper_out` pixels
+/// shrinks back to nothing at its end. Tapering out needs the `length` of the whole stroke,
+/// so without it only the start tapers.
+///
+/// A stroke too short for both tapers has them shortened in proportion, so it still reaches
+/// full size somewhere rather than disappearing. One with no length at all, a click, isn't
+/// tapered.
+pub fn taper(at: f32, length: Option<f32>, taper_in: f32, taper_out: f32) -> f32 {
+    let taper_in = taper_in.max(0.0);
+    let taper_out = match length {
+        Some(_) => taper_out.max(0.0),
+        None => 0.0,
+    };
+    let shorten = match length {
+        Some(length) if taper_in + taper_out > length => length / (taper_in + taper_out),
+        _ => 1.0,
+    };
+    let mut scale = 1.0f32;
+    if taper_in * shorten > 0.0 {
+        scale = scale.min(at / (taper_in * shorten));
+    }
+    if let Some(length) = length.filter(|_| taper_out * shorten > 0.0) {
+        scale = scale.min((length - at) / (taper_out * shorten));
+    }
+    scale.clamp(0.0, 1.0)
+}
+
+/// Settings for the fade tail: when a stroke is released while the pointer is still moving
+/// faster than `min_speed` (pixels per second), the stroke continues along its last direction
+/// for `length` pixels with size and opacity ramping down to zero.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FadeTail {
+    pub length: f32,
+    pub min_speed: f32,
+}
+
+impl Default for FadeTail {
+    fn default() -> Self {
+        Self {
+            length: 40.0,
+            min_speed: 300.0,
+        }
+    }
+}
+
+/// Distance between the synthesized tail points, in pixels.
+const FADE_TAIL_STEP: f32 = 2.0;
+
+impl FadeTail {
+    /// Points along the tail starting at `end` and heading in `direction`, each paired with
+    /// the scale (1 down towards 0) to apply to the brush size and opacity for the segment
+    /// ending at that point. Returns nothing when `speed` is below the threshold.
+    pub fn points(
+        &self,
+        end: (f32, f32),
+        direction: (f32, f32),
+        speed: f32,
+    ) -> Vec<((f32, f32), f32)> {
+        let length = (direction.0 * direction.0 + direction.1 * direction.1).sqrt();
+        if speed < self.min_speed || self.length <= 0.0 || length <= 0.0 {
+            return Vec::new();
+        }
+
+        let direction = (direction.0 / length, direction.1 / length);
+        let steps = (self.length / FADE_TAIL_STEP).ceil().max(1.0) as usize;
+        (1..=steps)
+            .map(|i| {
+                let t = i as f32 / steps as f32;
+                let distance = self.length * t;
+                let position = (
+                    end.0 + direction.0 * distance,
+                    end.1 + direction.1 * distance,
+                );
+                (position, 1.0 - t)
+            })
+            .filter(|(_, scale)| *scale > 0.0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Touches every pixel of the 10x10 square at `at` on a `width` wide layer.
+    fn touch(buffer: &mut StrokeBuffer, pixels: &[Color32], width: u32, at: (u32, u32)) {
+        let (left, top) = at;
+        for y in top..top + 10 {
+            for x in left..left + 10 {
+                buffer.accumulate(pixels, width, (y * width + x) as usize, 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn a_small_stroke_holds_memory_for_its_tiles_not_the_layer() {
+        let width = 4096;
+        let pixels = vec![Color32::RED; (width * width) as usize];
+        let mut buffer = StrokeBuffer::default();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.bytes(), 0);
+
+        // ten pixels square, across the line between two tiles
+        touch(&mut buffer, &pixels, width, (60, 100));
+        assert_eq!(buffer.bytes(), 2 * Tile::BYTES);
+        assert!(buffer.bytes() < 100 * 1024);
+
+        // the next stroke reuses them rather than allocating more
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.bytes(), 2 * Tile::BYTES);
+        touch(&mut buffer, &pixels, width, (2000, 3000));
+        assert_eq!(buffer.bytes(), 2 * Tile::BYTES);
+    }
+
+    #[test]
+    fn accumulating_keeps_the_pixel_from_before_the_stroke() {
+        let width = 100;
+        let mut pixels = vec![Color32::RED; (width * width) as usize];
+        let mut buffer = StrokeBuffer::default();
+        let index = 70 * width as usize + 65;
+        assert_eq!(
+            buffer.accumulate(&pixels, width, index, 0.4),
+            (0.4, Color32::RED)
+        );
+
+        // painting the layer doesn't change what the stroke started from
+        pixels[index] = Color32::BLUE;
+        assert_eq!(
+            buffer.accumulate(&pixels, width, index, 0.2),
+            (0.4, Color32::RED)
+        );
+        assert_eq!(
+            buffer.accumulate(&pixels, width, index, 0.7),
+            (0.7, Color32::RED)
+        );
+        let (coverage, _) = buffer.build_up(&pixels, width, index, 0.5);
+        assert!((coverage - 0.85).abs() < 1e-6);
+
+        buffer.clear();
+        assert_eq!(
+            buffer.accumulate(&pixels, width, index, 0.1),
+            (0.1, Color32::BLUE)
+        );
+    }
+
+    /// Where the dabs land along a straight stroke `length` long, drawn at `speed` pixels a
+    /// second and sampled `fps` times a second.
+    fn dabs_at(fps: f32, length: f32, speed: f32, spacing: f32, interval: Option<f32>) -> Vec<f32> {
+        let mut state = StrokeState::default();
+        let mut dabs = state.place(0.0, 0.0, spacing, interval);
+        let step = speed / fps;
+        let mut start = 0.0;
+        while start < length {
+            let segment = step.min(length - start);
+            let placed = state.place(segment, segment / speed, spacing, interval);
+            dabs.extend(placed.into_iter().map(|t| start + t * segment));
+            start += segment;
+        }
+        dabs
+    }
+
+    fn assert_same_dabs(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len(), "{a:?} != {b:?}");
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-3, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn dabs_keep_to_the_spacing_at_any_frame_rate() {
+        let expected: Vec<f32> = (0..=48).map(|i| i as f32 * 2.5).collect();
+        for fps in [30.0, 60.0, 240.0, 1000.0] {
+            assert_same_dabs(&dabs_at(fps, 121.0, 300.0, 2.5, None), &expected);
+        }
+    }
+
+    #[test]
+    fn a_minimum_interval_spaces_slow_dabs_by_time() {
+        // at 10 pixels a second, a tenth of a second apart is a pixel apart
+        let expected: Vec<f32> = (0..=20).map(|i| i as f32).collect();
+        for fps in [30.0, 60.0, 240.0] {
+            assert_same_dabs(&dabs_at(fps, 20.5, 10.0, 0.25, Some(0.1)), &expected);
+        }
+        // when the spacing takes longer to cover, it's what keeps them apart
+        let expected: Vec<f32> = (0..=8).map(|i| i as f32 * 2.5).collect();
+        for fps in [30.0, 60.0, 240.0] {
+            assert_same_dabs(&dabs_at(fps, 21.0, 10.0, 2.5, Some(0.1)), &expected);
+        }
+    }
+
+    #[test]
+    fn holding_still_lands_no_more_dabs() {
+        let mut state = StrokeState::default();
+        assert_eq!(state.place(0.0, 0.1, 2.0, None), [0.0]);
+        for _ in 0..100 {
+            assert!(state.place(0.0, 0.1, 2.0, None).is_empty());
+        }
+        assert_eq!(state.place(1.0, 0.1, 2.0, None), Vec::<f32>::new());
+        assert_eq!(state.place(2.0, 0.1, 2.0, None), [0.5]);
+        assert_eq!(state.travelled(), 3.0);
+    }
+
+    #[test]
+    fn a_fade_tail_ramps_down_along_the_last_direction() {
+        let tail = FadeTail {
+            length: 20.0,
+            min_speed: 300.0,
+        };
+        assert!(tail.points((0.0, 0.0), (1.0, 0.0), 299.0).is_empty());
+
+        let points = tail.points((10.0, 5.0), (0.0, -3.0), 1000.0);
+        assert!(points.windows(2).all(|w| w[0].1 > w[1].1));
+        assert!(points.iter().all(|&((x, y), scale)| {
+            x == 10.0 && (-15.0..5.0).contains(&y) && scale > 0.0 && scale < 1.0
+        }));
+    }
+}
@@ -0,0 +1,87 @@
+use std::f32::consts::{PI, TAU};
+
+/// Where the dabs of a stroke are repeated as they're painted, in the pixels of the buffer
+/// they're painted into. Repeating the dabs inside the operation, rather than painting the
+/// stroke once for each image, keeps a symmetrical stroke a single stroke.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Symmetry {
+    #[default]
+    None,
+    /// Mirrored left to right, across the vertical line at `axis_x`.
+    Horizontal { axis_x: f32 },
+    /// Mirrored top to bottom, across the horizontal line at `axis_y`.
+    Vertical { axis_y: f32 },
+    /// Mirrored across both lines, into all four quarters.
+    Both { axis_x: f32, axis_y: f32 },
+    /// Turned around `(cx, cy)` into `segments` equal segments, including the one painted
+    /// in.
+    Radial { cx: f32, cy: f32, segments: u32 },
+}
+
+impl Symmetry {
+    /// Where `point` is repeated, not counting `point` itself. The images of two points come
+    /// out in the same order, so a segment maps to a segment.
+    pub fn images(&self, point: (f32, f32)) -> Vec<(f32, f32)> {
+        let (x, y) = point;
+        match *self {
+            Symmetry::None => Vec::new(),
+            Symmetry::Horizontal { axis_x } => vec![(2.0 * axis_x - x, y)],
+            Symmetry::Vertical { axis_y } => vec![(x, 2.0 * axis_y - y)],
+            Symmetry::Both { axis_x, axis_y } => vec![
+                (2.0 * axis_x - x, y),
+                (x, 2.0 * axis_y - y),
+                (2.0 * axis_x - x, 2.0 * axis_y - y),
+            ],
+            Symmetry::Radial { cx, cy, segments } => {
+                let (dx, dy) = (x - cx, y - cy);
+                let segments = segments.max(1);
+                (1..segments)
+                    .map(|i| {
+                        let (sin, cos) = (TAU * i as f32 / segments as f32).sin_cos();
+                        (cx + dx * cos - dy * sin, cy + dx * sin + dy * cos)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Which way something facing `angle` radians, clockwise from the x axis, faces in each
+    /// of the images, in the same order as [`Symmetry::images`].
+    pub fn image_angles(&self, angle: f32) -> Vec<f32> {
+        match *self {
+            Symmetry::None => Vec::new(),
+            Symmetry::Horizontal { .. } => vec![PI - angle],
+            Symmetry::Vertical { .. } => vec![-angle],
+            Symmetry::Both { .. } => vec![PI - angle, -angle, angle + PI],
+            Symmetry::Radial { segments, .. } => {
+                let segments = segments.max(1);
+                (1..segments)
+                    .map(|i| angle + TAU * i as f32 / segments as f32)
+                    .collect()
+            }
+        }
+    }
+
+    /// The same symmetry with everything moved by `(dx, dy)`, such as into the pixels of a
+    /// layer that doesn't start at the canvas's corner.
+    pub fn translated(self, dx: f32, dy: f32) -> Self {
+        match self {
+            Symmetry::None => Symmetry::None,
+            Symmetry::Horizontal { axis_x } => Symmetry::Horizontal {
+                axis_x: axis_x + dx,
+            },
+            Symmetry::Vertical { axis_y } => Symmetry::Vertical {
+                axis_y: axis_y + dy,
+            },
+            Symmetry::Both { axis_x, axis_y } => Symmetry::Both {
+                axis_x: axis_x + dx,
+                axis_y: axis_y + dy,
+            },
+            Symmetry::Radial { cx, cy, segments } => Symmetry::Radial {
+                cx: cx + dx,
+                cy: cy + dy,
+                segments,
+            },
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Long operations run on a background thread, such as filtering or exporting a large
+//! canvas, so the UI stays responsive. The work reports how far along it is and checks
+//! whether it's been cancelled between chunks of rows, and hands back its result when done.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// How far along a task is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TaskProgress {
+    /// From 0 when it starts to 1 when it's done. It never goes back.
+    pub fraction: f32,
+    /// What it's doing, such as "Merging layers".
+    pub message: String,
+}
+
+/// What the work of a task is given: where to report progress and how to tell it's been
+/// cancelled.
+pub struct TaskContext {
+    cancelled: Arc<AtomicBool>,
+    progress: Sender<TaskProgress>,
+    reported: Cell<f32>,
+}
+
+impl TaskContext {
+    /// A context for running a task's work right away, on the calling thread, such as a
+    /// filter that's quick enough on a small layer. It's never cancelled, and its progress
+    /// goes nowhere.
+    pub fn detached() -> Self {
+        let (progress, _) = mpsc::channel();
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            progress,
+            reported: Cell::new(0.0),
+        }
+    }
+
+    /// Whether the task has been cancelled, in which case the work should stop as soon as
+    /// it can and return `None`, without changing anything that outlives it.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Reports that the work is `fraction` of the way done. The fraction is kept within
+    /// 0..=1, and one lower than what was last reported is raised to it, so progress only
+    /// ever goes forwards.
+    pub fn report(&self, fraction: f32, message: impl Into<String>) {
+        let fraction = match fraction.is_nan() {
+            true => self.reported.get(),
+            false => fraction.clamp(self.reported.get(), 1.0),
+        };
+        self.reported.set(fraction);
+        // nobody's listening once the task has been dropped, which is fine
+        let _ = self.progress.send(TaskProgress {
+            fraction,
+            message: message.into(),
+        });
+    }
+}
+
+/// How a task is getting on, from [`BackgroundTask::poll`].
+#[derive(Debug, PartialEq)]
+pub enum TaskStatus<T> {
+    Running,
+    /// The work finished, with this result. It's only handed out once.
+    Finished(T),
+    /// The work stopped early after [`BackgroundTask::cancel`].
+    Cancelled,
+    /// The work panicked, with the panic's message if it had one.
+    Failed(String),
+}
+
+/// Work running on its own thread. Dropping the task cancels it, and the thread finishes on
+/// its own in the background.
+pub struct BackgroundTask<T> {
+    cancelled: Arc<AtomicBool>,
+    progress: Receiver<TaskProgress>,
+    latest: TaskProgress,
+    thread: Option<JoinHandle<Option<T>>>,
+}
+
+impl<T: Send + 'static> BackgroundTask<T> {
+    /// Starts `work` on a new thread named `name`. The work returns its result, or `None`
+    /// if it stopped because it was cancelled, see [`TaskContext::is_cancelled`].
+    pub fn spawn(
+        name: &str,
+        work: impl FnOnce(&TaskContext) -> Option<T> + Send + 'static,
+    ) -> std::io::Result<Self> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (sender, progress) = mpsc::channel();
+        let context = TaskContext {
+            cancelled: Arc::clone(&cancelled),
+            progress: sender,
+            reported: Cell::new(0.0),
+        };
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || work(&context))?;
+        Ok(Self {
+            cancelled,
+            progress,
+            latest: TaskProgress::default(),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl<T> BackgroundTask<T> {
+    /// Asks the work to stop. It stops the next time it checks, and [`BackgroundTask::poll`]
+    /// says it was cancelled once it has.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// The last progress reported, as of the last [`BackgroundTask::poll`].
+    pub fn progress(&self) -> &TaskProgress {
+        &self.latest
+    }
+
+    /// Catches up on the progress reported, and returns the result if the work is done.
+    /// Once it has said the work finished, was cancelled or failed, it says it was
+    /// cancelled from then on.
+    pub fn poll(&mut self) -> TaskStatus<T> {
+        if let Some(latest) = self.progress.try_iter().last() {
+            self.latest = latest;
+        }
+        match &self.thread {
+            Some(thread) if !thread.is_finished() => return TaskStatus::Running,
+            Some(_) => {}
+            None => return TaskStatus::Cancelled,
+        }
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(Some(result))) if !self.cancelled.load(Ordering::Relaxed) => {
+                TaskStatus::Finished(result)
+            }
+            Some(Ok(_)) | None => TaskStatus::Cancelled,
+            Some(Err(panic)) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "the task panicked".to_string());
+                TaskStatus::Failed(message)
+            }
+        }
+    }
+}
+
+impl<T> Drop for BackgroundTask<T> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter_registry::{apply_filter, apply_filter_in_task};
+    use crate::filters::GaussianBlur;
+    use crate::pixel_buffer::PixelSlice;
+    use crate::Color32;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    /// Polls `task` until it's no longer running, failing if that takes over 5 seconds.
+    fn wait<T>(task: &mut BackgroundTask<T>) -> TaskStatus<T> {
+        let start = Instant::now();
+        loop {
+            match task.poll() {
+                TaskStatus::Running => {
+                    assert!(start.elapsed() < Duration::from_secs(5), "still running");
+                    thread::sleep(Duration::from_millis(1));
+                }
+                status => return status,
+            }
+        }
+    }
+
+    /// A context whose progress is kept in the receiver, for checking everything reported.
+    fn listened_to(cancelled: bool) -> (TaskContext, Receiver<TaskProgress>) {
+        let (progress, received) = mpsc::channel();
+        let context = TaskContext {
+            cancelled: Arc::new(AtomicBool::new(cancelled)),
+            progress,
+            reported: Cell::new(0.0),
+        };
+        (context, received)
+    }
+
+    #[test]
+    fn progress_only_goes_forwards() {
+        let (context, received) = listened_to(false);
+        for fraction in [0.1, 0.5, 0.3, f32::NAN, 0.7, 2.0, -1.0] {
+            context.report(fraction, "working");
+        }
+        let fractions: Vec<f32> = received.try_iter().map(|p| p.fraction).collect();
+        assert_eq!(fractions, [0.1, 0.5, 0.5, 0.5, 0.7, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn a_finished_task_hands_back_its_result_once() {
+        let mut task = BackgroundTask::spawn("sum", |task| {
+            let mut sum = 0;
+            for i in 1..=100 {
+                sum += i;
+                task.report(i as f32 / 100.0, "adding");
+            }
+            Some(sum)
+        })
+        .unwrap();
+        assert_eq!(wait(&mut task), TaskStatus::Finished(5050));
+        assert_eq!(task.progress().fraction, 1.0);
+        assert_eq!(task.progress().message, "adding");
+        assert_eq!(task.poll(), TaskStatus::Cancelled);
+    }
+
+    #[test]
+    fn cancelling_stops_the_work_promptly_and_leaves_the_document_alone() {
+        let document = vec![7u8; 1024];
+        let chunks = Arc::new(AtomicUsize::new(0));
+        let mut task = {
+            let (mut copy, chunks) = (document.clone(), Arc::clone(&chunks));
+            BackgroundTask::spawn("endless", move |task| {
+                // goes over the document again and again until it's cancelled
+                for i in 0.. {
+                    if task.is_cancelled() {
+                        return None;
+                    }
+                    let start = i * 16 % copy.len();
+                    copy[start..start + 16].fill(i as u8);
+                    chunks.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Some(copy)
+            })
+            .unwrap()
+        };
+        while chunks.load(Ordering::Relaxed) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let cancelled_at = Instant::now();
+        task.cancel();
+        assert_eq!(wait(&mut task), TaskStatus::Cancelled);
+        assert!(cancelled_at.elapsed() < Duration::from_millis(500));
+        let done = chunks.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(chunks.load(Ordering::Relaxed), done);
+        assert!(document.iter().all(|&byte| byte == 7));
+    }
+
+    #[test]
+    fn a_task_that_panics_says_why() {
+        let mut task = BackgroundTask::<()>::spawn("panics", |_| panic!("out of paint")).unwrap();
+        assert_eq!(
+            wait(&mut task),
+            TaskStatus::Failed("out of paint".to_string())
+        );
+    }
+
+    #[test]
+    fn a_blur_in_a_task_matches_one_in_place_until_it_is_cancelled() {
+        let (width, height) = (96, 80);
+        let original: Vec<Color32> = (0..width * height)
+            .map(|i| Color32::from_gray((i * 37 % 251) as u8))
+            .collect();
+        let blur = GaussianBlur { radius: 6.0 };
+        let mut in_place = original.clone();
+        apply_filter(
+            &blur,
+            &mut PixelSlice::new(&mut in_place, width, height),
+            None,
+            None,
+        );
+
+        let (context, received) = listened_to(false);
+        let mut in_task = original.clone();
+        let mut pixels = PixelSlice::new(&mut in_task, width, height);
+        let dirty = apply_filter_in_task(&blur, &mut pixels, None, None, &context);
+        assert!(dirty.is_some());
+        assert!(in_task == in_place);
+        let fractions: Vec<f32> = received.try_iter().map(|p| p.fraction).collect();
+        assert!(fractions.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(fractions.last(), Some(&1.0));
+
+        let (context, _) = listened_to(true);
+        let mut copy = original.clone();
+        let mut pixels = PixelSlice::new(&mut copy, width, height);
+        assert_eq!(
+            apply_filter_in_task(&blur, &mut pixels, None, None, &context),
+            None
+        );
+    }
+}
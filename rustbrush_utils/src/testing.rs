@@ -0,0 +1,116 @@
+//! Canvases, brushes and strokes for tests, here and in the crates built on this one, see
+//! the `test-support` feature. Strokes are painted the way replaying the history does.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use ecolor::{Color32, Rgba};
+use emath::Pos2;
+
+use crate::canvas::{
+    BrushStrokeFrame, BrushStrokeKind, Canvas, CanvasLayer, CanvasState, LayerContents, LayerPixels,
+};
+use crate::symmetry::Symmetry;
+use crate::Brush;
+
+/// A `width` by `height` document with `layers` empty layers covering it.
+pub fn canvas_state(width: u32, height: u32, layers: usize) -> CanvasState {
+    let layers = (0..layers)
+        .map(|i| CanvasLayer::new(width, height, format!("Layer {}", i + 1)).unwrap())
+        .collect();
+    CanvasState {
+        layers,
+        width,
+        height,
+    }
+}
+
+/// A canvas holding [`canvas_state`].
+pub fn canvas(width: u32, height: u32, layers: usize) -> Canvas {
+    Canvas::new(canvas_state(width, height, layers)).unwrap()
+}
+
+/// Fills every pixel of `layer` with `color`.
+pub fn fill_layer(canvas: &mut Canvas, layer: usize, color: Color32) {
+    let bounds = canvas.layers()[layer].bounds();
+    let pixels = vec![color; bounds.width as usize * bounds.height as usize];
+    canvas.restore_layer(
+        layer,
+        LayerContents {
+            bounds,
+            pixels: LayerPixels::Color(Arc::new(pixels)),
+        },
+    );
+}
+
+/// A hard brush of `radius`, fully opaque.
+pub fn hard_brush(radius: f32) -> Brush {
+    Brush::default()
+        .with_radius(radius)
+        .with_hardness(1.0)
+        .with_opacity(1.0)
+}
+
+/// One frame of a stroke from `from` to `to`, as if it came `elapsed` seconds after the one
+/// before it.
+pub fn frame(brush: &Brush, color: Rgba, from: Pos2, to: Pos2, elapsed: f32) -> BrushStrokeFrame {
+    BrushStrokeFrame {
+        brush: brush.clone(),
+        color,
+        cursor_position: to,
+        last_cursor_position: from,
+        previous_cursor_position: None,
+        timestamp: Instant::now(),
+        elapsed,
+    }
+}
+
+/// Strokes `layer` through `points`, a frame between each pair.
+pub fn stroke(
+    canvas: &mut Canvas,
+    layer: usize,
+    kind: BrushStrokeKind,
+    brush: &Brush,
+    color: Rgba,
+    points: &[Pos2],
+) {
+    stroke_through(canvas, layer, None, kind, brush, color, points);
+}
+
+/// Like [`stroke`], painted through the `stencil` layer.
+pub fn stroke_through(
+    canvas: &mut Canvas,
+    layer: usize,
+    stencil: Option<usize>,
+    kind: BrushStrokeKind,
+    brush: &Brush,
+    color: Rgba,
+    points: &[Pos2],
+) {
+    canvas.begin_brush_stroke(Symmetry::None, stencil, 0, None);
+    paint_frames(canvas, layer, kind, brush, color, points);
+    canvas.end_brush_stroke();
+}
+
+/// The frames of a stroke through `points` on `layer`, once it's begun.
+pub fn paint_frames(
+    canvas: &mut Canvas,
+    layer: usize,
+    kind: BrushStrokeKind,
+    brush: &Brush,
+    color: Rgba,
+    points: &[Pos2],
+) {
+    let first = points[0];
+    let frames = std::iter::once((first, first)).chain(points.windows(2).map(|w| (w[0], w[1])));
+    for (i, (from, to)) in frames.enumerate() {
+        let elapsed = if i == 0 { 0.0 } else { 1.0 / 60.0 };
+        canvas
+            .process_brush_stroke_frame(
+                layer,
+                kind.clone(),
+                &frame(brush, color, from, to, elapsed),
+            )
+            .unwrap();
+    }
+}